@@ -201,6 +201,13 @@ impl CryptoVec {
 	/// ```
 	pub fn is_empty(&self) -> bool { self.len() == 0 }
 
+	/// The number of bytes this CryptoVec can hold without reallocating.
+	///
+	/// ```
+	/// assert_eq!(russh_cryptovec::CryptoVec::with_capacity(100).capacity(), 128)
+	/// ```
+	pub fn capacity(&self) -> usize { self.capacity }
+
 	/// Resize this CryptoVec, appending zeros at the end. This may
 	/// perform at most one reallocation, overwriting the previous
 	/// version with zeros.
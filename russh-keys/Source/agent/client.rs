@@ -55,7 +55,51 @@ impl AgentClient<tokio::net::UnixStream> {
 	}
 }
 
-#[cfg(not(unix))]
+/// The named pipe of the Win32-OpenSSH agent.
+#[cfg(windows)]
+pub const OPENSSH_AGENT_PIPE:&str = r"\\.\pipe\openssh-ssh-agent";
+
+#[cfg(windows)]
+impl AgentClient<tokio::net::windows::named_pipe::NamedPipeClient> {
+	/// Connect to an SSH agent listening on a named pipe, such as
+	/// [`OPENSSH_AGENT_PIPE`], waiting for a while if all its instances
+	/// are busy.
+	pub async fn connect_named_pipe<P:AsRef<std::ffi::OsStr>>(path:P) -> Result<Self, Error> {
+		// `ERROR_PIPE_BUSY`, from `winerror.h`.
+		const ERROR_PIPE_BUSY:i32 = 231;
+
+		let mut attempts = 0;
+
+		loop {
+			match tokio::net::windows::named_pipe::ClientOptions::new().open(path.as_ref()) {
+				Ok(stream) => return Ok(AgentClient { stream, buf:CryptoVec::new() }),
+				Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) && attempts < 100 => {
+					attempts += 1;
+
+					tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+				},
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+
+	/// Connect to the SSH agent named by the `SSH_AUTH_SOCK` environment
+	/// variable if it is set, else to the Win32-OpenSSH agent.
+	pub async fn connect_env() -> Result<Self, Error> {
+		let var = std::env::var("SSH_AUTH_SOCK").ok();
+
+		match Self::connect_named_pipe(var.as_deref().unwrap_or(OPENSSH_AGENT_PIPE)).await {
+			Err(Error::IO(io_err))
+				if var.is_some() && io_err.kind() == std::io::ErrorKind::NotFound =>
+			{
+				Err(Error::BadAuthSock)
+			},
+			owise => owise,
+		}
+	}
+}
+
+#[cfg(not(any(unix, windows)))]
 impl AgentClient<tokio::net::TcpStream> {
 	/// Build a future that connects to an SSH agent via the provided
 	/// stream (on Unix, usually a Unix-domain socket).
@@ -88,12 +132,71 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 		Ok(())
 	}
 
+	/// Send a raw message (without its length) to the agent, and return
+	/// the raw answer.
+	pub(crate) async fn forward_message(&mut self, message:&[u8]) -> Result<&[u8], Error> {
+		self.buf.clear();
+
+		self.buf.push_u32_be(message.len() as u32);
+
+		self.buf.extend(message);
+
+		self.read_response().await?;
+
+		Ok(&self.buf)
+	}
+
+	/// Send the request in the buffer, and expect `SSH_AGENT_SUCCESS`.
+	async fn read_success(&mut self) -> Result<(), Error> {
+		self.read_response().await?;
+
+		match self.buf.first() {
+			Some(&msg::SUCCESS) => Ok(()),
+			Some(&msg::FAILURE) => Err(Error::AgentFailure),
+			_ => Err(Error::AgentProtocolError),
+		}
+	}
+
+	/// Append constraints to an `ADD_ID_CONSTRAINED` or
+	/// `ADD_SMARTCARD_KEY_CONSTRAINED` request.
+	fn write_constraints(&mut self, constraints:&[Constraint]) {
+		for cons in constraints {
+			match *cons {
+				Constraint::KeyLifetime { seconds } => {
+					self.buf.push(msg::CONSTRAIN_LIFETIME);
+
+					self.buf.push_u32_be(seconds)
+				},
+				Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
+				Constraint::Extensions { ref name, ref details } => {
+					self.buf.push(msg::CONSTRAIN_EXTENSION);
+
+					self.buf.extend_ssh_string(name);
+
+					self.buf.extend_ssh_string(details);
+				},
+			}
+		}
+	}
+
 	/// Send a key to the agent, with a (possibly empty) slice of
 	/// constraints to apply when using the key to sign.
 	pub async fn add_identity(
 		&mut self,
 		key:&key::KeyPair,
 		constraints:&[Constraint],
+	) -> Result<(), Error> {
+		self.add_identity_constrained(key, "", constraints).await
+	}
+
+	/// Send a key to the agent with a comment, and a (possibly empty)
+	/// slice of constraints, such as a lifetime after which the agent
+	/// forgets the key, or a confirmation on each use.
+	pub async fn add_identity_constrained(
+		&mut self,
+		key:&key::KeyPair,
+		comment:&str,
+		constraints:&[Constraint],
 	) -> Result<(), Error> {
 		self.buf.clear();
 
@@ -118,7 +221,7 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 				self.buf.extend(pair.public.as_bytes());
 
-				self.buf.extend_ssh_string(b"");
+				self.buf.extend_ssh_string(comment.as_bytes());
 			},
 			#[cfg(feature = "openssl")]
 			#[allow(clippy::unwrap_used)] // key is known to be private
@@ -147,39 +250,17 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 				self.buf.extend_ssh_mpint(&key.q().unwrap().to_vec());
 
-				self.buf.extend_ssh_string(b"");
+				self.buf.extend_ssh_string(comment.as_bytes());
 			},
 		}
 
-		if !constraints.is_empty() {
-			self.buf.push_u32_be(constraints.len() as u32);
-
-			for cons in constraints {
-				match *cons {
-					Constraint::KeyLifetime { seconds } => {
-						self.buf.push(msg::CONSTRAIN_LIFETIME);
-
-						self.buf.push_u32_be(seconds)
-					},
-					Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-					Constraint::Extensions { ref name, ref details } => {
-						self.buf.push(msg::CONSTRAIN_EXTENSION);
-
-						self.buf.extend_ssh_string(name);
-
-						self.buf.extend_ssh_string(details);
-					},
-				}
-			}
-		}
+		self.write_constraints(constraints);
 
 		let len = self.buf.len() - 4;
 
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Add a smart card to the agent, with a (possibly empty) set of
@@ -204,35 +285,13 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 		self.buf.extend_ssh_string(pin);
 
-		if !constraints.is_empty() {
-			self.buf.push_u32_be(constraints.len() as u32);
-
-			for cons in constraints {
-				match *cons {
-					Constraint::KeyLifetime { seconds } => {
-						self.buf.push(msg::CONSTRAIN_LIFETIME);
-
-						self.buf.push_u32_be(seconds)
-					},
-					Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-					Constraint::Extensions { ref name, ref details } => {
-						self.buf.push(msg::CONSTRAIN_EXTENSION);
-
-						self.buf.extend_ssh_string(name);
-
-						self.buf.extend_ssh_string(details);
-					},
-				}
-			}
-		}
+		self.write_constraints(constraints);
 
 		let len = self.buf.len() - 4;
 
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Lock the agent, making it refuse to sign until unlocked.
@@ -249,9 +308,7 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Unlock the agent, allowing it to sign again.
@@ -268,9 +325,7 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 		#[allow(clippy::indexing_slicing)] // static length
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Ask the agent for a list of the currently registered secret
@@ -332,6 +387,8 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 							r.read_string()?,
 						)?))
 					},
+					#[cfg(feature = "rs-crypto")]
+					b"sk-ssh-ed25519@openssh.com" => keys.push(PublicKey::parse(t, key)?),
 					t => {
 						info!("Unsupported key type: {:?}", std::str::from_utf8(t))
 					},
@@ -421,11 +478,17 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 	fn write_signature(&self, hash:u32, data:&mut CryptoVec) -> Result<(), Error> {
 		let mut r = self.buf.reader(1);
 
-		let mut resp = r.read_string()?.reader(0);
+		let blob = r.read_string()?;
+
+		let mut resp = blob.reader(0);
 
 		let t = resp.read_string()?;
 
-		if (hash == 2 && t == b"rsa-sha2-256") || (hash == 4 && t == b"rsa-sha2-512") || hash == 0 {
+		if t == key::SK_ED25519.0.as_bytes() {
+			// Security key signatures also carry flags and a counter, pass
+			// them through untouched.
+			data.extend_ssh_string(blob);
+		} else if (hash == 2 && t == b"rsa-sha2-256") || (hash == 4 && t == b"rsa-sha2-512") || hash == 0 {
 			let sig = resp.read_string()?;
 
 			data.push_u32_be((t.len() + sig.len() + 8) as u32);
@@ -485,53 +548,36 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 				return (self, Err(e));
 			}
 
-			if let Err(e) = self.read_response().await {
-				return (self, Err(e));
-			}
+			let sig = self.read_signature().await;
+			(self, sig)
+		}
+	}
 
-			#[allow(clippy::indexing_slicing)] // length is checked
-			if !self.buf.is_empty() && self.buf[0] == msg::SIGN_RESPONSE {
-				let as_sig = |buf:&CryptoVec| -> Result<crate::signature::Signature, Error> {
-					let mut r = buf.reader(1);
-
-					let mut resp = r.read_string()?.reader(0);
-
-					let typ = resp.read_string()?;
-
-					let sig = resp.read_string()?;
-
-					use crate::signature::Signature;
-
-					match typ {
-						b"ssh-rsa" => {
-							Ok(Signature::RSA { bytes:sig.to_vec(), hash:SignatureHash::SHA1 })
-						},
-						b"rsa-sha2-256" => {
-							Ok(Signature::RSA { bytes:sig.to_vec(), hash:SignatureHash::SHA2_256 })
-						},
-						b"rsa-sha2-512" => {
-							Ok(Signature::RSA { bytes:sig.to_vec(), hash:SignatureHash::SHA2_512 })
-						},
-						b"ssh-ed25519" => {
-							let mut sig_bytes = [0; 64];
-
-							sig_bytes.clone_from_slice(sig);
-
-							Ok(Signature::Ed25519(crate::signature::SignatureBytes(sig_bytes)))
-						},
-						_ => {
-							Err(Error::UnknownSignatureType {
-								sig_type:std::str::from_utf8(typ).unwrap_or("").to_string(),
-							})
-						},
-					}
-				};
-
-				let sig = as_sig(&self.buf);
-				(self, sig)
-			} else {
-				(self, Err(Error::AgentProtocolError))
-			}
+	/// Ask the agent to sign the supplied piece of data with `public`
+	/// (using its signature hash, for RSA keys), and return a
+	/// `Signature`.
+	pub async fn sign(
+		&mut self,
+		public:&key::PublicKey,
+		data:&[u8],
+	) -> Result<crate::signature::Signature, Error> {
+		self.prepare_sign_request(public, data)?;
+
+		self.read_signature().await
+	}
+
+	/// Send the sign request in the buffer, and parse the signature.
+	async fn read_signature(&mut self) -> Result<crate::signature::Signature, Error> {
+		self.read_response().await?;
+
+		match self.buf.first() {
+			Some(&msg::SIGN_RESPONSE) => {
+				let mut r = self.buf.reader(1);
+
+				crate::signature::Signature::from_bytes(r.read_string()?)
+			},
+			Some(&msg::FAILURE) => Err(Error::AgentFailure),
+			_ => Err(Error::AgentProtocolError),
 		}
 	}
 
@@ -549,9 +595,7 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Ask the agent to remove a smartcard from its memory.
@@ -570,9 +614,7 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 		BigEndian::write_u32(&mut self.buf[..], len as u32);
 
-		self.read_response().await?;
-
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Ask the agent to forget all known keys.
@@ -583,11 +625,9 @@ impl<S:AsyncRead + AsyncWrite + Unpin> AgentClient<S> {
 
 		self.buf.push(msg::REMOVE_ALL_IDENTITIES);
 
-		BigEndian::write_u32(&mut self.buf[..], 5);
-
-		self.read_response().await?;
+		BigEndian::write_u32(&mut self.buf[..], 1);
 
-		Ok(())
+		self.read_success().await
 	}
 
 	/// Send a custom message to the agent.
@@ -670,6 +710,10 @@ fn key_blob(public:&key::PublicKey, buf:&mut CryptoVec) -> Result<(), Error> {
 			#[allow(clippy::indexing_slicing)] // length is known
 			BigEndian::write_u32(&mut buf[5..], (len1 - len0) as u32);
 		},
+		#[cfg(feature = "rs-crypto")]
+		PublicKey::SkEd25519 { .. } => {
+			buf.extend_ssh_string(&crate::PublicKeyBase64::public_key_bytes(public));
+		},
 	}
 
 	Ok(())
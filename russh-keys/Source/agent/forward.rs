@@ -0,0 +1,147 @@
+use byteorder::{BigEndian, ByteOrder};
+use futures::future::Future;
+use russh_cryptovec::CryptoVec;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{client::AgentClient, msg};
+use crate::{Error, encoding::Reader, key};
+
+/// The longest message accepted from a forwarded connection, as in
+/// OpenSSH.
+const MAX_MESSAGE_LEN:usize = 256 * 1024;
+
+/// A signing request received on a forwarded agent connection.
+#[derive(Debug, Clone)]
+pub struct SignRequest {
+	/// The data to sign.
+	pub data:Vec<u8>,
+	/// The request flags, for instance `SSH_AGENT_RSA_SHA2_256` (2).
+	pub flags:u32,
+}
+
+/// What to do with a signing request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+	/// Pass the request on to the agent.
+	Allow,
+	/// Answer `SSH_AGENT_FAILURE` without asking the agent.
+	Deny,
+	/// Pass the request on only if [`Filter::confirm`] accepts it.
+	Confirm,
+}
+
+/// A policy for the signing requests of a forwarded agent connection.
+///
+/// Any `Fn(&PublicKey, &SignRequest) -> Decision` is a filter, which
+/// refuses the requests it wants confirmed.
+pub trait Filter: Send + Sync {
+	/// Decide what to do with a request to sign with `key`.
+	fn decide(&self, key:&key::PublicKey, request:&SignRequest) -> Decision;
+
+	/// Confirm a request for which [`Filter::decide`] returned
+	/// [`Decision::Confirm`] (for instance using a dialog).
+	fn confirm(
+		&self,
+		_key:&key::PublicKey,
+		_request:&SignRequest,
+	) -> Box<dyn Future<Output = bool> + Unpin + Send> {
+		Box::new(futures::future::ready(false))
+	}
+}
+
+impl<F:Fn(&key::PublicKey, &SignRequest) -> Decision + Send + Sync> Filter for F {
+	fn decide(&self, key:&key::PublicKey, request:&SignRequest) -> Decision { self(key, request) }
+}
+
+/// Serve a forwarded agent connection, such as an
+/// `auth-agent@openssh.com` channel, from `agent`.
+///
+/// Signing requests go through `filter`, and the ones it refuses are
+/// answered with `SSH_AGENT_FAILURE`, leaving the connection open. Other
+/// messages are passed on untouched. Returns when `stream` is closed.
+pub async fn forward<S, A, F>(
+	mut stream:S,
+	agent:&mut AgentClient<A>,
+	filter:&F,
+) -> Result<(), Error>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	A: AsyncRead + AsyncWrite + Unpin,
+	F: Filter + ?Sized, {
+	let mut buf = CryptoVec::new();
+
+	loop {
+		buf.clear();
+
+		buf.resize(4);
+
+		match stream.read_exact(&mut buf).await {
+			Ok(_) => {},
+			Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+			Err(e) => return Err(e.into()),
+		}
+
+		let len = BigEndian::read_u32(&buf) as usize;
+
+		if len > MAX_MESSAGE_LEN {
+			return Err(Error::AgentProtocolError);
+		}
+
+		buf.clear();
+
+		buf.resize(len);
+
+		stream.read_exact(&mut buf).await?;
+
+		let allowed = if buf.first() == Some(&msg::SIGN_REQUEST) {
+			allowed(&buf, filter).await
+		} else {
+			true
+		};
+
+		if allowed {
+			let answer = agent.forward_message(&buf).await?;
+
+			stream.write_all(&(answer.len() as u32).to_be_bytes()).await?;
+
+			stream.write_all(answer).await?;
+		} else {
+			stream.write_all(&[0, 0, 0, 1, msg::FAILURE]).await?;
+		}
+
+		stream.flush().await?;
+	}
+}
+
+/// Whether `filter` lets a signing request through. Requests that cannot
+/// be parsed are refused.
+async fn allowed<F:Filter + ?Sized>(message:&[u8], filter:&F) -> bool {
+	let (key, request) = match parse_sign_request(message) {
+		Ok(r) => r,
+		Err(_) => return false,
+	};
+
+	match filter.decide(&key, &request) {
+		Decision::Allow => true,
+		Decision::Deny => false,
+		Decision::Confirm => filter.confirm(&key, &request).await,
+	}
+}
+
+fn parse_sign_request(message:&[u8]) -> Result<(key::PublicKey, SignRequest), Error> {
+	let mut r = message.reader(1);
+
+	let blob = r.read_string()?;
+
+	let data = r.read_string()?.to_vec();
+
+	let flags = r.read_u32()?;
+
+	let key = key::parse_public_key(
+		blob,
+		#[cfg(feature = "openssl")]
+		None,
+	)?;
+
+	Ok((key, SignRequest { data, flags }))
+}
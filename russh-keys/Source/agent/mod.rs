@@ -1,5 +1,7 @@
 /// Write clients for SSH agents.
 pub mod client;
+/// Forward agent connections, filtering signing requests.
+pub mod forward;
 mod msg;
 /// Write servers for SSH agents.
 pub mod server;
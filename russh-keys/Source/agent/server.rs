@@ -369,11 +369,10 @@ impl<S:AsyncRead + AsyncWrite + Send + Unpin + 'static, A:Agent + Send + 'static
 		let now = SystemTime::now();
 
 		if constrained {
-			let n = r.read_u32()?;
-
 			let mut c = Vec::new();
 
-			for _ in 0..n {
+			// Constraints run until the end of the message.
+			while !r.is_at_end() {
 				let t = r.read_byte()?;
 
 				if t == msg::CONSTRAIN_LIFETIME {
@@ -407,7 +406,7 @@ impl<S:AsyncRead + AsyncWrite + Send + Unpin + 'static, A:Agent + Send + 'static
 				}
 			}
 
-			w.insert(blob, (Arc::new(key), now, Vec::new()));
+			w.insert(blob, (Arc::new(key), now, c));
 		} else {
 			w.insert(blob, (Arc::new(key), now, Vec::new()));
 		}
@@ -0,0 +1,412 @@
+//! OpenSSH `authorized_keys` files, as described in the
+//! "AUTHORIZED_KEYS FILE FORMAT" section of sshd(8).
+//!
+//! Each line holds a public key, preceded by options restricting its use
+//! and followed by a comment:
+//!
+//! ```text
+//! from="10.0.0.0/8,!10.0.0.1",no-pty,command="uptime" ssh-ed25519 AAAA... alice@laptop
+//! ```
+
+use std::{
+	fs::File,
+	io::Read,
+	net::IpAddr,
+	path::Path,
+	str::FromStr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::BASE64_MIME;
+use log::debug;
+
+use crate::{Error, encoding::Reader, key, wildcard_matches};
+
+/// The key types that may start a line without options.
+pub(crate) const KEY_TYPES:&[&str] = &[
+	"ssh-ed25519",
+	"ssh-rsa",
+	"ssh-dss",
+	"ecdsa-sha2-nistp256",
+	"ecdsa-sha2-nistp384",
+	"ecdsa-sha2-nistp521",
+	"sk-ssh-ed25519@openssh.com",
+	"sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// A line of an `authorized_keys` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedKey {
+	/// The options preceding the key.
+	pub options:Options,
+	/// The key, or with [`Options::cert_authority`], the key of a CA
+	/// signing user certificates.
+	pub key:key::PublicKey,
+	/// The comment following the key, usually `user@host`.
+	pub comment:String,
+}
+
+/// The options of an `authorized_keys` line.
+///
+/// The forwarding and pty permissions are `None` unless an option sets
+/// them; use [`Options::allows_pty`] and its siblings to interpret them
+/// along with `restrict`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+	/// `agent-forwarding` or `no-agent-forwarding`.
+	pub agent_forwarding:Option<bool>,
+	/// `cert-authority`: the key is a CA trusted to certify users.
+	pub cert_authority:bool,
+	/// `command="..."`: run this command instead of the user's.
+	pub command:Option<String>,
+	/// `environment="NAME=value"` options.
+	pub environment:Vec<(String, String)>,
+	/// `expiry-time="timespec"`: the key is refused after this time. A
+	/// timespec without a trailing `Z` is read as UTC, not local time.
+	pub expiry_time:Option<SystemTime>,
+	/// `from="pattern-list"`: the hosts the key may connect from.
+	pub from:Option<String>,
+	/// `no-touch-required`: security key signatures need not prove user
+	/// presence.
+	pub no_touch_required:bool,
+	/// `permitlisten="[host:]port"` options.
+	pub permit_listen:Vec<String>,
+	/// `permitopen="host:port"` options.
+	pub permit_open:Vec<String>,
+	/// `port-forwarding` or `no-port-forwarding`.
+	pub port_forwarding:Option<bool>,
+	/// `principals="name,..."`, on `cert-authority` lines: the principals
+	/// accepted in certificates, instead of the user name.
+	pub principals:Option<Vec<String>>,
+	/// `pty` or `no-pty`.
+	pub pty:Option<bool>,
+	/// `restrict`: everything not explicitly permitted is refused.
+	pub restrict:bool,
+	/// `tunnel="n"`: the tun device to use.
+	pub tunnel:Option<String>,
+	/// `user-rc` or `no-user-rc`.
+	pub user_rc:Option<bool>,
+	/// `verify-required`: security key signatures must prove user
+	/// verification (such as a PIN).
+	pub verify_required:bool,
+	/// `X11-forwarding` or `no-X11-forwarding`.
+	pub x11_forwarding:Option<bool>,
+}
+
+impl Options {
+	/// Whether agent forwarding is allowed.
+	pub fn allows_agent_forwarding(&self) -> bool {
+		self.agent_forwarding.unwrap_or(!self.restrict)
+	}
+
+	/// Whether port forwarding is allowed.
+	pub fn allows_port_forwarding(&self) -> bool {
+		self.port_forwarding.unwrap_or(!self.restrict)
+	}
+
+	/// Whether a pty may be allocated.
+	pub fn allows_pty(&self) -> bool { self.pty.unwrap_or(!self.restrict) }
+
+	/// Whether `~/.ssh/rc` may be run.
+	pub fn allows_user_rc(&self) -> bool { self.user_rc.unwrap_or(!self.restrict) }
+
+	/// Whether X11 forwarding is allowed.
+	pub fn allows_x11_forwarding(&self) -> bool {
+		self.x11_forwarding.unwrap_or(!self.restrict)
+	}
+
+	/// Whether the key has expired at `now`.
+	pub fn is_expired(&self, now:SystemTime) -> bool {
+		self.expiry_time.map_or(false, |expiry| now >= expiry)
+	}
+
+	/// Whether the `from=` patterns allow a connection from `addr`, whose
+	/// host name is `host` if known. Patterns may use `*` and `?`
+	/// wildcards and CIDR masks, and patterns starting with `!` refuse
+	/// the hosts they match.
+	pub fn from_allows(&self, addr:IpAddr, host:Option<&str>) -> bool {
+		let patterns = match self.from {
+			Some(ref patterns) => patterns,
+			None => return true,
+		};
+
+		let addr_string = addr.to_string();
+
+		let mut allowed = false;
+
+		for pattern in patterns.split(',') {
+			let (negated, pattern) = match pattern.strip_prefix('!') {
+				Some(pattern) => (true, pattern),
+				None => (false, pattern),
+			};
+
+			let matches = cidr_matches(pattern, addr)
+				|| wildcard_matches(pattern.as_bytes(), addr_string.as_bytes())
+				|| host.map_or(false, |host| wildcard_matches(pattern.as_bytes(), host.as_bytes()));
+
+			if matches && negated {
+				return false;
+			}
+
+			allowed |= matches;
+		}
+
+		allowed
+	}
+
+	/// Parse the options prefix of a line, returning the rest of the line.
+	fn parse(mut s:&str) -> Result<(Self, &str), Error> {
+		let mut options = Options::default();
+
+		loop {
+			let name_end =
+				s.find(|c:char| c == '=' || c == ',' || c.is_whitespace()).unwrap_or(s.len());
+
+			let (name, rest) = s.split_at(name_end);
+
+			let (value, rest) = match rest.strip_prefix('=') {
+				Some(rest) => {
+					let (value, rest) = quoted(rest).ok_or_else(|| invalid_option(name))?;
+
+					(Some(value), rest)
+				},
+				None => (None, rest),
+			};
+
+			options.set(name, value)?;
+
+			s = match rest.strip_prefix(',') {
+				Some(rest) => rest,
+				None if rest.starts_with(char::is_whitespace) => return Ok((options, rest)),
+				None => return Err(Error::CouldNotReadKey),
+			};
+		}
+	}
+
+	fn set(&mut self, name:&str, value:Option<String>) -> Result<(), Error> {
+		let flag = value.is_none();
+
+		let required = |value:Option<String>| value.ok_or_else(|| invalid_option(name));
+
+		match name.to_ascii_lowercase().as_str() {
+			"agent-forwarding" if flag => self.agent_forwarding = Some(true),
+			"no-agent-forwarding" if flag => self.agent_forwarding = Some(false),
+			"cert-authority" if flag => self.cert_authority = true,
+			"command" => self.command = Some(required(value)?),
+			"environment" => {
+				let value = required(value)?;
+
+				let (var, val) = value.split_once('=').ok_or_else(|| invalid_option(name))?;
+
+				self.environment.push((var.to_string(), val.to_string()))
+			},
+			"expiry-time" => {
+				let time = parse_timespec(&required(value)?).ok_or_else(|| invalid_option(name))?;
+
+				// The earliest expiry time wins.
+				self.expiry_time = Some(self.expiry_time.map_or(time, |t| t.min(time)))
+			},
+			"from" => self.from = Some(required(value)?),
+			"no-touch-required" if flag => self.no_touch_required = true,
+			"permitlisten" => self.permit_listen.push(required(value)?),
+			"permitopen" => self.permit_open.push(required(value)?),
+			"port-forwarding" if flag => self.port_forwarding = Some(true),
+			"no-port-forwarding" if flag => self.port_forwarding = Some(false),
+			"principals" => {
+				self.principals = Some(required(value)?.split(',').map(String::from).collect())
+			},
+			"pty" if flag => self.pty = Some(true),
+			"no-pty" if flag => self.pty = Some(false),
+			"restrict" if flag => self.restrict = true,
+			"tunnel" => self.tunnel = Some(required(value)?),
+			"user-rc" if flag => self.user_rc = Some(true),
+			"no-user-rc" if flag => self.user_rc = Some(false),
+			"verify-required" if flag => self.verify_required = true,
+			"x11-forwarding" if flag => self.x11_forwarding = Some(true),
+			"no-x11-forwarding" if flag => self.x11_forwarding = Some(false),
+			_ => return Err(invalid_option(name)),
+		}
+
+		Ok(())
+	}
+}
+
+impl FromStr for AuthorizedKey {
+	type Err = Error;
+
+	/// Parse a line of an `authorized_keys` file.
+	fn from_str(line:&str) -> Result<Self, Error> {
+		let line = line.trim();
+
+		let first = line.split_whitespace().next().ok_or(Error::CouldNotReadKey)?;
+
+		let (options, rest) = if KEY_TYPES.contains(&first) {
+			(Options::default(), line)
+		} else {
+			Options::parse(line)?
+		};
+
+		let mut fields = rest.split_whitespace();
+
+		let (key_type, key) = match (fields.next(), fields.next()) {
+			(Some(key_type), Some(key)) => (key_type, key),
+			_ => return Err(Error::CouldNotReadKey),
+		};
+
+		let blob = BASE64_MIME.decode(key.as_bytes())?;
+
+		if blob.reader(0).read_string()? != key_type.as_bytes() {
+			return Err(Error::CouldNotReadKey);
+		}
+
+		let key = key::parse_public_key(
+			&blob,
+			#[cfg(feature = "openssl")]
+			None,
+		)?;
+
+		let comment = fields.collect::<Vec<_>>().join(" ");
+
+		Ok(AuthorizedKey { options, key, comment })
+	}
+}
+
+/// Parse the contents of an `authorized_keys` file. Comments and blank
+/// lines are ignored, and like sshd, lines that cannot be parsed (or
+/// hold keys of unsupported types) are skipped.
+pub fn parse_authorized_keys(contents:&str) -> Vec<AuthorizedKey> {
+	contents
+		.lines()
+		.enumerate()
+		.filter(|(_, line)| {
+			let line = line.trim_start();
+
+			!line.is_empty() && !line.starts_with('#')
+		})
+		.filter_map(|(n, line)| {
+			line.parse()
+				.map_err(|e| debug!("authorized_keys line {}: {:?}", n + 1, e))
+				.ok()
+		})
+		.collect()
+}
+
+/// Load an `authorized_keys` file, such as `~/.ssh/authorized_keys`.
+pub fn load_authorized_keys<P:AsRef<Path>>(path:P) -> Result<Vec<AuthorizedKey>, Error> {
+	let mut contents = String::new();
+
+	File::open(path)?.read_to_string(&mut contents)?;
+
+	Ok(parse_authorized_keys(&contents))
+}
+
+fn invalid_option(name:&str) -> Error { Error::InvalidAuthorizedKeysOption(name.to_string()) }
+
+/// Read a double-quoted option value, where `\"` is an escaped quote,
+/// returning it and the rest of the line.
+pub(crate) fn quoted(s:&str) -> Option<(String, &str)> {
+	let s = s.strip_prefix('"')?;
+
+	let mut value = String::new();
+
+	let mut chars = s.char_indices();
+
+	while let Some((i, c)) = chars.next() {
+		match c {
+			'"' => return Some((value, s.get(i + 1..)?)),
+			'\\' => {
+				match chars.next() {
+					Some((_, '"')) => value.push('"'),
+					Some((_, c)) => {
+						value.push('\\');
+
+						value.push(c)
+					},
+					None => return None,
+				}
+			},
+			c => value.push(c),
+		}
+	}
+
+	None
+}
+
+/// Whether `pattern` is a CIDR mask (such as `192.168.0.0/16`, or an
+/// address) containing `addr`.
+fn cidr_matches(pattern:&str, addr:IpAddr) -> bool {
+	let (network, bits) = match pattern.split_once('/') {
+		Some((network, bits)) => (network, bits.parse::<u32>().ok()),
+		None => (pattern, None),
+	};
+
+	match (network.parse::<IpAddr>(), addr) {
+		(Ok(IpAddr::V4(network)), IpAddr::V4(addr)) => {
+			let (network, addr) = (u32::from(network).into(), u32::from(addr).into());
+
+			prefix_matches(network, addr, bits.unwrap_or(32), 32)
+		},
+		(Ok(IpAddr::V6(network)), IpAddr::V6(addr)) => {
+			prefix_matches(network.into(), addr.into(), bits.unwrap_or(128), 128)
+		},
+		_ => false,
+	}
+}
+
+fn prefix_matches(network:u128, addr:u128, bits:u32, len:u32) -> bool {
+	if bits > len {
+		return false;
+	}
+
+	let shift = len - bits;
+
+	network.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+}
+
+/// Parse a timespec, `YYYYMMDD[HHMM[SS]][Z]`, as in `expiry-time`.
+pub(crate) fn parse_timespec(s:&str) -> Option<SystemTime> {
+	let s = s.strip_suffix(|c| c == 'Z' || c == 'z').unwrap_or(s);
+
+	if !s.bytes().all(|c| c.is_ascii_digit()) || ![8, 12, 14].contains(&s.len()) {
+		return None;
+	}
+
+	let field = |range:std::ops::Range<usize>| s.get(range).and_then(|f| f.parse::<u32>().ok());
+
+	let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+
+	let (hour, minute, second) =
+		(field(8..10).unwrap_or(0), field(10..12).unwrap_or(0), field(12..14).unwrap_or(0));
+
+	if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+		return None;
+	}
+
+	if hour > 23 || minute > 59 || second > 60 {
+		return None;
+	}
+
+	let days = days_from_civil(year, month, day)?;
+
+	let seconds = days * 86400 + u64::from(hour * 3600 + minute * 60 + second);
+
+	UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// The number of days from 1970-01-01 to a date of the proleptic
+/// Gregorian calendar, for dates after the epoch.
+fn days_from_civil(year:u32, month:u32, day:u32) -> Option<u64> {
+	let year = u64::from(if month <= 2 { year.checked_sub(1)? } else { year });
+
+	let (month, day) = (u64::from(month), u64::from(day));
+
+	let era = year / 400;
+
+	let year_of_era = year % 400;
+
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+	(era * 146097 + day_of_era).checked_sub(719468)
+}
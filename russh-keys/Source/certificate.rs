@@ -0,0 +1,408 @@
+//! OpenSSH certificates, as described in OpenSSH's `PROTOCOL.certkeys`.
+//!
+//! A certificate binds a public key to a set of principals and a
+//! validity period, and is signed by a certificate authority (CA). User
+//! certificates are validated by servers ([`Certificate::validate`]), host
+//! certificates by clients ([`Certificate::validate_host`]).
+
+use std::{
+	net::IpAddr,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+
+use crate::{
+	Error,
+	PublicKeyBase64,
+	encoding::Reader,
+	key::{self, PublicKey},
+};
+
+/// The name of the Ed25519 certificate algorithm.
+pub const ED25519_CERT:key::Name = key::Name("ssh-ed25519-cert-v01@openssh.com");
+/// The name of the Ed25519 certificate algorithm for FIDO security keys.
+pub const SK_ED25519_CERT:key::Name = key::Name("sk-ssh-ed25519-cert-v01@openssh.com");
+/// The name of the RSA certificate algorithm, signing with SHA2-512.
+pub const RSA_SHA2_512_CERT:key::Name = key::Name("rsa-sha2-512-cert-v01@openssh.com");
+/// The name of the RSA certificate algorithm, signing with SHA2-256.
+pub const RSA_SHA2_256_CERT:key::Name = key::Name("rsa-sha2-256-cert-v01@openssh.com");
+/// The name of the RSA certificate algorithm, signing with SHA1.
+pub const SSH_RSA_CERT:key::Name = key::Name("ssh-rsa-cert-v01@openssh.com");
+
+const CERT_SUFFIX:&[u8] = b"-cert-v01@openssh.com";
+
+/// Whether `algo` names a certificate algorithm.
+pub fn is_certificate_algorithm(algo:&[u8]) -> bool {
+	[ED25519_CERT, SK_ED25519_CERT, RSA_SHA2_512_CERT, RSA_SHA2_256_CERT, SSH_RSA_CERT]
+		.iter()
+		.any(|name| name.0.as_bytes() == algo)
+}
+
+/// Whether a certificate identifies a user or a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertType {
+	User,
+	Host,
+}
+
+/// An OpenSSH certificate.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+	/// The certified public key.
+	pub key:PublicKey,
+	pub nonce:Vec<u8>,
+	pub serial:u64,
+	pub cert_type:CertType,
+	pub key_id:String,
+	/// Users or hosts this certificate is valid for.
+	pub valid_principals:Vec<String>,
+	/// Start of the validity period, in seconds since the Unix epoch.
+	pub valid_after:u64,
+	/// End of the validity period (excluded), in seconds since the Unix
+	/// epoch.
+	pub valid_before:u64,
+	/// Critical options, as `(name, value)` pairs.
+	pub critical_options:Vec<(String, String)>,
+	/// Extensions, as `(name, value)` pairs.
+	pub extensions:Vec<(String, String)>,
+	/// The key of the CA that signed this certificate.
+	pub signature_key:PublicKey,
+	blob:Vec<u8>,
+	/// Length of the part of `blob` covered by `signature`.
+	signed_len:usize,
+	signature:Vec<u8>,
+}
+
+impl Certificate {
+	/// Parse a certificate blob, as sent in public key authentication or
+	/// found base64-encoded in `*-cert.pub` files.
+	pub fn parse(blob:&[u8]) -> Result<Self, Error> {
+		Self::parse_(blob).map_err(|e| {
+			debug!("could not parse certificate: {:?}", e);
+
+			Error::CouldNotReadKey
+		})
+	}
+
+	fn parse_(blob:&[u8]) -> Result<Self, Error> {
+		let mut r = blob.reader(0);
+
+		let algo = r.read_string()?;
+
+		let nonce = r.read_string()?.to_vec();
+
+		let key = match algo {
+			#[cfg(feature = "rs-crypto")]
+			b"ssh-ed25519-cert-v01@openssh.com" => {
+				PublicKey::Ed25519(ed25519_dalek::PublicKey::from_bytes(r.read_string()?)?)
+			},
+			#[cfg(feature = "rs-crypto")]
+			b"sk-ssh-ed25519-cert-v01@openssh.com" => {
+				let public = r.read_string()?;
+
+				let application = utf8(r.read_string()?)?;
+
+				PublicKey::SkEd25519 {
+					key:ed25519_dalek::PublicKey::from_bytes(public)?,
+					application,
+				}
+			},
+			#[cfg(feature = "openssl")]
+			b"ssh-rsa-cert-v01@openssh.com" => {
+				use openssl::{bn::BigNum, pkey::PKey, rsa::Rsa};
+
+				let e = r.read_mpint()?;
+
+				let n = r.read_mpint()?;
+
+				PublicKey::RSA {
+					key:key::OpenSSLPKey(PKey::from_rsa(Rsa::from_public_components(
+						BigNum::from_slice(n)?,
+						BigNum::from_slice(e)?,
+					)?)?),
+					hash:key::SignatureHash::SHA2_512,
+				}
+			},
+			_ => return Err(Error::UnsupportedKeyType(algo.to_vec())),
+		};
+
+		let serial = r.read_u64()?;
+
+		let cert_type = match r.read_u32()? {
+			1 => CertType::User,
+			2 => CertType::Host,
+			_ => return Err(Error::CouldNotReadKey),
+		};
+
+		let key_id = utf8(r.read_string()?)?;
+
+		let mut valid_principals = Vec::new();
+
+		let principals = r.read_string()?;
+
+		let mut p = principals.reader(0);
+
+		while p.position < principals.len() {
+			valid_principals.push(utf8(p.read_string()?)?);
+		}
+
+		let valid_after = r.read_u64()?;
+
+		let valid_before = r.read_u64()?;
+
+		let critical_options = read_options(r.read_string()?)?;
+
+		let extensions = read_options(r.read_string()?)?;
+
+		r.read_string()?; // reserved
+
+		let signature_key = key::parse_public_key(
+			r.read_string()?,
+			#[cfg(feature = "openssl")]
+			None,
+		)?;
+
+		let signed_len = r.position;
+
+		let signature = r.read_string()?.to_vec();
+
+		Ok(Certificate {
+			key,
+			nonce,
+			serial,
+			cert_type,
+			key_id,
+			valid_principals,
+			valid_after,
+			valid_before,
+			critical_options,
+			extensions,
+			signature_key,
+			blob:blob.to_vec(),
+			signed_len,
+			signature,
+		})
+	}
+
+	/// Algorithm name for this certificate. For RSA, this depends on
+	/// the signature hash of the certified key.
+	pub fn name(&self) -> &'static str {
+		match self.key {
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::Ed25519(_) => ED25519_CERT.0,
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => SK_ED25519_CERT.0,
+			#[cfg(feature = "openssl")]
+			PublicKey::RSA { ref hash, .. } => {
+				match hash {
+					key::SignatureHash::SHA2_512 => RSA_SHA2_512_CERT.0,
+					key::SignatureHash::SHA2_256 => RSA_SHA2_256_CERT.0,
+					key::SignatureHash::SHA1 => SSH_RSA_CERT.0,
+				}
+			},
+		}
+	}
+
+	/// Set the signature algorithm of the certified key from a
+	/// certificate algorithm name, such as
+	/// `rsa-sha2-256-cert-v01@openssh.com`.
+	pub fn set_algorithm(&mut self, algorithm:&[u8]) {
+		self.key.set_algorithm(algorithm.strip_suffix(CERT_SUFFIX).unwrap_or(algorithm))
+	}
+
+	/// The value of the `force-command` critical option: the only
+	/// command this certificate allows to run.
+	pub fn force_command(&self) -> Option<&str> {
+		self.critical_options
+			.iter()
+			.find(|(name, _)| name == "force-command")
+			.map(|(_, value)| value.as_str())
+	}
+
+	/// Check that this is a user certificate signed by one of
+	/// `trusted_cas`, currently valid for `principal`, and, if it has a
+	/// `source-address` critical option, used from `source_address`.
+	///
+	/// Unknown critical options are rejected. A `force-command` option
+	/// is accepted, enforcing it is up to the caller (see
+	/// [`Certificate::force_command`]).
+	pub fn validate(
+		&self,
+		trusted_cas:&[PublicKey],
+		principal:&str,
+		source_address:Option<IpAddr>,
+	) -> Result<(), Error> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+		self.validate_at(trusted_cas, principal, source_address, now)
+	}
+
+	pub(crate) fn validate_at(
+		&self,
+		trusted_cas:&[PublicKey],
+		principal:&str,
+		source_address:Option<IpAddr>,
+		now:u64,
+	) -> Result<(), Error> {
+		self.check(CertType::User, trusted_cas, principal, now)?;
+
+		for (name, value) in self.critical_options.iter() {
+			match name.as_str() {
+				"source-address" => {
+					if !source_address.map_or(false, |a| address_list_matches(value, a)) {
+						return Err(Error::CertificateInvalidSourceAddress);
+					}
+				},
+				"force-command" => {},
+				_ => return Err(Error::UnsupportedCriticalOption(name.clone())),
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Check that this is a host certificate signed by one of
+	/// `trusted_cas`, currently valid for `host`.
+	///
+	/// No critical options are defined for host certificates, any
+	/// critical option is rejected.
+	pub fn validate_host(&self, trusted_cas:&[PublicKey], host:&str) -> Result<(), Error> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+		self.validate_host_at(trusted_cas, host, now)
+	}
+
+	pub(crate) fn validate_host_at(
+		&self,
+		trusted_cas:&[PublicKey],
+		host:&str,
+		now:u64,
+	) -> Result<(), Error> {
+		self.check(CertType::Host, trusted_cas, host, now)?;
+
+		match self.critical_options.first() {
+			Some((name, _)) => Err(Error::UnsupportedCriticalOption(name.clone())),
+			None => Ok(()),
+		}
+	}
+
+	/// Checks common to user and host certificates.
+	fn check(
+		&self,
+		cert_type:CertType,
+		trusted_cas:&[PublicKey],
+		principal:&str,
+		now:u64,
+	) -> Result<(), Error> {
+		if self.cert_type != cert_type {
+			return Err(Error::WrongCertificateType);
+		}
+
+		if !self.is_signed_by(trusted_cas) {
+			return Err(Error::UntrustedCertificate);
+		}
+
+		if now < self.valid_after || now >= self.valid_before {
+			return Err(Error::CertificateNotValidNow);
+		}
+
+		if !self.valid_principals.iter().any(|p| p == principal) {
+			return Err(Error::CertificateInvalidPrincipal(principal.to_string()));
+		}
+
+		Ok(())
+	}
+
+	/// Whether this certificate is signed by one of `cas`, with a valid
+	/// signature. This does not check the certificate's type, validity
+	/// period or principals.
+	pub fn is_signed_by(&self, cas:&[PublicKey]) -> bool {
+		cas.contains(&self.signature_key) && self.verify_signature()
+	}
+
+	fn verify_signature(&self) -> bool {
+		let mut r = self.signature.reader(0);
+
+		let (algo, sig) = match (r.read_string(), r.read_string()) {
+			(Ok(algo), Ok(sig)) => (algo, sig),
+			_ => return false,
+		};
+
+		let mut ca = self.signature_key.clone();
+
+		ca.set_algorithm(algo);
+
+		#[allow(clippy::indexing_slicing)] // length checked when parsing
+		ca.verify_detached(&self.blob[..self.signed_len], sig)
+	}
+}
+
+impl PublicKeyBase64 for Certificate {
+	fn public_key_bytes(&self) -> Vec<u8> { self.blob.clone() }
+}
+
+fn utf8(s:&[u8]) -> Result<String, Error> {
+	std::str::from_utf8(s).map(str::to_string).map_err(|_| Error::CouldNotReadKey)
+}
+
+/// Reads critical options or extensions. Their values, if any, are
+/// themselves strings.
+fn read_options(options:&[u8]) -> Result<Vec<(String, String)>, Error> {
+	let mut result = Vec::new();
+
+	let mut r = options.reader(0);
+
+	while r.position < options.len() {
+		let name = utf8(r.read_string()?)?;
+
+		let data = r.read_string()?;
+
+		let value =
+			if data.is_empty() { String::new() } else { utf8(data.reader(0).read_string()?)? };
+
+		result.push((name, value));
+	}
+
+	Ok(result)
+}
+
+/// Whether `address` is in a comma-separated list of addresses and
+/// CIDR networks, as in the `source-address` critical option.
+fn address_list_matches(list:&str, address:IpAddr) -> bool {
+	list.split(',').any(|cidr| {
+		let (network, bits) = match cidr.split_once('/') {
+			Some((network, bits)) => {
+				match bits.parse() {
+					Ok(bits) => (network, Some(bits)),
+					Err(_) => return false,
+				}
+			},
+			None => (cidr, None),
+		};
+
+		match (network.parse(), address) {
+			(Ok(IpAddr::V4(network)), IpAddr::V4(address)) => {
+				prefix_matches(&network.octets(), &address.octets(), bits.unwrap_or(32))
+			},
+			(Ok(IpAddr::V6(network)), IpAddr::V6(address)) => {
+				prefix_matches(&network.octets(), &address.octets(), bits.unwrap_or(128))
+			},
+			_ => false,
+		}
+	})
+}
+
+fn prefix_matches(network:&[u8], address:&[u8], bits:u32) -> bool {
+	if bits as usize > network.len() * 8 {
+		return false;
+	}
+
+	network.iter().zip(address).enumerate().all(|(i, (n, a))| {
+		let bits = bits.saturating_sub(i as u32 * 8).min(8);
+
+		let mask = if bits == 0 { 0 } else { 0xffu8 << (8 - bits) };
+
+		(n ^ a) & mask == 0
+	})
+}
@@ -205,6 +205,9 @@ pub struct Position<'a> {
 	pub position:usize,
 }
 impl<'a> Position<'a> {
+	/// Whether everything has been read.
+	pub fn is_at_end(&self) -> bool { self.position >= self.s.len() }
+
 	/// Read one string from this reader.
 	pub fn read_string(&mut self) -> Result<&'a [u8], Error> {
 		let len = self.read_u32()? as usize;
@@ -235,6 +238,20 @@ impl<'a> Position<'a> {
 		}
 	}
 
+	/// Read a `u64` from this reader.
+	pub fn read_u64(&mut self) -> Result<u64, Error> {
+		if self.position + 8 <= self.s.len() {
+			#[allow(clippy::indexing_slicing)] // length is known
+			let u = BigEndian::read_u64(&self.s[self.position..]);
+
+			self.position += 8;
+
+			Ok(u)
+		} else {
+			Err(Error::IndexOutOfBounds)
+		}
+	}
+
 	/// Read one byte from this reader.
 	pub fn read_byte(&mut self) -> Result<u8, Error> {
 		if self.position < self.s.len() {
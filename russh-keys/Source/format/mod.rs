@@ -20,7 +20,12 @@ pub use self::pkcs5::*;
 
 pub mod pkcs8;
 
-const AES_128_CBC:&str = "DEK-Info: AES-128-CBC,";
+#[cfg(feature = "rs-crypto")]
+pub mod ppk;
+#[cfg(feature = "rs-crypto")]
+pub use self::ppk::*;
+
+const DEK_INFO:&str = "DEK-Info: ";
 
 #[derive(Clone, Copy, Debug)]
 /// AES encryption key.
@@ -43,8 +48,13 @@ enum Format {
 }
 
 /// Decode a secret key, possibly deciphering it with the supplied
-/// password.
+/// password. PuTTY `.ppk` files are also accepted, see `decode_ppk`.
 pub fn decode_secret_key(secret:&str, password:Option<&str>) -> Result<key::KeyPair, Error> {
+	#[cfg(feature = "rs-crypto")]
+	if secret.starts_with("PuTTY-User-Key-File-") {
+		return decode_ppk(secret.as_bytes(), password);
+	}
+
 	let mut format = None;
 
 	let secret = {
@@ -60,21 +70,10 @@ pub fn decode_secret_key(secret:&str, password:Option<&str>) -> Result<key::KeyP
 
 				if l.chars().all(is_base64_char) {
 					sec.push_str(l)
-				} else if l.starts_with(AES_128_CBC) {
+				} else if let Some(_dek_info) = l.strip_prefix(DEK_INFO) {
 					#[cfg(feature = "openssl")]
 					{
-						let iv_:Vec<u8> = HEXLOWER_PERMISSIVE
-							.decode(l.split_at(AES_128_CBC.len()).1.as_bytes())?;
-
-						if iv_.len() != 16 {
-							return Err(Error::CouldNotReadKey);
-						}
-
-						let mut iv = [0; 16];
-
-						iv.clone_from_slice(&iv_);
-
-						format = Some(Format::Pkcs5Encrypted(Encryption::Aes128Cbc(iv)))
+						format = Some(Format::Pkcs5Encrypted(parse_dek_info(_dek_info)?))
 					}
 				}
 			}
@@ -116,9 +115,12 @@ pub fn decode_secret_key(secret:&str, password:Option<&str>) -> Result<key::KeyP
 		Some(Format::Rsa) => decode_rsa(&secret),
 		#[cfg(feature = "openssl")]
 		Some(Format::Pkcs5Encrypted(enc)) => decode_pkcs5(&secret, password, enc),
-		Some(Format::Pkcs8Encrypted) | Some(Format::Pkcs8) => {
-			self::pkcs8::decode_pkcs8(&secret, password.map(|x| x.as_bytes()))
+		Some(Format::Pkcs8Encrypted) => {
+			let password = password.ok_or(Error::KeyIsEncrypted)?;
+
+			self::pkcs8::decode_pkcs8(&secret, Some(password.as_bytes()))
 		},
+		Some(Format::Pkcs8) => self::pkcs8::decode_pkcs8(&secret, None),
 		None => Err(Error::CouldNotReadKey),
 	}
 }
@@ -152,6 +154,31 @@ pub fn encode_pkcs8_pem_encrypted<W:Write>(
 	Ok(())
 }
 
+/// Parse the cipher and IV of a traditional PEM encrypted key, from
+/// `DEK-Info: <cipher>,<hex IV>`.
+#[cfg(feature = "openssl")]
+fn parse_dek_info(dek_info:&str) -> Result<Encryption, Error> {
+	let (cipher, iv_) = dek_info.split_once(',').ok_or(Error::CouldNotReadKey)?;
+
+	let encryption = match cipher {
+		"AES-128-CBC" => Encryption::Aes128Cbc,
+		"AES-256-CBC" => Encryption::Aes256Cbc,
+		_ => return Err(Error::UnsupportedCipher(cipher.to_string())),
+	};
+
+	let iv_:Vec<u8> = HEXLOWER_PERMISSIVE.decode(iv_.trim().as_bytes())?;
+
+	if iv_.len() != 16 {
+		return Err(Error::CouldNotReadKey);
+	}
+
+	let mut iv = [0; 16];
+
+	iv.clone_from_slice(&iv_);
+
+	Ok(encryption(iv))
+}
+
 #[cfg(feature = "openssl")]
 fn decode_rsa(secret:&[u8]) -> Result<key::KeyPair, Error> {
 	Ok(key::KeyPair::RSA {
@@ -159,3 +186,56 @@ fn decode_rsa(secret:&[u8]) -> Result<key::KeyPair, Error> {
 		hash:key::SignatureHash::SHA2_256,
 	})
 }
+
+/// Build an RSA key pair from the big-endian components found in private
+/// key files.
+#[cfg(feature = "openssl")]
+fn rsa_key_pair(
+	n:&[u8],
+	e:&[u8],
+	d:&[u8],
+	iqmp:&[u8],
+	p:&[u8],
+	q:&[u8],
+) -> Result<key::KeyPair, Error> {
+	use openssl::bn::BigNum;
+
+	let n = BigNum::from_slice(n)?;
+
+	let e = BigNum::from_slice(e)?;
+
+	let d = BigNum::from_slice(d)?;
+
+	let iqmp = BigNum::from_slice(iqmp)?;
+
+	let p = BigNum::from_slice(p)?;
+
+	let q = BigNum::from_slice(q)?;
+
+	let mut ctx = openssl::bn::BigNumContext::new()?;
+
+	let un = openssl::bn::BigNum::from_u32(1)?;
+
+	let mut p1 = openssl::bn::BigNum::new()?;
+
+	let mut q1 = openssl::bn::BigNum::new()?;
+
+	p1.checked_sub(&p, &un)?;
+
+	q1.checked_sub(&q, &un)?;
+
+	let mut dmp1 = openssl::bn::BigNum::new()?; // d mod p-1
+	dmp1.checked_rem(&d, &p1, &mut ctx)?;
+
+	let mut dmq1 = openssl::bn::BigNum::new()?; // d mod q-1
+	dmq1.checked_rem(&d, &q1, &mut ctx)?;
+
+	let key = openssl::rsa::RsaPrivateKeyBuilder::new(n, e, d)?
+		.set_factors(p, q)?
+		.set_crt_params(dmp1, dmq1, iqmp)?
+		.build();
+
+	key.check_key()?;
+
+	Ok(key::KeyPair::RSA { key, hash:key::SignatureHash::SHA2_512 })
+}
@@ -12,24 +12,43 @@ pub fn decode_pkcs5(
 	use openssl::symm::{Cipher, decrypt};
 
 	if let Some(pass) = password {
-		let sec = match enc {
-			Encryption::Aes128Cbc(ref iv) => {
-				let mut c = md5::Context::new();
-
-				c.consume(pass.as_bytes());
-
-				c.consume(&iv[..8]);
+		let (cipher, iv) = match enc {
+			Encryption::Aes128Cbc(ref iv) => (Cipher::aes_128_cbc(), iv),
+			Encryption::Aes256Cbc(ref iv) => (Cipher::aes_256_cbc(), iv),
+		};
 
-				let md5 = c.compute();
+		let key = bytes_to_key(pass.as_bytes(), &iv[..8], cipher.key_len());
 
-				#[allow(clippy::unwrap_used)] // AES parameters are static
-				decrypt(Cipher::aes_128_cbc(), &md5.0, Some(&iv[..]), secret)?
-			},
-			Encryption::Aes256Cbc(_) => unimplemented!(),
-		};
+		// With CBC, a wrong key is noticed when unpadding, or possibly
+		// when decoding the result.
+		let sec = decrypt(cipher, &key, Some(&iv[..]), secret)
+			.map_err(|_| Error::WrongPassphrase)?;
 
-		super::decode_rsa(&sec)
+		super::decode_rsa(&sec).map_err(|_| Error::WrongPassphrase)
 	} else {
 		Err(Error::KeyIsEncrypted)
 	}
 }
+
+/// OpenSSL's `EVP_BytesToKey`, with MD5 and one iteration.
+#[cfg(feature = "openssl")]
+fn bytes_to_key(pass:&[u8], salt:&[u8], len:usize) -> Vec<u8> {
+	let mut key:Vec<u8> = Vec::with_capacity(len + 16);
+
+	while key.len() < len {
+		let mut c = md5::Context::new();
+
+		#[allow(clippy::indexing_slicing)] // key.len() is a multiple of 16
+		c.consume(&key[key.len().saturating_sub(16)..]);
+
+		c.consume(pass);
+
+		c.consume(salt);
+
+		key.extend_from_slice(&c.compute().0);
+	}
+
+	key.truncate(len);
+
+	key
+}
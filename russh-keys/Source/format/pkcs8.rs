@@ -15,13 +15,18 @@ use crate::{Error, key};
 
 const PBES2:&[u64] = &[1, 2, 840, 113549, 1, 5, 13];
 const PBKDF2:&[u64] = &[1, 2, 840, 113549, 1, 5, 12];
+const SCRYPT:&[u64] = &[1, 3, 6, 1, 4, 1, 11591, 4, 11];
+const HMAC_SHA1:&[u64] = &[1, 2, 840, 113549, 2, 7];
 const HMAC_SHA256:&[u64] = &[1, 2, 840, 113549, 2, 9];
+const AES128CBC:&[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 2];
 const AES256CBC:&[u64] = &[2, 16, 840, 1, 101, 3, 4, 1, 42];
 const ED25519:&[u64] = &[1, 3, 101, 112];
 #[cfg(feature = "openssl")]
 const RSA:&[u64] = &[1, 2, 840, 113549, 1, 1, 1];
 
-/// Decode a PKCS#8-encoded private key.
+/// Decode a PKCS#8-encoded private key. Encrypted keys (PBES2, with
+/// PBKDF2 or scrypt, and AES-128-CBC or AES-256-CBC) are decrypted with
+/// `password`.
 pub fn decode_pkcs8(ciphertext:&[u8], password:Option<&[u8]>) -> Result<key::KeyPair, Error> {
 	let secret = if let Some(pass) = password {
 		Cow::Owned(yasna::parse_der(ciphertext, |reader| {
@@ -46,7 +51,7 @@ pub fn decode_pkcs8(ciphertext:&[u8], password:Option<&[u8]>) -> Result<key::Key
 		Cow::Borrowed(ciphertext)
 	};
 
-	yasna::parse_der(&secret, |reader| {
+	let key = yasna::parse_der(&secret, |reader| {
 		reader.read_sequence(|reader| {
 			let version = reader.next().read_u64()?;
 
@@ -58,7 +63,14 @@ pub fn decode_pkcs8(ciphertext:&[u8], password:Option<&[u8]>) -> Result<key::Key
 				Ok(Err(Error::CouldNotReadKey))
 			}
 		})
-	})?
+	});
+
+	match key {
+		Ok(key) => key,
+		// Garbage after decryption: the padding happened to be valid.
+		Err(_) if password.is_some() => Err(Error::WrongPassphrase),
+		Err(e) => Err(e.into()),
+	}
 }
 
 fn asn1_read_pbes2(
@@ -72,6 +84,8 @@ fn asn1_read_pbes2(
 
 			if oid.components().as_slice() == PBKDF2 {
 				asn1_read_pbkdf2(reader)
+			} else if oid.components().as_slice() == SCRYPT {
+				asn1_read_scrypt(reader)
 			} else {
 				Ok(Err(Error::UnknownAlgorithm(oid)))
 			}
@@ -80,8 +94,10 @@ fn asn1_read_pbes2(
 		let algorithm = reader.next().read_sequence(|reader| {
 			let oid = reader.next().read_oid()?;
 
-			if oid.components().as_slice() == AES256CBC {
-				asn1_read_aes256cbc(reader)
+			if oid.components().as_slice() == AES128CBC {
+				Ok(asn1_read_iv(reader)?.map(Encryption::Aes128Cbc))
+			} else if oid.components().as_slice() == AES256CBC {
+				Ok(asn1_read_iv(reader)?.map(Encryption::Aes256Cbc))
 			} else {
 				Ok(Err(Error::UnknownAlgorithm(oid)))
 			}
@@ -99,32 +115,67 @@ fn asn1_read_pbkdf2(
 
 		let rounds = reader.next().read_u64()?;
 
-		let digest = reader.next().read_sequence(|reader| {
-			let oid = reader.next().read_oid()?;
+		let _key_length = reader.read_optional(|reader| reader.read_u64())?;
 
-			if oid.components().as_slice() == HMAC_SHA256 {
-				reader.next().read_null()?;
+		// The PRF defaults to HMAC-SHA1.
+		let prf = reader.read_optional(|reader| {
+			reader.read_sequence(|reader| {
+				let oid = reader.next().read_oid()?;
 
-				Ok(Ok(()))
-			} else {
-				Ok(Err(Error::UnknownAlgorithm(oid)))
-			}
+				reader.read_optional(|reader| reader.read_null())?;
+
+				if oid.components().as_slice() == HMAC_SHA1 {
+					Ok(Ok(Prf::HmacSha1))
+				} else if oid.components().as_slice() == HMAC_SHA256 {
+					Ok(Ok(Prf::HmacSha256))
+				} else {
+					Ok(Err(Error::UnknownAlgorithm(oid)))
+				}
+			})
 		})?;
 
-		Ok(digest.map(|()| KeyDerivation::Pbkdf2 { salt, rounds }))
+		Ok(prf
+			.unwrap_or(Ok(Prf::HmacSha1))
+			.map(|prf| KeyDerivation::Pbkdf2 { salt, rounds, prf }))
+	})
+}
+
+fn asn1_read_scrypt(
+	reader:&mut yasna::BERReaderSeq,
+) -> Result<Result<KeyDerivation, Error>, yasna::ASN1Error> {
+	reader.next().read_sequence(|reader| {
+		let salt = reader.next().read_bytes()?;
+
+		let n = reader.next().read_u64()?;
+
+		let r = reader.next().read_u32()?;
+
+		let p = reader.next().read_u32()?;
+
+		let _key_length = reader.read_optional(|reader| reader.read_u64())?;
+
+		if !n.is_power_of_two() {
+			return Ok(Err(Error::KeyIsCorrupt));
+		}
+
+		Ok(Ok(KeyDerivation::Scrypt { salt, log_n:n.trailing_zeros() as u8, r, p }))
 	})
 }
 
-fn asn1_read_aes256cbc(
+fn asn1_read_iv(
 	reader:&mut yasna::BERReaderSeq,
-) -> Result<Result<Encryption, Error>, yasna::ASN1Error> {
+) -> Result<Result<[u8; 16], Error>, yasna::ASN1Error> {
 	let iv = reader.next().read_bytes()?;
 
 	let mut i = [0; 16];
 
+	if iv.len() != i.len() {
+		return Ok(Err(Error::KeyIsCorrupt));
+	}
+
 	i.clone_from_slice(&iv);
 
-	Ok(Ok(Encryption::Aes256Cbc(i)))
+	Ok(Ok(i))
 }
 
 #[cfg(feature = "rs-crypto")]
@@ -156,16 +207,9 @@ fn read_key_v1(reader:&mut BERReaderSeq) -> Result<key::KeyPair, Error> {
 	if oid.components().as_slice() == ED25519 && cfg!(feature = "rs-crypto") {
 		#[cfg(feature = "rs-crypto")]
 		{
-			use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+			use ed25519_dalek::{Keypair, PublicKey};
 
-			let secret = {
-				let s =
-					yasna::parse_der(&reader.next().read_bytes()?, |reader| reader.read_bytes())?;
-
-				s.get(..ed25519_dalek::SECRET_KEY_LENGTH)
-					.ok_or(Error::KeyIsCorrupt)
-					.and_then(|s| SecretKey::from_bytes(s).map_err(|_| Error::CouldNotReadKey))?
-			};
+			let secret = read_ed25519_secret(reader)?;
 
 			let public = {
 				let public = reader
@@ -183,6 +227,15 @@ fn read_key_v1(reader:&mut BERReaderSeq) -> Result<key::KeyPair, Error> {
 	Err(Error::CouldNotReadKey)
 }
 
+#[cfg(feature = "rs-crypto")]
+fn read_ed25519_secret(reader:&mut BERReaderSeq) -> Result<ed25519_dalek::SecretKey, Error> {
+	let s = yasna::parse_der(&reader.next().read_bytes()?, |reader| reader.read_bytes())?;
+
+	s.get(..ed25519_dalek::SECRET_KEY_LENGTH)
+		.ok_or(Error::KeyIsCorrupt)
+		.and_then(|s| ed25519_dalek::SecretKey::from_bytes(s).map_err(|_| Error::CouldNotReadKey))
+}
+
 #[cfg(feature = "openssl")]
 fn write_key_v0(writer:&mut yasna::DERWriterSeq, key:&Rsa<Private>) {
 	writer.next().write_u32(0);
@@ -227,16 +280,34 @@ fn write_key_v0(writer:&mut yasna::DERWriterSeq, key:&Rsa<Private>) {
 	writer.next().write_bytes(&bytes);
 }
 
-#[cfg(feature = "openssl")]
 fn read_key_v0(reader:&mut BERReaderSeq) -> Result<key::KeyPair, Error> {
 	let oid = reader.next().read_sequence(|reader| {
 		let oid = reader.next().read_oid()?;
 
-		reader.next().read_null()?;
+		// NULL for RSA, absent for Ed25519.
+		let _parameters = reader.read_optional(|reader| reader.read_der())?;
 
 		Ok(oid)
 	})?;
 
+	if oid.components().as_slice() == ED25519 && cfg!(feature = "rs-crypto") {
+		#[cfg(feature = "rs-crypto")]
+		{
+			use ed25519_dalek::{Keypair, PublicKey};
+
+			let secret = read_ed25519_secret(reader)?;
+
+			let public = PublicKey::from(&secret);
+
+			return Ok(key::KeyPair::Ed25519(Keypair { public, secret }));
+		}
+	}
+
+	read_rsa_v0(reader, oid)
+}
+
+#[cfg(feature = "openssl")]
+fn read_rsa_v0(reader:&mut BERReaderSeq, oid:ObjectIdentifier) -> Result<key::KeyPair, Error> {
 	if oid.components().as_slice() == RSA {
 		let seq = &reader.next().read_bytes()?;
 
@@ -269,12 +340,14 @@ fn read_key_v0(reader:&mut BERReaderSeq) -> Result<key::KeyPair, Error> {
 
 		Ok(key::KeyPair::RSA { key:rsa?, hash:SignatureHash::SHA2_256 })
 	} else {
-		Err(Error::CouldNotReadKey)
+		Err(Error::UnknownAlgorithm(oid))
 	}
 }
 
 #[cfg(not(feature = "openssl"))]
-fn read_key_v0(_:&mut BERReaderSeq) -> Result<key::KeyPair, Error> { Err(Error::CouldNotReadKey) }
+fn read_rsa_v0(_:&mut BERReaderSeq, oid:ObjectIdentifier) -> Result<key::KeyPair, Error> {
+	Err(Error::UnknownAlgorithm(oid))
+}
 
 #[test]
 #[allow(clippy::unwrap_used)]
@@ -304,21 +377,46 @@ fn test_read_write_pkcs8() {
 use yasna::models::ObjectIdentifier;
 
 #[cfg(feature = "rs-crypto")]
-fn pbkdf2(password:&[u8], salt:&[u8], rounds:u32, key:&mut [u8]) -> Result<(), Error> {
-	pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(password, salt, rounds, key);
+fn pbkdf2(password:&[u8], salt:&[u8], rounds:u32, prf:Prf, key:&mut [u8]) -> Result<(), Error> {
+	match prf {
+		Prf::HmacSha1 => pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(password, salt, rounds, key),
+		Prf::HmacSha256 => {
+			pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(password, salt, rounds, key)
+		},
+	}
+
+	Ok(())
+}
+
+#[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
+fn pbkdf2(password:&[u8], salt:&[u8], rounds:u32, prf:Prf, key:&mut [u8]) -> Result<(), Error> {
+	use openssl::hash::MessageDigest;
+
+	let digest = match prf {
+		Prf::HmacSha1 => MessageDigest::sha1(),
+		Prf::HmacSha256 => MessageDigest::sha256(),
+	};
+
+	openssl::pkcs5::pbkdf2_hmac(password, salt, rounds as usize, digest, key)?;
 
 	Ok(())
 }
 
+#[cfg(feature = "rs-crypto")]
+fn scrypt(password:&[u8], salt:&[u8], log_n:u8, r:u32, p:u32, key:&mut [u8]) -> Result<(), Error> {
+	let params = scrypt::Params::new(log_n, r, p).map_err(|_| Error::KeyIsCorrupt)?;
+
+	scrypt::scrypt(password, salt, &params, key).map_err(|_| Error::KeyIsCorrupt)
+}
+
 #[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
-fn pbkdf2(password:&[u8], salt:&[u8], rounds:u32, key:&mut [u8]) -> Result<(), Error> {
-	openssl::pkcs5::pbkdf2_hmac(
-		password,
-		salt,
-		rounds as usize,
-		openssl::hash::MessageDigest::sha256(),
-		key,
-	)?;
+fn scrypt(password:&[u8], salt:&[u8], log_n:u8, r:u32, p:u32, key:&mut [u8]) -> Result<(), Error> {
+	let n = 1u64 << log_n;
+
+	// Memory needed by scrypt, with some margin.
+	let max_mem = 256 * n * u64::from(r) * u64::from(p);
+
+	openssl::pkcs5::scrypt(password, salt, n, r.into(), p.into(), max_mem, key)?;
 
 	Ok(())
 }
@@ -375,7 +473,7 @@ pub fn encode_pkcs8_encrypted(pass:&[u8], rounds:u32, key:&key::KeyPair) -> Resu
 	rng.fill_bytes(&mut iv);
 
 	let mut dkey = [0; 32]; // AES256-CBC
-	pbkdf2(pass, &salt, rounds, &mut dkey)?;
+	pbkdf2(pass, &salt, rounds, Prf::HmacSha256, &mut dkey)?;
 
 	let mut plaintext = encode_pkcs8(key);
 
@@ -456,9 +554,8 @@ impl Algorithms {
 
 				der.derive(password, &mut key)?;
 
-				let out = enc.decrypt(&key, cipher)?;
-
-				Ok(out)
+				// With CBC, a wrong key is noticed when unpadding.
+				enc.decrypt(&key, cipher).map_err(|_| Error::WrongPassphrase)
 			},
 		}
 	}
@@ -467,10 +564,13 @@ impl Algorithms {
 impl KeyDerivation {
 	fn derive(&self, password:&[u8], key:&mut [u8]) -> Result<(), Error> {
 		match *self {
-			KeyDerivation::Pbkdf2 { ref salt, rounds } => {
-				pbkdf2(password, salt, rounds as u32, key)?
+			KeyDerivation::Pbkdf2 { ref salt, rounds, prf } => {
+				pbkdf2(password, salt, rounds as u32, prf, key)?
 				// pbkdf2_hmac(password, salt, rounds as usize, digest, key)?
 			},
+			KeyDerivation::Scrypt { ref salt, log_n, r, p } => {
+				scrypt(password, salt, log_n, r, p, key)?
+			},
 		}
 
 		Ok(())
@@ -555,5 +655,13 @@ impl Encryption {
 }
 
 enum KeyDerivation {
-	Pbkdf2 { salt:Vec<u8>, rounds:u64 },
+	Pbkdf2 { salt:Vec<u8>, rounds:u64, prf:Prf },
+	Scrypt { salt:Vec<u8>, log_n:u8, r:u32, p:u32 },
+}
+
+/// Pseudorandom function of PBKDF2.
+#[derive(Clone, Copy)]
+enum Prf {
+	HmacSha1,
+	HmacSha256,
 }
@@ -0,0 +1,273 @@
+//! PuTTY private key files (`.ppk`), versions 2 and 3, as described in
+//! appendix C of the PuTTY manual.
+
+use std::io::BufRead;
+
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::NoPadding};
+use data_encoding::{BASE64_MIME, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::{
+	Error,
+	KEYTYPE_ED25519,
+	KEYTYPE_RSA,
+	encoding::{Encoding, Reader},
+	key,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Version {
+	V2,
+	V3,
+}
+
+/// Decode a PuTTY private key file (`.ppk`, versions 2 and 3),
+/// deciphering it if needed using the supplied passphrase.
+///
+/// The file's MAC is checked before the key is decoded: for an encrypted
+/// file, a mismatch means the passphrase is wrong
+/// ([`Error::WrongPassphrase`]), for an unencrypted file that it is
+/// corrupt ([`Error::KeyIsCorrupt`]).
+pub fn decode_ppk<R:BufRead>(reader:R, passphrase:Option<&str>) -> Result<key::KeyPair, Error> {
+	let lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
+
+	let mut lines = lines.iter().map(|l| l.trim_end());
+
+	let (version, algorithm) = lines
+		.next()
+		.and_then(|l| l.strip_prefix("PuTTY-User-Key-File-"))
+		.and_then(|l| l.split_once(": "))
+		.ok_or(Error::CouldNotReadKey)?;
+
+	let version = match version {
+		"2" => Version::V2,
+		"3" => Version::V3,
+		_ => return Err(Error::CouldNotReadKey),
+	};
+
+	let encryption = header(&mut lines, "Encryption")?;
+
+	let comment = header(&mut lines, "Comment")?;
+
+	let public = base64_lines(&mut lines, "Public-Lines")?;
+
+	let encrypted = match encryption {
+		"none" => false,
+		"aes256-cbc" => true,
+		_ => return Err(Error::CouldNotReadKey),
+	};
+
+	let passphrase = match passphrase {
+		Some(passphrase) if encrypted => passphrase,
+		None if encrypted => return Err(Error::KeyIsEncrypted),
+		_ => "",
+	};
+
+	// Cipher key, IV and MAC key.
+	let mut keys = [0; 80];
+
+	let mac_key_len = match version {
+		Version::V2 => {
+			let mut h = Sha1::new();
+
+			h.update(b"putty-private-key-file-mac-key");
+
+			h.update(passphrase);
+
+			#[allow(clippy::indexing_slicing)] // static lengths
+			keys[48..68].clone_from_slice(&h.finalize());
+
+			if encrypted {
+				for i in 0..2u8 {
+					let mut h = Sha1::new();
+
+					h.update([0, 0, 0, i]);
+
+					h.update(passphrase);
+
+					let start = usize::from(i) * 20;
+
+					let end = (start + 20).min(32);
+
+					#[allow(clippy::indexing_slicing)] // static lengths
+					keys[start..end].clone_from_slice(&h.finalize()[..end - start]);
+				}
+			}
+
+			20
+		},
+		Version::V3 if encrypted => {
+			argon2(&mut lines, passphrase, &mut keys)?;
+
+			32
+		},
+		Version::V3 => 0,
+	};
+
+	let mut private = base64_lines(&mut lines, "Private-Lines")?;
+
+	let mac = HEXLOWER_PERMISSIVE.decode(header(&mut lines, "Private-MAC")?.as_bytes())?;
+
+	#[allow(clippy::indexing_slicing)] // static lengths
+	let (cipher_key, iv, mac_key) = (&keys[..32], &keys[32..48], &keys[48..48 + mac_key_len]);
+
+	if encrypted {
+		if private.len() % 16 != 0 {
+			return Err(Error::KeyIsCorrupt);
+		}
+
+		#[allow(clippy::unwrap_used)] // parameters are static
+		let cipher = cbc::Decryptor::<aes::Aes256>::new_from_slices(cipher_key, iv).unwrap();
+
+		cipher.decrypt_padded_mut::<NoPadding>(&mut private)?;
+	}
+
+	let mut mac_data = Vec::new();
+
+	mac_data.extend_ssh_string(algorithm.as_bytes());
+
+	mac_data.extend_ssh_string(encryption.as_bytes());
+
+	mac_data.extend_ssh_string(comment.as_bytes());
+
+	mac_data.extend_ssh_string(&public);
+
+	mac_data.extend_ssh_string(&private);
+
+	let mac_ok = match version {
+		Version::V2 => verify_mac::<Hmac<Sha1>>(mac_key, &mac_data, &mac),
+		Version::V3 => verify_mac::<Hmac<Sha256>>(mac_key, &mac_data, &mac),
+	};
+
+	if !mac_ok {
+		return Err(if encrypted { Error::WrongPassphrase } else { Error::KeyIsCorrupt });
+	}
+
+	decode_key(algorithm.as_bytes(), &public, &private)
+}
+
+/// Read a `Name: value` line.
+fn header<'a, I:Iterator<Item = &'a str>>(lines:&mut I, name:&str) -> Result<&'a str, Error> {
+	lines
+		.next()
+		.and_then(|l| l.split_once(": "))
+		.and_then(|(n, value)| if n == name { Some(value) } else { None })
+		.ok_or(Error::CouldNotReadKey)
+}
+
+/// Read a `Name: n` line followed by `n` lines of base64.
+fn base64_lines<'a, I:Iterator<Item = &'a str>>(
+	lines:&mut I,
+	name:&str,
+) -> Result<Vec<u8>, Error> {
+	let n:usize = header(lines, name)?.parse().map_err(|_| Error::CouldNotReadKey)?;
+
+	let mut data = String::new();
+
+	for _ in 0..n {
+		data.push_str(lines.next().ok_or(Error::CouldNotReadKey)?);
+	}
+
+	Ok(BASE64_MIME.decode(data.as_bytes())?)
+}
+
+/// Derive the cipher key, IV and MAC key of a version 3 file.
+fn argon2<'a, I:Iterator<Item = &'a str>>(
+	lines:&mut I,
+	passphrase:&str,
+	keys:&mut [u8; 80],
+) -> Result<(), Error> {
+	let algorithm = match header(lines, "Key-Derivation")? {
+		"Argon2d" => argon2::Algorithm::Argon2d,
+		"Argon2i" => argon2::Algorithm::Argon2i,
+		"Argon2id" => argon2::Algorithm::Argon2id,
+		_ => return Err(Error::CouldNotReadKey),
+	};
+
+	let mut param = |name:&str| {
+		header(lines, name)?.parse::<u32>().map_err(|_| Error::CouldNotReadKey)
+	};
+
+	let memory = param("Argon2-Memory")?;
+
+	let passes = param("Argon2-Passes")?;
+
+	let parallelism = param("Argon2-Parallelism")?;
+
+	let salt = HEXLOWER_PERMISSIVE.decode(header(lines, "Argon2-Salt")?.as_bytes())?;
+
+	let params = argon2::Params::new(memory, passes, parallelism, Some(keys.len()))
+		.map_err(|_| Error::KeyIsCorrupt)?;
+
+	argon2::Argon2::new(algorithm, argon2::Version::V0x13, params)
+		.hash_password_into(passphrase.as_bytes(), &salt, keys)
+		.map_err(|_| Error::KeyIsCorrupt)
+}
+
+fn verify_mac<M:Mac + hmac::digest::KeyInit>(key:&[u8], data:&[u8], mac:&[u8]) -> bool {
+	match <M as hmac::digest::KeyInit>::new_from_slice(key) {
+		Ok(mut m) => {
+			m.update(data);
+
+			m.verify_slice(mac).is_ok()
+		},
+		Err(_) => false,
+	}
+}
+
+fn decode_key(algorithm:&[u8], public:&[u8], private:&[u8]) -> Result<key::KeyPair, Error> {
+	let mut public = public.reader(0);
+
+	if public.read_string()? != algorithm {
+		return Err(Error::KeyIsCorrupt);
+	}
+
+	let mut private = private.reader(0);
+
+	if algorithm == KEYTYPE_ED25519 {
+		let pubkey = public.read_string()?;
+
+		// The secret is stored as a little-endian integer, without its
+		// trailing zeros.
+		let seckey = private.read_string()?;
+
+		let mut secret = [0; ed25519_dalek::SECRET_KEY_LENGTH];
+
+		secret.get_mut(..seckey.len()).ok_or(Error::KeyIsCorrupt)?.clone_from_slice(seckey);
+
+		let secret = ed25519_dalek::SecretKey::from_bytes(&secret)?;
+
+		let public:ed25519_dalek::PublicKey = (&secret).into();
+
+		if public.as_bytes() != pubkey {
+			return Err(Error::KeyIsCorrupt);
+		}
+
+		Ok(key::KeyPair::Ed25519(ed25519_dalek::Keypair { secret, public }))
+	} else if algorithm == KEYTYPE_RSA && cfg!(feature = "openssl") {
+		#[cfg(feature = "openssl")]
+		{
+			let e = public.read_string()?;
+
+			let n = public.read_string()?;
+
+			let d = private.read_string()?;
+
+			let p = private.read_string()?;
+
+			let q = private.read_string()?;
+
+			let iqmp = private.read_string()?;
+
+			super::rsa_key_pair(n, e, d, iqmp, p, q)
+		}
+		#[cfg(not(feature = "openssl"))]
+		{
+			unreachable!()
+		}
+	} else {
+		Err(Error::UnsupportedKeyType(algorithm.to_vec()))
+	}
+}
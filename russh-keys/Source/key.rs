@@ -33,6 +33,8 @@ impl AsRef<str> for Name {
 
 /// The name of the Ed25519 algorithm for SSH.
 pub const ED25519:Name = Name("ssh-ed25519");
+/// The name of the Ed25519 algorithm for FIDO security keys.
+pub const SK_ED25519:Name = Name("sk-ssh-ed25519@openssh.com");
 /// The name of the ssh-sha2-512 algorithm for SSH.
 pub const RSA_SHA2_512:Name = Name("rsa-sha2-512");
 /// The name of the ssh-sha2-256 algorithm for SSH.
@@ -42,6 +44,9 @@ pub const NONE:Name = Name("none");
 
 pub const SSH_RSA:Name = Name("ssh-rsa");
 
+/// Flag set in security key signatures if the user touched the key.
+pub const SK_USER_PRESENT:u8 = 0x01;
+
 impl Name {
 	/// Base name of the private key file for a key name.
 	pub fn identity_file(&self) -> &'static str {
@@ -104,12 +109,200 @@ impl SignatureHash {
 	}
 }
 
+/// The hash function of a key fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintHash {
+	/// The default of OpenSSH.
+	Sha256,
+	Sha512,
+	/// Legacy fingerprints, printed in hexadecimal.
+	Md5,
+}
+
+impl FingerprintHash {
+	fn name(self) -> &'static str {
+		match self {
+			FingerprintHash::Sha256 => "SHA256",
+			FingerprintHash::Sha512 => "SHA512",
+			FingerprintHash::Md5 => "MD5",
+		}
+	}
+
+	fn digest(self, data:&[u8]) -> Vec<u8> {
+		use sha2::{Digest, Sha256, Sha512};
+
+		match self {
+			FingerprintHash::Sha256 => Sha256::digest(data).to_vec(),
+			FingerprintHash::Sha512 => Sha512::digest(data).to_vec(),
+			FingerprintHash::Md5 => md5::compute(data).to_vec(),
+		}
+	}
+}
+
+/// A key fingerprint, which can be parsed from and printed to the format
+/// of `ssh-keygen -l`, for instance to pin a host key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+	pub hash:FingerprintHash,
+	/// The hash of the key's wire encoding.
+	pub digest:Vec<u8>,
+}
+
+impl Fingerprint {
+	/// The fingerprint of a key.
+	pub fn of(key:&PublicKey, hash:FingerprintHash) -> Self {
+		use super::PublicKeyBase64;
+
+		Fingerprint { hash, digest:hash.digest(&key.public_key_bytes()) }
+	}
+
+	/// Whether this is the fingerprint of `key`.
+	pub fn matches(&self, key:&PublicKey) -> bool { *self == Fingerprint::of(key, self.hash) }
+}
+
+impl std::str::FromStr for Fingerprint {
+	type Err = Error;
+
+	/// Parse `SHA256:` or `SHA512:` followed by unpadded base64, or `MD5:`
+	/// followed by colon-separated hexadecimal bytes. The `MD5:` prefix is
+	/// optional, as in older versions of OpenSSH.
+	fn from_str(s:&str) -> Result<Self, Error> {
+		let (hash, digest) = if let Some(b64) = s.strip_prefix("SHA256:") {
+			(FingerprintHash::Sha256, b64)
+		} else if let Some(b64) = s.strip_prefix("SHA512:") {
+			(FingerprintHash::Sha512, b64)
+		} else {
+			(FingerprintHash::Md5, s.strip_prefix("MD5:").unwrap_or(s))
+		};
+
+		let digest = if hash == FingerprintHash::Md5 {
+			digest
+				.split(':')
+				.map(|x| {
+					if x.len() == 2 {
+						u8::from_str_radix(x, 16).map_err(|_| Error::InvalidFingerprint)
+					} else {
+						Err(Error::InvalidFingerprint)
+					}
+				})
+				.collect::<Result<Vec<_>, _>>()?
+		} else {
+			data_encoding::BASE64_NOPAD
+				.decode(digest.trim_end_matches('=').as_bytes())
+				.map_err(|_| Error::InvalidFingerprint)?
+		};
+
+		if digest.len() != hash.digest(b"").len() {
+			return Err(Error::InvalidFingerprint);
+		}
+
+		Ok(Fingerprint { hash, digest })
+	}
+}
+
+impl std::fmt::Display for Fingerprint {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str(self.hash.name())?;
+
+		f.write_str(":")?;
+
+		if self.hash == FingerprintHash::Md5 {
+			let hex = self.digest.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+
+			f.write_str(&hex.join(":"))
+		} else {
+			f.write_str(&data_encoding::BASE64_NOPAD.encode(&self.digest))
+		}
+	}
+}
+
+/// The randomart of a digest, in a 17x9 field.
+fn randomart(digest:&[u8], name:&str, bits:u32, hash:&str) -> String {
+	const WIDTH:usize = 17;
+
+	const HEIGHT:usize = 9;
+
+	const SYMBOLS:&[u8] = b" .o+=*BOX@%&#/^SE";
+
+	let end = SYMBOLS.len() - 1;
+
+	let mut field = [[0usize; HEIGHT]; WIDTH];
+
+	let (mut x, mut y) = (WIDTH / 2, HEIGHT / 2);
+
+	// The bishop starts in the middle, and moves diagonally, two bits at
+	// a time.
+	for byte in digest {
+		let mut input = *byte;
+
+		for _ in 0..4 {
+			x = if input & 1 != 0 { (x + 1).min(WIDTH - 1) } else { x.saturating_sub(1) };
+
+			y = if input & 2 != 0 { (y + 1).min(HEIGHT - 1) } else { y.saturating_sub(1) };
+
+			if let Some(c) = field.get_mut(x).and_then(|c| c.get_mut(y)).filter(|c| **c < end - 2) {
+				*c += 1;
+			}
+
+			input >>= 2;
+		}
+	}
+
+	if let Some(c) = field.get_mut(WIDTH / 2).and_then(|c| c.get_mut(HEIGHT / 2)) {
+		*c = end - 1;
+	}
+
+	if let Some(c) = field.get_mut(x).and_then(|c| c.get_mut(y)) {
+		*c = end;
+	}
+
+	// Like OpenSSH, leave the size out if it does not fit.
+	let mut title = format!("[{} {}]", name, bits);
+
+	if title.len() > WIDTH {
+		title = format!("[{}]", name);
+	}
+
+	let border = |label:&str| {
+		let left = WIDTH.saturating_sub(label.len()) / 2;
+
+		let right = WIDTH.saturating_sub(left + label.len());
+
+		format!("+{}{}{}+", "-".repeat(left), label, "-".repeat(right))
+	};
+
+	let mut art = border(&title);
+
+	for y in 0..HEIGHT {
+		art.push_str("\n|");
+
+		for column in field.iter() {
+			let symbol = column.get(y).and_then(|c| SYMBOLS.get(*c)).copied().unwrap_or(b' ');
+
+			art.push(char::from(symbol));
+		}
+
+		art.push('|');
+	}
+
+	art.push('\n');
+
+	art.push_str(&border(&format!("[{}]", hash)));
+
+	art
+}
+
 /// Public key
 #[derive(Eq, Debug, Clone)]
 pub enum PublicKey {
 	#[doc(hidden)]
 	#[cfg(feature = "rs-crypto")]
 	Ed25519(ed25519_dalek::PublicKey),
+	/// An Ed25519 key held by a FIDO security key, for `application`
+	/// (usually `ssh:`).
+	#[doc(hidden)]
+	#[cfg(feature = "rs-crypto")]
+	SkEd25519 { key:ed25519_dalek::PublicKey, application:String },
 	#[doc(hidden)]
 	#[cfg(feature = "openssl")]
 	RSA { key:OpenSSLPKey, hash:SignatureHash },
@@ -122,6 +315,11 @@ impl PartialEq for PublicKey {
 			(Self::RSA { key: a, .. }, Self::RSA { key: b, .. }) => a == b,
 			#[cfg(feature = "rs-crypto")]
 			(Self::Ed25519(a), Self::Ed25519(b)) => a == b,
+			#[cfg(feature = "rs-crypto")]
+			(
+				Self::SkEd25519 { key: a, application: app_a },
+				Self::SkEd25519 { key: b, application: app_b },
+			) => a == b && app_a == app_b,
 			#[allow(unreachable_patterns)]
 			_ => false,
 		}
@@ -169,6 +367,16 @@ impl PublicKey {
 					.map(PublicKey::Ed25519)
 					.map_err(Error::from)
 			},
+			#[cfg(feature = "rs-crypto")]
+			b"sk-ssh-ed25519@openssh.com" => {
+				let mut p = pubkey.reader(0);
+
+				if p.read_string()? != algo {
+					return Err(Error::CouldNotReadKey);
+				}
+
+				read_sk_ed25519(&mut p)
+			},
 			b"ssh-rsa" | b"rsa-sha2-256" | b"rsa-sha2-512" if cfg!(feature = "openssl") => {
 				#[cfg(feature = "openssl")]
 				{
@@ -216,12 +424,27 @@ impl PublicKey {
 		match *self {
 			#[cfg(feature = "rs-crypto")]
 			PublicKey::Ed25519(_) => ED25519.0,
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => SK_ED25519.0,
 			#[cfg(feature = "openssl")]
 			PublicKey::RSA { ref hash, .. } => hash.name().0,
 		}
 	}
 
-	/// Verify a signature.
+	/// Whether this key is held by a FIDO security key, in which case
+	/// signatures are checked with [`PublicKey::verify_sk_detached`].
+	pub fn is_security_key(&self) -> bool {
+		match self {
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => true,
+			#[allow(unreachable_patterns)]
+			_ => false,
+		}
+	}
+
+	/// Verify a signature. Security key signatures can't be verified
+	/// without their flags and counter, see
+	/// [`PublicKey::verify_sk_detached`].
 	pub fn verify_detached(&self, buffer:&[u8], sig:&[u8]) -> bool {
 		match self {
 			#[cfg(feature = "rs-crypto")]
@@ -232,6 +455,8 @@ impl PublicKey {
 					.and_then(|sig| public.verify(buffer, &sig))
 					.is_ok()
 			},
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => false,
 
 			#[cfg(feature = "openssl")]
 			PublicKey::RSA { ref key, ref hash } => {
@@ -250,19 +475,51 @@ impl PublicKey {
 		}
 	}
 
-	/// Compute the key fingerprint, hashed with sha2-256.
-	pub fn fingerprint(&self) -> String {
-		use super::PublicKeyBase64;
+	/// Verify a signature made by a security key, along with the
+	/// `flags` and `counter` that follow it in the signature blob. This
+	/// doesn't check that [`SK_USER_PRESENT`] is set in `flags`.
+	pub fn verify_sk_detached(&self, buffer:&[u8], sig:&[u8], flags:u8, counter:u32) -> bool {
+		match self {
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { ref key, ref application } => {
+				use ed25519_dalek::Verifier;
 
-		let key = self.public_key_bytes();
+				let signed = sk_signed_data(application, flags, counter, buffer);
 
-		use sha2::{Digest, Sha256};
+				ed25519_dalek::Signature::from_bytes(sig)
+					.and_then(|sig| key.verify(&signed, &sig))
+					.is_ok()
+			},
+			#[allow(unreachable_patterns)]
+			_ => {
+				let _ = (buffer, sig, flags, counter);
+
+				false
+			},
+		}
+	}
 
-		let mut hasher = Sha256::new();
+	/// Compute the key fingerprint, in the format printed by `ssh-keygen
+	/// -l`, such as `SHA256:ldyiXa1JQakitNU5tErauu8DvWQ1dZ7aXu+rm7KQuog`.
+	pub fn fingerprint(&self, hash:FingerprintHash) -> String {
+		Fingerprint::of(self, hash).to_string()
+	}
+
+	/// Draw the "randomart" of the key fingerprint, as printed by
+	/// `ssh-keygen -lv` (OpenSSH's "drunken bishop" algorithm).
+	pub fn randomart(&self, hash:FingerprintHash) -> String {
+		let digest = Fingerprint::of(self, hash).digest;
 
-		hasher.update(&key[..]);
+		let (name, bits) = match *self {
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::Ed25519(_) => ("ED25519", 256),
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => ("ED25519-SK", 256),
+			#[cfg(feature = "openssl")]
+			PublicKey::RSA { ref key, .. } => ("RSA", key.0.bits()),
+		};
 
-		data_encoding::BASE64_NOPAD.encode(&hasher.finalize())
+		randomart(&digest, name, bits, hash.name())
 	}
 
 	#[cfg(feature = "openssl")]
@@ -379,8 +636,18 @@ impl KeyPair {
 		Some(KeyPair::Ed25519(keypair))
 	}
 
+	/// Smallest size of the RSA keys generated by
+	/// [`KeyPair::generate_rsa`].
+	pub const MIN_RSA_BITS:usize = 2048;
+
+	/// Generate an RSA key pair of `bits` bits, signing with `hash`.
+	/// Returns `None` if `bits` is less than [`KeyPair::MIN_RSA_BITS`].
 	#[cfg(feature = "openssl")]
 	pub fn generate_rsa(bits:usize, hash:SignatureHash) -> Option<Self> {
+		if bits < Self::MIN_RSA_BITS {
+			return None;
+		}
+
 		let key = openssl::rsa::Rsa::generate(bits as u32).ok()?;
 
 		Some(KeyPair::RSA { key, hash })
@@ -525,6 +792,43 @@ fn rsa_signature(
 	Ok(signer.sign_to_vec()?)
 }
 
+#[cfg(feature = "rs-crypto")]
+fn read_sk_ed25519(p:&mut crate::encoding::Position<'_>) -> Result<PublicKey, Error> {
+	let key = p.read_string()?;
+
+	let application = p.read_string()?;
+
+	if key.len() != ed25519_dalek::PUBLIC_KEY_LENGTH {
+		return Err(Error::CouldNotReadKey);
+	}
+
+	Ok(PublicKey::SkEd25519 {
+		key:ed25519_dalek::PublicKey::from_bytes(key)?,
+		application:std::str::from_utf8(application)
+			.map_err(|_| Error::CouldNotReadKey)?
+			.to_string(),
+	})
+}
+
+/// The data actually signed by a security key, as described in
+/// OpenSSH's `PROTOCOL.u2f`.
+#[cfg(feature = "rs-crypto")]
+pub(crate) fn sk_signed_data(application:&str, flags:u8, counter:u32, message:&[u8]) -> Vec<u8> {
+	use sha2::{Digest, Sha256};
+
+	let mut data = Vec::with_capacity(69);
+
+	data.extend_from_slice(&Sha256::digest(application.as_bytes()));
+
+	data.push(flags);
+
+	data.extend_from_slice(&counter.to_be_bytes());
+
+	data.extend_from_slice(&Sha256::digest(message));
+
+	data
+}
+
 /// Parse a public key from a byte slice.
 pub fn parse_public_key(
 	p:&[u8],
@@ -541,6 +845,10 @@ pub fn parse_public_key(
 			return Ok(PublicKey::Ed25519(p));
 		}
 	}
+	#[cfg(feature = "rs-crypto")]
+	if t == SK_ED25519.0.as_bytes() {
+		return read_sk_ed25519(&mut pos);
+	}
 
 	if t == b"ssh-rsa" {
 		#[cfg(feature = "openssl")]
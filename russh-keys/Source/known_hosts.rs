@@ -0,0 +1,103 @@
+//! Host keys recorded in several known_hosts files, such as the system's
+//! `/etc/ssh/ssh_known_hosts` and the user's `~/.ssh/known_hosts`.
+
+use std::path::PathBuf;
+
+use crate::{
+	Error,
+	KnownHostStatus,
+	key,
+	known_host_status_path,
+	learn_known_hosts_path,
+	learn_known_hosts_path_hashed,
+};
+
+/// The system-wide known_hosts file.
+#[cfg(not(target_os = "windows"))]
+pub const SYSTEM_KNOWN_HOSTS:&str = "/etc/ssh/ssh_known_hosts";
+
+/// A set of known_hosts files, looked up together.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+	/// Files that are only read.
+	pub files:Vec<PathBuf>,
+	/// The file new keys are recorded in, which is also read.
+	pub user_file:Option<PathBuf>,
+	/// Whether to hash the host names of new entries, as with OpenSSH's
+	/// `HashKnownHosts yes`.
+	pub hash:bool,
+}
+
+impl KnownHosts {
+	/// The system-wide known_hosts file (on Unix), and the user's
+	/// known_hosts file, in which new keys are recorded.
+	pub fn system_and_user() -> Result<Self, Error> {
+		let mut user_file = dirs::home_dir().ok_or(Error::NoHomeDir)?;
+
+		#[cfg(target_os = "windows")]
+		user_file.push("ssh");
+
+		#[cfg(not(target_os = "windows"))]
+		user_file.push(".ssh");
+
+		user_file.push("known_hosts");
+
+		Ok(KnownHosts {
+			#[cfg(target_os = "windows")]
+			files:Vec::new(),
+			#[cfg(not(target_os = "windows"))]
+			files:vec![PathBuf::from(SYSTEM_KNOWN_HOSTS)],
+			user_file:Some(user_file),
+			hash:false,
+		})
+	}
+
+	/// Look a server key up in all the files.
+	///
+	/// A key marked `@revoked` in any file is [`KnownHostStatus::Revoked`],
+	/// and a key recorded in any file is a [`KnownHostStatus::Match`].
+	/// Returns [`Error::KeyChanged`] if only other keys of the same type
+	/// are recorded for this host.
+	pub fn check(
+		&self,
+		host:&str,
+		port:u16,
+		pubkey:&key::PublicKey,
+	) -> Result<KnownHostStatus, Error> {
+		let mut matches = false;
+
+		let mut changed = None;
+
+		for path in self.files.iter().chain(self.user_file.iter()) {
+			match known_host_status_path(host, port, pubkey, path) {
+				Ok(KnownHostStatus::Revoked) => return Ok(KnownHostStatus::Revoked),
+				Ok(KnownHostStatus::Match) => matches = true,
+				Ok(KnownHostStatus::NotFound) => {},
+				Err(Error::KeyChanged { line }) => {
+					changed.get_or_insert(line);
+				},
+				Err(e) => return Err(e),
+			}
+		}
+
+		if matches {
+			Ok(KnownHostStatus::Match)
+		} else if let Some(line) = changed {
+			Err(Error::KeyChanged { line })
+		} else {
+			Ok(KnownHostStatus::NotFound)
+		}
+	}
+
+	/// Record a server key in the user file. The file is replaced
+	/// atomically, keeping its contents and permissions.
+	pub fn learn(&self, host:&str, port:u16, pubkey:&key::PublicKey) -> Result<(), Error> {
+		let path = self.user_file.as_ref().ok_or(Error::NoKnownHostsFile)?;
+
+		if self.hash {
+			learn_known_hosts_path_hashed(host, port, pubkey, path)
+		} else {
+			learn_known_hosts_path(host, port, pubkey, path)
+		}
+	}
+}
@@ -106,19 +106,23 @@
 
 use std::{
 	borrow::Cow,
-	fs::{File, OpenOptions},
-	io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+	fs::File,
+	io::{BufRead, BufReader, Read, Write},
 	path::Path,
 };
 
 use byteorder::{BigEndian, WriteBytesExt};
-use data_encoding::BASE64_MIME;
+use data_encoding::{BASE64, BASE64_MIME};
 use log::{debug, info};
 use thiserror::Error;
 
+pub mod authorized_keys;
+pub mod certificate;
 pub mod encoding;
 pub mod key;
+pub mod known_hosts;
 pub mod signature;
+pub mod sshsig;
 
 mod format;
 pub use format::*;
@@ -144,9 +148,42 @@ pub enum Error {
 	/// The key contents are inconsistent
 	#[error("The key is corrupt")]
 	KeyIsCorrupt,
+	/// The passphrase of an encrypted key is wrong
+	#[error("Wrong passphrase")]
+	WrongPassphrase,
+	/// The key is encrypted with an unsupported cipher
+	#[error("Unsupported cipher {0}")]
+	UnsupportedCipher(String),
+	/// The number of key derivation rounds is invalid
+	#[error("Invalid number of KDF rounds")]
+	InvalidKdfRounds,
 	/// Home directory could not be found
 	#[error("No home directory found")]
 	NoHomeDir,
+	/// The server key is marked `@revoked` in known_hosts
+	#[error("The host key is revoked")]
+	HostKeyRevoked,
+	/// No known_hosts file to record a key in
+	#[error("No known_hosts file to record the key in")]
+	NoKnownHostsFile,
+	/// A key fingerprint could not be parsed
+	#[error("Invalid fingerprint")]
+	InvalidFingerprint,
+	/// An `authorized_keys` option is unknown or malformed
+	#[error("Invalid authorized_keys option {0:?}")]
+	InvalidAuthorizedKeysOption(String),
+	/// An SSH signature is malformed
+	#[error("Invalid SSH signature")]
+	InvalidSshSig,
+	/// An SSH signature is bound to another namespace, or to none
+	#[error("Wrong SSH signature namespace {0:?}")]
+	SshSigNamespace(String),
+	/// An SSH signature does not match the signed message
+	#[error("Bad SSH signature")]
+	BadSshSig,
+	/// No allowed signer may sign with this key as this principal
+	#[error("The key is not allowed to sign for principal {0}")]
+	SshSigNotAllowed(String),
 	/// The server key has changed
 	#[error("The server key changed at line {}", line)]
 	KeyChanged { line:usize },
@@ -190,6 +227,25 @@ pub enum Error {
 		 points to a nonexistent file or directory."
 	)]
 	BadAuthSock,
+	/// The certificate is not a user certificate, or not a host
+	/// certificate
+	#[error("Wrong certificate type")]
+	WrongCertificateType,
+	/// The certificate is not signed by a trusted CA
+	#[error("The certificate is not signed by a trusted CA")]
+	UntrustedCertificate,
+	/// The certificate has expired, or is not valid yet
+	#[error("The certificate is not valid at this time")]
+	CertificateNotValidNow,
+	/// The certificate is not valid for this principal
+	#[error("The certificate is not valid for principal {0}")]
+	CertificateInvalidPrincipal(String),
+	/// The certificate is not valid from this address
+	#[error("The certificate is not valid from this address")]
+	CertificateInvalidSourceAddress,
+	/// The certificate has a critical option we don't know
+	#[error("Unsupported critical option {0}")]
+	UnsupportedCriticalOption(String),
 }
 
 impl From<yasna::ASN1Error> for Error {
@@ -240,6 +296,28 @@ pub fn parse_public_key_base64(key:&str) -> Result<key::PublicKey, Error> {
 	)
 }
 
+/// Load an OpenSSH certificate from a file, such as `id_ed25519-cert.pub`.
+pub fn load_openssh_certificate<P:AsRef<Path>>(path:P) -> Result<certificate::Certificate, Error> {
+	let mut cert = String::new();
+
+	let mut file = File::open(path.as_ref())?;
+
+	file.read_to_string(&mut cert)?;
+
+	let mut split = cert.split_whitespace();
+
+	match (split.next(), split.next()) {
+		(Some(_), Some(cert)) => parse_certificate_base64(cert),
+		(Some(cert), None) => parse_certificate_base64(cert),
+		_ => Err(Error::CouldNotReadKey),
+	}
+}
+
+/// Reads an OpenSSH certificate from its base64 encoding.
+pub fn parse_certificate_base64(cert:&str) -> Result<certificate::Certificate, Error> {
+	certificate::Certificate::parse(&BASE64_MIME.decode(cert.as_bytes())?)
+}
+
 pub trait PublicKeyBase64 {
 	/// Create the base64 part of the public key blob.
 	fn public_key_bytes(&self) -> Vec<u8>;
@@ -272,6 +350,16 @@ impl PublicKeyBase64 for key::PublicKey {
 
 				s.extend_from_slice(publickey.as_bytes());
 			},
+			#[cfg(feature = "rs-crypto")]
+			key::PublicKey::SkEd25519 { ref key, ref application } => {
+				use encoding::Encoding;
+
+				s.extend_ssh_string(key::SK_ED25519.0.as_bytes());
+
+				s.extend_ssh_string(key.as_bytes());
+
+				s.extend_ssh_string(application.as_bytes());
+			},
 			#[cfg(feature = "openssl")]
 			key::PublicKey::RSA { ref key, .. } => {
 				use encoding::Encoding;
@@ -364,60 +452,140 @@ pub fn learn_known_hosts_path<P:AsRef<Path>>(
 	pubkey:&key::PublicKey,
 	path:P,
 ) -> Result<(), Error> {
-	if let Some(parent) = path.as_ref().parent() {
-		std::fs::create_dir_all(parent)?
-	}
+	learn_known_hosts_path_(host, port, pubkey, path, false)
+}
 
-	let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+/// Record a host's public key into a nonstandard location, with the host
+/// name hashed as with OpenSSH's `HashKnownHosts yes`.
+pub fn learn_known_hosts_path_hashed<P:AsRef<Path>>(
+	host:&str,
+	port:u16,
+	pubkey:&key::PublicKey,
+	path:P,
+) -> Result<(), Error> {
+	learn_known_hosts_path_(host, port, pubkey, path, true)
+}
 
-	// Test whether the known_hosts file ends with a \n
-	let mut buf = [0; 1];
+fn learn_known_hosts_path_<P:AsRef<Path>>(
+	host:&str,
+	port:u16,
+	pubkey:&key::PublicKey,
+	path:P,
+	hash:bool,
+) -> Result<(), Error> {
+	let path = path.as_ref();
 
-	let mut ends_in_newline = false;
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?
+	}
 
-	if file.seek(SeekFrom::End(-1)).is_ok() {
-		file.read_exact(&mut buf)?;
+	let mut file = match std::fs::read(path) {
+		Ok(file) => file,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+		Err(e) => return Err(e.into()),
+	};
 
-		ends_in_newline = buf[0] == b'\n';
+	if !file.is_empty() && !file.ends_with(b"\n") {
+		file.push(b'\n');
 	}
 
-	// Write the key.
-	file.seek(SeekFrom::End(0))?;
+	let host_port = if port == 22 {
+		Cow::Borrowed(host)
+	} else {
+		Cow::Owned(format!("[{}]:{}", host, port))
+	};
 
-	let mut file = std::io::BufWriter::new(file);
+	if hash {
+		let salt = rand::random::<[u8; 20]>();
 
-	if !ends_in_newline {
-		file.write_all(b"\n")?;
-	}
+		let digest = hmac_sha1(&salt, &host_port).ok_or(Error::CouldNotReadKey)?;
 
-	if port != 22 {
-		write!(file, "[{}]:{} ", host, port)?
+		write!(file, "|1|{}|{} ", BASE64.encode(&salt), BASE64.encode(&digest))?
 	} else {
-		write!(file, "{} ", host)?
+		write!(file, "{} ", host_port)?
 	}
 
 	write_public_key_base64(&mut file, pubkey)?;
 
-	file.write_all(b"\n")?;
+	// The new file is written next to the old one, and then renamed over
+	// it, so that readers never see a partial file.
+	let mut tmp = path.as_os_str().to_owned();
 
-	Ok(())
+	tmp.push(format!(".{}.tmp", std::process::id()));
+
+	let tmp = std::path::PathBuf::from(tmp);
+
+	let write = || -> Result<(), Error> {
+		let mut f = File::create(&tmp)?;
+
+		f.write_all(&file)?;
+
+		if let Ok(metadata) = std::fs::metadata(path) {
+			f.set_permissions(metadata.permissions())?;
+		}
+
+		f.sync_all()?;
+
+		std::fs::rename(&tmp, path)?;
+
+		Ok(())
+	};
+
+	write().map_err(|e| {
+		std::fs::remove_file(&tmp).unwrap_or(());
+
+		e
+	})
+}
+
+/// The result of looking up a host key in a known_hosts file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownHostStatus {
+	/// The key is recorded for this host.
+	Match,
+	/// The key is marked `@revoked` for this host, and must never be
+	/// accepted.
+	Revoked,
+	/// No key is recorded for this host.
+	NotFound,
 }
 
 /// Check that a server key matches the one recorded in file `path`.
+///
+/// Returns [`Error::HostKeyRevoked`] if the key is marked `@revoked`, and
+/// [`Error::KeyChanged`] if only other keys are recorded for this host.
 pub fn check_known_hosts_path<P:AsRef<Path>>(
 	host:&str,
 	port:u16,
 	pubkey:&key::PublicKey,
 	path:P,
 ) -> Result<bool, Error> {
-	let mut f = if let Ok(f) = File::open(path) {
+	match known_host_status_path(host, port, pubkey, path)? {
+		KnownHostStatus::Match => Ok(true),
+		KnownHostStatus::Revoked => Err(Error::HostKeyRevoked),
+		KnownHostStatus::NotFound => Ok(false),
+	}
+}
+
+/// Look a server key up in file `path`.
+///
+/// `@revoked` lines take precedence over the other lines, and
+/// `@cert-authority` lines are skipped (see
+/// [`check_known_hosts_certificate_path`]). Returns
+/// [`Error::KeyChanged`] if only other keys of the same type are recorded
+/// for this host.
+pub fn known_host_status_path<P:AsRef<Path>>(
+	host:&str,
+	port:u16,
+	pubkey:&key::PublicKey,
+	path:P,
+) -> Result<KnownHostStatus, Error> {
+	let f = if let Ok(f) = File::open(path) {
 		BufReader::new(f)
 	} else {
-		return Ok(false);
+		return Ok(KnownHostStatus::NotFound);
 	};
 
-	let mut buffer = String::new();
-
 	let host_port = if port == 22 {
 		Cow::Borrowed(host)
 	} else {
@@ -426,49 +594,231 @@ pub fn check_known_hosts_path<P:AsRef<Path>>(
 
 	debug!("host_port = {:?}", host_port);
 
-	let mut line = 1;
+	let mut matches = false;
 
-	while f.read_line(&mut buffer)? > 0 {
-		{
-			if buffer.as_bytes().first() == Some(&b'#') {
-				buffer.clear();
+	let mut changed = None;
+
+	for (n, line) in f.lines().enumerate() {
+		let line = line?;
+
+		if line.starts_with('#') {
+			continue;
+		}
+
+		debug!("line = {:?}", line);
 
+		let mut s = line.split_whitespace();
+
+		let (marker, hosts) = match s.next() {
+			Some(marker) if marker.starts_with('@') => (Some(marker), s.next()),
+			hosts => (None, hosts),
+		};
+
+		let _ = s.next();
+
+		let key = s.next();
+
+		if let (Some(h), Some(k)) = (hosts, key) {
+			debug!("{:?} {:?}", h, k);
+
+			if !host_patterns_match(h, &host_port) {
 				continue;
 			}
 
-			debug!("line = {:?}", buffer);
+			match (marker, parse_public_key_base64(k)) {
+				(Some("@revoked"), Ok(k)) if &k == pubkey => return Ok(KnownHostStatus::Revoked),
+				(Some(_), _) => {},
+				(None, Ok(k)) if &k == pubkey => matches = true,
+				// Keys of other types may be recorded for the same host.
+				(None, Ok(k)) if std::mem::discriminant(&k) == std::mem::discriminant(pubkey) => {
+					changed.get_or_insert(n + 1);
+				},
+				(None, Ok(_)) => {},
+				(None, Err(e)) => info!("host file line '{}' failed to parse: {}", k, e),
+			}
+		}
+	}
+
+	if matches {
+		Ok(KnownHostStatus::Match)
+	} else if let Some(line) = changed {
+		Err(Error::KeyChanged { line })
+	} else {
+		Ok(KnownHostStatus::NotFound)
+	}
+}
+
+/// Whether a key is marked `@revoked` in file `path`, for any host.
+pub fn is_revoked_path<P:AsRef<Path>>(pubkey:&key::PublicKey, path:P) -> Result<bool, Error> {
+	let f = if let Ok(f) = File::open(path) {
+		BufReader::new(f)
+	} else {
+		return Ok(false);
+	};
+
+	for line in f.lines() {
+		let line = line?;
+
+		let mut s = line.split_whitespace();
+
+		if s.next() != Some("@revoked") {
+			continue;
+		}
+
+		let _hosts = s.next();
+
+		let _ = s.next();
+
+		if let Some(Ok(k)) = s.next().map(parse_public_key_base64) {
+			if &k == pubkey {
+				return Ok(true);
+			}
+		}
+	}
+
+	Ok(false)
+}
+
+/// Check that a server's host certificate is signed by a CA listed for
+/// this host in a `@cert-authority` line of file `path`, and is valid for
+/// `host`.
+///
+/// Returns `Ok(false)` if no CA is listed for this host, and an error if
+/// the certificate is not signed by one of the listed CAs, has expired,
+/// or is not valid for `host`.
+pub fn check_known_hosts_certificate_path<P:AsRef<Path>>(
+	host:&str,
+	port:u16,
+	cert:&certificate::Certificate,
+	path:P,
+) -> Result<bool, Error> {
+	let f = if let Ok(f) = File::open(path) {
+		BufReader::new(f)
+	} else {
+		return Ok(false);
+	};
+
+	let host_port = if port == 22 {
+		Cow::Borrowed(host)
+	} else {
+		Cow::Owned(format!("[{}]:{}", host, port))
+	};
+
+	let mut cas = Vec::new();
 
-			let mut s = buffer.split(' ');
+	for line in f.lines() {
+		let line = line?;
 
-			let hosts = s.next();
+		let mut s = line.split_whitespace();
 
-			let _ = s.next();
+		if s.next() != Some("@cert-authority") {
+			continue;
+		}
 
-			let key = s.next();
+		let hosts = s.next();
 
-			if let (Some(h), Some(k)) = (hosts, key) {
-				debug!("{:?} {:?}", h, k);
+		let _ = s.next();
 
-				let host_matches = h.split(',').any(|x| x == host_port);
+		let key = s.next();
 
-				if host_matches {
-					match parse_public_key_base64(k) {
-						Ok(k) if &k == pubkey => return Ok(true),
-						Ok(_) => return Err(Error::KeyChanged { line }),
-						Err(e) => {
-							info!("host file line '{}' failed to parse: {}", k, e)
-						},
-					}
+		if let (Some(h), Some(k)) = (hosts, key) {
+			if host_patterns_match(h, &host_port) {
+				match parse_public_key_base64(k) {
+					Ok(k) => cas.push(k),
+					Err(e) => info!("host file line '{}' failed to parse: {}", k, e),
 				}
 			}
 		}
+	}
+
+	if cas.is_empty() {
+		return Ok(false);
+	}
 
-		buffer.clear();
+	cert.validate_host(&cas, host)?;
 
-		line += 1;
+	Ok(true)
+}
+
+/// Whether `host` matches a comma-separated list of patterns, where `*`
+/// and `?` are wildcards and patterns starting with `!` exclude hosts, or
+/// a hashed host name.
+fn host_patterns_match(patterns:&str, host:&str) -> bool {
+	if patterns.starts_with("|1|") {
+		return hashed_host_matches(patterns, host);
 	}
 
-	Ok(false)
+	let mut matches = false;
+
+	for pattern in patterns.split(',') {
+		if let Some(pattern) = pattern.strip_prefix('!') {
+			if wildcard_matches(pattern.as_bytes(), host.as_bytes()) {
+				return false;
+			}
+		} else if wildcard_matches(pattern.as_bytes(), host.as_bytes()) {
+			matches = true;
+		}
+	}
+
+	matches
+}
+
+pub(crate) fn wildcard_matches(pattern:&[u8], s:&[u8]) -> bool {
+	match pattern.split_first() {
+		None => s.is_empty(),
+		Some((b'*', rest)) => {
+			(0..=s.len()).any(|i| wildcard_matches(rest, s.get(i..).unwrap_or(&[])))
+		},
+		Some((b'?', rest)) => s.split_first().map_or(false, |(_, s)| wildcard_matches(rest, s)),
+		Some((c, rest)) => {
+			s.split_first()
+				.map_or(false, |(d, s)| c.eq_ignore_ascii_case(d) && wildcard_matches(rest, s))
+		},
+	}
+}
+
+/// Whether `entry` is a hashed host name (`|1|salt|hash`, as written
+/// with OpenSSH's `HashKnownHosts yes`) for `host`, which must be in the
+/// `[host]:port` form for non-standard ports.
+fn hashed_host_matches(entry:&str, host:&str) -> bool {
+	let mut parts = match entry.strip_prefix("|1|") {
+		Some(parts) => parts.split('|'),
+		None => return false,
+	};
+
+	let (salt, hash) = match (parts.next(), parts.next(), parts.next()) {
+		(Some(salt), Some(hash), None) => (salt, hash),
+		_ => return false,
+	};
+
+	match (BASE64_MIME.decode(salt.as_bytes()), BASE64_MIME.decode(hash.as_bytes())) {
+		(Ok(salt), Ok(hash)) => hmac_sha1(&salt, host).map_or(false, |h| h == hash),
+		_ => false,
+	}
+}
+
+#[cfg(feature = "rs-crypto")]
+fn hmac_sha1(key:&[u8], host:&str) -> Option<Vec<u8>> {
+	use hmac::{Hmac, Mac};
+
+	let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key).ok()?;
+
+	mac.update(host.as_bytes());
+
+	Some(mac.finalize().into_bytes().to_vec())
+}
+
+#[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
+fn hmac_sha1(key:&[u8], host:&str) -> Option<Vec<u8>> {
+	use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+	let key = PKey::hmac(key).ok()?;
+
+	let mut signer = Signer::new(MessageDigest::sha1(), &key).ok()?;
+
+	signer.update(host.as_bytes()).ok()?;
+
+	signer.sign_to_vec().ok()
 }
 
 /// Record a host's public key into the user's known_hosts file.
@@ -499,6 +849,36 @@ pub fn learn_known_hosts(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<
 	}
 }
 
+/// Record a host's public key into the user's known_hosts file, with the
+/// host name hashed.
+#[cfg(target_os = "windows")]
+pub fn learn_known_hosts_hashed(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<(), Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push("ssh");
+
+		known_host_file.push("known_hosts");
+
+		learn_known_hosts_path_hashed(host, port, pubkey, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
+/// Record a host's public key into the user's known_hosts file, with the
+/// host name hashed.
+#[cfg(not(target_os = "windows"))]
+pub fn learn_known_hosts_hashed(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<(), Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push(".ssh");
+
+		known_host_file.push("known_hosts");
+
+		learn_known_hosts_path_hashed(host, port, pubkey, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
 /// Check whether the host is known, from its standard location.
 #[cfg(target_os = "windows")]
 pub fn check_known_hosts(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<bool, Error> {
@@ -513,6 +893,24 @@ pub fn check_known_hosts(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<
 	}
 }
 
+/// Look a server key up in the user's known_hosts file.
+#[cfg(target_os = "windows")]
+pub fn known_host_status(
+	host:&str,
+	port:u16,
+	pubkey:&key::PublicKey,
+) -> Result<KnownHostStatus, Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push("ssh");
+
+		known_host_file.push("known_hosts");
+
+		known_host_status_path(host, port, pubkey, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
 /// Check whether the host is known, from its standard location.
 #[cfg(not(target_os = "windows"))]
 pub fn check_known_hosts(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<bool, Error> {
@@ -527,6 +925,62 @@ pub fn check_known_hosts(host:&str, port:u16, pubkey:&key::PublicKey) -> Result<
 	}
 }
 
+/// Look a server key up in the user's known_hosts file.
+#[cfg(not(target_os = "windows"))]
+pub fn known_host_status(
+	host:&str,
+	port:u16,
+	pubkey:&key::PublicKey,
+) -> Result<KnownHostStatus, Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push(".ssh");
+
+		known_host_file.push("known_hosts");
+
+		known_host_status_path(host, port, pubkey, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
+/// Check a server's host certificate against the `@cert-authority` lines
+/// of the user's known_hosts file.
+#[cfg(target_os = "windows")]
+pub fn check_known_hosts_certificate(
+	host:&str,
+	port:u16,
+	cert:&certificate::Certificate,
+) -> Result<bool, Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push("ssh");
+
+		known_host_file.push("known_hosts");
+
+		check_known_hosts_certificate_path(host, port, cert, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
+/// Check a server's host certificate against the `@cert-authority` lines
+/// of the user's known_hosts file.
+#[cfg(not(target_os = "windows"))]
+pub fn check_known_hosts_certificate(
+	host:&str,
+	port:u16,
+	cert:&certificate::Certificate,
+) -> Result<bool, Error> {
+	if let Some(mut known_host_file) = dirs::home_dir() {
+		known_host_file.push(".ssh");
+
+		known_host_file.push("known_hosts");
+
+		check_known_hosts_certificate_path(host, port, cert, &known_host_file)
+	} else {
+		Err(Error::NoHomeDir)
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use std::{fs::File, io::Write};
@@ -615,7 +1069,70 @@ QR+u0AypRPmzHnOPAAAAEXJvb3RAMTQwOTExNTQ5NDBkAQ==
 		)
 		.unwrap();
 
-		assert_eq!(key.fingerprint(), "ldyiXa1JQakitNU5tErauu8DvWQ1dZ7aXu+rm7KQuog");
+		use key::FingerprintHash::*;
+
+		// Printed by `ssh-keygen -E <hash> -lvf`.
+		assert_eq!(key.fingerprint(Sha256), "SHA256:ldyiXa1JQakitNU5tErauu8DvWQ1dZ7aXu+rm7KQuog");
+
+		assert_eq!(
+			key.fingerprint(Sha512),
+			"SHA512:QSsF66nrZawT427192CAC231iBCnAw4vE8FvTq23cJV12agRob7wvlQKGCiXtuLFAJINqdHZ6RfCZOSSXrrj+A"
+		);
+
+		assert_eq!(key.fingerprint(Md5), "MD5:c9:25:17:d9:6a:9a:75:a6:52:88:c1:53:52:e9:64:76");
+
+		assert_eq!(
+			key.randomart(Sha256),
+			"+--[ED25519 256]--+
+|         o.oo.   |
+|      . ..++oo.  |
+|     . o. *++o.. |
+|      o+.=++ oo  |
+|      .oSo..oo   |
+|      ..+ . . . .|
+|      .+ +   . ..|
+|    . ..+ ..  o .|
+|   E ..=+. .o+ooo|
++----[SHA256]-----+"
+		);
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_parse_fingerprint() {
+		use key::{Fingerprint, FingerprintHash};
+
+		let key = parse_public_key_base64(
+			"AAAAC3NzaC1lZDI1NTE5AAAAILagOJFgwaMNhBWQINinKOXmqS4Gh5NgxgriXwdOoINJ",
+		)
+		.unwrap();
+
+		for s in [
+			"SHA256:ldyiXa1JQakitNU5tErauu8DvWQ1dZ7aXu+rm7KQuog",
+			"SHA512:QSsF66nrZawT427192CAC231iBCnAw4vE8FvTq23cJV12agRob7wvlQKGCiXtuLFAJINqdHZ6RfCZOSSXrrj+A",
+			"MD5:c9:25:17:d9:6a:9a:75:a6:52:88:c1:53:52:e9:64:76",
+		] {
+			let fingerprint:Fingerprint = s.parse().unwrap();
+
+			assert!(fingerprint.matches(&key));
+
+			assert_eq!(fingerprint.to_string(), s);
+		}
+
+		let legacy:Fingerprint = "c9:25:17:d9:6a:9a:75:a6:52:88:c1:53:52:e9:64:76".parse().unwrap();
+
+		assert_eq!(legacy.hash, FingerprintHash::Md5);
+
+		assert!(legacy.matches(&key));
+
+		let other:Fingerprint =
+			"SHA256:cmZL3+aAKXnUlEb02r847o2zlHLBLkiY5I0qbG21zZo".parse().unwrap();
+
+		assert!(!other.matches(&key));
+
+		for s in ["SHA256:ldyiXa1JQakitNU5", "SHA256:!!", "MD5:c9:25", "c9-25", ""] {
+			assert!(matches!(s.parse::<Fingerprint>(), Err(Error::InvalidFingerprint)));
+		}
 	}
 
 	#[test]
@@ -626,14 +1143,155 @@ QR+u0AypRPmzHnOPAAAAEXJvb3RAMTQwOTExNTQ5NDBkAQ==
         )
         .unwrap();
 
-		assert_eq!(key.fingerprint(), "cmZL3+aAKXnUlEb02r847o2zlHLBLkiY5I0qbG21zZo");
+		use key::FingerprintHash::*;
+
+		assert_eq!(key.fingerprint(Sha256), "SHA256:cmZL3+aAKXnUlEb02r847o2zlHLBLkiY5I0qbG21zZo");
+
+		assert_eq!(
+			key.fingerprint(Sha512),
+			"SHA512:Su2+o2qJq50+Z55rpWDVQR0JYr7sJkEYtWkhfRmkzbIpkt0SkCeSPR+7oqVMK3VIW2ldMNk0mkRktF2fsV80Zg"
+		);
+
+		assert_eq!(key.fingerprint(Md5), "MD5:b1:94:f6:18:52:ac:05:71:f9:95:89:21:56:0c:46:9b");
+
+		assert_eq!(
+			key.randomart(Md5),
+			"+---[RSA 1024]----+
+|      o=O=oo o   |
+|       =+=o +    |
+|      .oE. .     |
+|      .+ *.      |
+|        S .      |
+|                 |
+|                 |
+|                 |
+|                 |
++------[MD5]------+"
+		);
 	}
 
-	#[test]
-	fn test_check_known_hosts() {
-		env_logger::try_init().unwrap_or(());
+	// Signed by `CA_KEY` with `ssh-keygen -s ca -I alice-cert -n alice,bob -V always:forever`.
+	#[cfg(feature = "rs-crypto")]
+	const ED25519_CERT:&str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIFyxqz9VEk7Fg3p8USk9HLPofC0Gxn8s+EyQJdbUjcqjAAAAINOdPGjjiGgMKrr8tfpPuJQ6+cokPbhoSaJoQYoi4nwGAAAAAAAAACoAAAABAAAACmFsaWNlLWNlcnQAAAAQAAAABWFsaWNlAAAAA2JvYgAAAAAAAAAA//////////8AAAAAAAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACC4QTl6MTa9bvL5ehw4VPNgNSwVxwLAjiCkSyEJi/BOPgAAAFMAAAALc3NoLWVkMjU1MTkAAABAkQMeh0clngbPE3hguFHVlcdWxIUi99vYolFy5EpeEqiUc4uO5AgDmNSVACJcvP92hx0q5B8K3eGv//FDNibQAQ==";
 
-		let dir = tempdir::TempDir::new("russh").unwrap();
+	// Signed with `-I src -n alice -O source-address=10.0.0.0/8,::1 -O
+	// force-command=/bin/true -V 20200101:20300101`.
+	#[cfg(feature = "rs-crypto")]
+	const ED25519_SOURCE_CERT:&str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIN5SoOhhMT/UOCmqys/FghZOFtiLIhzWBCrqmzOjJBTeAAAAINOdPGjjiGgMKrr8tfpPuJQ6+cokPbhoSaJoQYoi4nwGAAAAAAAAAAcAAAABAAAAA3NyYwAAAAkAAAAFYWxpY2UAAAAAXgvhAAAAAABw29iAAAAASgAAAA1mb3JjZS1jb21tYW5kAAAADQAAAAkvYmluL3RydWUAAAAOc291cmNlLWFkZHJlc3MAAAASAAAADjEwLjAuMC4wLzgsOjoxAAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACC4QTl6MTa9bvL5ehw4VPNgNSwVxwLAjiCkSyEJi/BOPgAAAFMAAAALc3NoLWVkMjU1MTkAAABAZVymR9fK1qQtW9a3a7QMFbBBeTVUqOnlLM1HSMDpOF3EqXSMN6OLrC/wMZ/dlIrF87l9Fc8vyqNhB7je3HNBAw==";
+
+	#[cfg(feature = "rs-crypto")]
+	const CA_KEY:&str = "AAAAC3NzaC1lZDI1NTE5AAAAILhBOXoxNr1u8vl6HDhU82A1LBXHAsCOIKRLIQmL8E4+";
+
+	#[cfg(feature = "rs-crypto")]
+	const CERTIFIED_KEY:&str = "AAAAC3NzaC1lZDI1NTE5AAAAINOdPGjjiGgMKrr8tfpPuJQ6+cokPbhoSaJoQYoi4nwG";
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_parse_certificate() {
+		use certificate::CertType;
+
+		let cert = parse_certificate_base64(ED25519_CERT).unwrap();
+
+		assert_eq!(cert.name(), "ssh-ed25519-cert-v01@openssh.com");
+
+		assert_eq!(cert.key, parse_public_key_base64(CERTIFIED_KEY).unwrap());
+
+		assert_eq!(cert.signature_key, parse_public_key_base64(CA_KEY).unwrap());
+
+		assert_eq!(cert.serial, 42);
+
+		assert_eq!(cert.cert_type, CertType::User);
+
+		assert_eq!(cert.key_id, "alice-cert");
+
+		assert_eq!(cert.valid_principals, ["alice", "bob"]);
+
+		assert_eq!((cert.valid_after, cert.valid_before), (0, u64::MAX));
+
+		assert!(cert.critical_options.is_empty());
+
+		assert!(cert.extensions.iter().any(|(name, _)| name == "permit-pty"));
+
+		assert_eq!(cert.public_key_base64(), ED25519_CERT);
+
+		let cert = parse_certificate_base64(ED25519_SOURCE_CERT).unwrap();
+
+		assert_eq!(cert.force_command(), Some("/bin/true"));
+
+		assert_eq!(
+			cert.critical_options,
+			[
+				("force-command".to_string(), "/bin/true".to_string()),
+				("source-address".to_string(), "10.0.0.0/8,::1".to_string())
+			]
+		);
+
+		// A plain public key is not a certificate.
+		assert!(parse_certificate_base64(CA_KEY).is_err());
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_validate_certificate() {
+		let ca = [parse_public_key_base64(CA_KEY).unwrap()];
+
+		let cert = parse_certificate_base64(ED25519_CERT).unwrap();
+
+		cert.validate(&ca, "alice", None).unwrap();
+
+		cert.validate(&ca, "bob", None).unwrap();
+
+		assert!(matches!(
+			cert.validate(&ca, "carol", None),
+			Err(Error::CertificateInvalidPrincipal(_))
+		));
+
+		// The certified key is not a CA.
+		assert!(matches!(
+			cert.validate(&[cert.key.clone()], "alice", None),
+			Err(Error::UntrustedCertificate)
+		));
+
+		// Tampering with the certificate breaks the CA signature.
+		let mut forged = cert.public_key_bytes();
+
+		let id = forged.windows(10).position(|w| w == b"alice-cert").unwrap();
+
+		forged[id] = b'A';
+
+		let forged = certificate::Certificate::parse(&forged).unwrap();
+
+		assert!(matches!(forged.validate(&ca, "alice", None), Err(Error::UntrustedCertificate)));
+
+		// 2023 is in the validity period, 2031 is not.
+		let cert = parse_certificate_base64(ED25519_SOURCE_CERT).unwrap();
+
+		let now = 1_700_000_000;
+
+		let from = |a:&str| Some(a.parse().unwrap());
+
+		cert.validate_at(&ca, "alice", from("10.1.2.3"), now).unwrap();
+
+		cert.validate_at(&ca, "alice", from("::1"), now).unwrap();
+
+		for address in [from("192.168.1.1"), from("11.0.0.1"), from("::2"), None] {
+			assert!(matches!(
+				cert.validate_at(&ca, "alice", address, now),
+				Err(Error::CertificateInvalidSourceAddress)
+			));
+		}
+
+		assert!(matches!(
+			cert.validate_at(&ca, "alice", from("10.1.2.3"), 1_930_000_000),
+			Err(Error::CertificateNotValidNow)
+		));
+	}
+
+	#[test]
+	fn test_check_known_hosts() {
+		env_logger::try_init().unwrap_or(());
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
 
 		let path = dir.path().join("known_hosts");
 		{
@@ -679,38 +1337,547 @@ QR+u0AypRPmzHnOPAAAAEXJvb3RAMTQwOTExNTQ5NDBkAQ==
 
 			let port = 13265;
 
-			let hostkey = parse_public_key_base64(
-				"AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
-			)
-			.unwrap();
+			let hostkey = parse_public_key_base64(
+				"AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
+			)
+			.unwrap();
+
+			assert!(check_known_hosts_path(host, port, &hostkey, &path).unwrap());
+
+			// Valid key, several hosts, port 22
+			let host = "pijul.org";
+
+			let port = 22;
+
+			let hostkey = parse_public_key_base64(
+				"AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
+			)
+			.unwrap();
+
+			assert!(check_known_hosts_path(host, port, &hostkey, &path).unwrap());
+
+			// Now with the key in a comment above, check that it's not
+			// recognized
+			let host = "pijul.org";
+
+			let port = 22;
+
+			let hostkey = parse_public_key_base64(
+				"AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G2sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
+			)
+			.unwrap();
+
+			assert!(check_known_hosts_path(host, port, &hostkey, &path).is_err());
+		}
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_known_hosts_markers() {
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let path = dir.path().join("known_hosts");
+
+		let key = |k| parse_public_key_base64(k).unwrap();
+
+		let ca = "AAAAC3NzaC1lZDI1NTE5AAAAILhBOXoxNr1u8vl6HDhU82A1LBXHAsCOIKRLIQmL8E4+";
+
+		let revoked = "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ";
+
+		let valid = "AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X";
+
+		std::fs::write(
+			&path,
+			format!(
+				"# pijul.org ssh-ed25519 {valid}\n@cert-authority *.example.com ssh-ed25519 \
+				 {ca}\n@revoked pijul.org ssh-ed25519 {revoked}\npijul.org ssh-ed25519 \
+				 {revoked}\npijul.org ssh-ed25519 {valid}\n"
+			),
+		)
+		.unwrap();
+
+		assert_eq!(
+			known_host_status_path("pijul.org", 22, &key(valid), &path).unwrap(),
+			KnownHostStatus::Match
+		);
+
+		// `@revoked` wins over the plain line for the same key.
+		assert_eq!(
+			known_host_status_path("pijul.org", 22, &key(revoked), &path).unwrap(),
+			KnownHostStatus::Revoked
+		);
+
+		assert!(matches!(
+			check_known_hosts_path("pijul.org", 22, &key(revoked), &path),
+			Err(Error::HostKeyRevoked)
+		));
+
+		assert!(matches!(
+			known_host_status_path("pijul.org", 22, &key(ca), &path),
+			Err(Error::KeyChanged { line }) if line == 4
+		));
+
+		// `@cert-authority` lines are not host keys.
+		assert_eq!(
+			known_host_status_path("host.example.com", 22, &key(ca), &path).unwrap(),
+			KnownHostStatus::NotFound
+		);
+
+		assert!(is_revoked_path(&key(revoked), &path).unwrap());
+
+		assert!(!is_revoked_path(&key(valid), &path).unwrap());
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_known_hosts_learn() {
+		use known_hosts::KnownHosts;
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let system = dir.path().join("ssh_known_hosts");
+
+		let user = dir.path().join("known_hosts");
+
+		let public_key = |pair:&key::KeyPair| pair.clone_public_key().unwrap();
+
+		let server = public_key(&key::KeyPair::generate_ed25519().unwrap());
+
+		let other = public_key(&key::KeyPair::generate_ed25519().unwrap());
+
+		let sk = match server {
+			key::PublicKey::Ed25519(ref key) => {
+				key::PublicKey::SkEd25519 { key:*key, application:"ssh:".to_string() }
+			},
+			#[allow(unreachable_patterns)]
+			_ => unreachable!(),
+		};
+
+		std::fs::write(&system, format!("pijul.org {}\n", other.public_key_base64())).unwrap();
+
+		// A key of another type, and no final newline.
+		std::fs::write(&user, format!("[::1]:2222 {} {}", sk.name(), sk.public_key_base64()))
+			.unwrap();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+
+			std::fs::set_permissions(&user, std::fs::Permissions::from_mode(0o640)).unwrap();
+		}
+
+		let known_hosts =
+			KnownHosts { files:vec![system.clone()], user_file:Some(user.clone()), hash:false };
+
+		assert_eq!(known_hosts.check("::1", 2222, &server).unwrap(), KnownHostStatus::NotFound);
+
+		known_hosts.learn("::1", 2222, &server).unwrap();
+
+		known_hosts.learn("pijul.org", 22, &server).unwrap();
+
+		let written = std::fs::read_to_string(&user).unwrap();
+
+		let sk_line = format!("[::1]:2222 {} {}\n", sk.name(), sk.public_key_base64());
+
+		assert!(written.starts_with(&format!("{}[::1]:2222 ssh-ed25519 ", sk_line)));
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+
+			let mode = std::fs::metadata(&user).unwrap().permissions().mode();
+
+			assert_eq!(mode & 0o777, 0o640);
+		}
+
+		// Reload, with hashed entries.
+		let mut known_hosts =
+			KnownHosts { files:vec![system], user_file:Some(user.clone()), hash:true };
+
+		assert_eq!(known_hosts.check("::1", 2222, &server).unwrap(), KnownHostStatus::Match);
+
+		assert_eq!(known_hosts.check("::1", 2222, &sk).unwrap(), KnownHostStatus::Match);
+
+		assert_eq!(known_hosts.check("::1", 22, &server).unwrap(), KnownHostStatus::NotFound);
+
+		// Recorded in one file, and another key of the same type in the other.
+		assert_eq!(known_hosts.check("pijul.org", 22, &server).unwrap(), KnownHostStatus::Match);
+
+		assert!(matches!(
+			known_hosts.check("::1", 2222, &other),
+			Err(Error::KeyChanged { line }) if line == 2
+		));
+
+		known_hosts.learn("nest.pijul.org", 22, &other).unwrap();
+
+		assert_eq!(
+			known_hosts.check("nest.pijul.org", 22, &other).unwrap(),
+			KnownHostStatus::Match
+		);
+
+		known_hosts.user_file = None;
+
+		assert!(matches!(
+			known_hosts.learn("nest.pijul.org", 22, &other),
+			Err(Error::NoKnownHostsFile)
+		));
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_check_known_hosts_hashed() {
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let path = dir.path().join("known_hosts");
+
+		// Hashed by OpenSSH (`ssh-keygen -H`).
+		std::fs::write(&path, include_str!("../../files/known_hosts/hashed")).unwrap();
+
+		let pijul = parse_public_key_base64(
+			"AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
+		)
+		.unwrap();
+
+		let localhost = parse_public_key_base64(
+			"AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
+		)
+		.unwrap();
+
+		assert!(check_known_hosts_path("pijul.org", 22, &pijul, &path).unwrap());
+
+		assert!(check_known_hosts_path("localhost", 13265, &localhost, &path).unwrap());
+
+		assert!(matches!(
+			check_known_hosts_path("pijul.org", 22, &localhost, &path),
+			Err(Error::KeyChanged { .. })
+		));
+
+		assert!(!check_known_hosts_path("localhost", 22, &localhost, &path).unwrap());
+
+		assert!(!check_known_hosts_path("nest.pijul.org", 22, &pijul, &path).unwrap());
+
+		learn_known_hosts_path_hashed("nest.pijul.org", 2222, &pijul, &path).unwrap();
+
+		let written = std::fs::read_to_string(&path).unwrap();
+
+		assert!(written.lines().all(|l| l.starts_with("|1|")));
+
+		assert!(check_known_hosts_path("nest.pijul.org", 2222, &pijul, &path).unwrap());
+
+		assert!(!check_known_hosts_path("nest.pijul.org", 22, &pijul, &path).unwrap());
+	}
+
+	// Signed by `CA_KEY` with `ssh-keygen -s ca -h -I host-cert -n host.example.com -z 7`.
+	#[cfg(feature = "rs-crypto")]
+	const HOST_CERT:&str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIFpnKvatdikJyAoguEMoWZ4zC0gURGeauqCcRqyaa55AAAAAICkSVwkj0MgFvfSBRFLjx3fghy3KZm9wYrat51LLuZTkAAAAAAAAAAcAAAACAAAACWhvc3QtY2VydAAAABQAAAAQaG9zdC5leGFtcGxlLmNvbQAAAAAAAAAA//////////8AAAAAAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAILhBOXoxNr1u8vl6HDhU82A1LBXHAsCOIKRLIQmL8E4+AAAAUwAAAAtzc2gtZWQyNTUxOQAAAEDGPJSfUyfmZS+l0ybEGA4AjzeWRcrGb93hLnwFYkKaa+BjnB1Nshkcef+eG8n+BUYzKpZhoaQlpJO9axw4NCoH";
+
+	// Same host key, signed with `-V 20000101:20010101`.
+	#[cfg(feature = "rs-crypto")]
+	const EXPIRED_HOST_CERT:&str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIMUNOjrGFqFNR2QL8/9bRAQt9GeqK9xlU8F5gvdnxlVPAAAAICkSVwkj0MgFvfSBRFLjx3fghy3KZm9wYrat51LLuZTkAAAAAAAAAAAAAAACAAAADGhvc3QtZXhwaXJlZAAAABQAAAAQaG9zdC5leGFtcGxlLmNvbQAAAAA4bUOAAAAAADpPyIAAAAAAAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAILhBOXoxNr1u8vl6HDhU82A1LBXHAsCOIKRLIQmL8E4+AAAAUwAAAAtzc2gtZWQyNTUxOQAAAEAE+bWW3NaJqtnZJTKZqfn4xEiN41OOqudVzSrwE+QtYTNsgDqsZjDuf521ZZaMnzwTW/OOLJC3vswRclcCSvoI";
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_check_known_hosts_certificate() {
+		env_logger::try_init().unwrap_or(());
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let path = dir.path().join("known_hosts");
+		{
+			let mut f = File::create(&path).unwrap();
+
+			write!(
+				f,
+				"# {ca}\nother.org ssh-ed25519 {ca}\n\
+				 @cert-authority *.example.com,!bad.example.com ssh-ed25519 {ca}\n",
+				ca = CA_KEY
+			)
+			.unwrap();
+		}
+
+		let cert = parse_certificate_base64(HOST_CERT).unwrap();
+
+		assert_eq!(cert.cert_type, certificate::CertType::Host);
+
+		assert!(check_known_hosts_certificate_path("host.example.com", 22, &cert, &path).unwrap());
+
+		// No CA for these hosts.
+		assert!(
+			!check_known_hosts_certificate_path("host.example.com", 2222, &cert, &path).unwrap()
+		);
+
+		assert!(!check_known_hosts_certificate_path("bad.example.com", 22, &cert, &path).unwrap());
+
+		assert!(!check_known_hosts_certificate_path("other.org", 22, &cert, &path).unwrap());
+
+		// `@cert-authority` lines are not host keys.
+		let ca = parse_public_key_base64(CA_KEY).unwrap();
+
+		assert!(!check_known_hosts_path("host.example.com", 22, &ca, &path).unwrap());
+
+		// Principal mismatch.
+		assert!(matches!(
+			check_known_hosts_certificate_path("www.example.com", 22, &cert, &path),
+			Err(Error::CertificateInvalidPrincipal(_))
+		));
+
+		let expired = parse_certificate_base64(EXPIRED_HOST_CERT).unwrap();
+
+		assert!(matches!(
+			check_known_hosts_certificate_path("host.example.com", 22, &expired, &path),
+			Err(Error::CertificateNotValidNow)
+		));
+
+		// User certificates are not host certificates.
+		let user_cert = parse_certificate_base64(ED25519_CERT).unwrap();
+
+		assert!(matches!(
+			user_cert.validate_host(&[ca.clone()], "alice"),
+			Err(Error::WrongCertificateType)
+		));
+
+		// Untrusted CA.
+		assert!(matches!(
+			cert.validate_host(&[cert.key.clone()], "host.example.com"),
+			Err(Error::UntrustedCertificate)
+		));
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_sk_ed25519() {
+		use encoding::Encoding;
+
+		let keypair = key::KeyPair::generate_ed25519().unwrap();
+
+		let public = match keypair {
+			key::KeyPair::Ed25519(ref k) => k.public,
+			#[allow(unreachable_patterns)]
+			_ => unreachable!(),
+		};
+
+		let mut blob = Vec::new();
+
+		blob.extend_ssh_string(b"sk-ssh-ed25519@openssh.com");
+
+		blob.extend_ssh_string(public.as_bytes());
+
+		blob.extend_ssh_string(b"ssh:");
+
+		let key = key::PublicKey::parse(b"sk-ssh-ed25519@openssh.com", &blob).unwrap();
+
+		assert_eq!(key, key::PublicKey::SkEd25519 { key:public, application:"ssh:".to_string() });
+
+		assert_eq!(key.name(), "sk-ssh-ed25519@openssh.com");
+
+		assert!(key.is_security_key());
+
+		assert_eq!(key.public_key_bytes(), blob);
+
+		assert_eq!(parse_public_key_base64(&key.public_key_base64()).unwrap(), key);
+
+		// A software "security key": sign what the token would sign.
+		let message = b"session id and userauth request";
+
+		let signed = key::sk_signed_data("ssh:", 1, 42, message);
+
+		let sig = match keypair.sign_detached(&signed).unwrap() {
+			signature::Signature::Ed25519(bytes) => bytes.0,
+			#[allow(unreachable_patterns)]
+			_ => unreachable!(),
+		};
+
+		assert!(key.verify_sk_detached(message, &sig, 1, 42));
+
+		assert!(!key.verify_sk_detached(message, &sig, 0, 42));
+
+		assert!(!key.verify_sk_detached(message, &sig, 1, 43));
+
+		assert!(!key.verify_detached(message, &sig));
+
+		// Plain Ed25519 keys are not security keys.
+		assert!(!key::PublicKey::Ed25519(public).verify_sk_detached(message, &sig, 1, 42));
+	}
+
+	#[cfg(feature = "rs-crypto")]
+	fn check_ppk(unencrypted:[&str; 2], encrypted:[&str; 2], public:&str) {
+		let public = parse_public_key_base64(public).unwrap();
+
+		for ppk in unencrypted {
+			let key = decode_ppk(ppk.as_bytes(), None).unwrap();
+
+			assert_eq!(key.clone_public_key().unwrap(), public);
+
+			let key = decode_secret_key(ppk, None).unwrap();
+
+			assert_eq!(key.clone_public_key().unwrap(), public);
+
+			let corrupt = ppk.replace("Comment: ", "Comment: x");
+
+			assert!(matches!(decode_ppk(corrupt.as_bytes(), None), Err(Error::KeyIsCorrupt)));
+		}
+
+		for ppk in encrypted {
+			let key = decode_ppk(ppk.as_bytes(), Some("blabla")).unwrap();
+
+			assert_eq!(key.clone_public_key().unwrap(), public);
+
+			assert!(matches!(decode_ppk(ppk.as_bytes(), None), Err(Error::KeyIsEncrypted)));
+
+			assert!(matches!(
+				decode_ppk(ppk.as_bytes(), Some("blablo")),
+				Err(Error::WrongPassphrase)
+			));
+		}
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_decode_ppk_ed25519() {
+		check_ppk(
+			[
+				include_str!("../../files/ppk/ed25519-v2.ppk"),
+				include_str!("../../files/ppk/ed25519-v3.ppk"),
+			],
+			[
+				include_str!("../../files/ppk/ed25519-v2-enc.ppk"),
+				include_str!("../../files/ppk/ed25519-v3-enc.ppk"),
+			],
+			"AAAAC3NzaC1lZDI1NTE5AAAAINH5wheIQosstHwya8XTqB/jDpyAFgMcWG7F48GFNA91",
+		);
+	}
+
+	#[test]
+	#[cfg(all(feature = "rs-crypto", feature = "openssl"))]
+	fn test_decode_ppk_rsa() {
+		check_ppk(
+			[
+				include_str!("../../files/ppk/rsa-v2.ppk"),
+				include_str!("../../files/ppk/rsa-v3.ppk"),
+			],
+			[
+				include_str!("../../files/ppk/rsa-v2-enc.ppk"),
+				include_str!("../../files/ppk/rsa-v3-enc.ppk"),
+			],
+			"AAAAB3NzaC1yc2EAAAADAQABAAAAgQCnV9Yym4Kg8edZec4QCYXbUcGJyAUhhVVwq/4erLV+ED6KYVEm1Rqe89eRciLL7seoc/FG/q7539ZPGJxMEf8IdowulI+07AgIJUzkBuQn0Ze931E22DM3HpA8o9eXiqcBrWJ0aXXlg7Q0b8Rqzj3J/Mcg0AWU6lZ1s8jBO1bp0w==",
+		);
+	}
+
+	#[cfg(feature = "rs-crypto")]
+	fn check_encrypted(plain:&str, encrypted:&[&str]) {
+		let public = decode_secret_key(plain, None).unwrap().clone_public_key().unwrap();
+
+		// Unencrypted keys ignore the password.
+		let key = decode_secret_key(plain, Some("blabla")).unwrap();
+
+		assert_eq!(key.clone_public_key().unwrap(), public);
+
+		for secret in encrypted {
+			let key = decode_secret_key(secret, Some("blabla")).unwrap();
+
+			assert_eq!(key.clone_public_key().unwrap(), public);
+
+			assert!(matches!(decode_secret_key(secret, None), Err(Error::KeyIsEncrypted)));
+
+			assert!(matches!(
+				decode_secret_key(secret, Some("blablo")),
+				Err(Error::WrongPassphrase)
+			));
+		}
+	}
+
+	// Generated with `openssl genpkey`, and encrypted with `openssl pkcs8
+	// -topk8 -v2 <cipher> [-v2prf <prf> | -scrypt]` or `openssl rsa
+	// -traditional -<cipher>`, with passphrase "blabla".
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_decode_encrypted_pkcs8_ed25519() {
+		check_encrypted(include_str!("../../files/pkcs8/ed.pem"), &[
+			include_str!("../../files/pkcs8/ed-aes128-sha1.p8"),
+			include_str!("../../files/pkcs8/ed-aes256-sha256.p8"),
+			include_str!("../../files/pkcs8/ed-aes256-scrypt.p8"),
+		]);
+	}
+
+	#[test]
+	#[cfg(all(feature = "rs-crypto", feature = "openssl"))]
+	fn test_decode_encrypted_rsa() {
+		check_encrypted(include_str!("../../files/pkcs8/rsa.pem"), &[
+			include_str!("../../files/pkcs8/rsa-aes128-sha1.p8"),
+			include_str!("../../files/pkcs8/rsa-aes256-sha256.p8"),
+			include_str!("../../files/pkcs8/rsa-aes256-scrypt.p8"),
+			include_str!("../../files/pkcs8/rsa-aes128.pem"),
+			include_str!("../../files/pkcs8/rsa-aes256.pem"),
+		]);
+
+		assert!(matches!(
+			decode_secret_key(include_str!("../../files/pkcs8/rsa-des3.pem"), Some("blabla")),
+			Err(Error::UnsupportedCipher(_))
+		));
+	}
+
+	#[test]
+	fn test_generate_openssh() {
+		#[allow(unused_mut)]
+		let mut keys = Vec::new();
+
+		#[cfg(feature = "rs-crypto")]
+		keys.push(key::KeyPair::generate_ed25519().unwrap());
+
+		#[cfg(feature = "openssl")]
+		{
+			use key::{KeyPair, SignatureHash};
+
+			assert!(KeyPair::generate_rsa(1024, SignatureHash::SHA2_256).is_none());
+
+			keys.push(KeyPair::generate_rsa(2048, SignatureHash::SHA2_256).unwrap());
+
+			keys.push(KeyPair::generate_rsa(4096, SignatureHash::SHA2_512).unwrap());
+		}
+
+		for key in keys {
+			let mut pem = Vec::new();
+
+			encode_openssh_pem(&key, "generated", &mut pem).unwrap();
 
-			assert!(check_known_hosts_path(host, port, &hostkey, &path).unwrap());
+			let decoded = decode_secret_key(std::str::from_utf8(&pem).unwrap(), None).unwrap();
 
-			// Valid key, several hosts, port 22
-			let host = "pijul.org";
+			assert_eq!(decoded.clone_public_key().unwrap(), key.clone_public_key().unwrap());
+		}
+	}
 
-			let port = 22;
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_encode_encrypted_openssh() {
+		#[allow(unused_mut)]
+		let mut keys = vec![key::KeyPair::generate_ed25519().unwrap()];
 
-			let hostkey = parse_public_key_base64(
-				"AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
-			)
-			.unwrap();
+		#[cfg(feature = "openssl")]
+		keys.push(key::KeyPair::generate_rsa(2048, key::SignatureHash::SHA2_256).unwrap());
 
-			assert!(check_known_hosts_path(host, port, &hostkey, &path).unwrap());
+		for key in keys {
+			let pem = encode_openssh_private_key(&key, "encrypted", Some("blabla"), 4).unwrap();
 
-			// Now with the key in a comment above, check that it's not
-			// recognized
-			let host = "pijul.org";
+			let pem = std::str::from_utf8(&pem).unwrap();
 
-			let port = 22;
+			assert!(matches!(decode_secret_key(pem, None), Err(Error::KeyIsEncrypted)));
 
-			let hostkey = parse_public_key_base64(
-				"AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G2sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
-			)
-			.unwrap();
+			let decoded = decode_secret_key(pem, Some("blabla")).unwrap();
 
-			assert!(check_known_hosts_path(host, port, &hostkey, &path).is_err());
+			assert_eq!(decoded.clone_public_key().unwrap(), key.clone_public_key().unwrap());
 		}
+
+		let key = key::KeyPair::generate_ed25519().unwrap();
+
+		assert!(matches!(
+			encode_openssh_private_key(&key, "", Some("blabla"), 0),
+			Err(Error::InvalidKdfRounds)
+		));
+
+		// An empty passphrase leaves the key unencrypted, as with `ssh-keygen`.
+		let pem = encode_openssh_private_key(&key, "", Some(""), DEFAULT_KDF_ROUNDS).unwrap();
+
+		decode_secret_key(std::str::from_utf8(&pem).unwrap(), None).unwrap();
 	}
 
 	#[test]
@@ -968,6 +2135,220 @@ Cog3JMeTrb3LiPHgN6gU2P30MRp6L1j1J/MtlOAr5rux
 		decode_secret_key(PKCS8_ENCRYPTED, Some("blabla")).unwrap();
 	}
 
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_authorized_keys() {
+		use std::{
+			net::IpAddr,
+			time::{Duration, UNIX_EPOCH},
+		};
+
+		use authorized_keys::{AuthorizedKey, parse_authorized_keys};
+
+		let keys = parse_authorized_keys(include_str!("../../files/authorized_keys/options"));
+
+		let comments = keys.iter().map(|k| k.comment.as_str()).collect::<Vec<_>>();
+
+		// RSA keys need OpenSSL, and broken lines are skipped.
+		if cfg!(feature = "openssl") {
+			assert_eq!(comments, ["alice@laptop", "backup@db1", "carol", "user CA", "dave"]);
+		} else {
+			assert_eq!(comments, ["alice@laptop", "backup@db1", "user CA", "dave"]);
+		}
+
+		let find = |comment:&str| keys.iter().find(|k| k.comment == comment).unwrap();
+
+		let addr = |s:&str| s.parse::<IpAddr>().unwrap();
+
+		let alice = &find("alice@laptop").options;
+
+		assert_eq!(*alice, authorized_keys::Options::default());
+
+		assert!(alice.allows_pty() && alice.allows_agent_forwarding());
+
+		assert!(alice.from_allows(addr("192.0.2.1"), None));
+
+		let backup = &find("backup@db1").options;
+
+		assert_eq!(backup.command.as_deref(), Some("echo \"backup\" && /usr/bin/rsync --server"));
+
+		assert!(!backup.allows_pty() && backup.allows_port_forwarding());
+
+		assert!(backup.from_allows(addr("10.1.2.3"), None));
+
+		assert!(!backup.from_allows(addr("10.0.0.1"), Some("db1.example.com")));
+
+		assert!(!backup.from_allows(addr("192.0.2.1"), None));
+
+		assert!(backup.from_allows(addr("192.0.2.1"), Some("db1.example.com")));
+
+		assert!(!backup.from_allows(addr("::1"), Some("example.org")));
+
+		let ca = &find("user CA").options;
+
+		assert!(ca.cert_authority);
+
+		assert_eq!(ca.principals, Some(vec!["alice".to_string(), "admin".to_string()]));
+
+		let dave = &find("dave").options;
+
+		assert_eq!(dave.permit_open, ["db.internal:5432"]);
+
+		assert_eq!(dave.permit_listen, ["localhost:8080"]);
+
+		assert_eq!(dave.tunnel.as_deref(), Some("0"));
+
+		assert!(!dave.allows_agent_forwarding() && !dave.allows_port_forwarding());
+
+		assert!(!dave.allows_x11_forwarding() && !dave.allows_user_rc() && dave.allows_pty());
+
+		#[cfg(feature = "openssl")]
+		{
+			let carol = &find("carol").options;
+
+			assert!(carol.restrict && carol.allows_pty() && !carol.allows_port_forwarding());
+
+			assert_eq!(carol.environment, [("LANG".to_string(), "C.UTF-8".to_string())]);
+
+			let expiry = UNIX_EPOCH + Duration::from_secs(1893456000);
+
+			assert_eq!(carol.expiry_time, Some(expiry));
+
+			assert!(carol.is_expired(expiry) && !carol.is_expired(expiry - Duration::from_secs(1)));
+		}
+
+		// Options are case-insensitive, and the earliest expiry time wins.
+		let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMVqfp7mJeglR3NqbQ8Sk/TedLLWZOFLpP06UAf3XHDp";
+
+		let line = format!(
+			"No-PTY,expiry-time=\"20300101\",expiry-time=\"202001021530\" {key} two  words"
+		);
+
+		let parsed = line.parse::<AuthorizedKey>().unwrap();
+
+		assert_eq!(parsed.comment, "two words");
+
+		assert_eq!(parsed.options.pty, Some(false));
+
+		assert_eq!(parsed.options.expiry_time, Some(UNIX_EPOCH + Duration::from_secs(1577979000)));
+
+		for options in ["no-pty=\"yes\"", "command", "expiry-time=\"2030\"", "pty,,no-pty"] {
+			assert!(format!("{options} {key}").parse::<AuthorizedKey>().is_err());
+		}
+	}
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	fn test_sshsig() {
+		use std::process::{Command, Stdio};
+
+		use sshsig::{SshSig, parse_allowed_signers};
+
+		let message = include_bytes!("../../files/sshsig/message");
+
+		let allowed = parse_allowed_signers(include_str!("../../files/sshsig/allowed_signers"));
+
+		// The ECDSA line and the line with an unknown option are skipped.
+		let principals = allowed.iter().map(|s| s.principals.join(",")).collect::<Vec<_>>();
+
+		if cfg!(feature = "openssl") {
+			assert_eq!(principals, [
+				"alice@example.com",
+				"bob@example.com,releases@*",
+				"old@example.com"
+			]);
+		} else {
+			assert_eq!(principals, ["alice@example.com", "old@example.com"]);
+		}
+
+		// Made by `ssh-keygen -Y sign -n file`.
+		let ed25519_sig = include_str!("../../files/sshsig/message.ed25519.sig");
+
+		let alice = parse_public_key_base64(
+			include_str!("../../files/sshsig/id_ed25519.pub").split_whitespace().nth(1).unwrap(),
+		)
+		.unwrap();
+
+		let verify = |principal:&str, namespace:&str, message:&[u8], sig:&str| {
+			sshsig::verify(&allowed, principal, namespace, message, sig)
+		};
+
+		assert_eq!(verify("alice@example.com", "file", message, ed25519_sig).unwrap(), alice);
+
+		assert!(matches!(
+			verify("alice@example.com", "git", message, ed25519_sig),
+			Err(Error::SshSigNamespace(n)) if n == "file"
+		));
+
+		assert!(matches!(
+			verify("alice@example.com", "file", b"tampered", ed25519_sig),
+			Err(Error::BadSshSig)
+		));
+
+		// Not alice's principal, and expired.
+		for principal in ["bob@example.com", "old@example.com"] {
+			assert!(matches!(
+				verify(principal, "file", message, ed25519_sig),
+				Err(Error::SshSigNotAllowed(_))
+			));
+		}
+
+		#[cfg(feature = "openssl")]
+		{
+			let rsa_sig = include_str!("../../files/sshsig/message.rsa.sig");
+
+			let parsed = SshSig::parse(rsa_sig).unwrap();
+
+			assert_eq!(parsed.hash_algorithm, sshsig::HashAlgorithm::Sha512);
+
+			assert!(verify("releases@example.org", "file", message, rsa_sig).is_ok());
+
+			assert!(verify("alice@example.com", "file", message, rsa_sig).is_err());
+		}
+
+		// Our signatures can be checked by ssh-keygen.
+		let mut keys = vec![("alice@example.com", include_str!("../../files/sshsig/id_ed25519"))];
+
+		if cfg!(feature = "openssl") {
+			keys.push(("bob@example.com", include_str!("../../files/sshsig/id_rsa")));
+		}
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let allowed_signers = dir.path().join("allowed_signers");
+
+		std::fs::write(&allowed_signers, include_str!("../../files/sshsig/allowed_signers"))
+			.unwrap();
+
+		for (principal, key) in keys {
+			let key = decode_secret_key(key, None).unwrap();
+
+			let sig = sshsig::sign(&key, "file", &message[..]).unwrap();
+
+			assert_eq!(SshSig::parse(&sig).unwrap().to_armored(), sig);
+
+			assert!(verify(principal, "file", message, &sig).is_ok());
+
+			let sig_path = dir.path().join("message.sig");
+
+			std::fs::write(&sig_path, &sig).unwrap();
+
+			let mut child = Command::new("ssh-keygen")
+				.args(["-Y", "verify", "-n", "file", "-I", principal, "-f"])
+				.arg(&allowed_signers)
+				.arg("-s")
+				.arg(&sig_path)
+				.stdin(Stdio::piped())
+				.stdout(Stdio::null())
+				.spawn()
+				.expect("failed to execute ssh-keygen");
+
+			std::io::Write::write_all(&mut child.stdin.take().unwrap(), message).unwrap();
+
+			assert!(child.wait().unwrap().success());
+		}
+	}
+
 	#[cfg(unix)]
 	fn test_client_agent(key:key::KeyPair) {
 		env_logger::try_init().unwrap_or(());
@@ -1057,6 +2438,128 @@ Cog3JMeTrb3LiPHgN6gU2P30MRp6L1j1J/MtlOAr5rux
 		test_client_agent(key)
 	}
 
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	#[cfg(unix)]
+	fn test_client_agent_constraints() {
+		env_logger::try_init().unwrap_or(());
+
+		use std::process::{Command, Stdio};
+
+		use agent::Constraint;
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let agent_path = dir.path().join("agent");
+
+		let mut agent = Command::new("ssh-agent")
+			.arg("-a")
+			.arg(&agent_path)
+			.arg("-D")
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.expect("failed to execute process");
+
+		std::thread::sleep(std::time::Duration::from_millis(10));
+
+		let rt = tokio::runtime::Runtime::new().unwrap();
+
+		rt.block_on(async move {
+			let key = decode_secret_key(ED25519_KEY, Some("blabla"))?;
+
+			let public = key.clone_public_key()?;
+
+			let stream = tokio::net::UnixStream::connect(&agent_path).await?;
+
+			let mut client = agent::client::AgentClient::connect(stream);
+
+			let constraints = [Constraint::KeyLifetime { seconds:1 }, Constraint::Confirm];
+
+			client.add_identity_constrained(&key, "deploy key", &constraints).await?;
+
+			assert_eq!(client.request_identities().await?, vec![public.clone()]);
+
+			// A locked agent lists no keys.
+			client.lock(b"passphrase").await?;
+
+			assert!(client.request_identities().await?.is_empty());
+
+			assert!(matches!(client.unlock(b"wrong").await, Err(Error::AgentFailure)));
+
+			client.unlock(b"passphrase").await?;
+
+			assert_eq!(client.request_identities().await?.len(), 1);
+
+			// The key disappears after its lifetime.
+			tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+
+			assert!(client.request_identities().await?.is_empty());
+
+			client.add_identity(&key, &[]).await?;
+
+			client.remove_identity(&public).await?;
+
+			assert!(matches!(client.remove_identity(&public).await, Err(Error::AgentFailure)));
+
+			client.add_identity(&key, &[]).await?;
+
+			client.remove_all_identities().await?;
+
+			assert!(client.request_identities().await?.is_empty());
+
+			Ok::<(), Error>(())
+		})
+		.unwrap();
+
+		agent.kill().unwrap();
+
+		agent.wait().unwrap();
+	}
+
+	#[test]
+	#[cfg(windows)]
+	fn test_client_agent_named_pipe() {
+		use tokio::{
+			io::{AsyncReadExt, AsyncWriteExt},
+			net::windows::named_pipe::ServerOptions,
+		};
+
+		let rt = tokio::runtime::Runtime::new().unwrap();
+
+		rt.block_on(async move {
+			let pipe = format!(r"\\.\pipe\russh-agent-{}", std::process::id());
+
+			let mut server = ServerOptions::new().first_pipe_instance(true).create(&pipe)?;
+
+			// Answer one `REQUEST_IDENTITIES` with an empty list.
+			let agent = tokio::spawn(async move {
+				server.connect().await?;
+
+				let mut len = [0; 4];
+
+				server.read_exact(&mut len).await?;
+
+				let mut request = vec![0; u32::from_be_bytes(len) as usize];
+
+				server.read_exact(&mut request).await?;
+
+				assert_eq!(request, [11]);
+
+				server.write_all(&[0, 0, 0, 5, 12, 0, 0, 0, 0]).await
+			});
+
+			let mut client = agent::client::AgentClient::connect_named_pipe(&pipe).await?;
+
+			assert!(client.request_identities().await?.is_empty());
+
+			agent.await.unwrap()?;
+
+			Ok::<(), Error>(())
+		})
+		.unwrap();
+	}
+
 	#[test]
 	#[cfg(unix)]
 	#[cfg(feature = "openssl")]
@@ -1129,6 +2632,82 @@ Cog3JMeTrb3LiPHgN6gU2P30MRp6L1j1J/MtlOAr5rux
 		.unwrap()
 	}
 
+	#[test]
+	#[cfg(unix)]
+	#[cfg(feature = "rs-crypto")]
+	fn test_agent_forward_filter() {
+		env_logger::try_init().unwrap_or(());
+
+		use agent::forward::{Decision, SignRequest};
+
+		let dir = tempdir::TempDir::new("russh").unwrap();
+
+		let agent_path = dir.path().join("agent");
+
+		let core = tokio::runtime::Runtime::new().unwrap();
+
+		let agent_path_ = agent_path.clone();
+
+		core.spawn(async move {
+			let mut listener = tokio::net::UnixListener::bind(&agent_path_).unwrap();
+
+			agent::server::serve(Incoming { listener:&mut listener }, ()).await
+		});
+
+		core.block_on(async move {
+			let permitted = decode_secret_key(ED25519_KEY, Some("blabla"))?;
+
+			let other = decode_secret_key(ED25519_AESCTR_KEY, Some("test"))?;
+
+			let permitted_public = permitted.clone_public_key()?;
+
+			let other_public = other.clone_public_key()?;
+
+			let stream = tokio::net::UnixStream::connect(&agent_path).await?;
+
+			let mut local = agent::client::AgentClient::connect(stream);
+
+			local.add_identity(&permitted, &[]).await?;
+
+			local.add_identity(&other, &[]).await?;
+
+			// The forwarded channel.
+			let (channel, remote) = tokio::io::duplex(4096);
+
+			let only = permitted_public.clone();
+
+			tokio::spawn(async move {
+				let filter = move |key:&key::PublicKey, _:&SignRequest| {
+					if *key == only { Decision::Allow } else { Decision::Deny }
+				};
+
+				agent::forward::forward(channel, &mut local, &filter).await
+			});
+
+			let mut remote = agent::client::AgentClient::connect(remote);
+
+			assert_eq!(remote.request_identities().await?.len(), 2);
+
+			let data = russh_cryptovec::CryptoVec::from_slice(b"blabla");
+
+			let (remote, sig) = remote.sign_request(&permitted_public, data.clone()).await;
+
+			assert!(sig?.len() > data.len());
+
+			// A denied request fails without closing the channel.
+			let (remote, sig) = remote.sign_request(&other_public, data.clone()).await;
+
+			assert!(matches!(sig, Err(Error::AgentFailure)));
+
+			let (_, sig) = remote.sign_request(&permitted_public, data.clone()).await;
+
+			assert!(sig?.len() > data.len());
+
+			Ok::<(), Error>(())
+		})
+		.unwrap()
+	}
+
 	#[cfg(unix)]
 	struct Incoming<'a> {
 		listener:&'a mut tokio::net::UnixListener,
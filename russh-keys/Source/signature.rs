@@ -20,25 +20,35 @@ pub struct SignatureBytes(pub [u8; 64]);
 pub enum Signature {
 	/// An Ed25519 signature
 	Ed25519(SignatureBytes),
+	/// An Ed25519 signature made by a FIDO security key, with the flags
+	/// and counter it signed
+	SkEd25519 { bytes:SignatureBytes, flags:u8, counter:u32 },
 	/// An RSA signature
 	RSA { hash:SignatureHash, bytes:Vec<u8> },
 }
 
 impl Signature {
-	pub fn to_base64(&self) -> String {
+	/// The signature blob, as sent in the SSH protocol: the algorithm name
+	/// followed by the signature itself.
+	pub fn to_bytes(&self) -> Vec<u8> {
 		use crate::encoding::Encoding;
 
 		let mut bytes_ = Vec::new();
 
 		match self {
 			Signature::Ed25519(ref bytes) => {
-				let t = b"ssh-ed25519";
-				#[allow(clippy::unwrap_used)] // Vec<>.write_all can't fail
-				bytes_.write_u32::<BigEndian>((t.len() + bytes.0.len() + 8) as u32).unwrap();
+				bytes_.extend_ssh_string(b"ssh-ed25519");
 
-				bytes_.extend_ssh_string(t);
+				bytes_.extend_ssh_string(&bytes.0[..]);
+			},
+			Signature::SkEd25519 { ref bytes, flags, counter } => {
+				bytes_.extend_ssh_string(b"sk-ssh-ed25519@openssh.com");
 
 				bytes_.extend_ssh_string(&bytes.0[..]);
+
+				bytes_.push(*flags);
+
+				bytes_.extend_from_slice(&counter.to_be_bytes());
 			},
 			Signature::RSA { ref hash, ref bytes } => {
 				let t = match hash {
@@ -46,8 +56,6 @@ impl Signature {
 					SignatureHash::SHA2_512 => &b"rsa-sha2-512"[..],
 					SignatureHash::SHA1 => &b"ssh-rsa"[..],
 				};
-				#[allow(clippy::unwrap_used)] // Vec<>.write_all can't fail
-				bytes_.write_u32::<BigEndian>((t.len() + bytes.len() + 8) as u32).unwrap();
 
 				bytes_.extend_ssh_string(t);
 
@@ -55,6 +63,18 @@ impl Signature {
 			},
 		}
 
+		bytes_
+	}
+
+	pub fn to_base64(&self) -> String {
+		let bytes = self.to_bytes();
+
+		let mut bytes_ = Vec::new();
+		#[allow(clippy::unwrap_used)] // Vec<>.write_all can't fail
+		bytes_.write_u32::<BigEndian>(bytes.len() as u32).unwrap();
+
+		bytes_.extend_from_slice(&bytes);
+
 		data_encoding::BASE64_NOPAD.encode(&bytes_[..])
 	}
 
@@ -65,7 +85,12 @@ impl Signature {
 
 		let mut r = bytes_.reader(0);
 
-		let sig = r.read_string()?;
+		Self::from_bytes(r.read_string()?)
+	}
+
+	/// Parse a signature blob, as returned by [`Signature::to_bytes`].
+	pub fn from_bytes(sig:&[u8]) -> Result<Self, Error> {
+		use crate::encoding::Reader;
 
 		let mut r = sig.reader(0);
 
@@ -73,13 +98,28 @@ impl Signature {
 
 		let bytes = r.read_string()?;
 
+		let ed25519_bytes = || -> Result<SignatureBytes, Error> {
+			let mut bytes_ = [0; 64];
+
+			if bytes.len() != bytes_.len() {
+				return Err(Error::KeyIsCorrupt);
+			}
+
+			bytes_.clone_from_slice(bytes);
+
+			Ok(SignatureBytes(bytes_))
+		};
+
 		match typ {
-			b"ssh-ed25519" => {
-				let mut bytes_ = [0; 64];
+			b"ssh-ed25519" => Ok(Signature::Ed25519(ed25519_bytes()?)),
+			b"sk-ssh-ed25519@openssh.com" => {
+				let bytes = ed25519_bytes()?;
+
+				let flags = r.read_byte()?;
 
-				bytes_.clone_from_slice(bytes);
+				let counter = r.read_u32()?;
 
-				Ok(Signature::Ed25519(SignatureBytes(bytes_)))
+				Ok(Signature::SkEd25519 { bytes, flags, counter })
 			},
 			b"rsa-sha2-256" => {
 				Ok(Signature::RSA { hash:SignatureHash::SHA2_256, bytes:bytes.to_vec() })
@@ -101,6 +141,7 @@ impl AsRef<[u8]> for Signature {
 	fn as_ref(&self) -> &[u8] {
 		match *self {
 			Signature::Ed25519(ref signature) => &signature.0,
+			Signature::SkEd25519 { ref bytes, .. } => &bytes.0,
 			Signature::RSA { ref bytes, .. } => &bytes[..],
 		}
 	}
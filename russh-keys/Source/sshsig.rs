@@ -0,0 +1,482 @@
+//! SSH signatures of files or messages, as made by `ssh-keygen -Y sign`
+//! and checked by `ssh-keygen -Y verify`, in the format described in
+//! OpenSSH's `PROTOCOL.sshsig`.
+//!
+//! A signature is bound to a namespace (such as `file` or `git`), so
+//! that it can't be reused for another purpose, and verified against an
+//! allowed signers file mapping principals to keys:
+//!
+//! ```text
+//! alice@example.com namespaces="file,git" ssh-ed25519 AAAA... alice
+//! "bob@example.com,releases@*" valid-before="20300101" ssh-rsa AAAA... bob
+//! ```
+//!
+//! ECDSA keys are not supported.
+
+use std::{
+	fs::File,
+	io::Read,
+	path::Path,
+	str::FromStr,
+	time::SystemTime,
+};
+
+use data_encoding::{BASE64, BASE64_MIME};
+use log::debug;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+	Error,
+	PublicKeyBase64,
+	authorized_keys::{KEY_TYPES, parse_timespec, quoted},
+	encoding::{Encoding, Reader},
+	key::{self, KeyPair, SignatureHash},
+	signature::Signature,
+	wildcard_matches,
+};
+
+/// The magic string starting signatures and the data they sign.
+pub const MAGIC:&[u8] = b"SSHSIG";
+
+const VERSION:u32 = 1;
+
+const BEGIN:&str = "-----BEGIN SSH SIGNATURE-----";
+
+const END:&str = "-----END SSH SIGNATURE-----";
+
+/// The length of the base64 lines of armored signatures.
+const LINE_LEN:usize = 70;
+
+/// The hash of the signed message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+	/// SHA-256
+	Sha256,
+	/// SHA-512, used by ssh-keygen
+	Sha512,
+}
+
+impl HashAlgorithm {
+	/// The name of this algorithm in signatures.
+	pub fn name(&self) -> &'static str {
+		match self {
+			HashAlgorithm::Sha256 => "sha256",
+			HashAlgorithm::Sha512 => "sha512",
+		}
+	}
+
+	fn from_name(name:&[u8]) -> Option<Self> {
+		match name {
+			b"sha256" => Some(HashAlgorithm::Sha256),
+			b"sha512" => Some(HashAlgorithm::Sha512),
+			_ => None,
+		}
+	}
+
+	/// Hash everything `reader` returns.
+	fn digest<R:Read>(&self, mut reader:R) -> Result<Vec<u8>, Error> {
+		let mut sha256 = Sha256::new();
+
+		let mut sha512 = Sha512::new();
+
+		let mut buf = [0; 8192];
+
+		loop {
+			let n = reader.read(&mut buf)?;
+
+			let data = match buf.get(..n) {
+				Some(data) if n > 0 => data,
+				_ => break,
+			};
+
+			match self {
+				HashAlgorithm::Sha256 => sha256.update(data),
+				HashAlgorithm::Sha512 => sha512.update(data),
+			}
+		}
+
+		Ok(match self {
+			HashAlgorithm::Sha256 => sha256.finalize().to_vec(),
+			HashAlgorithm::Sha512 => sha512.finalize().to_vec(),
+		})
+	}
+}
+
+/// A parsed SSH signature.
+#[derive(Clone)]
+pub struct SshSig {
+	/// The key that made the signature.
+	pub public_key:key::PublicKey,
+	/// The namespace the signature is bound to.
+	pub namespace:String,
+	/// The hash of the signed message.
+	pub hash_algorithm:HashAlgorithm,
+	/// The signature of the message hash.
+	pub signature:Signature,
+}
+
+impl SshSig {
+	/// Parse an armored signature, as written by `ssh-keygen -Y sign`.
+	pub fn parse(armored:&str) -> Result<Self, Error> {
+		let armored = armored.trim();
+
+		let body = armored
+			.strip_prefix(BEGIN)
+			.and_then(|s| s.strip_suffix(END))
+			.ok_or(Error::InvalidSshSig)?;
+
+		let blob = BASE64.decode(body.split_whitespace().collect::<String>().as_bytes())?;
+
+		let mut r = blob.strip_prefix(MAGIC).ok_or(Error::InvalidSshSig)?.reader(0);
+
+		if r.read_u32()? != VERSION {
+			return Err(Error::InvalidSshSig);
+		}
+
+		let public_key = key::parse_public_key(
+			r.read_string()?,
+			#[cfg(feature = "openssl")]
+			None,
+		)?;
+
+		let namespace =
+			std::str::from_utf8(r.read_string()?).map_err(|_| Error::InvalidSshSig)?.to_string();
+
+		// Reserved.
+		r.read_string()?;
+
+		let hash_algorithm =
+			HashAlgorithm::from_name(r.read_string()?).ok_or(Error::InvalidSshSig)?;
+
+		let signature = Signature::from_bytes(r.read_string()?)?;
+
+		Ok(SshSig { public_key, namespace, hash_algorithm, signature })
+	}
+
+	/// Write this signature in the armored format.
+	pub fn to_armored(&self) -> String {
+		let mut blob = MAGIC.to_vec();
+
+		blob.extend_from_slice(&VERSION.to_be_bytes());
+
+		blob.extend_ssh_string(&self.public_key.public_key_bytes());
+
+		blob.extend_ssh_string(self.namespace.as_bytes());
+
+		blob.extend_ssh_string(b"");
+
+		blob.extend_ssh_string(self.hash_algorithm.name().as_bytes());
+
+		blob.extend_ssh_string(&self.signature.to_bytes());
+
+		let mut armored = format!("{}\n", BEGIN);
+
+		for (i, c) in BASE64.encode(&blob).chars().enumerate() {
+			if i > 0 && i % LINE_LEN == 0 {
+				armored.push('\n');
+			}
+
+			armored.push(c);
+		}
+
+		armored.push('\n');
+
+		armored.push_str(END);
+
+		armored.push('\n');
+
+		armored
+	}
+
+	/// Check that this is a signature of the message read from `reader`,
+	/// in `namespace`, by [`SshSig::public_key`]. Whether this key may
+	/// sign is up to the caller; see [`verify`].
+	pub fn verify_message<R:Read>(&self, namespace:&str, reader:R) -> Result<(), Error> {
+		if namespace.is_empty() || self.namespace != namespace {
+			return Err(Error::SshSigNamespace(self.namespace.clone()));
+		}
+
+		let digest = self.hash_algorithm.digest(reader)?;
+
+		let signed = signed_data(namespace, self.hash_algorithm, &digest);
+
+		let mut public_key = self.public_key.clone();
+
+		let valid = match self.signature {
+			Signature::Ed25519(ref bytes) => public_key.verify_detached(&signed, &bytes.0),
+			Signature::SkEd25519 { ref bytes, flags, counter } => {
+				flags & key::SK_USER_PRESENT != 0
+					&& public_key.verify_sk_detached(&signed, &bytes.0, flags, counter)
+			},
+			// SHA-1 signatures are refused, as by ssh-keygen.
+			Signature::RSA { hash:SignatureHash::SHA1, .. } => false,
+			Signature::RSA { ref hash, ref bytes } => {
+				public_key.set_algorithm(hash.name().0.as_bytes());
+
+				public_key.verify_detached(&signed, bytes)
+			},
+		};
+
+		if valid { Ok(()) } else { Err(Error::BadSshSig) }
+	}
+}
+
+/// A line of an allowed signers file, as described in the "ALLOWED
+/// SIGNERS" section of ssh-keygen(1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedSigner {
+	/// Patterns matching the principals (usually email addresses) allowed
+	/// to sign with this key, where `*` and `?` are wildcards and patterns
+	/// starting with `!` exclude principals.
+	pub principals:Vec<String>,
+	/// `cert-authority`: the key is a CA certifying signers. Signatures
+	/// by certificates are not supported, so these lines never match.
+	pub cert_authority:bool,
+	/// `namespaces="..."`: patterns matching the namespaces this key may
+	/// sign in, or `None` for all namespaces.
+	pub namespaces:Option<Vec<String>>,
+	/// `valid-after="..."`: the key may not sign before this time.
+	pub valid_after:Option<SystemTime>,
+	/// `valid-before="..."`: the key may not sign after this time.
+	pub valid_before:Option<SystemTime>,
+	/// The key.
+	pub key:key::PublicKey,
+}
+
+impl AllowedSigner {
+	/// Whether this line allows `key` to sign as `principal`, in
+	/// `namespace`, at `now`.
+	pub fn allows(
+		&self,
+		principal:&str,
+		namespace:&str,
+		key:&key::PublicKey,
+		now:SystemTime,
+	) -> bool {
+		!self.cert_authority
+			&& self.key == *key
+			&& patterns_match(&self.principals, principal)
+			&& self.namespaces.as_ref().map_or(true, |n| patterns_match(n, namespace))
+			&& self.valid_after.map_or(true, |t| now >= t)
+			&& self.valid_before.map_or(true, |t| now <= t)
+	}
+
+	fn set_option(&mut self, name:&str, value:Option<String>) -> Result<(), Error> {
+		let invalid = || Error::InvalidAuthorizedKeysOption(name.to_string());
+
+		let time = |value:Option<String>| {
+			value.as_deref().and_then(parse_timespec).ok_or_else(invalid)
+		};
+
+		match (name.to_ascii_lowercase().as_str(), value) {
+			("cert-authority", None) => self.cert_authority = true,
+			("namespaces", Some(value)) => {
+				self.namespaces = Some(value.split(',').map(String::from).collect())
+			},
+			("valid-after", value) => self.valid_after = Some(time(value)?),
+			("valid-before", value) => self.valid_before = Some(time(value)?),
+			_ => return Err(invalid()),
+		}
+
+		Ok(())
+	}
+}
+
+impl FromStr for AllowedSigner {
+	type Err = Error;
+
+	/// Parse a line of an allowed signers file. Timestamps are read as
+	/// UTC.
+	fn from_str(line:&str) -> Result<Self, Error> {
+		let line = line.trim();
+
+		let (principals, mut rest) = if line.starts_with('"') {
+			quoted(line).ok_or(Error::CouldNotReadKey)?
+		} else {
+			let end = line.find(char::is_whitespace).ok_or(Error::CouldNotReadKey)?;
+
+			let (principals, rest) = line.split_at(end);
+
+			(principals.to_string(), rest)
+		};
+
+		rest = rest.trim_start();
+
+		let mut options = Vec::new();
+
+		// The options are a comma-separated list, before the key type.
+		while !KEY_TYPES.iter().any(|t| rest.split_whitespace().next() == Some(*t)) {
+			let end =
+				rest.find(|c:char| c == '=' || c == ',' || c.is_whitespace()).unwrap_or(rest.len());
+
+			let (name, after) = rest.split_at(end);
+
+			let (value, after) = match after.strip_prefix('=') {
+				Some(after) => {
+					let (value, after) = quoted(after)
+						.ok_or_else(|| Error::InvalidAuthorizedKeysOption(name.to_string()))?;
+
+					(Some(value), after)
+				},
+				None => (None, after),
+			};
+
+			if name.is_empty() {
+				return Err(Error::CouldNotReadKey);
+			}
+
+			options.push((name, value));
+
+			rest = after.strip_prefix(',').unwrap_or_else(|| after.trim_start());
+		}
+
+		let mut fields = rest.split_whitespace();
+
+		let (key_type, key) = match (fields.next(), fields.next()) {
+			(Some(key_type), Some(key)) => (key_type, key),
+			_ => return Err(Error::CouldNotReadKey),
+		};
+
+		let blob = BASE64_MIME.decode(key.as_bytes())?;
+
+		if blob.reader(0).read_string()? != key_type.as_bytes() {
+			return Err(Error::CouldNotReadKey);
+		}
+
+		let mut signer = AllowedSigner {
+			principals:principals.split(',').map(String::from).collect(),
+			cert_authority:false,
+			namespaces:None,
+			valid_after:None,
+			valid_before:None,
+			key:key::parse_public_key(
+				&blob,
+				#[cfg(feature = "openssl")]
+				None,
+			)?,
+		};
+
+		for (name, value) in options {
+			signer.set_option(name, value)?;
+		}
+
+		Ok(signer)
+	}
+}
+
+/// Parse the contents of an allowed signers file. Comments and blank
+/// lines are ignored, and lines that cannot be parsed (or hold keys of
+/// unsupported types) are skipped.
+pub fn parse_allowed_signers(contents:&str) -> Vec<AllowedSigner> {
+	contents
+		.lines()
+		.enumerate()
+		.filter(|(_, line)| {
+			let line = line.trim_start();
+
+			!line.is_empty() && !line.starts_with('#')
+		})
+		.filter_map(|(n, line)| {
+			line.parse()
+				.map_err(|e| debug!("allowed signers line {}: {:?}", n + 1, e))
+				.ok()
+		})
+		.collect()
+}
+
+/// Load an allowed signers file.
+pub fn load_allowed_signers<P:AsRef<Path>>(path:P) -> Result<Vec<AllowedSigner>, Error> {
+	let mut contents = String::new();
+
+	File::open(path)?.read_to_string(&mut contents)?;
+
+	Ok(parse_allowed_signers(&contents))
+}
+
+/// Sign the message read from `reader` in `namespace`, like
+/// `ssh-keygen -Y sign -n namespace`, returning an armored signature.
+///
+/// The message is hashed with SHA-512, and RSA keys sign with
+/// `rsa-sha2-512`.
+pub fn sign<R:Read>(key:&KeyPair, namespace:&str, reader:R) -> Result<String, Error> {
+	if namespace.is_empty() {
+		return Err(Error::SshSigNamespace(String::new()));
+	}
+
+	let hash_algorithm = HashAlgorithm::Sha512;
+
+	let signed = signed_data(namespace, hash_algorithm, &hash_algorithm.digest(reader)?);
+
+	#[cfg(feature = "openssl")]
+	let rsa_sha512 = key.with_signature_hash(SignatureHash::SHA2_512);
+
+	#[cfg(feature = "openssl")]
+	let key = rsa_sha512.as_ref().unwrap_or(key);
+
+	let sig = SshSig {
+		public_key:key.clone_public_key()?,
+		namespace:namespace.to_string(),
+		hash_algorithm,
+		signature:key.sign_detached(&signed)?,
+	};
+
+	Ok(sig.to_armored())
+}
+
+/// Verify an armored `signature` of the message read from `reader`, like
+/// `ssh-keygen -Y verify -I principal -n namespace`: the signature must
+/// be valid, and made in `namespace` by a key allowed to sign as
+/// `principal` now. Returns the signing key.
+pub fn verify<R:Read>(
+	allowed_signers:&[AllowedSigner],
+	principal:&str,
+	namespace:&str,
+	reader:R,
+	signature:&str,
+) -> Result<key::PublicKey, Error> {
+	let sig = SshSig::parse(signature)?;
+
+	let now = SystemTime::now();
+
+	if !allowed_signers.iter().any(|s| s.allows(principal, namespace, &sig.public_key, now)) {
+		return Err(Error::SshSigNotAllowed(principal.to_string()));
+	}
+
+	sig.verify_message(namespace, reader)?;
+
+	Ok(sig.public_key)
+}
+
+/// The data actually signed: the magic string, the namespace and the
+/// message hash.
+fn signed_data(namespace:&str, hash_algorithm:HashAlgorithm, hash:&[u8]) -> Vec<u8> {
+	let mut data = MAGIC.to_vec();
+
+	data.extend_ssh_string(namespace.as_bytes());
+
+	data.extend_ssh_string(b"");
+
+	data.extend_ssh_string(hash_algorithm.name().as_bytes());
+
+	data.extend_ssh_string(hash);
+
+	data
+}
+
+/// Whether `s` matches a list of patterns, where `*` and `?` are
+/// wildcards and patterns starting with `!` exclude what they match.
+fn patterns_match(patterns:&[String], s:&str) -> bool {
+	let mut matches = false;
+
+	for pattern in patterns {
+		if let Some(pattern) = pattern.strip_prefix('!') {
+			if wildcard_matches(pattern.as_bytes(), s.as_bytes()) {
+				return false;
+			}
+		} else if wildcard_matches(pattern.as_bytes(), s.as_bytes()) {
+			matches = true;
+		}
+	}
+
+	matches
+}
@@ -14,28 +14,34 @@ use tokio::{
 
 use self::read_buffer::ReadBuffer;
 
-/// AsyncRead/AsyncWrite wrapper for SSH Channels
+/// AsyncRead/AsyncWrite wrapper for SSH Channels, see
+/// [`Channel::into_stream`](crate::Channel::into_stream).
 pub struct ChannelStream {
 	incoming:mpsc::UnboundedReceiver<Vec<u8>>,
-	outgoing:mpsc::UnboundedSender<Vec<u8>>,
+	outgoing:mpsc::Sender<Vec<u8>>,
 
 	readbuf:ReadBuffer,
 
 	is_write_fut_valid:bool,
+	is_shutdown:bool,
 	write_fut:tokio_util::sync::ReusableBoxFuture<'static, Result<(), Vec<u8>>>,
 }
 
 impl ChannelStream {
-	pub fn new() -> (Self, mpsc::UnboundedReceiver<Vec<u8>>, mpsc::UnboundedSender<Vec<u8>>) {
+	/// A stream, with the receiver of the data written to it, where an
+	/// empty buffer means EOF, and the sender of the data to read from it.
+	/// Writes wait while a buffer is not received.
+	pub fn new() -> (Self, mpsc::Receiver<Vec<u8>>, mpsc::UnboundedSender<Vec<u8>>) {
 		let (w_tx, w_rx) = mpsc::unbounded_channel();
 
-		let (r_tx, r_rx) = mpsc::unbounded_channel();
+		let (r_tx, r_rx) = mpsc::channel(1);
 		(
 			ChannelStream {
 				incoming:w_rx,
 				outgoing:r_tx,
 				readbuf:ReadBuffer::default(),
 				is_write_fut_valid:false,
+				is_shutdown:false,
 				write_fut:tokio_util::sync::ReusableBoxFuture::new(make_client_write_fut(None)),
 			},
 			r_rx,
@@ -49,10 +55,10 @@ impl ChannelStream {
 /// This is just like make_server_write_fut, but for clients (they don't share a
 /// trait...)
 async fn make_client_write_fut(
-	data:Option<(mpsc::UnboundedSender<Vec<u8>>, Vec<u8>)>,
+	data:Option<(mpsc::Sender<Vec<u8>>, Vec<u8>)>,
 ) -> Result<(), Vec<u8>> {
 	match data {
-		Some((sender, data)) => sender.send(data).map_err(|e| e.0),
+		Some((sender, data)) => sender.send(data).await.map_err(|e| e.0),
 		None => {
 			unreachable!("this future should not be pollable in this state")
 		},
@@ -65,15 +71,32 @@ impl AsyncWrite for ChannelStream {
 		cx:&mut std::task::Context<'_>,
 		buf:&[u8],
 	) -> Poll<Result<usize, io::Error>> {
-		if !self.is_write_fut_valid {
-			let outgoing = self.outgoing.clone();
-
-			self.write_fut.set(make_client_write_fut(Some((outgoing, buf.to_vec()))));
+		// An empty buffer would mean EOF.
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
 
-			self.is_write_fut_valid = true;
+		// Finish the previous write first, as `buf` is only taken once this
+		// returns its length: a write retried after `Pending` may pass more
+		// data, or other data.
+		match self.as_mut().poll_flush(cx) {
+			Poll::Ready(Ok(())) => {},
+			Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+			Poll::Pending => return Poll::Pending,
 		}
 
-		self.poll_flush(cx).map(|r| r.map(|_| buf.len()))
+		let outgoing = self.outgoing.clone();
+
+		self.write_fut.set(make_client_write_fut(Some((outgoing, buf.to_vec()))));
+
+		self.is_write_fut_valid = true;
+
+		// `buf` is taken even if the channel is full, the next write or
+		// flush waits for it to be received.
+		match self.poll_flush(cx) {
+			Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+			_ => Poll::Ready(Ok(buf.len())),
+		}
 	}
 
 	fn poll_flush(
@@ -102,16 +125,25 @@ impl AsyncWrite for ChannelStream {
 	}
 
 	fn poll_shutdown(
-		self: Pin<&mut Self>,
-		_cx:&mut std::task::Context<'_>,
+		mut self: Pin<&mut Self>,
+		cx:&mut std::task::Context<'_>,
 	) -> Poll<Result<(), io::Error>> {
-		if let Err(err) = self.outgoing.send("".into()) {
-			let err = format!("{err:?}");
+		if !self.is_shutdown {
+			match self.as_mut().poll_flush(cx) {
+				Poll::Ready(Ok(())) => {},
+				poll => return poll,
+			}
+
+			let outgoing = self.outgoing.clone();
+
+			self.write_fut.set(make_client_write_fut(Some((outgoing, Vec::new()))));
+
+			self.is_write_fut_valid = true;
 
-			return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+			self.is_shutdown = true;
 		}
 
-		Poll::Ready(Ok(()))
+		self.poll_flush(cx)
 	}
 }
 
@@ -134,3 +166,82 @@ impl AsyncRead for ChannelStream {
 		}
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_channel_stream_copy_bidirectional() {
+		use rand::RngCore;
+
+		/// Tunnel a local stream through `stream` with `copy_bidirectional`,
+		/// write `sent` to it, and read everything from it.
+		async fn tunnel(stream:ChannelStream, sent:Vec<u8>) -> Vec<u8> {
+			let (local, mut tunnel_end) = tokio::io::duplex(8192);
+
+			let copy = tokio::spawn(async move {
+				let mut stream = stream;
+
+				tokio::io::copy_bidirectional(&mut tunnel_end, &mut stream).await.unwrap()
+			});
+
+			let (mut reader, mut writer) = tokio::io::split(local);
+
+			tokio::spawn(async move {
+				writer.write_all(&sent).await.unwrap();
+
+				writer.shutdown().await.unwrap();
+			});
+
+			let mut received = Vec::new();
+
+			reader.read_to_end(&mut received).await.unwrap();
+
+			copy.await.unwrap();
+
+			received
+		}
+
+		let mut from_client = vec![0; 3 << 20];
+
+		rand::thread_rng().fill_bytes(&mut from_client);
+
+		let mut from_server = vec![0; 5 << 20];
+
+		rand::thread_rng().fill_bytes(&mut from_server);
+
+		let (channels, mut server_channels) = unbounded_channel();
+
+		let (sent_by_client, sent_by_server) = (from_client.clone(), from_server.clone());
+
+		test_session(
+			Client {},
+			ChannelServer { channels },
+			|client| {
+				async move {
+					let channel = client.channel_open_session().await.unwrap();
+
+					let received = tunnel(channel.into_stream(), from_client).await;
+
+					assert!(received == sent_by_server);
+
+					client
+				}
+			},
+			|server| {
+				async move {
+					let channel = server_channels.recv().await.unwrap();
+
+					let received = tunnel(channel.into_stream(), from_server).await;
+
+					assert!(received == sent_by_client);
+
+					server
+				}
+			},
+		)
+		.await;
+	}
+}
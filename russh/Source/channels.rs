@@ -1,8 +1,12 @@
 use log::debug;
 use russh_cryptovec::CryptoVec;
-use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+use tokio::sync::{
+	mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+	oneshot,
+	watch,
+};
 
-use crate::{ChannelId, ChannelOpenFailure, ChannelStream, Error, Pty, Sig};
+use crate::{ChannelId, ChannelOpenFailure, ChannelStream, Disconnect, Error, Sig, TerminalModes};
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -29,7 +33,7 @@ pub enum ChannelMsg {
 		row_height:u32,
 		pix_width:u32,
 		pix_height:u32,
-		terminal_modes:Vec<(Pty, u32)>,
+		terminal_modes:TerminalModes,
 	},
 	/// (client only)
 	RequestShell {
@@ -44,6 +48,12 @@ pub enum ChannelMsg {
 	Signal {
 		signal:Sig,
 	},
+	/// (client only) A break of `break_length` milliseconds, as on a
+	/// serial line, see RFC 4335.
+	Break {
+		want_reply:bool,
+		break_length:u32,
+	},
 	/// (client only)
 	RequestSubsystem {
 		want_reply:bool,
@@ -101,6 +111,12 @@ pub enum ChannelMsg {
 	/// (server only)
 	Close,
 	OpenFailure(ChannelOpenFailure),
+	/// The peer ended the session, after which the channel receives
+	/// nothing else.
+	Disconnected {
+		reason:Disconnect,
+		description:String,
+	},
 }
 
 /// A handle to a session channel.
@@ -112,6 +128,34 @@ pub struct Channel<Send:From<(ChannelId, ChannelMsg)>> {
 	pub(crate) receiver:UnboundedReceiver<ChannelMsg>,
 	pub(crate) max_packet_size:u32,
 	pub(crate) window_size:u32,
+	/// The exit status and signal received so far.
+	pub(crate) close_info:ChannelCloseInfo,
+	/// Whether [`Channel::wait`] saw the channel closed.
+	pub(crate) closed:bool,
+}
+
+/// How the command of a channel ended, see [`Channel::wait_close`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelCloseInfo {
+	/// The exit status, if the peer sent one.
+	pub exit_status:Option<u32>,
+	/// The signal that killed the command, whether it dumped core, and its
+	/// error message, if the peer sent them.
+	pub exit_signal:Option<(Sig, bool, String)>,
+}
+
+impl ChannelCloseInfo {
+	/// Remember the last exit status or signal.
+	fn record(&mut self, msg:&ChannelMsg) {
+		match msg {
+			ChannelMsg::ExitStatus { exit_status } => self.exit_status = Some(*exit_status),
+			ChannelMsg::ExitSignal { signal_name, core_dumped, error_message, .. } => {
+				self.exit_signal =
+					Some((signal_name.clone(), *core_dumped, error_message.clone()));
+			},
+			_ => {},
+		}
+	}
 }
 
 impl<T:From<(ChannelId, ChannelMsg)>> std::fmt::Debug for Channel<T> {
@@ -139,7 +183,7 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		row_height:u32,
 		pix_width:u32,
 		pix_height:u32,
-		terminal_modes:&[(Pty, u32)],
+		terminal_modes:&TerminalModes,
 	) -> Result<(), Error> {
 		self.send_msg(ChannelMsg::RequestPty {
 			want_reply,
@@ -148,7 +192,7 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 			row_height,
 			pix_width,
 			pix_height,
-			terminal_modes:terminal_modes.to_vec(),
+			terminal_modes:terminal_modes.clone(),
 		})
 		.await?;
 
@@ -171,13 +215,24 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		Ok(())
 	}
 
-	/// Signal a remote process.
+	/// Signal a remote process, for instance with [`Sig::INT`] when the
+	/// user types Ctrl-C. The request has no reply.
 	pub async fn signal(&mut self, signal:Sig) -> Result<(), Error> {
 		self.send_msg(ChannelMsg::Signal { signal }).await?;
 
 		Ok(())
 	}
 
+	/// Send a break of `break_length` milliseconds, as on a serial line
+	/// (RFC 4335), for instance to a serial console server. With
+	/// `want_reply`, this waits for the reply, and returns whether the
+	/// server performed the break, as [`Channel::set_env`].
+	pub async fn send_break(&mut self, want_reply:bool, break_length:u32) -> Result<bool, Error> {
+		self.send_msg(ChannelMsg::Break { want_reply, break_length }).await?;
+
+		if want_reply { self.wait_reply().await } else { Ok(true) }
+	}
+
 	/// Request the start of a subsystem with the given name.
 	pub async fn request_subsystem<A:Into<String>>(
 		&mut self,
@@ -214,13 +269,17 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		Ok(())
 	}
 
-	/// Set a remote environment variable.
+	/// Set a remote environment variable, before starting a shell or a
+	/// command. With `want_reply`, this waits for the reply, and returns
+	/// whether the server accepted the variable, discarding the other
+	/// messages received before it. Servers usually only accept a few
+	/// variables, as sshd's `AcceptEnv`.
 	pub async fn set_env<A:Into<String>, B:Into<String>>(
 		&mut self,
 		want_reply:bool,
 		variable_name:A,
 		variable_value:B,
-	) -> Result<(), Error> {
+	) -> Result<bool, Error> {
 		self.send_msg(ChannelMsg::SetEnv {
 			want_reply,
 			variable_name:variable_name.into(),
@@ -228,10 +287,11 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		})
 		.await?;
 
-		Ok(())
+		if want_reply { self.wait_reply().await } else { Ok(true) }
 	}
 
-	/// Inform the server that our window size has changed.
+	/// Inform the server that our window size has changed, in characters
+	/// and in pixels. The request has no reply.
 	pub async fn window_change(
 		&mut self,
 		col_width:u32,
@@ -252,6 +312,35 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		Ok(())
 	}
 
+	/// Request agent forwarding, as `ssh -A`, and wait for the reply. The
+	/// agent channels the server then opens are passed to
+	/// [`Handler::server_channel_open_agent_forward`], which serves them
+	/// from the local agent by default. Other messages received before the
+	/// reply are discarded, so this is best called before starting a shell
+	/// or a command.
+	///
+	/// [`Handler::server_channel_open_agent_forward`]: crate::client::Handler::server_channel_open_agent_forward
+	pub async fn request_agent_forwarding(&mut self) -> Result<(), Error> {
+		self.agent_forward(true).await?;
+
+		if self.wait_reply().await? { Ok(()) } else { Err(Error::ChannelRequestFailure) }
+	}
+
+	/// Wait for the reply to a request, discarding the other messages.
+	async fn wait_reply(&mut self) -> Result<bool, Error> {
+		loop {
+			match self.wait().await {
+				Some(ChannelMsg::Success) => return Ok(true),
+				Some(ChannelMsg::Failure) => return Ok(false),
+				Some(ChannelMsg::Disconnected { reason, description }) => {
+					return Err(Error::Disconnected { reason, description });
+				},
+				Some(msg) => debug!("waiting for a reply: {:?}", msg),
+				None => return Err(Error::Disconnect),
+			}
+		}
+	}
+
 	/// Send data to a channel.
 	pub async fn data<R:tokio::io::AsyncReadExt + Unpin>(&mut self, data:R) -> Result<(), Error> {
 		self.send_data(None, data).await
@@ -286,10 +375,13 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 
 						break;
 					},
+					Some(ChannelMsg::Disconnected { reason, description }) => {
+						return Err(Error::Disconnected { reason, description });
+					},
 					Some(msg) => {
 						debug!("unexpected channel msg: {:?}", msg);
 					},
-					None => break,
+					None => return Err(Error::Disconnect),
 				}
 			}
 
@@ -298,7 +390,7 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 				self.window_size, self.max_packet_size, total
 			);
 
-			let sendable = self.window_size.min(self.max_packet_size) as usize;
+			let sendable = self.window_size.min(self.max_packet_size.max(1)) as usize;
 
 			debug!("sendable {:?}", sendable);
 
@@ -308,7 +400,9 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 				continue;
 			}
 
-			let mut c = CryptoVec::new_zeroed(sendable);
+			let mut c = crate::pool::take();
+
+			c.resize(sendable);
 
 			let n = data.read(&mut c[..]).await?;
 
@@ -347,7 +441,8 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		Ok(())
 	}
 
-	/// Wait for data to come.
+	/// Wait for data to come. Once this returned [`ChannelMsg::Close`] or
+	/// `None`, sending on the channel fails with [`Error::WrongChannel`].
 	pub async fn wait(&mut self) -> Option<ChannelMsg> {
 		match self.receiver.recv().await {
 			Some(ChannelMsg::WindowAdjusted { new_size }) => {
@@ -355,65 +450,183 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 
 				Some(ChannelMsg::WindowAdjusted { new_size })
 			},
-			Some(msg) => Some(msg),
-			None => None,
+			Some(msg) => {
+				self.close_info.record(&msg);
+
+				self.closed |= matches!(msg, ChannelMsg::Close);
+
+				Some(msg)
+			},
+			None => {
+				self.closed = true;
+
+				None
+			},
+		}
+	}
+
+	/// Wait until the channel is closed, dropping the messages received in
+	/// the meantime, and return the last exit status and signal the peer
+	/// sent, including those already received through [`Channel::wait`].
+	///
+	/// The peer may send neither, for instance when a command is killed
+	/// without the server knowing why, or send them after EOF.
+	pub async fn wait_close(&mut self) -> ChannelCloseInfo {
+		while let Some(msg) = self.wait().await {
+			if matches!(msg, ChannelMsg::Close | ChannelMsg::Disconnected { .. }) {
+				break;
+			}
 		}
+
+		self.close_info.clone()
 	}
 
 	async fn send_msg(&self, msg:ChannelMsg) -> Result<(), Error> {
+		if self.closed {
+			return Err(Error::WrongChannel);
+		}
+
 		self.sender.send((self.id, msg).into()).await.map_err(|_| Error::SendError)
 	}
 
-	/// Request that the channel be closed.
+	/// Request that the channel be closed. This does nothing if it is
+	/// already closed.
 	pub async fn close(&self) -> Result<(), Error> {
+		if self.closed {
+			return Ok(());
+		}
+
 		self.send_msg(ChannelMsg::Close).await?;
 
 		Ok(())
 	}
 
+	/// Split this channel into halves, to read from one task and write
+	/// from another.
+	///
+	/// Window adjustments are received whether or not the read half is
+	/// read. Dropping the write half sends EOF, unless
+	/// [`eof`](ChannelWriteHalf::eof) or [`close`](ChannelWriteHalf::close)
+	/// was called, and dropping both halves closes the channel.
+	pub fn split(self) -> (ChannelReadHalf, ChannelWriteHalf<S>) {
+		let (messages, receiver) = mpsc::unbounded_channel();
+
+		let (window_sender, window) = watch::channel(self.window_size);
+
+		let (read_dropped, read_dropped_receiver) = oneshot::channel();
+
+		let (write_dropped, write_dropped_receiver) = oneshot::channel();
+
+		let read = ChannelReadHalf { id:self.id, receiver, _dropped:read_dropped };
+
+		let write = ChannelWriteHalf {
+			id:self.id,
+			sender:self.sender.clone(),
+			max_packet_size:self.max_packet_size,
+			window_size:self.window_size,
+			window,
+			end:WriteEnd::Open,
+			dropped:Some(write_dropped),
+		};
+
+		tokio::spawn(route_split(
+			self,
+			messages,
+			window_sender,
+			read_dropped_receiver,
+			write_dropped_receiver,
+		));
+
+		(read, write)
+	}
+
+	/// Turn this channel into a stream, for code expecting
+	/// [`AsyncRead`](tokio::io::AsyncRead) and
+	/// [`AsyncWrite`](tokio::io::AsyncWrite).
+	///
+	/// Reading returns the data of the channel, and ends when the peer sends
+	/// EOF or closes the channel. Extended data, such as the standard error
+	/// of a command, is dropped. Writes wait for the window of the peer, and
+	/// shutting the stream down sends EOF. The channel is closed when the
+	/// stream is dropped, or once EOF has been both sent and received.
 	pub fn into_stream(mut self) -> ChannelStream {
 		let (stream, mut r_rx, w_tx) = ChannelStream::new();
 
 		tokio::spawn(async move {
+			let mut w_tx = Some(w_tx);
+
+			// Data written to the stream, and how much of it was sent.
+			let mut pending:Option<(Vec<u8>, usize)> = None;
+
+			let mut eof_sent = false;
+
 			loop {
 				tokio::select! {
-					data = r_rx.recv() => {
+					data = r_rx.recv(), if pending.is_none() => {
 						match data {
-							Some(data) if !data.is_empty() => self.data(&data[..]).await?,
+							Some(data) if eof_sent => {
+								debug!("dropping {} bytes written after EOF", data.len());
+							},
+							Some(data) if !data.is_empty() => pending = Some((data, 0)),
 							Some(_) => {
-								log::debug!("closing chan {:?}, received empty data", &self.id);
+								debug!("sending EOF on {:?}", self.id);
 
 								self.eof().await?;
 
-								self.close().await?;
-
-								break;
+								eof_sent = true;
 							},
 							None => {
 								self.close().await?;
 
-								break
-							}
+								break;
+							},
 						}
 					},
 					msg = self.wait() => {
 						match msg {
-							Some(ChannelMsg::Data { data }) => {
-								w_tx.send(data[..].into()).map_err(|_| crate::Error::SendError)?;
-							}
+							Some(ChannelMsg::Data { data }) if !data.is_empty() => {
+								if let Some(ref w_tx) = w_tx {
+									// The stream may have been dropped.
+									w_tx.send(data[..].into()).unwrap_or(());
+								}
+
+								crate::pool::give(data);
+							},
+							// Dropping the sender ends reading.
+							Some(ChannelMsg::Eof) => w_tx = None,
+							Some(ChannelMsg::Close) | None => break,
+							_ => {},
+						}
+					}
+				}
 
-							Some(ChannelMsg::Eof) => {
-								// Send a 0-length chunk to indicate EOF.
-								w_tx.send("".into()).map_err(|_| crate::Error::SendError)?;
+				// Send as much as the window allows.
+				while let Some((data, from)) = pending.take() {
+					let size = (data.len() - from).min(self.writable_packet_size());
 
-								break
-							}
+					if size == 0 {
+						pending = Some((data, from));
 
-							None => break,
-							_ => (),
-						}
+						break;
+					}
+
+					#[allow(clippy::indexing_slicing)] // length checked
+					let packet = crate::pool::from_slice(&data[from..from + size]);
+
+					self.window_size -= size as u32;
+
+					self.send_data_packet(None, packet).await?;
+
+					if from + size < data.len() {
+						pending = Some((data, from + size));
 					}
 				}
+
+				if eof_sent && w_tx.is_none() {
+					self.close().await?;
+
+					break;
+				}
 			}
 
 			Ok::<_, crate::Error>(())
@@ -422,3 +635,1280 @@ impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> Channel<S> {
 		stream
 	}
 }
+
+/// Dispatch the messages of a split channel to its halves, and send EOF or
+/// close the channel when they are dropped.
+async fn route_split<S:From<(ChannelId, ChannelMsg)> + Send + 'static>(
+	mut channel:Channel<S>,
+	messages:UnboundedSender<ChannelMsg>,
+	window:watch::Sender<u32>,
+	mut read_dropped:oneshot::Receiver<()>,
+	mut write_dropped:oneshot::Receiver<WriteEnd>,
+) -> Result<(), Error> {
+	let mut read_open = true;
+
+	let mut write_open = true;
+
+	let mut closed = false;
+
+	while read_open || write_open {
+		tokio::select! {
+			msg = channel.wait() => {
+				match msg {
+					Some(msg) => {
+						if let ChannelMsg::WindowAdjusted { new_size } = msg {
+							window.send(new_size).unwrap_or(());
+						}
+
+						// The read half may have been dropped.
+						messages.send(msg).unwrap_or(());
+					},
+					None => return Ok(()),
+				}
+			},
+			_ = &mut read_dropped, if read_open => read_open = false,
+			end = &mut write_dropped, if write_open => {
+				write_open = false;
+
+				match end.unwrap_or(WriteEnd::Open) {
+					WriteEnd::Open => {
+						debug!("write half of {:?} dropped, sending EOF", channel.id);
+
+						channel.eof().await?;
+					},
+					WriteEnd::Eof => {},
+					WriteEnd::Closed => closed = true,
+				}
+			},
+		}
+	}
+
+	if closed {
+		return Ok(());
+	}
+
+	debug!("both halves of {:?} dropped, closing", channel.id);
+
+	channel.close().await
+}
+
+/// What the write half of a split channel sent last.
+#[derive(Debug, Clone, Copy)]
+enum WriteEnd {
+	Open,
+	Eof,
+	Closed,
+}
+
+/// The read half of a [`Channel`], see [`Channel::split`].
+pub struct ChannelReadHalf {
+	id:ChannelId,
+	receiver:UnboundedReceiver<ChannelMsg>,
+	_dropped:oneshot::Sender<()>,
+}
+
+impl std::fmt::Debug for ChannelReadHalf {
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ChannelReadHalf").field("id", &self.id).finish()
+	}
+}
+
+impl ChannelReadHalf {
+	pub fn id(&self) -> ChannelId { self.id }
+
+	/// Wait for a message, as [`Channel::wait`].
+	pub async fn wait(&mut self) -> Option<ChannelMsg> { self.receiver.recv().await }
+}
+
+/// The write half of a [`Channel`], see [`Channel::split`].
+pub struct ChannelWriteHalf<Send:From<(ChannelId, ChannelMsg)>> {
+	id:ChannelId,
+	sender:Sender<Send>,
+	max_packet_size:u32,
+	window_size:u32,
+	window:watch::Receiver<u32>,
+	end:WriteEnd,
+	dropped:Option<oneshot::Sender<WriteEnd>>,
+}
+
+impl<T:From<(ChannelId, ChannelMsg)>> std::fmt::Debug for ChannelWriteHalf<T> {
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ChannelWriteHalf").field("id", &self.id).finish()
+	}
+}
+
+impl<T:From<(ChannelId, ChannelMsg)>> Drop for ChannelWriteHalf<T> {
+	fn drop(&mut self) {
+		if let Some(dropped) = self.dropped.take() {
+			dropped.send(self.end).unwrap_or(());
+		}
+	}
+}
+
+impl<S:From<(ChannelId, ChannelMsg)> + Send + 'static> ChannelWriteHalf<S> {
+	pub fn id(&self) -> ChannelId { self.id }
+
+	/// Request a pseudo-terminal, as [`Channel::request_pty`].
+	#[allow(clippy::too_many_arguments)] // length checked
+	pub async fn request_pty(
+		&mut self,
+		want_reply:bool,
+		term:&str,
+		col_width:u32,
+		row_height:u32,
+		pix_width:u32,
+		pix_height:u32,
+		terminal_modes:&TerminalModes,
+	) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::RequestPty {
+			want_reply,
+			term:term.to_string(),
+			col_width,
+			row_height,
+			pix_width,
+			pix_height,
+			terminal_modes:terminal_modes.clone(),
+		})
+		.await
+	}
+
+	/// Request a remote shell.
+	pub async fn request_shell(&mut self, want_reply:bool) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::RequestShell { want_reply }).await
+	}
+
+	/// Execute a remote program, as [`Channel::exec`].
+	pub async fn exec<A:Into<Vec<u8>>>(&mut self, want_reply:bool, command:A) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::Exec { want_reply, command:command.into() }).await
+	}
+
+	/// Signal a remote process, for instance with [`Sig::INT`] when the
+	/// user types Ctrl-C. The request has no reply.
+	pub async fn signal(&mut self, signal:Sig) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::Signal { signal }).await
+	}
+
+	/// Send a break, as [`Channel::send_break`]. The reply goes to the
+	/// read half.
+	pub async fn send_break(&mut self, want_reply:bool, break_length:u32) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::Break { want_reply, break_length }).await
+	}
+
+	/// Request the start of a subsystem with the given name.
+	pub async fn request_subsystem<A:Into<String>>(
+		&mut self,
+		want_reply:bool,
+		name:A,
+	) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::RequestSubsystem { want_reply, name:name.into() }).await
+	}
+
+	/// Request X11 forwarding, as [`Channel::request_x11`].
+	pub async fn request_x11<A:Into<String>, B:Into<String>>(
+		&mut self,
+		want_reply:bool,
+		single_connection:bool,
+		x11_authentication_protocol:A,
+		x11_authentication_cookie:B,
+		x11_screen_number:u32,
+	) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::RequestX11 {
+			want_reply,
+			single_connection,
+			x11_authentication_protocol:x11_authentication_protocol.into(),
+			x11_authentication_cookie:x11_authentication_cookie.into(),
+			x11_screen_number,
+		})
+		.await
+	}
+
+	/// Set a remote environment variable. The reply goes to the read half.
+	pub async fn set_env<A:Into<String>, B:Into<String>>(
+		&mut self,
+		want_reply:bool,
+		variable_name:A,
+		variable_value:B,
+	) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::SetEnv {
+			want_reply,
+			variable_name:variable_name.into(),
+			variable_value:variable_value.into(),
+		})
+		.await
+	}
+
+	/// Inform the server that our window size has changed, in characters
+	/// and in pixels. The request has no reply.
+	pub async fn window_change(
+		&mut self,
+		col_width:u32,
+		row_height:u32,
+		pix_width:u32,
+		pix_height:u32,
+	) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::WindowChange { col_width, row_height, pix_width, pix_height })
+			.await
+	}
+
+	/// Inform the server that we will accept agent forwarding channels
+	pub async fn agent_forward(&mut self, want_reply:bool) -> Result<(), Error> {
+		self.send_msg(ChannelMsg::AgentForward { want_reply }).await
+	}
+
+	/// Send data to a channel.
+	pub async fn data<R:tokio::io::AsyncReadExt + Unpin>(&mut self, data:R) -> Result<(), Error> {
+		self.send_data(None, data).await
+	}
+
+	/// Send extended data to a channel.
+	pub async fn extended_data<R:tokio::io::AsyncReadExt + Unpin>(
+		&mut self,
+		ext:u32,
+		data:R,
+	) -> Result<(), Error> {
+		self.send_data(Some(ext), data).await
+	}
+
+	async fn send_data<R:tokio::io::AsyncReadExt + Unpin>(
+		&mut self,
+		ext:Option<u32>,
+		mut data:R,
+	) -> Result<(), Error> {
+		loop {
+			// Wait for the window to be restored.
+			while self.window_size == 0 {
+				self.window.changed().await.map_err(|_| Error::SendError)?;
+
+				self.window_size = *self.window.borrow();
+			}
+
+			let sendable = self.window_size.min(self.max_packet_size.max(1)) as usize;
+
+			let mut c = crate::pool::take();
+
+			c.resize(sendable);
+
+			let n = data.read(&mut c[..]).await?;
+
+			if n == 0 {
+				return Ok(());
+			}
+
+			c.resize(n);
+
+			self.window_size -= n as u32;
+
+			self.send_msg(if let Some(ext) = ext {
+				ChannelMsg::ExtendedData { ext, data:c }
+			} else {
+				ChannelMsg::Data { data:c }
+			})
+			.await?;
+		}
+	}
+
+	/// Send EOF.
+	pub async fn eof(&mut self) -> Result<(), Error> {
+		self.end = WriteEnd::Eof;
+
+		self.send_msg(ChannelMsg::Eof).await
+	}
+
+	/// Request that the channel be closed. This does nothing if it was
+	/// already closed with this half.
+	pub async fn close(&mut self) -> Result<(), Error> {
+		if matches!(self.end, WriteEnd::Closed) {
+			return Ok(());
+		}
+
+		let sent = self.send_msg(ChannelMsg::Close).await;
+
+		self.end = WriteEnd::Closed;
+
+		sent
+	}
+
+	async fn send_msg(&self, msg:ChannelMsg) -> Result<(), Error> {
+		if matches!(self.end, WriteEnd::Closed) {
+			return Err(Error::WrongChannel);
+		}
+
+		self.sender.send((self.id, msg).into()).await.map_err(|_| Error::SendError)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_channel_split_proxy() {
+		use rand::RngCore;
+
+		/// Read the data of `read` until EOF.
+		async fn read_to_eof(mut read:ChannelReadHalf) -> Vec<u8> {
+			let mut received = Vec::new();
+
+			while let Some(msg) = read.wait().await {
+				match msg {
+					ChannelMsg::Data { data } => received.extend_from_slice(&data),
+					ChannelMsg::Eof => break,
+					_ => {},
+				}
+			}
+
+			received
+		}
+
+		let mut from_client = vec![0; 3 << 20];
+
+		rand::thread_rng().fill_bytes(&mut from_client);
+
+		let mut from_server = vec![0; 5 << 20];
+
+		rand::thread_rng().fill_bytes(&mut from_server);
+
+		let (channels, mut server_channels) = unbounded_channel();
+
+		let (sent_by_client, sent_by_server) = (from_client.clone(), from_server.clone());
+
+		test_session(
+			Client {},
+			ChannelServer { channels },
+			|client| {
+				async move {
+					let channel = client.channel_open_session().await.unwrap();
+
+					// Proxy a local stream to the channel, one task per direction.
+					let (local, tunnel_end) = tokio::io::duplex(8192);
+
+					let (mut tunnel_read, mut tunnel_write) = tokio::io::split(tunnel_end);
+
+					let (mut read, mut write) = channel.split();
+
+					let upstream = tokio::spawn(async move {
+						// Dropping the write half sends EOF.
+						write.data(&mut tunnel_read).await.unwrap();
+					});
+
+					let downstream = tokio::spawn(async move {
+						while let Some(msg) = read.wait().await {
+							match msg {
+								ChannelMsg::Data { data } => {
+									tunnel_write.write_all(&data).await.unwrap();
+								},
+								ChannelMsg::Eof => break,
+								_ => {},
+							}
+						}
+
+						tunnel_write.shutdown().await.unwrap();
+					});
+
+					let (mut local_read, mut local_write) = tokio::io::split(local);
+
+					tokio::spawn(async move {
+						local_write.write_all(&from_client).await.unwrap();
+
+						local_write.shutdown().await.unwrap();
+					});
+
+					let mut received = Vec::new();
+
+					local_read.read_to_end(&mut received).await.unwrap();
+
+					assert!(received == sent_by_server);
+
+					upstream.await.unwrap();
+
+					downstream.await.unwrap();
+
+					client
+				}
+			},
+			|server| {
+				async move {
+					let (read, mut write) = server_channels.recv().await.unwrap().split();
+
+					let reader = tokio::spawn(read_to_eof(read));
+
+					write.data(&from_server[..]).await.unwrap();
+
+					drop(write);
+
+					assert!(reader.await.unwrap() == sent_by_client);
+
+					server
+				}
+			},
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn test_set_env() {
+		// Accepts `LANG` and `GIT_PROTOCOL`, and prints the variables it
+		// accepted when running a command.
+		struct ServerHandle {
+			env:Vec<String>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn env_request(
+				mut self,
+				_:ChannelId,
+				variable_name:&str,
+				variable_value:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if variable_name != "LANG" && variable_name != "GIT_PROTOCOL" {
+					return Ok((self, false, session));
+				}
+
+				self.env.push(format!("{}={}\n", variable_name, variable_value));
+
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.data(channel, CryptoVec::from(self.env.concat()))?;
+
+				session.exit_status_request(channel, 0);
+
+				session.eof(channel);
+
+				session.close(channel);
+
+				Ok((self, session))
+			}
+		}
+
+		test_session(
+			Client {},
+			ServerHandle { env:Vec::new() },
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					assert!(channel.set_env(true, "LANG", "C.UTF-8").await.unwrap());
+
+					// Refused, without closing the channel.
+					assert!(!channel.set_env(true, "FOO", "bar").await.unwrap());
+
+					assert!(channel.set_env(true, "GIT_PROTOCOL", "version=2").await.unwrap());
+
+					// Without a reply, but still delivered in order.
+					assert!(channel.set_env(false, "LANG", "fr_FR.UTF-8").await.unwrap());
+
+					channel.exec(true, "env").await.unwrap();
+
+					let mut output = Vec::new();
+
+					while let Some(msg) = channel.wait().await {
+						if let ChannelMsg::Data { data } = msg {
+							output.extend_from_slice(&data);
+						}
+					}
+
+					assert_eq!(
+						String::from_utf8(output).unwrap(),
+						"LANG=C.UTF-8\nGIT_PROTOCOL=version=2\nLANG=fr_FR.UTF-8\n"
+					);
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	/// Window parameters are checked when a session starts, and when a
+	/// channel is opened with its own.
+	#[tokio::test]
+	async fn test_window_params_validation() {
+		use std::sync::Arc;
+
+		use crate::WindowParams;
+
+		let invalid = |window_size, maximum_packet_size| {
+			let params = WindowParams { window_size, maximum_packet_size };
+
+			params.check(cipher::MAXIMUM_PACKET_LEN).is_err()
+		};
+
+		assert!(invalid(0, 32768));
+
+		assert!(invalid(2097152, WindowParams::MIN_PACKET_SIZE - 1));
+
+		assert!(invalid(2097152, u32::MAX));
+
+		assert!(!invalid(1, WindowParams::MIN_PACKET_SIZE));
+
+		let (client_stream, _) = tokio::io::duplex(65536);
+
+		let config = client::Config { window_size:0, ..Default::default() };
+
+		let client = client::connect_stream(Arc::new(config), client_stream, Client {}).await;
+
+		assert!(matches!(client, Err(crate::Error::InvalidWindowParams(_))));
+
+		let (server_stream, _) = tokio::io::duplex(65536);
+
+		let config = server::Config { maximum_packet_size:100, ..Default::default() };
+
+		let server = server::run_stream(Arc::new(config), server_stream, Server {}).await;
+
+		assert!(matches!(server, Err(crate::Error::InvalidWindowParams(_))));
+	}
+
+	/// Over a link with 100ms of latency each way, a channel opened with a
+	/// large window downloads faster than one opened with a small window,
+	/// which bounds the data in flight.
+	#[tokio::test]
+	async fn test_window_params_throughput() {
+		use std::sync::{
+			Arc,
+			atomic::{AtomicBool, Ordering},
+		};
+
+		use tokio::{
+			io::{AsyncRead, AsyncWrite},
+			time::{Duration, Instant},
+		};
+
+		use crate::WindowParams;
+
+		const LATENCY:Duration = Duration::from_millis(100);
+
+		const LEN:usize = 1 << 20;
+
+		/// Forward `from` to `to`, delaying each chunk by `LATENCY` once
+		/// `enabled`, without waiting for earlier chunks to be delivered.
+		fn delayed<R, W>(mut from:R, mut to:W, enabled:Arc<AtomicBool>)
+		where
+			R: AsyncRead + Unpin + Send + 'static,
+			W: AsyncWrite + Unpin + Send + 'static, {
+			let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+			tokio::spawn(async move {
+				let mut buf = vec![0; 65536];
+
+				while let Ok(n) = from.read(&mut buf).await {
+					let delay =
+						if enabled.load(Ordering::SeqCst) { LATENCY } else { Duration::ZERO };
+
+					let chunk = buf.get(..n).unwrap().to_vec();
+
+					if n == 0 || sender.send((Instant::now() + delay, chunk)).is_err() {
+						break;
+					}
+				}
+			});
+
+			tokio::spawn(async move {
+				while let Some((deadline, chunk)) = receiver.recv().await {
+					tokio::time::sleep_until(deadline).await;
+
+					if to.write_all(&chunk).await.is_err() {
+						break;
+					}
+				}
+
+				to.shutdown().await.ok();
+			});
+		}
+
+		/// Sends `LEN` bytes on each session channel.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				tokio::spawn(async move {
+					let mut stream = channel.into_stream();
+
+					stream.write_all(&vec![0; LEN]).await.ok();
+
+					stream.shutdown().await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+		}
+
+		let enabled = Arc::new(AtomicBool::new(false));
+
+		let (client_stream, client_relay) = tokio::io::duplex(65536);
+
+		let (server_relay, server_stream) = tokio::io::duplex(65536);
+
+		let (client_read, client_write) = tokio::io::split(client_relay);
+
+		let (server_read, server_write) = tokio::io::split(server_relay);
+
+		delayed(client_read, server_write, enabled.clone());
+
+		delayed(server_read, client_write, enabled.clone());
+
+		let mut config = server::Config::default();
+
+		config.keys.push(generate_keypair());
+
+		tokio::spawn(server::run_stream(Arc::new(config), server_stream, ServerHandle {}));
+
+		let mut client =
+			client::connect_stream(Default::default(), client_stream, Client {}).await.unwrap();
+
+		assert!(client.authenticate_password("user", "password").await.unwrap().success());
+
+		enabled.store(true, Ordering::SeqCst);
+
+		let tiny = WindowParams { window_size:65536, maximum_packet_size:16 };
+
+		assert!(matches!(
+			client.channel_open_session_with(tiny).await,
+			Err(crate::Error::InvalidWindowParams(_))
+		));
+
+		let mut elapsed = Vec::new();
+
+		for window_size in [65536, 4 << 20] {
+			let params = WindowParams { window_size, maximum_packet_size:32768 };
+
+			let start = Instant::now();
+
+			let channel = client.channel_open_session_with(params).await.unwrap();
+
+			let mut received = Vec::new();
+
+			channel.into_stream().read_to_end(&mut received).await.unwrap();
+
+			assert_eq!(received.len(), LEN);
+
+			elapsed.push(start.elapsed());
+		}
+
+		assert!(
+			matches!(elapsed.as_slice(), [small, large] if *small > *large * 2),
+			"{:?}",
+			elapsed
+		);
+	}
+
+	/// `Channel::wait_close` returns the exit status or signal of a
+	/// command, if any, once the channel is closed.
+	#[tokio::test]
+	async fn test_wait_close() {
+		use crate::{ChannelCloseInfo, Sig};
+
+		/// Ends each command as it says, after some output.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				command:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.data(channel, CryptoVec::from_slice(b"output\n"))?;
+
+				session.eof(channel);
+
+				match command {
+					b"exit 0" => session.exit_status_request(channel, 0),
+					b"exit 7" => session.exit_status_request(channel, 7),
+					b"kill" => session.exit_signal_request(channel, Sig::KILL, false, "Killed", ""),
+					_ => {},
+				}
+
+				session.close(channel);
+
+				Ok((self, session))
+			}
+		}
+
+		test_session(
+			Client {},
+			ServerHandle {},
+			|client| {
+				async move {
+					let exit = |command:&'static str| {
+						let client = &client;
+
+						async move {
+							let mut channel = client.channel_open_session().await.unwrap();
+
+							channel.exec(true, command).await.unwrap();
+
+							channel.wait_close().await
+						}
+					};
+
+					let status = |exit_status| ChannelCloseInfo { exit_status, exit_signal:None };
+
+					assert_eq!(exit("exit 0").await, status(Some(0)));
+
+					assert_eq!(exit("exit 7").await, status(Some(7)));
+
+					assert_eq!(
+						exit("kill").await,
+						ChannelCloseInfo {
+							exit_status:None,
+							exit_signal:Some((Sig::KILL, false, "Killed".to_string())),
+						}
+					);
+
+					assert_eq!(exit("none").await, status(None));
+
+					// The status was already received.
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					channel.exec(true, "exit 7").await.unwrap();
+
+					while let Some(msg) = channel.wait().await {
+						if matches!(msg, ChannelMsg::ExitStatus { .. }) {
+							break;
+						}
+					}
+
+					assert_eq!(channel.wait_close().await, status(Some(7)));
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	/// Opening and closing many channels, from both sides, with data in
+	/// flight while they close, never mixes up their data or reuses an
+	/// ID before both sides closed it.
+	#[tokio::test]
+	async fn test_channel_churn() {
+		use std::{
+			collections::HashSet,
+			sync::{Arc, Mutex},
+		};
+
+		use futures::StreamExt;
+		use tokio::time::{Duration, timeout};
+
+		const CHANNELS:usize = 10_000;
+
+		/// Echoes data, and closes channels on EOF.
+		struct ServerHandle {
+			open:Arc<Mutex<HashSet<ChannelId>>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				assert!(self.open.lock().unwrap().insert(channel.id()), "{} reused", channel.id());
+
+				Ok((self, true, session))
+			}
+
+			async fn data(
+				self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.data(channel, CryptoVec::from_slice(data))?;
+
+				Ok((self, session))
+			}
+
+			async fn channel_eof(
+				self,
+				channel:ChannelId,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.close(channel);
+
+				Ok((self, session))
+			}
+
+			async fn channel_close(
+				self,
+				channel:ChannelId,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.open.lock().unwrap().remove(&channel);
+
+				Ok((self, session))
+			}
+		}
+
+		let open = Arc::new(Mutex::new(HashSet::new()));
+
+		let (session, _server) = connect(ServerHandle { open:open.clone() }).await;
+
+		let session = &session;
+
+		let churn = futures::stream::iter(0..CHANNELS)
+			.map(|i| {
+				async move {
+					let mut channel = session.channel_open_session().await.unwrap();
+
+					let payload = format!("{:05} ", i).repeat(40).into_bytes();
+
+					channel.data(&payload[..]).await.unwrap();
+
+					// Even channels are closed by the server after their
+					// echo, odd ones by the client while it is in flight.
+					if i % 2 == 0 {
+						channel.eof().await.unwrap();
+					} else {
+						channel.close().await.unwrap();
+					}
+
+					let mut received = Vec::new();
+
+					while let Some(msg) = channel.wait().await {
+						if let ChannelMsg::Data { data } = msg {
+							received.extend_from_slice(&data);
+						}
+					}
+
+					if i % 2 == 0 {
+						assert!(received == payload, "channel {}", i);
+					} else {
+						assert!(payload.starts_with(&received), "channel {}", i);
+					}
+
+					assert!(matches!(channel.eof().await, Err(crate::Error::WrongChannel)));
+				}
+			})
+			.buffer_unordered(64)
+			.collect::<Vec<()>>();
+
+		timeout(Duration::from_secs(300), churn).await.unwrap();
+
+		// The client's last replies to the server's closes may still be on
+		// their way.
+		timeout(Duration::from_secs(10), async {
+			while !open.lock().unwrap().is_empty() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+	}
+
+	/// Window adjustments from a peer that push the window past
+	/// `u32::MAX`, or come in many tiny steps, or after EOF, leave the
+	/// window saturated and exact, and the channel working.
+	#[tokio::test]
+	async fn test_window_adjust_overflow() {
+		use tokio::time::{Duration, timeout};
+
+		/// Queue raw window adjustments of `amounts` on `channel`.
+		fn adjust(session:&mut Session, channel:ChannelId, amounts:&[u32]) {
+			let enc = session.common.encrypted.as_mut().unwrap();
+
+			let recipient = enc.channels.get(&channel).unwrap().recipient_channel;
+
+			for amount in amounts {
+				push_packet!(enc.write, {
+					enc.write.push(crate::msg::CHANNEL_WINDOW_ADJUST);
+
+					enc.write.push_u32_be(recipient);
+
+					enc.write.push_u32_be(*amount);
+				});
+			}
+		}
+
+		/// Echoes data, but for commands to send adjustments.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn data(
+				self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				match data {
+					b"huge" => adjust(&mut session, channel, &[u32::MAX, u32::MAX]),
+					b"tiny" => adjust(&mut session, channel, &[1; 1000]),
+					_ => session.data(channel, CryptoVec::from_slice(data))?,
+				}
+
+				Ok((self, session))
+			}
+
+			async fn channel_eof(
+				self,
+				channel:ChannelId,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				adjust(&mut session, channel, &[5]);
+
+				Ok((self, session))
+			}
+		}
+
+		async fn next_window(channel:&mut Channel<client::Msg>) -> u32 {
+			timeout(Duration::from_secs(10), async {
+				loop {
+					match channel.wait().await {
+						Some(ChannelMsg::WindowAdjusted { new_size }) => return new_size,
+						Some(_) => {},
+						None => panic!("channel closed"),
+					}
+				}
+			})
+			.await
+			.unwrap()
+		}
+
+		let window = server::Config::default().window_size;
+
+		let (session, _server) = connect(ServerHandle {}).await;
+
+		// Huge adjustments saturate instead of wrapping to a tiny window.
+		let mut channel = session.channel_open_session().await.unwrap();
+
+		channel.data(&b"huge"[..]).await.unwrap();
+
+		assert_eq!(next_window(&mut channel).await, u32::MAX);
+
+		assert_eq!(next_window(&mut channel).await, u32::MAX);
+
+		// Data still flows with the saturated window. Both session loops
+		// block on writes, so the echo has to fit in the in-memory stream.
+		let payload:Vec<u8> = (0..48 * 1024).map(|i| (i % 251) as u8).collect();
+
+		channel.data(&payload[..]).await.unwrap();
+
+		let mut echoed = Vec::new();
+
+		timeout(Duration::from_secs(10), async {
+			while echoed.len() < payload.len() {
+				match channel.wait().await {
+					Some(ChannelMsg::Data { data }) => echoed.extend_from_slice(&data),
+					Some(_) => {},
+					None => break,
+				}
+			}
+		})
+		.await
+		.unwrap();
+
+		assert!(echoed == payload);
+
+		// Tiny adjustments add up exactly.
+		let mut channel = session.channel_open_session().await.unwrap();
+
+		channel.data(&b"tiny"[..]).await.unwrap();
+
+		for i in 1..=1000 {
+			assert_eq!(next_window(&mut channel).await, window - 4 + i);
+		}
+
+		// Adjustments after EOF are still counted.
+		channel.eof().await.unwrap();
+
+		assert_eq!(next_window(&mut channel).await, window - 4 + 1000 + 5);
+
+		channel.close().await.unwrap();
+	}
+
+	/// Data written against an empty window waits for the next
+	/// adjustment, even when the handler does not send it itself, and a
+	/// window of 1 byte is restored once empty.
+	#[tokio::test]
+	async fn test_zero_window() {
+		use tokio::time::{Duration, timeout};
+
+		const LEN:usize = 100;
+
+		struct Client {}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn window_adjusted(
+				self,
+				_:ChannelId,
+				_:u32,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			received:usize,
+			done:UnboundedSender<usize>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn data(
+				mut self,
+				_:ChannelId,
+				data:&[u8],
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.received += data.len();
+
+				if self.received == LEN {
+					self.done.send(self.received).unwrap();
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let config = server::Config { window_size:1, ..Default::default() };
+
+		let (done, mut received) = unbounded_channel();
+
+		let server = ServerHandle { received:0, done };
+
+		let (mut session, _server) =
+			connect_with(client::Config::default(), config, Client {}, server).await;
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		let channel = session.channel_open_session().await.unwrap();
+
+		session.data(channel.id(), CryptoVec::from_slice(&[0x5e; LEN])).await.unwrap();
+
+		let received = timeout(Duration::from_secs(10), received.recv()).await.unwrap();
+
+		assert_eq!(received, Some(LEN));
+	}
+
+	#[tokio::test]
+	async fn test_env_signal_break() {
+		use crate::Sig;
+
+		#[derive(Debug)]
+		enum Request {
+			Env(String, String),
+			Signal(Sig),
+			Break(u32),
+		}
+
+		struct ServerHandle {
+			requests:UnboundedSender<Request>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn env_request(
+				self,
+				_:ChannelId,
+				variable_name:&str,
+				variable_value:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let accepted = variable_name.starts_with("LC_");
+
+				if accepted {
+					self.requests
+						.send(Request::Env(variable_name.to_string(), variable_value.to_string()))
+						.unwrap();
+				}
+
+				Ok((self, accepted, session))
+			}
+
+			async fn signal(
+				self,
+				_:ChannelId,
+				signal:Sig,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.requests.send(Request::Signal(signal)).unwrap();
+
+				Ok((self, session))
+			}
+
+			async fn break_request(
+				self,
+				_:ChannelId,
+				break_length:u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				self.requests.send(Request::Break(break_length)).unwrap();
+
+				Ok((self, break_length <= 3000, session))
+			}
+		}
+
+		let (requests, mut received) = unbounded_channel();
+
+		let (session, _server) = connect(ServerHandle { requests }).await;
+
+		let mut channel = session.channel_open_session().await.unwrap();
+
+		assert!(channel.set_env(true, "LC_ALL", "C").await.unwrap());
+
+		assert!(!channel.set_env(true, "LD_PRELOAD", "evil.so").await.unwrap());
+
+		assert!(channel.send_break(true, 500).await.unwrap());
+
+		assert!(!channel.send_break(true, 60_000).await.unwrap());
+
+		channel.signal(Sig::INT).await.unwrap();
+
+		// Signals have no reply: a break without one follows it, then one
+		// with a reply tells they were all handled.
+		channel.send_break(false, 0).await.unwrap();
+
+		assert!(channel.send_break(true, 1000).await.unwrap());
+
+		let mut handled = Vec::new();
+
+		while let Ok(request) = received.try_recv() {
+			handled.push(request);
+		}
+
+		assert!(
+			matches!(
+				handled.as_slice(),
+				[
+					Request::Env(name, value),
+					Request::Break(500),
+					Request::Break(60_000),
+					Request::Signal(Sig::INT),
+					Request::Break(0),
+					Request::Break(1000),
+				] if name == "LC_ALL" && value == "C"
+			),
+			"{:?}",
+			handled
+		);
+	}
+}
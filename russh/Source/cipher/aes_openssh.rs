@@ -80,22 +80,28 @@ impl super::OpeningKey for OpeningKey {
 	fn decrypt_packet_length(
 		&self,
 		_sequence_number:u32,
-		mut encrypted_packet_length:[u8; 4],
+		encrypted_packet_length:&[u8],
 	) -> Result<[u8; 4], Error> {
+		let mut packet_length = [0; PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			encrypted_packet_length.get(..PACKET_LENGTH_LEN).ok_or(Error::IndexOutOfBounds)?,
+		);
+
 		if self.mac.is_etm() {
-			Ok(encrypted_packet_length)
+			Ok(packet_length)
 		} else {
 			let mut ctx = CipherCtx::new().expect("expected to make openssl cipher");
 
 			ctx.decrypt_init(Some(self.cipher), Some(&self.key), Some(&self.iv))?;
 
-			let input = encrypted_packet_length;
+			let input = packet_length;
 
-			let n = ctx.cipher_update(&input, Some(&mut encrypted_packet_length))?;
+			let n = ctx.cipher_update(&input, Some(&mut packet_length))?;
 			#[allow(clippy::indexing_slicing)]
-			ctx.cipher_final(&mut encrypted_packet_length[n..])?;
+			ctx.cipher_final(&mut packet_length[n..])?;
 
-			Ok(encrypted_packet_length)
+			Ok(packet_length)
 		}
 	}
 
@@ -126,7 +132,7 @@ impl super::OpeningKey for OpeningKey {
 			}
 		}
 
-		Ok(ciphertext_in_plaintext_out)
+		ciphertext_in_plaintext_out.get(PACKET_LENGTH_LEN..).ok_or(Error::IndexOutOfBounds)
 	}
 }
 
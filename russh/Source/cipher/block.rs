@@ -16,13 +16,31 @@ use std::marker::PhantomData;
 use aes::cipher::{IvSizeUser, KeyIvInit, KeySizeUser, StreamCipher};
 use generic_array::GenericArray;
 use rand::RngCore;
+use zeroize::Zeroize;
 
 use super::{super::Error, PACKET_LENGTH_LEN};
 use crate::mac::{Mac, MacAlgorithm};
 
-pub struct SshBlockCipher<C:StreamCipher + KeySizeUser + IvSizeUser>(pub PhantomData<C>);
+/// A cipher which encrypts whole packets in place, either as a stream
+/// (CTR) or block by block (CBC).
+pub trait BlockStreamCipher {
+	/// Block size used for padding computations.
+	fn block_size() -> usize { 16 }
 
-impl<C:StreamCipher + KeySizeUser + IvSizeUser + KeyIvInit + Send + 'static> super::Cipher
+	fn encrypt_data(&mut self, data:&mut [u8]);
+
+	fn decrypt_data(&mut self, data:&mut [u8]);
+}
+
+impl<T:StreamCipher> BlockStreamCipher for T {
+	fn encrypt_data(&mut self, data:&mut [u8]) { self.apply_keystream(data); }
+
+	fn decrypt_data(&mut self, data:&mut [u8]) { self.apply_keystream(data); }
+}
+
+pub struct SshBlockCipher<C:BlockStreamCipher + KeySizeUser + IvSizeUser>(pub PhantomData<C>);
+
+impl<C:BlockStreamCipher + KeySizeUser + IvSizeUser + KeyIvInit + Send + 'static> super::Cipher
 	for SshBlockCipher<C>
 {
 	fn key_len(&self) -> usize { C::key_size() }
@@ -46,7 +64,13 @@ impl<C:StreamCipher + KeySizeUser + IvSizeUser + KeyIvInit + Send + 'static> sup
 
 		nonce.clone_from_slice(n);
 
-		Ok(Box::new(OpeningKey { cipher:C::new(&key, &nonce), mac:mac.make_mac(m) }))
+		let cipher = C::new(&key, &nonce);
+
+		key.as_mut_slice().zeroize();
+
+		nonce.as_mut_slice().zeroize();
+
+		Ok(Box::new(OpeningKey { cipher, mac:mac.make_mac(m) }))
 	}
 
 	fn make_sealing_key(
@@ -64,36 +88,52 @@ impl<C:StreamCipher + KeySizeUser + IvSizeUser + KeyIvInit + Send + 'static> sup
 
 		nonce.clone_from_slice(n);
 
-		Ok(Box::new(SealingKey { cipher:C::new(&key, &nonce), mac:mac.make_mac(m) }))
+		let cipher = C::new(&key, &nonce);
+
+		key.as_mut_slice().zeroize();
+
+		nonce.as_mut_slice().zeroize();
+
+		Ok(Box::new(SealingKey { cipher, mac:mac.make_mac(m) }))
 	}
 }
 
-pub struct OpeningKey<C:StreamCipher + KeySizeUser + IvSizeUser> {
+pub struct OpeningKey<C:BlockStreamCipher + KeySizeUser + IvSizeUser> {
 	cipher:C,
 	mac:Box<dyn Mac + Send>,
 }
 
-pub struct SealingKey<C:StreamCipher + KeySizeUser + IvSizeUser> {
+pub struct SealingKey<C:BlockStreamCipher + KeySizeUser + IvSizeUser> {
 	cipher:C,
 	mac:Box<dyn Mac + Send>,
 }
 
-impl<C:StreamCipher + KeySizeUser + IvSizeUser> super::OpeningKey for OpeningKey<C> {
+impl<C:BlockStreamCipher + KeySizeUser + IvSizeUser> super::OpeningKey for OpeningKey<C> {
+	fn packet_length_to_read_for_block_length(&self) -> usize {
+		if self.mac.is_etm() { PACKET_LENGTH_LEN } else { C::block_size() }
+	}
+
 	fn decrypt_packet_length(
 		&self,
 		_sequence_number:u32,
-		mut encrypted_packet_length:[u8; 4],
+		encrypted_packet_length:&[u8],
 	) -> Result<[u8; 4], Error> {
-		if self.mac.is_etm() {
-			Ok(encrypted_packet_length)
-		} else {
+		let mut first_block = encrypted_packet_length.to_vec();
+
+		if !self.mac.is_etm() {
 			// Work around uncloneable Aes<>
 			let mut cipher:C = unsafe { std::ptr::read(&self.cipher as *const C) };
 
-			cipher.apply_keystream(&mut encrypted_packet_length);
-
-			Ok(encrypted_packet_length)
+			cipher.decrypt_data(&mut first_block);
 		}
+
+		let mut packet_length = [0; PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			first_block.get(..PACKET_LENGTH_LEN).ok_or(Error::IndexOutOfBounds)?,
+		);
+
+		Ok(packet_length)
 	}
 
 	fn tag_len(&self) -> usize { self.mac.mac_len() }
@@ -109,23 +149,22 @@ impl<C:StreamCipher + KeySizeUser + IvSizeUser> super::OpeningKey for OpeningKey
 				return Err(Error::PacketAuth);
 			}
 			#[allow(clippy::indexing_slicing)]
-			self.cipher
-				.apply_keystream(&mut ciphertext_in_plaintext_out[PACKET_LENGTH_LEN..]);
+			self.cipher.decrypt_data(&mut ciphertext_in_plaintext_out[PACKET_LENGTH_LEN..]);
 		} else {
-			self.cipher.apply_keystream(ciphertext_in_plaintext_out);
+			self.cipher.decrypt_data(ciphertext_in_plaintext_out);
 
 			if !self.mac.verify(sequence_number, ciphertext_in_plaintext_out, tag) {
 				return Err(Error::PacketAuth);
 			}
 		}
 
-		Ok(ciphertext_in_plaintext_out)
+		ciphertext_in_plaintext_out.get(PACKET_LENGTH_LEN..).ok_or(Error::IndexOutOfBounds)
 	}
 }
 
-impl<C:StreamCipher + KeySizeUser + IvSizeUser> super::SealingKey for SealingKey<C> {
+impl<C:BlockStreamCipher + KeySizeUser + IvSizeUser> super::SealingKey for SealingKey<C> {
 	fn padding_length(&self, payload:&[u8]) -> usize {
-		let block_size = 16;
+		let block_size = C::block_size();
 
 		let pll = if self.mac.is_etm() { 0 } else { PACKET_LENGTH_LEN };
 
@@ -146,6 +185,8 @@ impl<C:StreamCipher + KeySizeUser + IvSizeUser> super::SealingKey for SealingKey
 
 	fn fill_padding(&self, padding_out:&mut [u8]) { rand::thread_rng().fill_bytes(padding_out); }
 
+	fn padding_block_size(&self) -> usize { C::block_size() }
+
 	fn tag_len(&self) -> usize { self.mac.mac_len() }
 
 	fn seal(
@@ -156,14 +197,13 @@ impl<C:StreamCipher + KeySizeUser + IvSizeUser> super::SealingKey for SealingKey
 	) {
 		if self.mac.is_etm() {
 			#[allow(clippy::indexing_slicing)]
-			self.cipher
-				.apply_keystream(&mut plaintext_in_ciphertext_out[PACKET_LENGTH_LEN..]);
+			self.cipher.encrypt_data(&mut plaintext_in_ciphertext_out[PACKET_LENGTH_LEN..]);
 
 			self.mac.compute(sequence_number, plaintext_in_ciphertext_out, tag_out);
 		} else {
 			self.mac.compute(sequence_number, plaintext_in_ciphertext_out, tag_out);
 
-			self.cipher.apply_keystream(plaintext_in_ciphertext_out);
+			self.cipher.encrypt_data(plaintext_in_ciphertext_out);
 		}
 	}
 }
@@ -0,0 +1,73 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// CBC mode as described in https://tools.ietf.org/html/rfc4253#section-6.3
+
+use aes::cipher::{
+	BlockCipher,
+	BlockDecrypt,
+	BlockDecryptMut,
+	BlockEncrypt,
+	BlockEncryptMut,
+	IvSizeUser,
+	KeyInit,
+	KeyIvInit,
+	KeySizeUser,
+};
+use generic_array::GenericArray;
+
+use super::block::BlockStreamCipher;
+
+/// Keeps a CBC encryptor and decryptor chained across packets, so that
+/// [`super::block::SshBlockCipher`] can drive it like a stream cipher.
+pub struct CbcWrapper<C:BlockEncrypt + BlockDecrypt + BlockCipher> {
+	encryptor:cbc::Encryptor<C>,
+	decryptor:cbc::Decryptor<C>,
+}
+
+impl<C:BlockEncrypt + BlockDecrypt + BlockCipher + KeySizeUser> KeySizeUser for CbcWrapper<C> {
+	type KeySize = C::KeySize;
+}
+
+impl<C:BlockEncrypt + BlockDecrypt + BlockCipher> IvSizeUser for CbcWrapper<C> {
+	type IvSize = C::BlockSize;
+}
+
+impl<C:BlockEncrypt + BlockDecrypt + BlockCipher + KeyInit> KeyIvInit for CbcWrapper<C> {
+	fn new(key:&GenericArray<u8, C::KeySize>, iv:&GenericArray<u8, C::BlockSize>) -> Self {
+		CbcWrapper {
+			encryptor:cbc::Encryptor::new(key, iv),
+			decryptor:cbc::Decryptor::new(key, iv),
+		}
+	}
+}
+
+impl<C:BlockEncrypt + BlockDecrypt + BlockCipher> BlockStreamCipher for CbcWrapper<C> {
+	fn block_size() -> usize { C::block_size() }
+
+	fn encrypt_data(&mut self, data:&mut [u8]) {
+		// The padding rules guarantee that packets are a multiple of the
+		// block size.
+		for block in data.chunks_exact_mut(C::block_size()) {
+			self.encryptor.encrypt_block_mut(GenericArray::from_mut_slice(block));
+		}
+	}
+
+	fn decrypt_data(&mut self, data:&mut [u8]) {
+		for block in data.chunks_exact_mut(C::block_size()) {
+			self.decryptor.decrypt_block_mut(GenericArray::from_mut_slice(block));
+		}
+	}
+}
@@ -28,6 +28,7 @@ use generic_array::{
 };
 use poly1305::Poly1305;
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 use super::super::Error;
 use crate::{cipher::PACKET_LENGTH_LEN, mac::MacAlgorithm};
@@ -92,6 +93,22 @@ pub struct SealingKey {
 	k2:Key,
 }
 
+impl Drop for OpeningKey {
+	fn drop(&mut self) {
+		self.k1.as_mut_slice().zeroize();
+
+		self.k2.as_mut_slice().zeroize();
+	}
+}
+
+impl Drop for SealingKey {
+	fn drop(&mut self) {
+		self.k1.as_mut_slice().zeroize();
+
+		self.k2.as_mut_slice().zeroize();
+	}
+}
+
 #[allow(clippy::indexing_slicing)] // length checked
 fn make_counter(sequence_number:u32) -> Nonce {
 	let mut nonce = Nonce::default();
@@ -107,15 +124,23 @@ impl super::OpeningKey for OpeningKey {
 	fn decrypt_packet_length(
 		&self,
 		sequence_number:u32,
-		mut encrypted_packet_length:[u8; 4],
+		encrypted_packet_length:&[u8],
 	) -> Result<[u8; 4], Error> {
+		let mut packet_length = [0; super::PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			encrypted_packet_length
+				.get(..super::PACKET_LENGTH_LEN)
+				.ok_or(Error::IndexOutOfBounds)?,
+		);
+
 		let nonce = make_counter(sequence_number);
 
 		let mut cipher = ChaCha20Legacy::new(&self.k1, &nonce);
 
-		cipher.apply_keystream(&mut encrypted_packet_length);
+		cipher.apply_keystream(&mut packet_length);
 
-		Ok(encrypted_packet_length)
+		Ok(packet_length)
 	}
 
 	fn tag_len(&self) -> usize { TagLength::to_usize() }
@@ -174,6 +199,8 @@ impl super::SealingKey for SealingKey {
 		}
 	}
 
+	fn padding_block_size(&self) -> usize { 8 }
+
 	fn tag_len(&self) -> usize { TagLength::to_usize() }
 
 	fn seal(&mut self, sequence_number:u32, plaintext_in_ciphertext_out:&mut [u8], tag:&mut [u8]) {
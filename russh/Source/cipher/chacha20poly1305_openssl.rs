@@ -0,0 +1,236 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// http://cvsweb.openbsd.org/cgi-bin/cvsweb/src/usr.bin/ssh/PROTOCOL.chacha20poly1305?annotate=HEAD
+//
+// OpenSSH's construction uses two ChaCha20 keys (one for the packet length,
+// one for the payload and the Poly1305 key) and a 64-bit nonce, so it cannot
+// be expressed with `EVP_chacha20_poly1305`. It is built here from the raw
+// `EVP_chacha20` stream cipher and a Poly1305 `EVP_PKEY`, as OpenSSH does in
+// `cipher-chachapoly-libcrypto.c`.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use openssl::{
+	cipher::Cipher,
+	cipher_ctx::CipherCtx,
+	memcmp,
+	pkey::{Id, PKey},
+	sign::Signer,
+};
+use zeroize::Zeroize;
+
+use super::{super::Error, PACKET_LENGTH_LEN};
+use crate::mac::MacAlgorithm;
+
+pub struct SshChacha20Poly1305Cipher {}
+
+const KEY_LEN:usize = 32;
+
+const TAG_LEN:usize = 16;
+
+impl super::Cipher for SshChacha20Poly1305Cipher {
+	fn key_len(&self) -> usize { KEY_LEN * 2 }
+
+	fn make_opening_key(
+		&self,
+		k:&[u8],
+		_:&[u8],
+		_:&[u8],
+		_:&dyn MacAlgorithm,
+	) -> Result<Box<dyn super::OpeningKey + Send>, Error> {
+		Ok(Box::new(OpeningKey(Keys::new(k)?)))
+	}
+
+	fn make_sealing_key(
+		&self,
+		k:&[u8],
+		_:&[u8],
+		_:&[u8],
+		_:&dyn MacAlgorithm,
+	) -> Result<Box<dyn super::SealingKey + Send>, Error> {
+		Ok(Box::new(SealingKey(Keys::new(k)?)))
+	}
+}
+
+struct Keys {
+	/// Key for the packet length.
+	k1:[u8; KEY_LEN],
+	/// Key for the payload and the Poly1305 key.
+	k2:[u8; KEY_LEN],
+}
+
+impl Keys {
+	fn new(k:&[u8]) -> Result<Self, Error> {
+		if k.len() != KEY_LEN * 2 {
+			return Err(Error::IndexOutOfBounds);
+		}
+
+		let mut keys = Keys { k1:[0; KEY_LEN], k2:[0; KEY_LEN] };
+
+		let (k2, k1) = k.split_at(KEY_LEN);
+
+		keys.k1.clone_from_slice(k1);
+
+		keys.k2.clone_from_slice(k2);
+
+		Ok(keys)
+	}
+}
+
+impl Drop for Keys {
+	fn drop(&mut self) {
+		self.k1.zeroize();
+
+		self.k2.zeroize();
+	}
+}
+
+/// The 16-byte IV of `EVP_chacha20` is a little-endian block counter
+/// followed by the nonce. OpenSSH uses a 64-bit counter and the sequence
+/// number as a 64-bit big-endian nonce.
+#[allow(clippy::indexing_slicing)] // length checked
+fn make_iv(counter:u64, sequence_number:u32) -> [u8; 16] {
+	let mut iv = [0; 16];
+
+	LittleEndian::write_u64(&mut iv[..8], counter);
+
+	BigEndian::write_u64(&mut iv[8..], sequence_number as u64);
+
+	iv
+}
+
+fn apply_keystream(key:&[u8], iv:&[u8; 16], data:&mut [u8]) -> Result<(), Error> {
+	let mut ctx = CipherCtx::new()?;
+
+	ctx.encrypt_init(Some(Cipher::chacha20()), Some(key), Some(iv))?;
+
+	let len = data.len();
+
+	let n = ctx.cipher_update_inplace(data, len)?;
+
+	#[allow(clippy::indexing_slicing)] // n <= data.len()
+	ctx.cipher_final(&mut data[n..])?;
+
+	Ok(())
+}
+
+fn compute_poly1305(key:&[u8], sequence_number:u32, data:&[u8]) -> Result<[u8; TAG_LEN], Error> {
+	let mut poly_key = [0; 32];
+
+	apply_keystream(key, &make_iv(0, sequence_number), &mut poly_key)?;
+
+	let pkey = PKey::private_key_from_raw_bytes(&poly_key, Id::POLY1305);
+
+	poly_key.zeroize();
+
+	let pkey = pkey?;
+
+	let mut signer = Signer::new_without_digest(&pkey)?;
+
+	let mut tag = [0; TAG_LEN];
+
+	signer.sign_oneshot(&mut tag, data)?;
+
+	Ok(tag)
+}
+
+pub struct OpeningKey(Keys);
+
+pub struct SealingKey(Keys);
+
+impl super::OpeningKey for OpeningKey {
+	fn decrypt_packet_length(
+		&self,
+		sequence_number:u32,
+		encrypted_packet_length:&[u8],
+	) -> Result<[u8; 4], Error> {
+		let mut packet_length = [0; PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			encrypted_packet_length.get(..PACKET_LENGTH_LEN).ok_or(Error::IndexOutOfBounds)?,
+		);
+
+		apply_keystream(&self.0.k1, &make_iv(0, sequence_number), &mut packet_length)?;
+
+		Ok(packet_length)
+	}
+
+	fn tag_len(&self) -> usize { TAG_LEN }
+
+	fn open<'a>(
+		&mut self,
+		sequence_number:u32,
+		ciphertext_in_plaintext_out:&'a mut [u8],
+		tag:&[u8],
+	) -> Result<&'a [u8], Error> {
+		if ciphertext_in_plaintext_out.len() < PACKET_LENGTH_LEN || tag.len() != TAG_LEN {
+			return Err(Error::IndexOutOfBounds);
+		}
+
+		let expected_tag = compute_poly1305(&self.0.k2, sequence_number, ciphertext_in_plaintext_out)?;
+
+		if !memcmp::eq(&expected_tag, tag) {
+			return Err(Error::DecryptionError);
+		}
+
+		let (_, payload) = ciphertext_in_plaintext_out.split_at_mut(PACKET_LENGTH_LEN);
+
+		apply_keystream(&self.0.k2, &make_iv(1, sequence_number), payload)?;
+
+		Ok(payload)
+	}
+}
+
+#[allow(clippy::expect_used)]
+impl super::SealingKey for SealingKey {
+	fn padding_length(&self, payload:&[u8]) -> usize {
+		let block_size = 8;
+
+		let extra_len = PACKET_LENGTH_LEN + super::PADDING_LENGTH_LEN;
+
+		let padding_len = if payload.len() + extra_len <= super::MINIMUM_PACKET_LEN {
+			super::MINIMUM_PACKET_LEN - payload.len() - super::PADDING_LENGTH_LEN
+		} else {
+			block_size - ((super::PADDING_LENGTH_LEN + payload.len()) % block_size)
+		};
+
+		if padding_len < PACKET_LENGTH_LEN { padding_len + block_size } else { padding_len }
+	}
+
+	// See the rs-crypto implementation: counter mode doesn't need random
+	// padding.
+	fn fill_padding(&self, padding_out:&mut [u8]) {
+		for padding_byte in padding_out {
+			*padding_byte = 0;
+		}
+	}
+
+	fn padding_block_size(&self) -> usize { 8 }
+
+	fn tag_len(&self) -> usize { TAG_LEN }
+
+	fn seal(&mut self, sequence_number:u32, plaintext_in_ciphertext_out:&mut [u8], tag:&mut [u8]) {
+		let (packet_length, payload) = plaintext_in_ciphertext_out.split_at_mut(PACKET_LENGTH_LEN);
+
+		apply_keystream(&self.0.k1, &make_iv(0, sequence_number), packet_length)
+			.expect("cipher update should not fail");
+
+		apply_keystream(&self.0.k2, &make_iv(1, sequence_number), payload)
+			.expect("cipher update should not fail");
+
+		tag.clone_from_slice(
+			&compute_poly1305(&self.0.k2, sequence_number, plaintext_in_ciphertext_out)
+				.expect("poly1305 should not fail"),
+		);
+	}
+}
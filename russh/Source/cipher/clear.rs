@@ -45,8 +45,14 @@ impl super::Cipher for Clear {
 }
 
 impl super::OpeningKey for Key {
-	fn decrypt_packet_length(&self, _seqn:u32, packet_length:[u8; 4]) -> Result<[u8; 4], Error> {
-		Ok(packet_length)
+	fn decrypt_packet_length(&self, _seqn:u32, packet_length:&[u8]) -> Result<[u8; 4], Error> {
+		let mut len = [0; super::PACKET_LENGTH_LEN];
+
+		len.clone_from_slice(
+			packet_length.get(..super::PACKET_LENGTH_LEN).ok_or(Error::IndexOutOfBounds)?,
+		);
+
+		Ok(len)
 	}
 
 	fn tag_len(&self) -> usize { 0 }
@@ -74,6 +80,8 @@ impl super::SealingKey for Key {
 		if padding_len < 4 { padding_len + block_size } else { padding_len }
 	}
 
+	fn padding_block_size(&self) -> usize { 8 }
+
 	fn fill_padding(&self, padding_out:&mut [u8]) {
 		// Since the packet is unencrypted anyway, there's no advantage to
 		// randomizing the padding, so avoid possibly leaking extra RNG state
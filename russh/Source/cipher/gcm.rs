@@ -15,23 +15,31 @@
 
 // http://cvsweb.openbsd.org/cgi-bin/cvsweb/src/usr.bin/ssh/PROTOCOL.chacha20poly1305?annotate=HEAD
 
+use std::marker::PhantomData;
+
 use aes_gcm::{AeadCore, AeadInPlace, Aes256Gcm, KeyInit, KeySizeUser};
 use byteorder::{BigEndian, ByteOrder};
 use digest::typenum::Unsigned;
 use generic_array::GenericArray;
 use rand::RngCore;
+use zeroize::Zeroize;
 
 use super::super::Error;
 use crate::mac::MacAlgorithm;
 
-pub struct GcmCipher {}
+/// AES-GCM as specified for `aes128-gcm@openssh.com` and
+/// `aes256-gcm@openssh.com`, generic over the key size.
+pub struct GcmCipher<C>(pub PhantomData<C>);
 
-type KeySize = <Aes256Gcm as KeySizeUser>::KeySize;
+// Nonce and tag sizes are identical for every AES-GCM key size.
 type NonceSize = <Aes256Gcm as AeadCore>::NonceSize;
 type TagSize = <Aes256Gcm as AeadCore>::TagSize;
 
-impl super::Cipher for GcmCipher {
-	fn key_len(&self) -> usize { Aes256Gcm::key_size() }
+impl<C> super::Cipher for GcmCipher<C>
+where
+	C: KeyInit + AeadInPlace + AeadCore<NonceSize = NonceSize, TagSize = TagSize> + Send + 'static,
+{
+	fn key_len(&self) -> usize { C::key_size() }
 
 	fn nonce_len(&self) -> usize { GenericArray::<u8, NonceSize>::default().len() }
 
@@ -42,7 +50,7 @@ impl super::Cipher for GcmCipher {
 		_:&[u8],
 		_:&dyn MacAlgorithm,
 	) -> Result<Box<dyn super::OpeningKey + Send>, Error> {
-		let mut key = GenericArray::<u8, KeySize>::default();
+		let mut key = GenericArray::<u8, <C as KeySizeUser>::KeySize>::default();
 
 		key.clone_from_slice(k);
 
@@ -50,7 +58,11 @@ impl super::Cipher for GcmCipher {
 
 		nonce.clone_from_slice(n);
 
-		Ok(Box::new(OpeningKey { nonce, cipher:Aes256Gcm::new(&key) }))
+		let cipher = C::new(&key);
+
+		key.as_mut_slice().zeroize();
+
+		Ok(Box::new(OpeningKey { nonce, cipher }))
 	}
 
 	fn make_sealing_key(
@@ -60,7 +72,7 @@ impl super::Cipher for GcmCipher {
 		_:&[u8],
 		_:&dyn MacAlgorithm,
 	) -> Result<Box<dyn super::SealingKey + Send>, Error> {
-		let mut key = GenericArray::<u8, KeySize>::default();
+		let mut key = GenericArray::<u8, <C as KeySizeUser>::KeySize>::default();
 
 		key.clone_from_slice(k);
 
@@ -68,18 +80,31 @@ impl super::Cipher for GcmCipher {
 
 		nonce.clone_from_slice(n);
 
-		Ok(Box::new(SealingKey { nonce, cipher:Aes256Gcm::new(&key) }))
+		let cipher = C::new(&key);
+
+		key.as_mut_slice().zeroize();
+
+		Ok(Box::new(SealingKey { nonce, cipher }))
 	}
 }
 
-pub struct OpeningKey {
+pub struct OpeningKey<C> {
 	nonce:GenericArray<u8, NonceSize>,
-	cipher:Aes256Gcm,
+	cipher:C,
 }
 
-pub struct SealingKey {
+pub struct SealingKey<C> {
 	nonce:GenericArray<u8, NonceSize>,
-	cipher:Aes256Gcm,
+	cipher:C,
+}
+
+// The AES key schedule is zeroized by `aes-gcm` itself.
+impl<C> Drop for OpeningKey<C> {
+	fn drop(&mut self) { self.nonce.as_mut_slice().zeroize(); }
+}
+
+impl<C> Drop for SealingKey<C> {
+	fn drop(&mut self) { self.nonce.as_mut_slice().zeroize(); }
 }
 
 const GCM_COUNTER_OFFSET:u64 = 3;
@@ -104,13 +129,23 @@ fn make_nonce(
 	new_nonce
 }
 
-impl super::OpeningKey for OpeningKey {
+impl<C:AeadInPlace + AeadCore<NonceSize = NonceSize, TagSize = TagSize>> super::OpeningKey
+	for OpeningKey<C>
+{
 	fn decrypt_packet_length(
 		&self,
 		_sequence_number:u32,
-		encrypted_packet_length:[u8; 4],
+		encrypted_packet_length:&[u8],
 	) -> Result<[u8; 4], Error> {
-		Ok(encrypted_packet_length)
+		let mut packet_length = [0; super::PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			encrypted_packet_length
+				.get(..super::PACKET_LENGTH_LEN)
+				.ok_or(Error::IndexOutOfBounds)?,
+		);
+
+		Ok(packet_length)
 	}
 
 	fn tag_len(&self) -> usize { TagSize::to_usize() }
@@ -148,11 +183,13 @@ impl super::OpeningKey for OpeningKey {
 			)
 			.map_err(|_| Error::DecryptionError)?;
 
-		Ok(ciphertext_in_plaintext_out)
+		ciphertext_in_plaintext_out.get(super::PACKET_LENGTH_LEN..).ok_or(Error::IndexOutOfBounds)
 	}
 }
 
-impl super::SealingKey for SealingKey {
+impl<C:AeadInPlace + AeadCore<NonceSize = NonceSize, TagSize = TagSize>> super::SealingKey
+	for SealingKey<C>
+{
 	fn padding_length(&self, payload:&[u8]) -> usize {
 		let block_size = 16;
 
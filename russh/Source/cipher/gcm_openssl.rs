@@ -0,0 +1,200 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// https://tools.ietf.org/html/rfc5647, with the OpenSSH changes described in
+// http://cvsweb.openbsd.org/cgi-bin/cvsweb/src/usr.bin/ssh/PROTOCOL?annotate=HEAD
+
+use byteorder::{BigEndian, ByteOrder};
+use openssl::{cipher::CipherRef, cipher_ctx::CipherCtx};
+use rand::RngCore;
+use zeroize::Zeroize;
+
+use super::{super::Error, PACKET_LENGTH_LEN};
+use crate::mac::MacAlgorithm;
+
+/// AES-GCM as specified for `aes128-gcm@openssh.com` and
+/// `aes256-gcm@openssh.com`, implemented with OpenSSL.
+pub struct GcmCipher(pub fn() -> &'static CipherRef);
+
+const NONCE_LEN:usize = 12;
+
+const TAG_LEN:usize = 16;
+
+impl super::Cipher for GcmCipher {
+	fn key_len(&self) -> usize { self.0().key_length() }
+
+	fn nonce_len(&self) -> usize { NONCE_LEN }
+
+	fn make_opening_key(
+		&self,
+		k:&[u8],
+		n:&[u8],
+		_:&[u8],
+		_:&dyn MacAlgorithm,
+	) -> Result<Box<dyn super::OpeningKey + Send>, Error> {
+		Ok(Box::new(OpeningKey(Key::new(self.0(), k, n)?)))
+	}
+
+	fn make_sealing_key(
+		&self,
+		k:&[u8],
+		n:&[u8],
+		_:&[u8],
+		_:&dyn MacAlgorithm,
+	) -> Result<Box<dyn super::SealingKey + Send>, Error> {
+		Ok(Box::new(SealingKey(Key::new(self.0(), k, n)?)))
+	}
+}
+
+struct Key {
+	cipher:&'static CipherRef,
+	key:Vec<u8>,
+	nonce:[u8; NONCE_LEN],
+}
+
+impl Key {
+	fn new(cipher:&'static CipherRef, k:&[u8], n:&[u8]) -> Result<Self, Error> {
+		if k.len() != cipher.key_length() {
+			return Err(Error::IndexOutOfBounds);
+		}
+
+		let mut nonce = [0; NONCE_LEN];
+
+		nonce.clone_from_slice(n.get(..NONCE_LEN).ok_or(Error::IndexOutOfBounds)?);
+
+		Ok(Key { cipher, key:k.to_vec(), nonce })
+	}
+
+	/// Same nonce derivation as the `aes-gcm` implementation.
+	#[allow(clippy::indexing_slicing)] // length checked
+	fn make_nonce(&self, sequence_number:u32) -> [u8; NONCE_LEN] {
+		let mut nonce = self.nonce;
+
+		let i0 = NONCE_LEN - 8;
+
+		let ctr = BigEndian::read_u64(&nonce[i0..]);
+
+		BigEndian::write_u64(&mut nonce[i0..], ctr + sequence_number as u64 - GCM_COUNTER_OFFSET);
+
+		nonce
+	}
+}
+
+impl Drop for Key {
+	fn drop(&mut self) {
+		self.key.zeroize();
+
+		self.nonce.zeroize();
+	}
+}
+
+const GCM_COUNTER_OFFSET:u64 = 3;
+
+pub struct OpeningKey(Key);
+
+pub struct SealingKey(Key);
+
+impl super::OpeningKey for OpeningKey {
+	fn decrypt_packet_length(
+		&self,
+		_sequence_number:u32,
+		encrypted_packet_length:&[u8],
+	) -> Result<[u8; 4], Error> {
+		let mut packet_length = [0; PACKET_LENGTH_LEN];
+
+		packet_length.clone_from_slice(
+			encrypted_packet_length.get(..PACKET_LENGTH_LEN).ok_or(Error::IndexOutOfBounds)?,
+		);
+
+		Ok(packet_length)
+	}
+
+	fn tag_len(&self) -> usize { TAG_LEN }
+
+	fn open<'a>(
+		&mut self,
+		sequence_number:u32,
+		ciphertext_in_plaintext_out:&'a mut [u8],
+		tag:&[u8],
+	) -> Result<&'a [u8], Error> {
+		if ciphertext_in_plaintext_out.len() < PACKET_LENGTH_LEN || tag.len() != TAG_LEN {
+			return Err(Error::IndexOutOfBounds);
+		}
+
+		let nonce = self.0.make_nonce(sequence_number);
+
+		let mut ctx = CipherCtx::new()?;
+
+		ctx.decrypt_init(Some(self.0.cipher), Some(&self.0.key), Some(&nonce))?;
+
+		let (packet_length, ciphertext) = ciphertext_in_plaintext_out.split_at_mut(PACKET_LENGTH_LEN);
+
+		// Packet length is sent unencrypted, as additional data.
+		ctx.cipher_update(packet_length, None)?;
+
+		let len = ciphertext.len();
+
+		let n = ctx.cipher_update_inplace(ciphertext, len)?;
+
+		ctx.set_tag(tag)?;
+
+		#[allow(clippy::indexing_slicing)] // n <= ciphertext.len()
+		ctx.cipher_final(&mut ciphertext[n..]).map_err(|_| Error::DecryptionError)?;
+
+		Ok(ciphertext)
+	}
+}
+
+#[allow(clippy::expect_used)]
+impl super::SealingKey for SealingKey {
+	fn padding_length(&self, payload:&[u8]) -> usize {
+		let block_size = 16;
+
+		let extra_len = PACKET_LENGTH_LEN + super::PADDING_LENGTH_LEN;
+
+		let padding_len = if payload.len() + extra_len <= super::MINIMUM_PACKET_LEN {
+			super::MINIMUM_PACKET_LEN - payload.len() - super::PADDING_LENGTH_LEN
+		} else {
+			block_size - ((super::PADDING_LENGTH_LEN + payload.len()) % block_size)
+		};
+
+		if padding_len < PACKET_LENGTH_LEN { padding_len + block_size } else { padding_len }
+	}
+
+	fn fill_padding(&self, padding_out:&mut [u8]) { rand::thread_rng().fill_bytes(padding_out); }
+
+	fn tag_len(&self) -> usize { TAG_LEN }
+
+	fn seal(&mut self, sequence_number:u32, plaintext_in_ciphertext_out:&mut [u8], tag:&mut [u8]) {
+		let nonce = self.0.make_nonce(sequence_number);
+
+		let mut ctx = CipherCtx::new().expect("expected to make openssl cipher");
+
+		ctx.encrypt_init(Some(self.0.cipher), Some(&self.0.key), Some(&nonce))
+			.expect("cipher init should not fail");
+
+		let (packet_length, plaintext) = plaintext_in_ciphertext_out.split_at_mut(PACKET_LENGTH_LEN);
+
+		// Packet length is sent unencrypted, as additional data.
+		ctx.cipher_update(packet_length, None).expect("cipher update should not fail");
+
+		let len = plaintext.len();
+
+		let n = ctx.cipher_update_inplace(plaintext, len).expect("cipher update should not fail");
+
+		#[allow(clippy::indexing_slicing)] // n <= plaintext.len()
+		ctx.cipher_final(&mut plaintext[n..]).expect("cipher final should not fail");
+
+		ctx.tag(tag).expect("tag should not fail");
+	}
+}
@@ -15,14 +15,17 @@
 //! This module exports cipher names for use with [Preferred].
 #[cfg(feature = "rs-crypto")]
 use std::marker::PhantomData;
-use std::{collections::HashMap, fmt::Debug, num::Wrapping};
+use std::{collections::HashMap, fmt::Debug, num::Wrapping, ops::RangeInclusive, sync::Arc};
 
 use byteorder::{BigEndian, ByteOrder};
 use log::debug;
 use once_cell::sync::Lazy;
+use rand::{Rng, RngCore};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::{Error, mac::MacAlgorithm, sshbuffer::SSHBuffer};
+#[doc(hidden)]
+pub use crate::sshbuffer::SSHBuffer;
+use crate::{Error, compression::Compress, mac::MacAlgorithm, pool};
 
 pub(crate) mod clear;
 
@@ -31,19 +34,32 @@ pub(crate) mod aes_openssh;
 #[cfg(feature = "rs-crypto")]
 pub(crate) mod block;
 #[cfg(feature = "rs-crypto")]
+pub(crate) mod cbc;
+#[cfg(feature = "rs-crypto")]
 pub(crate) mod chacha20poly1305;
+#[cfg(feature = "openssl")]
+pub(crate) mod chacha20poly1305_openssl;
 #[cfg(feature = "rs-crypto")]
 pub(crate) mod gcm;
+#[cfg(feature = "openssl")]
+pub(crate) mod gcm_openssl;
 
 #[cfg(feature = "rs-crypto")]
 use block::SshBlockCipher;
 #[cfg(feature = "rs-crypto")]
+use cbc::CbcWrapper;
+#[cfg(feature = "rs-crypto")]
 use chacha20poly1305::SshChacha20Poly1305Cipher;
 use clear::Clear;
 #[cfg(feature = "rs-crypto")]
 use gcm::GcmCipher;
 
-pub(crate) trait Cipher {
+/// A symmetric cipher, used to derive the [`OpeningKey`] and
+/// [`SealingKey`] of each direction after a key exchange.
+///
+/// Custom implementations can be made available to a session with
+/// [`CipherRegistry::register`].
+pub trait Cipher {
 	fn needs_mac(&self) -> bool { false }
 
 	fn key_len(&self) -> usize;
@@ -75,6 +91,15 @@ pub const AES_128_CTR:Name = Name("aes128-ctr");
 pub const AES_192_CTR:Name = Name("aes192-ctr");
 /// `aes256-ctr`
 pub const AES_256_CTR:Name = Name("aes256-ctr");
+/// `aes128-cbc`
+pub const AES_128_CBC:Name = Name("aes128-cbc");
+/// `aes256-cbc`
+pub const AES_256_CBC:Name = Name("aes256-cbc");
+/// `3des-cbc`
+#[cfg(feature = "legacy-ciphers")]
+pub const TRIPLE_DES_CBC:Name = Name("3des-cbc");
+/// `aes128-gcm@openssh.com`
+pub const AES_128_GCM:Name = Name("aes128-gcm@openssh.com");
 /// `aes256-gcm@openssh.com`
 pub const AES_256_GCM:Name = Name("aes256-gcm@openssh.com");
 /// `chacha20-poly1305@openssh.com`
@@ -103,8 +128,29 @@ static _AES_256_CTR:aes_openssh::AesSshCipher =
 static _AES_256_CTR:SshBlockCipher<ctr::Ctr128BE<aes::Aes256>> = SshBlockCipher(PhantomData);
 
 #[cfg(feature = "rs-crypto")]
-static _AES_256_GCM:GcmCipher = GcmCipher {};
+static _AES_128_CBC:SshBlockCipher<CbcWrapper<aes::Aes128>> = SshBlockCipher(PhantomData);
+
+#[cfg(feature = "rs-crypto")]
+static _AES_256_CBC:SshBlockCipher<CbcWrapper<aes::Aes256>> = SshBlockCipher(PhantomData);
+
+#[cfg(feature = "legacy-ciphers")]
+static _TRIPLE_DES_CBC:SshBlockCipher<CbcWrapper<des::TdesEde3>> = SshBlockCipher(PhantomData);
+
+#[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
+static _AES_128_GCM:gcm_openssl::GcmCipher =
+	gcm_openssl::GcmCipher(openssl::cipher::Cipher::aes_128_gcm);
+#[cfg(feature = "rs-crypto")]
+static _AES_128_GCM:GcmCipher<aes_gcm::Aes128Gcm> = GcmCipher(PhantomData);
 
+#[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
+static _AES_256_GCM:gcm_openssl::GcmCipher =
+	gcm_openssl::GcmCipher(openssl::cipher::Cipher::aes_256_gcm);
+#[cfg(feature = "rs-crypto")]
+static _AES_256_GCM:GcmCipher<aes_gcm::Aes256Gcm> = GcmCipher(PhantomData);
+
+#[cfg(all(feature = "openssl", not(feature = "rs-crypto")))]
+static _CHACHA20_POLY1305:chacha20poly1305_openssl::SshChacha20Poly1305Cipher =
+	chacha20poly1305_openssl::SshChacha20Poly1305Cipher {};
 #[cfg(feature = "rs-crypto")]
 static _CHACHA20_POLY1305:SshChacha20Poly1305Cipher = SshChacha20Poly1305Cipher {};
 
@@ -122,19 +168,60 @@ pub(crate) static CIPHERS:Lazy<HashMap<&'static Name, &(dyn Cipher + Send + Sync
 
 		h.insert(&AES_256_CTR, &_AES_256_CTR);
 		#[cfg(feature = "rs-crypto")]
-		h.insert(&AES_256_GCM, &_AES_256_GCM);
+		h.insert(&AES_128_CBC, &_AES_128_CBC);
 		#[cfg(feature = "rs-crypto")]
+		h.insert(&AES_256_CBC, &_AES_256_CBC);
+		#[cfg(feature = "legacy-ciphers")]
+		h.insert(&TRIPLE_DES_CBC, &_TRIPLE_DES_CBC);
+		#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
+		h.insert(&AES_128_GCM, &_AES_128_GCM);
+		#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
+		h.insert(&AES_256_GCM, &_AES_256_GCM);
+		#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
 		h.insert(&CHACHA20_POLY1305, &_CHACHA20_POLY1305);
 
 		h
 	});
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
-pub struct Name(&'static str);
+pub struct Name(pub &'static str);
 impl AsRef<str> for Name {
 	fn as_ref(&self) -> &str { self.0 }
 }
 
+/// The ciphers available to a session. Defaults to the built-in
+/// ciphers, and can be extended with custom implementations, which
+/// take precedence over built-in ones of the same name.
+///
+/// Registered ciphers still need to be listed in
+/// [`Preferred::cipher`](crate::Preferred::cipher) to be negotiated.
+#[derive(Clone, Default)]
+pub struct CipherRegistry {
+	custom:HashMap<Name, Arc<dyn Cipher + Send + Sync>>,
+}
+
+impl CipherRegistry {
+	/// Register a cipher implementation under `name`.
+	pub fn register(&mut self, name:Name, cipher:Arc<dyn Cipher + Send + Sync>) {
+		self.custom.insert(name, cipher);
+	}
+
+	/// The cipher registered under `name`, or else the built-in one.
+	pub fn get(&self, name:&Name) -> Option<&(dyn Cipher + Send + Sync)> {
+		if let Some(cipher) = self.custom.get(name) {
+			return Some(cipher.as_ref());
+		}
+
+		CIPHERS.get(name).copied()
+	}
+}
+
+impl Debug for CipherRegistry {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		f.debug_set().entries(self.custom.keys()).finish()
+	}
+}
+
 pub(crate) struct CipherPair {
 	pub local_to_remote:Box<dyn SealingKey + Send>,
 	pub remote_to_local:Box<dyn OpeningKey + Send>,
@@ -144,11 +231,17 @@ impl Debug for CipherPair {
 	fn fmt(&self, _:&mut std::fmt::Formatter) -> Result<(), std::fmt::Error> { Ok(()) }
 }
 
-pub(crate) trait OpeningKey {
+/// Decrypts and authenticates incoming packets.
+pub trait OpeningKey {
+	/// Number of bytes to read before the packet length can be
+	/// decrypted. Block ciphers with an encrypted length need a whole
+	/// block.
+	fn packet_length_to_read_for_block_length(&self) -> usize { PACKET_LENGTH_LEN }
+
 	fn decrypt_packet_length(
 		&self,
 		seqn:u32,
-		encrypted_packet_length:[u8; 4],
+		encrypted_packet_length:&[u8],
 	) -> Result<[u8; 4], Error>;
 
 	fn tag_len(&self) -> usize;
@@ -161,93 +254,213 @@ pub(crate) trait OpeningKey {
 	) -> Result<&'a [u8], Error>;
 }
 
-pub(crate) trait SealingKey {
+/// Encrypts and authenticates outgoing packets.
+pub trait SealingKey {
 	fn padding_length(&self, plaintext:&[u8]) -> usize;
 
 	fn fill_padding(&self, padding_out:&mut [u8]);
 
+	/// The length padding is aligned on, in which random extra padding
+	/// is added, see [`crate::server::Config::random_padding`]. The
+	/// default suits all block sizes up to 16.
+	fn padding_block_size(&self) -> usize { 16 }
+
 	fn tag_len(&self) -> usize;
 
 	fn seal(&mut self, seqn:u32, plaintext_in_ciphertext_out:&mut [u8], tag_out:&mut [u8]);
 
-	fn write(&mut self, payload:&[u8], buffer:&mut SSHBuffer) {
+	/// Write a packet of `payload` to `buffer`, compressed first if
+	/// compression was started on `buffer`.
+	fn write(&mut self, payload:&[u8], buffer:&mut SSHBuffer) -> Result<(), Error> {
+		let mut compress = std::mem::replace(&mut buffer.compress, Compress::None);
+
+		let mut compressed = std::mem::take(&mut buffer.compress_buffer);
+
+		let result = compress.compress(payload, &mut compressed).map(|packet| {
+			self.write_with(packet.len(), &mut |out| out.copy_from_slice(packet), buffer);
+
+			// Count the payload before compression.
+			buffer.payload_bytes += payload.len() as u64;
+
+			buffer.payload_bytes -= packet.len() as u64;
+		});
+
+		buffer.compress = compress;
+		// The compressed payload is still plaintext: zero it now, rather
+		// than when the next packet overwrites it.
+		compressed.clear();
+
+		pool::trim(&mut compressed);
+
+		buffer.compress_buffer = compressed;
+
+		result
+	}
+
+	/// Same as [`SealingKey::write`], but `fill` writes the `payload_len`
+	/// bytes of the payload directly into the packet, so that the
+	/// payload is never copied.
+	#[allow(clippy::indexing_slicing)] // lengths checked
+	fn write_with(
+		&mut self,
+		payload_len:usize,
+		fill:&mut dyn FnMut(&mut [u8]),
+		buffer:&mut SSHBuffer,
+	) {
 		// https://tools.ietf.org/html/rfc4253#section-6
 		//
 		// The variables `payload`, `packet_length` and `padding_length` refer
 		// to the protocol fields of the same names.
 		debug!("writing, seqn = {:?}", buffer.seqn.0);
 
-		let padding_length = self.padding_length(payload);
+		// The buffer may have been returned to the pool once flushed.
+		pool::refill(&mut buffer.buffer);
+
+		let offset = buffer.buffer.len();
+
+		let payload_offset = offset + PACKET_LENGTH_LEN + PADDING_LENGTH_LEN;
+
+		fill(&mut buffer.buffer.resize_mut(PACKET_LENGTH_LEN + PADDING_LENGTH_LEN + payload_len)
+			[PACKET_LENGTH_LEN + PADDING_LENGTH_LEN..]);
+
+		let mut padding_length = self.padding_length(&buffer.buffer[payload_offset..]);
+
+		let tag_len = self.tag_len();
+
+		if let Some(blocks) = &buffer.random_padding {
+			let packet_len = PACKET_LENGTH_LEN + PADDING_LENGTH_LEN + payload_len + tag_len;
+
+			padding_length += random_padding(
+				blocks,
+				self.padding_block_size(),
+				packet_len + padding_length,
+				padding_length,
+			);
+		}
 
 		debug!("padding length {:?}", padding_length);
 
-		let packet_length = PADDING_LENGTH_LEN + payload.len() + padding_length;
+		let packet_length = PADDING_LENGTH_LEN + payload_len + padding_length;
 
 		debug!("packet_length {:?}", packet_length);
 
-		let offset = buffer.buffer.len();
-
 		// Maximum packet length:
 		// https://tools.ietf.org/html/rfc4253#section-6.1
-		assert!(packet_length <= std::u32::MAX as usize);
+		assert!(packet_length <= u32::MAX as usize);
 
-		buffer.buffer.push_u32_be(packet_length as u32);
+		assert!(padding_length <= u8::MAX as usize);
 
-		assert!(padding_length <= std::u8::MAX as usize);
+		// Padding and tag are allocated at once, the tag is written by `seal`.
+		let padding = &mut buffer.buffer.resize_mut(padding_length + tag_len)[..padding_length];
 
-		buffer.buffer.push(padding_length as u8);
+		// Random lengths are only worth it with random contents.
+		if buffer.random_padding.is_some() {
+			rand::thread_rng().fill_bytes(padding);
+		} else {
+			self.fill_padding(padding);
+		}
 
-		buffer.buffer.extend(payload);
+		let packet = &mut buffer.buffer[offset..];
 
-		self.fill_padding(buffer.buffer.resize_mut(padding_length));
+		BigEndian::write_u32(&mut packet[..PACKET_LENGTH_LEN], packet_length as u32);
 
-		buffer.buffer.resize_mut(self.tag_len());
+		packet[PACKET_LENGTH_LEN] = padding_length as u8;
 
-		#[allow(clippy::indexing_slicing)] // length checked
-		let (plaintext, tag) = buffer.buffer[offset..].split_at_mut(PACKET_LENGTH_LEN + packet_length);
+		let (plaintext, tag) = packet.split_at_mut(PACKET_LENGTH_LEN + packet_length);
 
 		self.seal(buffer.seqn.0, plaintext, tag);
 
-		buffer.bytes += payload.len();
+		buffer.bytes += payload_len;
+
+		buffer.packets += 1;
+
+		buffer.wire_bytes += (buffer.buffer.len() - offset) as u64;
+
+		buffer.payload_bytes += payload_len as u64;
 		// Sequence numbers are on 32 bits and wrap.
 		// https://tools.ietf.org/html/rfc4253#section-6.4
 		buffer.seqn += Wrapping(1);
 	}
 }
 
+/// The random extra padding of a packet of `packet_len` bytes with
+/// `padding_length` bytes of padding: a number of blocks of `block_size`
+/// drawn from `blocks`, fewer if the padding would not fit in a byte or
+/// the packet in [`MAXIMUM_SENT_PACKET_LEN`].
+fn random_padding(
+	blocks:&RangeInclusive<usize>,
+	block_size:usize,
+	packet_len:usize,
+	padding_length:usize,
+) -> usize {
+	if blocks.is_empty() || block_size == 0 {
+		return 0;
+	}
+
+	let max_blocks = ((u8::MAX as usize).saturating_sub(padding_length) / block_size)
+		.min(MAXIMUM_SENT_PACKET_LEN.saturating_sub(packet_len) / block_size);
+
+	rand::thread_rng().gen_range(blocks.clone()).min(max_blocks) * block_size
+}
+
 pub(crate) async fn read<'a, R:AsyncRead + Unpin>(
 	stream:&'a mut R,
 	buffer:&'a mut SSHBuffer,
 	cipher:&'a mut (dyn OpeningKey + Send),
+	max_packet_size:usize,
 ) -> Result<usize, Error> {
+	let first_block_len = cipher.packet_length_to_read_for_block_length();
+
 	if buffer.len == 0 {
-		let mut len = [0; 4];
+		// Hold no buffer while waiting for the packet, which on an idle
+		// connection may take long.
+		pool::release(&mut buffer.buffer);
+
+		let mut first_block = [0; MAX_FIRST_BLOCK_LEN];
 
-		stream.read_exact(&mut len).await?;
+		let len = first_block.get_mut(..first_block_len).ok_or(Error::IndexOutOfBounds)?;
+
+		stream.read_exact(len).await?;
 
 		debug!("reading, len = {:?}", len);
 		{
 			let seqn = buffer.seqn.0;
 
-			buffer.buffer.clear();
+			buffer.buffer = pool::take();
 
-			buffer.buffer.extend(&len);
+			buffer.buffer.extend(len);
 
 			debug!("reading, seqn = {:?}", seqn);
 
-			let len = cipher.decrypt_packet_length(seqn, len)?;
+			let len = cipher.decrypt_packet_length(seqn, &len)?;
+
+			let len = BigEndian::read_u32(&len) as usize;
 
-			buffer.len = BigEndian::read_u32(&len) as usize + cipher.tag_len();
+			// Check the length before allocating anything, as it comes
+			// straight off the wire.
+			if len > max_packet_size {
+				return Err(Error::PacketTooLarge { length:len, limit:max_packet_size });
+			}
+
+			if len + PACKET_LENGTH_LEN < MINIMUM_PACKET_LEN {
+				return Err(Error::IndexOutOfBounds);
+			}
+
+			buffer.len = len + cipher.tag_len();
 
 			debug!("reading, clear len = {:?}", buffer.len);
 		}
 	}
 
+	if buffer.len + PACKET_LENGTH_LEN < first_block_len {
+		return Err(Error::IndexOutOfBounds);
+	}
+
 	buffer.buffer.resize(buffer.len + 4);
 
 	debug!("read_exact {:?}", buffer.len + 4);
 	#[allow(clippy::indexing_slicing)] // length checked
-	stream.read_exact(&mut buffer.buffer[4..]).await?;
+	stream.read_exact(&mut buffer.buffer[first_block_len..]).await?;
 
 	debug!("read_exact done");
 
@@ -266,6 +479,11 @@ pub(crate) async fn read<'a, R:AsyncRead + Unpin>(
 	let plaintext_end =
 		plaintext.len().checked_sub(padding_length).ok_or(Error::IndexOutOfBounds)?;
 
+	buffer.bytes += plaintext_end;
+
+	buffer.packets += 1;
+
+	buffer.wire_bytes += buffer.buffer.len() as u64;
 	// Sequence numbers are on 32 bits and wrap.
 	// https://tools.ietf.org/html/rfc4253#section-6.4
 	buffer.seqn += Wrapping(1);
@@ -280,6 +498,574 @@ pub(crate) async fn read<'a, R:AsyncRead + Unpin>(
 
 pub(crate) const PACKET_LENGTH_LEN:usize = 4;
 
+/// The longest first read of a packet, to decrypt its length: a cipher
+/// block, or the length alone.
+const MAX_FIRST_BLOCK_LEN:usize = 32;
+
 const MINIMUM_PACKET_LEN:usize = 16;
 
+/// Default limit on the length of incoming packets, as in OpenSSH.
+pub(crate) const MAXIMUM_PACKET_LEN:usize = 256 * 1024;
+
+/// The length of the packets all implementations must accept, which
+/// random padding never makes a packet exceed.
+/// https://tools.ietf.org/html/rfc4253#section-6.1
+const MAXIMUM_SENT_PACKET_LEN:usize = 35000;
+
 const PADDING_LENGTH_LEN:usize = 1;
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::mac;
+
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	async fn roundtrip(cipher_name:&Name, mac_name:&mac::Name) {
+		roundtrip_with(&CipherRegistry::default(), cipher_name, mac_name).await
+	}
+
+	#[allow(clippy::unwrap_used)]
+	async fn roundtrip_with(ciphers:&CipherRegistry, cipher_name:&Name, mac_name:&mac::Name) {
+		let cipher = ciphers.get(cipher_name).unwrap();
+
+		roundtrip_between(cipher, cipher, mac_name).await
+	}
+
+	/// Seals packets with `sealer` and opens them with `opener`, which must
+	/// implement the same algorithm.
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	async fn roundtrip_between(sealer:&dyn Cipher, opener:&dyn Cipher, mac_name:&mac::Name) {
+		let mac = mac::MACS.get(mac_name).unwrap();
+
+		let key = vec![0x2a; sealer.key_len()];
+
+		let nonce = vec![0x17; sealer.nonce_len()];
+
+		let mac_key = vec![0x55; mac.key_len()];
+
+		let mut sealing = sealer.make_sealing_key(&key, &nonce, &mac_key, *mac).unwrap();
+
+		let mut opening = opener.make_opening_key(&key, &nonce, &mac_key, *mac).unwrap();
+
+		let payloads:[&[u8]; 3] = [b"\x05ssh-userauth", &[0x5e; 100], &[0x5e; 1]];
+
+		let mut sent = SSHBuffer::new();
+
+		for payload in payloads {
+			sealing.write(payload, &mut sent).unwrap();
+		}
+
+		let mut stream = &sent.buffer[..];
+
+		let mut received = SSHBuffer::new();
+
+		for payload in payloads {
+			read(&mut stream, &mut received, opening.as_mut(), MAXIMUM_PACKET_LEN).await.unwrap();
+
+			assert_eq!(&received.buffer[5..5 + payload.len()], payload);
+		}
+
+		assert!(stream.is_empty());
+	}
+
+	#[tokio::test]
+	#[allow(clippy::indexing_slicing)]
+	async fn test_read_rejects_oversized_packet() {
+		let mut stream:&[u8] = &[0xff, 0xff, 0xff, 0xf0, 0, 0, 0, 0];
+
+		let mut buffer = SSHBuffer::new();
+
+		let result = read(&mut stream, &mut buffer, &mut clear::Key, MAXIMUM_PACKET_LEN).await;
+
+		assert!(matches!(
+			result,
+			Err(Error::PacketTooLarge { length:0xfffffff0, limit:MAXIMUM_PACKET_LEN })
+		));
+		// Only the length field was read and buffered.
+		assert_eq!(buffer.buffer.len(), PACKET_LENGTH_LEN);
+
+		assert_eq!(stream.len(), 4);
+	}
+
+	#[tokio::test]
+	async fn test_read_rejects_undersized_packet() {
+		let mut stream:&[u8] = &[0, 0, 0, 4, 0, 0, 0, 0];
+
+		let mut buffer = SSHBuffer::new();
+
+		let result = read(&mut stream, &mut buffer, &mut clear::Key, MAXIMUM_PACKET_LEN).await;
+
+		assert!(matches!(result, Err(Error::IndexOutOfBounds)));
+	}
+
+	#[cfg(feature = "rs-crypto")]
+	#[tokio::test]
+	async fn test_aes_cbc_roundtrip() {
+		roundtrip(&AES_128_CBC, &mac::HMAC_SHA256).await;
+
+		roundtrip(&AES_256_CBC, &mac::HMAC_SHA256_ETM).await;
+	}
+
+	#[tokio::test]
+	async fn test_legacy_mac_roundtrip() {
+		// The tag of `hmac-sha1-96` is shorter than the SHA-1 digest.
+		roundtrip(&AES_128_CTR, &mac::HMAC_SHA1_96).await;
+
+		roundtrip(&AES_256_CTR, &mac::HMAC_MD5).await;
+	}
+
+	#[cfg(feature = "rs-crypto")]
+	#[tokio::test]
+	async fn test_umac_roundtrip() {
+		roundtrip(&AES_128_CTR, &mac::UMAC_64).await;
+
+		roundtrip(&AES_128_CTR, &mac::UMAC_128).await;
+
+		roundtrip(&AES_256_CTR, &mac::UMAC_64_ETM).await;
+
+		roundtrip(&AES_256_CTR, &mac::UMAC_128_ETM).await;
+	}
+
+	#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
+	#[tokio::test]
+	async fn test_aead_roundtrip() {
+		roundtrip(&AES_128_GCM, &mac::NONE).await;
+
+		roundtrip(&AES_256_GCM, &mac::NONE).await;
+
+		roundtrip(&CHACHA20_POLY1305, &mac::NONE).await;
+	}
+
+	/// The OpenSSL implementations must interoperate with the RustCrypto
+	/// ones in both directions.
+	#[cfg(all(feature = "rs-crypto", feature = "openssl"))]
+	#[tokio::test]
+	async fn test_aead_openssl_parity() {
+		let pairs:[(&dyn Cipher, &dyn Cipher); 3] = [
+			(&_AES_128_GCM, &gcm_openssl::GcmCipher(openssl::cipher::Cipher::aes_128_gcm)),
+			(&_AES_256_GCM, &gcm_openssl::GcmCipher(openssl::cipher::Cipher::aes_256_gcm)),
+			(&_CHACHA20_POLY1305, &chacha20poly1305_openssl::SshChacha20Poly1305Cipher {}),
+		];
+
+		for (rs_crypto, openssl) in pairs {
+			roundtrip_between(rs_crypto, openssl, &mac::NONE).await;
+
+			roundtrip_between(openssl, rs_crypto, &mac::NONE).await;
+		}
+	}
+
+	/// Keys for both directions of a loopback, all derived from `byte`.
+	#[allow(clippy::unwrap_used)]
+	fn loopback_pair(byte:u8) -> CipherPair {
+		let cipher = CIPHERS.get(&AES_128_CTR).unwrap();
+
+		let mac = mac::MACS.get(&mac::HMAC_SHA256).unwrap();
+
+		let key = vec![byte; cipher.key_len()];
+
+		let nonce = vec![byte; cipher.nonce_len()];
+
+		let mac_key = vec![byte; mac.key_len()];
+
+		CipherPair {
+			local_to_remote:cipher.make_sealing_key(&key, &nonce, &mac_key, *mac).unwrap(),
+			remote_to_local:cipher.make_opening_key(&key, &nonce, &mac_key, *mac).unwrap(),
+		}
+	}
+
+	fn contains(haystack:&[u8], needle:&[u8]) -> bool {
+		haystack.windows(needle.len()).any(|window| window == needle)
+	}
+
+	/// Once a rekey replaced the `CipherPair`, the plaintext handled with
+	/// the old keys must be gone from the read and write buffers, including
+	/// the memory that they keep allocated.
+	#[tokio::test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	async fn test_rekey_leaves_no_plaintext() {
+		const SECRET:&[u8] = b"\x5eThe password is hunter2, and it must not linger";
+
+		let mut pair = loopback_pair(0x2a);
+
+		let mut sent = SSHBuffer::new();
+
+		#[cfg(feature = "flate2")]
+		crate::compression::Compression::Zlib.init_compress(&mut sent.compress, 0);
+
+		let mut received = SSHBuffer::new();
+
+		pair.local_to_remote.write(SECRET, &mut sent).unwrap();
+
+		let mut stream = &sent.buffer[..];
+
+		read(&mut stream, &mut received, pair.remote_to_local.as_mut(), MAXIMUM_PACKET_LEN)
+			.await
+			.unwrap();
+
+		assert!(contains(&received.buffer, SECRET));
+
+		let secret_len = received.buffer.len();
+
+		pair = loopback_pair(0x3b);
+
+		sent.compress = Compress::None;
+
+		sent.buffer.clear();
+
+		pair.local_to_remote.write(b"\x5eok", &mut sent).unwrap();
+
+		let mut stream = &sent.buffer[..];
+
+		read(&mut stream, &mut received, pair.remote_to_local.as_mut(), MAXIMUM_PACKET_LEN)
+			.await
+			.unwrap();
+
+		assert_eq!(&received.buffer[5..8], b"\x5eok");
+
+		assert!(!contains(&sent.buffer, SECRET));
+		// Growing the buffers within their capacity exposes the memory
+		// they held before, which must have been zeroed.
+		received.buffer.resize(secret_len);
+
+		assert!(!contains(&received.buffer, SECRET));
+
+		sent.compress_buffer.resize(SECRET.len());
+
+		assert!(sent.compress_buffer.iter().all(|&b| b == 0));
+	}
+
+	/// The packet framing of `SealingKey::write` before it filled packets
+	/// in place, kept to check that the output did not change.
+	#[allow(clippy::indexing_slicing)]
+	fn write_by_extending(key:&mut dyn SealingKey, payload:&[u8], buffer:&mut SSHBuffer) {
+		let padding_length = key.padding_length(payload);
+
+		let packet_length = PADDING_LENGTH_LEN + payload.len() + padding_length;
+
+		let offset = buffer.buffer.len();
+
+		buffer.buffer.push_u32_be(packet_length as u32);
+
+		buffer.buffer.push(padding_length as u8);
+
+		buffer.buffer.extend(payload);
+
+		key.fill_padding(buffer.buffer.resize_mut(padding_length));
+
+		buffer.buffer.resize_mut(key.tag_len());
+
+		let (plaintext, tag) =
+			buffer.buffer[offset..].split_at_mut(PACKET_LENGTH_LEN + packet_length);
+
+		key.seal(buffer.seqn.0, plaintext, tag);
+
+		buffer.bytes += payload.len();
+
+		buffer.seqn += Wrapping(1);
+	}
+
+	/// A sealing key filling the padding from a seeded generator, so that
+	/// two of them write the same packets.
+	struct SeededPadding(Box<dyn SealingKey + Send>, std::cell::RefCell<rand::rngs::StdRng>);
+
+	impl SeededPadding {
+		fn new(key:Box<dyn SealingKey + Send>) -> Self {
+			use rand::SeedableRng;
+
+			SeededPadding(key, std::cell::RefCell::new(rand::rngs::StdRng::seed_from_u64(42)))
+		}
+	}
+
+	impl SealingKey for SeededPadding {
+		fn padding_length(&self, payload:&[u8]) -> usize { self.0.padding_length(payload) }
+
+		fn fill_padding(&self, padding_out:&mut [u8]) {
+			self.1.borrow_mut().fill_bytes(padding_out);
+		}
+
+		fn padding_block_size(&self) -> usize { self.0.padding_block_size() }
+
+		fn tag_len(&self) -> usize { self.0.tag_len() }
+
+		fn seal(&mut self, seqn:u32, plaintext_in_ciphertext_out:&mut [u8], tag_out:&mut [u8]) {
+			self.0.seal(seqn, plaintext_in_ciphertext_out, tag_out)
+		}
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_write_in_place_is_byte_identical() {
+		let names = [
+			CLEAR,
+			CHACHA20_POLY1305,
+			AES_128_CTR,
+			AES_256_CTR,
+			AES_128_CBC,
+			AES_256_CBC,
+			AES_128_GCM,
+			AES_256_GCM,
+		];
+
+		for name in names {
+			let cipher = if let Some(cipher) = CIPHERS.get(&name) { cipher } else { continue };
+
+			let key = vec![0x2a; cipher.key_len()];
+
+			let nonce = vec![0x17; cipher.nonce_len()];
+
+			let mac =
+				mac::MACS.get(if cipher.needs_mac() { &mac::HMAC_SHA256 } else { &mac::NONE }).unwrap();
+
+			let mac_key = vec![0x55; mac.key_len()];
+
+			let make_key = || {
+				SeededPadding::new(cipher.make_sealing_key(&key, &nonce, &mac_key, *mac).unwrap())
+			};
+
+			let (mut in_place, mut extending) = (make_key(), make_key());
+
+			let (mut written, mut expected) = (SSHBuffer::new(), SSHBuffer::new());
+
+			// Payload lengths around each multiple of the block size, and
+			// around the minimum packet length.
+			for len in (0..70).chain(4090..4110) {
+				let payload:Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+				in_place.write(&payload, &mut written).unwrap();
+
+				write_by_extending(&mut extending, &payload, &mut expected);
+
+				assert_eq!(&written.buffer[..], &expected.buffer[..], "{} {}", name.0, len);
+			}
+
+			assert_eq!(written.seqn, expected.seqn);
+
+			assert_eq!(written.bytes, expected.bytes);
+		}
+	}
+
+	/// With random padding, the padding stays within the protocol's
+	/// bounds and aligned, and identical payloads give packets of varying
+	/// lengths, on the AEAD ciphers as well as with a separate MAC.
+	#[tokio::test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	async fn test_random_padding() {
+		let pairs = [
+			(AES_128_CTR, mac::HMAC_SHA256),
+			(AES_256_CTR, mac::HMAC_SHA256_ETM),
+			(AES_256_GCM, mac::NONE),
+			(CHACHA20_POLY1305, mac::NONE),
+			(CLEAR, mac::NONE),
+		];
+
+		for (name, mac_name) in pairs {
+			let cipher = if let Some(cipher) = CIPHERS.get(&name) { cipher } else { continue };
+
+			let mac = mac::MACS.get(&mac_name).unwrap();
+
+			let key = vec![0x2a; cipher.key_len()];
+
+			let nonce = vec![0x17; cipher.nonce_len()];
+
+			let mac_key = vec![0x55; mac.key_len()];
+
+			let mut sealing = cipher.make_sealing_key(&key, &nonce, &mac_key, *mac).unwrap();
+
+			let mut opening = cipher.make_opening_key(&key, &nonce, &mac_key, *mac).unwrap();
+
+			let block_size = sealing.padding_block_size();
+
+			let mut lengths = std::collections::HashSet::new();
+
+			let mut sent = SSHBuffer::new();
+
+			sent.random_padding = Some(0..=32);
+
+			let mut received = SSHBuffer::new();
+
+			for payload in [&b"ls"[..], &[0x5e; 34996]] {
+				let min_padding = sealing.padding_length(payload);
+
+				for _ in 0..64 {
+					sent.buffer.clear();
+
+					sealing.write(payload, &mut sent).unwrap();
+
+					let packet_length = sent.buffer.len() - PACKET_LENGTH_LEN - sealing.tag_len();
+
+					let padding_length = packet_length - PADDING_LENGTH_LEN - payload.len();
+
+					assert!(padding_length >= PACKET_LENGTH_LEN, "{}", name.0);
+
+					assert!(padding_length <= u8::MAX as usize, "{}", name.0);
+
+					assert_eq!((padding_length - min_padding) % block_size, 0, "{}", name.0);
+
+					if payload.len() > 2 {
+						// Too large for any extra padding.
+						assert_eq!(padding_length, min_padding, "{}", name.0);
+					} else {
+						lengths.insert(sent.buffer.len());
+					}
+
+					let mut stream = &sent.buffer[..];
+
+					read(&mut stream, &mut received, opening.as_mut(), MAXIMUM_PACKET_LEN)
+						.await
+						.unwrap();
+
+					assert_eq!(&received.buffer[5..5 + payload.len()], payload);
+				}
+			}
+
+			assert!(lengths.len() > 1, "{}", name.0);
+		}
+	}
+
+	#[cfg(feature = "legacy-ciphers")]
+	#[tokio::test]
+	async fn test_3des_cbc_roundtrip() { roundtrip(&TRIPLE_DES_CBC, &mac::HMAC_SHA1).await; }
+
+	struct Xor;
+
+	struct XorKey(u8);
+
+	impl Cipher for Xor {
+		fn key_len(&self) -> usize { 1 }
+
+		fn make_opening_key(
+			&self,
+			key:&[u8],
+			_:&[u8],
+			_:&[u8],
+			_:&dyn MacAlgorithm,
+		) -> Result<Box<dyn OpeningKey + Send>, Error> {
+			Ok(Box::new(XorKey(key.first().copied().unwrap_or(0))))
+		}
+
+		fn make_sealing_key(
+			&self,
+			key:&[u8],
+			_:&[u8],
+			_:&[u8],
+			_:&dyn MacAlgorithm,
+		) -> Result<Box<dyn SealingKey + Send>, Error> {
+			Ok(Box::new(XorKey(key.first().copied().unwrap_or(0))))
+		}
+	}
+
+	impl XorKey {
+		fn apply(&self, data:&mut [u8]) { data.iter_mut().for_each(|b| *b ^= self.0); }
+	}
+
+	#[allow(clippy::indexing_slicing)]
+	impl OpeningKey for XorKey {
+		fn decrypt_packet_length(&self, _:u32, packet_length:&[u8]) -> Result<[u8; 4], Error> {
+			let mut len = [0; PACKET_LENGTH_LEN];
+
+			len.clone_from_slice(&packet_length[..PACKET_LENGTH_LEN]);
+
+			self.apply(&mut len);
+
+			Ok(len)
+		}
+
+		fn tag_len(&self) -> usize { 0 }
+
+		fn open<'a>(&mut self, _:u32, data:&'a mut [u8], _:&[u8]) -> Result<&'a [u8], Error> {
+			self.apply(data);
+
+			Ok(&data[PACKET_LENGTH_LEN..])
+		}
+	}
+
+	impl SealingKey for XorKey {
+		fn padding_length(&self, payload:&[u8]) -> usize {
+			let padding_len = 8 - (PACKET_LENGTH_LEN + PADDING_LENGTH_LEN + payload.len()) % 8;
+
+			if padding_len < 4 { padding_len + 8 } else { padding_len }
+		}
+
+		fn fill_padding(&self, padding_out:&mut [u8]) { padding_out.fill(0); }
+
+		fn tag_len(&self) -> usize { 0 }
+
+		fn seal(&mut self, _:u32, data:&mut [u8], _:&mut [u8]) { self.apply(data); }
+	}
+
+	#[tokio::test]
+	async fn test_custom_cipher_registry() {
+		const XOR:Name = Name("xor@example.com");
+
+		let mut ciphers = CipherRegistry::default();
+
+		assert!(ciphers.get(&XOR).is_none());
+
+		ciphers.register(XOR, Arc::new(Xor));
+
+		roundtrip_with(&ciphers, &XOR, &mac::NONE).await;
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// Sessions work with random padding on both sides, with each cipher,
+	/// through key re-exchanges.
+	#[tokio::test]
+	async fn test_random_padding() {
+		use tokio::time::{Duration, timeout};
+
+		let ciphers:[&'static [crate::cipher::Name]; 3] = [
+			&[crate::cipher::CHACHA20_POLY1305],
+			&[crate::cipher::AES_256_GCM],
+			&[crate::cipher::AES_128_CTR],
+		];
+
+		for cipher in ciphers {
+			let server_config =
+				server::Config { random_padding:Some(0..=15), ..Default::default() };
+
+			let preferred = crate::Preferred { cipher, ..crate::Preferred::DEFAULT };
+
+			let config =
+				client::Config { preferred, random_padding:Some(0..=15), ..Default::default() };
+
+			let (mut client, _server) =
+				connect_with(config, server_config, Client {}, EchoServer {}).await;
+
+			assert!(client.authenticate_none("user").await.unwrap().success());
+
+			let mut channel = client.channel_open_session().await.unwrap();
+
+			for round in 0..2 {
+				if round == 1 {
+					client.rekey().await.unwrap();
+				}
+
+				// Keystrokes, one packet each.
+				for key in b"ls -l\n" {
+					channel.data(&[*key][..]).await.unwrap();
+				}
+
+				let mut echoed = Vec::new();
+
+				timeout(Duration::from_secs(10), async {
+					while echoed.len() < 6 {
+						match channel.wait().await {
+							Some(ChannelMsg::Data { data }) => echoed.extend_from_slice(&data),
+							Some(_) => {},
+							None => break,
+						}
+					}
+				})
+				.await
+				.unwrap();
+
+				assert_eq!(&echoed[..], b"ls -l\n", "{:?} round {}", cipher, round);
+			}
+		}
+	}
+}
@@ -0,0 +1,563 @@
+//! Authentication with several methods and identities, in order of
+//! preference, as OpenSSH does with `PreferredAuthentications`.
+//!
+//! An [`Authenticator`] first asks the server which methods it accepts,
+//! with the "none" method, then tries the preferred methods it accepts,
+//! following its answers until the user is authenticated or nothing is
+//! left to try. This includes multi-step authentications, where the
+//! server reports a partial success:
+//!
+//! ```no_run
+//! # async fn run<H:russh::client::Handler>(
+//! # 	session:&mut russh::client::Handle<H>,
+//! # 	key:std::sync::Arc<russh_keys::key::KeyPair>,
+//! # ) -> Result<(), russh::Error> {
+//! use russh::{MethodSet, client::authenticator::Authenticator};
+//!
+//! let summary = Authenticator::new("alice")
+//! 	.methods(&[MethodSet::PUBLICKEY, MethodSet::PASSWORD])
+//! 	.key(key)
+//! 	.password("hunter2")
+//! 	.authenticate(session)
+//! 	.await?;
+//!
+//! if !summary.authenticated {
+//! 	for attempt in summary.attempts {
+//! 		println!("{:?}: {:?}", attempt.method, attempt.outcome);
+//! 	}
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh_keys::{agent::client::AgentClient, certificate::Certificate, key, signature::Signature};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	sync::Mutex,
+};
+
+use super::{AuthResult, Handle, Handler, KeyboardInteractiveHandler, Prompt};
+use crate::auth::{self, MethodSet};
+
+/// How many times a password or keyboard-interactive prompt is shown, as
+/// OpenSSH's `NumberOfPasswordPrompts`.
+const PROMPTS:usize = 3;
+
+/// An identity for the "publickey" method.
+pub enum Identity {
+	/// A key pair.
+	Key(Arc<key::KeyPair>),
+	/// A key pair, with an OpenSSH certificate of its public key.
+	Certificate { key:Arc<key::KeyPair>, cert:Certificate },
+	/// A key held elsewhere, see [`agent_identities`] for the keys of an
+	/// SSH agent.
+	Signer(Arc<dyn auth::Signer>),
+}
+
+impl Identity {
+	fn public_key(&self) -> Option<key::PublicKey> {
+		match self {
+			Identity::Key(key) => key.clone_public_key().ok(),
+			Identity::Certificate { cert, .. } => Some(cert.key.clone()),
+			Identity::Signer(signer) => Some(signer.public_key()),
+		}
+	}
+}
+
+/// The outcome of one authentication attempt.
+#[derive(Debug)]
+pub enum Outcome {
+	/// The user is authenticated.
+	Success,
+	/// The attempt succeeded, but the server requires more methods.
+	PartialSuccess,
+	/// The server rejected the attempt.
+	Rejected,
+	/// The password has expired, `prompt` is the server's message.
+	PasswordChangeRequired { prompt:String },
+	/// The identity could not sign the request, for instance because an
+	/// agent refused.
+	SignerFailed(auth::SignerError),
+}
+
+/// An authentication attempt.
+#[derive(Debug)]
+pub struct Attempt {
+	/// The method, one of the flags of [`MethodSet`].
+	pub method:MethodSet,
+	/// The public key of the identity, for the "publickey" method.
+	pub key:Option<key::PublicKey>,
+	pub outcome:Outcome,
+}
+
+/// What an [`Authenticator`] tried.
+#[derive(Debug)]
+pub struct Summary {
+	pub authenticated:bool,
+	/// Every attempt, in order, starting with the "none" method.
+	pub attempts:Vec<Attempt>,
+	/// The methods the server still accepted when authentication stopped.
+	pub remaining_methods:MethodSet,
+	/// The preferred methods the server never accepted.
+	pub not_offered:MethodSet,
+}
+
+/// Runs authentication with several methods and identities, see the
+/// [module documentation](self).
+pub struct Authenticator {
+	user:String,
+	methods:Vec<MethodSet>,
+	identities:Vec<Identity>,
+	password:Option<String>,
+	prompter:Option<Box<dyn KeyboardInteractiveHandler>>,
+}
+
+impl Authenticator {
+	/// Authenticate as `user`, preferring the "publickey", then the
+	/// "keyboard-interactive", then the "password" method.
+	pub fn new<U:Into<String>>(user:U) -> Self {
+		Authenticator {
+			user:user.into(),
+			methods:vec![
+				MethodSet::PUBLICKEY,
+				MethodSet::KEYBOARD_INTERACTIVE,
+				MethodSet::PASSWORD,
+			],
+			identities:Vec::new(),
+			password:None,
+			prompter:None,
+		}
+	}
+
+	/// The methods to try, preferred first. Other methods are never tried.
+	pub fn methods(mut self, methods:&[MethodSet]) -> Self {
+		self.methods = methods.to_vec();
+
+		self
+	}
+
+	/// Add an identity for the "publickey" method. Identities are tried
+	/// in the order they were added, each at most once.
+	pub fn identity(mut self, identity:Identity) -> Self {
+		self.identities.push(identity);
+
+		self
+	}
+
+	/// Add a key pair for the "publickey" method.
+	pub fn key(self, key:Arc<key::KeyPair>) -> Self { self.identity(Identity::Key(key)) }
+
+	/// The password for the "password" method. It is tried once.
+	pub fn password<P:Into<String>>(mut self, password:P) -> Self {
+		self.password = Some(password.into());
+
+		self
+	}
+
+	/// Answer the prompts of the "keyboard-interactive" method with
+	/// `prompter`. Without a [password](Self::password), it is also asked
+	/// for one, as a single prompt without echo. Prompts are shown up to
+	/// three times.
+	pub fn prompter<K:KeyboardInteractiveHandler + 'static>(mut self, prompter:K) -> Self {
+		self.prompter = Some(Box::new(prompter));
+
+		self
+	}
+
+	/// Authenticate on `session`. Failing to authenticate is not an error,
+	/// see [`Summary::authenticated`].
+	pub async fn authenticate<H:Handler>(
+		&mut self,
+		session:&mut Handle<H>,
+	) -> Result<Summary, crate::Error> {
+		let mut summary = Summary {
+			authenticated:false,
+			attempts:Vec::new(),
+			remaining_methods:MethodSet::empty(),
+			not_offered:MethodSet::empty(),
+		};
+
+		// Ask the server which methods it accepts.
+		let result = session.authenticate_none(self.user.as_str()).await?;
+
+		let mut remaining = summary.record(MethodSet::NONE, None, result);
+
+		let mut offered = remaining;
+
+		let mut identities = 0;
+
+		let mut password_tried = false;
+
+		let mut password_prompts = 0;
+
+		let mut keyboard_interactive_prompts = 0;
+
+		while !summary.authenticated {
+			let has_untried = |method:MethodSet| {
+				if method == MethodSet::PUBLICKEY {
+					identities < self.identities.len()
+				} else if method == MethodSet::PASSWORD {
+					if self.password.is_some() {
+						!password_tried
+					} else {
+						self.prompter.is_some() && password_prompts < PROMPTS
+					}
+				} else if method == MethodSet::KEYBOARD_INTERACTIVE {
+					self.prompter.is_some() && keyboard_interactive_prompts < PROMPTS
+				} else {
+					false
+				}
+			};
+
+			let method = if let Some(method) =
+				self.methods.iter().copied().find(|m| remaining.contains(*m) && has_untried(*m))
+			{
+				method
+			} else {
+				break;
+			};
+
+			let (key, result) = if method == MethodSet::PUBLICKEY {
+				let identity = if let Some(identity) = self.identities.get(identities) {
+					identity
+				} else {
+					break;
+				};
+
+				identities += 1;
+
+				let key = identity.public_key();
+
+				let result = match identity {
+					Identity::Key(key) => {
+						session.authenticate_publickey(self.user.as_str(), key.clone()).await
+					},
+					Identity::Certificate { key, cert } => {
+						session
+							.authenticate_publickey_with_cert(
+								self.user.as_str(),
+								key.clone(),
+								cert.clone(),
+							)
+							.await
+					},
+					Identity::Signer(signer) => {
+						let signer = signer.as_ref();
+
+						session.authenticate_publickey_with(self.user.as_str(), signer).await
+					},
+				};
+
+				match result {
+					Err(crate::Error::Signer(e)) => {
+						summary.attempts.push(Attempt {
+							method,
+							key,
+							outcome:Outcome::SignerFailed(e),
+						});
+
+						continue;
+					},
+					result => (key, result?),
+				}
+			} else if method == MethodSet::PASSWORD {
+				let password = if let Some(ref password) = self.password {
+					password_tried = true;
+
+					password.clone()
+				} else if let Some(ref mut prompter) = self.prompter {
+					password_prompts += 1;
+
+					let prompt =
+						Prompt { prompt:format!("{}'s password: ", self.user), echo:false };
+
+					let mut responses = prompter.respond("", "", &[prompt]).await;
+
+					if responses.is_empty() { String::new() } else { responses.swap_remove(0) }
+				} else {
+					break;
+				};
+
+				(None, session.authenticate_password(self.user.as_str(), password).await?)
+			} else {
+				let prompter = if let Some(ref mut prompter) = self.prompter {
+					prompter
+				} else {
+					break;
+				};
+
+				keyboard_interactive_prompts += 1;
+
+				let result = session
+					.authenticate_keyboard_interactive(self.user.as_str(), prompter.as_mut())
+					.await?;
+
+				(None, result)
+			};
+
+			remaining = summary.record(method, key, result);
+
+			offered |= remaining;
+		}
+
+		summary.remaining_methods = remaining;
+
+		for method in &self.methods {
+			if !offered.contains(*method) {
+				summary.not_offered |= *method;
+			}
+		}
+
+		Ok(summary)
+	}
+}
+
+impl Summary {
+	/// Record an attempt, returning the methods that can continue.
+	fn record(
+		&mut self,
+		method:MethodSet,
+		key:Option<key::PublicKey>,
+		result:AuthResult,
+	) -> MethodSet {
+		let remaining = result.remaining_methods();
+
+		let outcome = match result {
+			AuthResult::Success => {
+				self.authenticated = true;
+
+				Outcome::Success
+			},
+			AuthResult::Failure { partial_success:true, .. } => Outcome::PartialSuccess,
+			AuthResult::Failure { .. } => Outcome::Rejected,
+			AuthResult::PasswordChangeRequired { prompt, .. } => {
+				Outcome::PasswordChangeRequired { prompt }
+			},
+		};
+
+		self.attempts.push(Attempt { method, key, outcome });
+
+		remaining
+	}
+}
+
+/// The keys of an SSH agent, as identities signing with the agent.
+pub async fn agent_identities<R>(mut agent:AgentClient<R>) -> Result<Vec<Identity>, crate::Error>
+where
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	let keys = agent.request_identities().await?;
+
+	let agent = Arc::new(Mutex::new(agent));
+
+	Ok(keys
+		.into_iter()
+		.map(|key| {
+			let signer = SharedAgentSigner { agent:agent.clone(), key };
+
+			Identity::Signer(Arc::new(signer))
+		})
+		.collect())
+}
+
+/// Like [`auth::AgentSigner`], with an agent shared by several keys.
+struct SharedAgentSigner<R:AsyncRead + AsyncWrite> {
+	agent:Arc<Mutex<AgentClient<R>>>,
+	key:key::PublicKey,
+}
+
+#[async_trait]
+impl<R:AsyncRead + AsyncWrite + Unpin + Send> auth::Signer for SharedAgentSigner<R> {
+	fn public_key(&self) -> key::PublicKey { self.key.clone() }
+
+	fn algorithms(&self) -> Vec<key::Name> { auth::agent_algorithms(&self.key) }
+
+	async fn sign(
+		&self,
+		algorithm:key::Name,
+		data:&[u8],
+	) -> Result<Signature, auth::SignerError> {
+		let mut key = self.key.clone();
+
+		key.set_algorithm(algorithm.0.as_bytes());
+
+		Ok(self.agent.lock().await.sign(&key, data).await?)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_authenticator() {
+		use std::{borrow::Cow, sync::Arc};
+
+		use russh_keys::key::{KeyPair, PublicKey};
+
+		use crate::{
+			auth::MethodSet,
+			client::authenticator::{Authenticator, Outcome},
+		};
+
+		/// Accepts one key, followed by a one-time code if `second_factor`
+		/// is set.
+		struct ServerHandle {
+			key:PublicKey,
+			second_factor:bool,
+			key_ok:bool,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				mut self,
+				_:&str,
+				key:&PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				if *key != self.key {
+					Ok((self, server::Auth::Reject { proceed_with_methods:None }))
+				} else if self.second_factor {
+					self.key_ok = true;
+
+					let proceed_with_methods = MethodSet::KEYBOARD_INTERACTIVE;
+
+					Ok((self, server::Auth::PartialSuccess { proceed_with_methods }))
+				} else {
+					Ok((self, server::Auth::Accept))
+				}
+			}
+
+			async fn auth_keyboard_interactive(
+				self,
+				_:&str,
+				_:&str,
+				response:Option<server::Response<'async_trait>>,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				if !self.key_ok {
+					return Ok((self, server::Auth::Reject { proceed_with_methods:None }));
+				}
+
+				let auth = match response.map(|r| r.collect::<Vec<_>>()) {
+					None => {
+						server::Auth::Partial {
+							name:Cow::Borrowed(""),
+							instructions:Cow::Borrowed(""),
+							prompts:Cow::Owned(vec![(Cow::Borrowed("Code: "), false)]),
+						}
+					},
+					Some(code) if code == [b"123456"] => server::Auth::Accept,
+					Some(_) => server::Auth::Reject { proceed_with_methods:None },
+				};
+
+				Ok((self, auth))
+			}
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+		}
+
+		async fn connect_methods(
+			methods:MethodSet,
+			handler:ServerHandle,
+		) -> client::Handle<Client> {
+			let server_config = server::Config {
+				methods,
+				auth_rejection_time:std::time::Duration::from_millis(10),
+				..Default::default()
+			};
+
+			connect_with(client::Config::default(), server_config, Client {}, handler).await.0
+		}
+
+		let keys = (0..3)
+			.map(|_| Arc::new(KeyPair::generate_ed25519().unwrap()))
+			.collect::<Vec<_>>();
+
+		let public = |i:usize| keys[i].clone_public_key().unwrap();
+
+		// A key, then a one-time code.
+		let handler = ServerHandle { key:public(0), second_factor:true, key_ok:false };
+
+		let mut session =
+			connect_methods(MethodSet::PUBLICKEY | MethodSet::KEYBOARD_INTERACTIVE, handler).await;
+
+		let summary = Authenticator::new("user")
+			.key(keys[0].clone())
+			.prompter(|_:&str, _:&str, _:&[client::Prompt]| vec!["123456".to_string()])
+			.authenticate(&mut session)
+			.await
+			.unwrap();
+
+		assert!(summary.authenticated);
+
+		let methods = summary.attempts.iter().map(|a| a.method).collect::<Vec<_>>();
+
+		assert_eq!(methods, [
+			MethodSet::NONE,
+			MethodSet::PUBLICKEY,
+			MethodSet::KEYBOARD_INTERACTIVE,
+		]);
+
+		assert!(matches!(summary.attempts[0].outcome, Outcome::Rejected));
+
+		assert!(matches!(summary.attempts[1].outcome, Outcome::PartialSuccess));
+
+		assert!(matches!(summary.attempts[2].outcome, Outcome::Success));
+
+		// Only the third key is accepted.
+		let handler = ServerHandle { key:public(2), second_factor:false, key_ok:false };
+
+		let mut session = connect_methods(MethodSet::PUBLICKEY, handler).await;
+
+		let mut authenticator = Authenticator::new("user");
+
+		for key in &keys {
+			authenticator = authenticator.key(key.clone());
+		}
+
+		let summary = authenticator.authenticate(&mut session).await.unwrap();
+
+		assert!(summary.authenticated);
+
+		let keys_tried = summary.attempts.iter().filter_map(|a| a.key.clone()).collect::<Vec<_>>();
+
+		assert_eq!(keys_tried, [public(0), public(1), public(2)]);
+
+		assert!(matches!(summary.attempts[1].outcome, Outcome::Rejected));
+
+		assert!(matches!(summary.attempts[2].outcome, Outcome::Rejected));
+
+		assert!(matches!(summary.attempts[3].outcome, Outcome::Success));
+
+		// The server does not accept passwords.
+		let handler = ServerHandle { key:public(0), second_factor:false, key_ok:false };
+
+		let mut session = connect_methods(MethodSet::PUBLICKEY, handler).await;
+
+		let summary = Authenticator::new("user")
+			.methods(&[MethodSet::PASSWORD])
+			.password("password")
+			.authenticate(&mut session)
+			.await
+			.unwrap();
+
+		assert!(!summary.authenticated);
+
+		assert_eq!(summary.attempts.len(), 1);
+
+		assert_eq!(summary.remaining_methods, MethodSet::PUBLICKEY);
+
+		assert_eq!(summary.not_offered, MethodSet::PASSWORD);
+	}
+}
@@ -0,0 +1,756 @@
+//! OpenSSH client configuration files (`~/.ssh/config`), as described in
+//! ssh_config(5).
+//!
+//! A configuration is a list of `Host` and `Match` blocks. For each
+//! keyword, the first value found in a block matching the host is used,
+//! except for `IdentityFile`, whose values add up:
+//!
+//! ```
+//! use russh::client::config::SshConfig;
+//!
+//! let config = SshConfig::parse(
+//! 	"Host db
+//! 	HostName db.%h.example.com
+//! 	User admin
+//!
+//! Host *
+//! 	User nobody
+//! 	Port 2222",
+//! )
+//! .unwrap();
+//!
+//! let db = config.resolve("db");
+//!
+//! assert_eq!(db.host_name, "db.db.example.com");
+//! assert_eq!(db.user.as_deref(), Some("admin"));
+//! assert_eq!(db.port, 2222);
+//! ```
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use russh_keys::known_hosts::KnownHosts;
+
+use super::{HostKeyVerification, StrictHostKeyChecking};
+
+/// How deeply `Include` directives may nest, as in OpenSSH.
+const MAX_INCLUDE_DEPTH:usize = 16;
+
+/// A parsed client configuration file, with its included files.
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+	conditions:Vec<Condition>,
+	entries:Vec<Entry>,
+}
+
+/// The settings of a host, resolved from an [`SshConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostConfig {
+	/// The host name given to [`SshConfig::resolve`], which may be an alias.
+	pub host:String,
+	/// `HostName`: the host to connect to.
+	pub host_name:String,
+	/// `Port`, 22 by default.
+	pub port:u16,
+	/// `User`, if set.
+	pub user:Option<String>,
+	/// `IdentityFile`s, in order.
+	pub identity_files:Vec<PathBuf>,
+	/// `ProxyJump`: the hosts to jump through, comma-separated.
+	pub proxy_jump:Option<String>,
+	/// `ProxyCommand`: the command whose standard input and output
+	/// replace the connection.
+	pub proxy_command:Option<String>,
+	/// `ServerAliveInterval`, if not zero, to use as
+	/// [`Config::keepalive_interval`][super::Config::keepalive_interval].
+	pub server_alive_interval:Option<Duration>,
+	/// `ServerAliveCountMax`, to use as
+	/// [`Config::keepalive_max`][super::Config::keepalive_max].
+	pub server_alive_count_max:Option<u32>,
+	/// `StrictHostKeyChecking`: `yes`, `no`, `ask` or `accept-new`.
+	pub strict_host_key_checking:Option<String>,
+	/// `UserKnownHostsFile`s.
+	pub user_known_hosts_files:Vec<PathBuf>,
+	/// `PreferredAuthentications`, such as `publickey` or `password`.
+	pub preferred_authentications:Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+	/// `Host` patterns, matched against the host name given.
+	Host(Vec<String>),
+	/// `Match` criteria, which must all be satisfied.
+	Match(Vec<Criterion>),
+}
+
+#[derive(Debug, Clone)]
+struct Criterion {
+	negated:bool,
+	kind:CriterionKind,
+}
+
+#[derive(Debug, Clone)]
+enum CriterionKind {
+	All,
+	/// Patterns matched against `HostName`, as resolved so far.
+	Host(String),
+	/// Patterns matched against the host name given.
+	OriginalHost(String),
+	User(String),
+	LocalUser(String),
+	/// `canonical`, `final`, `exec`, `localnetwork` or `tagged`, which
+	/// are never satisfied.
+	Unsupported,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+	/// Indices in [`SshConfig::conditions`] of the enclosing blocks.
+	conditions:Vec<usize>,
+	keyword:String,
+	args:Vec<String>,
+}
+
+impl SshConfig {
+	/// Parse a configuration. Relative `Include` paths are looked up in
+	/// `~/.ssh`.
+	pub fn parse(contents:&str) -> Result<Self, crate::Error> {
+		let mut config = SshConfig::default();
+
+		config.parse_into(contents, &[], 0)?;
+
+		Ok(config)
+	}
+
+	/// Load a configuration file, such as `~/.ssh/config`.
+	pub fn load<P:AsRef<Path>>(path:P) -> Result<Self, crate::Error> {
+		Self::parse(&std::fs::read_to_string(path)?)
+	}
+
+	/// Load `~/.ssh/config`, or an empty configuration if there is none.
+	pub fn load_default() -> Result<Self, crate::Error> {
+		match Self::load(ssh_dir()?.join("config")) {
+			Err(crate::Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+				Ok(SshConfig::default())
+			},
+			result => result,
+		}
+	}
+
+	fn parse_into(
+		&mut self,
+		contents:&str,
+		outer:&[usize],
+		depth:usize,
+	) -> Result<(), crate::Error> {
+		let mut current = outer.to_vec();
+
+		for (n, line) in contents.lines().enumerate() {
+			let invalid = || crate::Error::SshConfig { line:n + 1 };
+
+			let (keyword, args) = match split_line(line).ok_or_else(invalid)? {
+				Some(line) => line,
+				None => continue,
+			};
+
+			match keyword.as_str() {
+				"host" => {
+					if args.is_empty() {
+						return Err(invalid());
+					}
+
+					current = outer.to_vec();
+
+					current.push(self.conditions.len());
+
+					self.conditions.push(Condition::Host(args));
+				},
+				"match" => {
+					let criteria = parse_criteria(&args).ok_or_else(invalid)?;
+
+					current = outer.to_vec();
+
+					current.push(self.conditions.len());
+
+					self.conditions.push(Condition::Match(criteria));
+				},
+				"include" => {
+					if depth >= MAX_INCLUDE_DEPTH || args.is_empty() {
+						return Err(invalid());
+					}
+
+					for arg in args {
+						for path in include_paths(&arg)? {
+							let contents = std::fs::read_to_string(path)?;
+
+							self.parse_into(&contents, &current, depth + 1)?;
+						}
+					}
+				},
+				_ => {
+					if !valid_args(&keyword, &args) {
+						return Err(invalid());
+					}
+
+					self.entries.push(Entry { conditions:current.clone(), keyword, args });
+				},
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Resolve the settings of `host`, which may be an alias defined by a
+	/// `Host` block.
+	///
+	/// The tokens `%h` (the host name), `%p` (the port), `%r` (the remote
+	/// user), `%n` (the host name given), `%u` (the local user), `%d`
+	/// (the home directory) and `%%` are expanded in `IdentityFile`,
+	/// `ProxyCommand` and `UserKnownHostsFile`, and a leading `~` in
+	/// paths. `HostName` only expands `%h`, to the host name given.
+	pub fn resolve(&self, host:&str) -> HostConfig {
+		let local_user = local_user();
+
+		let mut values = HashMap::<&str, &[String]>::new();
+
+		let mut identity_files = Vec::new();
+
+		for entry in self.entries.iter() {
+			let host_name = values
+				.get("hostname")
+				.and_then(|args| args.first())
+				.map_or_else(|| host.to_string(), |h| expand_host_name(h, host));
+
+			let user = values.get("user").and_then(|args| args.first()).unwrap_or(&local_user);
+
+			let matches = entry.conditions.iter().all(|&i| {
+				self.conditions.get(i).map_or(false, |condition| {
+					condition.matches(host, &host_name, user, &local_user)
+				})
+			});
+
+			if !matches {
+				continue;
+			}
+
+			if entry.keyword == "identityfile" {
+				identity_files.extend(entry.args.first());
+			} else {
+				values.entry(entry.keyword.as_str()).or_insert(entry.args.as_slice());
+			}
+		}
+
+		let first = |keyword:&str| values.get(keyword).and_then(|args| args.first());
+
+		let host_name =
+			first("hostname").map_or_else(|| host.to_string(), |h| expand_host_name(h, host));
+
+		let port = first("port").and_then(|p| p.parse().ok()).unwrap_or(22);
+
+		let user = first("user").cloned();
+
+		let tokens = Tokens {
+			host_name:&host_name,
+			port,
+			user:user.as_deref().unwrap_or(&local_user),
+			original:host,
+			local_user:&local_user,
+		};
+
+		let identity_files = identity_files
+			.into_iter()
+			.filter(|f| !is_none(f))
+			.map(|f| expand_path(&tokens.expand(f)))
+			.collect();
+
+		// The command is the rest of the line.
+		let proxy_command = values
+			.get("proxycommand")
+			.map(|args| args.join(" "))
+			.filter(|c| !is_none(c))
+			.map(|c| tokens.expand(&c));
+
+		let user_known_hosts_files = values
+			.get("userknownhostsfile")
+			.map(|files| files.iter().map(|f| expand_path(&tokens.expand(f))).collect())
+			.unwrap_or_default();
+
+		HostConfig {
+			host:host.to_string(),
+			host_name,
+			port,
+			user,
+			identity_files,
+			proxy_jump:first("proxyjump").filter(|j| !is_none(j)).cloned(),
+			proxy_command,
+			server_alive_interval:first("serveraliveinterval")
+				.and_then(|s| s.parse().ok())
+				.filter(|&s| s > 0)
+				.map(Duration::from_secs),
+			server_alive_count_max:first("serveralivecountmax").and_then(|s| s.parse().ok()),
+			strict_host_key_checking:first("stricthostkeychecking").map(|s| {
+				match s.to_ascii_lowercase().as_str() {
+					"off" => "no".to_string(),
+					"on" => "yes".to_string(),
+					s => s.to_string(),
+				}
+			}),
+			user_known_hosts_files,
+			preferred_authentications:first("preferredauthentications")
+				.map(|methods| methods.split(',').map(String::from).collect())
+				.unwrap_or_default(),
+		}
+	}
+}
+
+impl HostConfig {
+	/// The address to give to [`crate::client::connect`].
+	pub fn addr(&self) -> (&str, u16) { (&self.host_name, self.port) }
+
+	/// Host key checking following `StrictHostKeyChecking` and
+	/// `UserKnownHostsFile`, to use as
+	/// [`Config::host_key_verification`][super::Config::host_key_verification].
+	///
+	/// `yes` refuses unknown keys, `accept-new` and `no` record them
+	/// (changed keys are always refused). Returns `None` for `ask`, which
+	/// is also the default, leaving the decision to
+	/// [`Handler::check_server_key`][super::Handler::check_server_key].
+	pub fn host_key_verification(&self) -> Result<Option<HostKeyVerification>, crate::Error> {
+		let policy = match self.strict_host_key_checking.as_deref() {
+			Some("yes") => StrictHostKeyChecking::Strict,
+			Some("accept-new") | Some("no") => StrictHostKeyChecking::AcceptNew,
+			_ => return Ok(None),
+		};
+
+		let mut known_hosts = KnownHosts::system_and_user()?;
+
+		let mut files = self.user_known_hosts_files.iter().cloned();
+
+		if let Some(user_file) = files.next() {
+			known_hosts.user_file = Some(user_file);
+
+			known_hosts.files.extend(files);
+		}
+
+		Ok(Some(HostKeyVerification {
+			host:self.host_name.clone(),
+			port:self.port,
+			known_hosts,
+			policy,
+		}))
+	}
+}
+
+impl Condition {
+	fn matches(&self, original:&str, host_name:&str, user:&str, local_user:&str) -> bool {
+		match self {
+			Condition::Host(patterns) => {
+				patterns_match(patterns.iter().map(|p| p.as_str()), original)
+			},
+			Condition::Match(criteria) => {
+				criteria.iter().all(|criterion| {
+					let matches = match criterion.kind {
+						CriterionKind::All => true,
+						CriterionKind::Host(ref p) => patterns_match(p.split(','), host_name),
+						CriterionKind::OriginalHost(ref p) => {
+							patterns_match(p.split(','), original)
+						},
+						CriterionKind::User(ref p) => patterns_match(p.split(','), user),
+						CriterionKind::LocalUser(ref p) => {
+							patterns_match(p.split(','), local_user)
+						},
+						CriterionKind::Unsupported => false,
+					};
+
+					matches != criterion.negated
+				})
+			},
+		}
+	}
+}
+
+struct Tokens<'a> {
+	host_name:&'a str,
+	port:u16,
+	user:&'a str,
+	original:&'a str,
+	local_user:&'a str,
+}
+
+impl Tokens<'_> {
+	fn expand(&self, s:&str) -> String {
+		let mut expanded = String::new();
+
+		let mut chars = s.chars();
+
+		while let Some(c) = chars.next() {
+			if c != '%' {
+				expanded.push(c);
+
+				continue;
+			}
+
+			match chars.next() {
+				Some('%') => expanded.push('%'),
+				Some('h') => expanded.push_str(self.host_name),
+				Some('p') => expanded.push_str(&self.port.to_string()),
+				Some('r') => expanded.push_str(self.user),
+				Some('n') => expanded.push_str(self.original),
+				Some('u') => expanded.push_str(self.local_user),
+				Some('d') => {
+					expanded.push_str(&dirs::home_dir().unwrap_or_default().to_string_lossy())
+				},
+				Some(c) => {
+					expanded.push('%');
+
+					expanded.push(c)
+				},
+				None => expanded.push('%'),
+			}
+		}
+
+		expanded
+	}
+}
+
+/// `none` disables identity files and proxies.
+fn is_none(value:&str) -> bool { value.eq_ignore_ascii_case("none") }
+
+fn expand_host_name(host_name:&str, original:&str) -> String {
+	host_name.replace("%%", "\0").replace("%h", original).replace('\0', "%")
+}
+
+/// Expand a leading `~` to the home directory.
+fn expand_path(path:&str) -> PathBuf {
+	match (path.strip_prefix('~'), dirs::home_dir()) {
+		(Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+			home.join(rest.trim_start_matches('/'))
+		},
+		_ => PathBuf::from(path),
+	}
+}
+
+fn ssh_dir() -> Result<PathBuf, crate::Error> {
+	Ok(dirs::home_dir().ok_or(crate::Error::NoHomeDir)?.join(".ssh"))
+}
+
+fn local_user() -> String {
+	std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default()
+}
+
+/// The files named by an `Include` argument, which may have wildcards in
+/// its last component. Relative paths are in `~/.ssh`.
+fn include_paths(arg:&str) -> Result<Vec<PathBuf>, crate::Error> {
+	let path = expand_path(arg);
+
+	let path = if path.is_absolute() { path } else { ssh_dir()?.join(path) };
+
+	let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+	if !name.contains(|c:char| c == '*' || c == '?') {
+		// Missing files are ignored.
+		return Ok(if path.exists() { vec![path] } else { Vec::new() });
+	}
+
+	let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+	let mut paths = match std::fs::read_dir(&dir) {
+		Ok(entries) => {
+			entries
+				.filter_map(|entry| entry.ok())
+				.filter(|entry| wildcard_matches(&name, &entry.file_name().to_string_lossy()))
+				.map(|entry| entry.path())
+				.collect::<Vec<_>>()
+		},
+		Err(_) => Vec::new(),
+	};
+
+	paths.sort();
+
+	Ok(paths)
+}
+
+/// Split a line into a lowercase keyword and its arguments, or `None` for
+/// blank lines and comments. Arguments may be quoted, and a `#` starting
+/// an argument starts a comment.
+fn split_line(line:&str) -> Option<Option<(String, Vec<String>)>> {
+	let line = line.trim();
+
+	if line.is_empty() || line.starts_with('#') {
+		return Some(None);
+	}
+
+	let end = line.find(|c:char| c == '=' || c.is_whitespace()).unwrap_or(line.len());
+
+	let (keyword, rest) = line.split_at(end);
+
+	// The keyword may be followed by `=`.
+	let rest = rest.trim_start();
+
+	let rest = rest.strip_prefix('=').unwrap_or(rest);
+
+	let mut args = Vec::new();
+
+	let mut chars = rest.chars().peekable();
+
+	loop {
+		while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+		match chars.peek() {
+			None | Some('#') => break,
+			_ => {},
+		}
+
+		let mut arg = String::new();
+
+		let mut quote = None;
+
+		while let Some(c) = chars.next() {
+			match (c, quote) {
+				('\\', _) if matches!(chars.peek(), Some('\\' | '"' | '\'' | ' ')) => {
+					arg.extend(chars.next())
+				},
+				('"' | '\'', None) => quote = Some(c),
+				(c, Some(q)) if c == q => quote = None,
+				(c, None) if c.is_whitespace() => break,
+				(c, _) => arg.push(c),
+			}
+		}
+
+		// Unterminated quote.
+		if quote.is_some() {
+			return None;
+		}
+
+		args.push(arg);
+	}
+
+	Some(Some((keyword.to_ascii_lowercase(), args)))
+}
+
+/// Whether these are valid arguments for one of the keywords interpreted by
+/// [`SshConfig::resolve`]. Other keywords are accepted as they are.
+fn valid_args(keyword:&str, args:&[String]) -> bool {
+	let first = args.first();
+
+	match keyword {
+		"hostname" | "user" | "identityfile" | "proxyjump" | "proxycommand"
+		| "userknownhostsfile" | "preferredauthentications" => first.is_some(),
+		"port" => first.and_then(|p| p.parse::<u16>().ok()).is_some(),
+		"serveraliveinterval" => first.and_then(|s| s.parse::<u64>().ok()).is_some(),
+		"serveralivecountmax" => first.and_then(|s| s.parse::<u32>().ok()).is_some(),
+		"stricthostkeychecking" => {
+			first.map_or(false, |s| {
+				["yes", "no", "ask", "accept-new", "on", "off"]
+					.contains(&s.to_ascii_lowercase().as_str())
+			})
+		},
+		_ => true,
+	}
+}
+
+fn parse_criteria(args:&[String]) -> Option<Vec<Criterion>> {
+	let mut criteria = Vec::new();
+
+	let mut args = args.iter();
+
+	while let Some(arg) = args.next() {
+		let arg = arg.to_ascii_lowercase();
+
+		let (negated, name) = match arg.strip_prefix('!') {
+			Some(name) => (true, name),
+			None => (false, arg.as_str()),
+		};
+
+		let kind = match name {
+			"all" => CriterionKind::All,
+			"canonical" | "final" => CriterionKind::Unsupported,
+			"host" => CriterionKind::Host(args.next()?.clone()),
+			"originalhost" => CriterionKind::OriginalHost(args.next()?.clone()),
+			"user" => CriterionKind::User(args.next()?.clone()),
+			"localuser" => CriterionKind::LocalUser(args.next()?.clone()),
+			"exec" | "localnetwork" | "tagged" => {
+				args.next()?;
+
+				CriterionKind::Unsupported
+			},
+			_ => return None,
+		};
+
+		criteria.push(Criterion { negated, kind });
+	}
+
+	if criteria.is_empty() { None } else { Some(criteria) }
+}
+
+/// Whether `s` matches a list of patterns, where `*` and `?` are
+/// wildcards. Patterns starting with `!` exclude what they match.
+fn patterns_match<'a, I:Iterator<Item = &'a str>>(patterns:I, s:&str) -> bool {
+	let s = s.to_ascii_lowercase();
+
+	let mut matches = false;
+
+	for pattern in patterns {
+		let pattern = pattern.to_ascii_lowercase();
+
+		if let Some(pattern) = pattern.strip_prefix('!') {
+			if wildcard_matches(pattern, &s) {
+				return false;
+			}
+		} else if wildcard_matches(&pattern, &s) {
+			matches = true;
+		}
+	}
+
+	matches
+}
+
+fn wildcard_matches(pattern:&str, s:&str) -> bool {
+	fn matches(pattern:&[u8], s:&[u8]) -> bool {
+		match pattern.split_first() {
+			None => s.is_empty(),
+			Some((b'*', rest)) => (0..=s.len()).any(|i| matches(rest, s.get(i..).unwrap_or(&[]))),
+			Some((b'?', rest)) => s.split_first().map_or(false, |(_, s)| matches(rest, s)),
+			Some((c, rest)) => s.split_first().map_or(false, |(d, s)| c == d && matches(rest, s)),
+		}
+	}
+
+	matches(pattern.as_bytes(), s.as_bytes())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_sessions {
+
+	#[test]
+	fn test_ssh_config() {
+		use std::{path::PathBuf, time::Duration};
+
+		use crate::client::config::SshConfig;
+
+		let home = dirs::home_dir().unwrap();
+
+		let config = SshConfig::parse(include_str!("../../../files/ssh_config/config")).unwrap();
+
+		let db = config.resolve("internal-db");
+
+		assert_eq!(db.host, "internal-db");
+
+		assert_eq!(db.addr(), ("internal-db.corp.example.com", 2022));
+
+		assert_eq!(db.user.as_deref(), Some("nobody"));
+
+		assert_eq!(db.proxy_jump.as_deref(), Some("bastion"));
+
+		assert_eq!(db.identity_files, vec![
+			home.join(".ssh/id corp"),
+			home.join(".ssh/id_nobody@internal-db.corp.example.com"),
+		]);
+
+		// The value before any Host block comes first.
+		assert_eq!(db.server_alive_interval, Some(Duration::from_secs(30)));
+
+		assert_eq!(db.server_alive_count_max, Some(5));
+
+		assert_eq!(db.strict_host_key_checking.as_deref(), Some("accept-new"));
+
+		// Excluded by the negated pattern.
+		let legacy = config.resolve("internal-legacy");
+
+		assert_eq!(legacy.addr(), ("10.0.0.5", 22));
+
+		assert_eq!(legacy.proxy_jump, None);
+
+		assert_eq!(legacy.proxy_command.as_deref(), Some("ssh -W 10.0.0.5:22 bastion"));
+
+		assert_eq!(legacy.strict_host_key_checking.as_deref(), Some("no"));
+
+		let bastion = config.resolve("bastion");
+
+		assert_eq!(bastion.addr(), ("bastion.example.com", 2200));
+
+		assert_eq!(bastion.identity_files, vec![
+			home.join(".ssh/bastion"),
+			home.join(".ssh/id_jump@bastion.example.com"),
+		]);
+
+		let git = config.resolve("git.example.com");
+
+		assert_eq!(git.user.as_deref(), Some("git"));
+
+		assert_eq!(git.user_known_hosts_files, vec![
+			home.join(".ssh/known_hosts.git"),
+			PathBuf::from("/etc/ssh/git_known_hosts"),
+		]);
+
+		assert_eq!(git.preferred_authentications, vec!["publickey", "keyboard-interactive"]);
+
+		let verification = git.host_key_verification().unwrap().unwrap();
+
+		assert_eq!(verification.known_hosts.user_file, Some(home.join(".ssh/known_hosts.git")));
+
+		let other = config.resolve("OTHER");
+
+		assert_eq!(other.addr(), ("OTHER", 22));
+
+		assert_eq!(other.user.as_deref(), Some("nobody"));
+
+		// A leading `Host *` overrides the blocks after it.
+		let config = SshConfig::parse("Host *\n User first\nHost h\n User second\n Port 23\n")
+			.unwrap();
+
+		assert_eq!(config.resolve("h").user.as_deref(), Some("first"));
+
+		assert_eq!(config.resolve("h").port, 23);
+
+		// `Match user` sees the user set by earlier blocks, and `none`
+		// disables identity files.
+		let config = SshConfig::parse(
+			"Host h\n User deploy\n IdentityFile none\nMatch user deploy\n Port 2022\n",
+		)
+		.unwrap();
+
+		assert_eq!(config.resolve("h").port, 2022);
+
+		assert!(config.resolve("h").identity_files.is_empty());
+
+		assert_eq!(config.resolve("h").host_key_verification().unwrap().map(|_| ()), None);
+
+		// Included files are read under the enclosing block.
+		let dir = std::env::temp_dir().join(format!("russh-ssh-config-{}", std::process::id()));
+
+		std::fs::create_dir_all(&dir).unwrap();
+
+		std::fs::write(dir.join("hosts.conf"), "Port 2323\nUser included\n").unwrap();
+
+		let config = SshConfig::parse(&format!(
+			"Host inc\n Include {}/*.conf\nHost *\n User nobody\n",
+			dir.display()
+		))
+		.unwrap();
+
+		assert_eq!(config.resolve("inc").addr(), ("inc", 2323));
+
+		assert_eq!(config.resolve("inc").user.as_deref(), Some("included"));
+
+		assert_eq!(config.resolve("out").port, 22);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		for (invalid, line) in [
+			("Host a\nPort ssh\n", 2),
+			("Host\n", 1),
+			("User \"unterminated\n", 1),
+			("Match nonsense\n", 1),
+			("\nStrictHostKeyChecking maybe\n", 2),
+		] {
+			assert!(matches!(
+				SshConfig::parse(invalid),
+				Err(crate::Error::SshConfig { line: l }) if l == line
+			));
+		}
+	}
+}
@@ -12,13 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use std::{cell::RefCell, convert::TryInto};
+use std::{cell::RefCell, convert::TryInto, num::Wrapping};
 
 use log::{debug, error, info, trace, warn};
 use russh_cryptovec::CryptoVec;
 use russh_keys::{
 	encoding::{Encoding, Reader},
-	key::parse_public_key,
+	key,
+	key::{SignatureHash, parse_public_key},
+	signature::Signature,
 };
 use tokio::sync::mpsc::unbounded_channel;
 
@@ -27,9 +29,10 @@ use crate::{
 	ChannelId,
 	ChannelOpenFailure,
 	ChannelParams,
+	GlobalRequestResponse,
 	Sig,
 	auth,
-	client::{Handler, Msg, Prompt, Reply, Session},
+	client::{GlobalRequest, Handler, Msg, Prompt, Reply, Session},
 	key::PubKey,
 	msg,
 	negotiation,
@@ -46,6 +49,7 @@ impl Session {
 	pub(crate) async fn client_read_encrypted<H:Handler>(
 		mut self,
 		mut client:H,
+		seqn:&mut Wrapping<u32>,
 		buf:&[u8],
 	) -> Result<(H, Self), H::Error> {
 		#[allow(clippy::indexing_slicing)] // length checked
@@ -64,8 +68,14 @@ impl Session {
 				} else if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
 					Some(KexInit::received_rekey(
 						exchange,
-						negotiation::Client::read_kex(buf, &self.common.config.as_ref().preferred)?,
+						negotiation::Client::read_kex(
+							buf,
+							&self.common.config.as_ref().preferred,
+							enc.none_cipher,
+							&self.common.config.as_ref().ciphers,
+						)?,
 						&enc.session_id,
+						enc.none_cipher,
 					))
 				} else {
 					None
@@ -101,9 +111,29 @@ impl Session {
 						enc.rekey = Some(Kex::DhDone(kexdhdone));
 
 						Ok((client, self))
-					} else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
+					} else if kexdhdone.kex.is_group_exchange()
+						&& buf.first() == Some(&msg::KEX_DH_GEX_GROUP)
+					{
+						kexdhdone.client_gex_group(
+							&mut *self.common.cipher.local_to_remote,
+							buf,
+							&mut self.common.write_buffer,
+						)?;
+
+						enc.rekey = Some(Kex::DhDone(kexdhdone));
+
+						self.flush()?;
+
+						Ok((client, self))
+					} else if buf.first() == Some(&msg::KEX_ECDH_REPLY)
+						|| buf.first() == Some(&msg::KEX_DH_GEX_REPLY)
+					{
 						// We've sent ECDH_INIT, waiting for ECDH_REPLY
-						let (kex, h) = kexdhdone.server_key_check(true, client, buf).await?;
+						let config = &self.common.config;
+
+						let (kex, h) = kexdhdone
+							.server_key_check(true, config, client, buf)
+							.await?;
 
 						client = h;
 
@@ -112,7 +142,11 @@ impl Session {
 						self.common
 							.cipher
 							.local_to_remote
-							.write(&[msg::NEWKEYS], &mut self.common.write_buffer);
+							.write(&[msg::NEWKEYS], &mut self.common.write_buffer)?;
+
+						if self.common.strict_kex {
+							self.common.write_buffer.seqn = Wrapping(0);
+						}
 
 						self.flush()?;
 
@@ -128,6 +162,10 @@ impl Session {
 						return Err(crate::Error::Kex.into());
 					}
 
+					if self.common.strict_kex {
+						*seqn = Wrapping(0);
+					}
+
 					self.common.write_buffer.bytes = 0;
 
 					enc.last_rekey = std::time::Instant::now();
@@ -135,6 +173,8 @@ impl Session {
 					// Ok, NEWKEYS received, now encrypted.
 					enc.flush_all_pending();
 
+					enc.wake_writers(&self.common.backlogs);
+
 					let mut pending = std::mem::take(&mut self.pending_reads);
 
 					for p in pending.drain(..) {
@@ -151,6 +191,12 @@ impl Session {
 
 					self.common.newkeys(newkeys);
 
+					self.common.stats.rekeyed();
+
+					if let Some(ref enc) = self.common.encrypted {
+						client.on_rekey(&enc.negotiated);
+					}
+
 					self.flush()?;
 
 					return Ok((client, self));
@@ -203,6 +249,7 @@ impl Session {
 									crate::auth::Method::KeyboardInteractive { submethods } => {
 										auth::AuthRequest {
 											methods:auth::MethodSet::all(),
+											user:None,
 											partial_success:false,
 											current:Some(
 												auth::CurrentRequest::KeyboardInteractive {
@@ -215,6 +262,7 @@ impl Session {
 									_ => {
 										auth::AuthRequest {
 											methods:auth::MethodSet::all(),
+											user:None,
 											partial_success:false,
 											current:None,
 											rejection_count:0,
@@ -228,6 +276,8 @@ impl Session {
 								if enc.write_auth_request(&self.common.auth_user, meth) {
 									debug!("enc: {:?}", &enc.write[len..]);
 
+									self.common.stats.auth_attempted();
+
 									enc.state = EncryptedState::WaitingAuthRequest(auth_request)
 								}
 							} else {
@@ -236,6 +286,8 @@ impl Session {
 						}
 					} else if buf.first() == Some(&msg::EXT_INFO) {
 						return self.handle_ext_info(client, buf);
+					} else if buf.first() == Some(&msg::USERAUTH_BANNER) {
+						return self.handle_auth_banner(client, buf).await;
 					} else {
 						debug!("unknown message: {:?}", buf);
 
@@ -252,21 +304,13 @@ impl Session {
 
 						enc.state = EncryptedState::InitCompression;
 
-						enc.server_compression.init_decompress(&mut enc.decompress);
+						enc.start_delayed_decompress();
+
+						enc.start_delayed_compress(&mut self.common.write_buffer.compress);
 
 						return Ok((client, self));
 					} else if buf.first() == Some(&msg::USERAUTH_BANNER) {
-						let mut r = buf.reader(1);
-
-						let banner = r.read_string().map_err(crate::Error::from)?;
-
-						return if let Ok(banner) = std::str::from_utf8(banner) {
-							let (h, s) = client.auth_banner(banner, self).await?;
-
-							Ok((h, s))
-						} else {
-							Ok((client, self))
-						};
+						return self.handle_auth_banner(client, buf).await;
 					} else if buf.first() == Some(&msg::USERAUTH_FAILURE) {
 						debug!("userauth_failure");
 
@@ -284,12 +328,21 @@ impl Session {
 							}
 						}
 
+						// Older servers may leave out the partial success flag.
+						auth_request.partial_success =
+							r.read_byte().map(|b| b != 0).unwrap_or(false);
+
 						let no_more_methods = auth_request.methods.is_empty();
 
 						self.common.auth_method = None;
 
+						auth_request.current = None;
+
 						self.sender
-							.send(Reply::AuthFailure)
+							.send(Reply::AuthFailure {
+								remaining_methods:auth_request.methods,
+								partial_success:auth_request.partial_success,
+							})
 							.map_err(|_| crate::Error::SendError)?;
 
 						// If no other authentication method is allowed by the
@@ -348,6 +401,7 @@ impl Session {
 							let responses = loop {
 								match self.receiver.recv().await {
 									Some(Msg::AuthInfoResponse { responses }) => break responses,
+									None => return Err(crate::Error::Disconnect.into()),
 									_ => {},
 								}
 							};
@@ -360,7 +414,10 @@ impl Session {
 
 						// continue with userauth_pk_ok
 						match self.common.auth_method.take() {
-							Some(auth_method @ auth::Method::PublicKey { .. }) => {
+							Some(
+								auth_method @ (auth::Method::PublicKey { .. }
+								| auth::Method::OpenSshCertificate { .. }),
+							) => {
 								self.common.buffer.clear();
 
 								enc.client_send_signature(
@@ -369,7 +426,7 @@ impl Session {
 									&mut self.common.buffer,
 								)?
 							},
-							Some(auth::Method::FuturePublicKey { key }) => {
+							Some(auth::Method::FuturePublicKey { key, .. }) => {
 								debug!("public key");
 
 								self.common.buffer.clear();
@@ -407,6 +464,27 @@ impl Session {
 									})
 								}
 							},
+							Some(
+								auth::Method::Password { .. } | auth::Method::PasswordChange { .. },
+							) => {
+								debug!("userauth_passwd_changereq");
+
+								let mut r = buf.reader(1);
+
+								let prompt = String::from_utf8_lossy(
+									r.read_string().map_err(crate::Error::from)?,
+								)
+								.to_string();
+
+								let language_tag = String::from_utf8_lossy(
+									r.read_string().map_err(crate::Error::from)?,
+								)
+								.to_string();
+
+								self.sender
+									.send(Reply::PasswordChangeRequest { prompt, language_tag })
+									.map_err(|_| crate::Error::SendError)?;
+							},
 							_ => {},
 						}
 					} else if buf.first() == Some(&msg::EXT_INFO) {
@@ -429,17 +507,130 @@ impl Session {
 		}
 	}
 
-	fn handle_ext_info<H:Handler>(self, client:H, buf:&[u8]) -> Result<(H, Self), H::Error> {
+	async fn handle_auth_banner<H:Handler>(
+		self,
+		client:H,
+		buf:&[u8],
+	) -> Result<(H, Self), H::Error> {
+		let mut r = buf.reader(1);
+
+		let banner = r.read_string().map_err(crate::Error::from)?;
+
+		// Banners are shown to the user even if they are not valid UTF-8.
+		let banner = String::from_utf8_lossy(banner);
+
+		client.auth_banner(&banner, self).await
+	}
+
+	fn handle_ext_info<H:Handler>(mut self, client:H, buf:&[u8]) -> Result<(H, Self), H::Error> {
 		debug!("Received EXT_INFO: {:?}", buf);
 
+		let mut r = buf.reader(1);
+
+		let n = r.read_u32().map_err(crate::Error::from)?;
+
+		for _ in 0..n {
+			let name = r.read_string().map_err(crate::Error::from)?;
+
+			let value = r.read_string().map_err(crate::Error::from)?;
+
+			if name == b"server-sig-algs" {
+				self.server_sig_algs = Some(parse_sig_algs(value));
+			}
+		}
+
+		// An authentication request may be waiting for this.
+		if let Some(method) = self.common.auth_method.take() {
+			self.common.auth_method = Some(self.with_server_sig_algs(method));
+		}
+
 		Ok((client, self))
 	}
 
+	/// The strongest RSA signature hash the server accepts, if it sent
+	/// `server-sig-algs` at all.
+	#[cfg(feature = "openssl")]
+	fn best_rsa_hash(&self) -> Option<key::SignatureHash> {
+		let algs = self.server_sig_algs.as_ref()?;
+
+		[key::SignatureHash::SHA2_512, key::SignatureHash::SHA2_256, key::SignatureHash::SHA1]
+			.iter()
+			.copied()
+			.find(|hash| algs.contains(&hash.name()))
+	}
+
+	/// Adjusts the signature algorithm of public key authentication to the
+	/// server's `server-sig-algs`.
+	fn with_server_sig_algs(&self, method:auth::Method) -> auth::Method {
+		match method {
+			auth::Method::FuturePublicKey { mut key, algorithms } => {
+				if let Some(algorithm) = self.signer_algorithm(&algorithms) {
+					key.set_algorithm(algorithm.0.as_bytes());
+				}
+
+				auth::Method::FuturePublicKey { key, algorithms }
+			},
+			method => self.with_best_rsa_hash(method),
+		}
+	}
+
+	/// The first of a signer's algorithms the server accepts, or its
+	/// preferred one.
+	fn signer_algorithm(&self, algorithms:&[key::Name]) -> Option<key::Name> {
+		let accepted = |algorithm:&&key::Name| {
+			self.server_sig_algs.as_ref().map_or(false, |algs| algs.contains(algorithm))
+		};
+
+		algorithms.iter().find(accepted).or_else(|| algorithms.first()).copied()
+	}
+
+	/// Signs RSA public key authentication with the hash chosen by
+	/// [`Self::best_rsa_hash`], keeping the key's own otherwise.
+	#[cfg(feature = "openssl")]
+	fn with_best_rsa_hash(&self, method:auth::Method) -> auth::Method {
+		let hash = if let Some(hash) = self.best_rsa_hash() {
+			hash
+		} else {
+			return method;
+		};
+
+		match method {
+			auth::Method::PublicKey { key } => {
+				let key = key.with_signature_hash(hash).map(std::sync::Arc::new).unwrap_or(key);
+
+				auth::Method::PublicKey { key }
+			},
+			auth::Method::OpenSshCertificate { key, mut cert } => {
+				let key = key.with_signature_hash(hash).map(std::sync::Arc::new).unwrap_or(key);
+
+				cert.key.set_algorithm(key.name().as_bytes());
+
+				auth::Method::OpenSshCertificate { key, cert }
+			},
+			auth::Method::Hostbased { key, host_name, local_user } => {
+				let key = key.with_signature_hash(hash).map(std::sync::Arc::new).unwrap_or(key);
+
+				auth::Method::Hostbased { key, host_name, local_user }
+			},
+			method => method,
+		}
+	}
+
+	#[cfg(not(feature = "openssl"))]
+	fn with_best_rsa_hash(&self, method:auth::Method) -> auth::Method { method }
+
 	async fn client_read_authenticated<H:Handler>(
 		mut self,
 		mut client:H,
 		buf:&[u8],
 	) -> Result<(H, Self), H::Error> {
+		// https://tools.ietf.org/html/rfc4254#section-5.3
+		if self.common.encrypted.as_ref().map_or(false, |enc| enc.is_for_closing_channel(buf)) {
+			debug!("discarding a message on a closed channel");
+
+			return Ok((client, self));
+		}
+
 		match buf.first() {
 			Some(&msg::CHANNEL_OPEN_CONFIRMATION) => {
 				debug!("channel_open_confirmation");
@@ -453,6 +644,9 @@ impl Session {
 				if let Some(ref mut enc) = self.common.encrypted {
 					if let Some(parameters) = enc.channels.get_mut(&local_id) {
 						parameters.confirm(&msg);
+
+						// Send the data queued before the confirmation.
+						enc.flush_pending(local_id);
 					} else {
 						// We've not requested this channel, close connection.
 						return Err(crate::Error::Inconsistent.into());
@@ -480,7 +674,7 @@ impl Session {
 				if let Some(ref mut enc) = self.common.encrypted {
 					// The CHANNEL_CLOSE message must be sent to the server at
 					// this point or the session will not be released.
-					enc.close(channel_num);
+					enc.close_received(channel_num);
 				}
 
 				client.channel_close(channel_num, self).await
@@ -680,16 +874,20 @@ impl Session {
 				debug!("amount: {:?}", amount);
 
 				if let Some(ref mut enc) = self.common.encrypted {
-					if let Some(ref mut channel) = enc.channels.get_mut(&channel_num) {
-						channel.recipient_window_size += amount;
-
-						new_value = channel.recipient_window_size;
-					} else {
-						return Err(crate::Error::WrongChannel.into());
+					match enc.window_adjusted(channel_num, amount) {
+						Some(window) => new_value = window,
+						None => return Err(crate::Error::WrongChannel.into()),
 					}
 				}
 
-				client.window_adjusted(channel_num, new_value, self).await
+				let (client, mut session) =
+					client.window_adjusted(channel_num, new_value, self).await?;
+
+				if let Some(ref mut enc) = session.common.encrypted {
+					enc.wake_writers(&session.common.backlogs);
+				}
+
+				Ok((client, session))
 			},
 			Some(&msg::GLOBAL_REQUEST) => {
 				let mut r = buf.reader(1);
@@ -712,6 +910,8 @@ impl Session {
 					} else if req == b"hostkeys-00@openssh.com" {
 						let mut keys = vec![];
 
+						let mut blobs = vec![];
+
 						loop {
 							match r.read_string() {
 								Ok(key) => {
@@ -722,7 +922,11 @@ impl Session {
 									let key = parse_public_key(key, None).map_err(crate::Error::from);
 
 									match key {
-										Ok(key) => keys.push(key),
+										Ok(key) => {
+											keys.push(key);
+
+											blobs.push(key2.to_vec());
+										},
 										Err(err) => {
 											debug!(
 												"failed to parse announced host key {:?}: {:?}",
@@ -740,22 +944,134 @@ impl Session {
 							}
 						}
 
+						// Ask the server to prove that it holds these keys.
+						if !keys.is_empty() {
+							push_packet!(enc.write, {
+								enc.write.push(msg::GLOBAL_REQUEST);
+
+								enc.write.extend_ssh_string(b"hostkeys-prove-00@openssh.com");
+
+								enc.write.push(1);
+
+								for blob in blobs.iter() {
+									enc.write.extend_ssh_string(blob);
+								}
+							});
+
+							self.pending_global_requests.push_back(GlobalRequest::HostKeysProve(
+								keys.iter().cloned().zip(blobs).collect(),
+							));
+						}
+
 						return client.openssh_ext_host_keys_announced(keys, self).await;
 					} else {
-						warn!(
-							"Unhandled global request: {:?} {:?}",
-							std::str::from_utf8(req),
-							wants_reply
-						);
+						let name = String::from_utf8_lossy(req);
 
-						self.common.wants_reply = false;
+						let payload = buf.get(r.position..).unwrap_or_default();
+
+						let (client, response, mut session) =
+							client.global_request(&name, payload, self).await?;
+
+						if let Some(ref mut enc) = session.common.encrypted {
+							if wants_reply == 1 {
+								session.common.wants_reply = false;
+
+								push_packet!(enc.write, {
+									match response {
+										GlobalRequestResponse::Success(ref data) => {
+											enc.write.push(msg::REQUEST_SUCCESS);
+
+											enc.write.extend(data);
+										},
+										GlobalRequestResponse::Failure => {
+											enc.write.push(msg::REQUEST_FAILURE);
+										},
+									}
+								});
+							}
+						}
 
-						push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+						return Ok((client, session));
 					}
 				}
 
 				Ok((client, self))
 			},
+			Some(&msg::REQUEST_SUCCESS) => {
+				let keys = match self.pending_global_requests.pop_front() {
+					Some(GlobalRequest::HostKeysProve(keys)) => keys,
+					Some(GlobalRequest::TcpIpForward { port, reply }) => {
+						// The server tells which port it chose.
+						let port =
+							if port == 0 { buf.reader(1).read_u32().unwrap_or(0) } else { port };
+
+						reply.send(Some(port)).unwrap_or(());
+
+						return Ok((client, self));
+					},
+					Some(GlobalRequest::Accepted(reply)) => {
+						reply.send(true).unwrap_or(());
+
+						return Ok((client, self));
+					},
+					Some(GlobalRequest::Keepalive) => {
+						self.keepalives_unanswered = 0;
+
+						return Ok((client, self));
+					},
+					Some(GlobalRequest::Reply(reply)) => {
+						reply.send(Some(buf.get(1..).unwrap_or_default().to_vec())).unwrap_or(());
+
+						return Ok((client, self));
+					},
+					_ => return Ok((client, self)),
+				};
+
+				let session_id = match self.common.encrypted {
+					Some(ref enc) => enc.session_id.as_ref(),
+					None => return Err(crate::Error::Inconsistent.into()),
+				};
+
+				let mut r = buf.reader(1);
+
+				let mut proven = Vec::new();
+
+				for (key, blob) in keys {
+					// A missing signature fails the proof.
+					let signature = r.read_string().unwrap_or(&[]);
+
+					if host_key_proven(&key, &blob, session_id, signature) {
+						proven.push(key);
+					} else {
+						warn!("the server could not prove it holds host key {:?}", key);
+					}
+				}
+
+				client.server_host_keys_updated(proven, self).await
+			},
+			Some(&msg::REQUEST_FAILURE) => {
+				match self.pending_global_requests.pop_front() {
+					Some(GlobalRequest::HostKeysProve(_)) => {
+						warn!("the server could not prove it holds its announced host keys");
+					},
+					Some(GlobalRequest::TcpIpForward { reply, .. }) => {
+						reply.send(None).unwrap_or(());
+					},
+					Some(GlobalRequest::Accepted(reply)) => {
+						reply.send(false).unwrap_or(());
+					},
+					// The server is alive, whether it knows the request or not.
+					Some(GlobalRequest::Keepalive) => {
+						self.keepalives_unanswered = 0;
+					},
+					Some(GlobalRequest::Reply(reply)) => {
+						reply.send(None).unwrap_or(());
+					},
+					_ => {},
+				}
+
+				Ok((client, self))
+			},
 			Some(&msg::CHANNEL_SUCCESS) => {
 				let mut r = buf.reader(1);
 
@@ -775,6 +1091,8 @@ impl Session {
 
 				let msg = OpenChannelMessage::parse(&mut r)?;
 
+				self.common.stats.channel_opened();
+
 				if let Some(ref mut enc) = self.common.encrypted {
 					let id = enc.new_channel_id();
 
@@ -785,9 +1103,15 @@ impl Session {
 						sender_window_size:self.common.config.window_size,
 						recipient_maximum_packet_size:msg.recipient_maximum_packet_size,
 						sender_maximum_packet_size:self.common.config.maximum_packet_size,
+						target_window_size:None,
 						confirmed:true,
 						wants_reply:false,
 						pending_data:std::collections::VecDeque::new(),
+						data_queued:0,
+						writers:std::collections::VecDeque::new(),
+						flushes:Vec::new(),
+						closes:Vec::new(),
+						pending_exit:None,
 					};
 
 					let confirm = || {
@@ -853,10 +1177,43 @@ impl Session {
 								)
 								.await?
 						},
+						ChannelType::ForwardedStreamLocal(d) => {
+							confirm();
+
+							let channel = self.accept_server_initiated_channel(id, &msg);
+
+							client
+								.server_channel_open_forwarded_streamlocal(
+									channel,
+									&d.socket_path,
+									self,
+								)
+								.await?
+						},
+						ChannelType::DirectStreamLocal(_) => {
+							debug!("refusing a direct-streamlocal channel from the server");
+
+							msg.unknown_type(&mut enc.write);
+
+							(client, self)
+						},
+						ChannelType::AgentForward if !self.agent_forward_requested => {
+							debug!("refusing an agent channel, forwarding was not requested");
+
+							msg.fail(
+								&mut enc.write,
+								ChannelOpenFailure::AdministrativelyProhibited as u8,
+								b"Agent forwarding not requested",
+							);
+
+							(client, self)
+						},
 						ChannelType::AgentForward => {
 							confirm();
 
-							client.server_channel_open_agent_forward(id, self).await?
+							let channel = self.accept_server_initiated_channel(id, &msg);
+
+							client.server_channel_open_agent_forward(channel, self).await?
 						},
 						ChannelType::Unknown { typ } => {
 							if client.server_channel_handle_unknown(id, typ) {
@@ -896,22 +1253,43 @@ impl Session {
 			receiver,
 			max_packet_size:msg.recipient_maximum_packet_size,
 			window_size:msg.recipient_window_size,
+			close_info:Default::default(),
+			closed:false,
 		}
 	}
 
-	pub(crate) fn write_auth_request_if_needed(&mut self, user:&str, meth:auth::Method) -> bool {
+	pub(crate) fn write_auth_request_if_needed(
+		&mut self,
+		user:&str,
+		meth:auth::Method,
+	) -> Result<bool, crate::Error> {
+		let meth = self.with_server_sig_algs(meth);
+
 		let mut is_waiting = false;
 
 		if let Some(ref mut enc) = self.common.encrypted {
 			is_waiting = match enc.state {
-				EncryptedState::WaitingAuthRequest(_) => true,
+				EncryptedState::WaitingAuthRequest(ref mut auth_request) => {
+					// Replies such as USERAUTH_INFO_REQUEST are read according
+					// to the method being tried.
+					auth_request.current = match meth {
+						auth::Method::KeyboardInteractive { ref submethods } => {
+							Some(auth::CurrentRequest::KeyboardInteractive {
+								submethods:submethods.to_string(),
+							})
+						},
+						_ => None,
+					};
+
+					true
+				},
 				EncryptedState::WaitingAuthServiceRequest { accepted, ref mut sent } => {
 					debug!("sending ssh-userauth service requset");
 
 					if !*sent {
 						let p = b"\x05\0\0\0\x0Cssh-userauth";
 
-						self.common.cipher.local_to_remote.write(p, &mut self.common.write_buffer);
+						self.common.cipher.local_to_remote.write(p, &mut self.common.write_buffer)?;
 						*sent = true
 					}
 
@@ -922,8 +1300,8 @@ impl Session {
 
 			debug!("write_auth_request_if_needed: is_waiting = {:?}", is_waiting);
 
-			if is_waiting {
-				enc.write_auth_request(user, &meth);
+			if is_waiting && enc.write_auth_request(user, &meth) {
+				self.common.stats.auth_attempted();
 			}
 		}
 
@@ -933,12 +1311,24 @@ impl Session {
 
 		self.common.auth_method = Some(meth);
 
-		is_waiting
+		Ok(is_waiting)
 	}
 }
 
 impl Encrypted {
 	fn write_auth_request(&mut self, user:&str, auth_method:&auth::Method) -> bool {
+		if let auth::Method::Hostbased { ref key, ref host_name, ref local_user } = *auth_method {
+			// There is no query for host keys, the first request is signed.
+			return match self.client_send_hostbased(user, key, host_name, local_user) {
+				Ok(()) => true,
+				Err(e) => {
+					error!("hostbased signature failed: {:?}", e);
+
+					false
+				},
+			};
+		}
+
 		// The server is waiting for our USERAUTH_REQUEST.
 		push_packet!(self.write, {
 			self.write.push(msg::USERAUTH_REQUEST);
@@ -966,6 +1356,21 @@ impl Encrypted {
 
 					true
 				},
+				auth::Method::PasswordChange { ref password, ref new_password } => {
+					self.write.extend_ssh_string(user.as_bytes());
+
+					self.write.extend_ssh_string(b"ssh-connection");
+
+					self.write.extend_ssh_string(b"password");
+
+					self.write.push(1);
+
+					self.write.extend_ssh_string(password.as_bytes());
+
+					self.write.extend_ssh_string(new_password.as_bytes());
+
+					true
+				},
 				auth::Method::PublicKey { ref key } => {
 					self.write.extend_ssh_string(user.as_bytes());
 
@@ -983,6 +1388,23 @@ impl Encrypted {
 
 					true
 				},
+				auth::Method::OpenSshCertificate { ref cert, .. } => {
+					self.write.extend_ssh_string(user.as_bytes());
+
+					self.write.extend_ssh_string(b"ssh-connection");
+
+					self.write.extend_ssh_string(b"publickey");
+
+					self.write.push(0); // This is a probe
+
+					debug!("write_auth_request: {:?}", cert.name());
+
+					self.write.extend_ssh_string(cert.name().as_bytes());
+
+					cert.push_to(&mut self.write);
+
+					true
+				},
 				auth::Method::FuturePublicKey { ref key, .. } => {
 					self.write.extend_ssh_string(user.as_bytes());
 
@@ -1012,6 +1434,8 @@ impl Encrypted {
 
 					true
 				},
+				// Signed and sent above.
+				auth::Method::Hostbased { .. } => unreachable!(),
 			}
 		})
 	}
@@ -1062,12 +1486,63 @@ impl Encrypted {
 					self.write.extend(&buffer[i0..]);
 				})
 			},
+			auth::Method::OpenSshCertificate { ref key, ref cert } => {
+				let i0 = self.client_make_to_sign(user, cert, buffer);
+				// Signed with the certified key.
+				key.add_self_signature(buffer)?;
+
+				push_packet!(self.write, {
+					#[allow(clippy::indexing_slicing)] // length checked
+					self.write.extend(&buffer[i0..]);
+				})
+			},
 			_ => {},
 		}
 
 		Ok(())
 	}
 
+	/// Sends a "hostbased" request, signed with the host key `key` of
+	/// the client host `host_name`, where the user is `local_user`.
+	fn client_send_hostbased(
+		&mut self,
+		user:&str,
+		key:&key::KeyPair,
+		host_name:&str,
+		local_user:&str,
+	) -> Result<(), crate::Error> {
+		let mut buffer = CryptoVec::new();
+
+		buffer.extend_ssh_string(self.session_id.as_ref());
+
+		let i0 = buffer.len();
+
+		buffer.push(msg::USERAUTH_REQUEST);
+
+		buffer.extend_ssh_string(user.as_bytes());
+
+		buffer.extend_ssh_string(b"ssh-connection");
+
+		buffer.extend_ssh_string(b"hostbased");
+
+		buffer.extend_ssh_string(key.name().as_bytes());
+
+		key.push_to(&mut buffer);
+
+		buffer.extend_ssh_string(host_name.as_bytes());
+
+		buffer.extend_ssh_string(local_user.as_bytes());
+
+		key.add_self_signature(&mut buffer)?;
+
+		push_packet!(self.write, {
+			#[allow(clippy::indexing_slicing)] // length checked
+			self.write.extend(&buffer[i0..]);
+		});
+
+		Ok(())
+	}
+
 	fn client_send_auth_response(&mut self, responses:&[String]) -> Result<(), crate::Error> {
 		push_packet!(self.write, {
 			self.write.push(msg::USERAUTH_INFO_RESPONSE);
@@ -1082,3 +1557,40 @@ impl Encrypted {
 		Ok(())
 	}
 }
+
+/// The signature algorithms we know of in a `server-sig-algs` name-list,
+/// in the server's order.
+fn parse_sig_algs(list:&[u8]) -> Vec<key::Name> {
+	const KNOWN:&[key::Name] = &[key::ED25519, key::RSA_SHA2_512, key::RSA_SHA2_256, key::SSH_RSA];
+
+	list.split(|&c| c == b',')
+		.filter_map(|name| KNOWN.iter().find(|known| known.0.as_bytes() == name).copied())
+		.collect()
+}
+
+/// Whether `signature` proves that the server holds `key` (whose blob is
+/// `blob`), as described for `hostkeys-prove-00@openssh.com` in OpenSSH's
+/// `PROTOCOL`.
+fn host_key_proven(key:&key::PublicKey, blob:&[u8], session_id:&[u8], signature:&[u8]) -> bool {
+	let mut data = CryptoVec::new();
+
+	data.extend_ssh_string(b"hostkeys-prove-00@openssh.com");
+
+	data.extend_ssh_string(session_id);
+
+	data.extend_ssh_string(blob);
+
+	let mut key = key.clone();
+
+	match Signature::from_bytes(signature) {
+		Ok(Signature::Ed25519(ref bytes)) => key.verify_detached(&data, &bytes.0),
+		// SHA-1 signatures are refused.
+		Ok(Signature::RSA { hash:SignatureHash::SHA1, .. }) => false,
+		Ok(Signature::RSA { ref hash, ref bytes }) => {
+			key.set_algorithm(hash.name().0.as_bytes());
+
+			key.verify_detached(&data, bytes)
+		},
+		_ => false,
+	}
+}
@@ -0,0 +1,502 @@
+//! Port forwarding, as OpenSSH's `-L` option, and agent forwarding, as
+//! its `-A` option.
+//!
+//! ```no_run
+//! # async fn run<H:russh::client::Handler>(
+//! # 	session:&russh::client::Handle<H>,
+//! # ) -> Result<(), russh::Error> {
+//! // Connections to port 8080 reach port 80 of the server.
+//! let forward = session.forward_local_port("127.0.0.1", 8080, "localhost", 80).await?;
+//!
+//! // ...
+//!
+//! forward.cancel().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+
+use log::debug;
+use russh_keys::{
+	agent::{
+		client::AgentClient,
+		forward::{Decision, SignRequest, forward},
+	},
+	key::PublicKey,
+};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::{mpsc::Sender, watch},
+	task::JoinHandle,
+};
+
+use super::{Handle, Handler, Msg, channel_open_direct_tcpip};
+use crate::Channel;
+
+/// A port forwarding, which stops when this is dropped, closing its
+/// connections.
+pub struct ForwardHandle {
+	local_addr:SocketAddr,
+	// Dropping this stops the listener and the connections.
+	_shutdown:watch::Sender<()>,
+	listener:JoinHandle<()>,
+}
+
+impl std::fmt::Debug for ForwardHandle {
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ForwardHandle").field("local_addr", &self.local_addr).finish()
+	}
+}
+
+impl ForwardHandle {
+	/// The address of the listener, for instance to find the port chosen
+	/// when binding to port 0.
+	pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+
+	/// Stop forwarding, and wait until the listener is closed.
+	pub async fn cancel(self) {
+		let ForwardHandle { _shutdown, listener, .. } = self;
+
+		drop(_shutdown);
+
+		listener.await.unwrap_or(());
+	}
+}
+
+impl<H:Handler> Handle<H> {
+	/// Listen on `bind_addr` and `bind_port`, and forward each connection to
+	/// `target_host` and `target_port` from the server, on a new direct
+	/// TCP/IP channel. Connections are closed if the server refuses to
+	/// open the channel.
+	pub async fn forward_local_port<A:AsRef<str>, B:Into<String>>(
+		&self,
+		bind_addr:A,
+		bind_port:u16,
+		target_host:B,
+		target_port:u32,
+	) -> Result<ForwardHandle, crate::Error> {
+		let listener = TcpListener::bind((bind_addr.as_ref(), bind_port)).await?;
+
+		let local_addr = listener.local_addr()?;
+
+		let (shutdown, stopped) = watch::channel(());
+
+		let listener = tokio::spawn(accept_loop(
+			listener,
+			self.sender.clone(),
+			target_host.into(),
+			target_port,
+			stopped,
+		));
+
+		Ok(ForwardHandle { local_addr, _shutdown:shutdown, listener })
+	}
+}
+
+async fn accept_loop(
+	listener:TcpListener,
+	sender:Sender<Msg>,
+	target_host:String,
+	target_port:u32,
+	mut stopped:watch::Receiver<()>,
+) {
+	loop {
+		let (stream, originator) = tokio::select! {
+			accepted = listener.accept() => {
+				match accepted {
+					Ok(accepted) => accepted,
+					Err(e) => {
+						debug!("forwarding: accept failed: {:?}", e);
+
+						continue;
+					},
+				}
+			},
+			// The sender is dropped.
+			_ = stopped.changed() => return,
+		};
+
+		tokio::spawn(forward_connection(
+			stream,
+			originator,
+			sender.clone(),
+			target_host.clone(),
+			target_port,
+			stopped.clone(),
+		));
+	}
+}
+
+async fn forward_connection(
+	mut stream:TcpStream,
+	originator:SocketAddr,
+	sender:Sender<Msg>,
+	target_host:String,
+	target_port:u32,
+	mut stopped:watch::Receiver<()>,
+) {
+	let open = channel_open_direct_tcpip(
+		&sender,
+		target_host,
+		target_port,
+		originator.ip().to_string(),
+		originator.port() as u32,
+	);
+
+	let channel = tokio::select! {
+		channel = open => channel,
+		_ = stopped.changed() => return,
+	};
+
+	// Dropping the stream closes the connection.
+	let channel = match channel {
+		Ok(channel) => channel,
+		Err(e) => {
+			debug!("forwarding {:?}: {:?}", originator, e);
+
+			return;
+		},
+	};
+
+	let mut channel = channel.into_stream();
+
+	tokio::select! {
+		result = tokio::io::copy_bidirectional(&mut stream, &mut channel) => {
+			debug!("forwarding {:?} done: {:?}", originator, result);
+		},
+		_ = stopped.changed() => {},
+	}
+}
+
+/// Serve an agent forwarding channel from the agent named by
+/// `SSH_AUTH_SOCK`, allowing every request, until the channel is closed.
+/// Each channel has its own connection to the agent.
+pub async fn forward_agent(channel:Channel<Msg>) -> Result<(), crate::Error> {
+	let mut agent = AgentClient::connect_env().await?;
+
+	let allow = |_:&PublicKey, _:&SignRequest| Decision::Allow;
+
+	forward(channel.into_stream(), &mut agent, &allow).await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_forward_local_port() {
+		use tokio::net::{TcpListener, TcpStream};
+
+		struct ServerHandle {
+			echo_port:u32,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_direct_tcpip(
+				self,
+				channel:Channel<server::Msg>,
+				host_to_connect:&str,
+				port_to_connect:u32,
+				originator_address:&str,
+				_:u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if port_to_connect != self.echo_port || originator_address != "127.0.0.1" {
+					return Ok((self, false, session));
+				}
+
+				let target = format!("{}:{}", host_to_connect, port_to_connect);
+
+				tokio::spawn(async move {
+					let mut stream = TcpStream::connect(target).await.unwrap();
+
+					let mut channel = channel.into_stream();
+
+					tokio::io::copy_bidirectional(&mut stream, &mut channel).await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+		}
+
+		let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let echo_port = echo.local_addr().unwrap().port() as u32;
+
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = echo.accept().await {
+				tokio::spawn(async move {
+					let (mut read, mut write) = stream.split();
+
+					tokio::io::copy(&mut read, &mut write).await.ok();
+				});
+			}
+		});
+
+		test_session(
+			Client {},
+			ServerHandle { echo_port },
+			move |client| {
+				async move {
+					let forward = client
+						.forward_local_port("127.0.0.1", 0, "127.0.0.1", echo_port)
+						.await
+						.unwrap();
+
+					let addr = forward.local_addr();
+
+					let connections = (0..8u8)
+						.map(|i| {
+							tokio::spawn(async move {
+								let sent = vec![i; 256 << 10];
+
+								let stream = TcpStream::connect(addr).await.unwrap();
+
+								let (mut read, mut write) = tokio::io::split(stream);
+
+								let writer = tokio::spawn(async move {
+									write.write_all(&sent).await.unwrap();
+
+									write.shutdown().await.unwrap();
+
+									sent
+								});
+
+								let mut received = Vec::new();
+
+								read.read_to_end(&mut received).await.unwrap();
+
+								assert!(received == writer.await.unwrap());
+							})
+						})
+						.collect::<Vec<_>>();
+
+					for connection in connections {
+						connection.await.unwrap();
+					}
+
+					// The server refuses to connect to other ports.
+					let refused =
+						client.forward_local_port("127.0.0.1", 0, "127.0.0.1", 1).await.unwrap();
+
+					let mut stream = TcpStream::connect(refused.local_addr()).await.unwrap();
+
+					let mut received = Vec::new();
+
+					let read = stream.read_to_end(&mut received).await;
+
+					assert!(received.is_empty() && read.map(|n| n == 0).unwrap_or(true));
+
+					forward.cancel().await;
+
+					assert!(TcpStream::connect(addr).await.is_err());
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	#[cfg(unix)]
+	#[cfg(feature = "rs-crypto")]
+	#[tokio::test]
+	async fn test_agent_forwarding() {
+		use std::{net::SocketAddr, sync::Arc};
+
+		use russh_keys::{
+			agent::client::AgentClient,
+			key::{KeyPair, PublicKey},
+		};
+		use tokio::net::{TcpListener, UnixListener};
+
+		// Agent channels are served from `SSH_AUTH_SOCK`.
+		struct ServerHandle {
+			target:SocketAddr,
+			refused:UnboundedSender<bool>,
+			logins:UnboundedSender<bool>,
+		}
+
+		// Log in to `target` with the first key of the forwarded agent.
+		async fn log_in(handle:server::Handle, target:SocketAddr) -> Result<bool, crate::Error> {
+			let channel = handle.channel_open_agent().await?;
+
+			let mut agent = AgentClient::connect(channel.into_stream());
+
+			let key = agent.request_identities().await?.pop().ok_or(crate::Error::NoAuthMethod)?;
+
+			let signer = crate::AgentSigner::new(agent, key);
+
+			let config = Arc::new(client::Config::default());
+
+			let mut session = client::connect(config, target, Client {}).await?;
+
+			Ok(session.authenticate_publickey_with("user", &signer).await?.success())
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				// Agent forwarding is not requested yet.
+				let (handle, refused) = (session.handle(), self.refused.clone());
+
+				tokio::spawn(async move {
+					let result = handle.channel_open_agent().await;
+
+					let refusal = matches!(result, Err(crate::Error::ChannelOpenFailure(_)));
+
+					refused.send(refusal).unwrap();
+				});
+
+				Ok((self, true, session))
+			}
+
+			async fn agent_request(
+				self,
+				_:ChannelId,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				// Concurrent agent channels.
+				for _ in 0..3 {
+					let (handle, target, logins) =
+						(session.handle(), self.target, self.logins.clone());
+
+					tokio::spawn(async move {
+						logins.send(log_in(handle, target).await.unwrap()).unwrap();
+					});
+				}
+
+				Ok((self, true, session))
+			}
+		}
+
+		// The third host, which only accepts the key of the agent.
+		#[derive(Clone)]
+		struct Target {
+			key:PublicKey,
+		}
+
+		#[async_trait]
+		impl server::Handler for Target {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				key:&PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				if *key == self.key {
+					Ok((self, server::Auth::Accept))
+				} else {
+					Ok((self, server::Auth::Reject { proceed_with_methods:None }))
+				}
+			}
+		}
+
+		// The local agent, holding the only key accepted by the target.
+		let agent_path =
+			std::env::temp_dir().join(format!("russh-agent-{}.sock", std::process::id()));
+
+		std::fs::remove_file(&agent_path).ok();
+
+		let listener = UnixListener::bind(&agent_path).unwrap();
+
+		let connections = Box::pin(futures::stream::unfold(listener, |listener| {
+			async move {
+				let connection = listener.accept().await.map(|(stream, _)| stream);
+
+				Some((connection, listener))
+			}
+		}));
+
+		tokio::spawn(russh_keys::agent::server::serve(connections, ()));
+
+		let key = KeyPair::generate_ed25519().unwrap();
+
+		let mut agent = AgentClient::connect_uds(&agent_path).await.unwrap();
+
+		agent.add_identity(&key, &[]).await.unwrap();
+
+		std::env::set_var("SSH_AUTH_SOCK", &agent_path);
+
+		let mut target_config = server::Config::default();
+
+		target_config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		let target_config = Arc::new(target_config);
+
+		let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let target = target_listener.local_addr().unwrap();
+
+		let handler = Target { key:key.clone_public_key().unwrap() };
+
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = target_listener.accept().await {
+				let (config, handler) = (target_config.clone(), handler.clone());
+
+				tokio::spawn(async move {
+					server::run_stream(config, stream, handler).await?.await
+				});
+			}
+		});
+
+		let (refused, mut refusals) = unbounded_channel();
+
+		let (logins, mut results) = unbounded_channel();
+
+		test_session(
+			Client {},
+			ServerHandle { target, refused, logins },
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					assert!(refusals.recv().await.unwrap());
+
+					channel.request_agent_forwarding().await.unwrap();
+
+					for _ in 0..3 {
+						assert!(results.recv().await.unwrap());
+					}
+
+					std::fs::remove_file(&agent_path).ok();
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+}
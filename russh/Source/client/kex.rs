@@ -1,9 +1,10 @@
 use log::{debug, trace};
+use russh_cryptovec::CryptoVec;
 
 use crate::{
 	cipher::SealingKey,
 	client::Config,
-	kex::KEXES,
+	kex::{KEXES, KexAlgorithm},
 	negotiation,
 	negotiation::Select,
 	session::{KexDhDone, KexInit},
@@ -26,7 +27,12 @@ impl KexInit {
 
 			self.exchange.server_kex_init.extend(buf);
 
-			negotiation::Client::read_kex(buf, &config.preferred)?
+			negotiation::Client::read_kex(
+				buf,
+				&config.preferred,
+				self.none_cipher,
+				&config.ciphers,
+			)?
 		};
 
 		debug!("algo = {:?}", algo);
@@ -37,34 +43,28 @@ impl KexInit {
 			self.client_write(config, cipher, write_buffer)?
 		}
 
-		// This function is called from the public API.
-		//
-		// In order to simplify the public API, we reuse the
-		// self.exchange.client_kex buffer to send an extra packet,
-		// then truncate that buffer. Without that, we would need an
-		// extra buffer.
-		let i0 = self.exchange.client_kex_init.len();
+		let kex = match self.guessed.take() {
+			// The server uses the first packet we've already sent.
+			Some(kex) if algo.guessed_right => kex,
+			_ => {
+				let mut kex = KEXES.get(&algo.kex).ok_or(crate::Error::UnknownAlgo)?.make();
 
-		debug!("i0 = {:?}", i0);
+				self.exchange.client_ephemeral.clear();
 
-		let mut kex = KEXES
-			.get(
-				&self
-					.algo
-					.as_ref()
-					.map(|x| &x.kex)
-					.or_else(|| config.preferred.kex.first())
-					.ok_or(crate::Error::NoCommonKexAlgo)?,
-			)
-			.ok_or(crate::Error::UnknownAlgo)?
-			.make();
+				let mut packet = CryptoVec::new();
 
-		kex.client_dh(&mut self.exchange.client_ephemeral, &mut self.exchange.client_kex_init)?;
+				write_first_packet(
+					&mut *kex,
+					config,
+					&mut self.exchange.client_ephemeral,
+					&mut packet,
+				)?;
 
-		#[allow(clippy::indexing_slicing)] // length checked
-		cipher.write(&self.exchange.client_kex_init[i0..], write_buffer);
+				cipher.write(&packet, write_buffer)?;
 
-		self.exchange.client_kex_init.resize(i0);
+				kex
+			},
+		};
 
 		debug!("moving to kexdhdone, exchange = {:?}", self.exchange);
 
@@ -87,11 +87,81 @@ impl KexInit {
 	) -> Result<(), crate::Error> {
 		self.exchange.client_kex_init.clear();
 
-		negotiation::write_kex(&config.preferred, &mut self.exchange.client_kex_init, false)?;
+		// No need to guess once the server's KEXINIT is known.
+		let guessed = if config.send_kex_guess && self.exchange.server_kex_init.is_empty() {
+			config
+				.preferred
+				.kex
+				.first()
+				.and_then(|name| KEXES.get(name))
+				.map(|kex| kex.make())
+				.filter(|kex| !kex.skip_exchange())
+		} else {
+			None
+		};
+
+		negotiation::write_kex(
+			&config.preferred,
+			self.none_cipher,
+			&mut self.exchange.client_kex_init,
+			false,
+			guessed.is_some(),
+		)?;
 
 		self.sent = true;
 
-		cipher.write(&self.exchange.client_kex_init, write_buffer);
+		cipher.write(&self.exchange.client_kex_init, write_buffer)?;
+
+		if let Some(mut kex) = guessed {
+			let mut packet = CryptoVec::new();
+
+			write_first_packet(
+				&mut *kex,
+				config,
+				&mut self.exchange.client_ephemeral,
+				&mut packet,
+			)?;
+
+			cipher.write(&packet, write_buffer)?;
+
+			self.guessed = Some(kex);
+		}
+
+		Ok(())
+	}
+}
+
+/// Writes the first packet of a key exchange: the group request of a
+/// group exchange, else our ephemeral key.
+fn write_first_packet(
+	kex:&mut dyn KexAlgorithm,
+	config:&Config,
+	client_ephemeral:&mut CryptoVec,
+	buf:&mut CryptoVec,
+) -> Result<(), crate::Error> {
+	if kex.is_group_exchange() {
+		kex.client_gex_request(&config.gex, buf)
+	} else {
+		kex.client_dh(client_ephemeral, buf)
+	}
+}
+
+impl KexDhDone {
+	/// Reads the group chosen by the server in a group exchange, and
+	/// sends the client's ephemeral key.
+	pub fn client_gex_group(
+		&mut self,
+		cipher:&mut dyn SealingKey,
+		buf:&[u8],
+		write_buffer:&mut SSHBuffer,
+	) -> Result<(), crate::Error> {
+		self.kex.client_gex_group(buf)?;
+
+		let mut init = CryptoVec::new();
+
+		self.kex.client_dh(&mut self.exchange.client_ephemeral, &mut init)?;
+
+		cipher.write(&init, write_buffer)?;
 
 		Ok(())
 	}
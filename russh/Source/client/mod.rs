@@ -81,6 +81,7 @@
 //! 		.await
 //! 		.1
 //! 		.unwrap()
+//! 		.success()
 //! 	{
 //! 		let mut channel = session.channel_open_session().await.unwrap();
 //! 		channel.data(&b"Hello, world!"[..]).await.unwrap();
@@ -93,7 +94,13 @@
 //!
 //! [Session]: client::Session
 
-use std::{cell::RefCell, collections::HashMap, pin::Pin, sync::Arc};
+use std::{
+	cell::RefCell,
+	collections::{HashMap, VecDeque},
+	num::Wrapping,
+	pin::Pin,
+	sync::Arc,
+};
 
 use async_trait::async_trait;
 use futures::{
@@ -105,12 +112,16 @@ use russh_cryptovec::CryptoVec;
 #[cfg(feature = "openssl")]
 use russh_keys::key::SignatureHash;
 use russh_keys::{
-	encoding::Reader,
+	KnownHostStatus,
+	agent::client::AgentClient,
+	certificate::{self, Certificate},
+	encoding::{Encoding, Reader},
 	key::{self, PublicKey, parse_public_key},
+	known_hosts::KnownHosts,
 };
 use tokio::{
 	self,
-	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
 	net::{TcpStream, ToSocketAddrs},
 	pin,
 	sync::mpsc::{
@@ -119,28 +130,46 @@ use tokio::{
 		UnboundedReceiver,
 		UnboundedSender,
 		channel,
+		error::TrySendError,
 		unbounded_channel,
 	},
+	time::Instant,
 };
 
 use crate::{
 	ChannelId,
 	ChannelOpenFailure,
+	ConnectionStats,
 	Disconnect,
+	GlobalRequestResponse,
 	Limits,
 	Sig,
+	WindowParams,
 	auth,
 	channels::{Channel, ChannelMsg},
 	cipher::{self, CipherPair, OpeningKey, clear},
-	key::PubKey,
+	mac,
 	msg,
 	negotiation,
-	session::{CommonSession, EncryptedState, Exchange, Kex, KexDhDone, KexInit, NewKeys},
+	session::{
+		Backlogs,
+		CommonSession,
+		EncryptedState,
+		Exchange,
+		Kex,
+		KexDhDone,
+		KexInit,
+		NewKeys,
+	},
 	ssh_read::SshRead,
 	sshbuffer::{SSHBuffer, SshId},
+	stats::Stats,
 };
 
+pub mod authenticator;
+pub mod config;
 mod encrypted;
+pub mod forward;
 mod kex;
 mod session;
 
@@ -159,6 +188,35 @@ pub struct Session {
 	pending_len:u32,
 	inbound_channel_sender:Sender<Msg>,
 	inbound_channel_receiver:Receiver<Msg>,
+	server_sig_algs:Option<Vec<key::Name>>,
+	/// The global requests sent with `want_reply`, in order.
+	pending_global_requests:VecDeque<GlobalRequest>,
+	/// Whether agent forwarding was requested, without which agent
+	/// channels opened by the server are refused.
+	agent_forward_requested:bool,
+	/// The keepalives sent since the last reply to one.
+	keepalives_unanswered:u32,
+}
+
+/// A global request waiting for its reply.
+#[derive(Debug)]
+pub(crate) enum GlobalRequest {
+	/// A request whose reply is ignored.
+	Other,
+	/// `hostkeys-prove-00@openssh.com`, for these announced host keys
+	/// (with their blobs).
+	HostKeysProve(Vec<(PublicKey, Vec<u8>)>),
+	/// `tcpip-forward` on `port`, zero to let the server choose, replying
+	/// with the port the server listens on.
+	TcpIpForward { port:u32, reply:tokio::sync::oneshot::Sender<Option<u32>> },
+	/// A request replying whether the server accepted it.
+	Accepted(tokio::sync::oneshot::Sender<bool>),
+	/// `keepalive@openssh.com`, for which any reply shows that the server
+	/// is alive.
+	Keepalive,
+	/// A request of [`Handle::global_request`], replying with the data of
+	/// its success, or `None` on failure.
+	Reply(tokio::sync::oneshot::Sender<Option<Vec<u8>>>),
 }
 
 impl Drop for Session {
@@ -167,9 +225,10 @@ impl Drop for Session {
 
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
-enum Reply {
+pub(crate) enum Reply {
 	AuthSuccess,
-	AuthFailure,
+	AuthFailure { remaining_methods:auth::MethodSet, partial_success:bool },
+	PasswordChangeRequest { prompt:String, language_tag:String },
 	ChannelOpenFailure,
 	SignRequest { key:key::PublicKey, data:CryptoVec },
 	AuthInfoRequest { name:String, instructions:String, prompts:Vec<Prompt> },
@@ -188,6 +247,7 @@ pub enum Msg {
 		data:CryptoVec,
 	},
 	ChannelOpenSession {
+		params:Option<WindowParams>,
 		sender:UnboundedSender<ChannelMsg>,
 	},
 	ChannelOpenX11 {
@@ -207,23 +267,66 @@ pub enum Msg {
 		sender:UnboundedSender<ChannelMsg>,
 	},
 	TcpIpForward {
-		want_reply:bool,
 		address:String,
 		port:u32,
+		reply:tokio::sync::oneshot::Sender<Option<u32>>,
 	},
 	CancelTcpIpForward {
-		want_reply:bool,
 		address:String,
 		port:u32,
+		reply:tokio::sync::oneshot::Sender<bool>,
+	},
+	StreamLocalForward {
+		socket_path:String,
+		reply:tokio::sync::oneshot::Sender<bool>,
+	},
+	CancelStreamLocalForward {
+		socket_path:String,
+		reply:tokio::sync::oneshot::Sender<bool>,
+	},
+	Eof {
+		id:ChannelId,
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
 	},
 	Close {
 		id:ChannelId,
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
 	},
 	Disconnect {
 		reason:Disconnect,
 		description:String,
 		language_tag:String,
 	},
+	GlobalRequest {
+		name:String,
+		payload:Vec<u8>,
+		reply:Option<tokio::sync::oneshot::Sender<Option<Vec<u8>>>>,
+	},
+	Rekey {
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
+	},
+	NegotiatedAlgorithms {
+		reply:tokio::sync::oneshot::Sender<Option<crate::Negotiated>>,
+	},
+	ExchangeHash {
+		reply:tokio::sync::oneshot::Sender<Option<CryptoVec>>,
+	},
+	SwitchToNoneCipher {
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
+	},
+	Debug {
+		always_display:bool,
+		message:String,
+	},
+	Ignore {
+		payload_len:usize,
+	},
+	Data {
+		id:ChannelId,
+		ext:Option<u32>,
+		data:CryptoVec,
+		sent:Option<tokio::sync::oneshot::Sender<()>>,
+	},
 	Channel(ChannelId, ChannelMsg),
 }
 
@@ -234,7 +337,7 @@ impl From<(ChannelId, ChannelMsg)> for Msg {
 #[derive(Debug)]
 pub enum KeyboardInteractiveAuthResponse {
 	Success,
-	Failure,
+	Failure { remaining_methods:auth::MethodSet, partial_success:bool },
 	InfoRequest { name:String, instructions:String, prompts:Vec<Prompt> },
 }
 
@@ -244,12 +347,156 @@ pub struct Prompt {
 	pub echo:bool,
 }
 
+/// The outcome of an authentication attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+	/// The user is authenticated.
+	Success,
+	/// The attempt was rejected. The server may still accept the methods
+	/// in `remaining_methods`. When `partial_success` is set, the attempt
+	/// itself succeeded, but the server requires more methods.
+	Failure { remaining_methods:auth::MethodSet, partial_success:bool },
+	/// The password has expired, and must be changed with
+	/// [`Handle::authenticate_password_change`]. `prompt` is the
+	/// server's message to the user, in the language of `language_tag`.
+	PasswordChangeRequired { prompt:String, language_tag:String },
+}
+
+impl AuthResult {
+	pub fn success(&self) -> bool { matches!(self, AuthResult::Success) }
+
+	/// The methods the server still accepts, none after a success.
+	pub fn remaining_methods(&self) -> auth::MethodSet {
+		match *self {
+			AuthResult::Success => auth::MethodSet::empty(),
+			AuthResult::Failure { remaining_methods, .. } => remaining_methods,
+			AuthResult::PasswordChangeRequired { .. } => auth::MethodSet::PASSWORD,
+		}
+	}
+
+	/// The result of an attempt cut short by the end of the session.
+	fn disconnected() -> Self {
+		AuthResult::Failure { remaining_methods:auth::MethodSet::empty(), partial_success:false }
+	}
+}
+
+/// Answers the prompts of keyboard-interactive authentication, see
+/// [`Handle::authenticate_keyboard_interactive`].
+///
+/// Closures taking the same arguments implement this trait.
+#[async_trait]
+pub trait KeyboardInteractiveHandler: Send {
+	/// Answer one round of prompts, with one response per prompt. The
+	/// server may send rounds without any prompt, only to display `name`
+	/// and `instructions`.
+	async fn respond(&mut self, name:&str, instructions:&str, prompts:&[Prompt]) -> Vec<String>;
+}
+
+#[async_trait]
+impl<F> KeyboardInteractiveHandler for F
+where
+	F: FnMut(&str, &str, &[Prompt]) -> Vec<String> + Send,
+{
+	async fn respond(&mut self, name:&str, instructions:&str, prompts:&[Prompt]) -> Vec<String> {
+		self(name, instructions, prompts)
+	}
+}
+
+/// The size of the buffers of [`CommandStream`].
+const COMMAND_BUFFER_SIZE:usize = 65536;
+
+/// The output of a command, see [`Handle::exec_collect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+	pub stdout:Vec<u8>,
+	pub stderr:Vec<u8>,
+	/// The exit status, if the server sent one.
+	pub exit_status:Option<u32>,
+	/// The signal that killed the command, if the server sent one.
+	pub exit_signal:Option<Sig>,
+}
+
+/// A running command, see [`Handle::exec_stream`].
+pub struct CommandStream {
+	pub stdout:DuplexStream,
+	pub stderr:DuplexStream,
+	exit:tokio::task::JoinHandle<Result<(Option<u32>, Option<Sig>), crate::Error>>,
+}
+
+impl CommandStream {
+	/// Wait until the command exits, returning its exit status and the
+	/// signal that killed it, if the server sent them.
+	pub async fn wait(self) -> Result<(Option<u32>, Option<Sig>), crate::Error> { self.exit.await? }
+}
+
+/// Wait for confirmation that a channel is open
+async fn wait_channel_confirmation(
+	sender:&Sender<Msg>,
+	mut receiver:UnboundedReceiver<ChannelMsg>,
+) -> Result<Channel<Msg>, crate::Error> {
+	loop {
+		match receiver.recv().await {
+			Some(ChannelMsg::Open { id, max_packet_size, window_size }) => {
+				return Ok(Channel {
+					id,
+					sender:sender.clone(),
+					receiver,
+					max_packet_size,
+					window_size,
+					close_info:Default::default(),
+					closed:false,
+				});
+			},
+			Some(ChannelMsg::OpenFailure(reason)) => {
+				return Err(crate::Error::ChannelOpenFailure(reason));
+			},
+			Some(ChannelMsg::Disconnected { reason, description }) => {
+				return Err(crate::Error::Disconnected { reason, description });
+			},
+			None => {
+				return Err(crate::Error::Disconnect);
+			},
+			msg => {
+				debug!("msg = {:?}", msg);
+			},
+		}
+	}
+}
+
+/// Open a direct TCP/IP channel on the session of `session_sender`, see
+/// [`Handle::channel_open_direct_tcpip`].
+async fn channel_open_direct_tcpip(
+	session_sender:&Sender<Msg>,
+	host_to_connect:String,
+	port_to_connect:u32,
+	originator_address:String,
+	originator_port:u32,
+) -> Result<Channel<Msg>, crate::Error> {
+	let (sender, receiver) = unbounded_channel();
+
+	session_sender
+		.send(Msg::ChannelOpenDirectTcpIp {
+			host_to_connect,
+			port_to_connect,
+			originator_address,
+			originator_port,
+			sender,
+		})
+		.await
+		.map_err(|_| crate::Error::SendError)?;
+
+	wait_channel_confirmation(session_sender, receiver).await
+}
+
 /// Handle to a session, used to send messages to a client outside of
 /// the request/response cycle.
 pub struct Handle<H:Handler> {
 	sender:Sender<Msg>,
 	receiver:UnboundedReceiver<Reply>,
 	join:tokio::task::JoinHandle<Result<(), H::Error>>,
+	session_id:CryptoVec,
+	backlogs:Arc<Backlogs>,
+	stats:Arc<Stats>,
 }
 
 impl<H:Handler> Drop for Handle<H> {
@@ -261,10 +508,14 @@ impl<H:Handler> Handle<H> {
 
 	/// Perform no authentication. This is useful for testing, but should not be
 	/// used in most other circumstances.
+	///
+	/// Unless the server lets the user in without authentication, this
+	/// is also how clients learn which methods the server accepts, from
+	/// the `remaining_methods` of the [`AuthResult::Failure`].
 	pub async fn authenticate_none<U:Into<String>>(
 		&mut self,
 		user:U,
-	) -> Result<bool, crate::Error> {
+	) -> Result<AuthResult, crate::Error> {
 		let user = user.into();
 
 		self.sender
@@ -280,7 +531,7 @@ impl<H:Handler> Handle<H> {
 		&mut self,
 		user:U,
 		password:P,
-	) -> Result<bool, crate::Error> {
+	) -> Result<AuthResult, crate::Error> {
 		let user = user.into();
 
 		self.sender
@@ -294,6 +545,36 @@ impl<H:Handler> Handle<H> {
 		self.wait_recv_reply().await
 	}
 
+	/// Change an expired password, after
+	/// [`AuthResult::PasswordChangeRequired`], and authenticate with the
+	/// new one. The server may ask for another change, for instance if
+	/// `new_password` is too weak.
+	pub async fn authenticate_password_change<
+		U:Into<String>,
+		P:Into<String>,
+		N:Into<String>,
+	>(
+		&mut self,
+		user:U,
+		old_password:P,
+		new_password:N,
+	) -> Result<AuthResult, crate::Error> {
+		let user = user.into();
+
+		self.sender
+			.send(Msg::Authenticate {
+				user,
+				method:auth::Method::PasswordChange {
+					password:old_password.into(),
+					new_password:new_password.into(),
+				},
+			})
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		self.wait_recv_reply().await
+	}
+
 	/// Initiate Keyboard-Interactive based SSH authentication.
 	///
 	/// * `submethods` - Hnts to the server the preferred methods to be used for
@@ -337,6 +618,40 @@ impl<H:Handler> Handle<H> {
 		self.wait_recv_keyboard_interactive_reply().await
 	}
 
+	/// Perform Keyboard-Interactive based SSH authentication, answering
+	/// every round of prompts sent by the server with `handler`, until the
+	/// server accepts or rejects the attempt.
+	///
+	/// Missing responses are sent as empty strings, and extra ones are
+	/// dropped.
+	pub async fn authenticate_keyboard_interactive<
+		U:Into<String>,
+		K:KeyboardInteractiveHandler + ?Sized,
+	>(
+		&mut self,
+		user:U,
+		handler:&mut K,
+	) -> Result<AuthResult, crate::Error> {
+		let mut response =
+			self.authenticate_keyboard_interactive_start(user, None::<String>).await?;
+
+		loop {
+			match response {
+				KeyboardInteractiveAuthResponse::Success => return Ok(AuthResult::Success),
+				KeyboardInteractiveAuthResponse::Failure { remaining_methods, partial_success } => {
+					return Ok(AuthResult::Failure { remaining_methods, partial_success });
+				},
+				KeyboardInteractiveAuthResponse::InfoRequest { name, instructions, prompts } => {
+					let mut responses = handler.respond(&name, &instructions, &prompts).await;
+
+					responses.resize(prompts.len(), String::new());
+
+					response = self.authenticate_keyboard_interactive_respond(responses).await?;
+				},
+			}
+		}
+	}
+
 	async fn wait_recv_keyboard_interactive_reply(
 		&mut self,
 	) -> Result<KeyboardInteractiveAuthResponse, crate::Error> {
@@ -345,8 +660,11 @@ impl<H:Handler> Handle<H> {
 				Some(Reply::AuthSuccess) => {
 					return Ok(KeyboardInteractiveAuthResponse::Success);
 				},
-				Some(Reply::AuthFailure) => {
-					return Ok(KeyboardInteractiveAuthResponse::Failure);
+				Some(Reply::AuthFailure { remaining_methods, partial_success }) => {
+					return Ok(KeyboardInteractiveAuthResponse::Failure {
+						remaining_methods,
+						partial_success,
+					});
 				},
 				Some(Reply::AuthInfoRequest { name, instructions, prompts }) => {
 					return Ok(KeyboardInteractiveAuthResponse::InfoRequest {
@@ -355,17 +673,28 @@ impl<H:Handler> Handle<H> {
 						prompts,
 					});
 				},
+				None => {
+					return Ok(KeyboardInteractiveAuthResponse::Failure {
+						remaining_methods:auth::MethodSet::empty(),
+						partial_success:false,
+					});
+				},
 				_ => {},
 			}
 		}
 	}
 
-	async fn wait_recv_reply(&mut self) -> Result<bool, crate::Error> {
+	async fn wait_recv_reply(&mut self) -> Result<AuthResult, crate::Error> {
 		loop {
 			match self.receiver.recv().await {
-				Some(Reply::AuthSuccess) => return Ok(true),
-				Some(Reply::AuthFailure) => return Ok(false),
-				None => return Ok(false),
+				Some(Reply::AuthSuccess) => return Ok(AuthResult::Success),
+				Some(Reply::AuthFailure { remaining_methods, partial_success }) => {
+					return Ok(AuthResult::Failure { remaining_methods, partial_success });
+				},
+				Some(Reply::PasswordChangeRequest { prompt, language_tag }) => {
+					return Ok(AuthResult::PasswordChangeRequired { prompt, language_tag });
+				},
+				None => return Ok(AuthResult::disconnected()),
 				_ => {},
 			}
 		}
@@ -376,7 +705,7 @@ impl<H:Handler> Handle<H> {
 		&mut self,
 		user:U,
 		key:Arc<key::KeyPair>,
-	) -> Result<bool, crate::Error> {
+	) -> Result<AuthResult, crate::Error> {
 		let user = user.into();
 
 		self.sender
@@ -387,80 +716,129 @@ impl<H:Handler> Handle<H> {
 		self.wait_recv_reply().await
 	}
 
-	/// Authenticate using a custom method that implements the
-	/// [`Signer`][auth::Signer] trait. Currently, this crate only provides an
-	/// implementation for an [SSH
-	/// agent][russh_keys::agent::client::AgentClient].
-	pub async fn authenticate_future<U:Into<String>, S:auth::Signer>(
+	/// Perform host-based SSH authentication, as user `local_user` on
+	/// the client host `host_name`, with `key`, the host key of the
+	/// client host. Like OpenSSH, servers usually expect `host_name` to be
+	/// fully qualified, with a trailing dot.
+	pub async fn authenticate_hostbased<U:Into<String>, N:Into<String>, L:Into<String>>(
 		&mut self,
 		user:U,
-		key:key::PublicKey,
-		mut future:S,
-	) -> (S, Result<bool, S::Error>) {
+		key:Arc<key::KeyPair>,
+		host_name:N,
+		local_user:L,
+	) -> Result<AuthResult, crate::Error> {
 		let user = user.into();
 
-		if self
-			.sender
-			.send(Msg::Authenticate { user, method:auth::Method::FuturePublicKey { key } })
+		let method = auth::Method::Hostbased {
+			key,
+			host_name:host_name.into(),
+			local_user:local_user.into(),
+		};
+
+		self.sender
+			.send(Msg::Authenticate { user, method })
 			.await
-			.is_err()
-		{
-			return (future, Err((crate::SendError {}).into()));
-		}
+			.map_err(|_| crate::Error::SendError)?;
 
-		loop {
-			let reply = self.receiver.recv().await;
+		self.wait_recv_reply().await
+	}
+
+	/// Perform public key-based SSH authentication with an OpenSSH
+	/// certificate of `key`, signed by a CA the server trusts.
+	pub async fn authenticate_publickey_with_cert<U:Into<String>>(
+		&mut self,
+		user:U,
+		key:Arc<key::KeyPair>,
+		mut cert:Certificate,
+	) -> Result<AuthResult, crate::Error> {
+		let user = user.into();
+
+		// The certificate algorithm follows the signature hash of the key.
+		cert.key.set_algorithm(key.name().as_bytes());
 
-			match reply {
-				Some(Reply::AuthSuccess) => return (future, Ok(true)),
-				Some(Reply::AuthFailure) => return (future, Ok(false)),
-				Some(Reply::SignRequest { key, data }) => {
-					let (f, data) = future.auth_publickey_sign(&key, data).await;
+		self.sender
+			.send(Msg::Authenticate { user, method:auth::Method::OpenSshCertificate { key, cert } })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
 
-					future = f;
+		self.wait_recv_reply().await
+	}
 
-					let data = match data {
-						Ok(data) => data,
-						Err(e) => return (future, Err(e)),
-					};
+	/// Perform public key-based SSH authentication with a key held
+	/// elsewhere, such as in an HSM or a cloud KMS, which `signer` asks
+	/// for a signature.
+	///
+	/// A failure of the signer is returned as [`crate::Error::Signer`],
+	/// and leaves the connection usable for other authentication attempts.
+	pub async fn authenticate_publickey_with<U:Into<String>, S:auth::Signer + ?Sized>(
+		&mut self,
+		user:U,
+		signer:&S,
+	) -> Result<AuthResult, crate::Error> {
+		let user = user.into();
+
+		let method = auth::Method::FuturePublicKey {
+			key:signer.public_key(),
+			algorithms:signer.algorithms(),
+		};
+
+		self.sender
+			.send(Msg::Authenticate { user, method })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		loop {
+			match self.receiver.recv().await {
+				Some(Reply::AuthSuccess) => return Ok(AuthResult::Success),
+				Some(Reply::AuthFailure { remaining_methods, partial_success }) => {
+					return Ok(AuthResult::Failure { remaining_methods, partial_success });
+				},
+				Some(Reply::SignRequest { key, mut data }) => {
+					let signed = signer.sign(key::Name(key.name()), &data).await;
+
+					// On failure, the unchanged buffer tells the session not
+					// to send anything.
+					if let Ok(ref sig) = signed {
+						data.extend_ssh_string(&sig.to_bytes());
+					}
+
+					self.sender
+						.send(Msg::Signed { data })
+						.await
+						.map_err(|_| crate::Error::SendError)?;
 
-					if self.sender.send(Msg::Signed { data }).await.is_err() {
-						return (future, Err((crate::SendError {}).into()));
+					if let Err(e) = signed {
+						return Err(crate::Error::Signer(e));
 					}
 				},
-				None => return (future, Ok(false)),
+				None => return Ok(AuthResult::disconnected()),
 				_ => {},
 			}
 		}
 	}
 
+	/// Authenticate with a key held by an SSH agent. This is
+	/// [`Handle::authenticate_publickey_with`] with an
+	/// [`AgentSigner`][auth::AgentSigner], which gives the agent back.
+	pub async fn authenticate_future<U:Into<String>, R:AsyncRead + AsyncWrite + Unpin + Send>(
+		&mut self,
+		user:U,
+		key:key::PublicKey,
+		agent:AgentClient<R>,
+	) -> (AgentClient<R>, Result<AuthResult, crate::Error>) {
+		let signer = auth::AgentSigner::new(agent, key);
+
+		let result = self.authenticate_publickey_with(user, &signer).await;
+
+		(signer.into_inner(), result)
+	}
+
 	/// Wait for confirmation that a channel is open
 	async fn wait_channel_confirmation(
 		&self,
-		mut receiver:UnboundedReceiver<ChannelMsg>,
+		receiver:UnboundedReceiver<ChannelMsg>,
 	) -> Result<Channel<Msg>, crate::Error> {
-		loop {
-			match receiver.recv().await {
-				Some(ChannelMsg::Open { id, max_packet_size, window_size }) => {
-					return Ok(Channel {
-						id,
-						sender:self.sender.clone(),
-						receiver,
-						max_packet_size,
-						window_size,
-					});
-				},
-				Some(ChannelMsg::OpenFailure(reason)) => {
-					return Err(crate::Error::ChannelOpenFailure(reason));
-				},
-				None => {
-					return Err(crate::Error::Disconnect);
-				},
-				msg => {
-					debug!("msg = {:?}", msg);
-				},
-			}
-		}
+		wait_channel_confirmation(&self.sender, receiver).await
 	}
 
 	/// Request a session channel (the most basic type of
@@ -472,13 +850,104 @@ impl<H:Handler> Handle<H> {
 		let (sender, receiver) = unbounded_channel();
 
 		self.sender
-			.send(Msg::ChannelOpenSession { sender })
+			.send(Msg::ChannelOpenSession { params:None, sender })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		self.wait_channel_confirmation(receiver).await
+	}
+
+	/// Request a session channel, like [`Handle::channel_open_session`],
+	/// with its own window parameters instead of those of the
+	/// configuration: for instance a larger window for a bulk transfer
+	/// over a high-latency link.
+	pub async fn channel_open_session_with(
+		&self,
+		params:WindowParams,
+	) -> Result<Channel<Msg>, crate::Error> {
+		params.check(cipher::MAXIMUM_PACKET_LEN)?;
+
+		let (sender, receiver) = unbounded_channel();
+
+		self.sender
+			.send(Msg::ChannelOpenSession { params:Some(params), sender })
 			.await
 			.map_err(|_| crate::Error::SendError)?;
 
 		self.wait_channel_confirmation(receiver).await
 	}
 
+	/// Run `command` on a new session channel, and wait until it exits,
+	/// like [`std::process::Command::output`].
+	pub async fn exec_collect<A:Into<Vec<u8>>>(
+		&self,
+		command:A,
+	) -> Result<CommandOutput, crate::Error> {
+		let mut command = self.exec_stream(command).await?;
+
+		let mut stdout = Vec::new();
+
+		let mut stderr = Vec::new();
+
+		let (out, err) = tokio::join!(
+			command.stdout.read_to_end(&mut stdout),
+			command.stderr.read_to_end(&mut stderr)
+		);
+
+		out?;
+
+		err?;
+
+		let (exit_status, exit_signal) = command.wait().await?;
+
+		Ok(CommandOutput { stdout, stderr, exit_status, exit_signal })
+	}
+
+	/// Run `command` on a new session channel, streaming its output. The
+	/// output is not read further while [`CommandStream::stdout`] or
+	/// [`CommandStream::stderr`] is full, so both must be read or
+	/// dropped.
+	pub async fn exec_stream<A:Into<Vec<u8>>>(
+		&self,
+		command:A,
+	) -> Result<CommandStream, crate::Error> {
+		let mut channel = self.channel_open_session().await?;
+
+		channel.exec(true, command).await?;
+
+		let (stdout, mut stdout_writer) = tokio::io::duplex(COMMAND_BUFFER_SIZE);
+
+		let (stderr, mut stderr_writer) = tokio::io::duplex(COMMAND_BUFFER_SIZE);
+
+		let exit = tokio::spawn(async move {
+			let mut exit_status = None;
+
+			let mut exit_signal = None;
+
+			// The server closes the channel once the command has exited,
+			// sometimes without an exit status.
+			while let Some(msg) = channel.wait().await {
+				match msg {
+					// Errors mean that the output is not read.
+					ChannelMsg::Data { data } => {
+						stdout_writer.write_all(&data).await.unwrap_or(());
+					},
+					ChannelMsg::ExtendedData { data, ext:1 } => {
+						stderr_writer.write_all(&data).await.unwrap_or(());
+					},
+					ChannelMsg::ExitStatus { exit_status:status } => exit_status = Some(status),
+					ChannelMsg::ExitSignal { signal_name, .. } => exit_signal = Some(signal_name),
+					ChannelMsg::Failure => return Err(crate::Error::ChannelRequestFailure),
+					_ => {},
+				}
+			}
+
+			Ok((exit_status, exit_signal))
+		});
+
+		Ok(CommandStream { stdout, stderr, exit })
+	}
+
 	/// Request an X11 channel, on which the X11 protocol may be tunneled.
 	pub async fn channel_open_x11<A:Into<String>>(
 		&self,
@@ -514,20 +983,14 @@ impl<H:Handler> Handle<H> {
 		originator_address:B,
 		originator_port:u32,
 	) -> Result<Channel<Msg>, crate::Error> {
-		let (sender, receiver) = unbounded_channel();
-
-		self.sender
-			.send(Msg::ChannelOpenDirectTcpIp {
-				host_to_connect:host_to_connect.into(),
-				port_to_connect,
-				originator_address:originator_address.into(),
-				originator_port,
-				sender,
-			})
-			.await
-			.map_err(|_| crate::Error::SendError)?;
-
-		self.wait_channel_confirmation(receiver).await
+		channel_open_direct_tcpip(
+			&self.sender,
+			host_to_connect.into(),
+			port_to_connect,
+			originator_address.into(),
+			originator_port,
+		)
+		.await
 	}
 
 	pub async fn channel_open_direct_streamlocal<S:Into<String>>(
@@ -544,37 +1007,138 @@ impl<H:Handler> Handle<H> {
 		self.wait_channel_confirmation(receiver).await
 	}
 
+	/// Ask the server to listen on `address` and `port`, as OpenSSH's
+	/// `-R` option, and return the port it listens on, which it chooses if
+	/// `port` is 0. Each connection is a forwarded TCP/IP channel, see
+	/// [`Handler::server_channel_open_forwarded_tcpip`], whose connected
+	/// address and port tell which forwarding it belongs to.
+	///
+	/// Returns [`Error::GlobalRequestFailure`](crate::Error::GlobalRequestFailure)
+	/// if the server refuses.
 	pub async fn tcpip_forward<A:Into<String>>(
-		&mut self,
+		&self,
 		address:A,
 		port:u32,
-	) -> Result<bool, crate::Error> {
+	) -> Result<u32, crate::Error> {
+		let (reply, receiver) = tokio::sync::oneshot::channel();
+
 		self.sender
-			.send(Msg::TcpIpForward { want_reply:true, address:address.into(), port })
+			.send(Msg::TcpIpForward { address:address.into(), port, reply })
 			.await
 			.map_err(|_| crate::Error::SendError)?;
 
-		if port == 0 {
-			self.wait_recv_reply().await?;
-		}
-
-		Ok(true)
+		receiver.await.map_err(|_| crate::Error::Disconnect)?.ok_or(crate::Error::GlobalRequestFailure)
 	}
 
+	/// Stop a forwarding started with [`Handle::tcpip_forward`], with the
+	/// same address and the port it returned.
 	pub async fn cancel_tcpip_forward<A:Into<String>>(
 		&self,
 		address:A,
 		port:u32,
-	) -> Result<bool, crate::Error> {
+	) -> Result<(), crate::Error> {
+		let (reply, accepted) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::CancelTcpIpForward { address:address.into(), port, reply })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		if accepted.await.map_err(|_| crate::Error::Disconnect)? {
+			Ok(())
+		} else {
+			Err(crate::Error::GlobalRequestFailure)
+		}
+	}
+
+	/// Ask the server to listen on the Unix domain socket at `socket_path`,
+	/// as OpenSSH's `-R` option with a socket path. Each connection is a
+	/// channel, see [`Handler::server_channel_open_forwarded_streamlocal`].
+	///
+	/// Returns [`Error::GlobalRequestFailure`](crate::Error::GlobalRequestFailure)
+	/// if the server refuses.
+	pub async fn streamlocal_forward<A:Into<String>>(
+		&self,
+		socket_path:A,
+	) -> Result<(), crate::Error> {
+		let (reply, accepted) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::StreamLocalForward { socket_path:socket_path.into(), reply })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		if accepted.await.map_err(|_| crate::Error::Disconnect)? {
+			Ok(())
+		} else {
+			Err(crate::Error::GlobalRequestFailure)
+		}
+	}
+
+	/// Stop a forwarding started with [`Handle::streamlocal_forward`].
+	pub async fn cancel_streamlocal_forward<A:Into<String>>(
+		&self,
+		socket_path:A,
+	) -> Result<(), crate::Error> {
+		let (reply, accepted) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::CancelStreamLocalForward { socket_path:socket_path.into(), reply })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		if accepted.await.map_err(|_| crate::Error::Disconnect)? {
+			Ok(())
+		} else {
+			Err(crate::Error::GlobalRequestFailure)
+		}
+	}
+
+	/// Send the global request `name`, with its request-specific
+	/// `payload`. With `want_reply`, this waits for the reply of the
+	/// server, and returns the data of its success, or
+	/// [`Error::GlobalRequestFailure`](crate::Error::GlobalRequestFailure).
+	/// Replies are matched with requests in the order they were sent.
+	pub async fn global_request<N:Into<String>>(
+		&self,
+		name:N,
+		want_reply:bool,
+		payload:&[u8],
+	) -> Result<Option<Vec<u8>>, crate::Error> {
+		let (reply, replied) = tokio::sync::oneshot::channel();
+
 		self.sender
-			.send(Msg::CancelTcpIpForward { want_reply:true, address:address.into(), port })
+			.send(Msg::GlobalRequest {
+				name:name.into(),
+				payload:payload.to_vec(),
+				reply:if want_reply { Some(reply) } else { None },
+			})
 			.await
 			.map_err(|_| crate::Error::SendError)?;
 
-		Ok(true)
+		if !want_reply {
+			return Ok(None);
+		}
+
+		match replied.await.map_err(|_| crate::Error::Disconnect)? {
+			Some(data) => Ok(Some(data)),
+			None => Err(crate::Error::GlobalRequestFailure),
+		}
+	}
+
+	/// Tell the server that no more session channels will be opened, with
+	/// `no-more-sessions@openssh.com`, as OpenSSH does once its
+	/// forwardings are set up. Sessions already open keep working, and
+	/// the server refuses to open new ones.
+	pub async fn no_more_sessions(&self) -> Result<(), crate::Error> {
+		self.global_request("no-more-sessions@openssh.com", false, &[]).await?;
+
+		Ok(())
 	}
 
-	/// Sends a disconnect message.
+	/// Sends a disconnect message, and waits until the messages sent
+	/// before it are written and the connection is closed. Channels of
+	/// the session see their messages end.
 	pub async fn disconnect(
 		&self,
 		reason:Disconnect,
@@ -590,29 +1154,181 @@ impl<H:Handler> Handle<H> {
 			.await
 			.map_err(|_| crate::Error::SendError)?;
 
+		// The session drops its receiver once the stream is shut down.
+		self.sender.closed().await;
+
 		Ok(())
 	}
 
-	/// Send data to the session referenced by this handler.
-	///
-	/// This is useful for server-initiated channels; for channels created by
-	/// the client, prefer to use the Channel returned from the `open_*`
-	/// methods.
-	pub async fn data(&self, id:ChannelId, data:CryptoVec) -> Result<(), CryptoVec> {
+	/// Send a debug message to the server, which OpenSSH logs at the `DEBUG`
+	/// level, or shows to the user if `always_display`.
+	pub async fn send_debug(&self, always_display:bool, message:&str) -> Result<(), crate::Error> {
 		self.sender
-			.send(Msg::Channel(id, ChannelMsg::Data { data }))
+			.send(Msg::Debug { always_display, message:message.into() })
 			.await
-			.map_err(|e| {
-				match e.0 {
-					Msg::Channel(_, ChannelMsg::Data { data, .. }) => data,
-					_ => unreachable!(),
-				}
-			})
+			.map_err(|_| crate::Error::SendError)
 	}
-}
 
-impl<H:Handler> Future for Handle<H> {
-	type Output = Result<(), H::Error>;
+	/// Send `payload_len` random bytes the server discards, in ignore
+	/// messages, for instance to pad the traffic against its analysis.
+	pub async fn send_ignore(&self, payload_len:usize) -> Result<(), crate::Error> {
+		self.sender.send(Msg::Ignore { payload_len }).await.map_err(|_| crate::Error::SendError)
+	}
+
+	/// Re-exchange the session keys, and wait until the new keys are in
+	/// use. Channel data sent in the meantime is queued, and sent with the
+	/// new keys.
+	///
+	/// Returns [`Error::RekeyInProgress`](crate::Error::RekeyInProgress) if
+	/// a key exchange is already running, and
+	/// [`Error::Inconsistent`](crate::Error::Inconsistent) if none could be
+	/// started.
+	pub async fn rekey(&self) -> Result<(), crate::Error> {
+		let (reply, done) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Rekey { reply }).await.map_err(|_| crate::Error::SendError)?;
+
+		done.await.map_err(|_| crate::Error::Disconnect)?
+	}
+
+	/// Re-exchange the session keys proposing only the `none` cipher, so
+	/// that the rest of the session is neither encrypted nor
+	/// authenticated. This and all later key exchanges use `none`.
+	///
+	/// This requires [`Config::allow_none_cipher_after_auth`] and a
+	/// completed authentication. If the server refuses `none`, the key
+	/// exchange fails and the session is disconnected.
+	pub async fn switch_to_none_cipher(&self) -> Result<(), crate::Error> {
+		let (reply, done) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::SwitchToNoneCipher { reply })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		done.await.map_err(|_| crate::Error::Disconnect)?
+	}
+
+	/// Algorithms negotiated by the last key exchange, or `None` before
+	/// the first one has completed. This is updated after each rekey.
+	pub async fn negotiated_algorithms(&self) -> Result<Option<crate::Negotiated>, crate::Error> {
+		let (reply, negotiated) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::NegotiatedAlgorithms { reply })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		negotiated.await.map_err(|_| crate::Error::Disconnect)
+	}
+
+	/// The session identifier, i.e. the exchange hash of the initial key
+	/// exchange. It stays the same after a rekey (RFC 4253, section 7.2).
+	pub fn session_id(&self) -> &[u8] { &self.session_id }
+
+	/// The exchange hash of the last key exchange. Equal to
+	/// [`Handle::session_id`] until the first rekey.
+	pub async fn exchange_hash(&self) -> Result<CryptoVec, crate::Error> {
+		let (reply, hash) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::ExchangeHash { reply }).await.map_err(|_| crate::Error::SendError)?;
+
+		hash.await.map_err(|_| crate::Error::Disconnect)?.ok_or(crate::Error::Disconnect)
+	}
+
+	/// Send data to the session referenced by this handler.
+	///
+	/// This is useful for server-initiated channels; for channels created by
+	/// the client, prefer to use the Channel returned from the `open_*`
+	/// methods.
+	///
+	/// The returned future completes once the data is sent within the
+	/// server's window, but for [`Config::channel_buffer_size`] bytes left
+	/// queued. It fails with [`crate::Error::SendError`] if the session is
+	/// closed, and with [`crate::Error::WrongChannel`] if the channel is
+	/// unknown, or closed before the data is sent.
+	pub async fn data(&self, id:ChannelId, data:CryptoVec) -> Result<(), crate::Error> {
+		self.write(id, None, data).await
+	}
+
+	/// Send extended data to the session referenced by this handler,
+	/// waiting as [`Handle::data`].
+	pub async fn extended_data(
+		&self,
+		id:ChannelId,
+		ext:u32,
+		data:CryptoVec,
+	) -> Result<(), crate::Error> {
+		self.write(id, Some(ext), data).await
+	}
+
+	async fn write(
+		&self,
+		id:ChannelId,
+		ext:Option<u32>,
+		data:CryptoVec,
+	) -> Result<(), crate::Error> {
+		let (sent, sent_receiver) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::Data { id, ext, data, sent:Some(sent) })
+			.await
+			.map_err(|_| crate::Error::SendError)?;
+
+		// The session drops `sent` if the channel is closed.
+		sent_receiver.await.map_err(|_| crate::Error::WrongChannel)
+	}
+
+	/// Send EOF to the session referenced by this handler. Fails with
+	/// [`crate::Error::WrongChannel`] if the channel is unknown or closed.
+	pub async fn eof(&self, id:ChannelId) -> Result<(), crate::Error> {
+		let (reply, checked) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Eof { id, reply }).await.map_err(|_| crate::Error::SendError)?;
+
+		checked.await.map_err(|_| crate::Error::Disconnect)?
+	}
+
+	/// Close a channel. Fails with [`crate::Error::WrongChannel`] if the
+	/// channel is unknown or already closed.
+	pub async fn close(&self, id:ChannelId) -> Result<(), crate::Error> {
+		let (reply, checked) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Close { id, reply }).await.map_err(|_| crate::Error::SendError)?;
+
+		checked.await.map_err(|_| crate::Error::Disconnect)?
+	}
+
+	/// The traffic and event counters of this connection, cheap to read.
+	pub fn stats(&self) -> ConnectionStats { self.stats.snapshot() }
+
+	/// Send data to a channel without waiting. The data is returned if
+	/// the channel has more than [`Config::channel_buffer_size`] bytes
+	/// waiting for the server's window, or if the session is busy or
+	/// closed.
+	pub fn try_data(&self, id:ChannelId, data:CryptoVec) -> Result<(), CryptoVec> {
+		if !self.backlogs.has_room(id, data.len()) {
+			return Err(data);
+		}
+
+		self.sender.try_send(Msg::Data { id, ext:None, data, sent:None }).map_err(|e| {
+			match e {
+				TrySendError::Full(msg) | TrySendError::Closed(msg) => returned_data(msg),
+			}
+		})
+	}
+}
+
+/// The data of a [`Msg::Data`] the session did not take.
+fn returned_data(msg:Msg) -> CryptoVec {
+	match msg {
+		Msg::Data { data, .. } => data,
+		_ => CryptoVec::new(),
+	}
+}
+
+impl<H:Handler> Future for Handle<H> {
+	type Output = Result<(), H::Error>;
 
 	fn poll(mut self: Pin<&mut Self>, cx:&mut Context) -> Poll<Self::Output> {
 		match Future::poll(Pin::new(&mut self.join), cx) {
@@ -640,34 +1356,135 @@ pub async fn connect<H:Handler + Send + 'static, A:ToSocketAddrs>(
 	addrs:A,
 	handler:H,
 ) -> Result<Handle<H>, H::Error> {
-	let socket = TcpStream::connect(addrs).await.map_err(crate::Error::from)?;
+	let timeouts =
+		Timeouts { connection:config.connection_timeout, handshake:config.handshake_timeout };
+
+	connect_with_timeouts(config, addrs, handler, timeouts).await
+}
+
+/// The timeouts of [`connect_with_timeouts`], `None` waiting forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+	/// See [`Config::connection_timeout`].
+	pub connection:Option<std::time::Duration>,
+	/// See [`Config::handshake_timeout`].
+	pub handshake:Option<std::time::Duration>,
+}
+
+/// [`connect`], with `timeouts` instead of those of `config`.
+pub async fn connect_with_timeouts<H:Handler + Send + 'static, A:ToSocketAddrs>(
+	config:Arc<Config>,
+	addrs:A,
+	handler:H,
+	timeouts:Timeouts,
+) -> Result<Handle<H>, H::Error> {
+	let deadline = timeouts.connection.map(|t| Instant::now() + t);
+
+	let socket = match with_deadline(deadline, TcpStream::connect(addrs)).await {
+		Some(socket) => socket.map_err(crate::Error::from)?,
+		None => return Err(crate::Error::ConnectTimeout.into()),
+	};
 
-	connect_stream(config, socket, handler).await
+	let peer_addr = socket.peer_addr().ok();
+
+	handshake(config, socket, peer_addr, handler, timeouts.handshake).await
 }
 
 /// Connect a stream to a server. This stream must implement
 /// [`tokio::io::AsyncRead`] and [`tokio::io::AsyncWrite`], as well as [`Unpin`]
 /// and [`Send`]. Typically, you may prefer to use [`connect`], which uses a
 /// [`tokio::net::TcpStream`] and then calls this function under the hood.
+/// The session has no [`ConnectionInfo::peer_addr`](crate::ConnectionInfo::peer_addr).
 pub async fn connect_stream<H, R>(
+	config:Arc<Config>,
+	stream:R,
+	handler:H,
+) -> Result<Handle<H>, H::Error>
+where
+	H: Handler + Send + 'static,
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	let timeout = config.handshake_timeout;
+
+	handshake(config, stream, None, handler, timeout).await
+}
+
+/// Connect to `target_addr` through the server of `jump`, as OpenSSH's
+/// `ProxyJump` (see [`config::HostConfig::proxy_jump`]). The session runs
+/// over a direct TCP/IP channel from the server of `jump` to the target,
+/// with [`Channel::into_stream`], so `jump` may itself be connected this
+/// way to go through several hosts. Closing the session closes the
+/// channel, but not `jump`.
+pub async fn connect_via<J, H, A>(
+	jump:&Handle<J>,
+	target_addr:(A, u16),
+	config:Arc<Config>,
+	handler:H,
+) -> Result<Handle<H>, H::Error>
+where
+	J: Handler,
+	H: Handler + Send + 'static,
+	A: Into<String>, {
+	let (host, port) = target_addr;
+
+	// The originator of OpenSSH's `-W`, on which `-J` relies.
+	let channel = jump.channel_open_direct_tcpip(host, port.into(), "127.0.0.1", 65535).await?;
+
+	connect_stream(config, channel.into_stream(), handler).await
+}
+
+/// `future`, or `None` if `deadline` passes first.
+async fn with_deadline<F:Future>(deadline:Option<Instant>, future:F) -> Option<F::Output> {
+	match deadline {
+		Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+		None => Some(future.await),
+	}
+}
+
+/// Exchange versions and keys on `stream`, connected to `peer_addr`,
+/// within `timeout`, and start the session.
+async fn handshake<H, R>(
 	config:Arc<Config>,
 	mut stream:R,
+	peer_addr:Option<std::net::SocketAddr>,
 	handler:H,
+	timeout:Option<std::time::Duration>,
 ) -> Result<Handle<H>, H::Error>
 where
 	H: Handler + Send + 'static,
 	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	config.window_params().check(config.max_packet_size)?;
+
+	config.client_id.check()?;
+
+	let deadline = timeout.map(|t| Instant::now() + t);
+
 	// Writing SSH id.
 	let mut write_buffer = SSHBuffer::new();
 
+	write_buffer.random_padding = config.random_padding.clone();
+
 	write_buffer.send_ssh_id(&config.as_ref().client_id);
 
-	stream.write_all(&write_buffer.buffer).await.map_err(crate::Error::from)?;
+	// Reading SSH id and allocating a session if correct. The stream is
+	// dropped, and closed, on timeouts.
+	let sshid = with_deadline(deadline, async {
+		stream.write_all(&write_buffer.buffer).await.map_err(crate::Error::from)?;
+
+		let mut stream = SshRead::new(stream);
+
+		let sshid = stream
+			.read_ssh_id(config.max_preamble_lines, config.max_preamble_bytes)
+			.await?
+			.to_vec();
 
-	// Reading SSH id and allocating a session if correct.
-	let mut stream = SshRead::new(stream);
+		Ok::<_, crate::Error>((stream, sshid))
+	})
+	.await;
 
-	let sshid = stream.read_ssh_id().await?;
+	let (stream, sshid) = match sshid {
+		Some(result) => result?,
+		None => return Err(crate::Error::HandshakeTimeout.into()),
+	};
 
 	let (handle_sender, session_receiver) = channel(10);
 
@@ -680,6 +1497,13 @@ where
 		);
 	}
 
+	let backlogs = Backlogs::new(config.channel_buffer_size);
+
+	let stats = Arc::new(Stats::default());
+
+	let info =
+		crate::ConnectionInfo::new(peer_addr, config.client_id.as_kex_hash_bytes(), &sshid);
+
 	let mut session = Session::new(
 		config.window_size,
 		CommonSession {
@@ -697,34 +1521,57 @@ where
 			wants_reply:false,
 			disconnected:false,
 			buffer:CryptoVec::new(),
+			strict_kex:false,
+			backlogs:backlogs.clone(),
+			stats:stats.clone(),
+			info,
 		},
 		session_receiver,
 		session_sender,
 	);
 
-	session.read_ssh_id(sshid)?;
+	session.read_ssh_id(&sshid)?;
 
 	let (encrypted_signal, encrypted_recv) = tokio::sync::oneshot::channel();
 
 	let join = tokio::spawn(session.run(stream, handler, Some(encrypted_signal)));
 
-	if encrypted_recv.await.is_err() {
-		join.await.map_err(crate::Error::Join)??;
+	let session_id = match with_deadline(deadline, encrypted_recv).await {
+		Some(Ok(session_id)) => session_id,
+		Some(Err(_)) => {
+			join.await.map_err(crate::Error::Join)??;
 
-		return Err(H::Error::from(crate::Error::Disconnect));
-	}
+			return Err(H::Error::from(crate::Error::Disconnect));
+		},
+		None => {
+			// Closes the stream, owned by the session.
+			join.abort();
+
+			join.await.ok();
 
-	Ok(Handle { sender:handle_sender, receiver:handle_receiver, join })
+			return Err(crate::Error::HandshakeTimeout.into());
+		},
+	};
+
+	Ok(Handle {
+		sender:handle_sender,
+		receiver:handle_receiver,
+		join,
+		session_id,
+		backlogs,
+		stats,
+	})
 }
 
 async fn start_reading<R:AsyncRead + Unpin>(
 	mut stream_read:R,
 	mut buffer:SSHBuffer,
 	mut cipher:Box<dyn OpeningKey + Send>,
+	max_packet_size:usize,
 ) -> Result<(usize, R, SSHBuffer, Box<dyn OpeningKey + Send>), crate::Error> {
 	buffer.buffer.clear();
 
-	let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher).await?;
+	let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher, max_packet_size).await?;
 
 	Ok((n, stream_read, buffer, cipher))
 }
@@ -748,6 +1595,10 @@ impl Session {
 			channels:HashMap::new(),
 			pending_reads:Vec::new(),
 			pending_len:0,
+			server_sig_algs:None,
+			pending_global_requests:VecDeque::new(),
+			agent_forward_requested:false,
+			keepalives_unanswered:0,
 		}
 	}
 
@@ -755,7 +1606,7 @@ impl Session {
 		mut self,
 		mut stream:SshRead<R>,
 		mut handler:H,
-		mut encrypted_signal:Option<tokio::sync::oneshot::Sender<()>>,
+		mut encrypted_signal:Option<tokio::sync::oneshot::Sender<CryptoVec>>,
 	) -> Result<(), H::Error> {
 		self.flush()?;
 
@@ -770,6 +1621,8 @@ impl Session {
 			stream.flush().await.map_err(crate::Error::from)?;
 		}
 
+		self.common.stats.sent(&self.common.write_buffer);
+
 		self.common.write_buffer.buffer.clear();
 
 		let mut decomp = CryptoVec::new();
@@ -783,17 +1636,36 @@ impl Session {
 
 		std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 
-		let reading = start_reading(stream_read, buffer, opening_cipher);
+		let max_packet_size = self.common.config.max_packet_size;
+
+		let reading = start_reading(stream_read, buffer, opening_cipher, max_packet_size);
 
 		pin!(reading);
 
+		let keepalive_interval = self.common.config.keepalive_interval;
+
+		let mut keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
+
+		// The error ending the session if the server disconnects.
+		let mut peer_disconnected = None;
+
 		#[allow(clippy::panic)] // false positive in select! macro
 		while !self.common.disconnected {
+			let time_until_rekey = self.time_until_rekey();
+
+			let keepalive_at = keepalive_deadline.unwrap_or_else(Instant::now);
+
 			tokio::select! {
 				r = &mut reading => {
-					let (stream_read, buffer, mut opening_cipher) = match r {
+					keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
+
+					let (stream_read, mut buffer, mut opening_cipher) = match r {
 						Ok((_, stream_read, buffer, opening_cipher)) => (stream_read, buffer, opening_cipher),
-						Err(e) => return Err(e.into())
+						Err(e) => {
+							handler.on_close(Some(&e));
+
+							return Err(e.into())
+						}
 					};
 
 					std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
@@ -807,6 +1679,7 @@ impl Session {
 						if let Ok(buf) = enc.decompress.decompress(
 							&buffer.buffer[5..],
 							&mut decomp,
+							max_packet_size,
 						) {
 							buf
 						} else {
@@ -817,12 +1690,18 @@ impl Session {
 						&buffer.buffer[5..]
 					};
 
+					self.common.stats.received(&buffer, buf.len());
+
 					if !buf.is_empty() {
 						#[allow(clippy::indexing_slicing)] // length checked
 						if buf[0] == crate::msg::DISCONNECT {
+							peer_disconnected = Some(self.peer_disconnected(buf));
+
 							break;
-						} else if buf[0] > 4 {
-							let (h, s) = reply(self, handler, &mut encrypted_signal, buf).await?;
+						} else {
+							let (h, s) =
+								reply(self, handler, &mut encrypted_signal, &mut buffer.seqn, buf)
+									.await?;
 
 							handler = h;
 
@@ -830,9 +1709,26 @@ impl Session {
 						}
 					}
 
+					if self.common.config.limits.read_limit_reached(&buffer) {
+						info!("Re-exchanging keys");
+
+						self.initiate_rekey()?;
+
+						buffer.bytes = 0;
+					}
+
 					std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 
-					reading.set(start_reading(stream_read, buffer, opening_cipher));
+					reading.set(start_reading(stream_read, buffer, opening_cipher, max_packet_size));
+				}
+
+				// `flush` below starts the key exchange once the time limit is reached.
+				_ = tokio::time::sleep(time_until_rekey.unwrap_or_default()), if time_until_rekey.is_some() => {}
+
+				_ = tokio::time::sleep_until(keepalive_at), if keepalive_deadline.is_some() => {
+					self.send_keepalive()?;
+
+					keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
 				}
 
 				msg = self.receiver.recv(), if !self.is_rekeying() => {
@@ -845,8 +1741,9 @@ impl Session {
 						}
 					};
 
-					// eagerly take all outgoing messages so writes are batched
-					while !self.is_rekeying() {
+					// eagerly take all outgoing messages so writes are batched,
+					// and nothing is written after a disconnect message
+					while !self.is_rekeying() && !self.common.disconnected {
 						match self.receiver.try_recv() {
 							Ok(next) => self.handle_msg(next)?,
 							Err(_) => break
@@ -860,8 +1757,9 @@ impl Session {
 						None => (),
 					}
 
-					// eagerly take all outgoing messages so writes are batched
-					while !self.is_rekeying() {
+					// eagerly take all outgoing messages so writes are batched,
+					// and nothing is written after a disconnect message
+					while !self.is_rekeying() && !self.common.disconnected {
 						match self.inbound_channel_receiver.try_recv() {
 							Ok(next) => self.handle_msg(next)?,
 							Err(_) => break
@@ -883,12 +1781,12 @@ impl Session {
 				stream_write.flush().await.map_err(crate::Error::from)?;
 			}
 
-			self.common.write_buffer.buffer.clear();
+			self.common.stats.sent(&self.common.write_buffer);
+
+			crate::pool::release(&mut self.common.write_buffer.buffer);
 
 			if let Some(ref mut enc) = self.common.encrypted {
 				if let EncryptedState::InitCompression = enc.state {
-					enc.client_compression.init_compress(&mut enc.compress);
-
 					enc.state = EncryptedState::Authenticated;
 				}
 			}
@@ -900,18 +1798,34 @@ impl Session {
 			stream_write.shutdown().await.map_err(crate::Error::from)?;
 		}
 
-		Ok(())
+		handler.on_close(peer_disconnected.as_ref());
+
+		match peer_disconnected {
+			Some(e) => Err(e.into()),
+			None => Ok(()),
+		}
 	}
 
 	fn handle_msg(&mut self, msg:Msg) -> Result<(), crate::Error> {
 		match msg {
 			Msg::Authenticate { user, method } => {
-				self.write_auth_request_if_needed(&user, method);
+				self.write_auth_request_if_needed(&user, method)?;
 			},
 			Msg::Signed { .. } => {},
 			Msg::AuthInfoResponse { .. } => {},
-			Msg::ChannelOpenSession { sender } => {
-				let id = self.channel_open_session()?;
+			Msg::Rekey { reply } => self.request_rekey(reply)?,
+			Msg::SwitchToNoneCipher { reply } => self.request_none_cipher(reply)?,
+			Msg::NegotiatedAlgorithms { reply } => {
+				reply.send(self.negotiated_algorithms().cloned()).unwrap_or(());
+			},
+			Msg::ExchangeHash { reply } => {
+				reply.send(self.exchange_hash().map(CryptoVec::from_slice)).unwrap_or(());
+			},
+			Msg::ChannelOpenSession { params, sender } => {
+				let id = match params {
+					Some(params) => self.channel_open_session_with(params)?,
+					None => self.channel_open_session()?,
+				};
 
 				self.channels.insert(id, sender);
 			},
@@ -941,21 +1855,62 @@ impl Session {
 
 				self.channels.insert(id, sender);
 			},
-			Msg::TcpIpForward { want_reply, address, port } => {
-				self.tcpip_forward(want_reply, &address, port)
+			Msg::TcpIpForward { address, port, reply } => {
+				let request = GlobalRequest::TcpIpForward { port, reply };
+
+				self.forward_request(b"tcpip-forward", &address, port, Some(request))
+			},
+			Msg::CancelTcpIpForward { address, port, reply } => {
+				let request = GlobalRequest::Accepted(reply);
+
+				self.forward_request(b"cancel-tcpip-forward", &address, port, Some(request))
+			},
+			Msg::StreamLocalForward { socket_path, reply } => {
+				let request = GlobalRequest::Accepted(reply);
+
+				self.streamlocal_forward_request(
+					b"streamlocal-forward@openssh.com",
+					&socket_path,
+					Some(request),
+				)
 			},
-			Msg::CancelTcpIpForward { want_reply, address, port } => {
-				self.cancel_tcpip_forward(want_reply, &address, port)
+			Msg::CancelStreamLocalForward { socket_path, reply } => {
+				let request = GlobalRequest::Accepted(reply);
+
+				self.streamlocal_forward_request(
+					b"cancel-streamlocal-forward@openssh.com",
+					&socket_path,
+					Some(request),
+				)
 			},
 			Msg::Disconnect { reason, description, language_tag } => {
 				self.disconnect(reason, &description, &language_tag)
 			},
-			Msg::Channel(id, ChannelMsg::Data { data }) => self.data(id, data),
+			Msg::Debug { always_display, message } => self.send_debug(always_display, &message),
+			Msg::Ignore { payload_len } => self.send_ignore(payload_len),
+			Msg::GlobalRequest { name, payload, reply } => {
+				let request = reply.map(GlobalRequest::Reply);
+
+				self.global_request(name.as_bytes(), request, |buf| buf.extend(&payload))
+			},
+			Msg::Data { id, ext, data, sent } => self.handle_data(id, ext, data, sent),
+			Msg::Eof { id, reply } => {
+				let eof = self.common.check_channel(id).map(|()| self.eof(id));
+
+				reply.send(eof).unwrap_or(());
+			},
+			Msg::Close { id, reply } => {
+				let close = self.common.check_channel(id).map(|()| self.close(id));
+
+				reply.send(close).unwrap_or(());
+			},
+			// Channels drop their data once closed.
+			Msg::Channel(id, ChannelMsg::Data { data }) => self.data(id, data).unwrap_or(()),
 			Msg::Channel(id, ChannelMsg::Eof) => {
 				self.eof(id);
 			},
 			Msg::Channel(id, ChannelMsg::ExtendedData { data, ext }) => {
-				self.extended_data(id, ext, data);
+				self.extended_data(id, ext, data).unwrap_or(());
 			},
 			Msg::Channel(
 				id,
@@ -1013,6 +1968,9 @@ impl Session {
 				self.exec(id, want_reply, &command)
 			},
 			Msg::Channel(id, ChannelMsg::Signal { signal }) => self.signal(id, signal),
+			Msg::Channel(id, ChannelMsg::Break { want_reply, break_length }) => {
+				self.send_break(id, want_reply, break_length)
+			},
 			Msg::Channel(id, ChannelMsg::RequestSubsystem { want_reply, name }) => {
 				self.request_subsystem(want_reply, id, &name)
 			},
@@ -1047,7 +2005,7 @@ impl Session {
 		// Preparing the response
 		exchange.client_id.extend(self.common.config.client_id.as_kex_hash_bytes());
 
-		let mut kexinit = KexInit { exchange, algo:None, sent:false, session_id:None };
+		let mut kexinit = KexInit::new(exchange);
 
 		self.common.write_buffer.buffer.clear();
 
@@ -1065,26 +2023,39 @@ impl Session {
 	/// Flush the temporary cleartext buffer into the encryption
 	/// buffer. This does *not* flush to the socket.
 	fn flush(&mut self) -> Result<(), crate::Error> {
-		if let Some(ref mut enc) = self.common.encrypted {
-			if enc.flush(
+		let rekey = if let Some(ref mut enc) = self.common.encrypted {
+			enc.flush(
 				&self.common.config.as_ref().limits,
 				&mut *self.common.cipher.local_to_remote,
 				&mut self.common.write_buffer,
-			)? {
-				info!("Re-exchanging keys");
+			)?
+		} else {
+			false
+		};
 
-				if enc.rekey.is_none() {
-					if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
-						let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
+		if rekey {
+			info!("Re-exchanging keys");
 
-						kexinit.client_write(
-							self.common.config.as_ref(),
-							&mut *self.common.cipher.local_to_remote,
-							&mut self.common.write_buffer,
-						)?;
+			self.initiate_rekey()?;
+		}
 
-						enc.rekey = Some(Kex::Init(kexinit))
-					}
+		Ok(())
+	}
+
+	/// Send a KEXINIT, unless a key exchange is already running.
+	fn initiate_rekey(&mut self) -> Result<(), crate::Error> {
+		if let Some(ref mut enc) = self.common.encrypted {
+			if enc.rekey.is_none() {
+				if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
+					let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id, enc.none_cipher);
+
+					kexinit.client_write(
+						self.common.config.as_ref(),
+						&mut *self.common.cipher.local_to_remote,
+						&mut self.common.write_buffer,
+					)?;
+
+					enc.rekey = Some(Kex::Init(kexinit))
 				}
 			}
 		}
@@ -1092,6 +2063,69 @@ impl Session {
 		Ok(())
 	}
 
+	/// Start a key exchange on behalf of [`Handle::rekey`].
+	fn request_rekey(
+		&mut self,
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
+	) -> Result<(), crate::Error> {
+		if self.common.encrypted.as_ref().map(|enc| enc.is_rekeying()).unwrap_or(true) {
+			reply.send(Err(crate::Error::RekeyInProgress)).unwrap_or(());
+
+			return Ok(());
+		}
+
+		self.initiate_rekey()?;
+
+		match self.common.encrypted {
+			Some(ref mut enc) if enc.rekey.is_some() => {
+				enc.rekey_done = Some(reply);
+			},
+			// No KEXINIT was sent, there is nothing to wait for.
+			_ => {
+				reply.send(Err(crate::Error::Inconsistent)).unwrap_or(());
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Start a key exchange on behalf of [`Handle::switch_to_none_cipher`].
+	fn request_none_cipher(
+		&mut self,
+		reply:tokio::sync::oneshot::Sender<Result<(), crate::Error>>,
+	) -> Result<(), crate::Error> {
+		let refused = if !self.common.config.allow_none_cipher_after_auth {
+			Some(crate::Error::NoneCipherNotAllowed)
+		} else if let Some(ref mut enc) = self.common.encrypted {
+			if !matches!(enc.state, EncryptedState::Authenticated) {
+				Some(crate::Error::NotAuthenticated)
+			} else if enc.is_rekeying() {
+				Some(crate::Error::RekeyInProgress)
+			} else {
+				enc.none_cipher = negotiation::NoneCipher::Only;
+
+				None
+			}
+		} else {
+			Some(crate::Error::NotAuthenticated)
+		};
+
+		if let Some(err) = refused {
+			reply.send(Err(err)).unwrap_or(());
+
+			return Ok(());
+		}
+
+		self.request_rekey(reply)
+	}
+
+	fn time_until_rekey(&self) -> Option<std::time::Duration> {
+		self.common
+			.encrypted
+			.as_ref()
+			.and_then(|enc| enc.time_until_rekey(&self.common.config.limits))
+	}
+
 	/// Send a `ChannelMsg` from the background handler to the client.
 	pub fn send_channel_msg(&self, channel:ChannelId, msg:ChannelMsg) -> bool {
 		if let Some(chan) = self.channels.get(&channel) {
@@ -1112,23 +2146,51 @@ impl KexDhDone {
 	async fn server_key_check<H:Handler>(
 		mut self,
 		rekey:bool,
+		config:&Config,
 		mut handler:H,
 		buf:&[u8],
 	) -> Result<(NewKeys, H), H::Error> {
 		let mut reader = buf.reader(1);
 
-		let pubkey = reader.read_string().map_err(crate::Error::from)?; // server public key.
-		let pubkey = parse_public_key(
-			pubkey,
-			#[cfg(feature = "openssl")]
-			SignatureHash::from_rsa_hostkey_algo(self.names.key.0.as_bytes()),
-		)
-		.map_err(crate::Error::from)?;
+		let pubkey_blob = reader.read_string().map_err(crate::Error::from)?; // server public key.
+
+		let (pubkey, cert) = if certificate::is_certificate_algorithm(self.names.key.0.as_bytes()) {
+			let mut cert = Certificate::parse(pubkey_blob).map_err(crate::Error::from)?;
+
+			cert.set_algorithm(self.names.key.0.as_bytes());
+
+			(cert.key.clone(), Some(cert))
+		} else {
+			let pubkey = parse_public_key(
+				pubkey_blob,
+				#[cfg(feature = "openssl")]
+				SignatureHash::from_rsa_hostkey_algo(self.names.key.0.as_bytes()),
+			)
+			.map_err(crate::Error::from)?;
+
+			(pubkey, None)
+		};
 
 		debug!("server_public_Key: {:?}", pubkey);
 
 		if !rekey {
-			let ret = handler.check_server_key(&pubkey).await?;
+			if let Some(ref known_hosts) = config.known_hosts {
+				let ca = cert.as_ref().map(|cert| &cert.signature_key);
+
+				for key in std::iter::once(&pubkey).chain(ca) {
+					if russh_keys::is_revoked_path(key, known_hosts).map_err(crate::Error::from)? {
+						return Err(crate::Error::from(russh_keys::Error::HostKeyRevoked).into());
+					}
+				}
+			}
+
+			let ret = if let Some(ref cert) = cert {
+				handler.check_server_certificate(cert).await?
+			} else if let Some(ref verification) = config.host_key_verification {
+				(handler, verification.check(&pubkey)?)
+			} else {
+				handler.check_server_key(&pubkey).await?
+			};
 
 			handler = ret.0;
 
@@ -1155,9 +2217,11 @@ impl KexDhDone {
 
 				debug!("kexdhdone.exchange = {:?}", self.exchange);
 
+				// The exchange hash covers the host key (or certificate)
+				// exactly as sent by the server.
 				let mut pubkey_vec = CryptoVec::new();
 
-				pubkey.push_to(&mut pubkey_vec);
+				pubkey_vec.extend_ssh_string(pubkey_blob);
 
 				let hash =
 					self.kex.compute_exchange_hash(&pubkey_vec, &self.exchange, &mut buffer)?;
@@ -1187,7 +2251,7 @@ impl KexDhDone {
 				hash
 			};
 
-			let mut newkeys = self.compute_keys(hash, false)?;
+			let mut newkeys = self.compute_keys(hash, &config.ciphers, &config.macs, false)?;
 
 			newkeys.sent = true;
 
@@ -1199,9 +2263,50 @@ impl KexDhDone {
 async fn reply<H:Handler>(
 	mut session:Session,
 	mut handler:H,
-	sender:&mut Option<tokio::sync::oneshot::Sender<()>>,
+	sender:&mut Option<tokio::sync::oneshot::Sender<CryptoVec>>,
+	seqn:&mut Wrapping<u32>,
 	buf:&[u8],
 ) -> Result<(H, Session), H::Error> {
+	session.common.check_strict_kex(buf, *seqn)?;
+
+	if buf.first().map_or(true, |&t| t <= msg::DEBUG) {
+		// IGNORE, UNIMPLEMENTED and DEBUG, of which the handler sees the
+		// last two once the first key exchange is done.
+		if session.common.encrypted.is_some() {
+			match buf.first() {
+				Some(&msg::UNIMPLEMENTED) => {
+					if let Some(seqn) = crate::session::read_unimplemented(buf) {
+						debug!("unimplemented message: {:?}", seqn);
+
+						return handler.unimplemented(seqn, session).await;
+					}
+				},
+				Some(&msg::DEBUG) => {
+					if let Some((always_display, message, language_tag)) =
+						crate::session::read_debug(buf)
+					{
+						debug!("debug message: {:?}", message);
+
+						return handler
+							.debug(always_display, &message, &language_tag, session)
+							.await;
+					}
+				},
+				_ => {},
+			}
+		}
+
+		return Ok((handler, session));
+	}
+
+	if buf.first().map_or(false, |&t| !msg::is_known(t)) {
+		debug!("unknown message type: {:?}", buf.first());
+
+		session.common.send_unimplemented(seqn.0.wrapping_sub(1))?;
+
+		return Ok((handler, session));
+	}
+
 	match session.common.kex.take() {
 		Some(Kex::Init(kexinit)) => {
 			if kexinit.algo.is_some()
@@ -1215,15 +2320,36 @@ async fn reply<H:Handler>(
 					&mut session.common.write_buffer,
 				)?;
 
+				if session.common.encrypted.is_none() && done.names.strict_kex {
+					// The server's KEXINIT must be its very first packet.
+					if seqn.0 != 1 {
+						return Err(crate::Error::StrictKeyExchangeViolation {
+							message_type:msg::KEXINIT,
+							sequence_number:seqn.0.wrapping_sub(1),
+						}
+						.into());
+					}
+
+					session.common.strict_kex = true;
+				}
+
 				if done.kex.skip_exchange() {
-					session.common.encrypted(
-						initial_encrypted_state(&session),
-						done.compute_keys(CryptoVec::new(), false)?,
-					);
+					let newkeys = done.compute_keys(
+						CryptoVec::new(),
+						&session.common.config.ciphers,
+						&session.common.config.macs,
+						false,
+					)?;
 
 					if let Some(sender) = sender.take() {
-						sender.send(()).unwrap_or(());
+						sender.send(newkeys.session_id.clone()).unwrap_or(());
 					}
+
+					session.common.encrypted(
+						initial_encrypted_state(&session),
+						newkeys,
+						session.common.config.compression_level,
+					);
 				} else {
 					session.common.kex = Some(Kex::DhDone(done));
 				}
@@ -1240,9 +2366,29 @@ async fn reply<H:Handler>(
 				session.common.kex = Some(Kex::DhDone(kexdhdone));
 
 				Ok((handler, session))
-			} else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
+			} else if kexdhdone.kex.is_group_exchange()
+				&& buf.first() == Some(&msg::KEX_DH_GEX_GROUP)
+			{
+				kexdhdone.client_gex_group(
+					&mut *session.common.cipher.local_to_remote,
+					buf,
+					&mut session.common.write_buffer,
+				)?;
+
+				session.common.kex = Some(Kex::DhDone(kexdhdone));
+
+				session.flush()?;
+
+				Ok((handler, session))
+			} else if buf.first() == Some(&msg::KEX_ECDH_REPLY)
+				|| buf.first() == Some(&msg::KEX_DH_GEX_REPLY)
+			{
 				// We've sent ECDH_INIT, waiting for ECDH_REPLY
-				let (kex, h) = kexdhdone.server_key_check(false, handler, buf).await?;
+				let config = session.common.config.clone();
+
+				let (kex, h) = kexdhdone
+					.server_key_check(false, &config, handler, buf)
+					.await?;
 
 				handler = h;
 
@@ -1252,7 +2398,11 @@ async fn reply<H:Handler>(
 					.common
 					.cipher
 					.local_to_remote
-					.write(&[msg::NEWKEYS], &mut session.common.write_buffer);
+					.write(&[msg::NEWKEYS], &mut session.common.write_buffer)?;
+
+				if session.common.strict_kex {
+					session.common.write_buffer.seqn = Wrapping(0);
+				}
 
 				session.flush()?;
 
@@ -1270,11 +2420,19 @@ async fn reply<H:Handler>(
 				return Err(crate::Error::Kex.into());
 			}
 
-			if let Some(sender) = sender.take() {
-				sender.send(()).unwrap_or(());
+			if session.common.strict_kex {
+				*seqn = Wrapping(0);
 			}
 
-			session.common.encrypted(initial_encrypted_state(&session), newkeys);
+			if let Some(sender) = sender.take() {
+				sender.send(newkeys.session_id.clone()).unwrap_or(());
+			}
+
+			session.common.encrypted(
+				initial_encrypted_state(&session),
+				newkeys,
+				session.common.config.compression_level,
+			);
 			// Ok, NEWKEYS received, now encrypted.
 			Ok((handler, session))
 		},
@@ -1283,7 +2441,7 @@ async fn reply<H:Handler>(
 
 			Ok((handler, session))
 		},
-		None => session.client_read_encrypted(handler, buf).await,
+		None => session.client_read_encrypted(handler, seqn, buf).await,
 	}
 }
 
@@ -1295,23 +2453,161 @@ fn initial_encrypted_state(session:&Session) -> EncryptedState {
 	}
 }
 
+/// What to do with server keys that are not recorded in the known_hosts
+/// files, as with OpenSSH's `StrictHostKeyChecking` option. A changed key
+/// is always refused.
+#[derive(Clone)]
+pub enum StrictHostKeyChecking {
+	/// Refuse unknown keys.
+	Strict,
+	/// Accept unknown keys, and record them.
+	AcceptNew,
+	/// Ask a callback, given the host, port and key, whether to accept an
+	/// unknown key, which is then recorded.
+	Ask(Arc<dyn Fn(&str, u16, &PublicKey) -> bool + Send + Sync>),
+}
+
+impl std::fmt::Debug for StrictHostKeyChecking {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			StrictHostKeyChecking::Strict => f.write_str("Strict"),
+			StrictHostKeyChecking::AcceptNew => f.write_str("AcceptNew"),
+			StrictHostKeyChecking::Ask(_) => f.write_str("Ask(_)"),
+		}
+	}
+}
+
+/// Checking of server keys against known_hosts files.
+#[derive(Debug, Clone)]
+pub struct HostKeyVerification {
+	/// The host name, as recorded in known_hosts.
+	pub host:String,
+	/// The port, recorded as `[host]:port` if it is not 22.
+	pub port:u16,
+	/// The known_hosts files.
+	pub known_hosts:KnownHosts,
+	/// What to do with unknown keys.
+	pub policy:StrictHostKeyChecking,
+}
+
+impl HostKeyVerification {
+	fn check(&self, pubkey:&PublicKey) -> Result<bool, crate::Error> {
+		match self.known_hosts.check(&self.host, self.port, pubkey)? {
+			KnownHostStatus::Match => Ok(true),
+			KnownHostStatus::Revoked => Err(russh_keys::Error::HostKeyRevoked.into()),
+			KnownHostStatus::NotFound => {
+				let accept = match self.policy {
+					StrictHostKeyChecking::Strict => false,
+					StrictHostKeyChecking::AcceptNew => true,
+					StrictHostKeyChecking::Ask(ref ask) => ask(&self.host, self.port, pubkey),
+				};
+
+				if accept {
+					self.known_hosts.learn(&self.host, self.port, pubkey)?;
+				}
+
+				Ok(accept)
+			},
+		}
+	}
+}
+
 /// The configuration of clients.
 #[derive(Debug)]
 pub struct Config {
-	/// The client ID string sent at the beginning of the protocol.
+	/// The client ID string sent at the beginning of the protocol. It must
+	/// start with `SSH-2.0-`, followed by a software version without
+	/// spaces or `-`, optionally a space and a comment, and fit in 255
+	/// bytes with its line ending, or sessions fail with
+	/// [`crate::Error::InvalidSshId`].
 	pub client_id:SshId,
-	/// The bytes and time limits before key re-exchange.
+	/// The lines the server may send before its identification string,
+	/// after which the connection fails with
+	/// [`crate::Error::TooManyPreambleLines`].
+	pub max_preamble_lines:usize,
+	/// The bytes of the lines the server may send before its identification
+	/// string, after which the connection fails with
+	/// [`crate::Error::PreambleTooLong`].
+	pub max_preamble_bytes:usize,
+	/// The bytes and time limits before key re-exchange, which starts
+	/// automatically once one of them is reached.
 	pub limits:Limits,
-	/// The initial size of a channel (used for flow control).
+	/// The initial size of a channel (used for flow control), unless the
+	/// channel is opened with its own [`WindowParams`].
 	pub window_size:u32,
-	/// The maximal size of a single packet.
+	/// The maximal size of the data of a packet sent to us on a channel.
+	/// It must be at least [`WindowParams::MIN_PACKET_SIZE`], and fit in
+	/// [`Config::max_packet_size`].
 	pub maximum_packet_size:u32,
+	/// The bytes of a channel that [`Handle::data`] may leave queued,
+	/// waiting for the peer's window, before waiting itself.
+	pub channel_buffer_size:usize,
 	/// Lists of preferred algorithms.
 	pub preferred:negotiation::Preferred,
-	/// Time after which the connection is garbage-collected.
+	/// The zlib level, from 0 to 9, of the packets sent when `zlib` or
+	/// `zlib@openssh.com` is negotiated, see
+	/// [`Preferred::COMPRESSED`](crate::Preferred::COMPRESSED).
+	pub compression_level:u32,
+	/// If set, each packet sent gets a random number of extra blocks of
+	/// padding in this range, on top of the minimum, so that the lengths
+	/// of packets do not reveal those of keystrokes or commands. Fewer
+	/// are added when the padding would exceed the 255 bytes allowed, or
+	/// the packet the 35000 bytes every peer must accept. The padding is
+	/// then random, whatever the cipher. Off by default.
+	pub random_padding:Option<std::ops::RangeInclusive<usize>>,
+	/// The time allowed to establish the TCP connection in [`connect`],
+	/// after which it fails with [`crate::Error::ConnectTimeout`].
 	pub connection_timeout:Option<std::time::Duration>,
+	/// The time allowed for the version exchange and the initial key
+	/// exchange, until authentication can start, after which
+	/// [`connect`] and [`connect_stream`] fail with
+	/// [`crate::Error::HandshakeTimeout`] and close the connection.
+	pub handshake_timeout:Option<std::time::Duration>,
+	/// If set, a `keepalive@openssh.com` global request is sent when
+	/// nothing was received from the server for this long, as OpenSSH's
+	/// `ServerAliveInterval`.
+	pub keepalive_interval:Option<std::time::Duration>,
+	/// How many keepalives may go unanswered before the connection is
+	/// closed with [`crate::Error::KeepaliveTimeout`], as OpenSSH's
+	/// `ServerAliveCountMax`.
+	pub keepalive_max:u32,
 	/// Whether to expect and wait for an authentication call.
 	pub anonymous:bool,
+	/// The ciphers available for negotiation.
+	pub ciphers:cipher::CipherRegistry,
+	/// The MACs available for negotiation.
+	pub macs:mac::MacRegistry,
+	/// Group sizes to request with `diffie-hellman-group-exchange-*`.
+	pub gex:crate::kex::GexParams,
+	/// The maximal length of an incoming packet. Larger packets are
+	/// rejected before being read.
+	pub max_packet_size:usize,
+	/// Whether [`Handle::switch_to_none_cipher`] may be used once
+	/// authenticated. Only use this on trusted networks.
+	pub allow_none_cipher_after_auth:bool,
+	/// Whether to send the first packet of our preferred key exchange
+	/// along with the KEXINIT, saving a round-trip if the server prefers
+	/// the same kex and host key algorithms. A wrong guess is ignored by
+	/// the server, and costs one wasted packet.
+	pub send_kex_guess:bool,
+	/// A known_hosts file whose `@revoked` keys are refused, for any host,
+	/// before [`Handler::check_server_key`] or
+	/// [`Handler::check_server_certificate`] is called.
+	pub known_hosts:Option<std::path::PathBuf>,
+	/// If set, server keys are checked against known_hosts files, and
+	/// unknown keys handled according to its policy, instead of calling
+	/// [`Handler::check_server_key`].
+	pub host_key_verification:Option<HostKeyVerification>,
+}
+
+impl Config {
+	/// The window parameters of channels opened with this configuration.
+	pub fn window_params(&self) -> WindowParams {
+		WindowParams {
+			window_size:self.window_size,
+			maximum_packet_size:self.maximum_packet_size,
+		}
+	}
 }
 
 impl Default for Config {
@@ -1322,12 +2618,28 @@ impl Default for Config {
 				env!("CARGO_PKG_NAME"),
 				env!("CARGO_PKG_VERSION")
 			)),
+			max_preamble_lines:1024,
+			max_preamble_bytes:65536,
 			limits:Limits::default(),
 			window_size:2097152,
 			maximum_packet_size:32768,
+			channel_buffer_size:65536,
 			preferred:Default::default(),
+			compression_level:crate::compression::DEFAULT_LEVEL,
+			random_padding:None,
 			connection_timeout:None,
+			handshake_timeout:None,
+			keepalive_interval:None,
+			keepalive_max:3,
 			anonymous:false,
+			ciphers:Default::default(),
+			macs:Default::default(),
+			gex:Default::default(),
+			max_packet_size:cipher::MAXIMUM_PACKET_LEN,
+			allow_none_cipher_after_auth:false,
+			send_kex_guess:false,
+			known_hosts:None,
+			host_key_verification:None,
 		}
 	}
 }
@@ -1347,7 +2659,8 @@ pub trait Handler: Sized + Send {
 	/// [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.4) for
 	/// more details.
 	///
-	/// The returned Boolean is ignored.
+	/// Servers may send several banners, at any time before the user is
+	/// authenticated. Invalid UTF-8 sequences are replaced with `U+FFFD`.
 	#[allow(unused_variables)]
 	async fn auth_banner(
 		self,
@@ -1357,9 +2670,39 @@ pub trait Handler: Sized + Send {
 		Ok((self, session))
 	}
 
+	/// Called when the server answers a packet we sent with an unimplemented
+	/// message, because it does not know its type. `seqn` is the sequence
+	/// number of that packet, counting the packets sent on the connection
+	/// from 0. Messages received before the first key exchange is done are
+	/// ignored.
+	#[allow(unused_variables)]
+	async fn unimplemented(
+		self,
+		seqn:u32,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		Ok((self, session))
+	}
+
+	/// Called when the server sends a debug message, which should be
+	/// shown to the user if `always_display`, as OpenSSH does. Invalid
+	/// UTF-8 sequences are replaced with `U+FFFD`. Messages received
+	/// before the first key exchange is done are ignored.
+	#[allow(unused_variables)]
+	async fn debug(
+		self,
+		always_display:bool,
+		message:&str,
+		language_tag:&str,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		Ok((self, session))
+	}
+
 	/// Called to check the server's public key. This is a very important
 	/// step to help prevent man-in-the-middle attacks. The default
-	/// implementation rejects all keys.
+	/// implementation rejects all keys. Not called if
+	/// [`Config::host_key_verification`] is set.
 	#[allow(unused_variables)]
 	async fn check_server_key(
 		self,
@@ -1368,6 +2711,21 @@ pub trait Handler: Sized + Send {
 		Ok((self, false))
 	}
 
+	/// Called instead of [`Handler::check_server_key`] when the server
+	/// presents a host certificate, which only happens if a certificate
+	/// algorithm such as [`certificate::ED25519_CERT`] is in
+	/// [`Preferred::key`](crate::Preferred::key). The certificate can be
+	/// checked with [`russh_keys::check_known_hosts_certificate`], or
+	/// [`Certificate::validate_host`]. The default implementation
+	/// rejects all certificates.
+	#[allow(unused_variables)]
+	async fn check_server_certificate(
+		self,
+		certificate:&Certificate,
+	) -> Result<(Self, bool), Self::Error> {
+		Ok((self, false))
+	}
+
 	/// Called when the server confirmed our request to open a
 	/// channel. A channel can only be written to after receiving this
 	/// message (this library panics otherwise).
@@ -1478,13 +2836,36 @@ pub trait Handler: Sized + Send {
 		Ok((self, session))
 	}
 
-	/// Called when the server opens an agent forwarding channel
+	/// Called when the server opens a channel for a connection to a Unix
+	/// domain socket forwarded with [`Handle::streamlocal_forward`].
 	#[allow(unused_variables)]
+	async fn server_channel_open_forwarded_streamlocal(
+		self,
+		channel:Channel<Msg>,
+		socket_path:&str,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		Ok((self, session))
+	}
+
+	/// Called when the server opens an agent forwarding channel, after
+	/// agent forwarding was requested with
+	/// [`Channel::request_agent_forwarding`]. By default, the channel is
+	/// served from the agent named by `SSH_AUTH_SOCK`, see
+	/// [`forward::forward_agent`]. It can also be served with
+	/// [`russh_keys::agent::forward::forward`], which lets signing requests
+	/// be filtered.
 	async fn server_channel_open_agent_forward(
 		self,
-		channel:ChannelId,
+		channel:Channel<Msg>,
 		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
+		tokio::spawn(async move {
+			if let Err(e) = forward::forward_agent(channel).await {
+				debug!("agent forwarding: {:?}", e);
+			}
+		});
+
 		Ok((self, session))
 	}
 
@@ -1542,7 +2923,7 @@ pub trait Handler: Sized + Send {
 		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
 		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::Data { data:CryptoVec::from_slice(data) }).unwrap_or(())
+			chan.send(ChannelMsg::Data { data:crate::pool::from_slice(data) }).unwrap_or(())
 		}
 
 		Ok((self, session))
@@ -1561,7 +2942,7 @@ pub trait Handler: Sized + Send {
 		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
 		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::ExtendedData { ext, data:CryptoVec::from_slice(data) })
+			chan.send(ChannelMsg::ExtendedData { ext, data:crate::pool::from_slice(data) })
 				.unwrap_or(())
 		}
 
@@ -1625,21 +3006,16 @@ pub trait Handler: Sized + Send {
 	}
 
 	/// Called when the network window is adjusted, meaning that we
-	/// can send more bytes. This is useful if this client wants to
-	/// send huge amounts of data, for instance if we have called
-	/// `Session::data` before, and it returned less than the
-	/// full amount of data.
+	/// can send more bytes. The data waiting for the window, queued by
+	/// `Session::data` for instance, was sent first, and `new_size` is
+	/// the window left.
 	#[allow(unused_variables)]
 	async fn window_adjusted(
 		self,
 		channel:ChannelId,
-		mut new_size:u32,
-		mut session:Session,
+		new_size:u32,
+		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(ref mut enc) = session.common.encrypted {
-			new_size -= enc.flush_pending(channel) as u32;
-		}
-
 		if let Some(chan) = session.channels.get(&channel) {
 			chan.send(ChannelMsg::WindowAdjusted { new_size }).unwrap_or(())
 		}
@@ -1652,7 +3028,24 @@ pub trait Handler: Sized + Send {
 	#[allow(unused_variables)]
 	fn adjust_window(&mut self, channel:ChannelId, window:u32) -> u32 { window }
 
-	/// Called when the server signals success.
+	/// Called after each key re-exchange completes, with the algorithms
+	/// now in use. It is not called for the first key exchange.
+	#[allow(unused_variables)]
+	fn on_rekey(&mut self, negotiated:&crate::Negotiated) {}
+
+	/// Called once when the session ends: `reason` is `None` if either
+	/// side closed the connection cleanly,
+	/// [`crate::Error::Disconnected`] if the peer sent a disconnect
+	/// message, and the transport error otherwise. It is not called when
+	/// the session ends because a handler method returned an error.
+	#[allow(unused_variables)]
+	fn on_close(&mut self, reason:Option<&crate::Error>) {}
+
+	/// Called when the server announces its host keys with
+	/// `hostkeys-00@openssh.com`, after authentication. These keys are not
+	/// verified yet: the server is then asked to prove that it holds them,
+	/// and [`Handler::server_host_keys_updated`] is called with the keys
+	/// it proved.
 	#[allow(unused_variables)]
 	async fn openssh_ext_host_keys_announced(
 		self,
@@ -1663,4 +3056,1806 @@ pub trait Handler: Sized + Send {
 
 		Ok((self, session))
 	}
+
+	/// Called when the server sends a global request that this library
+	/// does not handle, with its request-specific data. The response is
+	/// only sent if the server asked for one. By default, the request is
+	/// refused.
+	#[allow(unused_variables)]
+	async fn global_request(
+		self,
+		name:&str,
+		payload:&[u8],
+		session:Session,
+	) -> Result<(Self, GlobalRequestResponse, Session), Self::Error> {
+		debug!("global_request: {:?}", name);
+
+		Ok((self, GlobalRequestResponse::Failure, session))
+	}
+
+	/// Called with the host keys the server announced and proved to hold
+	/// (with `hostkeys-prove-00@openssh.com`), which may include new keys
+	/// to add to known_hosts, for instance with
+	/// [`KnownHosts::learn`](russh_keys::known_hosts::KnownHosts::learn).
+	/// Keys whose proof failed are left out.
+	///
+	/// Recorded keys of this host missing from `keys` were removed from
+	/// the server.
+	#[allow(unused_variables)]
+	async fn server_host_keys_updated(
+		self,
+		keys:Vec<PublicKey>,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		debug!("server_host_keys_updated: {:?}", keys);
+
+		Ok((self, session))
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// Both sides close the connection after `keepalive_max` unanswered
+	/// keepalives, when the other side stops sending anything.
+	#[tokio::test]
+	async fn test_keepalive_timeout() {
+		use std::{
+			sync::{
+				Arc,
+				atomic::{AtomicBool, Ordering},
+			},
+			time::{Duration, Instant},
+		};
+
+		use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+		/// Forward `from` to `to`, dropping everything once `silent` is set.
+		fn relay(
+			mut from:ReadHalf<DuplexStream>,
+			mut to:WriteHalf<DuplexStream>,
+			silent:Arc<AtomicBool>,
+		) {
+			tokio::spawn(async move {
+				let mut buf = vec![0; 65536];
+
+				while let Ok(n) = from.read(&mut buf).await {
+					if n == 0 {
+						break;
+					}
+
+					let data = buf.get(..n).unwrap_or_default();
+
+					if !silent.load(Ordering::SeqCst) && to.write_all(data).await.is_err() {
+						break;
+					}
+				}
+			});
+		}
+
+		let interval = Duration::from_millis(200);
+
+		let max = 2;
+
+		// The keepalive timer restarts from the last packet received, which
+		// comes before the silence.
+		let limit = interval * (max + 1);
+
+		let slack = Duration::from_millis(50);
+
+		// The server stops answering the client.
+		let (client_stream, relay_client) = tokio::io::duplex(65536);
+
+		let (relay_server, server_stream) = tokio::io::duplex(65536);
+
+		let (client_read, client_write) = tokio::io::split(relay_client);
+
+		let (server_read, server_write) = tokio::io::split(relay_server);
+
+		let server_silent = Arc::new(AtomicBool::new(false));
+
+		relay(client_read, server_write, Arc::new(AtomicBool::new(false)));
+
+		relay(server_read, client_write, server_silent.clone());
+
+		let mut server_config = server::Config::default();
+
+		server_config.keys.push(generate_keypair());
+
+		tokio::spawn(async move {
+			server::run_stream(Arc::new(server_config), server_stream, Server {})
+				.await
+				.unwrap()
+				.await
+				.ok();
+		});
+
+		let client_config = Arc::new(client::Config {
+			keepalive_interval:Some(interval),
+			keepalive_max:max,
+			..Default::default()
+		});
+
+		let mut session = client::connect_stream(client_config, client_stream, Client {})
+			.await
+			.unwrap();
+
+		assert!(session.authenticate_password("user", "password").await.unwrap().success());
+
+		// Answered keepalives keep the connection open.
+		tokio::time::sleep(interval * 4).await;
+
+		assert!(session.channel_open_session().await.is_ok());
+
+		server_silent.store(true, Ordering::SeqCst);
+
+		let start = Instant::now();
+
+		let result = tokio::time::timeout(limit * 2, session).await.unwrap();
+
+		assert!(matches!(result, Err(crate::Error::KeepaliveTimeout)));
+
+		assert!(start.elapsed() < limit + slack, "{:?}", start.elapsed());
+
+		// The client stops answering the server.
+		let (client_stream, relay_client) = tokio::io::duplex(65536);
+
+		let (relay_server, server_stream) = tokio::io::duplex(65536);
+
+		let (client_read, client_write) = tokio::io::split(relay_client);
+
+		let (server_read, server_write) = tokio::io::split(relay_server);
+
+		let client_silent = Arc::new(AtomicBool::new(false));
+
+		relay(client_read, server_write, client_silent.clone());
+
+		relay(server_read, client_write, Arc::new(AtomicBool::new(false)));
+
+		let server_config = server::Config {
+			keepalive_interval:Some(interval),
+			keepalive_max:max,
+			keys:vec![generate_keypair()],
+			..Default::default()
+		};
+
+		let server = tokio::spawn(async move {
+			server::run_stream(Arc::new(server_config), server_stream, Server {})
+				.await
+				.unwrap()
+				.await
+		});
+
+		let mut session =
+			client::connect_stream(Arc::new(client::Config::default()), client_stream, Client {})
+				.await
+				.unwrap();
+
+		assert!(session.authenticate_password("user", "password").await.unwrap().success());
+
+		tokio::time::sleep(interval * 4).await;
+
+		assert!(session.channel_open_session().await.is_ok());
+
+		client_silent.store(true, Ordering::SeqCst);
+
+		let start = Instant::now();
+
+		let result = tokio::time::timeout(limit * 2, server).await.unwrap().unwrap();
+
+		assert!(matches!(result, Err(crate::Error::KeepaliveTimeout)));
+
+		assert!(start.elapsed() < limit + slack, "{:?}", start.elapsed());
+	}
+
+	/// Connecting to an address that does not answer, and to servers that
+	/// stall before or after their identification string, times out, and
+	/// closes the connection.
+	#[tokio::test]
+	async fn test_connect_timeouts() {
+		use std::{
+			sync::Arc,
+			time::{Duration, Instant},
+		};
+
+		use tokio::net::TcpListener;
+
+		let timeout = Duration::from_millis(300);
+
+		let slack = Duration::from_millis(200);
+
+		let config = Arc::new(client::Config {
+			connection_timeout:Some(timeout),
+			handshake_timeout:Some(timeout),
+			..Default::default()
+		});
+
+		// A non-routable address, which fails at once on hosts without a
+		// route to it.
+		let start = Instant::now();
+
+		let result = client::connect(config.clone(), ("10.255.255.1", 22), Client {}).await;
+
+		assert!(start.elapsed() < timeout + slack, "{:?}", start.elapsed());
+
+		assert!(matches!(result, Err(crate::Error::ConnectTimeout) | Err(crate::Error::IO(_))));
+
+		// The timeout of the call overrides that of the configuration.
+		let start = Instant::now();
+
+		let timeouts = client::Timeouts { connection:Some(timeout / 3), handshake:None };
+
+		let result =
+			client::connect_with_timeouts(config.clone(), ("10.255.255.1", 22), Client {}, timeouts)
+				.await;
+
+		assert!(start.elapsed() < timeout / 3 + slack, "{:?}", start.elapsed());
+
+		assert!(matches!(result, Err(crate::Error::ConnectTimeout) | Err(crate::Error::IO(_))));
+
+		// A server that never sends its identification string, and one that
+		// stops after it.
+		for send_id in [false, true] {
+			let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+			let addr = listener.local_addr().unwrap();
+
+			let server = tokio::spawn(async move {
+				let (mut socket, _) = listener.accept().await.unwrap();
+
+				if send_id {
+					socket.write_all(b"SSH-2.0-stalled\r\n").await.unwrap();
+				}
+
+				// Read until the client closes the connection.
+				let mut buf = [0; 1024];
+
+				while socket.read(&mut buf).await.unwrap_or(0) > 0 {}
+
+				Instant::now()
+			});
+
+			let start = Instant::now();
+
+			let result = client::connect(config.clone(), addr, Client {}).await;
+
+			assert!(start.elapsed() < timeout + slack, "{:?}", start.elapsed());
+
+			assert!(matches!(result, Err(crate::Error::HandshakeTimeout)));
+
+			let closed = tokio::time::timeout(slack, server).await.unwrap().unwrap();
+
+			assert!(closed.duration_since(start) < timeout + slack);
+		}
+	}
+
+	/// Either side can end the session in the middle of a transfer, and
+	/// the other side gets the reason, on the session and its channels.
+	#[tokio::test]
+	async fn test_disconnect() {
+		use std::time::Duration;
+
+		use crate::Disconnect;
+
+		/// The reason and description of a disconnection by the peer.
+		fn disconnected(result:Result<(), crate::Error>) -> Option<(Disconnect, String)> {
+			match result {
+				Err(crate::Error::Disconnected { reason, description }) => {
+					Some((reason, description))
+				},
+				_ => None,
+			}
+		}
+
+		let timeout = Duration::from_secs(5);
+
+		for server_disconnects in [true, false] {
+			let (channels, mut server_channels) = unbounded_channel();
+
+			let (session, server) = connect(ChannelServer { channels }).await;
+
+			let mut channel = session.channel_open_session().await.unwrap();
+
+			// Send data until the session ends.
+			let transfer =
+				tokio::spawn(async move { channel.data(tokio::io::repeat(0)).await });
+
+			let mut server_channel = server_channels.recv().await.unwrap();
+
+			assert!(matches!(server_channel.wait().await, Some(ChannelMsg::Data { .. })));
+
+			if server_disconnects {
+				let handle = server.handle();
+
+				let disconnect =
+					handle.disconnect(Disconnect::ByApplication, "maintenance", "en");
+
+				tokio::time::timeout(timeout, disconnect).await.unwrap().unwrap();
+
+				let result = tokio::time::timeout(timeout, session).await.unwrap();
+
+				let expected = Some((Disconnect::ByApplication, "maintenance".into()));
+
+				assert_eq!(disconnected(result), expected);
+
+				assert!(tokio::time::timeout(timeout, server).await.unwrap().is_ok());
+			} else {
+				let disconnect = session.disconnect(Disconnect::ByApplication, "bye", "en");
+
+				tokio::time::timeout(timeout, disconnect).await.unwrap().unwrap();
+
+				let result = tokio::time::timeout(timeout, server).await.unwrap();
+
+				assert_eq!(disconnected(result), Some((Disconnect::ByApplication, "bye".into())));
+
+				// The last message of the server's channel is the reason.
+				let mut last = None;
+
+				while let Some(msg) = server_channel.wait().await {
+					last = Some(msg);
+				}
+
+				let last = match last {
+					Some(ChannelMsg::Disconnected { reason, description }) => {
+						Some((reason, description))
+					},
+					_ => None,
+				};
+
+				assert_eq!(last, Some((Disconnect::ByApplication, "bye".into())));
+			}
+
+			// The transfer stops instead of waiting forever.
+			let result = tokio::time::timeout(timeout, transfer).await.unwrap().unwrap();
+
+			assert!(result.is_err());
+		}
+	}
+
+	/// Sessions run through a jump host, itself reached through a jump
+	/// host, with more data than fits in the windows of the channels.
+	#[tokio::test]
+	async fn test_connect_via() {
+		use std::sync::Arc;
+
+		use rand::RngCore;
+		use russh_keys::key::KeyPair;
+		use tokio::net::{TcpListener, TcpStream};
+
+		/// Echoes session channels, and connects direct TCP/IP channels.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				tokio::spawn(async move {
+					let (mut read, mut write) = tokio::io::split(channel.into_stream());
+
+					tokio::io::copy(&mut read, &mut write).await.ok();
+
+					write.shutdown().await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+
+			async fn channel_open_direct_tcpip(
+				self,
+				channel:Channel<server::Msg>,
+				host_to_connect:&str,
+				port_to_connect:u32,
+				_:&str,
+				_:u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let target = format!("{}:{}", host_to_connect, port_to_connect);
+
+				tokio::spawn(async move {
+					let mut stream = TcpStream::connect(target).await.unwrap();
+
+					let mut channel = channel.into_stream();
+
+					tokio::io::copy_bidirectional(&mut stream, &mut channel).await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+		}
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let addr = listener.local_addr().unwrap();
+
+		let mut config = server::Config::default();
+
+		config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		let config = Arc::new(config);
+
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let config = config.clone();
+
+				tokio::spawn(async move {
+					if let Ok(session) = server::run_stream(config, stream, ServerHandle {}).await {
+						session.await.ok();
+					}
+				});
+			}
+		});
+
+		let config = Arc::new(client::Config::default());
+
+		let mut bastion = client::connect(config.clone(), addr, Client {}).await.unwrap();
+
+		assert!(bastion.authenticate_password("user", "password").await.unwrap().success());
+
+		// The same server is the second jump host, and the target.
+		let target_addr = ("127.0.0.1", addr.port());
+
+		let mut second =
+			client::connect_via(&bastion, target_addr, config.clone(), Client {}).await.unwrap();
+
+		assert!(second.authenticate_password("user", "password").await.unwrap().success());
+
+		let mut target =
+			client::connect_via(&second, target_addr, config.clone(), Client {}).await.unwrap();
+
+		assert!(target.authenticate_password("user", "password").await.unwrap().success());
+
+		let mut sent = vec![0; 5 << 20];
+
+		rand::thread_rng().fill_bytes(&mut sent);
+
+		let channel = target.channel_open_session().await.unwrap();
+
+		let (mut read, mut write) = tokio::io::split(channel.into_stream());
+
+		let data = sent.clone();
+
+		let writer = tokio::spawn(async move {
+			write.write_all(&data).await.unwrap();
+
+			write.shutdown().await.unwrap();
+		});
+
+		let mut received = Vec::new();
+
+		read.read_to_end(&mut received).await.unwrap();
+
+		writer.await.unwrap();
+
+		assert!(received == sent);
+
+		// Ending the inner session leaves the jump hosts usable.
+		target.disconnect(crate::Disconnect::ByApplication, "", "en").await.unwrap();
+
+		assert!(second.channel_open_session().await.is_ok());
+
+		assert!(bastion.channel_open_session().await.is_ok());
+	}
+
+	/// Both sides send overlapping global requests, with and without
+	/// replies, which are matched with the replies of the other side.
+	#[tokio::test]
+	async fn test_global_requests() {
+		use crate::GlobalRequestResponse;
+
+		/// Echo `echo@russh` requests, and refuse the others.
+		fn respond(name:&str, payload:&[u8]) -> GlobalRequestResponse {
+			if name == "echo@russh" {
+				GlobalRequestResponse::Success(payload.to_vec())
+			} else {
+				GlobalRequestResponse::Failure
+			}
+		}
+
+		struct Client {}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn global_request(
+				self,
+				name:&str,
+				payload:&[u8],
+				session:client::Session,
+			) -> Result<(Self, GlobalRequestResponse, client::Session), Self::Error> {
+				Ok((self, respond(name, payload), session))
+			}
+		}
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn global_request(
+				self,
+				name:&str,
+				payload:&[u8],
+				session:Session,
+			) -> Result<(Self, GlobalRequestResponse, Session), Self::Error> {
+				Ok((self, respond(name, payload), session))
+			}
+		}
+
+		test_session(
+			Client {},
+			ServerHandle {},
+			|client| {
+				async move {
+					let (first, refused, unreplied, second, empty) = tokio::join!(
+						client.global_request("echo@russh", true, b"first"),
+						client.global_request("refuse@russh", true, b"refused"),
+						client.global_request("echo@russh", false, b"unreplied"),
+						client.global_request("echo@russh", true, b"second"),
+						client.global_request("echo@russh", true, b""),
+					);
+
+					assert_eq!(first.unwrap(), Some(b"first".to_vec()));
+
+					assert!(matches!(refused, Err(crate::Error::GlobalRequestFailure)));
+
+					assert_eq!(unreplied.unwrap(), None);
+
+					assert_eq!(second.unwrap(), Some(b"second".to_vec()));
+
+					assert_eq!(empty.unwrap(), Some(Vec::new()));
+
+					client
+				}
+			},
+			|server| {
+				async move {
+					let (refused, first, unreplied, second) = tokio::join!(
+						server.global_request("refuse@russh", true, b"refused"),
+						server.global_request("echo@russh", true, b"first"),
+						server.global_request("refuse@russh", false, b"unreplied"),
+						server.global_request("echo@russh", true, b"second"),
+					);
+
+					assert!(matches!(refused, Err(crate::Error::GlobalRequestFailure)));
+
+					assert_eq!(first.unwrap(), Some(b"first".to_vec()));
+
+					assert_eq!(unreplied.unwrap(), None);
+
+					assert_eq!(second.unwrap(), Some(b"second".to_vec()));
+
+					server
+				}
+			},
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn test_session_id() {
+		struct ServerHandle {
+			session_id:Option<tokio::sync::oneshot::Sender<CryptoVec>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn auth_succeeded(
+				mut self,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if let (Some(tx), Some(session_id)) = (self.session_id.take(), session.session_id())
+				{
+					tx.send(CryptoVec::from_slice(session_id)).unwrap();
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let (tx, rx) = tokio::sync::oneshot::channel();
+
+		test_session(
+			Client {},
+			ServerHandle { session_id:Some(tx) },
+			|c| {
+				async move {
+					let server_session_id = rx.await.unwrap();
+
+					assert!(!c.session_id().is_empty());
+
+					assert_eq!(c.session_id(), server_session_id.as_ref());
+
+					assert_eq!(c.exchange_hash().await.unwrap().as_ref(), c.session_id());
+
+					c.rekey().await.unwrap();
+
+					assert_eq!(c.session_id(), server_session_id.as_ref());
+
+					assert_ne!(c.exchange_hash().await.unwrap().as_ref(), c.session_id());
+
+					c
+				}
+			},
+			|s| {
+				async move {
+					let session_id = s.session_id().await.unwrap().unwrap();
+
+					assert!(!session_id.is_empty());
+
+					s
+				}
+			},
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn test_switch_to_none_cipher() {
+		struct ServerHandle {
+			received:usize,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn data(
+				mut self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.received += data.len();
+
+				// Acknowledge each chunk, so the client knows it arrived.
+				if self.received % CHUNK == 0 {
+					session.data(channel, CryptoVec::from_slice(b"ack"))?;
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		const CHUNK:usize = 1 << 20;
+
+		async fn transfer(ch:&mut Channel<client::Msg>) {
+			let payload = vec![0x5e; CHUNK];
+
+			ch.data(&payload[..]).await.unwrap();
+
+			loop {
+				if let Some(ChannelMsg::Data { .. }) = ch.wait().await {
+					break;
+				}
+			}
+		}
+
+		let client_config =
+			client::Config { allow_none_cipher_after_auth:true, ..Default::default() };
+
+		let server_config =
+			server::Config { allow_none_cipher_after_auth:true, ..Default::default() };
+
+		test_session_with_configs(
+			client_config,
+			server_config,
+			Client {},
+			ServerHandle { received:0 },
+			|c| {
+				async move {
+					let mut ch = c.channel_open_session().await.unwrap();
+
+					transfer(&mut ch).await;
+
+					c.switch_to_none_cipher().await.unwrap();
+
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.cipher, cipher::NONE);
+
+					// The channel carries on in the clear.
+					transfer(&mut ch).await;
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_revoked_host_key() {
+		use std::sync::{
+			Arc,
+			atomic::{AtomicBool, Ordering},
+		};
+
+		use russh_keys::{PublicKeyBase64, key::KeyPair};
+
+		struct Client {
+			checked:Arc<AtomicBool>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				self.checked.store(true, Ordering::SeqCst);
+
+				Ok((self, true))
+			}
+		}
+
+		async fn connect_known_hosts(
+			known_hosts:&std::path::Path,
+			revoked:bool,
+		) -> (Result<client::Handle<Client>, crate::Error>, bool) {
+			let key = KeyPair::generate_ed25519().unwrap();
+
+			let mut hosts = String::from("# A comment\n");
+
+			if revoked {
+				hosts.push_str("@revoked * ssh-ed25519 ");
+			} else {
+				hosts.push_str("@cert-authority * ssh-ed25519 ");
+			}
+
+			hosts.push_str(&key.clone_public_key().unwrap().public_key_base64());
+
+			std::fs::write(known_hosts, hosts).unwrap();
+
+			let mut server_config = server::Config::default();
+
+			server_config.keys.push(key);
+
+			let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+			tokio::spawn(async move {
+				if let Ok(session) =
+					server::run_stream(Arc::new(server_config), server_stream, Server {})
+						.await
+				{
+					session.await.ok();
+				}
+			});
+
+			let client_config = client::Config {
+				known_hosts:Some(known_hosts.to_path_buf()),
+				..Default::default()
+			};
+
+			let checked = Arc::new(AtomicBool::new(false));
+
+			let client = Client { checked:checked.clone() };
+
+			let session =
+				client::connect_stream(Arc::new(client_config), client_stream, client).await;
+
+			(session, checked.load(Ordering::SeqCst))
+		}
+
+		let known_hosts =
+			std::env::temp_dir().join(format!("russh-known-hosts-{}", std::process::id()));
+
+		let (session, checked) = connect_known_hosts(&known_hosts, true).await;
+
+		assert!(matches!(session, Err(crate::Error::Keys(russh_keys::Error::HostKeyRevoked))));
+
+		// The handler is not asked to accept a revoked key.
+		assert!(!checked);
+
+		let (session, checked) = connect_known_hosts(&known_hosts, false).await;
+
+		assert!(session.is_ok());
+
+		assert!(checked);
+
+		std::fs::remove_file(&known_hosts).unwrap();
+	}
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_strict_host_key_checking() {
+		use std::sync::Arc;
+
+		use russh_keys::{
+			key::{KeyPair, PublicKey},
+			known_hosts::KnownHosts,
+		};
+
+		use crate::client::{HostKeyVerification, StrictHostKeyChecking};
+
+		// The handler's own check rejects all keys.
+		struct Client {}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+		}
+
+		async fn connect(
+			key:&str,
+			known_hosts:&std::path::Path,
+			policy:StrictHostKeyChecking,
+		) -> Result<client::Handle<Client>, crate::Error> {
+			let mut server_config = server::Config::default();
+
+			server_config.keys.push(russh_keys::decode_secret_key(key, None).unwrap());
+
+			let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+			tokio::spawn(async move {
+				if let Ok(session) =
+					server::run_stream(Arc::new(server_config), server_stream, Server {})
+						.await
+				{
+					session.await.ok();
+				}
+			});
+
+			let verification = HostKeyVerification {
+				host:"::1".to_string(),
+				port:2222,
+				known_hosts:KnownHosts {
+					user_file:Some(known_hosts.to_path_buf()),
+					..Default::default()
+				},
+				policy,
+			};
+
+			let client_config = client::Config {
+				host_key_verification:Some(verification),
+				..Default::default()
+			};
+
+			client::connect_stream(Arc::new(client_config), client_stream, Client {}).await
+		}
+
+		let known_hosts =
+			std::env::temp_dir().join(format!("russh-strict-host-keys-{}", std::process::id()));
+
+		let generate = || {
+			let key = KeyPair::generate_ed25519().unwrap();
+
+			let pem = russh_keys::encode_openssh_private_key(&key, "", None, 16).unwrap();
+
+			String::from_utf8(pem).unwrap()
+		};
+
+		let key = generate();
+
+		let other_key = generate();
+
+		assert!(matches!(
+			connect(&key, &known_hosts, StrictHostKeyChecking::Strict).await,
+			Err(crate::Error::UnknownKey)
+		));
+
+		let refuse = StrictHostKeyChecking::Ask(Arc::new(|host:&str, port:u16, _:&PublicKey| {
+			assert_eq!((host, port), ("::1", 2222));
+
+			false
+		}));
+
+		assert!(matches!(connect(&key, &known_hosts, refuse).await, Err(crate::Error::UnknownKey)));
+
+		assert!(connect(&key, &known_hosts, StrictHostKeyChecking::AcceptNew).await.is_ok());
+
+		// The key was recorded.
+		assert!(connect(&key, &known_hosts, StrictHostKeyChecking::Strict).await.is_ok());
+
+		// A changed key is refused, whatever the policy.
+		assert!(matches!(
+			connect(&other_key, &known_hosts, StrictHostKeyChecking::AcceptNew).await,
+			Err(crate::Error::Keys(russh_keys::Error::KeyChanged { .. }))
+		));
+
+		let accept = StrictHostKeyChecking::Ask(Arc::new(|_:&str, _:u16, _:&PublicKey| true));
+
+		assert!(matches!(
+			connect(&other_key, &known_hosts, accept).await,
+			Err(crate::Error::Keys(russh_keys::Error::KeyChanged { .. }))
+		));
+
+		std::fs::remove_file(&known_hosts).unwrap();
+	}
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_host_keys_update() {
+		use russh_keys::key::{KeyPair, PublicKey};
+		use tokio::sync::oneshot;
+
+		struct Client {
+			announced:Option<oneshot::Sender<Vec<PublicKey>>>,
+			updated:Option<oneshot::Sender<Vec<PublicKey>>>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn openssh_ext_host_keys_announced(
+				mut self,
+				keys:Vec<PublicKey>,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				if let Some(tx) = self.announced.take() {
+					tx.send(keys).unwrap();
+				}
+
+				Ok((self, session))
+			}
+
+			async fn server_host_keys_updated(
+				mut self,
+				keys:Vec<PublicKey>,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				if let Some(tx) = self.updated.take() {
+					tx.send(keys).unwrap();
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let mut server_config = server::Config { announce_host_keys:true, ..Default::default() };
+
+		server_config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		// The key the server is moving to, of another type since host keys
+		// are of different types.
+		#[cfg(feature = "openssl")]
+		server_config.keys.push(
+			KeyPair::generate_rsa(2048, russh_keys::key::SignatureHash::SHA2_256).unwrap(),
+		);
+
+		let host_keys = server_config
+			.keys
+			.iter()
+			.map(|key| key.clone_public_key().unwrap())
+			.collect::<Vec<_>>();
+
+		let (announced_tx, announced_rx) = oneshot::channel();
+
+		let (updated_tx, updated_rx) = oneshot::channel();
+
+		let client = Client { announced:Some(announced_tx), updated:Some(updated_tx) };
+
+		let (mut session, _server) =
+			connect_with(client::Config::default(), server_config, client, Server {}).await;
+
+		assert!(session.authenticate_password("user", "password").await.unwrap().success());
+
+		assert_eq!(announced_rx.await.unwrap(), host_keys);
+
+		// All the keys were proven.
+		assert_eq!(updated_rx.await.unwrap(), host_keys);
+	}
+
+	#[tokio::test]
+	async fn test_exec_collect() {
+		use crate::Sig;
+
+		const LARGE:usize = 5 << 20;
+
+		struct ServerHandle {
+			// A channel to close once its output is sent.
+			closing:Option<ChannelId>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				mut self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if data == b"refuse" {
+					session.channel_failure(channel);
+
+					return Ok((self, session));
+				}
+
+				session.channel_success(channel);
+
+				match data {
+					b"out; err; exit 3" => {
+						session.data(channel, CryptoVec::from_slice(b"out\n"))?;
+
+						session.extended_data(channel, 1, CryptoVec::from_slice(b"err\n"))?;
+
+						session.exit_status_request(channel, 3);
+					},
+					b"kill" => {
+						session.exit_signal_request(channel, Sig::TERM, false, "Terminated", "");
+					},
+					b"large" => {
+						session.data(channel, CryptoVec::from_slice(&vec![b'x'; LARGE]))?;
+
+						// Closing now would drop the output beyond the window.
+						self.closing = Some(channel);
+
+						return Ok((self, session));
+					},
+					_ => {},
+				}
+
+				session.eof(channel);
+
+				session.close(channel);
+
+				Ok((self, session))
+			}
+
+			async fn window_adjusted(
+				mut self,
+				channel:ChannelId,
+				_:u32,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if let Some(ref mut enc) = session.common.encrypted {
+					enc.flush_pending(channel);
+				}
+
+				if self.closing == Some(channel) && !session.has_pending_data(channel) {
+					self.closing = None;
+
+					session.exit_status_request(channel, 0);
+
+					session.eof(channel);
+
+					session.close(channel);
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let (session, _server) = connect(ServerHandle { closing:None }).await;
+
+		let output = session.exec_collect("out; err; exit 3").await.unwrap();
+
+		assert_eq!(output, client::CommandOutput {
+			stdout:b"out\n".to_vec(),
+			stderr:b"err\n".to_vec(),
+			exit_status:Some(3),
+			exit_signal:None,
+		});
+
+		let output = session.exec_collect("kill").await.unwrap();
+
+		assert_eq!(output.exit_status, None);
+
+		assert_eq!(output.exit_signal, Some(Sig::TERM));
+
+		// Closed without an exit status.
+		let output = session.exec_collect("close").await.unwrap();
+
+		assert_eq!((output.exit_status, output.exit_signal), (None, None));
+
+		// More output than the window, read as a stream.
+		let mut command = session.exec_stream("large").await.unwrap();
+
+		let mut stdout = Vec::new();
+
+		command.stdout.read_to_end(&mut stdout).await.unwrap();
+
+		assert_eq!(stdout.len(), LARGE);
+
+		assert_eq!(command.wait().await.unwrap(), (Some(0), None));
+
+		assert!(matches!(
+			session.exec_collect("refuse").await,
+			Err(crate::Error::ChannelRequestFailure)
+		));
+	}
+
+	/// The data, EOF and close of a handle fail with `Error::WrongChannel`
+	/// once the channel is closed, as on a channel that never existed.
+	#[tokio::test]
+	async fn test_handle_wrong_channel() {
+		let (session, _server) = connect(Server {}).await;
+
+		let channel = session.channel_open_session().await.unwrap();
+
+		session.data(channel.id(), CryptoVec::from_slice(b"data")).await.unwrap();
+
+		session.eof(channel.id()).await.unwrap();
+
+		session.close(channel.id()).await.unwrap();
+
+		let late = || CryptoVec::from_slice(b"late");
+
+		for id in [channel.id(), ChannelId(1234)] {
+			assert!(matches!(session.data(id, late()).await, Err(crate::Error::WrongChannel)));
+
+			let extended = session.extended_data(id, 1, late()).await;
+
+			assert!(matches!(extended, Err(crate::Error::WrongChannel)));
+
+			assert!(matches!(session.eof(id).await, Err(crate::Error::WrongChannel)));
+
+			assert!(matches!(session.close(id).await, Err(crate::Error::WrongChannel)));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_tcpip_forward() {
+		use std::collections::HashMap;
+
+		struct Client {
+			forwarded:UnboundedSender<(String, u32, Channel<client::Msg>)>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn server_channel_open_forwarded_tcpip(
+				self,
+				channel:Channel<client::Msg>,
+				connected_address:&str,
+				connected_port:u32,
+				_:&str,
+				_:u32,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				let address = connected_address.to_string();
+
+				self.forwarded.send((address, connected_port, channel)).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn tcpip_forward(
+				self,
+				address:&str,
+				port:&mut u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if address != "127.0.0.1" {
+					return Ok((self, false, session));
+				}
+
+				if *port == 0 {
+					*port = 40000;
+				}
+
+				// A connection to the forwarded port.
+				let (handle, address, port) = (session.handle(), address.to_string(), *port);
+
+				tokio::spawn(async move {
+					let mut channel =
+						handle.channel_open_forwarded_tcpip(address, port, "10.0.0.1", 1234).await?;
+
+					channel.data(format!("to {}", port).as_bytes()).await?;
+
+					channel.eof().await
+				});
+
+				Ok((self, true, session))
+			}
+
+			async fn cancel_tcpip_forward(
+				self,
+				_:&str,
+				_:u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+		}
+
+		let (forwarded, mut connections) = unbounded_channel();
+
+		test_session(
+			Client { forwarded },
+			ServerHandle {},
+			|client| {
+				async move {
+					assert_eq!(client.tcpip_forward("127.0.0.1", 0).await.unwrap(), 40000);
+
+					assert_eq!(client.tcpip_forward("127.0.0.1", 2222).await.unwrap(), 2222);
+
+					let mut received = HashMap::new();
+
+					for _ in 0..2 {
+						let (address, port, mut channel) = connections.recv().await.unwrap();
+
+						assert_eq!(address, "127.0.0.1");
+
+						let mut data = Vec::new();
+
+						while let Some(msg) = channel.wait().await {
+							match msg {
+								ChannelMsg::Data { data: d } => data.extend_from_slice(&d),
+								ChannelMsg::Eof => break,
+								_ => {},
+							}
+						}
+
+						received.insert(port, String::from_utf8(data).unwrap());
+					}
+
+					assert_eq!(received[&40000], "to 40000");
+
+					assert_eq!(received[&2222], "to 2222");
+
+					assert!(matches!(
+						client.tcpip_forward("0.0.0.0", 0).await,
+						Err(crate::Error::GlobalRequestFailure)
+					));
+
+					client.cancel_tcpip_forward("127.0.0.1", 2222).await.unwrap();
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_streamlocal() {
+		use tokio::net::{UnixListener, UnixStream};
+
+		struct Client {
+			forwarded:UnboundedSender<(String, Channel<client::Msg>)>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn server_channel_open_forwarded_streamlocal(
+				self,
+				channel:Channel<client::Msg>,
+				socket_path:&str,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.forwarded.send((socket_path.to_string(), channel)).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_direct_streamlocal(
+				self,
+				channel:Channel<server::Msg>,
+				socket_path:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let socket_path = socket_path.to_string();
+
+				tokio::spawn(async move {
+					let mut stream = UnixStream::connect(socket_path).await.unwrap();
+
+					let mut channel = channel.into_stream();
+
+					tokio::io::copy_bidirectional(&mut stream, &mut channel).await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+
+			async fn streamlocal_forward(
+				self,
+				socket_path:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if socket_path.starts_with("/refused") {
+					return Ok((self, false, session));
+				}
+
+				// A connection to the forwarded socket.
+				let (handle, socket_path) = (session.handle(), socket_path.to_string());
+
+				tokio::spawn(async move {
+					let mut channel =
+						handle.channel_open_forwarded_streamlocal(socket_path.as_str()).await?;
+
+					channel.data(format!("to {}", socket_path).as_bytes()).await?;
+
+					channel.eof().await
+				});
+
+				Ok((self, true, session))
+			}
+
+			async fn cancel_streamlocal_forward(
+				self,
+				_:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+		}
+
+		let echo_path =
+			std::env::temp_dir().join(format!("russh-echo-{}.sock", std::process::id()));
+
+		std::fs::remove_file(&echo_path).ok();
+
+		let echo = UnixListener::bind(&echo_path).unwrap();
+
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = echo.accept().await {
+				tokio::spawn(async move {
+					let (mut read, mut write) = stream.split();
+
+					tokio::io::copy(&mut read, &mut write).await.ok();
+				});
+			}
+		});
+
+		let echo_path = echo_path.to_str().unwrap().to_string();
+
+		let (forwarded, mut connections) = unbounded_channel();
+
+		test_session(
+			Client { forwarded },
+			ServerHandle {},
+			|client| {
+				async move {
+					// Local forwarding, to the echo socket.
+					let channel = client.channel_open_direct_streamlocal(echo_path.as_str()).await;
+
+					let mut stream = channel.unwrap().into_stream();
+
+					stream.write_all(b"ping").await.unwrap();
+
+					stream.shutdown().await.unwrap();
+
+					let mut received = Vec::new();
+
+					stream.read_to_end(&mut received).await.unwrap();
+
+					assert_eq!(received, b"ping");
+
+					std::fs::remove_file(&echo_path).ok();
+
+					// Remote forwarding.
+					client.streamlocal_forward("/run/app.sock").await.unwrap();
+
+					let (socket_path, mut channel) = connections.recv().await.unwrap();
+
+					assert_eq!(socket_path, "/run/app.sock");
+
+					let mut data = Vec::new();
+
+					while let Some(msg) = channel.wait().await {
+						match msg {
+							ChannelMsg::Data { data: d } => data.extend_from_slice(&d),
+							ChannelMsg::Eof => break,
+							_ => {},
+						}
+					}
+
+					assert_eq!(data, b"to /run/app.sock");
+
+					client.cancel_streamlocal_forward("/run/app.sock").await.unwrap();
+
+					assert!(matches!(
+						client.streamlocal_forward("/refused.sock").await,
+						Err(crate::Error::GlobalRequestFailure)
+					));
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	/// Channels beyond `max_channels_per_connection` are refused for lack
+	/// of resources, and handlers choose why they refuse the others.
+	#[tokio::test]
+	async fn test_channel_open_failures() {
+		use crate::{ChannelOpenFailure, Error};
+
+		struct Client {
+			failures:UnboundedSender<(ChannelOpenFailure, String)>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn channel_open_failure(
+				self,
+				channel:ChannelId,
+				reason:ChannelOpenFailure,
+				description:&str,
+				_:&str,
+				mut session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.failures.send((reason, description.to_string())).unwrap();
+
+				if let Some(sender) = session.channels.remove(&channel) {
+					sender.send(ChannelMsg::OpenFailure(reason)).unwrap_or(());
+				}
+
+				session.sender.send(client::Reply::ChannelOpenFailure).unwrap_or(());
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn channel_open_direct_tcpip(
+				self,
+				channel:Channel<server::Msg>,
+				host_to_connect:&str,
+				_:u32,
+				_:&str,
+				_:u32,
+				mut session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let description = format!("No forwarding to {}", host_to_connect);
+
+				session.channel_open_failure(
+					channel.id(),
+					ChannelOpenFailure::AdministrativelyProhibited,
+					&description,
+					"en",
+				);
+
+				Ok((self, false, session))
+			}
+		}
+
+		let (failures, mut received) = unbounded_channel();
+
+		let config = server::Config { max_channels_per_connection:Some(2), ..Default::default() };
+
+		test_session_with_configs(
+			Default::default(),
+			config,
+			Client { failures },
+			ServerHandle {},
+			|c| {
+				async move {
+					let refused = c.channel_open_direct_tcpip("example.com", 80, "127.0.0.1", 1);
+
+					assert!(matches!(
+						refused.await,
+						Err(Error::ChannelOpenFailure(
+							ChannelOpenFailure::AdministrativelyProhibited
+						))
+					));
+
+					assert_eq!(
+						received.recv().await.unwrap(),
+						(
+							ChannelOpenFailure::AdministrativelyProhibited,
+							"No forwarding to example.com".to_string()
+						)
+					);
+
+					// The refused channel does not count.
+					let _first = c.channel_open_session().await.unwrap();
+
+					let _second = c.channel_open_session().await.unwrap();
+
+					assert!(matches!(
+						c.channel_open_session().await,
+						Err(Error::ChannelOpenFailure(ChannelOpenFailure::ResourceShortage))
+					));
+
+					assert_eq!(
+						received.recv().await.unwrap(),
+						(ChannelOpenFailure::ResourceShortage, "Too many channels".to_string())
+					);
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+
+	/// Each side answers a message of unknown type 200 with an
+	/// unimplemented message, reaching the handler of the other side with
+	/// the sequence number of the unknown message, and the session goes on.
+	#[tokio::test]
+	async fn test_unimplemented() {
+		use byteorder::{BigEndian, ByteOrder};
+
+		use crate::session::CommonSession;
+
+		/// Queue a message of unknown type, returning the sequence number
+		/// of its packet, after those already queued.
+		fn inject<C>(common:&mut CommonSession<C>) -> u32 {
+			let enc = common.encrypted.as_mut().unwrap();
+
+			let mut seqn = common.write_buffer.seqn.0;
+
+			let mut cursor = enc.write_cursor;
+
+			while cursor < enc.write.len() {
+				cursor += 4 + BigEndian::read_u32(&enc.write[cursor..]) as usize;
+
+				seqn += 1;
+			}
+
+			push_packet!(enc.write, enc.write.extend(&[200, 1, 2, 3]));
+
+			seqn
+		}
+
+		struct Client {
+			injected:UnboundedSender<u32>,
+			answered:UnboundedSender<u32>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn debug(
+				self,
+				_:bool,
+				_:&str,
+				_:&str,
+				mut session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.injected.send(inject(&mut session.common)).unwrap();
+
+				Ok((self, session))
+			}
+
+			async fn unimplemented(
+				self,
+				seqn:u32,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.answered.send(seqn).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			injected:UnboundedSender<u32>,
+			answered:UnboundedSender<u32>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn debug(
+				self,
+				_:bool,
+				_:&str,
+				_:&str,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.injected.send(inject(&mut session.common)).unwrap();
+
+				Ok((self, session))
+			}
+
+			async fn unimplemented(
+				self,
+				seqn:u32,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.answered.send(seqn).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		let (injected, mut server_injected) = unbounded_channel();
+
+		let (answered, mut server_answered) = unbounded_channel();
+
+		let server = ServerHandle { injected, answered };
+
+		let (injected, mut client_injected) = unbounded_channel();
+
+		let (answered, mut client_answered) = unbounded_channel();
+
+		let client = Client { injected, answered };
+
+		let (mut session, server) =
+			connect_with(client::Config::default(), server::Config::default(), client, server)
+				.await;
+
+		let server = server.handle();
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		// The server sends an unknown message, which the client answers.
+		session.send_debug(false, "inject").await.unwrap();
+
+		let seqn = server_injected.recv().await.unwrap();
+
+		assert_eq!(server_answered.recv().await.unwrap(), seqn);
+
+		// The other way round.
+		server.send_debug(false, "inject").await.unwrap();
+
+		let seqn = client_injected.recv().await.unwrap();
+
+		assert_eq!(client_answered.recv().await.unwrap(), seqn);
+
+		// The session goes on: the server refuses sessions by default.
+		assert!(session.channel_open_session().await.is_err());
+	}
 }
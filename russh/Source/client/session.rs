@@ -1,8 +1,18 @@
 use log::error;
 use russh_cryptovec::CryptoVec;
-use russh_keys::encoding::Encoding;
-
-use crate::{ChannelId, Disconnect, Pty, Sig, client::Session, msg, session::EncryptedState};
+use russh_keys::{encoding::Encoding, key};
+
+use crate::{
+	ChannelId,
+	ChannelMsg,
+	Disconnect,
+	Sig,
+	TerminalModes,
+	WindowParams,
+	client::{GlobalRequest, Session},
+	msg,
+	session::EncryptedState,
+};
 
 impl Session {
 	fn channel_open_generic<F>(
@@ -10,15 +20,28 @@ impl Session {
 		kind:&[u8],
 		write_suffix:F,
 	) -> Result<ChannelId, crate::Error>
+	where
+		F: FnOnce(&mut CryptoVec), {
+		let params = self.common.config.window_params();
+
+		self.channel_open_generic_with(kind, params, write_suffix)
+	}
+
+	fn channel_open_generic_with<F>(
+		&mut self,
+		kind:&[u8],
+		params:WindowParams,
+		write_suffix:F,
+	) -> Result<ChannelId, crate::Error>
 	where
 		F: FnOnce(&mut CryptoVec), {
 		let result = if let Some(ref mut enc) = self.common.encrypted {
 			match enc.state {
 				EncryptedState::Authenticated => {
-					let sender_channel = enc.new_channel(
-						self.common.config.window_size,
-						self.common.config.maximum_packet_size,
-					);
+					let sender_channel =
+						enc.new_channel(params.window_size, params.maximum_packet_size);
+
+					self.common.stats.channel_opened();
 
 					push_packet!(enc.write, {
 						enc.write.push(msg::CHANNEL_OPEN);
@@ -29,10 +52,10 @@ impl Session {
 						enc.write.push_u32_be(sender_channel.0);
 
 						// window.
-						enc.write.push_u32_be(self.common.config.as_ref().window_size);
+						enc.write.push_u32_be(params.window_size);
 
 						// max packet size.
-						enc.write.push_u32_be(self.common.config.as_ref().maximum_packet_size);
+						enc.write.push_u32_be(params.maximum_packet_size);
 
 						write_suffix(&mut enc.write);
 					});
@@ -52,6 +75,26 @@ impl Session {
 		self.channel_open_generic(b"session", |_| ())
 	}
 
+	/// Open a session channel with its own window parameters, see
+	/// [`crate::client::Handle::channel_open_session_with`].
+	pub fn channel_open_session_with(
+		&mut self,
+		params:WindowParams,
+	) -> Result<ChannelId, crate::Error> {
+		params.check(self.common.config.max_packet_size)?;
+
+		let id = self.channel_open_generic_with(b"session", params, |_| ())?;
+
+		// Keep restoring this window, rather than the session's.
+		let channel = self.common.encrypted.as_mut().and_then(|enc| enc.channels.get_mut(&id));
+
+		if let Some(channel) = channel {
+			channel.target_window_size = Some(params.window_size);
+		}
+
+		Ok(id)
+	}
+
 	pub fn channel_open_x11(
 		&mut self,
 		originator_address:&str,
@@ -103,7 +146,7 @@ impl Session {
 		row_height:u32,
 		pix_width:u32,
 		pix_height:u32,
-		terminal_modes:&[(Pty, u32)],
+		terminal_modes:&TerminalModes,
 	) {
 		if let Some(ref mut enc) = self.common.encrypted {
 			if let Some(channel) = enc.channels.get(&channel) {
@@ -126,15 +169,7 @@ impl Session {
 
 					enc.write.push_u32_be(pix_height);
 
-					enc.write.push_u32_be((1 + 5 * terminal_modes.len()) as u32);
-
-					for &(code, value) in terminal_modes {
-						enc.write.push(code as u8);
-
-						enc.write.push_u32_be(value)
-					}
-					// 0 code (to terminate the list)
-					enc.write.push(0);
+					enc.write.extend_ssh_string(&terminal_modes.encode());
 				});
 			}
 		}
@@ -254,6 +289,24 @@ impl Session {
 		}
 	}
 
+	pub fn send_break(&mut self, channel:ChannelId, want_reply:bool, break_length:u32) {
+		if let Some(ref mut enc) = self.common.encrypted {
+			if let Some(channel) = enc.channels.get(&channel) {
+				push_packet!(enc.write, {
+					enc.write.push(msg::CHANNEL_REQUEST);
+
+					enc.write.push_u32_be(channel.recipient_channel);
+
+					enc.write.extend_ssh_string(b"break");
+
+					enc.write.push(want_reply as u8);
+
+					enc.write.push_u32_be(break_length);
+				});
+			}
+		}
+	}
+
 	pub fn request_subsystem(&mut self, want_reply:bool, channel:ChannelId, name:&str) {
 		if let Some(ref mut enc) = self.common.encrypted {
 			if let Some(channel) = enc.channels.get(&channel) {
@@ -303,43 +356,127 @@ impl Session {
 	}
 
 	pub fn tcpip_forward(&mut self, want_reply:bool, address:&str, port:u32) {
-		if let Some(ref mut enc) = self.common.encrypted {
-			push_packet!(enc.write, {
-				enc.write.push(msg::GLOBAL_REQUEST);
+		let request = if want_reply { Some(GlobalRequest::Other) } else { None };
 
-				enc.write.extend_ssh_string(b"tcpip-forward");
+		self.forward_request(b"tcpip-forward", address, port, request)
+	}
 
-				enc.write.push(want_reply as u8);
+	pub fn cancel_tcpip_forward(&mut self, want_reply:bool, address:&str, port:u32) {
+		let request = if want_reply { Some(GlobalRequest::Other) } else { None };
 
-				enc.write.extend_ssh_string(address.as_bytes());
+		self.forward_request(b"cancel-tcpip-forward", address, port, request)
+	}
 
-				enc.write.push_u32_be(port);
-			});
+	pub fn streamlocal_forward(&mut self, want_reply:bool, socket_path:&str) {
+		let request = if want_reply { Some(GlobalRequest::Other) } else { None };
+
+		self.streamlocal_forward_request(b"streamlocal-forward@openssh.com", socket_path, request)
+	}
+
+	pub fn cancel_streamlocal_forward(&mut self, want_reply:bool, socket_path:&str) {
+		let request = if want_reply { Some(GlobalRequest::Other) } else { None };
+
+		self.streamlocal_forward_request(
+			b"cancel-streamlocal-forward@openssh.com",
+			socket_path,
+			request,
+		)
+	}
+
+	/// Send a `tcpip-forward` or `cancel-tcpip-forward` global request,
+	/// with a reply if `request` is set.
+	pub(crate) fn forward_request(
+		&mut self,
+		name:&[u8],
+		address:&str,
+		port:u32,
+		request:Option<GlobalRequest>,
+	) {
+		self.global_request(name, request, |write| {
+			write.extend_ssh_string(address.as_bytes());
+
+			write.push_u32_be(port);
+		})
+	}
+
+	/// Send a `streamlocal-forward@openssh.com` or
+	/// `cancel-streamlocal-forward@openssh.com` global request, with a reply
+	/// if `request` is set.
+	pub(crate) fn streamlocal_forward_request(
+		&mut self,
+		name:&[u8],
+		socket_path:&str,
+		request:Option<GlobalRequest>,
+	) {
+		self.global_request(name, request, |write| {
+			write.extend_ssh_string(socket_path.as_bytes());
+		})
+	}
+
+	/// Send a `keepalive@openssh.com` global request, or fail if the
+	/// last [`Config::keepalive_max`](super::Config::keepalive_max) ones
+	/// were not answered. Nothing is sent before authentication. During a
+	/// key exchange nothing is sent either, but the silence of the server
+	/// still counts.
+	pub(crate) fn send_keepalive(&mut self) -> Result<(), crate::Error> {
+		let authenticated = self
+			.common
+			.encrypted
+			.as_ref()
+			.map_or(false, |enc| matches!(enc.state, EncryptedState::Authenticated));
+
+		if !authenticated {
+			return Ok(());
 		}
+
+		if self.keepalives_unanswered >= self.common.config.keepalive_max {
+			return Err(crate::Error::KeepaliveTimeout);
+		}
+
+		self.keepalives_unanswered += 1;
+
+		if !self.is_rekeying() {
+			self.global_request(b"keepalive@openssh.com", Some(GlobalRequest::Keepalive), |_| {});
+		}
+
+		Ok(())
 	}
 
-	pub fn cancel_tcpip_forward(&mut self, want_reply:bool, address:&str, port:u32) {
+	pub(crate) fn global_request<F>(
+		&mut self,
+		name:&[u8],
+		request:Option<GlobalRequest>,
+		write_suffix:F,
+	)
+	where
+		F: FnOnce(&mut CryptoVec), {
 		if let Some(ref mut enc) = self.common.encrypted {
 			push_packet!(enc.write, {
 				enc.write.push(msg::GLOBAL_REQUEST);
 
-				enc.write.extend_ssh_string(b"cancel-tcpip-forward");
+				enc.write.extend_ssh_string(name);
 
-				enc.write.push(want_reply as u8);
+				enc.write.push(request.is_some() as u8);
 
-				enc.write.extend_ssh_string(address.as_bytes());
-
-				enc.write.push_u32_be(port);
+				write_suffix(&mut enc.write);
 			});
+
+			if let Some(request) = request {
+				self.pending_global_requests.push_back(request);
+			}
 		}
 	}
 
-	pub fn data(&mut self, channel:ChannelId, data:CryptoVec) {
+	/// Send data to a channel. Fails with [`crate::Error::WrongChannel`]
+	/// if the channel is unknown or closed.
+	pub fn data(&mut self, channel:ChannelId, data:CryptoVec) -> Result<(), crate::Error> {
+		self.common.check_channel(channel)?;
+
 		if let Some(ref mut enc) = self.common.encrypted {
 			enc.data(channel, data)
-		} else {
-			unreachable!()
 		}
+
+		Ok(())
 	}
 
 	pub fn eof(&mut self, channel:ChannelId) {
@@ -358,17 +495,56 @@ impl Session {
 		}
 	}
 
-	pub fn extended_data(&mut self, channel:ChannelId, ext:u32, data:CryptoVec) {
+	/// Send extended data to a channel, failing as [`Session::data`].
+	pub fn extended_data(
+		&mut self,
+		channel:ChannelId,
+		ext:u32,
+		data:CryptoVec,
+	) -> Result<(), crate::Error> {
+		self.common.check_channel(channel)?;
+
 		if let Some(ref mut enc) = self.common.encrypted {
 			enc.extended_data(channel, ext, data)
-		} else {
-			unreachable!()
+		}
+
+		Ok(())
+	}
+
+	/// Send data written through a [`crate::client::Handle`], replying on
+	/// `sent` once it is sent, but for
+	/// [`crate::client::Config::channel_buffer_size`] bytes.
+	pub(crate) fn handle_data(
+		&mut self,
+		channel:ChannelId,
+		ext:Option<u32>,
+		data:CryptoVec,
+		sent:Option<tokio::sync::oneshot::Sender<()>>,
+	) {
+		let written = match ext {
+			Some(ext) => self.extended_data(channel, ext, data),
+			None => self.data(channel, data),
+		};
+
+		// Dropping `sent` fails the writer with `Error::WrongChannel`.
+		if written.is_err() {
+			return;
+		}
+
+		if let Some(ref mut enc) = self.common.encrypted {
+			if let Some(sent) = sent {
+				enc.wait_sent(channel, sent);
+			}
+
+			enc.wake_writers(&self.common.backlogs);
 		}
 	}
 
 	pub fn agent_forward(&mut self, channel:ChannelId, want_reply:bool) {
 		if let Some(ref mut enc) = self.common.encrypted {
 			if let Some(channel) = enc.channels.get(&channel) {
+				self.agent_forward_requested = true;
+
 				push_packet!(enc.write, {
 					enc.write.push(msg::CHANNEL_REQUEST);
 
@@ -382,10 +558,36 @@ impl Session {
 		}
 	}
 
+	/// Send a debug message, which the peer may log, or show to its user
+	/// if `always_display`.
+	pub fn send_debug(&mut self, always_display:bool, message:&str) {
+		self.common.send_debug(always_display, message, "");
+	}
+
+	/// Send `payload_len` random bytes the peer discards, for instance to
+	/// pad the traffic.
+	pub fn send_ignore(&mut self, payload_len:usize) { self.common.send_ignore(payload_len); }
+
 	pub fn disconnect(&mut self, reason:Disconnect, description:&str, language_tag:&str) {
 		self.common.disconnect(reason, description, language_tag);
 	}
 
+	/// End the session on the disconnect message `buf` of the peer, passing
+	/// its reason on to the open channels.
+	pub(crate) fn peer_disconnected(&mut self, buf:&[u8]) -> crate::Error {
+		let error = crate::session::disconnected_by_peer(buf);
+
+		if let crate::Error::Disconnected { reason, ref description } = error {
+			for sender in self.channels.values() {
+				let msg = ChannelMsg::Disconnected { reason, description:description.clone() };
+
+				sender.send(msg).unwrap_or(());
+			}
+		}
+
+		error
+	}
+
 	pub fn has_pending_data(&self, channel:ChannelId) -> bool {
 		if let Some(ref enc) = self.common.encrypted {
 			enc.has_pending_data(channel)
@@ -401,4 +603,32 @@ impl Session {
 			0
 		}
 	}
+
+	/// The address of the server, if known, and the version strings of
+	/// both sides, as exchanged before any handler method is called.
+	pub fn connection_info(&self) -> &crate::ConnectionInfo { &self.common.info }
+
+	/// Algorithms negotiated by the last key exchange, or `None` before
+	/// the first one has completed.
+	pub fn negotiated_algorithms(&self) -> Option<&crate::Negotiated> {
+		self.common.encrypted.as_ref().map(|enc| &enc.negotiated)
+	}
+
+	/// The session identifier, i.e. the exchange hash of the initial key
+	/// exchange, or `None` before it has completed. It stays the same
+	/// after a rekey.
+	pub fn session_id(&self) -> Option<&[u8]> {
+		self.common.encrypted.as_ref().map(|enc| enc.session_id.as_ref())
+	}
+
+	/// The exchange hash of the last key exchange, or `None` before the
+	/// first one has completed.
+	pub fn exchange_hash(&self) -> Option<&[u8]> {
+		self.common.encrypted.as_ref().map(|enc| enc.exchange_hash.as_ref())
+	}
+
+	/// Signature algorithms the server accepts for public key
+	/// authentication, from its `server-sig-algs` extension (RFC 8308).
+	/// `None` if the server didn't send one.
+	pub fn server_sig_algs(&self) -> Option<&[key::Name]> { self.server_sig_algs.as_deref() }
 }
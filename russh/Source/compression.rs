@@ -1,8 +1,13 @@
-#[derive(Debug)]
+/// The compression of one direction of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Compression {
 	None,
+	/// `zlib`, started with the keys of the key exchange.
 	#[cfg(feature = "flate2")]
 	Zlib,
+	/// `zlib@openssh.com`, started once the client is authenticated.
+	#[cfg(feature = "flate2")]
+	ZlibDelayed,
 }
 
 #[derive(Debug)]
@@ -19,37 +24,43 @@ pub enum Decompress {
 	Zlib(flate2::Decompress),
 }
 
+impl Default for Compress {
+	fn default() -> Self { Compress::None }
+}
+
+/// The compression level used unless configured otherwise.
+pub const DEFAULT_LEVEL:u32 = 6;
+
 #[cfg(feature = "flate2")]
 impl Compression {
 	pub fn from_string(s:&str) -> Self {
-		if s == "zlib" || s == "zlib@openssh.com" {
-			Compression::Zlib
-		} else {
-			Compression::None
+		match s {
+			"zlib" => Compression::Zlib,
+			"zlib@openssh.com" => Compression::ZlibDelayed,
+			_ => Compression::None,
 		}
 	}
 
-	pub fn init_compress(&self, comp:&mut Compress) {
-		if let Compression::Zlib = *self {
-			if let Compress::Zlib(ref mut c) = *comp {
-				c.reset()
-			} else {
-				*comp = Compress::Zlib(flate2::Compress::new(flate2::Compression::fast(), true))
-			}
+	/// Whether this compression waits for the client to be
+	/// authenticated.
+	pub fn is_delayed(&self) -> bool { *self == Compression::ZlibDelayed }
+
+	/// Start a new compression stream at `level` (from 0 to 9), or none.
+	/// A stream lasts until the next key exchange.
+	pub fn init_compress(&self, comp:&mut Compress, level:u32) {
+		*comp = if let Compression::None = *self {
+			Compress::None
 		} else {
-			*comp = Compress::None
+			Compress::Zlib(flate2::Compress::new(flate2::Compression::new(level.min(9)), true))
 		}
 	}
 
+	/// Start a new decompression stream, or none.
 	pub fn init_decompress(&self, comp:&mut Decompress) {
-		if let Compression::Zlib = *self {
-			if let Decompress::Zlib(ref mut c) = *comp {
-				c.reset(true)
-			} else {
-				*comp = Decompress::Zlib(flate2::Decompress::new(true))
-			}
+		*comp = if let Compression::None = *self {
+			Decompress::None
 		} else {
-			*comp = Decompress::None
+			Decompress::Zlib(flate2::Decompress::new(true))
 		}
 	}
 }
@@ -58,7 +69,9 @@ impl Compression {
 impl Compression {
 	pub fn from_string(_:&str) -> Self { Compression::None }
 
-	pub fn init_compress(&self, _:&mut Compress) {}
+	pub fn is_delayed(&self) -> bool { false }
+
+	pub fn init_compress(&self, _:&mut Compress, _:u32) {}
 
 	pub fn init_decompress(&self, _:&mut Decompress) {}
 }
@@ -80,6 +93,7 @@ impl Decompress {
 		&mut self,
 		input:&'a [u8],
 		_:&'a mut russh_cryptovec::CryptoVec,
+		_:usize,
 	) -> Result<&'a [u8], crate::Error> {
 		Ok(input)
 	}
@@ -87,6 +101,9 @@ impl Decompress {
 
 #[cfg(feature = "flate2")]
 impl Compress {
+	/// Compress the payload of a packet. Each packet ends with a sync
+	/// flush, so that the peer can decompress it on its own, while the
+	/// stream carries on with the next packet.
 	pub fn compress<'a>(
 		&mut self,
 		input:&'a [u8],
@@ -101,28 +118,30 @@ impl Compress {
 
 				let n_out = z.total_out() as usize;
 
-				output.resize(input.len() + 10);
-
-				let flush = flate2::FlushCompress::Partial;
+				output.resize(input.len() + 64);
 
 				loop {
 					let n_in_ = z.total_in() as usize - n_in;
 
 					let n_out_ = z.total_out() as usize - n_out;
 					#[allow(clippy::indexing_slicing)] // length checked
-					let c = z.compress(&input[n_in_..], &mut output[n_out_..], flush)?;
+					z.compress(
+						&input[n_in_..],
+						&mut output[n_out_..],
+						flate2::FlushCompress::Sync,
+					)?;
+
+					let n_in_ = z.total_in() as usize - n_in;
 
-					match c {
-						flate2::Status::BufError => {
-							output.resize(output.len() * 2);
-						},
-						_ => break,
+					let n_out_ = z.total_out() as usize - n_out;
+					// The flush is complete once it leaves room in the output.
+					if n_in_ >= input.len() && n_out_ < output.len() {
+						#[allow(clippy::indexing_slicing)] // length checked
+						return Ok(&output[..n_out_]);
 					}
-				}
 
-				let n_out_ = z.total_out() as usize - n_out;
-				#[allow(clippy::indexing_slicing)] // length checked
-				Ok(&output[..n_out_])
+					output.resize(output.len() * 2);
+				}
 			},
 		}
 	}
@@ -130,10 +149,12 @@ impl Compress {
 
 #[cfg(feature = "flate2")]
 impl Decompress {
+	/// Decompress the payload of a packet, into at most `limit` bytes.
 	pub fn decompress<'a>(
 		&mut self,
 		input:&'a [u8],
 		output:&'a mut russh_cryptovec::CryptoVec,
+		limit:usize,
 	) -> Result<&'a [u8], crate::Error> {
 		match *self {
 			Decompress::None => Ok(input),
@@ -144,29 +165,380 @@ impl Decompress {
 
 				let n_out = z.total_out() as usize;
 
-				output.resize(input.len());
-
-				let flush = flate2::FlushDecompress::None;
+				output.resize(input.len().clamp(1, limit.max(1)) * 2);
 
 				loop {
 					let n_in_ = z.total_in() as usize - n_in;
 
 					let n_out_ = z.total_out() as usize - n_out;
 					#[allow(clippy::indexing_slicing)] // length checked
-					let d = z.decompress(&input[n_in_..], &mut output[n_out_..], flush);
+					let status = z.decompress(
+						&input[n_in_..],
+						&mut output[n_out_..],
+						flate2::FlushDecompress::Sync,
+					)?;
+
+					let n_in_ = z.total_in() as usize - n_in;
+
+					let n_out_ = z.total_out() as usize - n_out;
 
-					match d? {
-						flate2::Status::Ok => {
-							output.resize(output.len() * 2);
-						},
-						_ => break,
+					if n_out_ > limit {
+						return Err(crate::Error::PacketTooLarge { length:n_out_, limit });
 					}
-				}
 
-				let n_out_ = z.total_out() as usize - n_out;
-				#[allow(clippy::indexing_slicing)] // length checked
-				Ok(&output[..n_out_])
+					if status == flate2::Status::StreamEnd
+						|| (n_in_ >= input.len() && n_out_ < output.len())
+					{
+						#[allow(clippy::indexing_slicing)] // length checked
+						return Ok(&output[..n_out_]);
+					}
+
+					output.resize(output.len() * 2);
+				}
 			},
 		}
 	}
 }
+
+#[cfg(all(test, feature = "flate2"))]
+mod test {
+	use russh_cryptovec::CryptoVec;
+
+	use super::*;
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_stream_across_packets() {
+		let (mut compress, mut decompress) = (Compress::None, Decompress::None);
+
+		Compression::Zlib.init_compress(&mut compress, DEFAULT_LEVEL);
+
+		Compression::Zlib.init_decompress(&mut decompress);
+
+		let (mut compressed, mut decompressed) = (CryptoVec::new(), CryptoVec::new());
+
+		// Repetitive, empty-ish and incompressible payloads, each of which
+		// must be readable on its own.
+		let mut noise = vec![0u8; 40000];
+
+		let mut x = 1u32;
+
+		for b in noise.iter_mut() {
+			x = x.wrapping_mul(1103515245).wrapping_add(12345);
+
+			*b = (x >> 24) as u8;
+		}
+
+		let payloads = [vec![7u8; 40000], vec![5u8], noise, vec![7u8; 40000]];
+
+		for payload in payloads.iter() {
+			let packet = compress.compress(payload, &mut compressed).unwrap().to_vec();
+
+			let out = decompress.decompress(&packet, &mut decompressed, 1 << 20).unwrap();
+
+			assert!(out == &payload[..]);
+		}
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_decompress_limit() {
+		let (mut compress, mut decompress) = (Compress::None, Decompress::None);
+
+		Compression::ZlibDelayed.init_compress(&mut compress, DEFAULT_LEVEL);
+
+		Compression::ZlibDelayed.init_decompress(&mut decompress);
+
+		let (mut compressed, mut decompressed) = (CryptoVec::new(), CryptoVec::new());
+
+		let packet = compress.compress(&[0; 100000], &mut compressed).unwrap().to_vec();
+
+		assert!(matches!(
+			decompress.decompress(&packet, &mut decompressed, 65536),
+			Err(crate::Error::PacketTooLarge { .. })
+		));
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// Data goes through `zlib` and `zlib@openssh.com` compression, with
+	/// a key re-exchange while compression is running.
+	#[cfg(feature = "flate2")]
+	#[tokio::test]
+	async fn test_compression() {
+		use tokio::time::{Duration, timeout};
+
+		const PAYLOAD:usize = 256 * 1024;
+
+		let payload:Vec<u8> = (0..PAYLOAD).map(|i| (i % 251) as u8).collect();
+
+		for compression in ["zlib", "zlib@openssh.com"] {
+			let only:&'static [&'static str] =
+				if compression == "zlib" { &["zlib"] } else { &["zlib@openssh.com"] };
+
+			let preferred = crate::Preferred { compression:only, ..crate::Preferred::DEFAULT };
+
+			let config = client::Config { preferred, compression_level:9, ..Default::default() };
+
+			let (mut client, _server) =
+				connect_with(config, server::Config::default(), Client {}, EchoServer {}).await;
+
+			assert!(client.authenticate_password("user", "password").await.unwrap().success());
+
+			let negotiated = client.negotiated_algorithms().await.unwrap().unwrap();
+
+			assert_eq!(negotiated.client_compression, compression);
+
+			assert_eq!(negotiated.server_compression, compression);
+
+			let mut channel = client.channel_open_session().await.unwrap();
+
+			for round in 0..2 {
+				if round == 1 {
+					client.rekey().await.unwrap();
+				}
+
+				channel.data(&payload[..]).await.unwrap();
+
+				let mut echoed = Vec::new();
+
+				timeout(Duration::from_secs(10), async {
+					while echoed.len() < PAYLOAD {
+						match channel.wait().await {
+							Some(ChannelMsg::Data { data }) => echoed.extend_from_slice(&data),
+							Some(_) => {},
+							None => break,
+						}
+					}
+				})
+				.await
+				.unwrap();
+
+				assert!(echoed == payload, "{} round {}", compression, round);
+			}
+
+			let stats = client.stats();
+
+			assert_eq!(stats.rekeys, 1);
+
+			// The payload is repetitive enough to be compressed well.
+			assert!(stats.bytes_sent < stats.payload_bytes_sent / 4);
+
+			assert!(stats.bytes_received < stats.payload_bytes_received / 4);
+		}
+	}
+
+	/// OpenSSH's `ssh`, with compression and frequent key re-exchanges,
+	/// runs a command on a russh server. Run with `cargo test -- --ignored`,
+	/// with `ssh` in the `PATH`.
+	#[cfg(all(unix, feature = "flate2"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_compression_openssh_client() {
+		use std::sync::Arc;
+
+		use tokio::{net::TcpListener, process::Command, sync::oneshot};
+
+		const OUTPUT:usize = 4 * 1024 * 1024;
+
+		/// Answers any command with `OUTPUT` bytes.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				let output:Vec<u8> = (0..OUTPUT).map(|i| (i % 251) as u8).collect();
+
+				for chunk in output.chunks(65536) {
+					session.data(channel, CryptoVec::from_slice(chunk))?;
+				}
+
+				session.exit_status_request(channel, 0);
+
+				session.eof(channel);
+
+				session.close(channel);
+
+				Ok((self, session))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		let (stats, stats_receiver) = oneshot::channel();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			let session =
+				server::run_stream(Arc::new(config), stream, ServerHandle {}).await.unwrap();
+
+			let handle = session.handle();
+
+			session.await.ok();
+
+			stats.send(handle.stats()).ok();
+		});
+
+		let output = Command::new("ssh")
+			.args(["-p", &port.to_string()])
+			.args(["-o", "Compression=yes"])
+			.args(["-o", "RekeyLimit=256K"])
+			.args(["-o", "BatchMode=yes"])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.arg("user@127.0.0.1")
+			.arg("output")
+			.output()
+			.await
+			.unwrap();
+
+		assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+		assert_eq!(output.stdout.len(), OUTPUT);
+
+		assert!(output.stdout.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+		let stats = stats_receiver.await.unwrap();
+
+		assert!(stats.rekeys > 0);
+
+		assert!(stats.bytes_sent < stats.payload_bytes_sent / 4);
+	}
+
+	/// A russh client runs commands on OpenSSH's sshd, with compression
+	/// and frequent key re-exchanges. Run with `cargo test -- --ignored`,
+	/// with `sshd` and `ssh-keygen` in the `PATH`.
+	#[cfg(feature = "flate2")]
+	#[tokio::test]
+	#[ignore]
+	async fn test_compression_sshd() {
+		use std::{process::Command, sync::Arc};
+
+		const OUTPUT:usize = 4 * 1024 * 1024;
+
+		let dir = std::env::temp_dir().join(format!("russh-sshd-zlib-{}", std::process::id()));
+
+		std::fs::create_dir_all(&dir).unwrap();
+
+		for name in ["host", "user"] {
+			let status = Command::new("ssh-keygen")
+				.args(["-q", "-t", "ed25519", "-N", "", "-f"])
+				.arg(dir.join(name))
+				.status()
+				.unwrap();
+
+			assert!(status.success());
+		}
+
+		std::fs::copy(dir.join("user.pub"), dir.join("authorized_keys")).unwrap();
+
+		let port = 2223;
+
+		let config = format!(
+			"Port {}\nListenAddress 127.0.0.1\nHostKey {}\nAuthorizedKeysFile {}\n\
+			 PidFile none\nStrictModes no\nUsePAM no\nCompression yes\nRekeyLimit 256K\n",
+			port,
+			dir.join("host").display(),
+			dir.join("authorized_keys").display(),
+		);
+
+		std::fs::write(dir.join("sshd_config"), config).unwrap();
+
+		let sshd = Command::new("which").arg("sshd").output().unwrap();
+
+		let sshd = String::from_utf8(sshd.stdout).unwrap();
+
+		let mut sshd = Command::new(sshd.trim())
+			.args(["-D", "-e", "-f"])
+			.arg(dir.join("sshd_config"))
+			.spawn()
+			.unwrap();
+
+		tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+		let key = Arc::new(russh_keys::load_secret_key(dir.join("user"), None).unwrap());
+
+		let config =
+			client::Config { preferred:crate::Preferred::COMPRESSED, ..Default::default() };
+
+		let mut session = client::connect(Arc::new(config), ("127.0.0.1", port), Client {})
+			.await
+			.unwrap();
+
+		let user = std::env::var("USER").unwrap();
+
+		let authenticated = session.authenticate_publickey(user, key).await.unwrap().success();
+
+		let negotiated = session.negotiated_algorithms().await.unwrap().unwrap();
+
+		let mut outputs = Vec::new();
+
+		for round in 0..2 {
+			if round == 1 {
+				session.rekey().await.unwrap();
+			}
+
+			let mut channel = session.channel_open_session().await.unwrap();
+
+			channel.exec(true, format!("head -c {} /dev/zero", OUTPUT)).await.unwrap();
+
+			let mut output = 0;
+
+			while let Some(msg) = channel.wait().await {
+				if let ChannelMsg::Data { data } = msg {
+					output += data.len();
+				}
+			}
+
+			outputs.push(output);
+		}
+
+		let stats = session.stats();
+
+		sshd.kill().ok();
+
+		std::fs::remove_dir_all(dir).ok();
+
+		assert!(authenticated);
+
+		assert_eq!(negotiated.server_compression, "zlib@openssh.com");
+
+		assert_eq!(outputs, [OUTPUT, OUTPUT]);
+
+		assert!(stats.rekeys > 1);
+
+		assert!(stats.bytes_received < stats.payload_bytes_received / 4);
+	}
+}
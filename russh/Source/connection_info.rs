@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+
+/// What is known of a connection once the version strings are exchanged,
+/// see [`crate::server::Session::connection_info`] and
+/// [`crate::client::Session::connection_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+	/// The address of the peer, or `None` if the session runs on a stream
+	/// that is not a TCP connection, as with
+	/// [`crate::server::run_stream`] and [`crate::client::connect_stream`].
+	pub peer_addr:Option<SocketAddr>,
+	/// The identification string of the client, without its line ending,
+	/// e.g. `SSH-2.0-OpenSSH_9.6`.
+	pub client_version:String,
+	/// The identification string of the server, without its line ending.
+	pub server_version:String,
+}
+
+impl ConnectionInfo {
+	pub(crate) fn new(
+		peer_addr:Option<SocketAddr>,
+		client_version:&[u8],
+		server_version:&[u8],
+	) -> Self {
+		ConnectionInfo {
+			peer_addr,
+			client_version:String::from_utf8_lossy(client_version).into_owned(),
+			server_version:String::from_utf8_lossy(server_version).into_owned(),
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_connection_info() {
+		use crate::ConnectionInfo;
+
+		struct Client {
+			info:Option<tokio::sync::oneshot::Sender<ConnectionInfo>>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn auth_banner(
+				mut self,
+				_:&str,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				if let Some(tx) = self.info.take() {
+					tx.send(session.connection_info().clone()).unwrap();
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			info:Option<tokio::sync::oneshot::Sender<ConnectionInfo>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn auth_succeeded(
+				mut self,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if let Some(tx) = self.info.take() {
+					tx.send(session.connection_info().clone()).unwrap();
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let client_config = client::Config {
+			client_id:crate::SshId::Standard("SSH-2.0-Client_1.0 comment".to_string()),
+			..Default::default()
+		};
+
+		let server_config = server::Config {
+			server_id:crate::SshId::Raw("SSH-2.0-Server_2.0\r\n".to_string()),
+			auth_banner:Some("banner".to_string()),
+			..Default::default()
+		};
+
+		let (client_tx, client_rx) = tokio::sync::oneshot::channel();
+
+		let (server_tx, server_rx) = tokio::sync::oneshot::channel();
+
+		test_session_with_configs(
+			client_config,
+			server_config,
+			Client { info:Some(client_tx) },
+			ServerHandle { info:Some(server_tx) },
+			|c| {
+				async move {
+					let info = client_rx.await.unwrap();
+
+					assert_eq!(info.client_version, "SSH-2.0-Client_1.0 comment");
+
+					assert_eq!(info.server_version, "SSH-2.0-Server_2.0");
+
+					// The client connects with `client::connect`.
+					assert!(info.peer_addr.unwrap().ip().is_loopback());
+
+					c
+				}
+			},
+			|s| {
+				async move {
+					let info = server_rx.await.unwrap();
+
+					assert_eq!(info.client_version, "SSH-2.0-Client_1.0 comment");
+
+					assert_eq!(info.server_version, "SSH-2.0-Server_2.0");
+
+					// The server runs with `server::run_stream`.
+					assert_eq!(info.peer_addr, None);
+
+					s
+				}
+			},
+		)
+		.await;
+	}
+}
@@ -7,6 +7,7 @@ use curve25519_dalek::{
 use log::debug;
 use russh_cryptovec::CryptoVec;
 use russh_keys::encoding::Encoding;
+use zeroize::Zeroize;
 
 use super::{KexAlgorithm, KexType, compute_keys};
 use crate::{
@@ -31,6 +32,18 @@ pub struct Curve25519Kex {
 	shared_secret:Option<MontgomeryPoint>,
 }
 
+impl Drop for Curve25519Kex {
+	fn drop(&mut self) {
+		if let Some(ref mut local_secret) = self.local_secret {
+			local_secret.zeroize();
+		}
+
+		if let Some(ref mut shared_secret) = self.shared_secret {
+			shared_secret.0.zeroize();
+		}
+	}
+}
+
 impl std::fmt::Debug for Curve25519Kex {
 	fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
 		write!(f, "Algorithm {{ local_secret: [hidden], shared_secret: [hidden] }}",)
@@ -169,9 +182,9 @@ impl KexAlgorithm for Curve25519Kex {
 		&self,
 		session_id:&CryptoVec,
 		exchange_hash:&CryptoVec,
-		cipher:cipher::Name,
-		remote_to_local_mac:mac::Name,
-		local_to_remote_mac:mac::Name,
+		cipher:&(dyn cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
 		is_server:bool,
 	) -> Result<super::cipher::CipherPair, crate::Error> {
 		compute_keys::<sha2::Sha256>(
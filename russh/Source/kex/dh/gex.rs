@@ -0,0 +1,535 @@
+// https://tools.ietf.org/html/rfc4419
+use std::marker::PhantomData;
+
+use digest::Digest;
+use log::debug;
+use num_bigint::{BigUint, RandBigInt};
+use russh_cryptovec::CryptoVec;
+use russh_keys::encoding::{Encoding, Reader};
+use zeroize::Zeroize;
+
+use super::{
+	client_dh_in,
+	groups::{DH, DH_GROUP14, DH_GROUP15, DH_GROUP16, DhGroup},
+	server_dh_in,
+	shared_secret_in,
+};
+use crate::{
+	cipher,
+	kex::{KexAlgorithm, compute_keys},
+	mac,
+	msg,
+	session::Exchange,
+};
+
+/// Sizes of the group requested by the client during a
+/// `diffie-hellman-group-exchange-*` key exchange, in bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GexParams {
+	/// Smallest acceptable group.
+	pub min_group_size:u32,
+	/// Size of the group to ask for.
+	pub preferred_group_size:u32,
+	/// Largest acceptable group.
+	pub max_group_size:u32,
+}
+
+impl Default for GexParams {
+	fn default() -> Self {
+		GexParams { min_group_size:2048, preferred_group_size:3072, max_group_size:8192 }
+	}
+}
+
+impl GexParams {
+	fn is_valid(&self) -> bool {
+		self.min_group_size <= self.preferred_group_size
+			&& self.preferred_group_size <= self.max_group_size
+	}
+}
+
+/// Groups the server chooses from, by increasing size.
+const SERVER_GROUPS:&[&DhGroup] = &[&DH_GROUP14, &DH_GROUP15, &DH_GROUP16];
+
+/// The smallest group of at least the preferred size, or else the
+/// largest group within bounds.
+fn select_group(params:&GexParams) -> Option<&'static DhGroup> {
+	let in_bounds = SERVER_GROUPS.iter().copied().filter(|group| {
+		let bits = group.prime.len() as u32 * 8;
+
+		params.min_group_size <= bits && bits <= params.max_group_size
+	});
+
+	let mut largest = None;
+
+	for group in in_bounds {
+		if group.prime.len() as u32 * 8 >= params.preferred_group_size {
+			return Some(group);
+		}
+
+		largest = Some(group);
+	}
+
+	largest
+}
+
+const SMALL_PRIMES:&[u32] = &[
+	3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101,
+	103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197,
+	199, 211, 223, 227, 229, 233, 239, 241, 251,
+];
+
+/// Miller-Rabin rounds on `(p - 1) / 2`. A composite number passes each
+/// of them with probability at most 1/4.
+const MILLER_RABIN_ROUNDS:usize = 16;
+
+/// Miller-Rabin test of an odd `n` larger than 3, with random bases.
+fn is_probable_prime(n:&BigUint, rounds:usize) -> bool {
+	let one = BigUint::from(1u8);
+
+	let two = BigUint::from(2u8);
+
+	let n_minus_one = n - &one;
+
+	// `n - 1 = d * 2^s`, with `d` odd.
+	let s = n_minus_one.trailing_zeros().unwrap_or(0);
+
+	let d = &n_minus_one >> s;
+
+	let mut rng = rand::thread_rng();
+
+	'rounds: for _ in 0..rounds {
+		let mut x = rng.gen_biguint_range(&two, &n_minus_one).modpow(&d, n);
+
+		if x == one || x == n_minus_one {
+			continue;
+		}
+
+		for _ in 1..s {
+			x = &x * &x % n;
+
+			if x == n_minus_one {
+				continue 'rounds;
+			}
+		}
+
+		return false;
+	}
+
+	true
+}
+
+/// Checks that `p` is a safe prime, i.e. that `p` and `q = (p - 1) / 2`
+/// are prime. After screening small factors, `q` goes through
+/// Miller-Rabin tests, and `p` through a Fermat test to base 2. When `q`
+/// is prime, the latter proves that `p` is prime too, by Pocklington's
+/// criterion: `q` is larger than the square root of `p`, and 3 does not
+/// divide `p`.
+fn is_probable_safe_prime(p:&BigUint) -> bool {
+	let one = BigUint::from(1u8);
+
+	let two = BigUint::from(2u8);
+
+	// `(p - 1) / 2` must be odd.
+	if p % 4u8 != BigUint::from(3u8) {
+		return false;
+	}
+
+	let q:BigUint = (p - &one) / &two;
+
+	for &small in SMALL_PRIMES {
+		if (p % small) == BigUint::from(0u8) || (&q % small) == BigUint::from(0u8) {
+			return false;
+		}
+	}
+
+	is_probable_prime(&q, MILLER_RABIN_ROUNDS) && two.modpow(&(p - &one), p) == one
+}
+
+#[doc(hidden)]
+pub struct DhGexKex<D:Digest> {
+	/// The sizes requested by the client.
+	params:Option<GexParams>,
+	/// The group chosen by the server.
+	dh:Option<DH>,
+	shared_secret:Option<Vec<u8>>,
+	_digest:PhantomData<D>,
+}
+
+impl<D:Digest> Drop for DhGexKex<D> {
+	fn drop(&mut self) {
+		if let Some(ref mut shared_secret) = self.shared_secret {
+			shared_secret.zeroize();
+		}
+	}
+}
+
+impl<D:Digest> Default for DhGexKex<D> {
+	fn default() -> DhGexKex<D> {
+		DhGexKex { params:None, dh:None, shared_secret:None, _digest:PhantomData }
+	}
+}
+
+impl<D:Digest> std::fmt::Debug for DhGexKex<D> {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"Algorithm {{ params: {:?}, local_secret: [hidden], shared_secret: [hidden] }}",
+			self.params
+		)
+	}
+}
+
+impl<D:Digest> KexAlgorithm for DhGexKex<D> {
+	fn skip_exchange(&self) -> bool { false }
+
+	fn is_group_exchange(&self) -> bool { true }
+
+	fn client_gex_request(
+		&mut self,
+		params:&GexParams,
+		buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		if !params.is_valid() {
+			return Err(crate::Error::InvalidDhGroup);
+		}
+
+		self.params = Some(*params);
+
+		buf.push(msg::KEX_DH_GEX_REQUEST);
+
+		buf.push_u32_be(params.min_group_size);
+
+		buf.push_u32_be(params.preferred_group_size);
+
+		buf.push_u32_be(params.max_group_size);
+
+		Ok(())
+	}
+
+	fn client_gex_group(&mut self, payload:&[u8]) -> Result<(), crate::Error> {
+		let params = match (self.params, &self.dh) {
+			(Some(params), None) => params,
+			_ => return Err(crate::Error::Inconsistent),
+		};
+
+		if payload.first() != Some(&msg::KEX_DH_GEX_GROUP) {
+			return Err(crate::Error::Inconsistent);
+		}
+
+		let mut r = payload.reader(1);
+
+		let prime = BigUint::from_bytes_be(r.read_mpint()?);
+
+		let generator = BigUint::from_bytes_be(r.read_mpint()?);
+
+		let bits = prime.bits();
+
+		if bits < params.min_group_size as u64 || bits > params.max_group_size as u64 {
+			debug!("group of {} bits out of the requested bounds {:?}", bits, params);
+
+			return Err(crate::Error::InvalidDhGroup);
+		}
+
+		let one = BigUint::from(1u8);
+
+		if generator <= one || generator >= &prime - &one || !is_probable_safe_prime(&prime) {
+			return Err(crate::Error::InvalidDhGroup);
+		}
+
+		self.dh = Some(DH::from_prime(prime, generator));
+
+		Ok(())
+	}
+
+	fn server_gex_group(&mut self, payload:&[u8], buf:&mut CryptoVec) -> Result<(), crate::Error> {
+		if self.dh.is_some() || payload.first() != Some(&msg::KEX_DH_GEX_REQUEST) {
+			return Err(crate::Error::Inconsistent);
+		}
+
+		let mut r = payload.reader(1);
+
+		let params = GexParams {
+			min_group_size:r.read_u32()?,
+			preferred_group_size:r.read_u32()?,
+			max_group_size:r.read_u32()?,
+		};
+
+		if !params.is_valid() {
+			return Err(crate::Error::InvalidDhGroup);
+		}
+
+		let group = select_group(&params).ok_or(crate::Error::InvalidDhGroup)?;
+
+		debug!("group of {} bits for request {:?}", group.prime.len() * 8, params);
+
+		let dh = DH::new(group);
+
+		buf.push(msg::KEX_DH_GEX_GROUP);
+
+		buf.extend_ssh_mpint(&dh.prime().to_bytes_be());
+
+		buf.extend_ssh_mpint(&dh.generator().to_bytes_be());
+
+		self.params = Some(params);
+
+		self.dh = Some(dh);
+
+		Ok(())
+	}
+
+	#[doc(hidden)]
+	fn server_dh(&mut self, exchange:&mut Exchange, payload:&[u8]) -> Result<(), crate::Error> {
+		let dh = self.dh.as_mut().ok_or(crate::Error::Inconsistent)?;
+
+		self.shared_secret = Some(server_dh_in(dh, msg::KEX_DH_GEX_INIT, exchange, payload)?);
+
+		Ok(())
+	}
+
+	#[doc(hidden)]
+	fn client_dh(
+		&mut self,
+		client_ephemeral:&mut CryptoVec,
+		buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		let dh = self.dh.as_mut().ok_or(crate::Error::Inconsistent)?;
+
+		client_dh_in(dh, msg::KEX_DH_GEX_INIT, client_ephemeral, buf)
+	}
+
+	fn compute_shared_secret(&mut self, remote_pubkey_:&[u8]) -> Result<(), crate::Error> {
+		let dh = self.dh.as_mut().ok_or(crate::Error::Inconsistent)?;
+
+		self.shared_secret = Some(shared_secret_in(dh, remote_pubkey_)?);
+
+		Ok(())
+	}
+
+	fn compute_exchange_hash(
+		&self,
+		key:&CryptoVec,
+		exchange:&Exchange,
+		buffer:&mut CryptoVec,
+	) -> Result<CryptoVec, crate::Error> {
+		let (params, dh) = match (self.params, &self.dh) {
+			(Some(params), Some(dh)) => (params, dh),
+			_ => return Err(crate::Error::Inconsistent),
+		};
+		// Computing the exchange hash, see page 3 of RFC 4419.
+		buffer.clear();
+
+		buffer.extend_ssh_string(&exchange.client_id);
+
+		buffer.extend_ssh_string(&exchange.server_id);
+
+		buffer.extend_ssh_string(&exchange.client_kex_init);
+
+		buffer.extend_ssh_string(&exchange.server_kex_init);
+
+		buffer.extend(key);
+
+		buffer.push_u32_be(params.min_group_size);
+
+		buffer.push_u32_be(params.preferred_group_size);
+
+		buffer.push_u32_be(params.max_group_size);
+
+		buffer.extend_ssh_mpint(&dh.prime().to_bytes_be());
+
+		buffer.extend_ssh_mpint(&dh.generator().to_bytes_be());
+
+		buffer.extend_ssh_string(&exchange.client_ephemeral);
+
+		buffer.extend_ssh_string(&exchange.server_ephemeral);
+
+		if let Some(ref shared) = self.shared_secret {
+			buffer.extend_ssh_mpint(shared);
+		}
+
+		let mut hasher = D::new();
+
+		hasher.update(&buffer);
+
+		let mut res = CryptoVec::new();
+
+		res.extend(hasher.finalize().as_slice());
+
+		Ok(res)
+	}
+
+	fn compute_keys(
+		&self,
+		session_id:&CryptoVec,
+		exchange_hash:&CryptoVec,
+		cipher:&(dyn cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		is_server:bool,
+	) -> Result<cipher::CipherPair, crate::Error> {
+		compute_keys::<D>(
+			self.shared_secret.as_deref(),
+			session_id,
+			exchange_hash,
+			cipher,
+			remote_to_local_mac,
+			local_to_remote_mac,
+			is_server,
+		)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_select_group() {
+		let select = |min, preferred, max| {
+			select_group(&GexParams {
+				min_group_size:min,
+				preferred_group_size:preferred,
+				max_group_size:max,
+			})
+			.map(|group| group.prime.len() * 8)
+		};
+
+		assert_eq!(select(2048, 3072, 8192), Some(3072));
+
+		assert_eq!(select(2048, 8192, 8192), Some(4096));
+
+		assert_eq!(select(1024, 1024, 2048), Some(2048));
+
+		assert_eq!(select(1024, 1024, 1536), None);
+	}
+
+	#[test]
+	fn test_builtin_groups_are_safe_primes() {
+		for group in SERVER_GROUPS {
+			assert!(is_probable_safe_prime(&BigUint::from_bytes_be(group.prime)));
+		}
+
+		// The prime of group 14, with its last bit cleared.
+		let mut even = DH_GROUP14.prime.to_vec();
+
+		if let Some(last) = even.last_mut() {
+			*last &= !1;
+		}
+
+		assert!(!is_probable_safe_prime(&BigUint::from_bytes_be(&even)));
+
+		// A prime without small factors, but `(p - 1) / 2 = 257 * 283`.
+		assert!(!is_probable_safe_prime(&BigUint::from(145463u32)));
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_client_rejects_small_group() {
+		let mut client = DhGexKex::<sha2::Sha256>::default();
+
+		let mut request = CryptoVec::new();
+
+		client.client_gex_request(&GexParams::default(), &mut request).unwrap();
+
+		// A server that only has 1024-bit groups.
+		let mut group = CryptoVec::new();
+
+		group.push(msg::KEX_DH_GEX_GROUP);
+
+		group.extend_ssh_mpint(super::super::groups::DH_GROUP1.prime);
+
+		group.extend_ssh_mpint(&[2]);
+
+		assert!(matches!(client.client_gex_group(&group), Err(crate::Error::InvalidDhGroup)));
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_gex_shared_secret() {
+		let mut client = DhGexKex::<sha2::Sha256>::default();
+
+		let mut server = DhGexKex::<sha2::Sha256>::default();
+
+		let params =
+			GexParams { min_group_size:2048, preferred_group_size:2048, max_group_size:2048 };
+
+		let mut request = CryptoVec::new();
+
+		client.client_gex_request(&params, &mut request).unwrap();
+
+		let mut group = CryptoVec::new();
+
+		server.server_gex_group(&request, &mut group).unwrap();
+
+		client.client_gex_group(&group).unwrap();
+
+		let mut exchange = Exchange::new();
+
+		let mut init = CryptoVec::new();
+
+		client.client_dh(&mut exchange.client_ephemeral, &mut init).unwrap();
+
+		assert_eq!(init.first(), Some(&msg::KEX_DH_GEX_INIT));
+
+		server.server_dh(&mut exchange, &init).unwrap();
+
+		client.compute_shared_secret(&exchange.server_ephemeral).unwrap();
+
+		assert_eq!(client.shared_secret, server.shared_secret);
+
+		let key = CryptoVec::from_slice(b"host key");
+
+		let mut buffer = CryptoVec::new();
+
+		let client_hash = client.compute_exchange_hash(&key, &exchange, &mut buffer).unwrap();
+
+		let server_hash = server.compute_exchange_hash(&key, &exchange, &mut buffer).unwrap();
+
+		assert_eq!(&client_hash[..], &server_hash[..]);
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_dh_group_exchange() {
+
+		const PREFERRED:crate::Preferred =
+			crate::Preferred { kex:&[kex::DH_GEX_SHA256], ..crate::Preferred::DEFAULT };
+
+		let client_config = client::Config {
+			preferred:PREFERRED,
+			gex:kex::GexParams {
+				min_group_size:2048,
+				preferred_group_size:2048,
+				max_group_size:8192,
+			},
+			..Default::default()
+		};
+
+		let server_config = server::Config { preferred:PREFERRED, ..Default::default() };
+
+		test_session_with_configs(
+			client_config,
+			server_config,
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.kex, kex::DH_GEX_SHA256);
+
+					// Group exchange also works within an encrypted session.
+					c.rekey().await.unwrap();
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+}
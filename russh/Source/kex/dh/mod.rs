@@ -1,3 +1,4 @@
+pub(crate) mod gex;
 mod groups;
 use std::marker::PhantomData;
 
@@ -9,9 +10,10 @@ use num_bigint::BigUint;
 use russh_cryptovec::CryptoVec;
 use russh_keys::encoding::Encoding;
 use sha1::Sha1;
-use sha2::Sha256;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroize;
 
-use self::groups::{DH_GROUP1, DH_GROUP14, DhGroup};
+use self::groups::{DH_GROUP1, DH_GROUP14, DH_GROUP15, DH_GROUP16, DH_GROUP18, DhGroup};
 use super::{KexAlgorithm, KexType, compute_keys};
 use crate::{cipher, mac, msg, session::Exchange};
 
@@ -36,6 +38,34 @@ impl KexType for DhGroup14Sha256KexType {
 		Box::new(DhGroupKex::<Sha256>::new(&DH_GROUP14)) as Box<dyn KexAlgorithm + Send>
 	}
 }
+pub struct DhGroup15Sha512KexType {}
+
+impl KexType for DhGroup15Sha512KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(DhGroupKex::<Sha512>::new(&DH_GROUP15)) as Box<dyn KexAlgorithm + Send>
+	}
+}
+pub struct DhGroup16Sha512KexType {}
+
+impl KexType for DhGroup16Sha512KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(DhGroupKex::<Sha512>::new(&DH_GROUP16)) as Box<dyn KexAlgorithm + Send>
+	}
+}
+pub struct DhGroup18Sha512KexType {}
+
+impl KexType for DhGroup18Sha512KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(DhGroupKex::<Sha512>::new(&DH_GROUP18)) as Box<dyn KexAlgorithm + Send>
+	}
+}
+pub struct DhGexSha256KexType {}
+
+impl KexType for DhGexSha256KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(gex::DhGexKex::<Sha256>::default()) as Box<dyn KexAlgorithm + Send>
+	}
+}
 
 #[doc(hidden)]
 pub struct DhGroupKex<D:Digest> {
@@ -44,6 +74,14 @@ pub struct DhGroupKex<D:Digest> {
 	_digest:PhantomData<D>,
 }
 
+impl<D:Digest> Drop for DhGroupKex<D> {
+	fn drop(&mut self) {
+		if let Some(ref mut shared_secret) = self.shared_secret {
+			shared_secret.zeroize();
+		}
+	}
+}
+
 impl<D:Digest> DhGroupKex<D> {
 	pub fn new(group:&DhGroup) -> DhGroupKex<D> {
 		let dh = DH::new(group);
@@ -74,104 +112,119 @@ fn biguint_to_mpint(biguint:&BigUint) -> Vec<u8> {
 	mpint
 }
 
-impl<D:Digest> KexAlgorithm for DhGroupKex<D> {
-	fn skip_exchange(&self) -> bool { false }
+/// Server side of the exchange in `dh`: reads the client's public key from
+/// the `init_msg` packet, fills in the server's, and returns the shared
+/// secret.
+fn server_dh_in(
+	dh:&mut DH,
+	init_msg:u8,
+	exchange:&mut Exchange,
+	payload:&[u8],
+) -> Result<Vec<u8>, crate::Error> {
+	debug!("server_dh");
+
+	let client_pubkey = {
+		if payload.first() != Some(&init_msg) {
+			return Err(crate::Error::Inconsistent);
+		}
 
-	#[doc(hidden)]
-	fn server_dh(&mut self, exchange:&mut Exchange, payload:&[u8]) -> Result<(), crate::Error> {
-		debug!("server_dh");
+		let pubkey_len = BigEndian::read_u32(payload.get(1..5).ok_or(crate::Error::Inconsistent)?)
+			as usize;
 
-		let client_pubkey = {
-			if payload.first() != Some(&msg::KEX_ECDH_INIT) {
-				return Err(crate::Error::Inconsistent);
-			}
+		if payload.len() < 5 + pubkey_len {
+			return Err(crate::Error::Inconsistent);
+		}
 
-			#[allow(clippy::indexing_slicing)] // length checked
-			let pubkey_len = BigEndian::read_u32(&payload[1..]) as usize;
+		&payload.get(5..(5 + pubkey_len)).ok_or(crate::Error::Inconsistent)?
+	};
 
-			if payload.len() < 5 + pubkey_len {
-				return Err(crate::Error::Inconsistent);
-			}
+	debug!("client_pubkey: {:?}", client_pubkey);
 
-			&payload.get(5..(5 + pubkey_len)).ok_or(crate::Error::Inconsistent)?
-		};
+	dh.generate_private_key(true);
 
-		debug!("client_pubkey: {:?}", client_pubkey);
+	let server_pubkey = &dh.generate_public_key();
 
-		self.dh.generate_private_key(true);
+	if !dh.validate_public_key(server_pubkey) {
+		return Err(crate::Error::Inconsistent);
+	}
 
-		let server_pubkey = &self.dh.generate_public_key();
+	let encoded_server_pubkey = biguint_to_mpint(server_pubkey);
 
-		if !self.dh.validate_public_key(server_pubkey) {
-			return Err(crate::Error::Inconsistent);
-		}
+	// fill exchange.
+	exchange.server_ephemeral.clear();
 
-		let encoded_server_pubkey = biguint_to_mpint(server_pubkey);
+	exchange.server_ephemeral.extend(&encoded_server_pubkey);
 
-		// fill exchange.
-		exchange.server_ephemeral.clear();
+	shared_secret_in(dh, client_pubkey)
+}
 
-		exchange.server_ephemeral.extend(&encoded_server_pubkey);
+/// Client side of the exchange in `dh`: writes the client's public key in
+/// an `init_msg` packet.
+fn client_dh_in(
+	dh:&mut DH,
+	init_msg:u8,
+	client_ephemeral:&mut CryptoVec,
+	buf:&mut CryptoVec,
+) -> Result<(), crate::Error> {
+	dh.generate_private_key(false);
 
-		let decoded_client_pubkey = DH::decode_public_key(client_pubkey);
+	let client_pubkey = &dh.generate_public_key();
 
-		if !self.dh.validate_public_key(&decoded_client_pubkey) {
-			return Err(crate::Error::Inconsistent);
-		}
+	if !dh.validate_public_key(client_pubkey) {
+		return Err(crate::Error::Inconsistent);
+	}
 
-		let shared = self.dh.compute_shared_secret(decoded_client_pubkey);
+	// fill exchange.
+	let encoded_pubkey = biguint_to_mpint(client_pubkey);
 
-		if !self.dh.validate_shared_secret(&shared) {
-			return Err(crate::Error::Inconsistent);
-		}
+	client_ephemeral.clear();
 
-		self.shared_secret = Some(biguint_to_mpint(&shared));
+	client_ephemeral.extend(&encoded_pubkey);
 
-		Ok(())
-	}
+	buf.push(init_msg);
 
-	#[doc(hidden)]
-	fn client_dh(
-		&mut self,
-		client_ephemeral:&mut CryptoVec,
-		buf:&mut CryptoVec,
-	) -> Result<(), crate::Error> {
-		self.dh.generate_private_key(false);
+	buf.extend_ssh_string(&encoded_pubkey);
 
-		let client_pubkey = &self.dh.generate_public_key();
+	Ok(())
+}
 
-		if !self.dh.validate_public_key(client_pubkey) {
-			return Err(crate::Error::Inconsistent);
-		}
+fn shared_secret_in(dh:&mut DH, remote_pubkey:&[u8]) -> Result<Vec<u8>, crate::Error> {
+	let remote_pubkey = DH::decode_public_key(remote_pubkey);
 
-		// fill exchange.
-		let encoded_pubkey = biguint_to_mpint(client_pubkey);
+	if !dh.validate_public_key(&remote_pubkey) {
+		return Err(crate::Error::Inconsistent);
+	}
+
+	let shared = dh.compute_shared_secret(remote_pubkey);
 
-		client_ephemeral.clear();
+	if !dh.validate_shared_secret(&shared) {
+		return Err(crate::Error::Inconsistent);
+	}
 
-		client_ephemeral.extend(&encoded_pubkey);
+	Ok(biguint_to_mpint(&shared))
+}
 
-		buf.push(msg::KEX_ECDH_INIT);
+impl<D:Digest> KexAlgorithm for DhGroupKex<D> {
+	fn skip_exchange(&self) -> bool { false }
 
-		buf.extend_ssh_string(&encoded_pubkey);
+	#[doc(hidden)]
+	fn server_dh(&mut self, exchange:&mut Exchange, payload:&[u8]) -> Result<(), crate::Error> {
+		self.shared_secret = Some(server_dh_in(&mut self.dh, msg::KEX_ECDH_INIT, exchange, payload)?);
 
 		Ok(())
 	}
 
-	fn compute_shared_secret(&mut self, remote_pubkey_:&[u8]) -> Result<(), crate::Error> {
-		let remote_pubkey = DH::decode_public_key(remote_pubkey_);
-
-		if !self.dh.validate_public_key(&remote_pubkey) {
-			return Err(crate::Error::Inconsistent);
-		}
-
-		let shared = self.dh.compute_shared_secret(remote_pubkey);
-
-		if !self.dh.validate_shared_secret(&shared) {
-			return Err(crate::Error::Inconsistent);
-		}
+	#[doc(hidden)]
+	fn client_dh(
+		&mut self,
+		client_ephemeral:&mut CryptoVec,
+		buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		client_dh_in(&mut self.dh, msg::KEX_ECDH_INIT, client_ephemeral, buf)
+	}
 
-		self.shared_secret = Some(biguint_to_mpint(&shared));
+	fn compute_shared_secret(&mut self, remote_pubkey_:&[u8]) -> Result<(), crate::Error> {
+		self.shared_secret = Some(shared_secret_in(&mut self.dh, remote_pubkey_)?);
 
 		Ok(())
 	}
@@ -218,9 +271,9 @@ impl<D:Digest> KexAlgorithm for DhGroupKex<D> {
 		&self,
 		session_id:&CryptoVec,
 		exchange_hash:&CryptoVec,
-		cipher:cipher::Name,
-		remote_to_local_mac:mac::Name,
-		local_to_remote_mac:mac::Name,
+		cipher:&(dyn cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
 		is_server:bool,
 	) -> Result<super::cipher::CipherPair, crate::Error> {
 		compute_keys::<D>(
@@ -234,3 +287,87 @@ impl<D:Digest> KexAlgorithm for DhGroupKex<D> {
 		)
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_large_groups_agree() {
+		for group in [&DH_GROUP15, &DH_GROUP16, &DH_GROUP18].iter() {
+			let mut client = DhGroupKex::<Sha512>::new(group);
+
+			let mut server = DhGroupKex::<Sha512>::new(group);
+
+			let mut exchange = Exchange::new();
+
+			let mut init = CryptoVec::new();
+
+			client.client_dh(&mut exchange.client_ephemeral, &mut init).unwrap();
+
+			server.server_dh(&mut exchange, &init).unwrap();
+
+			client.compute_shared_secret(&exchange.server_ephemeral).unwrap();
+
+			assert!(client.shared_secret.is_some());
+
+			assert_eq!(client.shared_secret, server.shared_secret);
+		}
+	}
+
+	#[test]
+	fn test_rejects_out_of_range_public_key() {
+		let mut dh = DH::new(&DH_GROUP16);
+
+		dh.generate_private_key(false);
+
+		let p = BigUint::from_bytes_be(DH_GROUP16.prime);
+
+		for public_key in [BigUint::from(0u8), BigUint::from(1u8), &p - 1u8, p.clone()] {
+			assert!(matches!(
+				shared_secret_in(&mut dh, &public_key.to_bytes_be()),
+				Err(crate::Error::Inconsistent)
+			));
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_large_dh_groups() {
+
+		const GROUPS:&[&[kex::Name]] =
+			&[&[kex::DH_G15_SHA512], &[kex::DH_G16_SHA512], &[kex::DH_G18_SHA512]];
+
+		for &group in GROUPS {
+			// Only the server is pinned to the group.
+			let server_config = server::Config {
+				preferred:crate::Preferred { kex:group, ..crate::Preferred::DEFAULT },
+				..Default::default()
+			};
+
+			test_session_with_configs(
+				client::Config::default(),
+				server_config,
+				Client {},
+				Server {},
+				move |c| {
+					async move {
+						let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+						assert_eq!(Some(&negotiated.kex), group.first());
+
+						c
+					}
+				},
+				|s| async move { s },
+			)
+			.await;
+		}
+	}
+}
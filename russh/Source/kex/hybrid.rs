@@ -0,0 +1,434 @@
+use std::marker::PhantomData;
+
+use byteorder::{BigEndian, ByteOrder};
+use curve25519_dalek::{
+	constants::ED25519_BASEPOINT_TABLE,
+	montgomery::MontgomeryPoint,
+	scalar::Scalar,
+};
+use digest::Digest;
+use log::debug;
+use rand::RngCore;
+use russh_cryptovec::CryptoVec;
+use russh_keys::encoding::Encoding;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroize;
+
+use super::{KexAlgorithm, KexType, compute_keys_encoded, mlkem768, sntrup761};
+use crate::{
+	cipher,
+	mac::{self},
+	msg,
+	session::Exchange,
+};
+
+/// A key encapsulation mechanism, combined with X25519 by [`HybridKex`].
+pub(crate) trait Kem {
+	const PUBLIC_KEY_BYTES:usize;
+
+	const CIPHERTEXT_BYTES:usize;
+
+	type SecretKey:Send;
+
+	fn keypair<R:RngCore>(rng:&mut R) -> (Vec<u8>, Self::SecretKey);
+
+	fn encapsulate<R:RngCore>(pk:&[u8], rng:&mut R) -> Result<(Vec<u8>, [u8; 32]), crate::Error>;
+
+	fn decapsulate(c:&[u8], sk:&Self::SecretKey) -> Result<[u8; 32], crate::Error>;
+}
+
+pub struct Sntrup761 {}
+
+impl Kem for Sntrup761 {
+	const PUBLIC_KEY_BYTES:usize = sntrup761::PUBLIC_KEY_BYTES;
+
+	const CIPHERTEXT_BYTES:usize = sntrup761::CIPHERTEXT_BYTES;
+
+	type SecretKey = sntrup761::SecretKey;
+
+	fn keypair<R:RngCore>(rng:&mut R) -> (Vec<u8>, Self::SecretKey) { sntrup761::keypair(rng) }
+
+	fn encapsulate<R:RngCore>(pk:&[u8], rng:&mut R) -> Result<(Vec<u8>, [u8; 32]), crate::Error> {
+		sntrup761::encapsulate(pk, rng)
+	}
+
+	fn decapsulate(c:&[u8], sk:&Self::SecretKey) -> Result<[u8; 32], crate::Error> {
+		sntrup761::decapsulate(c, sk)
+	}
+}
+
+pub struct MlKem768 {}
+
+impl Kem for MlKem768 {
+	const PUBLIC_KEY_BYTES:usize = mlkem768::PUBLIC_KEY_BYTES;
+
+	const CIPHERTEXT_BYTES:usize = mlkem768::CIPHERTEXT_BYTES;
+
+	type SecretKey = mlkem768::SecretKey;
+
+	fn keypair<R:RngCore>(rng:&mut R) -> (Vec<u8>, Self::SecretKey) { mlkem768::keypair(rng) }
+
+	fn encapsulate<R:RngCore>(pk:&[u8], rng:&mut R) -> Result<(Vec<u8>, [u8; 32]), crate::Error> {
+		mlkem768::encapsulate(pk, rng)
+	}
+
+	fn decapsulate(c:&[u8], sk:&Self::SecretKey) -> Result<[u8; 32], crate::Error> {
+		mlkem768::decapsulate(c, sk)
+	}
+}
+
+pub struct Sntrup761X25519KexType {}
+
+impl KexType for Sntrup761X25519KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(HybridKex::<Sntrup761, Sha512>::default()) as Box<dyn KexAlgorithm + Send>
+	}
+}
+
+pub struct MlKem768X25519KexType {}
+
+impl KexType for MlKem768X25519KexType {
+	fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+		Box::new(HybridKex::<MlKem768, Sha256>::default()) as Box<dyn KexAlgorithm + Send>
+	}
+}
+
+/// Post-quantum hybrid key exchange, as implemented by OpenSSH for
+/// `sntrup761x25519-sha512@openssh.com` and `mlkem768x25519-sha256`: the
+/// public values are the KEM public key (from the client) or ciphertext
+/// (from the server), followed by an X25519 public key, and the shared
+/// secret is the hash of the two shared secrets, encoded as a string.
+#[doc(hidden)]
+pub struct HybridKex<K:Kem, D:Digest> {
+	local_secret:Option<(K::SecretKey, Scalar)>,
+	shared_secret:Option<CryptoVec>,
+	_digest:PhantomData<D>,
+}
+
+impl<K:Kem, D:Digest> Default for HybridKex<K, D> {
+	fn default() -> Self { HybridKex { local_secret:None, shared_secret:None, _digest:PhantomData } }
+}
+
+impl<K:Kem, D:Digest> Drop for HybridKex<K, D> {
+	fn drop(&mut self) {
+		if let Some((_, ref mut local_secret)) = self.local_secret {
+			local_secret.zeroize();
+		}
+	}
+}
+
+impl<K:Kem, D:Digest> std::fmt::Debug for HybridKex<K, D> {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Algorithm {{ local_secret: [hidden], shared_secret: [hidden] }}",)
+	}
+}
+
+fn x25519_public(secret:&Scalar) -> MontgomeryPoint {
+	(&ED25519_BASEPOINT_TABLE * secret).to_montgomery()
+}
+
+fn x25519_remote(public:&[u8]) -> MontgomeryPoint {
+	let mut remote = MontgomeryPoint([0; 32]);
+
+	remote.0.clone_from_slice(public);
+
+	remote
+}
+
+/// `HASH(kem_shared || x25519_shared)`, encoded as a string.
+fn combine<D:Digest>(kem_shared:&[u8], mut x25519_shared:MontgomeryPoint) -> CryptoVec {
+	let mut hash = D::new().chain_update(kem_shared).chain_update(x25519_shared.0).finalize();
+
+	let mut shared = CryptoVec::new();
+
+	shared.extend_ssh_string(&hash);
+
+	hash.zeroize();
+
+	x25519_shared.0.zeroize();
+
+	shared
+}
+
+impl<K:Kem, D:Digest> HybridKex<K, D> {
+	fn server_dh_with<R:RngCore>(
+		&mut self,
+		rng:&mut R,
+		server_secret:Scalar,
+		exchange:&mut Exchange,
+		payload:&[u8],
+	) -> Result<(), crate::Error> {
+		debug!("server_dh");
+
+		if payload.first() != Some(&msg::KEX_ECDH_INIT) || payload.len() < 5 {
+			return Err(crate::Error::Inconsistent);
+		}
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		let client_pubkey_len = BigEndian::read_u32(&payload[1..]) as usize;
+
+		if client_pubkey_len != K::PUBLIC_KEY_BYTES + 32 {
+			return Err(crate::Error::Kex);
+		}
+
+		if payload.len() < 5 + client_pubkey_len {
+			return Err(crate::Error::Inconsistent);
+		}
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		let (kem_pubkey, x25519_pubkey) =
+			payload[5..5 + client_pubkey_len].split_at(K::PUBLIC_KEY_BYTES);
+
+		let (ciphertext, mut kem_shared) = K::encapsulate(kem_pubkey, rng)?;
+
+		let server_pubkey = x25519_public(&server_secret);
+
+		// fill exchange.
+		exchange.server_ephemeral.clear();
+
+		exchange.server_ephemeral.extend(&ciphertext);
+
+		exchange.server_ephemeral.extend(&server_pubkey.0);
+
+		let x25519_shared = server_secret * x25519_remote(x25519_pubkey);
+
+		self.shared_secret = Some(combine::<D>(&kem_shared, x25519_shared));
+
+		kem_shared.zeroize();
+
+		Ok(())
+	}
+
+	fn client_dh_with<R:RngCore>(
+		&mut self,
+		rng:&mut R,
+		client_secret:Scalar,
+		client_ephemeral:&mut CryptoVec,
+		buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		let (kem_pubkey, kem_secret) = K::keypair(rng);
+
+		let client_pubkey = x25519_public(&client_secret);
+
+		// fill exchange.
+		client_ephemeral.clear();
+
+		client_ephemeral.extend(&kem_pubkey);
+
+		client_ephemeral.extend(&client_pubkey.0);
+
+		buf.push(msg::KEX_ECDH_INIT);
+
+		buf.extend_ssh_string(client_ephemeral);
+
+		self.local_secret = Some((kem_secret, client_secret));
+
+		Ok(())
+	}
+}
+
+impl<K:Kem, D:Digest> KexAlgorithm for HybridKex<K, D> {
+	fn skip_exchange(&self) -> bool { false }
+
+	#[doc(hidden)]
+	fn server_dh(&mut self, exchange:&mut Exchange, payload:&[u8]) -> Result<(), crate::Error> {
+		let server_secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
+
+		self.server_dh_with(&mut rand::thread_rng(), server_secret, exchange, payload)
+	}
+
+	#[doc(hidden)]
+	fn client_dh(
+		&mut self,
+		client_ephemeral:&mut CryptoVec,
+		buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		let client_secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
+
+		self.client_dh_with(&mut rand::thread_rng(), client_secret, client_ephemeral, buf)
+	}
+
+	fn compute_shared_secret(&mut self, remote_pubkey_:&[u8]) -> Result<(), crate::Error> {
+		let (kem_secret, mut local_secret) = self.local_secret.take().ok_or(crate::Error::KexInit)?;
+
+		if remote_pubkey_.len() != K::CIPHERTEXT_BYTES + 32 {
+			local_secret.zeroize();
+
+			return Err(crate::Error::Kex);
+		}
+
+		let (ciphertext, x25519_pubkey) = remote_pubkey_.split_at(K::CIPHERTEXT_BYTES);
+
+		let mut kem_shared = K::decapsulate(ciphertext, &kem_secret)?;
+
+		let x25519_shared = local_secret * x25519_remote(x25519_pubkey);
+
+		self.shared_secret = Some(combine::<D>(&kem_shared, x25519_shared));
+
+		kem_shared.zeroize();
+
+		local_secret.zeroize();
+
+		Ok(())
+	}
+
+	fn compute_exchange_hash(
+		&self,
+		key:&CryptoVec,
+		exchange:&Exchange,
+		buffer:&mut CryptoVec,
+	) -> Result<CryptoVec, crate::Error> {
+		// Same as RFC 5656, with the shared secret as a string.
+		buffer.clear();
+
+		buffer.extend_ssh_string(&exchange.client_id);
+
+		buffer.extend_ssh_string(&exchange.server_id);
+
+		buffer.extend_ssh_string(&exchange.client_kex_init);
+
+		buffer.extend_ssh_string(&exchange.server_kex_init);
+
+		buffer.extend(key);
+
+		buffer.extend_ssh_string(&exchange.client_ephemeral);
+
+		buffer.extend_ssh_string(&exchange.server_ephemeral);
+
+		if let Some(ref shared) = self.shared_secret {
+			buffer.extend(shared);
+		}
+
+		let mut hasher = D::new();
+
+		hasher.update(&buffer);
+
+		let mut res = CryptoVec::new();
+
+		res.extend(hasher.finalize().as_slice());
+
+		Ok(res)
+	}
+
+	fn compute_keys(
+		&self,
+		session_id:&CryptoVec,
+		exchange_hash:&CryptoVec,
+		cipher:&(dyn cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		is_server:bool,
+	) -> Result<super::cipher::CipherPair, crate::Error> {
+		compute_keys_encoded::<D>(
+			self.shared_secret.as_ref().map(|x| &x[..]).unwrap_or(&[]),
+			session_id,
+			exchange_hash,
+			cipher,
+			remote_to_local_mac,
+			local_to_remote_mac,
+			is_server,
+		)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::kex::sntrup761::test::HashRng;
+
+	const CLIENT_SECRET:[u8; 32] =
+		hex_literal::hex!("1011111111111111111111111111111111111111111111111111111111111151");
+
+	const SERVER_SECRET:[u8; 32] =
+		hex_literal::hex!("2022222222222222222222222222222222222222222222222222222222222262");
+
+	/// Runs both sides with fixed randomness, and returns the shared
+	/// secret.
+	#[allow(clippy::unwrap_used)]
+	fn exchange_fixed<K:Kem, D:Digest>() -> CryptoVec {
+		let mut client = HybridKex::<K, D>::default();
+
+		let mut server = HybridKex::<K, D>::default();
+
+		let mut exchange = Exchange::new();
+
+		let mut init = CryptoVec::new();
+
+		client
+			.client_dh_with(
+				&mut HashRng::new(b"keypair"),
+				Scalar::from_bytes_mod_order(CLIENT_SECRET),
+				&mut exchange.client_ephemeral,
+				&mut init,
+			)
+			.unwrap();
+
+		assert_eq!(exchange.client_ephemeral.len(), K::PUBLIC_KEY_BYTES + 32);
+
+		server
+			.server_dh_with(
+				&mut HashRng::new(b"encapsulate"),
+				Scalar::from_bytes_mod_order(SERVER_SECRET),
+				&mut exchange,
+				&init,
+			)
+			.unwrap();
+
+		assert_eq!(exchange.server_ephemeral.len(), K::CIPHERTEXT_BYTES + 32);
+
+		client.compute_shared_secret(&exchange.server_ephemeral).unwrap();
+
+		let client_shared = client.shared_secret.take().unwrap();
+
+		let server_shared = server.shared_secret.take().unwrap();
+
+		assert_eq!(&client_shared[..], &server_shared[..]);
+
+		client_shared
+	}
+
+	#[test]
+	fn test_sntrup761x25519_fixed_keys() {
+		assert_eq!(
+			&exchange_fixed::<Sntrup761, Sha512>()[..],
+			&hex_literal::hex!(
+				"00000040"
+				"4e7abd50d38bc171331313e34332b930fe55f297d449b97206ce3db888c18b4d"
+				"e95145132a7fc12a848427ed52d0e75d96b83e2c30c893c0187a53a642bdd3ae"
+			)[..]
+		);
+	}
+
+	#[test]
+	fn test_mlkem768x25519_fixed_keys() {
+		assert_eq!(
+			&exchange_fixed::<MlKem768, Sha256>()[..],
+			&hex_literal::hex!(
+				"00000020"
+				"12015bf96346e20b2910cf8c61d9c6d8178be51cb84d38bea8c70058a40f2f65"
+			)[..]
+		);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_rejects_wrong_lengths() {
+		let mut server = HybridKex::<MlKem768, Sha256>::default();
+
+		let mut exchange = Exchange::new();
+
+		let mut init = CryptoVec::new();
+
+		init.push(msg::KEX_ECDH_INIT);
+
+		init.extend_ssh_string(&[0; 32]);
+
+		assert!(matches!(server.server_dh(&mut exchange, &init), Err(crate::Error::Kex)));
+
+		let mut client = HybridKex::<Sntrup761, Sha512>::default();
+
+		client.client_dh(&mut exchange.client_ephemeral, &mut init).unwrap();
+
+		assert!(matches!(client.compute_shared_secret(&[0; 32]), Err(crate::Error::Kex)));
+	}
+}
@@ -0,0 +1,927 @@
+// ML-KEM-768, as specified in FIPS 203. Keys, ciphertexts and shared
+// secrets are byte-for-byte compatible with other implementations, such
+// as the one used by OpenSSH.
+//
+// Arithmetic on secret data avoids branches and secret-dependent memory
+// accesses. The only divisions are by the public constant `Q`, which
+// compilers turn into multiplications.
+
+use digest::{ExtendableOutput, Update, XofReader};
+use rand::RngCore;
+use sha3::{Digest, Sha3_256, Sha3_512, Shake128, Shake256};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use zeroize::Zeroize;
+
+const N:usize = 256;
+
+const Q:u32 = 3329;
+
+const K:usize = 3;
+
+const ETA1:usize = 2;
+
+const ETA2:usize = 2;
+
+const DU:usize = 10;
+
+const DV:usize = 4;
+
+const POLY_BYTES:usize = 384;
+
+const HASH_BYTES:usize = 32;
+
+pub(crate) const PUBLIC_KEY_BYTES:usize = K * POLY_BYTES + 32;
+
+pub(crate) const SECRET_KEY_BYTES:usize = K * POLY_BYTES + PUBLIC_KEY_BYTES + 2 * HASH_BYTES;
+
+pub(crate) const CIPHERTEXT_BYTES:usize = K * N * DU / 8 + N * DV / 8;
+
+pub(crate) const SHARED_SECRET_BYTES:usize = 32;
+
+type Poly = [u16; N];
+
+/// A decapsulation key, `dk_pke || ek || H(ek) || z`.
+pub(crate) struct SecretKey(Vec<u8>);
+
+impl Drop for SecretKey {
+	fn drop(&mut self) { self.0.zeroize(); }
+}
+
+const fn bitrev7(i:usize) -> usize {
+	let mut r = 0;
+
+	let mut k = 0;
+
+	while k < 7 {
+		r |= ((i >> k) & 1) << (6 - k);
+
+		k += 1;
+	}
+
+	r
+}
+
+const fn pow17(e:usize) -> u16 {
+	let mut r = 1;
+
+	let mut k = 0;
+
+	while k < e {
+		r = r * 17 % Q;
+
+		k += 1;
+	}
+
+	r as u16
+}
+
+/// `17^bitrev7(i)`, for the NTT.
+#[allow(clippy::indexing_slicing)] // indices are below 128
+const ZETAS:[u16; 128] = {
+	let mut z = [0; 128];
+
+	let mut i = 0;
+
+	while i < 128 {
+		z[i] = pow17(bitrev7(i));
+
+		i += 1;
+	}
+
+	z
+};
+
+/// `17^(2 * bitrev7(i) + 1)`, for multiplication in the NTT domain.
+#[allow(clippy::indexing_slicing)] // indices are below 128
+const GAMMAS:[u16; 128] = {
+	let mut z = [0; 128];
+
+	let mut i = 0;
+
+	while i < 128 {
+		z[i] = pow17(2 * bitrev7(i) + 1);
+
+		i += 1;
+	}
+
+	z
+};
+
+fn reduce(x:u32) -> u16 { (x % Q) as u16 }
+
+fn h(input:&[u8]) -> [u8; HASH_BYTES] {
+	let mut out = [0; HASH_BYTES];
+
+	out.copy_from_slice(&Sha3_256::digest(input));
+
+	out
+}
+
+#[allow(clippy::indexing_slicing)] // SHA3-512 is 64 bytes
+fn g(a:&[u8], b:&[u8]) -> ([u8; 32], [u8; 32]) {
+	let mut hash = Sha3_512::new().chain_update(a).chain_update(b).finalize();
+
+	let mut out = ([0; 32], [0; 32]);
+
+	out.0.copy_from_slice(&hash[..32]);
+
+	out.1.copy_from_slice(&hash[32..]);
+
+	hash.zeroize();
+
+	out
+}
+
+fn j(z:&[u8], c:&[u8]) -> [u8; SHARED_SECRET_BYTES] {
+	let mut out = [0; SHARED_SECRET_BYTES];
+
+	let mut shake = Shake256::default();
+
+	shake.update(z);
+
+	shake.update(c);
+
+	shake.finalize_xof().read(&mut out);
+
+	out
+}
+
+/// Samples a polynomial from the centered binomial distribution, with
+/// bytes from `PRF(s, n)`.
+#[allow(clippy::indexing_slicing)] // lengths are constant
+fn sample_cbd(eta:usize, s:&[u8; 32], n:u8) -> Poly {
+	let mut bytes = [0; 64 * 3];
+
+	let bytes = &mut bytes[..64 * eta];
+
+	let mut shake = Shake256::default();
+
+	shake.update(s);
+
+	shake.update(&[n]);
+
+	shake.finalize_xof().read(bytes);
+
+	let bit = |i:usize| ((bytes[i / 8] >> (i % 8)) & 1) as u32;
+
+	let mut f = [0; N];
+
+	for (i, f) in f.iter_mut().enumerate() {
+		let mut x = 0;
+
+		let mut y = 0;
+
+		for k in 0..eta {
+			x += bit(2 * i * eta + k);
+
+			y += bit(2 * i * eta + eta + k);
+		}
+
+		*f = reduce(x + Q - y);
+	}
+
+	bytes.zeroize();
+
+	f
+}
+
+/// Samples a uniform polynomial in the NTT domain, from the public seed.
+fn sample_ntt(rho:&[u8], i:u8, j:u8) -> Poly {
+	let mut shake = Shake128::default();
+
+	shake.update(rho);
+
+	shake.update(&[j, i]);
+
+	let mut reader = shake.finalize_xof();
+
+	let mut f = [0; N];
+
+	let mut k = 0;
+
+	while k < N {
+		let mut b = [0u8; 3];
+
+		reader.read(&mut b);
+
+		let d1 = b[0] as u16 | ((b[1] as u16 & 0xf) << 8);
+
+		let d2 = (b[1] as u16 >> 4) | ((b[2] as u16) << 4);
+
+		for d in [d1, d2] {
+			if (d as u32) < Q {
+				if let Some(f) = f.get_mut(k) {
+					*f = d;
+
+					k += 1;
+				}
+			}
+		}
+	}
+
+	f
+}
+
+#[allow(clippy::indexing_slicing)] // indices are below `N`
+fn ntt(f:&mut Poly) {
+	let mut k = 1;
+
+	let mut len = 128;
+
+	while len >= 2 {
+		for start in (0..N).step_by(2 * len) {
+			let zeta = ZETAS[k] as u32;
+
+			k += 1;
+
+			for j in start..start + len {
+				let t = reduce(zeta * f[j + len] as u32) as u32;
+
+				f[j + len] = reduce(f[j] as u32 + Q - t);
+
+				f[j] = reduce(f[j] as u32 + t);
+			}
+		}
+
+		len /= 2;
+	}
+}
+
+#[allow(clippy::indexing_slicing)] // indices are below `N`
+fn inverse_ntt(f:&mut Poly) {
+	let mut k = 127;
+
+	let mut len = 2;
+
+	while len <= 128 {
+		for start in (0..N).step_by(2 * len) {
+			let zeta = ZETAS[k] as u32;
+
+			k -= 1;
+
+			for j in start..start + len {
+				let t = f[j] as u32;
+
+				f[j] = reduce(t + f[j + len] as u32);
+
+				f[j + len] = reduce(zeta * (f[j + len] as u32 + Q - t));
+			}
+		}
+
+		len *= 2;
+	}
+
+	for f in f.iter_mut() {
+		// 3303 is 128^-1 mod Q.
+		*f = reduce(*f as u32 * 3303);
+	}
+}
+
+/// Adds `f * g` to `acc`, in the NTT domain.
+#[allow(clippy::indexing_slicing)] // indices are below `N`
+fn multiply_add(acc:&mut Poly, f:&Poly, g:&Poly) {
+	for i in 0..N / 2 {
+		let (a0, a1) = (f[2 * i] as u32, f[2 * i + 1] as u32);
+
+		let (b0, b1) = (g[2 * i] as u32, g[2 * i + 1] as u32);
+
+		let c0 = a0 * b0 + reduce(a1 * b1) as u32 * GAMMAS[i] as u32;
+
+		let c1 = a0 * b1 + a1 * b0;
+
+		acc[2 * i] = reduce(acc[2 * i] as u32 + c0);
+
+		acc[2 * i + 1] = reduce(acc[2 * i + 1] as u32 + c1);
+	}
+}
+
+fn add(f:&mut Poly, g:&Poly) {
+	for (f, g) in f.iter_mut().zip(g.iter()) {
+		*f = reduce(*f as u32 + *g as u32);
+	}
+}
+
+fn compress(x:u16, d:usize) -> u16 {
+	((((x as u32) << d) + Q / 2) / Q) as u16 & ((1 << d) - 1)
+}
+
+fn decompress(y:u16, d:usize) -> u16 { ((y as u32 * Q + (1 << (d - 1))) >> d) as u16 }
+
+/// Appends the `d` low bits of each coefficient, little-endian.
+fn byte_encode(out:&mut Vec<u8>, f:&Poly, d:usize) {
+	let mut acc = 0u32;
+
+	let mut bits = 0;
+
+	for &f in f.iter() {
+		acc |= (f as u32) << bits;
+
+		bits += d;
+
+		while bits >= 8 {
+			out.push(acc as u8);
+
+			acc >>= 8;
+
+			bits -= 8;
+		}
+	}
+}
+
+/// Reads coefficients of `d` bits, reduced modulo `Q` when `d` is 12.
+fn byte_decode(b:&[u8], d:usize) -> Poly {
+	let mut f = [0; N];
+
+	let mut acc = 0u32;
+
+	let mut bits = 0;
+
+	let mut bytes = b.iter();
+
+	for f in f.iter_mut() {
+		while bits < d {
+			acc |= (*bytes.next().unwrap_or(&0) as u32) << bits;
+
+			bits += 8;
+		}
+
+		let x = acc & ((1 << d) - 1);
+
+		*f = if d == 12 { reduce(x) } else { x as u16 };
+
+		acc >>= d;
+
+		bits -= d;
+	}
+
+	f
+}
+
+/// The matrix `A`, in the NTT domain. `transpose` gives `A^T`.
+fn matrix(rho:&[u8], transpose:bool) -> [[Poly; K]; K] {
+	let mut a = [[[0; N]; K]; K];
+
+	for (i, row) in a.iter_mut().enumerate() {
+		for (j, a) in row.iter_mut().enumerate() {
+			*a = if transpose {
+				sample_ntt(rho, j as u8, i as u8)
+			} else {
+				sample_ntt(rho, i as u8, j as u8)
+			};
+		}
+	}
+
+	a
+}
+
+/// K-PKE.KeyGen, returning the encryption and decryption keys.
+fn pke_keygen(d:&[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+	let (rho, mut sigma) = g(d, &[K as u8]);
+
+	let a = matrix(&rho, false);
+
+	let mut s = [[0; N]; K];
+
+	let mut e = [[0; N]; K];
+
+	for (i, s) in s.iter_mut().enumerate() {
+		*s = sample_cbd(ETA1, &sigma, i as u8);
+
+		ntt(s);
+	}
+
+	for (i, e) in e.iter_mut().enumerate() {
+		*e = sample_cbd(ETA1, &sigma, (K + i) as u8);
+
+		ntt(e);
+	}
+
+	let mut ek = Vec::with_capacity(PUBLIC_KEY_BYTES);
+
+	for (a, e) in a.iter().zip(e.iter()) {
+		let mut t = *e;
+
+		for (a, s) in a.iter().zip(s.iter()) {
+			multiply_add(&mut t, a, s);
+		}
+
+		byte_encode(&mut ek, &t, 12);
+	}
+
+	ek.extend_from_slice(&rho);
+
+	let mut dk = Vec::with_capacity(K * POLY_BYTES);
+
+	for s in s.iter() {
+		byte_encode(&mut dk, s, 12);
+	}
+
+	sigma.zeroize();
+
+	s.zeroize();
+
+	e.zeroize();
+
+	(ek, dk)
+}
+
+/// K-PKE.Encrypt.
+#[allow(clippy::indexing_slicing)] // lengths are constant
+fn pke_encrypt(ek:&[u8], m:&[u8; 32], r:&[u8; 32]) -> Vec<u8> {
+	let (t, rho) = ek.split_at(K * POLY_BYTES);
+
+	let a = matrix(rho, true);
+
+	let mut y = [[0; N]; K];
+
+	for (i, y) in y.iter_mut().enumerate() {
+		*y = sample_cbd(ETA1, r, i as u8);
+
+		ntt(y);
+	}
+
+	let mut c = Vec::with_capacity(CIPHERTEXT_BYTES);
+
+	for (i, a) in a.iter().enumerate() {
+		let mut u = [0; N];
+
+		for (a, y) in a.iter().zip(y.iter()) {
+			multiply_add(&mut u, a, y);
+		}
+
+		inverse_ntt(&mut u);
+
+		add(&mut u, &sample_cbd(ETA2, r, (K + i) as u8));
+
+		for u in u.iter_mut() {
+			*u = compress(*u, DU);
+		}
+
+		byte_encode(&mut c, &u, DU);
+
+		u.zeroize();
+	}
+
+	let mut v = [0; N];
+
+	for (t, y) in t.chunks(POLY_BYTES).zip(y.iter()) {
+		multiply_add(&mut v, &byte_decode(t, 12), y);
+	}
+
+	inverse_ntt(&mut v);
+
+	add(&mut v, &sample_cbd(ETA2, r, (2 * K) as u8));
+
+	let mut mu = byte_decode(m, 1);
+
+	for mu in mu.iter_mut() {
+		*mu = decompress(*mu, 1);
+	}
+
+	add(&mut v, &mu);
+
+	for v in v.iter_mut() {
+		*v = compress(*v, DV);
+	}
+
+	byte_encode(&mut c, &v, DV);
+
+	y.zeroize();
+
+	v.zeroize();
+
+	mu.zeroize();
+
+	c
+}
+
+/// K-PKE.Decrypt.
+#[allow(clippy::indexing_slicing)] // lengths are constant
+fn pke_decrypt(dk:&[u8], c:&[u8]) -> [u8; 32] {
+	let (c1, c2) = c.split_at(K * N * DU / 8);
+
+	let mut su = [0; N];
+
+	for (s, u) in dk.chunks(POLY_BYTES).zip(c1.chunks(N * DU / 8)) {
+		let mut u = byte_decode(u, DU);
+
+		for u in u.iter_mut() {
+			*u = decompress(*u, DU);
+		}
+
+		ntt(&mut u);
+
+		let mut s = byte_decode(s, 12);
+
+		multiply_add(&mut su, &s, &u);
+
+		s.zeroize();
+	}
+
+	inverse_ntt(&mut su);
+
+	let mut w = byte_decode(c2, DV);
+
+	for (w, su) in w.iter_mut().zip(su.iter()) {
+		*w = compress(reduce(decompress(*w, DV) as u32 + Q - *su as u32), 1);
+	}
+
+	let mut m = Vec::with_capacity(32);
+
+	byte_encode(&mut m, &w, 1);
+
+	let mut out = [0; 32];
+
+	out.copy_from_slice(&m);
+
+	su.zeroize();
+
+	w.zeroize();
+
+	m.zeroize();
+
+	out
+}
+
+fn keypair_derand(d:&[u8; 32], z:&[u8; 32]) -> (Vec<u8>, SecretKey) {
+	let (ek, mut dk) = pke_keygen(d);
+
+	dk.reserve(SECRET_KEY_BYTES - dk.len());
+
+	dk.extend_from_slice(&ek);
+
+	dk.extend_from_slice(&h(&ek));
+
+	dk.extend_from_slice(z);
+
+	(ek, SecretKey(dk))
+}
+
+fn encapsulate_derand(
+	ek:&[u8],
+	m:&[u8; 32],
+) -> Result<(Vec<u8>, [u8; SHARED_SECRET_BYTES]), crate::Error> {
+	if ek.len() != PUBLIC_KEY_BYTES {
+		return Err(crate::Error::Kex);
+	}
+
+	// Modulus check: the key must encode coefficients below `Q`.
+	let mut check = Vec::with_capacity(K * POLY_BYTES);
+
+	for t in ek.chunks(POLY_BYTES).take(K) {
+		byte_encode(&mut check, &byte_decode(t, 12), 12);
+	}
+
+	if ek.get(..K * POLY_BYTES) != Some(&check[..]) {
+		return Err(crate::Error::Kex);
+	}
+
+	let (k, mut r) = g(m, &h(ek));
+
+	let c = pke_encrypt(ek, m, &r);
+
+	r.zeroize();
+
+	Ok((c, k))
+}
+
+/// Generates a key pair.
+pub(crate) fn keypair<R:RngCore>(rng:&mut R) -> (Vec<u8>, SecretKey) {
+	let mut d = [0; 32];
+
+	let mut z = [0; 32];
+
+	rng.fill_bytes(&mut d);
+
+	rng.fill_bytes(&mut z);
+
+	let kp = keypair_derand(&d, &z);
+
+	d.zeroize();
+
+	z.zeroize();
+
+	kp
+}
+
+/// Encapsulates a random shared secret to `ek`, returning the ciphertext
+/// and the shared secret.
+pub(crate) fn encapsulate<R:RngCore>(
+	ek:&[u8],
+	rng:&mut R,
+) -> Result<(Vec<u8>, [u8; SHARED_SECRET_BYTES]), crate::Error> {
+	let mut m = [0; 32];
+
+	rng.fill_bytes(&mut m);
+
+	let res = encapsulate_derand(ek, &m);
+
+	m.zeroize();
+
+	res
+}
+
+/// Decapsulates the shared secret in `c`. Invalid ciphertexts yield a
+/// pseudo-random secret derived from `sk` (implicit rejection).
+pub(crate) fn decapsulate(
+	c:&[u8],
+	sk:&SecretKey,
+) -> Result<[u8; SHARED_SECRET_BYTES], crate::Error> {
+	if c.len() != CIPHERTEXT_BYTES {
+		return Err(crate::Error::Kex);
+	}
+
+	let (dk, rest) = sk.0.split_at(K * POLY_BYTES);
+
+	let (ek, rest) = rest.split_at(PUBLIC_KEY_BYTES);
+
+	let (hash, z) = rest.split_at(HASH_BYTES);
+
+	let mut m = pke_decrypt(dk, c);
+
+	let (mut k, mut r) = g(&m, hash);
+
+	let rejected = j(z, c);
+
+	let equal = pke_encrypt(ek, &m, &r).as_slice().ct_eq(c);
+
+	for (k, rejected) in k.iter_mut().zip(rejected.iter()) {
+		*k = u8::conditional_select(rejected, k, equal);
+	}
+
+	m.zeroize();
+
+	r.zeroize();
+
+	Ok(k)
+}
+
+#[cfg(test)]
+mod test {
+	use std::convert::TryInto;
+
+	use super::*;
+
+	#[test]
+	fn test_sizes() {
+		assert_eq!(PUBLIC_KEY_BYTES, 1184);
+
+		assert_eq!(SECRET_KEY_BYTES, 2400);
+
+		assert_eq!(CIPHERTEXT_BYTES, 1088);
+
+		let (ek, sk) = keypair(&mut rand::thread_rng());
+
+		assert_eq!(ek.len(), PUBLIC_KEY_BYTES);
+
+		assert_eq!(sk.0.len(), SECRET_KEY_BYTES);
+	}
+
+	#[test]
+	fn test_ntt_roundtrip() {
+		let mut f = [0; N];
+
+		for (i, f) in f.iter_mut().enumerate() {
+			*f = reduce(i as u32 * 1337);
+		}
+
+		let mut g = f;
+
+		ntt(&mut g);
+
+		inverse_ntt(&mut g);
+
+		assert_eq!(f, g);
+	}
+
+	/// The entries of a known answer file, separated by blank lines, as
+	/// maps from field names to their values.
+	#[allow(clippy::unwrap_used)]
+	fn entries(kat:&str) -> Vec<std::collections::HashMap<&str, &str>> {
+		kat.split("\n\n")
+			.filter(|entry| !entry.starts_with('#'))
+			.map(|entry| entry.lines().map(|line| line.split_once(" = ").unwrap()).collect())
+			.collect()
+	}
+
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn hex(hex:&str) -> Vec<u8> {
+		(0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+	}
+
+	/// The key generation vectors of the NIST ACVP server for FIPS 203.
+	#[test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_acvp_key_generation() {
+		let entries = entries(include_str!("../../../files/kat/mlkem768_keygen.rsp"));
+
+		assert_eq!(entries.len(), 10);
+
+		for entry in entries {
+			let d = hex(entry["d"]).try_into().unwrap();
+
+			let z = hex(entry["z"]).try_into().unwrap();
+
+			let (ek, sk) = keypair_derand(&d, &z);
+
+			assert_eq!(ek, hex(entry["ek"]), "tcId = {}", entry["tcId"]);
+
+			assert_eq!(sk.0, hex(entry["dk"]), "tcId = {}", entry["tcId"]);
+		}
+	}
+
+	/// The encapsulation vectors of the NIST ACVP server for FIPS 203.
+	#[test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_acvp_encapsulation() {
+		let entries = entries(include_str!("../../../files/kat/mlkem768_encap.rsp"));
+
+		assert_eq!(entries.len(), 10);
+
+		for entry in entries {
+			let m = hex(entry["m"]).try_into().unwrap();
+
+			let (c, k) = encapsulate_derand(&hex(entry["ek"]), &m).unwrap();
+
+			assert_eq!(c, hex(entry["c"]), "tcId = {}", entry["tcId"]);
+
+			assert_eq!(&k[..], &hex(entry["k"])[..], "tcId = {}", entry["tcId"]);
+		}
+	}
+
+	/// The decapsulation vectors of the NIST ACVP server for FIPS 203,
+	/// half of which have modified ciphertexts, implicitly rejected.
+	#[test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_acvp_decapsulation() {
+		let entries = entries(include_str!("../../../files/kat/mlkem768_decap.rsp"));
+
+		let (key, tests) = entries.split_first().unwrap();
+
+		let sk = SecretKey(hex(key["dk"]));
+
+		assert_eq!(tests.len(), 10);
+
+		for entry in tests {
+			let k = decapsulate(&hex(entry["c"]), &sk).unwrap();
+
+			assert_eq!(&k[..], &hex(entry["k"])[..], "tcId = {}", entry["tcId"]);
+		}
+	}
+
+	/// Fixed seeds given to this implementation and to the ML-KEM of
+	/// OpenSSL. Run with `cargo test -- --ignored`, with `openssl` 3.5 or
+	/// later in the `PATH`.
+	#[cfg(unix)]
+	#[test]
+	#[ignore]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_openssl() {
+		use std::process::Command;
+
+		let dir = std::env::temp_dir().join(format!("russh-mlkem768-{}", std::process::id()));
+
+		std::fs::create_dir_all(&dir).unwrap();
+
+		let openssl = |args:&[&str]| {
+			let output = Command::new("openssl").current_dir(&dir).args(args).output().unwrap();
+
+			assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+		};
+
+		let read = |name:&str| std::fs::read(dir.join(name)).unwrap();
+
+		let (ek, sk) = keypair_derand(&[0; 32], &[1; 32]);
+
+		let seed = format!("hexseed:{}{}", "00".repeat(32), "01".repeat(32));
+
+		openssl(&["genpkey", "-algorithm", "ML-KEM-768", "-pkeyopt", &seed, "-out", "dk.pem"]);
+
+		openssl(&["pkey", "-in", "dk.pem", "-pubout", "-outform", "DER", "-out", "ek.der"]);
+
+		// The keys are the last bytes of their DER encodings.
+		assert!(read("ek.der").ends_with(&ek));
+
+		openssl(&[
+			"pkey",
+			"-in",
+			"dk.pem",
+			"-outform",
+			"DER",
+			"-provparam",
+			"ml-kem.output_formats=priv-only",
+			"-out",
+			"dk.der",
+		]);
+
+		assert!(read("dk.der").ends_with(&sk.0));
+
+		let (c, k) = encapsulate_derand(&ek, &[2; 32]).unwrap();
+
+		let ikme = format!("hexikme:{}", "02".repeat(32));
+
+		openssl(&[
+			"pkeyutl", "-encap", "-inkey", "ek.der", "-keyform", "DER", "-pubin", "-pkeyopt",
+			&ikme, "-out", "c.bin", "-secret", "k.bin",
+		]);
+
+		assert_eq!(read("c.bin"), c);
+
+		assert_eq!(read("k.bin"), k);
+
+		// The implicit rejection of a modified ciphertext.
+		let mut c = c;
+
+		c[0] ^= 1;
+
+		std::fs::write(dir.join("rejected.bin"), &c).unwrap();
+
+		openssl(&[
+			"pkeyutl",
+			"-decap",
+			"-inkey",
+			"dk.pem",
+			"-in",
+			"rejected.bin",
+			"-secret",
+			"rejected_k.bin",
+		]);
+
+		assert_eq!(read("rejected_k.bin"), decapsulate(&c, &sk).unwrap());
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	#[allow(clippy::indexing_slicing)]
+	fn test_rejects_invalid_public_key() {
+		let (mut ek, _) = keypair_derand(&[0; 32], &[1; 32]);
+
+		// A coefficient of 0xfff, which is not below `Q`.
+		ek[0] = 0xff;
+
+		ek[1] |= 0x0f;
+
+		assert!(matches!(encapsulate_derand(&ek, &[2; 32]), Err(crate::Error::Kex)));
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_mlkem768x25519() {
+		const PREFERRED:crate::Preferred = crate::Preferred {
+			kex:&[kex::MLKEM768X25519_SHA256, kex::CURVE25519],
+			..crate::Preferred::DEFAULT
+		};
+
+		// Preferred over classical ECDH when both sides support it.
+		test_session_with_configs(
+			client::Config { preferred:PREFERRED, ..Default::default() },
+			server::Config::default(),
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.kex, kex::MLKEM768X25519_SHA256);
+
+					c.rekey().await.unwrap();
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+
+		// Peers without it fall back to the next common algorithm.
+		let server_config = server::Config {
+			preferred:crate::Preferred { kex:&[kex::CURVE25519], ..crate::Preferred::DEFAULT },
+			..Default::default()
+		};
+
+		test_session_with_configs(
+			client::Config { preferred:PREFERRED, ..Default::default() },
+			server_config,
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.kex, kex::CURVE25519);
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+}
@@ -17,21 +17,37 @@
 #[cfg(feature = "rs-crypto")]
 mod curve25519;
 mod dh;
+#[cfg(feature = "rs-crypto")]
+mod hybrid;
+#[cfg(feature = "rs-crypto")]
+mod mlkem768;
 mod none;
+#[cfg(feature = "rs-crypto")]
+mod sntrup761;
 use std::{cell::RefCell, collections::HashMap, fmt::Debug};
 
 #[cfg(feature = "rs-crypto")]
 use curve25519::Curve25519KexType;
-use dh::{DhGroup1Sha1KexType, DhGroup14Sha1KexType, DhGroup14Sha256KexType};
+pub use dh::gex::GexParams;
+use dh::{
+	DhGexSha256KexType,
+	DhGroup1Sha1KexType,
+	DhGroup14Sha1KexType,
+	DhGroup14Sha256KexType,
+	DhGroup15Sha512KexType,
+	DhGroup16Sha512KexType,
+	DhGroup18Sha512KexType,
+};
 use digest::Digest;
+#[cfg(feature = "rs-crypto")]
+use hybrid::{MlKem768X25519KexType, Sntrup761X25519KexType};
 use once_cell::sync::Lazy;
 use russh_cryptovec::CryptoVec;
 use russh_keys::encoding::Encoding;
 
 use crate::{
 	cipher,
-	cipher::CIPHERS,
-	mac::{self, MACS},
+	mac,
 	session::Exchange,
 };
 
@@ -56,6 +72,32 @@ pub(crate) trait KexAlgorithm {
 
 	fn compute_shared_secret(&mut self, remote_pubkey_:&[u8]) -> Result<(), crate::Error>;
 
+	/// Whether the group is negotiated before the exchange, as in
+	/// RFC 4419. The client then starts with
+	/// [`client_gex_request`](Self::client_gex_request) instead of
+	/// [`client_dh`](Self::client_dh).
+	fn is_group_exchange(&self) -> bool { false }
+
+	/// Writes the client's request for a group.
+	fn client_gex_request(
+		&mut self,
+		_params:&GexParams,
+		_buf:&mut CryptoVec,
+	) -> Result<(), crate::Error> {
+		Err(crate::Error::Inconsistent)
+	}
+
+	/// Reads the group chosen by the server, before
+	/// [`client_dh`](Self::client_dh).
+	fn client_gex_group(&mut self, _payload:&[u8]) -> Result<(), crate::Error> {
+		Err(crate::Error::Inconsistent)
+	}
+
+	/// Reads the client's request, and writes the chosen group.
+	fn server_gex_group(&mut self, _payload:&[u8], _buf:&mut CryptoVec) -> Result<(), crate::Error> {
+		Err(crate::Error::Inconsistent)
+	}
+
 	fn compute_exchange_hash(
 		&self,
 		key:&CryptoVec,
@@ -67,9 +109,9 @@ pub(crate) trait KexAlgorithm {
 		&self,
 		session_id:&CryptoVec,
 		exchange_hash:&CryptoVec,
-		cipher:cipher::Name,
-		remote_to_local_mac:mac::Name,
-		local_to_remote_mac:mac::Name,
+		cipher:&(dyn cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
 		is_server:bool,
 	) -> Result<super::cipher::CipherPair, crate::Error>;
 }
@@ -88,18 +130,42 @@ pub const DH_G1_SHA1:Name = Name("diffie-hellman-group1-sha1");
 pub const DH_G14_SHA1:Name = Name("diffie-hellman-group14-sha1");
 /// `diffie-hellman-group14-sha256`
 pub const DH_G14_SHA256:Name = Name("diffie-hellman-group14-sha256");
+/// `diffie-hellman-group15-sha512`
+pub const DH_G15_SHA512:Name = Name("diffie-hellman-group15-sha512");
+/// `diffie-hellman-group16-sha512`
+pub const DH_G16_SHA512:Name = Name("diffie-hellman-group16-sha512");
+/// `diffie-hellman-group18-sha512`
+pub const DH_G18_SHA512:Name = Name("diffie-hellman-group18-sha512");
+/// `diffie-hellman-group-exchange-sha256`
+pub const DH_GEX_SHA256:Name = Name("diffie-hellman-group-exchange-sha256");
+/// `sntrup761x25519-sha512@openssh.com`
+pub const SNTRUP761X25519_SHA512:Name = Name("sntrup761x25519-sha512@openssh.com");
+/// `mlkem768x25519-sha256`
+pub const MLKEM768X25519_SHA256:Name = Name("mlkem768x25519-sha256");
 /// `none`
 pub const NONE:Name = Name("none");
 /// `ext-info-c`
 pub const EXTENSION_SUPPORT_AS_CLIENT:Name = Name("ext-info-c");
 /// `ext-info-s`
 pub const EXTENSION_SUPPORT_AS_SERVER:Name = Name("ext-info-s");
+/// `kex-strict-c-v00@openssh.com`
+pub const EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT:Name = Name("kex-strict-c-v00@openssh.com");
+/// `kex-strict-s-v00@openssh.com`
+pub const EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER:Name = Name("kex-strict-s-v00@openssh.com");
 
 #[cfg(feature = "rs-crypto")]
 const _CURVE25519:Curve25519KexType = Curve25519KexType {};
 const _DH_G1_SHA1:DhGroup1Sha1KexType = DhGroup1Sha1KexType {};
 const _DH_G14_SHA1:DhGroup14Sha1KexType = DhGroup14Sha1KexType {};
 const _DH_G14_SHA256:DhGroup14Sha256KexType = DhGroup14Sha256KexType {};
+const _DH_G15_SHA512:DhGroup15Sha512KexType = DhGroup15Sha512KexType {};
+const _DH_G16_SHA512:DhGroup16Sha512KexType = DhGroup16Sha512KexType {};
+const _DH_G18_SHA512:DhGroup18Sha512KexType = DhGroup18Sha512KexType {};
+const _DH_GEX_SHA256:DhGexSha256KexType = DhGexSha256KexType {};
+#[cfg(feature = "rs-crypto")]
+const _SNTRUP761X25519_SHA512:Sntrup761X25519KexType = Sntrup761X25519KexType {};
+#[cfg(feature = "rs-crypto")]
+const _MLKEM768X25519_SHA256:MlKem768X25519KexType = MlKem768X25519KexType {};
 const _NONE:none::NoneKexType = none::NoneKexType {};
 
 pub(crate) static KEXES:Lazy<HashMap<&'static Name, &(dyn KexType + Send + Sync)>> =
@@ -108,8 +174,22 @@ pub(crate) static KEXES:Lazy<HashMap<&'static Name, &(dyn KexType + Send + Sync)
 		#[cfg(feature = "rs-crypto")]
 		h.insert(&CURVE25519, &_CURVE25519);
 
+		#[cfg(feature = "rs-crypto")]
+		h.insert(&SNTRUP761X25519_SHA512, &_SNTRUP761X25519_SHA512);
+
+		#[cfg(feature = "rs-crypto")]
+		h.insert(&MLKEM768X25519_SHA256, &_MLKEM768X25519_SHA256);
+
 		h.insert(&DH_G14_SHA256, &_DH_G14_SHA256);
 
+		h.insert(&DH_GEX_SHA256, &_DH_GEX_SHA256);
+
+		h.insert(&DH_G16_SHA512, &_DH_G16_SHA512);
+
+		h.insert(&DH_G18_SHA512, &_DH_G18_SHA512);
+
+		h.insert(&DH_G15_SHA512, &_DH_G15_SHA512);
+
 		h.insert(&DH_G14_SHA1, &_DH_G14_SHA1);
 
 		h.insert(&DH_G1_SHA1, &_DH_G1_SHA1);
@@ -133,17 +213,40 @@ pub(crate) fn compute_keys<D:Digest>(
 	shared_secret:Option<&[u8]>,
 	session_id:&CryptoVec,
 	exchange_hash:&CryptoVec,
-	cipher:cipher::Name,
-	remote_to_local_mac:mac::Name,
-	local_to_remote_mac:mac::Name,
+	cipher:&(dyn cipher::Cipher + Send + Sync),
+	remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+	local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
 	is_server:bool,
 ) -> Result<super::cipher::CipherPair, crate::Error> {
-	let cipher = CIPHERS.get(&cipher).ok_or(crate::Error::UnknownAlgo)?;
-
-	let remote_to_local_mac = MACS.get(&remote_to_local_mac).ok_or(crate::Error::UnknownAlgo)?;
-
-	let local_to_remote_mac = MACS.get(&local_to_remote_mac).ok_or(crate::Error::UnknownAlgo)?;
+	let mut encoded = CryptoVec::new();
+
+	if let Some(shared) = shared_secret {
+		encoded.extend_ssh_mpint(shared);
+	}
+
+	compute_keys_encoded::<D>(
+		&encoded,
+		session_id,
+		exchange_hash,
+		cipher,
+		remote_to_local_mac,
+		local_to_remote_mac,
+		is_server,
+	)
+}
 
+/// Same as [`compute_keys`], with the shared secret already encoded the
+/// way it is in the exchange hash. Hybrid post-quantum methods encode it
+/// as a string rather than an mpint.
+pub(crate) fn compute_keys_encoded<D:Digest>(
+	shared_secret:&[u8],
+	session_id:&CryptoVec,
+	exchange_hash:&CryptoVec,
+	cipher:&(dyn cipher::Cipher + Send + Sync),
+	remote_to_local_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+	local_to_remote_mac:&(dyn mac::MacAlgorithm + Send + Sync),
+	is_server:bool,
+) -> Result<super::cipher::CipherPair, crate::Error> {
 	// https://tools.ietf.org/html/rfc4253#section-7.2
 	BUFFER.with(|buffer| {
 		KEY_BUF.with(|key| {
@@ -156,9 +259,7 @@ pub(crate) fn compute_keys<D:Digest>(
 
 						key.clear();
 
-						if let Some(shared) = shared_secret {
-							buffer.extend_ssh_mpint(shared);
-						}
+						buffer.extend(shared_secret);
 
 						buffer.extend(exchange_hash.as_ref());
 
@@ -180,9 +281,7 @@ pub(crate) fn compute_keys<D:Digest>(
 							// extend.
 							buffer.clear();
 
-							if let Some(shared) = shared_secret {
-								buffer.extend_ssh_mpint(shared);
-							}
+							buffer.extend(shared_secret);
 
 							buffer.extend(exchange_hash.as_ref());
 
@@ -226,7 +325,7 @@ pub(crate) fn compute_keys<D:Digest>(
 					compute_key(local_to_remote_mac_key, &mut mac, local_to_remote_mac.key_len())?;
 
 					let local_to_remote =
-						cipher.make_sealing_key(&key, &nonce, &mac, *local_to_remote_mac)?;
+						cipher.make_sealing_key(&key, &nonce, &mac, local_to_remote_mac)?;
 
 					compute_key(remote_to_local, &mut key, cipher.key_len())?;
 
@@ -235,7 +334,16 @@ pub(crate) fn compute_keys<D:Digest>(
 					compute_key(remote_to_local_mac_key, &mut mac, remote_to_local_mac.key_len())?;
 
 					let remote_to_local =
-						cipher.make_opening_key(&key, &nonce, &mac, *remote_to_local_mac)?;
+						cipher.make_opening_key(&key, &nonce, &mac, remote_to_local_mac)?;
+
+					// Clearing a `CryptoVec` overwrites its contents.
+					key.clear();
+
+					nonce.clear();
+
+					mac.clear();
+
+					buffer.borrow_mut().clear();
 
 					Ok(super::cipher::CipherPair { local_to_remote, remote_to_local })
 				})
@@ -243,3 +351,198 @@ pub(crate) fn compute_keys<D:Digest>(
 		})
 	})
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_compute_keys_clears_buffers() {
+		let session_id = CryptoVec::from_slice(b"session id");
+
+		let cipher = cipher::CIPHERS.get(&cipher::AES_256_CTR).unwrap();
+
+		let macs = mac::MacRegistry::default();
+
+		let mac = macs.get(&mac::HMAC_SHA256).unwrap();
+
+		compute_keys::<sha2::Sha256>(
+			Some(b"shared secret"),
+			&session_id,
+			&session_id,
+			*cipher,
+			mac,
+			mac,
+			false,
+		)
+		.unwrap();
+
+		for buf in [&KEY_BUF, &NONCE_BUF, &MAC_BUF, &BUFFER] {
+			buf.with(|buf| {
+				let mut buf = buf.borrow_mut();
+
+				assert!(buf.is_empty());
+
+				// Growing within the capacity exposes the backing storage
+				// as `compute_keys` left it.
+				let capacity = buf.capacity();
+
+				assert!(capacity > 0);
+
+				buf.resize(capacity);
+
+				assert!(buf.as_ref().iter().all(|&b| b == 0));
+
+				buf.clear();
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_kex_guess() {
+
+		// (client kex, server kex, negotiated kex)
+		const CASES:&[(&[kex::Name], &[kex::Name], kex::Name)] = &[
+			// Right guess.
+			(&[kex::DH_G14_SHA256], &[kex::DH_G14_SHA256], kex::DH_G14_SHA256),
+			// Right guess of a group exchange.
+			(&[kex::DH_GEX_SHA256], &[kex::DH_GEX_SHA256], kex::DH_GEX_SHA256),
+			// Wrong guess, the server picks the client's second choice.
+			(
+				&[kex::DH_G16_SHA512, kex::DH_G14_SHA256],
+				&[kex::DH_G14_SHA256],
+				kex::DH_G14_SHA256,
+			),
+			// Wrong guess of the negotiated kex: the server prefers another
+			// one, so the guessed packet is still ignored.
+			(
+				&[kex::DH_G14_SHA256, kex::DH_G16_SHA512],
+				&[kex::DH_G16_SHA512, kex::DH_G14_SHA256],
+				kex::DH_G14_SHA256,
+			),
+		];
+
+		for &(client_kex, server_kex, negotiated_kex) in CASES {
+			let client_config = client::Config {
+				preferred:crate::Preferred { kex:client_kex, ..crate::Preferred::DEFAULT },
+				send_kex_guess:true,
+				..Default::default()
+			};
+
+			let server_config = server::Config {
+				preferred:crate::Preferred { kex:server_kex, ..crate::Preferred::DEFAULT },
+				..Default::default()
+			};
+
+			test_session_with_configs(
+				client_config,
+				server_config,
+				Client {},
+				Server {},
+				move |c| {
+					async move {
+						let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+						assert_eq!(negotiated.kex, negotiated_kex);
+
+						// Rekeys guess too.
+						c.rekey().await.unwrap();
+
+						c
+					}
+				},
+				|s| async move { s },
+			)
+			.await;
+		}
+	}
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_strict_kex_prefix_injection() {
+		use std::sync::Arc;
+
+		let _ = env_logger::try_init();
+
+		let mut server_config = server::Config::default();
+
+		server_config.connection_timeout = None;
+
+		server_config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let (client_stream, mitm_client) = tokio::io::duplex(65536);
+
+		let (server_stream, mitm_server) = tokio::io::duplex(65536);
+
+		let (mut from_client, mut to_client) = tokio::io::split(mitm_client);
+
+		let (mut from_server, mut to_server) = tokio::io::split(mitm_server);
+
+		tokio::spawn(async move {
+			tokio::io::copy(&mut from_server, &mut to_client).await.ok();
+
+			to_client.shutdown().await.ok();
+		});
+
+		// A man in the middle relays the client's identification string and
+		// KEXINIT, then injects a clear-text IGNORE before the client's NEWKEYS.
+		tokio::spawn(async move {
+			let mut byte = [0];
+
+			while byte[0] != b'\n' {
+				from_client.read_exact(&mut byte).await.unwrap();
+
+				to_server.write_all(&byte).await.unwrap();
+			}
+
+			let len = from_client.read_u32().await.unwrap();
+
+			let mut kexinit = vec![0; len as usize];
+
+			from_client.read_exact(&mut kexinit).await.unwrap();
+
+			to_server.write_u32(len).await.unwrap();
+
+			to_server.write_all(&kexinit).await.unwrap();
+
+			// Padding length, IGNORE with an empty string, padding.
+			let ignore = [6, crate::msg::IGNORE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+			to_server.write_u32(ignore.len() as u32).await.unwrap();
+
+			to_server.write_all(&ignore).await.unwrap();
+
+			tokio::io::copy(&mut from_client, &mut to_server).await.ok();
+
+			to_server.shutdown().await.ok();
+		});
+
+		let server = tokio::spawn(async move {
+			server::run_stream(Arc::new(server_config), server_stream, Server {})
+				.await
+				.unwrap()
+				.await
+		});
+
+		let client =
+			client::connect_stream(Arc::new(client::Config::default()), client_stream, Client {})
+				.await;
+
+		assert!(client.is_err());
+
+		assert!(matches!(
+			server.await.unwrap(),
+			Err(crate::Error::StrictKeyExchangeViolation {
+				message_type:crate::msg::IGNORE,
+				sequence_number:1,
+			})
+		));
+	}
+}
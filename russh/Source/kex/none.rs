@@ -46,9 +46,9 @@ impl KexAlgorithm for NoneKexAlgorithm {
 		&self,
 		session_id:&russh_cryptovec::CryptoVec,
 		exchange_hash:&russh_cryptovec::CryptoVec,
-		cipher:crate::cipher::Name,
-		remote_to_local_mac:crate::mac::Name,
-		local_to_remote_mac:crate::mac::Name,
+		cipher:&(dyn crate::cipher::Cipher + Send + Sync),
+		remote_to_local_mac:&(dyn crate::mac::MacAlgorithm + Send + Sync),
+		local_to_remote_mac:&(dyn crate::mac::MacAlgorithm + Send + Sync),
 		is_server:bool,
 	) -> Result<crate::cipher::CipherPair, crate::Error> {
 		super::compute_keys::<sha2::Sha256>(
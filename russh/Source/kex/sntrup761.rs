@@ -0,0 +1,1219 @@
+// Streamlined NTRU Prime sntrup761, ported from the public domain
+// reference implementation (https://ntruprime.cr.yp.to) that OpenSSH
+// ships as `sntrup761.c`. Keys, ciphertexts and shared secrets are
+// byte-for-byte compatible with it.
+//
+// Arithmetic on secret data avoids branches and secret-dependent memory
+// accesses, as in the reference implementation.
+
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use zeroize::Zeroize;
+
+const P:usize = 761;
+
+const Q:i32 = 4591;
+
+const W:usize = 286;
+
+const Q12:i32 = (Q - 1) / 2;
+
+const SMALL_BYTES:usize = (P + 3) / 4;
+
+const RQ_BYTES:usize = 1158;
+
+const ROUNDED_BYTES:usize = 1007;
+
+const HASH_BYTES:usize = 32;
+
+pub(crate) const PUBLIC_KEY_BYTES:usize = RQ_BYTES;
+
+pub(crate) const SECRET_KEY_BYTES:usize =
+	2 * SMALL_BYTES + PUBLIC_KEY_BYTES + SMALL_BYTES + HASH_BYTES;
+
+pub(crate) const CIPHERTEXT_BYTES:usize = ROUNDED_BYTES + HASH_BYTES;
+
+pub(crate) const SHARED_SECRET_BYTES:usize = HASH_BYTES;
+
+/// A secret key, `f || 1/g || pk || rho || Hash(4, pk)`.
+pub(crate) struct SecretKey(Vec<u8>);
+
+impl Drop for SecretKey {
+	fn drop(&mut self) { self.0.zeroize(); }
+}
+
+/// -1 if `x` is nonzero, 0 otherwise.
+fn nonzero_mask(x:i32) -> i32 { -(((x as i64 | -(x as i64)) >> 63) & 1) as i32 }
+
+/// -1 if `x` is negative, 0 otherwise.
+fn negative_mask(x:i32) -> i32 { x >> 31 }
+
+/// Reduces `x` to `-Q12..=Q12`, for `|x| < 2^24`.
+fn fq_freeze(x:i32) -> i16 {
+	let x = x - Q * ((57 * x) >> 18);
+
+	(x - Q * ((29235 * x + 67108864) >> 27)) as i16
+}
+
+/// Reduces `x` to `-1..=1`, for `|x| < 2^14`.
+fn f3_freeze(x:i32) -> i8 { (x - 3 * ((10923 * x + 16384) >> 15)) as i8 }
+
+fn fq_recip(a:i16) -> i16 {
+	let mut ai = a;
+
+	for _ in 1..Q - 2 {
+		ai = fq_freeze(a as i32 * ai as i32);
+	}
+
+	ai
+}
+
+#[allow(clippy::indexing_slicing)] // SHA-512 is longer
+fn hash_prefix(b:u8, input:&[u8]) -> [u8; HASH_BYTES] {
+	let h = Sha512::new().chain_update([b]).chain_update(input).finalize();
+
+	let mut out = [0; HASH_BYTES];
+
+	out.copy_from_slice(&h[..HASH_BYTES]);
+
+	out
+}
+
+/// Sorts in constant time (djbsort).
+#[allow(clippy::indexing_slicing)] // indices are below `n`
+fn sort_u32(x:&mut [u32]) {
+	fn minmax(a:i32, b:i32) -> (i32, i32) {
+		let (a, b) = (a as i64, b as i64);
+
+		let ab = b ^ a;
+
+		let mut c = b - a;
+
+		c ^= ab & (c ^ b);
+
+		c >>= 31;
+
+		c &= ab;
+
+		((a ^ c) as i32, (b ^ c) as i32)
+	}
+
+	let mut y:Vec<i32> = x.iter().map(|&v| (v ^ 0x80000000) as i32).collect();
+
+	let n = y.len();
+
+	if n >= 2 {
+		let mut top = 1;
+
+		while top < n - top {
+			top += top;
+		}
+
+		let mut p = top;
+
+		while p > 0 {
+			for i in 0..n - p {
+				if i & p == 0 {
+					let (a, b) = minmax(y[i], y[i + p]);
+
+					y[i] = a;
+
+					y[i + p] = b;
+				}
+			}
+
+			let mut i = 0;
+
+			let mut q = top;
+
+			while q > p {
+				while i < n - q {
+					if i & p == 0 {
+						let mut a = y[i + p];
+
+						let mut r = q;
+
+						while r > p {
+							let (a_, b) = minmax(a, y[i + r]);
+
+							a = a_;
+
+							y[i + r] = b;
+
+							r >>= 1;
+						}
+
+						y[i + p] = a;
+					}
+
+					i += 1;
+				}
+
+				q >>= 1;
+			}
+
+			p >>= 1;
+		}
+	}
+
+	for (x, y) in x.iter_mut().zip(y.iter()) {
+		*x = *y as u32 ^ 0x80000000;
+	}
+
+	y.zeroize();
+}
+
+/// `P` random 32-bit integers, drawn at once like the reference
+/// implementation does, which matters to reproduce its known answers.
+fn urandom32s<R:RngCore>(rng:&mut R) -> [u32; P] {
+	let mut c = [0; 4 * P];
+
+	rng.fill_bytes(&mut c);
+
+	let mut out = [0; P];
+
+	for (out, c) in out.iter_mut().zip(c.chunks_exact(4)) {
+		let mut x = [0; 4];
+
+		x.copy_from_slice(c);
+
+		*out = u32::from_le_bytes(x);
+	}
+
+	c.zeroize();
+
+	out
+}
+
+/// A random polynomial with exactly `W` nonzero coefficients in `-1..=1`.
+fn short_random<R:RngCore>(rng:&mut R) -> [i8; P] {
+	let mut l = urandom32s(rng);
+
+	for (i, l) in l.iter_mut().enumerate() {
+		*l = if i < W { *l & !1 } else { (*l & !3) | 1 };
+	}
+
+	sort_u32(&mut l);
+
+	let mut out = [0; P];
+
+	for (out, l) in out.iter_mut().zip(l.iter()) {
+		*out = (l & 3) as i8 - 1;
+	}
+
+	l.zeroize();
+
+	out
+}
+
+/// A random polynomial with coefficients in `-1..=1`.
+fn small_random<R:RngCore>(rng:&mut R) -> [i8; P] {
+	let mut l = urandom32s(rng);
+
+	let mut out = [0; P];
+
+	for (out, l) in out.iter_mut().zip(l.iter()) {
+		*out = ((((l & 0x3fffffff) * 3) >> 30) as i32 - 1) as i8;
+	}
+
+	l.zeroize();
+
+	out
+}
+
+/// Product in `R/3 = (Z/3)[x]/(x^P - x - 1)`.
+#[allow(clippy::indexing_slicing)] // indices are below `2 * P - 1`
+fn r3_mult(f:&[i8; P], g:&[i8; P]) -> [i8; P] {
+	let mut fg = [0i8; P + P - 1];
+
+	for i in 0..P {
+		let mut result = 0;
+
+		for j in 0..=i {
+			result = f3_freeze(result as i32 + f[j] as i32 * g[i - j] as i32);
+		}
+
+		fg[i] = result;
+	}
+
+	for i in P..P + P - 1 {
+		let mut result = 0;
+
+		for j in i - P + 1..P {
+			result = f3_freeze(result as i32 + f[j] as i32 * g[i - j] as i32);
+		}
+
+		fg[i] = result;
+	}
+
+	for i in (P..P + P - 1).rev() {
+		fg[i - P] = f3_freeze(fg[i - P] as i32 + fg[i] as i32);
+
+		fg[i - P + 1] = f3_freeze(fg[i - P + 1] as i32 + fg[i] as i32);
+	}
+
+	let mut h = [0; P];
+
+	h.copy_from_slice(&fg[..P]);
+
+	fg.zeroize();
+
+	h
+}
+
+/// Product in `R/q = (Z/q)[x]/(x^P - x - 1)`, of `f` by a small `g`.
+#[allow(clippy::indexing_slicing)] // indices are below `2 * P - 1`
+fn rq_mult_small(f:&[i16; P], g:&[i8; P]) -> [i16; P] {
+	let mut fg = [0i16; P + P - 1];
+
+	for i in 0..P {
+		let mut result = 0;
+
+		for j in 0..=i {
+			result = fq_freeze(result as i32 + f[j] as i32 * g[i - j] as i32);
+		}
+
+		fg[i] = result;
+	}
+
+	for i in P..P + P - 1 {
+		let mut result = 0;
+
+		for j in i - P + 1..P {
+			result = fq_freeze(result as i32 + f[j] as i32 * g[i - j] as i32);
+		}
+
+		fg[i] = result;
+	}
+
+	for i in (P..P + P - 1).rev() {
+		fg[i - P] = fq_freeze(fg[i - P] as i32 + fg[i] as i32);
+
+		fg[i - P + 1] = fq_freeze(fg[i - P + 1] as i32 + fg[i] as i32);
+	}
+
+	let mut h = [0; P];
+
+	h.copy_from_slice(&fg[..P]);
+
+	fg.zeroize();
+
+	h
+}
+
+/// Inverse in `R/3`, if it exists.
+#[allow(clippy::indexing_slicing)] // indices are below `P + 1`
+fn r3_recip(input:&[i8; P]) -> Option<[i8; P]> {
+	let mut f = [0i8; P + 1];
+
+	let mut g = [0i8; P + 1];
+
+	let mut v = [0i8; P + 1];
+
+	let mut r = [0i8; P + 1];
+
+	r[0] = 1;
+
+	f[0] = 1;
+
+	f[P - 1] = -1;
+
+	f[P] = -1;
+
+	for i in 0..P {
+		g[P - 1 - i] = input[i];
+	}
+
+	let mut delta:i32 = 1;
+
+	for _ in 0..2 * P - 1 {
+		v.copy_within(0..P, 1);
+
+		v[0] = 0;
+
+		let sign = -(g[0] as i32) * f[0] as i32;
+
+		let swap = negative_mask(-delta) & nonzero_mask(g[0] as i32);
+
+		delta ^= swap & (delta ^ -delta);
+
+		delta += 1;
+
+		for i in 0..P + 1 {
+			let t = swap as i8 & (f[i] ^ g[i]);
+
+			f[i] ^= t;
+
+			g[i] ^= t;
+
+			let t = swap as i8 & (v[i] ^ r[i]);
+
+			v[i] ^= t;
+
+			r[i] ^= t;
+		}
+
+		for i in 0..P + 1 {
+			g[i] = f3_freeze(g[i] as i32 + sign * f[i] as i32);
+
+			r[i] = f3_freeze(r[i] as i32 + sign * v[i] as i32);
+		}
+
+		g.copy_within(1..P + 1, 0);
+
+		g[P] = 0;
+	}
+
+	let sign = f[0];
+
+	let mut out = [0; P];
+
+	for i in 0..P {
+		out[i] = sign * v[P - 1 - i];
+	}
+
+	f.zeroize();
+
+	g.zeroize();
+
+	v.zeroize();
+
+	r.zeroize();
+
+	if delta == 0 { Some(out) } else { None }
+}
+
+/// Inverse of `3 * input` in `R/q`, which always exists for small
+/// nonzero `input`.
+#[allow(clippy::indexing_slicing)] // indices are below `P + 1`
+fn rq_recip3(input:&[i8; P]) -> [i16; P] {
+	let mut f = [0i16; P + 1];
+
+	let mut g = [0i16; P + 1];
+
+	let mut v = [0i16; P + 1];
+
+	let mut r = [0i16; P + 1];
+
+	r[0] = fq_recip(3);
+
+	f[0] = 1;
+
+	f[P - 1] = -1;
+
+	f[P] = -1;
+
+	for i in 0..P {
+		g[P - 1 - i] = input[i] as i16;
+	}
+
+	let mut delta:i32 = 1;
+
+	for _ in 0..2 * P - 1 {
+		v.copy_within(0..P, 1);
+
+		v[0] = 0;
+
+		let swap = negative_mask(-delta) & nonzero_mask(g[0] as i32);
+
+		delta ^= swap & (delta ^ -delta);
+
+		delta += 1;
+
+		for i in 0..P + 1 {
+			let t = swap as i16 & (f[i] ^ g[i]);
+
+			f[i] ^= t;
+
+			g[i] ^= t;
+
+			let t = swap as i16 & (v[i] ^ r[i]);
+
+			v[i] ^= t;
+
+			r[i] ^= t;
+		}
+
+		let f0 = f[0] as i32;
+
+		let g0 = g[0] as i32;
+
+		for i in 0..P + 1 {
+			g[i] = fq_freeze(f0 * g[i] as i32 - g0 * f[i] as i32);
+
+			r[i] = fq_freeze(f0 * r[i] as i32 - g0 * v[i] as i32);
+		}
+
+		g.copy_within(1..P + 1, 0);
+
+		g[P] = 0;
+	}
+
+	let scale = fq_recip(f[0]) as i32;
+
+	let mut out = [0; P];
+
+	for i in 0..P {
+		out[i] = fq_freeze(scale * v[P - 1 - i] as i32);
+	}
+
+	f.zeroize();
+
+	g.zeroize();
+
+	v.zeroize();
+
+	r.zeroize();
+
+	out
+}
+
+/// Rounds each coefficient to the nearest multiple of 3.
+fn round(a:&[i16; P]) -> [i16; P] {
+	let mut out = [0; P];
+
+	for (out, &a) in out.iter_mut().zip(a.iter()) {
+		*out = a - f3_freeze(a as i32) as i16;
+	}
+
+	out
+}
+
+#[allow(clippy::indexing_slicing)] // length is P
+fn small_encode(f:&[i8; P]) -> [u8; SMALL_BYTES] {
+	let mut s = [0; SMALL_BYTES];
+
+	for (s, f) in s.iter_mut().zip(f.chunks(4)) {
+		for (k, f) in f.iter().enumerate() {
+			*s |= ((f + 1) as u8) << (2 * k);
+		}
+	}
+
+	s
+}
+
+fn small_decode(s:&[u8]) -> [i8; P] {
+	let mut f = [0; P];
+
+	for (f, s) in f.chunks_mut(4).zip(s.iter()) {
+		for (k, f) in f.iter_mut().enumerate() {
+			*f = ((s >> (2 * k)) & 3) as i8 - 1;
+		}
+	}
+
+	f
+}
+
+/// Encodes `r`, with `0 <= r[i] < m[i] < 16384`.
+#[allow(clippy::indexing_slicing)] // indices checked
+fn encode(out:&mut Vec<u8>, r:&[u16], m:&[u16]) {
+	if r.len() == 1 {
+		let mut r = r[0];
+
+		let mut m = m[0];
+
+		while m > 1 {
+			out.push(r as u8);
+
+			r >>= 8;
+
+			m = (m + 255) >> 8;
+		}
+	} else if r.len() > 1 {
+		let mut r2 = Vec::with_capacity((r.len() + 1) / 2);
+
+		let mut m2 = Vec::with_capacity((r.len() + 1) / 2);
+
+		for (r, m) in r.chunks(2).zip(m.chunks(2)) {
+			if r.len() == 2 {
+				let m0 = m[0] as u32;
+
+				let mut r = r[0] as u32 + r[1] as u32 * m0;
+
+				let mut m = m[1] as u32 * m0;
+
+				while m >= 16384 {
+					out.push(r as u8);
+
+					r >>= 8;
+
+					m = (m + 255) >> 8;
+				}
+
+				r2.push(r as u16);
+
+				m2.push(m as u16);
+			} else {
+				r2.push(r[0]);
+
+				m2.push(m[0]);
+			}
+		}
+
+		encode(out, &r2, &m2);
+	}
+}
+
+/// Decodes the output of [`encode`]. Invalid inputs decode to some
+/// output within bounds.
+#[allow(clippy::indexing_slicing)] // indices checked
+fn decode(s:&[u8], m:&[u16]) -> Vec<u16> {
+	let byte = |i:usize| s.get(i).copied().unwrap_or(0) as u32;
+
+	if m.len() == 1 {
+		return vec![if m[0] == 1 {
+			0
+		} else if m[0] <= 256 {
+			(byte(0) % m[0] as u32) as u16
+		} else {
+			((byte(0) + (byte(1) << 8)) % m[0] as u32) as u16
+		}];
+	}
+
+	let mut pos = 0;
+
+	let mut m2 = Vec::with_capacity((m.len() + 1) / 2);
+
+	let mut bottom = Vec::with_capacity(m.len() / 2);
+
+	for m in m.chunks(2) {
+		if m.len() == 2 {
+			let mm = m[0] as u32 * m[1] as u32;
+
+			if mm > 256 * 16383 {
+				bottom.push((256 * 256, byte(pos) + 256 * byte(pos + 1)));
+
+				pos += 2;
+
+				m2.push(((((mm + 255) >> 8) + 255) >> 8) as u16);
+			} else if mm >= 16384 {
+				bottom.push((256, byte(pos)));
+
+				pos += 1;
+
+				m2.push(((mm + 255) >> 8) as u16);
+			} else {
+				bottom.push((1, 0));
+
+				m2.push(mm as u16);
+			}
+		} else {
+			m2.push(m[0]);
+		}
+	}
+
+	let r2 = decode(s.get(pos..).unwrap_or(&[]), &m2);
+
+	let mut out = Vec::with_capacity(m.len());
+
+	for (i, (t, r)) in bottom.into_iter().enumerate() {
+		let r = r + t * r2[i] as u32;
+
+		let (m0, m1) = (m[2 * i] as u32, m[2 * i + 1] as u32);
+
+		out.push((r % m0) as u16);
+
+		out.push(((r / m0) % m1) as u16);
+	}
+
+	if m.len() % 2 == 1 {
+		out.push(r2[r2.len() - 1]);
+	}
+
+	out
+}
+
+fn rq_encode(r:&[i16; P]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(RQ_BYTES);
+
+	let r:Vec<u16> = r.iter().map(|&x| (x as i32 + Q12) as u16).collect();
+
+	encode(&mut out, &r, &[Q as u16; P]);
+
+	out
+}
+
+fn rq_decode(s:&[u8]) -> [i16; P] {
+	let mut out = [0; P];
+
+	for (out, r) in out.iter_mut().zip(decode(s, &[Q as u16; P])) {
+		*out = (r as i32 - Q12) as i16;
+	}
+
+	out
+}
+
+fn rounded_encode(r:&[i16; P]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(ROUNDED_BYTES);
+
+	let r:Vec<u16> = r.iter().map(|&x| (((x as i32 + Q12) * 10923) >> 15) as u16).collect();
+
+	encode(&mut out, &r, &[((Q + 2) / 3) as u16; P]);
+
+	out
+}
+
+fn rounded_decode(s:&[u8]) -> [i16; P] {
+	let mut out = [0; P];
+
+	for (out, r) in out.iter_mut().zip(decode(s, &[((Q + 2) / 3) as u16; P])) {
+		*out = (r as i32 * 3 - Q12) as i16;
+	}
+
+	out
+}
+
+/// Computes the ciphertext of `r` and its confirmation hash, and returns
+/// them with the encoding of `r`.
+fn hide(r:&[i8; P], pk:&[u8], cache:&[u8; HASH_BYTES]) -> (Vec<u8>, [u8; SMALL_BYTES]) {
+	let r_enc = small_encode(r);
+
+	let h = rq_decode(pk);
+
+	let mut c = rounded_encode(&round(&rq_mult_small(&h, r)));
+
+	let mut x = [0; 2 * HASH_BYTES];
+
+	let (x0, x1) = x.split_at_mut(HASH_BYTES);
+
+	x0.copy_from_slice(&hash_prefix(3, &r_enc));
+
+	x1.copy_from_slice(cache);
+
+	c.extend_from_slice(&hash_prefix(2, &x));
+
+	(c, r_enc)
+}
+
+fn hash_session(b:u8, y:&[u8], z:&[u8]) -> [u8; SHARED_SECRET_BYTES] {
+	let mut x = Vec::with_capacity(HASH_BYTES + CIPHERTEXT_BYTES);
+
+	x.extend_from_slice(&hash_prefix(3, y));
+
+	x.extend_from_slice(z);
+
+	let k = hash_prefix(b, &x);
+
+	x.zeroize();
+
+	k
+}
+
+/// Generates a key pair.
+pub(crate) fn keypair<R:RngCore>(rng:&mut R) -> (Vec<u8>, SecretKey) {
+	let (mut g, ginv) = loop {
+		let g = small_random(rng);
+
+		if let Some(ginv) = r3_recip(&g) {
+			break (g, ginv);
+		}
+	};
+
+	let mut f = short_random(rng);
+
+	let h = rq_mult_small(&rq_recip3(&f), &g);
+
+	let pk = rq_encode(&h);
+
+	let mut sk = Vec::with_capacity(SECRET_KEY_BYTES);
+
+	sk.extend_from_slice(&small_encode(&f));
+
+	sk.extend_from_slice(&small_encode(&ginv));
+
+	sk.extend_from_slice(&pk);
+
+	let mut rho = [0; SMALL_BYTES];
+
+	rng.fill_bytes(&mut rho);
+
+	sk.extend_from_slice(&rho);
+
+	sk.extend_from_slice(&hash_prefix(4, &pk));
+
+	f.zeroize();
+
+	g.zeroize();
+
+	rho.zeroize();
+
+	(pk, SecretKey(sk))
+}
+
+/// Encapsulates a random shared secret to `pk`, returning the ciphertext
+/// and the shared secret.
+pub(crate) fn encapsulate<R:RngCore>(
+	pk:&[u8],
+	rng:&mut R,
+) -> Result<(Vec<u8>, [u8; SHARED_SECRET_BYTES]), crate::Error> {
+	if pk.len() != PUBLIC_KEY_BYTES {
+		return Err(crate::Error::Kex);
+	}
+
+	let cache = hash_prefix(4, pk);
+
+	let mut r = short_random(rng);
+
+	let (c, mut r_enc) = hide(&r, pk, &cache);
+
+	let k = hash_session(1, &r_enc, &c);
+
+	r.zeroize();
+
+	r_enc.zeroize();
+
+	Ok((c, k))
+}
+
+/// Decapsulates the shared secret in `c`. Invalid ciphertexts yield a
+/// pseudo-random secret derived from `sk` (implicit rejection).
+#[allow(clippy::indexing_slicing)] // lengths are constant
+pub(crate) fn decapsulate(
+	c:&[u8],
+	sk:&SecretKey,
+) -> Result<[u8; SHARED_SECRET_BYTES], crate::Error> {
+	if c.len() != CIPHERTEXT_BYTES {
+		return Err(crate::Error::Kex);
+	}
+
+	let (f, rest) = sk.0.split_at(SMALL_BYTES);
+
+	let (ginv, rest) = rest.split_at(SMALL_BYTES);
+
+	let (pk, rest) = rest.split_at(PUBLIC_KEY_BYTES);
+
+	let (rho, cache) = rest.split_at(SMALL_BYTES);
+
+	let mut f = small_decode(f);
+
+	let mut ginv = small_decode(ginv);
+
+	let mut cache_ = [0; HASH_BYTES];
+
+	cache_.copy_from_slice(cache);
+
+	// Decrypt.
+	let cf = rq_mult_small(&rounded_decode(&c[..ROUNDED_BYTES]), &f);
+
+	let mut e = [0; P];
+
+	for (e, cf) in e.iter_mut().zip(cf.iter()) {
+		*e = f3_freeze(fq_freeze(3 * *cf as i32) as i32);
+	}
+
+	let ev = r3_mult(&e, &ginv);
+
+	let weight = ev.iter().map(|&x| (x & 1) as i32).sum::<i32>();
+
+	let mask = nonzero_mask(weight - W as i32) as i8;
+
+	let mut r = [0; P];
+
+	for i in 0..P {
+		r[i] = if i < W { ((ev[i] ^ 1) & !mask) ^ 1 } else { ev[i] & !mask };
+	}
+
+	// Re-encrypt, and compare.
+	let (cnew, mut r_enc) = hide(&r, pk, &cache_);
+
+	let equal = cnew.as_slice().ct_eq(c);
+
+	let mut y = [0; SMALL_BYTES];
+
+	for i in 0..SMALL_BYTES {
+		y[i] = u8::conditional_select(&rho[i], &r_enc[i], equal);
+	}
+
+	let k = hash_session(equal.unwrap_u8(), &y, c);
+
+	f.zeroize();
+
+	ginv.zeroize();
+
+	e.zeroize();
+
+	r.zeroize();
+
+	r_enc.zeroize();
+
+	y.zeroize();
+
+	Ok(k)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+
+	/// Deterministic randomness: SHA-512 of the label and a counter.
+	pub(crate) struct HashRng {
+		label:&'static [u8],
+		counter:u64,
+		buffer:Vec<u8>,
+	}
+
+	impl HashRng {
+		pub(crate) fn new(label:&'static [u8]) -> Self { HashRng { label, counter:0, buffer:Vec::new() } }
+	}
+
+	impl RngCore for HashRng {
+		fn next_u32(&mut self) -> u32 {
+			let mut c = [0; 4];
+
+			self.fill_bytes(&mut c);
+
+			u32::from_le_bytes(c)
+		}
+
+		fn next_u64(&mut self) -> u64 {
+			let mut c = [0; 8];
+
+			self.fill_bytes(&mut c);
+
+			u64::from_le_bytes(c)
+		}
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		fn fill_bytes(&mut self, dest:&mut [u8]) {
+			while self.buffer.len() < dest.len() {
+				self.buffer.extend_from_slice(
+					&Sha512::new()
+						.chain_update(self.label)
+						.chain_update(self.counter.to_le_bytes())
+						.finalize(),
+				);
+
+				self.counter += 1;
+			}
+
+			dest.copy_from_slice(&self.buffer[..dest.len()]);
+
+			self.buffer.drain(..dest.len());
+		}
+
+		fn try_fill_bytes(&mut self, dest:&mut [u8]) -> Result<(), rand::Error> {
+			self.fill_bytes(dest);
+
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_sizes() {
+		assert_eq!(SMALL_BYTES, 191);
+
+		assert_eq!(SECRET_KEY_BYTES, 1763);
+
+		assert_eq!(CIPHERTEXT_BYTES, 1039);
+
+		let (pk, sk) = keypair(&mut rand::thread_rng());
+
+		assert_eq!(pk.len(), PUBLIC_KEY_BYTES);
+
+		assert_eq!(sk.0.len(), SECRET_KEY_BYTES);
+
+		assert_eq!(rounded_encode(&[0; P]).len(), ROUNDED_BYTES);
+	}
+
+	#[test]
+	fn test_sort() {
+		let mut x:Vec<u32> = (0..P as u32).map(|i| i.wrapping_mul(2654435761)).collect();
+
+		let mut sorted = x.clone();
+
+		sorted.sort_unstable();
+
+		sort_u32(&mut x);
+
+		assert_eq!(x, sorted);
+	}
+
+	#[test]
+	fn test_encode_roundtrip() {
+		let (pk, _) = keypair(&mut rand::thread_rng());
+
+		assert_eq!(rq_encode(&rq_decode(&pk)), pk);
+	}
+
+	/// The AES-256 CTR_DRBG the NIST known answer tests draw their
+	/// randomness from (`rng.c` of the submissions).
+	struct KatRng {
+		key:[u8; 32],
+		v:[u8; 16],
+	}
+
+	impl KatRng {
+		fn new(seed:&[u8]) -> Self {
+			let mut rng = KatRng { key:[0; 32], v:[0; 16] };
+
+			rng.update(seed);
+
+			rng
+		}
+
+		fn block(&mut self) -> [u8; 16] {
+			use aes::{
+				cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+				Aes256,
+			};
+
+			for v in self.v.iter_mut().rev() {
+				*v = v.wrapping_add(1);
+
+				if *v != 0 {
+					break;
+				}
+			}
+
+			let mut block = GenericArray::from(self.v);
+
+			Aes256::new(&GenericArray::from(self.key)).encrypt_block(&mut block);
+
+			block.into()
+		}
+
+		#[allow(clippy::indexing_slicing)] // lengths are constant
+		fn update(&mut self, provided:&[u8]) {
+			let mut temp = [0; 48];
+
+			for chunk in temp.chunks_exact_mut(16) {
+				chunk.copy_from_slice(&self.block());
+			}
+
+			for (t, p) in temp.iter_mut().zip(provided) {
+				*t ^= p;
+			}
+
+			self.key.copy_from_slice(&temp[..32]);
+
+			self.v.copy_from_slice(&temp[32..]);
+		}
+	}
+
+	impl RngCore for KatRng {
+		fn next_u32(&mut self) -> u32 {
+			let mut c = [0; 4];
+
+			self.fill_bytes(&mut c);
+
+			u32::from_le_bytes(c)
+		}
+
+		fn next_u64(&mut self) -> u64 {
+			let mut c = [0; 8];
+
+			self.fill_bytes(&mut c);
+
+			u64::from_le_bytes(c)
+		}
+
+		#[allow(clippy::indexing_slicing)] // chunks are at most 16 bytes
+		fn fill_bytes(&mut self, dest:&mut [u8]) {
+			for chunk in dest.chunks_mut(16) {
+				let block = self.block();
+
+				chunk.copy_from_slice(&block[..chunk.len()]);
+			}
+
+			self.update(&[]);
+		}
+
+		fn try_fill_bytes(&mut self, dest:&mut [u8]) -> Result<(), rand::Error> {
+			self.fill_bytes(dest);
+
+			Ok(())
+		}
+	}
+
+	/// The known answers of the NIST submission, each drawing the
+	/// randomness of key generation and encapsulation from its seed.
+	#[test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_known_answers() {
+		let kat = include_str!("../../../files/kat/sntrup761.rsp");
+
+		let entries = kat.split("\n\n").filter(|entry| entry.starts_with("count = "));
+
+		let mut count = 0;
+
+		for entry in entries {
+			let field = |name:&str| -> Vec<u8> {
+				let hex = entry
+					.lines()
+					.find_map(|line| line.strip_prefix(name)?.strip_prefix(" = "))
+					.unwrap();
+
+				(0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+			};
+
+			let mut rng = KatRng::new(&field("seed"));
+
+			let (pk, sk) = keypair(&mut rng);
+
+			assert_eq!(pk, field("pk"), "count = {}", count);
+
+			assert_eq!(sk.0, field("sk"), "count = {}", count);
+
+			let (c, k) = encapsulate(&pk, &mut rng).unwrap();
+
+			assert_eq!(c, field("ct"), "count = {}", count);
+
+			assert_eq!(&k[..], &field("ss")[..], "count = {}", count);
+
+			assert_eq!(decapsulate(&c, &sk).unwrap(), k);
+
+			// A modified ciphertext is implicitly rejected.
+			let mut c = c;
+
+			c[0] ^= 1;
+
+			assert_ne!(decapsulate(&c, &sk).unwrap(), k);
+
+			count += 1;
+		}
+
+		assert_eq!(count, 10);
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	#[cfg(feature = "rs-crypto")]
+	async fn test_sntrup761x25519() {
+
+		// Both sides prefer it by default.
+		test_session_with_configs(
+			client::Config::default(),
+			server::Config::default(),
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.kex, kex::SNTRUP761X25519_SHA512);
+
+					c.rekey().await.unwrap();
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+
+		// Peers without it fall back to the next common algorithm.
+		let server_config = server::Config {
+			preferred:crate::Preferred { kex:&[kex::CURVE25519], ..crate::Preferred::DEFAULT },
+			..Default::default()
+		};
+
+		test_session_with_configs(
+			client::Config::default(),
+			server_config,
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.kex, kex::CURVE25519);
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+
+	/// `ssh` connecting to a russh server which only accepts
+	/// `sntrup761x25519-sha512@openssh.com`, checking the encapsulation
+	/// against the OpenSSH decapsulation. Run with `cargo test --
+	/// --ignored`, with `ssh` (OpenSSH 8.5 or later) in the `PATH`.
+	#[cfg(all(unix, feature = "rs-crypto"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_sntrup761x25519_openssh_client() {
+		use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+		use tokio::process::Command;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.data(channel, CryptoVec::from_slice(b"ok\n"))?;
+
+				session.send_exit_and_close(channel, 0);
+
+				Ok((self, session))
+			}
+		}
+
+		let mut config = server::Config {
+			preferred:crate::Preferred {
+				kex:&[kex::SNTRUP761X25519_SHA512],
+				..crate::Preferred::DEFAULT
+			},
+			..Default::default()
+		};
+
+		config.keys.push(generate_keypair());
+
+		let addr:SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+		let server =
+			server::start(Arc::new(config), addr, |_:SocketAddr| ServerHandle {}).await.unwrap();
+
+		let addr = *server.local_addrs().first().unwrap();
+
+		let ssh = Command::new("ssh")
+			.args(["-p", &addr.port().to_string()])
+			.args(["-o", "KexAlgorithms=sntrup761x25519-sha512@openssh.com"])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.arg(format!("user@{}", addr.ip()))
+			.arg("true")
+			.stdin(std::process::Stdio::null())
+			.output();
+
+		let output = tokio::time::timeout(Duration::from_secs(30), ssh).await.unwrap().unwrap();
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		assert!(output.status.success(), "{}", stderr);
+
+		assert_eq!(output.stdout, b"ok\n");
+
+		server.shutdown(Duration::from_secs(1)).await;
+	}
+}
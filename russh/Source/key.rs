@@ -13,7 +13,7 @@
 // limitations under the License.
 //
 use russh_cryptovec::CryptoVec;
-use russh_keys::{encoding::*, key::*};
+use russh_keys::{PublicKeyBase64, certificate::Certificate, encoding::*, key::*};
 
 #[doc(hidden)]
 pub trait PubKey {
@@ -31,6 +31,8 @@ impl PubKey for PublicKey {
 
 				buffer.extend_ssh_string(public.as_bytes());
 			},
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => buffer.extend_ssh_string(&self.public_key_bytes()),
 			#[cfg(feature = "openssl")]
 			PublicKey::RSA { ref key, .. } => {
 				#[allow(clippy::unwrap_used)] // type known
@@ -52,6 +54,10 @@ impl PubKey for PublicKey {
 	}
 }
 
+impl PubKey for Certificate {
+	fn push_to(&self, buffer:&mut CryptoVec) { buffer.extend_ssh_string(&self.public_key_bytes()); }
+}
+
 impl PubKey for KeyPair {
 	fn push_to(&self, buffer:&mut CryptoVec) {
 		match self {
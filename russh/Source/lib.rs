@@ -115,14 +115,15 @@ mod compression;
 mod key;
 mod msg;
 mod negotiation;
+mod pool;
 mod ssh_read;
 mod sshbuffer;
 
-pub use negotiation::Preferred;
+pub use negotiation::{Negotiated, Preferred};
 
 mod pty;
 
-pub use pty::Pty;
+pub use pty::{Pty, TerminalModes};
 pub use sshbuffer::SshId;
 
 macro_rules! push_packet {
@@ -147,7 +148,7 @@ macro_rules! push_packet {
 }
 
 mod channels;
-pub use channels::{Channel, ChannelMsg};
+pub use channels::{Channel, ChannelCloseInfo, ChannelMsg, ChannelReadHalf, ChannelWriteHalf};
 
 mod channel_stream;
 pub use channel_stream::ChannelStream;
@@ -155,12 +156,28 @@ pub use channel_stream::ChannelStream;
 mod parsing;
 mod session;
 
+mod stats;
+pub use stats::ConnectionStats;
+
+mod connection_info;
+pub use connection_info::ConnectionInfo;
+
 /// Server side of this library.
 pub mod server;
 
 /// Client side of this library.
 pub mod client;
 
+pub mod x11;
+
+/// SFTP client and server.
+#[cfg(feature = "sftp")]
+pub mod sftp;
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_harness;
+
 #[derive(Debug, Error)]
 pub enum Error {
 	/// The key file could not be parsed.
@@ -199,6 +216,22 @@ pub enum Error {
 	#[error("invalid SSH version string")]
 	Version,
 
+	/// The peer sent more lines than allowed before its identification
+	/// string, see [`client::Config::max_preamble_lines`] and
+	/// [`server::Config::max_preamble_lines`].
+	#[error("More than {0} lines before the identification string")]
+	TooManyPreambleLines(usize),
+
+	/// The peer sent more bytes than allowed before its identification
+	/// string, see [`client::Config::max_preamble_bytes`] and
+	/// [`server::Config::max_preamble_bytes`].
+	#[error("More than {0} bytes before the identification string")]
+	PreambleTooLong(usize),
+
+	/// Our [`SshId`] is not a valid identification string.
+	#[error("Invalid identification string ({0})")]
+	InvalidSshId(&'static str),
+
 	/// Error during key exchange.
 	#[error("Key exchange failed")]
 	Kex,
@@ -223,6 +256,10 @@ pub enum Error {
 	#[error("Unknown server key")]
 	UnknownKey,
 
+	/// The server has several host keys of this type.
+	#[error("Several host keys of type {0}")]
+	DuplicateHostKey(&'static str),
+
 	/// The server provided a wrong signature.
 	#[error("Wrong server signature")]
 	WrongServerSig,
@@ -235,10 +272,22 @@ pub enum Error {
 	#[error("Failed to open channel ({0:?})")]
 	ChannelOpenFailure(ChannelOpenFailure),
 
+	/// The peer refused a channel request, such as running a command.
+	#[error("Channel request refused")]
+	ChannelRequestFailure,
+
+	/// The server refused a global request, such as a port forwarding.
+	#[error("Global request refused")]
+	GlobalRequestFailure,
+
 	/// Disconnected
 	#[error("Disconnected")]
 	Disconnect,
 
+	/// The peer ended the session with a disconnect message.
+	#[error("Disconnected by the peer ({reason:?}): {description}")]
+	Disconnected { reason:Disconnect, description:String },
+
 	/// No home directory found when trying to learn new host key.
 	#[error("No home directory when saving host key")]
 	NoHomeDir,
@@ -256,6 +305,32 @@ pub enum Error {
 	#[error("Connection timeout")]
 	ConnectionTimeout,
 
+	/// The TCP connection was not established within
+	/// [`client::Config::connection_timeout`].
+	#[error("Timed out connecting")]
+	ConnectTimeout,
+
+	/// The version exchange and initial key exchange did not complete
+	/// within [`client::Config::handshake_timeout`].
+	#[error("Timed out during the handshake")]
+	HandshakeTimeout,
+
+	/// The remote side did not reply to the last keepalives, see
+	/// [`client::Config::keepalive_interval`] and
+	/// [`server::Config::keepalive_interval`].
+	#[error("No reply to keepalives")]
+	KeepaliveTimeout,
+
+	/// Nothing was received from the client within
+	/// [`server::Config::inactivity_timeout`].
+	#[error("Inactivity timeout")]
+	InactivityTimeout,
+
+	/// The client was not authenticated within
+	/// [`server::Config::auth_timeout`].
+	#[error("Authentication timeout")]
+	AuthTimeout,
+
 	/// Missing authentication method.
 	#[error("No authentication method")]
 	NoAuthMethod,
@@ -269,6 +344,50 @@ pub enum Error {
 	#[error("Failed to decrypt a packet")]
 	DecryptionError,
 
+	/// The peer announced a packet larger than the configured limit.
+	#[error("Packet of {length} bytes exceeds the limit of {limit} bytes")]
+	PacketTooLarge { length:usize, limit:usize },
+
+	/// A channel window size or maximum packet size is out of bounds, see
+	/// [`WindowParams`].
+	#[error(
+		"Invalid window size ({}) or maximum packet size ({})",
+		.0.window_size,
+		.0.maximum_packet_size
+	)]
+	InvalidWindowParams(WindowParams),
+
+	/// Switching to the `none` cipher is not allowed by the configuration.
+	#[error("The none cipher is not allowed")]
+	NoneCipherNotAllowed,
+
+	/// A key exchange is already in progress.
+	#[error("Key exchange already in progress")]
+	RekeyInProgress,
+
+	/// The Diffie-Hellman group requested by the client, or sent by the
+	/// server, is unacceptable.
+	#[error("Unacceptable Diffie-Hellman group")]
+	InvalidDhGroup,
+
+	/// A packet other than a key exchange message was received during the
+	/// initial key exchange, while strict key exchange is in effect.
+	#[error("Strict key exchange violation (message {message_type}, seqn {sequence_number})")]
+	StrictKeyExchangeViolation { message_type:u8, sequence_number:u32 },
+
+	/// A [`Signer`] could not sign an authentication request.
+	#[error("Signer failed: {0}")]
+	Signer(SignerError),
+
+	/// An invalid line in an ssh_config file.
+	#[error("Invalid ssh_config line {line}")]
+	SshConfig { line:usize },
+
+	/// The SFTP server refused a request.
+	#[cfg(feature = "sftp")]
+	#[error("SFTP request failed ({:?}): {}", .0.code, .0.message)]
+	Sftp(sftp::Status),
+
 	#[error(transparent)]
 	Keys(#[from] russh_keys::Error),
 
@@ -302,11 +421,19 @@ pub enum Error {
 pub struct SendError {}
 
 /// The number of bytes read/written, and the number of seconds before a key
-/// re-exchange is requested.
+/// re-exchange is requested, like the `RekeyLimit` of OpenSSH.
+///
+/// Both the client and the server [`Config`](client::Config::limits) take
+/// their byte and time limits from here, rather than from separate fields.
+/// Whatever the limits, keys are also re-exchanged before the sequence
+/// numbers of packets wrap.
 #[derive(Debug, Clone)]
 pub struct Limits {
+	/// The bytes sent with the same keys.
 	pub rekey_write_limit:usize,
+	/// The bytes received with the same keys.
 	pub rekey_read_limit:usize,
+	/// The time elapsed since the last key exchange.
 	pub rekey_time_limit:std::time::Duration,
 }
 
@@ -324,6 +451,21 @@ impl Limits {
 	}
 }
 
+// Every packet carries at least one byte of payload, so capping the byte
+// limits also forces a key re-exchange well before 2^32 packets have been
+// sent with the same keys, where sequence numbers wrap.
+const MAX_REKEY_BYTES:usize = 1 << 31;
+
+impl Limits {
+	pub(crate) fn write_limit_reached(&self, write_buffer:&sshbuffer::SSHBuffer) -> bool {
+		write_buffer.bytes >= self.rekey_write_limit.min(MAX_REKEY_BYTES)
+	}
+
+	pub(crate) fn read_limit_reached(&self, read_buffer:&sshbuffer::SSHBuffer) -> bool {
+		read_buffer.bytes >= self.rekey_read_limit.min(MAX_REKEY_BYTES)
+	}
+}
+
 impl Default for Limits {
 	fn default() -> Self {
 		// Following the recommendations of
@@ -336,11 +478,11 @@ impl Default for Limits {
 	}
 }
 
-pub use auth::{AgentAuthError, MethodSet, Signer};
+pub use auth::{AgentSigner, MethodSet, Signer, SignerError};
 
 /// A reason for disconnection.
 #[allow(missing_docs)] // This should be relatively self-explanatory.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Disconnect {
 	HostNotAllowedToConnect = 1,
 	ProtocolError = 2,
@@ -360,13 +502,37 @@ pub enum Disconnect {
 	IllegalUserName = 15,
 }
 
+impl Disconnect {
+	/// The reason with code `code`, unknown codes being read as
+	/// [`Disconnect::ProtocolError`].
+	pub(crate) fn from_code(code:u32) -> Self {
+		match code {
+			1 => Disconnect::HostNotAllowedToConnect,
+			3 => Disconnect::KeyExchangeFailed,
+			4 => Disconnect::Reserved,
+			5 => Disconnect::MACError,
+			6 => Disconnect::CompressionError,
+			7 => Disconnect::ServiceNotAvailable,
+			8 => Disconnect::ProtocolVersionNotSupported,
+			9 => Disconnect::HostKeyNotVerifiable,
+			10 => Disconnect::ConnectionLost,
+			11 => Disconnect::ByApplication,
+			12 => Disconnect::TooManyConnections,
+			13 => Disconnect::AuthCancelledByUser,
+			14 => Disconnect::NoMoreAuthMethodsAvailable,
+			15 => Disconnect::IllegalUserName,
+			_ => Disconnect::ProtocolError,
+		}
+	}
+}
+
 /// The type of signals that can be sent to a remote process. If you
 /// plan to use custom signals, read [the
 /// RFC](https://tools.ietf.org/html/rfc4254#section-6.10) to
 /// understand the encoding.
 #[allow(missing_docs)]
 // This should be relatively self-explanatory.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Sig {
 	ABRT,
 	ALRM,
@@ -380,6 +546,7 @@ pub enum Sig {
 	SEGV,
 	TERM,
 	USR1,
+	USR2,
 	Custom(String),
 }
 
@@ -398,6 +565,7 @@ impl Sig {
 			Sig::SEGV => "SEGV",
 			Sig::TERM => "TERM",
 			Sig::USR1 => "USR1",
+			Sig::USR2 => "USR2",
 			Sig::Custom(ref c) => c,
 		}
 	}
@@ -416,19 +584,24 @@ impl Sig {
 			b"SEGV" => Ok(Sig::SEGV),
 			b"TERM" => Ok(Sig::TERM),
 			b"USR1" => Ok(Sig::USR1),
+			b"USR2" => Ok(Sig::USR2),
 			x => Ok(Sig::Custom(std::str::from_utf8(x)?.to_string())),
 		}
 	}
 }
 
-/// Reason for not being able to open a channel.
+/// Reason for not being able to open a channel (RFC 4254, section 5.1).
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-#[allow(missing_docs)]
 pub enum ChannelOpenFailure {
+	/// `SSH_OPEN_ADMINISTRATIVELY_PROHIBITED`: refused by policy.
 	AdministrativelyProhibited = 1,
+	/// `SSH_OPEN_CONNECT_FAILED`: the destination could not be reached.
 	ConnectFailed = 2,
+	/// `SSH_OPEN_UNKNOWN_CHANNEL_TYPE`.
 	UnknownChannelType = 3,
+	/// `SSH_OPEN_RESOURCE_SHORTAGE`: too many channels, or out of memory.
 	ResourceShortage = 4,
+	/// A reason code not defined by the RFC, received from the peer.
 	Unknown = 0,
 }
 
@@ -444,6 +617,56 @@ impl ChannelOpenFailure {
 	}
 }
 
+/// The flow control parameters of a channel, announced to the peer when
+/// the channel is opened: the peer sends at most `window_size` bytes
+/// until the window is adjusted, in packets of at most
+/// `maximum_packet_size` bytes.
+///
+/// Large windows help bulk transfers over high-latency links, where the
+/// window bounds the data in flight, and small ones bound the memory used
+/// by each channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowParams {
+	/// The initial window size, restored by window adjustments.
+	pub window_size:u32,
+	/// The maximum size of the data of a packet.
+	pub maximum_packet_size:u32,
+}
+
+impl WindowParams {
+	/// The smallest maximum packet size accepted.
+	pub const MIN_PACKET_SIZE:u32 = 1024;
+
+	/// Check that the window is not empty, and that data packets are at
+	/// least [`Self::MIN_PACKET_SIZE`] bytes, and fit in transport packets
+	/// of `max_packet_len` bytes.
+	pub(crate) fn check(&self, max_packet_len:usize) -> Result<(), Error> {
+		// The channel headers, the padding and the MAC of data packets.
+		const OVERHEAD:usize = 256;
+
+		if self.window_size == 0
+			|| self.maximum_packet_size < Self::MIN_PACKET_SIZE
+			|| self.maximum_packet_size as usize + OVERHEAD > max_packet_len
+		{
+			return Err(Error::InvalidWindowParams(*self));
+		}
+
+		Ok(())
+	}
+}
+
+/// The reply to a global request this library does not handle, see
+/// [`client::Handler::global_request`] and
+/// [`server::Handler::global_request`]. It is only sent if the peer asked
+/// for one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlobalRequestResponse {
+	/// Accept the request, replying with this request-specific data.
+	Success(Vec<u8>),
+	/// Refuse the request.
+	Failure,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// The identifier of a channel.
 pub struct ChannelId(u32);
@@ -461,10 +684,27 @@ pub(crate) struct ChannelParams {
 	sender_window_size:u32,
 	recipient_maximum_packet_size:u32,
 	sender_maximum_packet_size:u32,
+	/// The window restored by window adjustments, if the channel was
+	/// opened with its own instead of that of the session.
+	target_window_size:Option<u32>,
 	/// Has the other side confirmed the channel?
 	pub confirmed:bool,
 	wants_reply:bool,
 	pending_data:std::collections::VecDeque<(CryptoVec, Option<u32>, usize)>,
+	/// The number of bytes ever queued by `data` and `extended_data`.
+	data_queued:u64,
+	/// The writers waiting for their data to be sent, with the value of
+	/// `data_queued` after their data, in order.
+	writers:std::collections::VecDeque<(u64, tokio::sync::oneshot::Sender<()>)>,
+	/// The flushes waiting for `pending_data` to be sent, and written to
+	/// the connection.
+	flushes:Vec<tokio::sync::oneshot::Sender<()>>,
+	/// The tasks waiting for the channel to close, told so by dropping
+	/// these with the channel.
+	closes:Vec<tokio::sync::oneshot::Sender<()>>,
+	/// The exit status to send, followed by EOF and close, once
+	/// `pending_data` is sent.
+	pending_exit:Option<u32>,
 }
 
 impl ChannelParams {
@@ -552,7 +792,8 @@ mod test_compress {
 				Arc::new(client_key),
 			)
 			.await
-			.unwrap();
+			.unwrap()
+			.success();
 
 		assert!(authenticated);
 
@@ -626,7 +867,7 @@ mod test_compress {
 		) -> Result<(Self, Session), Self::Error> {
 			debug!("server data = {:?}", std::str::from_utf8(data));
 
-			session.data(channel, CryptoVec::from_slice(data));
+			session.data(channel, CryptoVec::from_slice(data))?;
 
 			Ok((self, session))
 		}
@@ -658,6 +899,34 @@ async fn test_session<RC, RS, CH, SH, F1, F2, CERR, SERR>(
 	server_handler:SH,
 	run_client:RC,
 	run_server:RS,
+) where
+	RC: FnOnce(crate::client::Handle<CH>) -> F1 + Send + Sync + 'static,
+	RS: FnOnce(crate::server::Handle) -> F2 + Send + Sync + 'static,
+	F1: Future<Output = crate::client::Handle<CH>> + Send + Sync + 'static,
+	F2: Future<Output = crate::server::Handle> + Send + Sync + 'static,
+	CERR: std::fmt::Debug + Send,
+	SERR: std::fmt::Debug + Send,
+	CH: crate::client::Handler<Error = CERR> + Send + Sync + 'static,
+	SH: crate::server::Handler<Error = SERR> + Send + Sync + 'static, {
+	test_session_with_configs(
+		crate::client::Config::default(),
+		crate::server::Config::default(),
+		client_handler,
+		server_handler,
+		run_client,
+		run_server,
+	)
+	.await
+}
+
+#[cfg(test)]
+async fn test_session_with_configs<RC, RS, CH, SH, F1, F2, CERR, SERR>(
+	client_config:crate::client::Config,
+	mut config:crate::server::Config,
+	client_handler:CH,
+	server_handler:SH,
+	run_client:RC,
+	run_server:RS,
 ) where
 	RC: FnOnce(crate::client::Handle<CH>) -> F1 + Send + Sync + 'static,
 	RS: FnOnce(crate::server::Handle) -> F2 + Send + Sync + 'static,
@@ -686,8 +955,6 @@ async fn test_session<RC, RS, CH, SH, F1, F2, CERR, SERR>(
 
 	let client_key = generate_keypair();
 
-	let mut config = server::Config::default();
-
 	config.connection_timeout = None;
 
 	config.auth_rejection_time = std::time::Duration::from_secs(3);
@@ -713,7 +980,7 @@ async fn test_session<RC, RS, CH, SH, F1, F2, CERR, SERR>(
 	});
 
 	let client_join = tokio::spawn(async move {
-		let config = Arc::new(client::Config::default());
+		let config = Arc::new(client_config);
 
 		let mut session = client::connect(config, addr, client_handler).await.unwrap();
 
@@ -723,7 +990,8 @@ async fn test_session<RC, RS, CH, SH, F1, F2, CERR, SERR>(
 				Arc::new(client_key),
 			)
 			.await
-			.unwrap();
+			.unwrap()
+			.success();
 
 		assert!(authenticated);
 
@@ -775,7 +1043,7 @@ mod test_channels {
 			) -> Result<(Self, client::Session), Self::Error> {
 				assert_eq!(data, &b"hello world!"[..]);
 
-				session.data(channel, CryptoVec::from_slice(&b"hey there!"[..]));
+				session.data(channel, CryptoVec::from_slice(&b"hey there!"[..]))?;
 
 				Ok((self, session))
 			}
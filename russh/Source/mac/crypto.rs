@@ -4,6 +4,7 @@ use byteorder::{BigEndian, ByteOrder};
 use digest::{KeyInit, typenum::Unsigned};
 use generic_array::{ArrayLength, GenericArray};
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 use super::{Mac, MacAlgorithm};
 
@@ -17,6 +18,12 @@ pub struct CryptoMac<M:digest::Mac + KeyInit + Send + 'static, KL:ArrayLength<u8
 	pub(crate) p:PhantomData<M>,
 }
 
+impl<M:digest::Mac + KeyInit + Send + 'static, KL:ArrayLength<u8> + 'static> Drop
+	for CryptoMac<M, KL>
+{
+	fn drop(&mut self) { self.key.as_mut_slice().zeroize(); }
+}
+
 impl<M:digest::Mac + KeyInit + Send + 'static, KL:ArrayLength<u8> + 'static> MacAlgorithm
 	for CryptoMacAlgorithm<M, KL>
 {
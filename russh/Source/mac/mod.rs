@@ -12,38 +12,61 @@
 //
 
 //! This module exports cipher names for use with [Preferred].
-use std::{collections::HashMap, marker::PhantomData};
+use std::{collections::HashMap, fmt::Debug, marker::PhantomData, sync::Arc};
 
-use digest::typenum::{U20, U32, U64};
+use digest::typenum::{U16, U20, U32, U64};
 use hmac::Hmac;
+use md5::Md5;
 use once_cell::sync::Lazy;
 use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 
-use self::{crypto::CryptoMacAlgorithm, crypto_etm::CryptoEtmMacAlgorithm, none::NoMacAlgorithm};
+use self::{
+	crypto::CryptoMacAlgorithm,
+	crypto_etm::CryptoEtmMacAlgorithm,
+	none::NoMacAlgorithm,
+	truncated::TruncatedMacAlgorithm,
+};
 
 mod crypto;
 mod crypto_etm;
 mod none;
+mod truncated;
+#[cfg(feature = "rs-crypto")]
+mod umac;
 
-pub(crate) trait MacAlgorithm {
+/// A MAC algorithm, used to derive [`Mac`] instances from key material.
+///
+/// Custom implementations can be made available to a session with
+/// [`MacRegistry::register`].
+pub trait MacAlgorithm {
+	/// Length of the key derived for this MAC during key exchange. An
+	/// unkeyed MAC returns 0, and receives an empty key.
 	fn key_len(&self) -> usize;
 
 	fn make_mac(&self, key:&[u8]) -> Box<dyn Mac + Send>;
 }
 
-pub(crate) trait Mac {
+/// A keyed MAC instance for one direction of the connection.
+pub trait Mac {
+	/// Length of the tag appended to each packet. It must not change
+	/// during the lifetime of the instance.
 	fn mac_len(&self) -> usize;
 
+	/// Whether the tag is computed over the encrypted packet
+	/// (encrypt-then-MAC) rather than the plaintext.
 	fn is_etm(&self) -> bool { false }
 
+	/// Writes the tag of `payload` to `output`, which is exactly
+	/// [`mac_len`](Mac::mac_len) bytes long.
 	fn compute(&self, sequence_number:u32, payload:&[u8], output:&mut [u8]);
 
+	/// Checks `mac` against the tag of `payload`, in constant time.
 	fn verify(&self, sequence_number:u32, payload:&[u8], mac:&[u8]) -> bool;
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
-pub struct Name(&'static str);
+pub struct Name(pub &'static str);
 impl AsRef<str> for Name {
 	fn as_ref(&self) -> &str { self.0 }
 }
@@ -52,6 +75,10 @@ impl AsRef<str> for Name {
 pub const NONE:Name = Name("none");
 /// `hmac-sha1`
 pub const HMAC_SHA1:Name = Name("hmac-sha1");
+/// `hmac-sha1-96`, for legacy servers only.
+pub const HMAC_SHA1_96:Name = Name("hmac-sha1-96");
+/// `hmac-md5`, for legacy servers only.
+pub const HMAC_MD5:Name = Name("hmac-md5");
 /// `hmac-sha2-256`
 pub const HMAC_SHA256:Name = Name("hmac-sha2-256");
 /// `hmac-sha2-512`
@@ -62,10 +89,20 @@ pub const HMAC_SHA1_ETM:Name = Name("hmac-sha1-etm@openssh.com");
 pub const HMAC_SHA256_ETM:Name = Name("hmac-sha2-256-etm@openssh.com");
 /// `hmac-sha2-512-etm@openssh.com`
 pub const HMAC_SHA512_ETM:Name = Name("hmac-sha2-512-etm@openssh.com");
+/// `umac-64@openssh.com`
+pub const UMAC_64:Name = Name("umac-64@openssh.com");
+/// `umac-128@openssh.com`
+pub const UMAC_128:Name = Name("umac-128@openssh.com");
+/// `umac-64-etm@openssh.com`
+pub const UMAC_64_ETM:Name = Name("umac-64-etm@openssh.com");
+/// `umac-128-etm@openssh.com`
+pub const UMAC_128_ETM:Name = Name("umac-128-etm@openssh.com");
 
 static _NONE:NoMacAlgorithm = NoMacAlgorithm {};
 static _HMAC_SHA1:CryptoMacAlgorithm<Hmac<Sha1>, U20> =
 	CryptoMacAlgorithm(PhantomData, PhantomData);
+static _HMAC_SHA1_96:TruncatedMacAlgorithm = TruncatedMacAlgorithm(&_HMAC_SHA1, 12);
+static _HMAC_MD5:CryptoMacAlgorithm<Hmac<Md5>, U16> = CryptoMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA256:CryptoMacAlgorithm<Hmac<Sha256>, U32> =
 	CryptoMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA512:CryptoMacAlgorithm<Hmac<Sha512>, U64> =
@@ -76,6 +113,14 @@ static _HMAC_SHA256_ETM:CryptoEtmMacAlgorithm<Hmac<Sha256>, U64> =
 	CryptoEtmMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA512_ETM:CryptoEtmMacAlgorithm<Hmac<Sha512>, U64> =
 	CryptoEtmMacAlgorithm(PhantomData, PhantomData);
+#[cfg(feature = "rs-crypto")]
+static _UMAC_64:umac::UmacAlgorithm = umac::UmacAlgorithm(8, false);
+#[cfg(feature = "rs-crypto")]
+static _UMAC_128:umac::UmacAlgorithm = umac::UmacAlgorithm(16, false);
+#[cfg(feature = "rs-crypto")]
+static _UMAC_64_ETM:umac::UmacAlgorithm = umac::UmacAlgorithm(8, true);
+#[cfg(feature = "rs-crypto")]
+static _UMAC_128_ETM:umac::UmacAlgorithm = umac::UmacAlgorithm(16, true);
 
 pub(crate) static MACS:Lazy<HashMap<&'static Name, &(dyn MacAlgorithm + Send + Sync)>> =
 	Lazy::new(|| {
@@ -85,6 +130,10 @@ pub(crate) static MACS:Lazy<HashMap<&'static Name, &(dyn MacAlgorithm + Send + S
 
 		h.insert(&HMAC_SHA1, &_HMAC_SHA1);
 
+		h.insert(&HMAC_SHA1_96, &_HMAC_SHA1_96);
+
+		h.insert(&HMAC_MD5, &_HMAC_MD5);
+
 		h.insert(&HMAC_SHA256, &_HMAC_SHA256);
 
 		h.insert(&HMAC_SHA512, &_HMAC_SHA512);
@@ -95,5 +144,282 @@ pub(crate) static MACS:Lazy<HashMap<&'static Name, &(dyn MacAlgorithm + Send + S
 
 		h.insert(&HMAC_SHA512_ETM, &_HMAC_SHA512_ETM);
 
+		#[cfg(feature = "rs-crypto")]
+		{
+			h.insert(&UMAC_64, &_UMAC_64);
+
+			h.insert(&UMAC_128, &_UMAC_128);
+
+			h.insert(&UMAC_64_ETM, &_UMAC_64_ETM);
+
+			h.insert(&UMAC_128_ETM, &_UMAC_128_ETM);
+		}
+
 		h
 	});
+
+/// The MACs available to a session. Defaults to the built-in MACs, and
+/// can be extended with custom implementations, which take precedence
+/// over built-in ones of the same name.
+///
+/// Registered MACs still need to be listed in
+/// [`Preferred::mac`](crate::Preferred::mac) to be negotiated.
+#[derive(Clone, Default)]
+pub struct MacRegistry {
+	custom:HashMap<Name, Arc<dyn MacAlgorithm + Send + Sync>>,
+}
+
+impl MacRegistry {
+	/// Register a MAC implementation under `name`.
+	pub fn register(&mut self, name:Name, mac:Arc<dyn MacAlgorithm + Send + Sync>) {
+		self.custom.insert(name, mac);
+	}
+
+	/// The MAC registered under `name`, or else the built-in one.
+	pub fn get(&self, name:&Name) -> Option<&(dyn MacAlgorithm + Send + Sync)> {
+		if let Some(mac) = self.custom.get(name) {
+			return Some(mac.as_ref());
+		}
+
+		MACS.get(name).copied()
+	}
+}
+
+impl Debug for MacRegistry {
+	fn fmt(&self, f:&mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		f.debug_set().entries(self.custom.keys()).finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+	fn test_hmac_sha1_96_is_truncated_hmac_sha1() {
+		let key = [0x0b; 20];
+
+		let full = MACS.get(&HMAC_SHA1).unwrap().make_mac(&key);
+
+		let truncated = MACS.get(&HMAC_SHA1_96).unwrap().make_mac(&key);
+
+		assert_eq!(truncated.mac_len(), 12);
+
+		let mut full_tag = [0; 20];
+
+		full.compute(7, b"payload", &mut full_tag);
+
+		let mut tag = [0; 12];
+
+		truncated.compute(7, b"payload", &mut tag);
+
+		assert_eq!(tag, full_tag[..12]);
+
+		assert!(truncated.verify(7, b"payload", &tag));
+
+		assert!(!truncated.verify(8, b"payload", &tag));
+
+		assert!(!truncated.verify(7, b"payload", &full_tag));
+
+		tag[11] ^= 1;
+
+		assert!(!truncated.verify(7, b"payload", &tag));
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_hmac_md5() {
+		// RFC 2104 test vector, with the 4-byte sequence number as the
+		// start of the data.
+		let mac = MACS.get(&HMAC_MD5).unwrap().make_mac(b"Jefe\0\0\0\0\0\0\0\0\0\0\0\0");
+
+		let mut tag = [0; 16];
+
+		mac.compute(u32::from_be_bytes(*b"what"), b" do ya want for nothing?", &mut tag);
+
+		assert_eq!(tag, hex_literal::hex!("750c783e6ab0b503eaa86e310a5db738"));
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_custom_mac_registry() {
+		use std::sync::Arc;
+
+		use sha2::{Digest, Sha256};
+		use subtle::ConstantTimeEq;
+
+		const KEYED_SHA256:mac::Name = mac::Name("keyed-sha256@example.com");
+
+		/// SHA-256 of the key, sequence number and payload, truncated to
+		/// 16 bytes.
+		struct KeyedSha256;
+
+		struct KeyedSha256Mac(Vec<u8>);
+
+		impl mac::MacAlgorithm for KeyedSha256 {
+			fn key_len(&self) -> usize { 32 }
+
+			fn make_mac(&self, key:&[u8]) -> Box<dyn mac::Mac + Send> {
+				Box::new(KeyedSha256Mac(key.to_vec()))
+			}
+		}
+
+		impl mac::Mac for KeyedSha256Mac {
+			fn mac_len(&self) -> usize { 16 }
+
+			#[allow(clippy::indexing_slicing)] // length checked
+			fn compute(&self, sequence_number:u32, payload:&[u8], output:&mut [u8]) {
+				let hash = Sha256::new()
+					.chain_update(&self.0)
+					.chain_update(sequence_number.to_be_bytes())
+					.chain_update(payload)
+					.finalize();
+
+				output.copy_from_slice(&hash[..16]);
+			}
+
+			fn verify(&self, sequence_number:u32, payload:&[u8], mac:&[u8]) -> bool {
+				let mut expected = [0; 16];
+
+				self.compute(sequence_number, payload, &mut expected);
+
+				expected.ct_eq(mac).into()
+			}
+		}
+
+		const PREFERRED:crate::Preferred = crate::Preferred {
+			cipher:&[cipher::AES_256_CTR],
+			mac:&[KEYED_SHA256],
+			..crate::Preferred::DEFAULT
+		};
+
+		let mut client_config = client::Config { preferred:PREFERRED, ..Default::default() };
+
+		client_config.macs.register(KEYED_SHA256, Arc::new(KeyedSha256));
+
+		let mut server_config = server::Config { preferred:PREFERRED, ..Default::default() };
+
+		server_config.macs.register(KEYED_SHA256, Arc::new(KeyedSha256));
+
+		test_session_with_configs(
+			client_config,
+			server_config,
+			Client {},
+			Server {},
+			|c| {
+				async move {
+					let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+					assert_eq!(negotiated.client_mac, KEYED_SHA256);
+
+					assert_eq!(negotiated.server_mac, KEYED_SHA256);
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+
+	/// `ssh` connecting to a russh server which only accepts the UMAC
+	/// algorithms, one at a time, checking the tags against the OpenSSH
+	/// `umac.c`. Run with `cargo test -- --ignored`, with `ssh` in the
+	/// `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_umac_openssh_client() {
+		use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+		use tokio::process::Command;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.data(channel, CryptoVec::from_slice(b"ok\n"))?;
+
+				session.send_exit_and_close(channel, 0);
+
+				Ok((self, session))
+			}
+		}
+
+		for (name, mac) in [
+			("umac-128-etm@openssh.com", &[mac::UMAC_128_ETM]),
+			("umac-128@openssh.com", &[mac::UMAC_128]),
+			("umac-64-etm@openssh.com", &[mac::UMAC_64_ETM]),
+			("umac-64@openssh.com", &[mac::UMAC_64]),
+		] {
+			let mut config = server::Config {
+				preferred:crate::Preferred {
+					cipher:&[cipher::AES_128_CTR],
+					mac,
+					..crate::Preferred::DEFAULT
+				},
+				..Default::default()
+			};
+
+			config.keys.push(generate_keypair());
+
+			let addr:SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+			let server = server::start(Arc::new(config), addr, |_:SocketAddr| ServerHandle {})
+				.await
+				.unwrap();
+
+			let addr = *server.local_addrs().first().unwrap();
+
+			let ssh = Command::new("ssh")
+				.args(["-p", &addr.port().to_string()])
+				.args(["-c", "aes128-ctr"])
+				.args(["-m", name])
+				.args(["-o", "StrictHostKeyChecking=no"])
+				.args(["-o", "UserKnownHostsFile=/dev/null"])
+				.args(["-o", "BatchMode=yes"])
+				.arg(format!("user@{}", addr.ip()))
+				.arg("true")
+				.stdin(std::process::Stdio::null())
+				.output();
+
+			let output = tokio::time::timeout(Duration::from_secs(30), ssh).await.unwrap().unwrap();
+
+			let stderr = String::from_utf8_lossy(&output.stderr);
+
+			assert!(output.status.success(), "{}: {}", name, stderr);
+
+			assert_eq!(output.stdout, b"ok\n", "{}", name);
+
+			server.shutdown(Duration::from_secs(1)).await;
+		}
+	}
+}
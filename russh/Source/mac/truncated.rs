@@ -0,0 +1,57 @@
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+use super::{Mac, MacAlgorithm};
+
+/// A MAC whose tag is the first `self.1` bytes of the tag of another MAC,
+/// as in `hmac-sha1-96`.
+pub struct TruncatedMacAlgorithm(pub &'static (dyn MacAlgorithm + Send + Sync), pub usize);
+
+pub struct TruncatedMac {
+	inner:Box<dyn Mac + Send>,
+	len:usize,
+}
+
+impl MacAlgorithm for TruncatedMacAlgorithm {
+	fn key_len(&self) -> usize { self.0.key_len() }
+
+	fn make_mac(&self, key:&[u8]) -> Box<dyn Mac + Send> {
+		Box::new(TruncatedMac { inner:self.0.make_mac(key), len:self.1 })
+	}
+}
+
+impl TruncatedMac {
+	fn compute_full(&self, sequence_number:u32, payload:&[u8]) -> Vec<u8> {
+		let mut full = vec![0; self.inner.mac_len()];
+
+		self.inner.compute(sequence_number, payload, &mut full);
+
+		full
+	}
+}
+
+impl Mac for TruncatedMac {
+	fn mac_len(&self) -> usize { self.len }
+
+	fn is_etm(&self) -> bool { self.inner.is_etm() }
+
+	fn compute(&self, sequence_number:u32, payload:&[u8], output:&mut [u8]) {
+		let mut full = self.compute_full(sequence_number, payload);
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		output.clone_from_slice(&full[..self.len]);
+
+		full.zeroize();
+	}
+
+	fn verify(&self, sequence_number:u32, payload:&[u8], mac:&[u8]) -> bool {
+		let mut full = self.compute_full(sequence_number, payload);
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		let ok = mac.len() == self.len && bool::from(full[..self.len].ct_eq(mac));
+
+		full.zeroize();
+
+		ok
+	}
+}
@@ -0,0 +1,483 @@
+// UMAC, as specified in https://tools.ietf.org/html/rfc4418, and used by
+// OpenSSH as `umac-64@openssh.com` and `umac-128@openssh.com` with the
+// sequence number as an 8-byte big-endian nonce.
+
+use std::convert::TryInto;
+
+use aes::{
+	cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+	Aes128,
+};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+use super::{Mac, MacAlgorithm};
+
+const KEY_LEN:usize = 16;
+
+/// Length of the NH key, and of the chunks hashed by L1-HASH.
+const L1_CHUNK_LEN:usize = 1024;
+
+/// L1-HASH output past this many bytes is hashed by POLY128 in L2-HASH.
+const L2_POLY64_LEN:usize = 1 << 17;
+
+const P36:u64 = (1 << 36) - 5;
+
+const P64:u64 = u64::MAX - 58;
+
+const P128:u128 = u128::MAX - 158;
+
+/// UMAC with a tag of `self.0` bytes (8 or 16), and `self.1` whether it
+/// is the encrypt-then-MAC variant.
+pub struct UmacAlgorithm(pub usize, pub bool);
+
+impl MacAlgorithm for UmacAlgorithm {
+	fn key_len(&self) -> usize { KEY_LEN }
+
+	fn make_mac(&self, key:&[u8]) -> Box<dyn Mac + Send> {
+		Box::new(Umac { key:UmacKey::new(key, self.0), etm:self.1 })
+	}
+}
+
+pub struct Umac {
+	key:UmacKey,
+	etm:bool,
+}
+
+impl Mac for Umac {
+	fn mac_len(&self) -> usize { self.key.tag_len }
+
+	fn is_etm(&self) -> bool { self.etm }
+
+	fn compute(&self, sequence_number:u32, payload:&[u8], output:&mut [u8]) {
+		let nonce = (sequence_number as u64).to_be_bytes();
+
+		self.key.umac(&nonce, payload, output)
+	}
+
+	fn verify(&self, sequence_number:u32, payload:&[u8], mac:&[u8]) -> bool {
+		let mut expected = [0; 16];
+
+		#[allow(clippy::indexing_slicing)] // tag_len <= 16
+		let expected = &mut expected[..self.key.tag_len];
+
+		self.compute(sequence_number, payload, expected);
+
+		let ok = expected.ct_eq(mac).into();
+
+		expected.zeroize();
+
+		ok
+	}
+}
+
+/// The subkeys of UMAC, derived once per key.
+pub(crate) struct UmacKey {
+	tag_len:usize,
+	/// NH key words, with a 16-byte shift between iterations.
+	l1:Vec<u32>,
+	/// POLY64 and POLY128 keys of each iteration.
+	l2:Vec<(u64, u128)>,
+	/// Inner-product key words (reduced mod p36), and final mask.
+	l3:Vec<([u64; 8], u32)>,
+	/// Cipher used to encrypt the nonce.
+	pdf:Aes128,
+}
+
+impl Drop for UmacKey {
+	fn drop(&mut self) {
+		self.l1.zeroize();
+
+		for (k64, k128) in self.l2.iter_mut() {
+			k64.zeroize();
+
+			k128.zeroize();
+		}
+
+		for (k1, k2) in self.l3.iter_mut() {
+			k1.zeroize();
+
+			k2.zeroize();
+		}
+	}
+}
+
+/// Fills `out` with `AES(key, index || 1) || AES(key, index || 2) || ...`.
+#[allow(clippy::indexing_slicing)] // lengths checked
+fn kdf(cipher:&Aes128, index:u64, out:&mut [u8]) {
+	for (i, chunk) in out.chunks_mut(16).enumerate() {
+		let mut block = GenericArray::default();
+
+		block[..8].copy_from_slice(&index.to_be_bytes());
+
+		block[8..].copy_from_slice(&(i as u64 + 1).to_be_bytes());
+
+		cipher.encrypt_block(&mut block);
+
+		chunk.copy_from_slice(&block[..chunk.len()]);
+
+		block.as_mut_slice().zeroize();
+	}
+}
+
+#[allow(clippy::indexing_slicing, clippy::unwrap_used)] // lengths checked
+impl UmacKey {
+	pub(crate) fn new(key:&[u8], tag_len:usize) -> Self {
+		let iters = tag_len / 4;
+
+		let cipher = Aes128::new(GenericArray::from_slice(key));
+
+		let mut l1 = vec![0; L1_CHUNK_LEN + (iters - 1) * 16];
+
+		kdf(&cipher, 1, &mut l1);
+
+		let mut l2 = vec![0; iters * 24];
+
+		kdf(&cipher, 2, &mut l2);
+
+		let mut l3_1 = vec![0; iters * 64];
+
+		kdf(&cipher, 3, &mut l3_1);
+
+		let mut l3_2 = vec![0; iters * 4];
+
+		kdf(&cipher, 4, &mut l3_2);
+
+		let mut pdf_key = [0; 16];
+
+		kdf(&cipher, 0, &mut pdf_key);
+
+		let key = UmacKey {
+			tag_len,
+			l1:l1.chunks_exact(4).map(|w| u32::from_be_bytes(w.try_into().unwrap())).collect(),
+			l2:l2
+				.chunks_exact(24)
+				.map(|k| {
+					(
+						u64::from_be_bytes(k[..8].try_into().unwrap()) & 0x01ffffff01ffffff,
+						u128::from_be_bytes(k[8..].try_into().unwrap())
+							& 0x01ffffff01ffffff01ffffff01ffffff,
+					)
+				})
+				.collect(),
+			l3:l3_1
+				.chunks_exact(64)
+				.zip(l3_2.chunks_exact(4))
+				.map(|(k1, k2)| {
+					let mut words = [0; 8];
+
+					for (w, k) in words.iter_mut().zip(k1.chunks_exact(8)) {
+						*w = u64::from_be_bytes(k.try_into().unwrap()) % P36;
+					}
+
+					(words, u32::from_be_bytes(k2.try_into().unwrap()))
+				})
+				.collect(),
+			pdf:Aes128::new(&GenericArray::from(pdf_key)),
+		};
+
+		l1.zeroize();
+
+		l2.zeroize();
+
+		l3_1.zeroize();
+
+		l3_2.zeroize();
+
+		pdf_key.zeroize();
+
+		key
+	}
+
+	/// Writes the `tag_len` bytes of the tag of `message` to `output`.
+	pub(crate) fn umac(&self, nonce:&[u8; 8], message:&[u8], output:&mut [u8]) {
+		// PDF: encrypt the nonce. Short tags use half of the block, selected
+		// by the last bit of the nonce.
+		let index = if self.tag_len == 8 { (nonce[7] & 1) as usize } else { 0 };
+
+		let mut block = GenericArray::default();
+
+		block[..8].copy_from_slice(nonce);
+
+		block[7] &= !1 | (self.tag_len != 8) as u8;
+
+		self.pdf.encrypt_block(&mut block);
+
+		output.copy_from_slice(&block[index * self.tag_len..(index + 1) * self.tag_len]);
+
+		block.as_mut_slice().zeroize();
+
+		// UHASH, one 4-byte word per iteration.
+		for (i, (out, ((k64, k128), (k1, k2)))) in
+			output.chunks_exact_mut(4).zip(self.l2.iter().zip(self.l3.iter())).enumerate()
+		{
+			let a = l1_hash(&self.l1[i * 4..i * 4 + L1_CHUNK_LEN / 4], message);
+
+			let b = if message.len() <= L1_CHUNK_LEN {
+				a.first().copied().unwrap_or(0) as u128
+			} else {
+				l2_hash(*k64, *k128, &a)
+			};
+
+			let c = l3_hash(k1, *k2, b);
+
+			for (o, c) in out.iter_mut().zip(c.to_be_bytes()) {
+				*o ^= c
+			}
+		}
+	}
+}
+
+/// NH over `message`, whose length is a multiple of 32 bytes. Message
+/// words are little-endian (the ENDIAN-SWAP of the RFC).
+#[allow(clippy::indexing_slicing)] // lengths checked
+fn nh(key:&[u32], message:&[u8]) -> u64 {
+	let mut y = 0u64;
+
+	for (k, m) in key.chunks_exact(8).zip(message.chunks_exact(32)) {
+		let m = |j:usize| u32::from_le_bytes([m[4 * j], m[4 * j + 1], m[4 * j + 2], m[4 * j + 3]]);
+
+		for j in 0..4 {
+			let a = m(j).wrapping_add(k[j]) as u64;
+
+			let b = m(j + 4).wrapping_add(k[j + 4]) as u64;
+
+			y = y.wrapping_add(a.wrapping_mul(b));
+		}
+	}
+
+	y
+}
+
+/// L1-HASH: NH of each 1024-byte chunk, plus the bit length of the chunk.
+#[allow(clippy::indexing_slicing)] // lengths checked
+fn l1_hash(key:&[u32], message:&[u8]) -> Vec<u64> {
+	let mut y = Vec::with_capacity(message.len() / L1_CHUNK_LEN + 1);
+
+	let mut chunks = message.chunks(L1_CHUNK_LEN).peekable();
+
+	while let Some(chunk) = chunks.next() {
+		if chunks.peek().is_some() {
+			y.push(nh(key, chunk).wrapping_add(8 * L1_CHUNK_LEN as u64));
+		} else {
+			y.push(nh_last(key, chunk));
+		}
+	}
+
+	if y.is_empty() {
+		y.push(nh_last(key, &[]));
+	}
+
+	y
+}
+
+/// NH of the last chunk, zero-padded to a positive multiple of 32 bytes.
+#[allow(clippy::indexing_slicing)] // lengths checked
+fn nh_last(key:&[u32], chunk:&[u8]) -> u64 {
+	let mut padded = [0; L1_CHUNK_LEN];
+
+	padded[..chunk.len()].copy_from_slice(chunk);
+
+	let padded_len = ((chunk.len() + 31) / 32).max(1) * 32;
+
+	nh(key, &padded[..padded_len]).wrapping_add(8 * chunk.len() as u64)
+}
+
+/// L2-HASH of the output of L1-HASH.
+#[allow(clippy::indexing_slicing)] // lengths checked
+fn l2_hash(k64:u64, k128:u128, words:&[u64]) -> u128 {
+	let n64 = L2_POLY64_LEN / 8;
+
+	if words.len() <= n64 {
+		return poly64(k64, words) as u128;
+	}
+
+	let y = poly64(k64, &words[..n64]);
+
+	// The rest is padded with 0x80 and zeros to 16-byte words.
+	let mut rest:Vec<u8> = words[n64..].iter().flat_map(|w| w.to_be_bytes()).collect();
+
+	rest.push(0x80);
+
+	rest.resize((rest.len() + 15) / 16 * 16, 0);
+
+	let rest = rest.chunks_exact(16).map(|w| {
+		let mut word = [0; 16];
+
+		word.copy_from_slice(w);
+
+		u128::from_be_bytes(word)
+	});
+
+	poly128(k128, std::iter::once(y as u128).chain(rest))
+}
+
+fn poly64(k:u64, words:&[u64]) -> u64 {
+	let mul_add = |y:u64, m:u64| ((k as u128 * y as u128 + m as u128) % P64 as u128) as u64;
+
+	let mut y = 1;
+
+	for &m in words {
+		if m >= u64::MAX - 0xffffffff {
+			y = mul_add(y, P64 - 1);
+
+			y = mul_add(y, m - (u64::MAX - P64 + 1));
+		} else {
+			y = mul_add(y, m);
+		}
+	}
+
+	y
+}
+
+fn poly128(k:u128, words:impl Iterator<Item = u128>) -> u128 {
+	let mul_add = |y:u128, m:u128| add_mod_p128(mul_mod_p128(k, y), m);
+
+	let mut y = 1;
+
+	for m in words {
+		if m >= u128::MAX - ((1 << 96) - 1) {
+			y = mul_add(y, P128 - 1);
+
+			y = mul_add(y, m - (u128::MAX - P128 + 1));
+		} else {
+			y = mul_add(y, m);
+		}
+	}
+
+	y
+}
+
+/// 128x128 -> 256 bits multiplication, as (high, low).
+fn mul_wide(a:u128, b:u128) -> (u128, u128) {
+	let (a1, a0) = (a >> 64, a & u64::MAX as u128);
+
+	let (b1, b0) = (b >> 64, b & u64::MAX as u128);
+
+	let (lo, hi) = (a0 * b0, a1 * b1);
+
+	let (mid, mid_carry) = (a0 * b1).overflowing_add(a1 * b0);
+
+	let (lo, lo_carry) = lo.overflowing_add(mid << 64);
+
+	let hi = hi + (mid >> 64) + ((mid_carry as u128) << 64) + lo_carry as u128;
+
+	(hi, lo)
+}
+
+/// Reduces `hi * 2^128 + lo` mod p128, using `2^128 = 159 mod p128`.
+fn reduce_p128(hi:u128, lo:u128) -> u128 {
+	let (hi, mut r) = {
+		let (h, l) = mul_wide(hi, u128::MAX - P128 + 1);
+
+		let (r, carry) = lo.overflowing_add(l);
+
+		(h + carry as u128, r)
+	};
+
+	let mut hi = hi;
+
+	while hi > 0 {
+		let (s, carry) = r.overflowing_add(hi * (u128::MAX - P128 + 1));
+
+		r = s;
+
+		hi = carry as u128;
+	}
+
+	if r >= P128 { r - P128 } else { r }
+}
+
+fn mul_mod_p128(a:u128, b:u128) -> u128 {
+	let (hi, lo) = mul_wide(a, b);
+
+	reduce_p128(hi, lo)
+}
+
+fn add_mod_p128(a:u128, b:u128) -> u128 {
+	let (s, carry) = a.overflowing_add(b);
+
+	reduce_p128(carry as u128, s)
+}
+
+/// L3-HASH, mapping the 16-byte output of L2-HASH to 4 bytes.
+#[allow(clippy::indexing_slicing)] // length checked
+fn l3_hash(k1:&[u64; 8], k2:u32, m:u128) -> u32 {
+	let m = m.to_be_bytes();
+
+	let y = k1
+		.iter()
+		.zip(m.chunks_exact(2))
+		.map(|(k, m)| k * u16::from_be_bytes([m[0], m[1]]) as u64)
+		.sum::<u64>()
+		% P36;
+
+	(y as u32) ^ k2
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Test vectors of RFC 4418, appendix, with the UMAC-128 tags of the
+	/// same inputs. RFC 4418 stops at UMAC-96, which is the first 12 bytes
+	/// of UMAC-128; the last 4 bytes are checked against OpenSSH by
+	/// `mac::test_sessions::test_umac_openssh_client`.
+	#[test]
+	fn test_rfc4418_vectors() {
+		let vectors:[(Vec<u8>, [&str; 3]); 6] = [
+			(
+				vec![],
+				["6E155FAD26900BE1", "32FEDB100C79AD58F07FF764", "32FEDB100C79AD58F07FF7643CC60465"],
+			),
+			(
+				vec![b'a'; 3],
+				["44B5CB542F220104", "185E4FE905CBA7BD85E4C2DC", "185E4FE905CBA7BD85E4C2DC3D117D8D"],
+			),
+			(
+				vec![b'a'; 1 << 10],
+				["26BF2F5D60118BD9", "7A54ABE04AF82D60FB298C3C", "7A54ABE04AF82D60FB298C3CBD195BCB"],
+			),
+			(
+				vec![b'a'; 1 << 15],
+				["27F8EF643B0D118D", "7B136BD911E4B734286EF2BE", "7B136BD911E4B734286EF2BE501F2C3C"],
+			),
+			(
+				b"abc".to_vec(),
+				["D4D7B9F6BD4FBFCF", "883C3D4B97A61976FFCF2323", "883C3D4B97A61976FFCF232308CBA5A5"],
+			),
+			(
+				b"abc".repeat(500),
+				["D4CF26DDEFD5C01A", "8824A260C53C66A36C9260A6", "8824A260C53C66A36C9260A62CB83AA1"],
+			),
+		];
+
+		for (message, tags) in vectors.iter() {
+			for (&tag_len, expected) in [8, 12, 16].iter().zip(tags.iter()) {
+				let key = UmacKey::new(b"abcdefghijklmnop", tag_len);
+
+				let mut tag = vec![0; tag_len];
+
+				key.umac(b"bcdefghi", message, &mut tag);
+
+				assert_eq!(
+					tag.iter().map(|b| format!("{:02X}", b)).collect::<String>(),
+					*expected,
+					"UMAC-{} of {} bytes",
+					tag_len * 8,
+					message.len()
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn test_poly128_reduction() {
+		// (p - 1)^2 = 1 mod p
+		assert_eq!(mul_mod_p128(P128 - 1, P128 - 1), 1);
+
+		assert_eq!(add_mod_p128(P128 - 1, 2), 1);
+
+		assert_eq!(mul_mod_p128(1 << 64, 1 << 64), 159);
+	}
+}
@@ -16,9 +16,7 @@
 pub const DISCONNECT:u8 = 1;
 #[allow(dead_code)]
 pub const IGNORE:u8 = 2;
-#[allow(dead_code)]
 pub const UNIMPLEMENTED:u8 = 3;
-#[allow(dead_code)]
 pub const DEBUG:u8 = 4;
 
 pub const SERVICE_REQUEST:u8 = 5;
@@ -31,6 +29,12 @@ pub const NEWKEYS:u8 = 21;
 pub const KEX_ECDH_INIT:u8 = 30;
 pub const KEX_ECDH_REPLY:u8 = 31;
 
+// https://tools.ietf.org/html/rfc4419#section-5
+pub const KEX_DH_GEX_GROUP:u8 = 31;
+pub const KEX_DH_GEX_INIT:u8 = 32;
+pub const KEX_DH_GEX_REPLY:u8 = 33;
+pub const KEX_DH_GEX_REQUEST:u8 = 34;
+
 // https://tools.ietf.org/html/rfc4250#section-4.1.2
 pub const USERAUTH_REQUEST:u8 = 50;
 pub const USERAUTH_FAILURE:u8 = 51;
@@ -38,6 +42,9 @@ pub const USERAUTH_SUCCESS:u8 = 52;
 pub const USERAUTH_BANNER:u8 = 53;
 pub const USERAUTH_PK_OK:u8 = 60;
 
+// https://tools.ietf.org/html/rfc4252#section-8
+pub const USERAUTH_PASSWD_CHANGEREQ:u8 = 60;
+
 // https://tools.ietf.org/html/rfc4256#section-5
 pub const USERAUTH_INFO_REQUEST:u8 = 60;
 pub const USERAUTH_INFO_RESPONSE:u8 = 61;
@@ -62,6 +69,23 @@ pub const CHANNEL_REQUEST:u8 = 98;
 pub const CHANNEL_SUCCESS:u8 = 99;
 pub const CHANNEL_FAILURE:u8 = 100;
 
+/// Whether message type `t` is one this implementation knows, including
+/// the ranges reserved for key exchange and authentication methods. The
+/// others are answered with [`UNIMPLEMENTED`], see
+/// [RFC4253](https://tools.ietf.org/html/rfc4253#section-11.4).
+pub fn is_known(t:u8) -> bool {
+	matches!(
+		t,
+		DISCONNECT..=EXT_INFO
+			| KEXINIT
+			| NEWKEYS
+			| 30..=49
+			| USERAUTH_REQUEST..=USERAUTH_BANNER
+			| 60..=79
+			| GLOBAL_REQUEST..=CHANNEL_FAILURE
+	)
+}
+
 pub const SSH_OPEN_ADMINISTRATIVELY_PROHIBITED:u8 = 1;
 #[allow(dead_code)]
 pub const SSH_OPEN_CONNECT_FAILED:u8 = 2;
@@ -18,12 +18,13 @@ use log::debug;
 use rand::RngCore;
 use russh_cryptovec::CryptoVec;
 use russh_keys::{
+	certificate::Certificate,
 	encoding::{Encoding, Reader},
 	key,
 	key::{KeyPair, PublicKey},
 };
 
-use crate::{Error, cipher, cipher::CIPHERS, compression::*, kex, mac, msg};
+use crate::{Error, cipher, compression::*, kex, mac, msg};
 
 #[derive(Debug)]
 pub struct Names {
@@ -34,7 +35,49 @@ pub struct Names {
 	pub server_mac:mac::Name,
 	pub server_compression:Compression,
 	pub client_compression:Compression,
+	pub server_compression_name:&'static str,
+	pub client_compression_name:&'static str,
 	pub ignore_guessed:bool,
+	/// Both sides prefer the same kex and host key algorithms, so a
+	/// guessed first key exchange packet is right (RFC 4253, section 7).
+	pub guessed_right:bool,
+	/// Both sides advertised strict key exchange (the OpenSSH
+	/// `kex-strict-*-v00@openssh.com` markers).
+	pub strict_kex:bool,
+}
+
+impl Names {
+	/// The algorithms of this key exchange, as reported to users.
+	pub fn negotiated(&self) -> Negotiated {
+		Negotiated {
+			kex:self.kex,
+			key:self.key,
+			cipher:self.cipher,
+			client_mac:self.client_mac,
+			server_mac:self.server_mac,
+			client_compression:self.client_compression_name,
+			server_compression:self.server_compression_name,
+		}
+	}
+}
+
+/// Algorithms negotiated by the last key exchange of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+	/// Key exchange algorithm.
+	pub kex:kex::Name,
+	/// Host key algorithm.
+	pub key:key::Name,
+	/// Cipher, used in both directions.
+	pub cipher:cipher::Name,
+	/// MAC used from the client to the server.
+	pub client_mac:mac::Name,
+	/// MAC used from the server to the client.
+	pub server_mac:mac::Name,
+	/// Compression used from the client to the server.
+	pub client_compression:&'static str,
+	/// Compression used from the server to the client.
+	pub server_compression:&'static str,
 }
 
 /// Lists of preferred algorithms. This is normally hard-coded into
@@ -54,20 +97,32 @@ pub struct Preferred {
 }
 
 const KEX_ORDER:&[kex::Name] = &[
+	#[cfg(feature = "rs-crypto")]
+	kex::SNTRUP761X25519_SHA512,
+	#[cfg(feature = "rs-crypto")]
+	kex::MLKEM768X25519_SHA256,
 	#[cfg(feature = "rs-crypto")]
 	kex::CURVE25519,
 	kex::DH_G14_SHA256,
+	kex::DH_GEX_SHA256,
+	kex::DH_G16_SHA512,
+	kex::DH_G18_SHA512,
+	kex::DH_G15_SHA512,
 	kex::DH_G14_SHA1,
 	kex::DH_G1_SHA1,
 	kex::EXTENSION_SUPPORT_AS_CLIENT,
 	kex::EXTENSION_SUPPORT_AS_SERVER,
+	kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+	kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
 ];
 
 const CIPHER_ORDER:&[cipher::Name] = &[
-	#[cfg(feature = "rs-crypto")]
+	#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
 	cipher::CHACHA20_POLY1305,
-	#[cfg(feature = "rs-crypto")]
+	#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
 	cipher::AES_256_GCM,
+	#[cfg(any(feature = "rs-crypto", feature = "openssl"))]
+	cipher::AES_128_GCM,
 	cipher::AES_256_CTR,
 	cipher::AES_192_CTR,
 	cipher::AES_128_CTR,
@@ -76,6 +131,10 @@ const CIPHER_ORDER:&[cipher::Name] = &[
 const HMAC_ORDER:&[mac::Name] = &[
 	mac::HMAC_SHA512_ETM,
 	mac::HMAC_SHA256_ETM,
+	#[cfg(feature = "rs-crypto")]
+	mac::UMAC_128_ETM,
+	#[cfg(feature = "rs-crypto")]
+	mac::UMAC_64_ETM,
 	mac::HMAC_SHA512,
 	mac::HMAC_SHA256,
 	mac::HMAC_SHA1_ETM,
@@ -83,7 +142,18 @@ const HMAC_ORDER:&[mac::Name] = &[
 	mac::NONE,
 ];
 
+/// Compression is only used if the client prefers it.
+const COMPRESSION_ORDER:&[&str] = &[
+	"none",
+	#[cfg(feature = "flate2")]
+	"zlib",
+	#[cfg(feature = "flate2")]
+	"zlib@openssh.com",
+];
+
 impl Preferred {
+	/// Prefers compression, including `zlib` which, unlike
+	/// `zlib@openssh.com`, compresses before authentication.
 	pub const COMPRESSED:Preferred = Preferred {
 		kex:KEX_ORDER,
 		key:&[
@@ -94,14 +164,31 @@ impl Preferred {
 		],
 		cipher:CIPHER_ORDER,
 		mac:HMAC_ORDER,
-		compression:&["zlib", "zlib@openssh.com", "none"],
+		compression:&[
+			#[cfg(feature = "flate2")]
+			"zlib",
+			#[cfg(feature = "flate2")]
+			"zlib@openssh.com",
+			"none",
+		],
 	};
 	#[cfg(feature = "openssl")]
 	pub const DEFAULT:Preferred = Preferred {
 		kex:&[
+			#[cfg(feature = "rs-crypto")]
+			kex::SNTRUP761X25519_SHA512,
+			#[cfg(feature = "rs-crypto")]
+			kex::MLKEM768X25519_SHA256,
 			#[cfg(feature = "rs-crypto")]
 			kex::CURVE25519,
 			kex::DH_G14_SHA256,
+			kex::DH_G16_SHA512,
+			kex::DH_G18_SHA512,
+			kex::DH_G15_SHA512,
+			kex::EXTENSION_SUPPORT_AS_CLIENT,
+			kex::EXTENSION_SUPPORT_AS_SERVER,
+			kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+			kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
 		],
 		key:&[
 			#[cfg(feature = "rs-crypto")]
@@ -111,7 +198,7 @@ impl Preferred {
 		],
 		cipher:CIPHER_ORDER,
 		mac:HMAC_ORDER,
-		compression:&["none", "zlib", "zlib@openssh.com"],
+		compression:COMPRESSION_ORDER,
 	};
 	#[cfg(not(feature = "openssl"))]
 	pub const DEFAULT:Preferred = Preferred {
@@ -119,7 +206,7 @@ impl Preferred {
 		key:&[key::ED25519],
 		cipher:CIPHER_ORDER,
 		mac:HMAC_ORDER,
-		compression:&["none", "zlib", "zlib@openssh.com"],
+		compression:COMPRESSION_ORDER,
 	};
 }
 
@@ -127,6 +214,28 @@ impl Default for Preferred {
 	fn default() -> Preferred { Preferred::DEFAULT }
 }
 
+/// Whether the `none` cipher is proposed in a key exchange, on top of
+/// the [`Preferred`] ciphers. This is only changed after authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoneCipher {
+	/// Propose the preferred ciphers.
+	Unchanged,
+	/// Propose `none` after the preferred ciphers.
+	Accept,
+	/// Propose `none` only.
+	Only,
+}
+
+impl NoneCipher {
+	pub(crate) fn ciphers(self, pref:&Preferred) -> Vec<cipher::Name> {
+		match self {
+			NoneCipher::Unchanged => pref.cipher.to_vec(),
+			NoneCipher::Accept => pref.cipher.iter().copied().chain(Some(cipher::NONE)).collect(),
+			NoneCipher::Only => vec![cipher::NONE],
+		}
+	}
+}
+
 /// Named algorithms.
 pub trait Named {
 	/// The name of this algorithm.
@@ -147,12 +256,18 @@ impl Named for PublicKey {
 		match self {
 			#[cfg(feature = "rs-crypto")]
 			PublicKey::Ed25519(_) => ED25519.0,
+			#[cfg(feature = "rs-crypto")]
+			PublicKey::SkEd25519 { .. } => key::SK_ED25519.0,
 			#[cfg(feature = "openssl")]
 			PublicKey::RSA { .. } => SSH_RSA.0,
 		}
 	}
 }
 
+impl Named for Certificate {
+	fn name(&self) -> &'static str { Certificate::name(self) }
+}
+
 impl Named for KeyPair {
 	fn name(&self) -> &'static str {
 		match self {
@@ -164,14 +279,51 @@ impl Named for KeyPair {
 	}
 }
 
+/// The host key algorithms `key` can sign with, starting with the name of
+/// its key type. RSA keys sign with any of the RSA signature hashes.
+pub(crate) fn host_key_algorithms(key:&KeyPair) -> &'static [key::Name] {
+	match key {
+		#[cfg(feature = "rs-crypto")]
+		KeyPair::Ed25519 { .. } => &[ED25519],
+		#[cfg(feature = "openssl")]
+		KeyPair::RSA { .. } => &[SSH_RSA, key::RSA_SHA2_256, key::RSA_SHA2_512],
+	}
+}
+
 pub trait Select {
+	/// The strict key exchange marker advertised by this side.
+	const STRICT_KEX:kex::Name;
+
+	/// The strict key exchange marker advertised by the other side.
+	const PEER_STRICT_KEX:kex::Name;
+
 	fn select<S:AsRef<str> + Copy>(a:&[S], b:&[u8]) -> Option<(bool, S)>;
 
-	fn read_kex(buffer:&[u8], pref:&Preferred) -> Result<Names, Error> {
+	fn read_kex(
+		buffer:&[u8],
+		pref:&Preferred,
+		none_cipher:NoneCipher,
+		ciphers:&cipher::CipherRegistry,
+	) -> Result<Names, Error> {
+		Self::read_kex_with_host_keys(buffer, pref, pref.key, none_cipher, ciphers)
+	}
+
+	/// [`Select::read_kex`], choosing the host key algorithm from
+	/// `host_keys` rather than `pref.key`.
+	fn read_kex_with_host_keys(
+		buffer:&[u8],
+		pref:&Preferred,
+		host_keys:&[key::Name],
+		none_cipher:NoneCipher,
+		ciphers:&cipher::CipherRegistry,
+	) -> Result<Names, Error> {
 		let mut r = buffer.reader(17);
 
 		let kex_string = r.read_string()?;
 
+		let strict_kex = pref.kex.contains(&Self::STRICT_KEX)
+			&& Self::select(&[Self::PEER_STRICT_KEX], kex_string).is_some();
+
 		let (kex_both_first, kex_algorithm) = if let Some(x) = Self::select(pref.kex, kex_string) {
 			x
 		} else {
@@ -187,14 +339,14 @@ pub trait Select {
 
 		let key_string = r.read_string()?;
 
-		let (key_both_first, key_algorithm) = if let Some(x) = Self::select(pref.key, key_string) {
+		let (key_both_first, key_algorithm) = if let Some(x) = Self::select(host_keys, key_string) {
 			x
 		} else {
 			debug!(
 				"Could not find common key algorithm, other side only supports {:?}, we only \
 				 support {:?}",
 				from_utf8(key_string),
-				pref.key
+				host_keys
 			);
 
 			return Err(Error::NoCommonKeyAlgo);
@@ -202,13 +354,15 @@ pub trait Select {
 
 		let cipher_string = r.read_string()?;
 
-		let cipher = Self::select(pref.cipher, cipher_string);
+		let pref_cipher = none_cipher.ciphers(pref);
+
+		let cipher = Self::select(&pref_cipher, cipher_string);
 
 		if cipher.is_none() {
 			debug!(
 				"Could not find common cipher, other side only supports {:?}, we only support {:?}",
 				from_utf8(cipher_string),
-				pref.cipher
+				pref_cipher
 			);
 
 			return Err(Error::NoCommonCipher);
@@ -218,7 +372,7 @@ pub trait Select {
 		debug!("kex {}", line!());
 
 		let need_mac =
-			cipher.and_then(|x| CIPHERS.get(&x.1)).map(|x| x.needs_mac()).unwrap_or(false);
+			cipher.and_then(|x| ciphers.get(&x.1)).map(|x| x.needs_mac()).unwrap_or(false);
 
 		let client_mac = if let Some((_, m)) = Self::select(pref.mac, r.read_string()?) {
 			m
@@ -238,18 +392,18 @@ pub trait Select {
 
 		debug!("kex {}", line!());
 		// client-to-server compression.
-		let client_compression =
+		let (client_compression, client_compression_name) =
 			if let Some((_, c)) = Self::select(pref.compression, r.read_string()?) {
-				Compression::from_string(c)
+				(Compression::from_string(c), c)
 			} else {
 				return Err(Error::NoCommonCompression);
 			};
 
 		debug!("kex {}", line!());
 		// server-to-client compression.
-		let server_compression =
+		let (server_compression, server_compression_name) =
 			if let Some((_, c)) = Self::select(pref.compression, r.read_string()?) {
-				Compression::from_string(c)
+				(Compression::from_string(c), c)
 			} else {
 				return Err(Error::NoCommonCompression);
 			};
@@ -261,6 +415,8 @@ pub trait Select {
 
 		let follows = r.read_byte()? != 0;
 
+		let guessed_right = kex_both_first && key_both_first;
+
 		match (cipher, follows) {
 			(Some((_, cipher)), fol) => {
 				Ok(Names {
@@ -271,9 +427,13 @@ pub trait Select {
 					server_mac,
 					client_compression,
 					server_compression,
+					client_compression_name,
+					server_compression_name,
 					// Ignore the next packet if (1) it follows and (2) it's not
 					// the correct guess.
-					ignore_guessed:fol && !(kex_both_first && key_both_first),
+					ignore_guessed:fol && !guessed_right,
+					guessed_right,
+					strict_kex,
 				})
 			},
 			_ => Err(Error::KexInit),
@@ -285,6 +445,10 @@ pub struct Server;
 pub struct Client;
 
 impl Select for Server {
+	const STRICT_KEX:kex::Name = kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER;
+
+	const PEER_STRICT_KEX:kex::Name = kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT;
+
 	fn select<S:AsRef<str> + Copy>(server_list:&[S], client_list:&[u8]) -> Option<(bool, S)> {
 		let mut both_first_choice = true;
 
@@ -303,6 +467,10 @@ impl Select for Server {
 }
 
 impl Select for Client {
+	const STRICT_KEX:kex::Name = kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT;
+
+	const PEER_STRICT_KEX:kex::Name = kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER;
+
 	fn select<S:AsRef<str> + Copy>(client_list:&[S], server_list:&[u8]) -> Option<(bool, S)> {
 		let mut both_first_choice = true;
 
@@ -320,7 +488,32 @@ impl Select for Client {
 	}
 }
 
-pub fn write_kex(prefs:&Preferred, buf:&mut CryptoVec, as_server:bool) -> Result<(), Error> {
+pub fn write_kex(
+	prefs:&Preferred,
+	none_cipher:NoneCipher,
+	buf:&mut CryptoVec,
+	as_server:bool,
+	first_kex_packet_follows:bool,
+) -> Result<(), Error> {
+	write_kex_with_host_keys(
+		prefs,
+		prefs.key,
+		none_cipher,
+		buf,
+		as_server,
+		first_kex_packet_follows,
+	)
+}
+
+/// [`write_kex`], advertising `host_keys` rather than `prefs.key`.
+pub fn write_kex_with_host_keys(
+	prefs:&Preferred,
+	host_keys:&[key::Name],
+	none_cipher:NoneCipher,
+	buf:&mut CryptoVec,
+	as_server:bool,
+	first_kex_packet_follows:bool,
+) -> Result<(), Error> {
 	// buf.clear();
 
 	buf.push(msg::KEXINIT);
@@ -330,18 +523,23 @@ pub fn write_kex(prefs:&Preferred, buf:&mut CryptoVec, as_server:bool) -> Result
 	rand::thread_rng().fill_bytes(&mut cookie);
 
 	buf.extend(&cookie); // cookie
-	buf.extend_list(prefs.kex.iter().filter(|k| {
-		**k != if as_server {
-			crate::kex::EXTENSION_SUPPORT_AS_CLIENT
-		} else {
-			crate::kex::EXTENSION_SUPPORT_AS_SERVER
-		}
-	})); // kex algo
+	let (peer_ext_info, peer_strict_kex) = if as_server {
+		(kex::EXTENSION_SUPPORT_AS_CLIENT, kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT)
+	} else {
+		(kex::EXTENSION_SUPPORT_AS_SERVER, kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER)
+	};
 
-	buf.extend_list(prefs.key.iter());
+	// Markers are side-specific, don't advertise the other side's.
+	buf.extend_list(
+		prefs.kex.iter().filter(|k| **k != peer_ext_info && **k != peer_strict_kex),
+	); // kex algo
 
-	buf.extend_list(prefs.cipher.iter()); // cipher client to server
-	buf.extend_list(prefs.cipher.iter()); // cipher server to client
+	buf.extend_list(host_keys.iter());
+
+	let ciphers = none_cipher.ciphers(prefs);
+
+	buf.extend_list(ciphers.iter()); // cipher client to server
+	buf.extend_list(ciphers.iter()); // cipher server to client
 
 	buf.extend_list(prefs.mac.iter()); // mac client to server
 	buf.extend_list(prefs.mac.iter()); // mac server to client
@@ -351,7 +549,174 @@ pub fn write_kex(prefs:&Preferred, buf:&mut CryptoVec, as_server:bool) -> Result
 	buf.write_empty_list(); // languages client to server
 	buf.write_empty_list(); // languagesserver to client
 
-	buf.push(0); // doesn't follow
+	buf.push(u8::from(first_kex_packet_follows));
 	buf.extend(&[0, 0, 0, 0]); // reserved
 	Ok(())
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[cfg(feature = "rs-crypto")]
+	#[allow(clippy::unwrap_used)]
+	fn test_negotiated_algorithms() {
+		let client = Preferred {
+			kex:&[kex::CURVE25519],
+			key:&[key::ED25519],
+			cipher:&[cipher::CHACHA20_POLY1305, cipher::CLEAR],
+			mac:&[mac::NONE],
+			compression:&["none"],
+		};
+
+		let server = Preferred {
+			kex:&[kex::DH_G14_SHA256, kex::CURVE25519],
+			key:&[key::ED25519],
+			cipher:&[cipher::CLEAR, cipher::CHACHA20_POLY1305],
+			mac:&[mac::HMAC_SHA256, mac::NONE],
+			compression:&["zlib", "none"],
+		};
+
+		let mut kexinit = CryptoVec::new();
+
+		write_kex(&server, NoneCipher::Unchanged, &mut kexinit, true, false).unwrap();
+
+		let names = Client::read_kex(
+			&kexinit,
+			&client,
+			NoneCipher::Unchanged,
+			&cipher::CipherRegistry::default(),
+		)
+		.unwrap();
+
+		assert_eq!(
+			names.negotiated(),
+			Negotiated {
+				kex:kex::CURVE25519,
+				key:key::ED25519,
+				cipher:cipher::CHACHA20_POLY1305,
+				client_mac:mac::NONE,
+				server_mac:mac::NONE,
+				client_compression:"none",
+				server_compression:"none",
+			}
+		);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_none_cipher_requires_server_consent() {
+		let pref = Preferred::DEFAULT;
+
+		let ciphers = cipher::CipherRegistry::default();
+
+		let mut client_kexinit = CryptoVec::new();
+
+		write_kex(&pref, NoneCipher::Only, &mut client_kexinit, false, false).unwrap();
+
+		// Before authentication, the server doesn't propose `none`.
+		assert!(matches!(
+			Server::read_kex(&client_kexinit, &pref, NoneCipher::Unchanged, &ciphers),
+			Err(Error::NoCommonCipher)
+		));
+
+		let names = Server::read_kex(&client_kexinit, &pref, NoneCipher::Accept, &ciphers).unwrap();
+
+		assert_eq!(names.cipher, cipher::NONE);
+
+		// A client that didn't ask for `none` keeps its preferred cipher.
+		let mut client_kexinit = CryptoVec::new();
+
+		write_kex(&pref, NoneCipher::Unchanged, &mut client_kexinit, false, false).unwrap();
+
+		let names = Server::read_kex(&client_kexinit, &pref, NoneCipher::Accept, &ciphers).unwrap();
+
+		assert_eq!(Some(&names.cipher), pref.cipher.first());
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_strict_kex_negotiation() {
+		let pref = Preferred::DEFAULT;
+
+		let ciphers = cipher::CipherRegistry::default();
+
+		let mut client_kexinit = CryptoVec::new();
+
+		write_kex(&pref, NoneCipher::Unchanged, &mut client_kexinit, false, false).unwrap();
+
+		let mut server_kexinit = CryptoVec::new();
+
+		write_kex(&pref, NoneCipher::Unchanged, &mut server_kexinit, true, false).unwrap();
+
+		let names =
+			Server::read_kex(&client_kexinit, &pref, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(names.strict_kex);
+
+		let names =
+			Client::read_kex(&server_kexinit, &pref, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(names.strict_kex);
+
+		// A side's own marker doesn't count as the peer's.
+		let names =
+			Server::read_kex(&server_kexinit, &pref, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(!names.strict_kex);
+
+		// Nor does the peer's marker if we don't advertise ours.
+		let legacy = Preferred { kex:&[kex::DH_G14_SHA256], ..Preferred::DEFAULT };
+
+		let names =
+			Server::read_kex(&client_kexinit, &legacy, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(!names.strict_kex);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_kex_guess() {
+		let client =
+			Preferred { kex:&[kex::DH_G14_SHA256, kex::DH_G16_SHA512], ..Preferred::DEFAULT };
+
+		let ciphers = cipher::CipherRegistry::default();
+
+		let mut client_kexinit = CryptoVec::new();
+
+		write_kex(&client, NoneCipher::Unchanged, &mut client_kexinit, false, true).unwrap();
+
+		// Same first choice: the guessed packet is used.
+		let names =
+			Server::read_kex(&client_kexinit, &client, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(names.guessed_right);
+
+		assert!(!names.ignore_guessed);
+
+		// Different first choice: the guessed packet is ignored, even though
+		// the client's first choice is negotiated.
+		let server =
+			Preferred { kex:&[kex::DH_G16_SHA512, kex::DH_G14_SHA256], ..Preferred::DEFAULT };
+
+		let names =
+			Server::read_kex(&client_kexinit, &server, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert_eq!(names.kex, kex::DH_G14_SHA256);
+
+		assert!(!names.guessed_right);
+
+		assert!(names.ignore_guessed);
+
+		// Nothing to ignore if no packet follows.
+		let mut client_kexinit = CryptoVec::new();
+
+		write_kex(&client, NoneCipher::Unchanged, &mut client_kexinit, false, false).unwrap();
+
+		let names =
+			Server::read_kex(&client_kexinit, &server, NoneCipher::Unchanged, &ciphers).unwrap();
+
+		assert!(!names.ignore_guessed);
+	}
+}
@@ -36,6 +36,24 @@ impl OpenChannelMessage {
 			},
 			b"direct-tcpip" => ChannelType::DirectTcpip(TcpChannelInfo::new(r)?),
 			b"forwarded-tcpip" => ChannelType::ForwardedTcpIp(TcpChannelInfo::new(r)?),
+			b"direct-streamlocal@openssh.com" => {
+				let socket_path = StreamLocalChannelInfo::new(r)?;
+
+				// Reserved, the originator address and port in OpenSSH.
+				r.read_string().map_err(crate::Error::from)?;
+
+				r.read_u32().map_err(crate::Error::from)?;
+
+				ChannelType::DirectStreamLocal(socket_path)
+			},
+			b"forwarded-streamlocal@openssh.com" => {
+				let socket_path = StreamLocalChannelInfo::new(r)?;
+
+				// Reserved.
+				r.read_string().map_err(crate::Error::from)?;
+
+				ChannelType::ForwardedStreamLocal(socket_path)
+			},
 			b"auth-agent@openssh.com" => ChannelType::AgentForward,
 			t => ChannelType::Unknown { typ:t.to_vec() },
 		};
@@ -69,6 +87,17 @@ impl OpenChannelMessage {
 
 	/// Pushes a failure message to the vec.
 	pub fn fail(&self, buffer:&mut CryptoVec, reason:u8, message:&[u8]) {
+		self.fail_with_language(buffer, reason, message, b"en")
+	}
+
+	/// Pushes a failure message in `language` to the vec.
+	pub fn fail_with_language(
+		&self,
+		buffer:&mut CryptoVec,
+		reason:u8,
+		message:&[u8],
+		language:&[u8],
+	) {
 		push_packet!(buffer, {
 			buffer.push(msg::CHANNEL_OPEN_FAILURE);
 
@@ -78,7 +107,7 @@ impl OpenChannelMessage {
 
 			buffer.extend_ssh_string(message);
 
-			buffer.extend_ssh_string(b"en");
+			buffer.extend_ssh_string(language);
 		});
 	}
 
@@ -94,6 +123,8 @@ pub enum ChannelType {
 	X11 { originator_address:String, originator_port:u32 },
 	DirectTcpip(TcpChannelInfo),
 	ForwardedTcpIp(TcpChannelInfo),
+	DirectStreamLocal(StreamLocalChannelInfo),
+	ForwardedStreamLocal(StreamLocalChannelInfo),
 	AgentForward,
 	Unknown { typ:Vec<u8> },
 }
@@ -124,6 +155,21 @@ impl TcpChannelInfo {
 	}
 }
 
+#[derive(Debug)]
+pub struct StreamLocalChannelInfo {
+	pub socket_path:String,
+}
+
+impl StreamLocalChannelInfo {
+	fn new(r:&mut Position) -> Result<Self, crate::Error> {
+		let socket_path = std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
+			.map_err(crate::Error::from)?
+			.to_owned();
+
+		Ok(Self { socket_path })
+	}
+}
+
 #[derive(Debug)]
 pub(crate) struct ChannelOpenConfirmation {
 	pub recipient_channel:u32,
@@ -1,3 +1,5 @@
+use russh_keys::encoding::Reader;
+
 #[allow(non_camel_case_types, missing_docs)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// Standard pseudo-terminal codes.
@@ -132,3 +134,650 @@ impl Pty {
 		}
 	}
 }
+
+/// The terminal modes of a pseudo-terminal request, encoded as described
+/// in [RFC 4254, section 8](https://tools.ietf.org/html/rfc4254#section-8).
+///
+/// ```
+/// use russh::{Pty, TerminalModes};
+///
+/// let modes = TerminalModes::new()
+/// 	.set(Pty::VINTR, 3)
+/// 	.flag(Pty::ECHO, true)
+/// 	.flag(Pty::ICANON, true)
+/// 	.input_speed(38400)
+/// 	.output_speed(38400);
+///
+/// assert_eq!(modes.get(Pty::VINTR), Some(3));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TerminalModes {
+	modes:Vec<(Pty, u32)>,
+}
+
+impl TerminalModes {
+	/// No modes, leaving the server's defaults.
+	pub fn new() -> Self { TerminalModes { modes:Vec::new() } }
+
+	/// Set `opcode` to `value`, replacing an earlier value. Special
+	/// characters such as [`Pty::VINTR`] take a character, 255 to disable
+	/// it, and flags such as [`Pty::ECHO`] take 0 or 1.
+	/// [`Pty::TTY_OP_END`] is ignored.
+	pub fn set(mut self, opcode:Pty, value:u32) -> Self {
+		if opcode == Pty::TTY_OP_END {
+			return self;
+		}
+
+		if let Some(mode) = self.modes.iter_mut().find(|(o, _)| *o == opcode) {
+			mode.1 = value;
+		} else {
+			self.modes.push((opcode, value));
+		}
+
+		self
+	}
+
+	/// Enable or disable a flag, such as [`Pty::ECHO`].
+	pub fn flag(self, opcode:Pty, enabled:bool) -> Self { self.set(opcode, enabled as u32) }
+
+	/// The input baud rate.
+	pub fn input_speed(self, baud:u32) -> Self { self.set(Pty::TTY_OP_ISPEED, baud) }
+
+	/// The output baud rate.
+	pub fn output_speed(self, baud:u32) -> Self { self.set(Pty::TTY_OP_OSPEED, baud) }
+
+	/// The value of `opcode`, if it is set.
+	pub fn get(&self, opcode:Pty) -> Option<u32> {
+		self.modes.iter().find(|(o, _)| *o == opcode).map(|(_, value)| *value)
+	}
+
+	/// The modes, in the order they were set.
+	pub fn as_slice(&self) -> &[(Pty, u32)] { &self.modes }
+
+	/// Whether no mode is set.
+	pub fn is_empty(&self) -> bool { self.modes.is_empty() }
+
+	/// The encoded modes, ending with [`Pty::TTY_OP_END`].
+	pub fn encode(&self) -> Vec<u8> {
+		let mut encoded = Vec::with_capacity(5 * self.modes.len() + 1);
+
+		for &(opcode, value) in &self.modes {
+			encoded.push(opcode as u8);
+
+			encoded.extend_from_slice(&value.to_be_bytes());
+		}
+
+		encoded.push(Pty::TTY_OP_END as u8);
+
+		encoded
+	}
+
+	/// Decode encoded modes. Unknown opcodes are skipped, and opcodes from
+	/// 160 on, whose arguments are not defined, stop decoding, as the RFC
+	/// requires.
+	pub fn decode(encoded:&[u8]) -> Result<Self, crate::Error> {
+		let mut modes = TerminalModes::new();
+
+		let mut r = encoded.reader(0);
+
+		while !r.is_at_end() {
+			let opcode = r.read_byte().map_err(crate::Error::from)?;
+
+			if opcode == Pty::TTY_OP_END as u8 || opcode >= 160 {
+				break;
+			}
+
+			let value = r.read_u32().map_err(crate::Error::from)?;
+
+			if let Some(opcode) = Pty::from_u8(opcode) {
+				modes = modes.set(opcode, value);
+			}
+		}
+
+		Ok(modes)
+	}
+
+	/// The modes of the terminal on the standard input, as OpenSSH sends
+	/// them.
+	#[cfg(unix)]
+	pub fn from_local() -> std::io::Result<Self> { Self::from_fd(libc::STDIN_FILENO) }
+
+	/// The modes of the terminal `fd`, as OpenSSH sends them.
+	#[cfg(unix)]
+	pub fn from_fd(fd:std::os::unix::io::RawFd) -> std::io::Result<Self> {
+		let mut tio = std::mem::MaybeUninit::<libc::termios>::uninit();
+
+		// `tcgetattr` initialises `tio` when it succeeds.
+		let tio = unsafe {
+			if libc::tcgetattr(fd, tio.as_mut_ptr()) != 0 {
+				return Err(std::io::Error::last_os_error());
+			}
+
+			tio.assume_init()
+		};
+
+		let mut modes = TerminalModes::new()
+			.output_speed(termios::baud(unsafe { libc::cfgetospeed(&tio) }))
+			.input_speed(termios::baud(unsafe { libc::cfgetispeed(&tio) }));
+
+		for &(opcode, index) in termios::CHARACTERS {
+			if let Some(&c) = tio.c_cc.get(index) {
+				let c = if c == termios::VDISABLE { 255 } else { c as u32 };
+
+				modes = modes.set(opcode, c);
+			}
+		}
+
+		for &(opcode, flags, flag) in termios::FLAGS {
+			let flags = match flags {
+				termios::Flags::Input => tio.c_iflag,
+				termios::Flags::Local => tio.c_lflag,
+				termios::Flags::Output => tio.c_oflag,
+				termios::Flags::Control => tio.c_cflag,
+			};
+
+			modes = modes.flag(opcode, flags & flag != 0);
+		}
+
+		Ok(modes)
+	}
+}
+
+impl From<&[(Pty, u32)]> for TerminalModes {
+	fn from(modes:&[(Pty, u32)]) -> Self {
+		modes.iter().fold(TerminalModes::new(), |modes, &(opcode, value)| modes.set(opcode, value))
+	}
+}
+
+/// The termios fields of the modes, in the order of OpenSSH's
+/// `ttymodes.h`.
+#[cfg(unix)]
+mod termios {
+	use super::Pty;
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	pub const VDISABLE:libc::cc_t = 0;
+
+	#[cfg(not(any(target_os = "linux", target_os = "android")))]
+	pub const VDISABLE:libc::cc_t = 0xff;
+
+	pub const CHARACTERS:&[(Pty, usize)] = &[
+		(Pty::VINTR, libc::VINTR),
+		(Pty::VQUIT, libc::VQUIT),
+		(Pty::VERASE, libc::VERASE),
+		(Pty::VKILL, libc::VKILL),
+		(Pty::VEOF, libc::VEOF),
+		(Pty::VEOL, libc::VEOL),
+		(Pty::VEOL2, libc::VEOL2),
+		(Pty::VSTART, libc::VSTART),
+		(Pty::VSTOP, libc::VSTOP),
+		(Pty::VSUSP, libc::VSUSP),
+		#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+		(Pty::VDSUSP, libc::VDSUSP),
+		(Pty::VREPRINT, libc::VREPRINT),
+		(Pty::VWERASE, libc::VWERASE),
+		(Pty::VLNEXT, libc::VLNEXT),
+		#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+		(Pty::VSTATUS, libc::VSTATUS),
+		(Pty::VDISCARD, libc::VDISCARD),
+	];
+
+	#[derive(Copy, Clone)]
+	pub enum Flags {
+		Input,
+		Local,
+		Output,
+		Control,
+	}
+
+	pub const FLAGS:&[(Pty, Flags, libc::tcflag_t)] = &[
+		(Pty::IGNPAR, Flags::Input, libc::IGNPAR),
+		(Pty::PARMRK, Flags::Input, libc::PARMRK),
+		(Pty::INPCK, Flags::Input, libc::INPCK),
+		(Pty::ISTRIP, Flags::Input, libc::ISTRIP),
+		(Pty::INLCR, Flags::Input, libc::INLCR),
+		(Pty::IGNCR, Flags::Input, libc::IGNCR),
+		(Pty::ICRNL, Flags::Input, libc::ICRNL),
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		(Pty::IUCLC, Flags::Input, libc::IUCLC),
+		(Pty::IXON, Flags::Input, libc::IXON),
+		(Pty::IXANY, Flags::Input, libc::IXANY),
+		(Pty::IXOFF, Flags::Input, libc::IXOFF),
+		(Pty::IMAXBEL, Flags::Input, libc::IMAXBEL),
+		#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+		(Pty::IUTF8, Flags::Input, libc::IUTF8),
+		(Pty::ISIG, Flags::Local, libc::ISIG),
+		(Pty::ICANON, Flags::Local, libc::ICANON),
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		(Pty::XCASE, Flags::Local, libc::XCASE),
+		(Pty::ECHO, Flags::Local, libc::ECHO),
+		(Pty::ECHOE, Flags::Local, libc::ECHOE),
+		(Pty::ECHOK, Flags::Local, libc::ECHOK),
+		(Pty::ECHONL, Flags::Local, libc::ECHONL),
+		(Pty::NOFLSH, Flags::Local, libc::NOFLSH),
+		(Pty::TOSTOP, Flags::Local, libc::TOSTOP),
+		(Pty::IEXTEN, Flags::Local, libc::IEXTEN),
+		(Pty::ECHOCTL, Flags::Local, libc::ECHOCTL),
+		(Pty::ECHOKE, Flags::Local, libc::ECHOKE),
+		(Pty::PENDIN, Flags::Local, libc::PENDIN),
+		(Pty::OPOST, Flags::Output, libc::OPOST),
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		(Pty::OLCUC, Flags::Output, libc::OLCUC),
+		(Pty::ONLCR, Flags::Output, libc::ONLCR),
+		(Pty::OCRNL, Flags::Output, libc::OCRNL),
+		(Pty::ONOCR, Flags::Output, libc::ONOCR),
+		(Pty::ONLRET, Flags::Output, libc::ONLRET),
+		(Pty::CS7, Flags::Control, libc::CS7),
+		(Pty::CS8, Flags::Control, libc::CS8),
+		(Pty::PARENB, Flags::Control, libc::PARENB),
+		(Pty::PARODD, Flags::Control, libc::PARODD),
+	];
+
+	/// The baud rate of a `speed_t`, which is the rate itself on some
+	/// systems, and a constant on others.
+	pub fn baud(speed:libc::speed_t) -> u32 {
+		match speed {
+			libc::B0 => 0,
+			libc::B50 => 50,
+			libc::B75 => 75,
+			libc::B110 => 110,
+			libc::B134 => 134,
+			libc::B150 => 150,
+			libc::B200 => 200,
+			libc::B300 => 300,
+			libc::B600 => 600,
+			libc::B1200 => 1200,
+			libc::B1800 => 1800,
+			libc::B2400 => 2400,
+			libc::B4800 => 4800,
+			libc::B9600 => 9600,
+			libc::B19200 => 19200,
+			libc::B38400 => 38400,
+			libc::B57600 => 57600,
+			libc::B115200 => 115200,
+			libc::B230400 => 230400,
+			speed => speed as u32,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// What OpenSSH sends for a terminal in its default Linux settings, at
+	// 38400 baud: both speeds, the special characters, then the flags.
+	const OPENSSH_MODES:[u8; 261] = hex_literal::hex!(
+		"810000960080000096000100000003020000001c030000007f0400000015050000000406000000ff"
+		"07000000ff080000001109000000130a0000001a0c000000120d000000170e00000016120000000f"
+		"1e000000001f00000000200000000021000000002200000000230000000024000000012500000000"
+		"26000000012700000000280000000029000000012a00000001320000000133000000013400000000"
+		"350000000136000000013700000001380000000039000000003a000000003b000000013c00000001"
+		"3d000000013e0000000046000000014700000000480000000149000000004a000000004b00000000"
+		"5a000000015b000000015c000000005d0000000000"
+	);
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_openssh_round_trip() {
+		let modes = TerminalModes::decode(&OPENSSH_MODES).unwrap();
+
+		assert_eq!(modes.as_slice().len(), 52);
+
+		assert_eq!(modes.get(Pty::TTY_OP_OSPEED), Some(38400));
+
+		assert_eq!(modes.get(Pty::VINTR), Some(3));
+
+		assert_eq!(modes.get(Pty::VERASE), Some(0x7f));
+
+		// Disabled.
+		assert_eq!(modes.get(Pty::VEOL), Some(255));
+
+		assert_eq!(modes.get(Pty::ECHO), Some(1));
+
+		assert_eq!(modes.get(Pty::ICANON), Some(1));
+
+		assert_eq!(modes.get(Pty::ISIG), Some(1));
+
+		assert_eq!(modes.get(Pty::OPOST), Some(1));
+
+		assert_eq!(modes.get(Pty::PARENB), Some(0));
+
+		assert_eq!(modes.get(Pty::VDSUSP), None);
+
+		assert_eq!(modes.encode(), OPENSSH_MODES);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_builder() {
+		let modes = TerminalModes::new()
+			.flag(Pty::ECHO, true)
+			.set(Pty::VINTR, 3)
+			.flag(Pty::ECHO, false)
+			.set(Pty::TTY_OP_END, 1)
+			.output_speed(9600);
+
+		let expected = [(Pty::ECHO, 0), (Pty::VINTR, 3), (Pty::TTY_OP_OSPEED, 9600)];
+
+		assert_eq!(modes.as_slice(), &expected);
+
+		assert_eq!(modes.encode(), hex_literal::hex!("3500000000 0100000003 8100002580 00"));
+
+		assert_eq!(TerminalModes::decode(&modes.encode()).unwrap(), modes);
+
+		assert_eq!(TerminalModes::new().encode(), [0]);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_decode() {
+		// An unknown opcode is skipped, and 160 stops decoding.
+		let encoded = hex_literal::hex!("6400000001 3500000001 a0 0100000003");
+
+		let modes = TerminalModes::decode(&encoded).unwrap();
+
+		assert_eq!(modes.as_slice(), &[(Pty::ECHO, 1)]);
+
+		// Without `TTY_OP_END`.
+		let modes = TerminalModes::decode(&hex_literal::hex!("3500000001")).unwrap();
+
+		assert_eq!(modes.as_slice(), &[(Pty::ECHO, 1)]);
+
+		assert!(TerminalModes::decode(&hex_literal::hex!("35000000")).is_err());
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_window_change_and_signal() {
+		// Echoes the resizes and signals back on the channel.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn window_change_request(
+				self,
+				channel:ChannelId,
+				col_width:u32,
+				row_height:u32,
+				pix_width:u32,
+				pix_height:u32,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				let size = format!("{}x{} {}x{}\n", col_width, row_height, pix_width, pix_height);
+
+				session.data(channel, CryptoVec::from(size))?;
+
+				Ok((self, session))
+			}
+
+			async fn signal(
+				self,
+				channel:ChannelId,
+				signal:crate::Sig,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.data(channel, CryptoVec::from(format!("{:?}\n", signal)))?;
+
+				Ok((self, session))
+			}
+		}
+
+		test_session(
+			Client {},
+			ServerHandle {},
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					// Neither request waits for a reply.
+					channel.window_change(80, 24, 640, 480).await.unwrap();
+
+					channel.signal(crate::Sig::INT).await.unwrap();
+
+					channel.window_change(132, 43, 0, 0).await.unwrap();
+
+					channel.signal(crate::Sig::Custom("WINCH".to_string())).await.unwrap();
+
+					channel.signal(crate::Sig::USR2).await.unwrap();
+
+					let expected = "80x24 640x480\nINT\n132x43 0x0\nCustom(\"WINCH\")\nUSR2\n";
+
+					let mut received = Vec::new();
+
+					while received.len() < expected.len() {
+						if let ChannelMsg::Data { data } = channel.wait().await.unwrap() {
+							received.extend_from_slice(&data);
+						}
+					}
+
+					assert_eq!(String::from_utf8(received).unwrap(), expected);
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	async fn test_pty_request() {
+		use crate::{Pty, TerminalModes};
+
+		// Refuses terminals it does not know, and describes the others on
+		// the channel.
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn pty_request(
+				self,
+				channel:ChannelId,
+				term:&str,
+				col_width:u32,
+				row_height:u32,
+				pix_width:u32,
+				pix_height:u32,
+				modes:&[(Pty, u32)],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if term == "unknown" {
+					session.channel_failure(channel);
+
+					return Ok((self, session));
+				}
+
+				let pty = format!(
+					"{} {}x{} {}x{} {:?}\n",
+					term, col_width, row_height, pix_width, pix_height, modes
+				);
+
+				session.data(channel, CryptoVec::from(pty))?;
+
+				session.channel_success(channel);
+
+				Ok((self, session))
+			}
+		}
+
+		test_session(
+			Client {},
+			ServerHandle {},
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					let none = TerminalModes::new();
+
+					channel.request_pty(true, "unknown", 80, 24, 0, 0, &none).await.unwrap();
+
+					assert!(matches!(channel.wait().await, Some(ChannelMsg::Failure)));
+
+					let modes = TerminalModes::new().set(Pty::VINTR, 3).flag(Pty::ECHO, false);
+
+					channel
+						.request_pty(true, "xterm-256color", 132, 43, 1056, 688, &modes)
+						.await
+						.unwrap();
+
+					let expected = "xterm-256color 132x43 1056x688 [(VINTR, 3), (ECHO, 0)]\n";
+
+					let mut received = Vec::new();
+
+					loop {
+						match channel.wait().await.unwrap() {
+							ChannelMsg::Data { data } => received.extend_from_slice(&data),
+							ChannelMsg::Success => break,
+							msg => panic!("unexpected message {:?}", msg),
+						}
+					}
+
+					assert_eq!(String::from_utf8(received).unwrap(), expected);
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	/// `ssh -tt` against a russh server, which prints the terminal it
+	/// was asked for. Run with `cargo test -- --ignored`, with `ssh` in
+	/// the `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_pty_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{net::TcpListener, process::Command};
+
+		use crate::Pty;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn pty_request(
+				self,
+				channel:ChannelId,
+				term:&str,
+				_:u32,
+				_:u32,
+				_:u32,
+				_:u32,
+				_:&[(Pty, u32)],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.data(channel, CryptoVec::from(format!("{}\r\n", term)))?;
+
+				session.channel_success(channel);
+
+				Ok((self, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.send_exit_and_close(channel, 0);
+
+				Ok((self, session))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			server::run_stream(Arc::new(config), stream, ServerHandle {}).await.unwrap().await.ok();
+		});
+
+		let ssh = Command::new("ssh")
+			.args(["-tt", "-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.env("TERM", "vt220")
+			.stdin(std::process::Stdio::null())
+			.arg("user@127.0.0.1")
+			.arg("true")
+			.output();
+
+		let output = tokio::time::timeout(Duration::from_secs(30), ssh).await.unwrap().unwrap();
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		assert!(output.status.success(), "{}", stderr);
+
+		assert_eq!(output.stdout, b"vt220\r\n");
+	}
+}
@@ -12,21 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use std::cell::RefCell;
+use std::{cell::RefCell, num::Wrapping};
 
 use auth::*;
-use byteorder::{BigEndian, ByteOrder};
-use log::{debug, error, info, trace, warn};
+use log::{debug, trace, warn};
 use msg;
 use negotiation::{self, Select};
 use russh_keys::{
+	certificate::{self, Certificate},
 	encoding::{Encoding, Position, Reader},
 	key,
 	key::Verify,
 };
 use tokio::{sync::mpsc::unbounded_channel, time::Instant};
 
-use super::{super::*, *};
+use super::{super::*, session::GlobalRequest, *};
 use crate::{
 	msg::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
 	parsing::{ChannelOpenConfirmation, ChannelType, OpenChannelMessage},
@@ -37,6 +37,7 @@ impl Session {
 	pub(crate) async fn server_read_encrypted<H:Handler + Send>(
 		mut self,
 		mut handler:H,
+		seqn:&mut Wrapping<u32>,
 		buf:&[u8],
 	) -> Result<(H, Self), H::Error> {
 		#[allow(clippy::indexing_slicing)] // length checked
@@ -62,8 +63,15 @@ impl Session {
 			} else if let Some(exchange) = enc.exchange.take() {
 				let kexinit = KexInit::received_rekey(
 					exchange,
-					negotiation::Server::read_kex(buf, &self.common.config.as_ref().preferred)?,
+					negotiation::Server::read_kex_with_host_keys(
+						buf,
+						&self.common.config.as_ref().preferred,
+						&self.common.config.host_key_algorithms(),
+						enc.none_cipher,
+						&self.common.config.as_ref().ciphers,
+					)?,
 					&enc.session_id,
+					enc.none_cipher,
 				);
 
 				enc.rekey = Some(kexinit.server_parse(
@@ -81,12 +89,21 @@ impl Session {
 
 		match enc.rekey.take() {
 			Some(Kex::Dh(kexdh)) => {
-				enc.rekey = Some(kexdh.parse(
-					self.common.config.as_ref(),
-					&mut *self.common.cipher.local_to_remote,
-					buf,
-					&mut self.common.write_buffer,
-				)?);
+				let kex = kexdh
+					.parse(
+						self.common.config.as_ref(),
+						&mut *self.common.cipher.local_to_remote,
+						buf,
+						&mut self.common.write_buffer,
+					)
+					.await?;
+
+				// `Keys` means our NEWKEYS was just written.
+				if self.common.strict_kex && matches!(kex, Kex::Keys(_)) {
+					self.common.write_buffer.seqn = Wrapping(0);
+				}
+
+				enc.rekey = Some(kex);
 
 				self.flush()?;
 
@@ -97,6 +114,10 @@ impl Session {
 					return Err(Error::Kex.into());
 				}
 
+				if self.common.strict_kex {
+					*seqn = Wrapping(0);
+				}
+
 				self.common.write_buffer.bytes = 0;
 
 				enc.last_rekey = std::time::Instant::now();
@@ -104,6 +125,8 @@ impl Session {
 				// Ok, NEWKEYS received, now encrypted.
 				enc.flush_all_pending();
 
+				enc.wake_writers(&self.common.backlogs);
+
 				let mut pending = std::mem::take(&mut self.pending_reads);
 
 				for p in pending.drain(..) {
@@ -120,6 +143,12 @@ impl Session {
 
 				self.common.newkeys(newkeys);
 
+				self.common.stats.rekeyed();
+
+				if let Some(ref enc) = self.common.encrypted {
+					handler.on_rekey(&enc.negotiated);
+				}
+
 				self.flush()?;
 
 				return Ok((handler, self));
@@ -181,7 +210,7 @@ impl Session {
 
 				if request == b"ssh-userauth" {
 					let auth_request = server_accept_service(
-						self.common.config.as_ref().auth_banner,
+						self.common.config.as_ref().auth_banner.as_deref(),
 						self.common.config.as_ref().methods,
 						&mut enc.write,
 					);
@@ -199,6 +228,7 @@ impl Session {
 					.server_read_auth_request(
 						rejection_wait_until,
 						initial_none_rejection_wait_until,
+						self.common.config.methods,
 						handler,
 						buf,
 						&mut self.common.auth_user,
@@ -207,11 +237,19 @@ impl Session {
 
 				self.common.auth_attempts += 1;
 
+				self.common.stats.auth_attempted();
+
 				if let EncryptedState::InitCompression = enc.state {
-					enc.client_compression.init_decompress(&mut enc.decompress);
+					enc.start_delayed_decompress();
+
+					self.admission.unauthenticated = None;
+
+					self.maybe_announce_host_keys();
 
 					handler.auth_succeeded(self).await
 				} else {
+					self.check_auth_rejections();
+
 					Ok((handler, self))
 				}
 			},
@@ -233,18 +271,34 @@ impl Session {
 				if resp {
 					enc.state = EncryptedState::InitCompression;
 
-					enc.client_compression.init_decompress(&mut enc.decompress);
+					enc.start_delayed_decompress();
+
+					self.admission.unauthenticated = None;
+
+					self.maybe_announce_host_keys();
 
 					handler.auth_succeeded(self).await
 				} else {
+					self.check_auth_rejections();
+
 					Ok((handler, self))
 				}
 			},
 			EncryptedState::InitCompression => {
-				enc.server_compression.init_compress(&mut enc.compress);
-
 				enc.state = EncryptedState::Authenticated;
 
+				if self.common.config.allow_none_cipher_after_auth {
+					let (h, allowed) = handler.allow_none_cipher(&self.common.auth_user).await?;
+
+					handler = h;
+
+					if allowed {
+						if let Some(ref mut enc) = self.common.encrypted {
+							enc.none_cipher = negotiation::NoneCipher::Accept;
+						}
+					}
+				}
+
 				self.server_read_authenticated(handler, buf).await
 			},
 			EncryptedState::Authenticated => self.server_read_authenticated(handler, buf).await,
@@ -276,6 +330,7 @@ fn server_accept_service(
 
 	AuthRequest {
 		methods,
+		user:None,
 		partial_success:false, // not used immediately anway.
 		current:None,
 		rejection_count:0,
@@ -288,6 +343,7 @@ impl Encrypted {
 		&mut self,
 		mut until:Instant,
 		initial_auth_until:Instant,
+		methods:MethodSet,
 		mut handler:H,
 		buf:&[u8],
 		auth_user:&mut String,
@@ -311,6 +367,27 @@ impl Encrypted {
 		);
 
 		if service_name == b"ssh-connection" {
+			let (h, offered) = self.offered_methods(handler, user, methods).await?;
+
+			handler = h;
+
+			// Methods not offered are rejected without asking the handler.
+			if let Some(requested) = MethodSet::from_bytes(method) {
+				if !offered.contains(requested) {
+					let handler = self
+						.reject_not_offered(
+							until,
+							initial_auth_until,
+							handler,
+							requested,
+							auth_user,
+						)
+						.await;
+
+					return Ok(handler);
+				}
+			}
+
 			if method == b"password" {
 				let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
 				{
@@ -323,31 +400,64 @@ impl Encrypted {
 
 				auth_user.push_str(user);
 
-				r.read_byte().map_err(crate::Error::from)?;
+				let change = r.read_byte().map_err(crate::Error::from)? != 0;
 
 				let password = r.read_string().map_err(crate::Error::from)?;
 
 				let password = std::str::from_utf8(password).map_err(crate::Error::from)?;
 
-				let (handler, auth) = handler.auth_password(user, password).await?;
+				let (handler, auth) = if change {
+					let new_password = r.read_string().map_err(crate::Error::from)?;
 
-				if let Auth::Accept = auth {
-					server_auth_request_success(&mut self.write);
+					let new_password =
+						std::str::from_utf8(new_password).map_err(crate::Error::from)?;
 
-					self.state = EncryptedState::InitCompression;
+					handler.auth_password_change(user, password, new_password).await?
 				} else {
-					auth_user.clear();
+					handler.auth_password(user, password).await?
+				};
 
-					auth_request.methods -= MethodSet::PASSWORD;
+				match auth {
+					Auth::Accept => {
+						server_auth_request_success(&mut self.write);
 
-					auth_request.partial_success = false;
+						self.state = EncryptedState::InitCompression;
+					},
+					Auth::PartialSuccess { proceed_with_methods } => {
+						auth_user.clear();
 
-					reject_auth_request(until, &mut self.write, auth_request).await;
+						server_auth_request_partial_success(
+							&mut self.write,
+							auth_request,
+							proceed_with_methods,
+						);
+					},
+					Auth::PasswordChange { prompt } => {
+						auth_user.clear();
+
+						push_packet!(self.write, {
+							self.write.push(msg::USERAUTH_PASSWD_CHANGEREQ);
+
+							self.write.extend_ssh_string(prompt.as_bytes());
+
+							self.write.extend_ssh_string(b""); // lang, should be empty
+						});
+					},
+					_ => {
+						auth_user.clear();
+
+						auth_request.partial_success = false;
+
+						reject_auth_request(until, &mut self.write, auth_request).await;
+					},
 				}
 
 				Ok(handler)
 			} else if method == b"publickey" {
 				self.server_read_auth_request_pk(until, handler, buf, auth_user, user, r).await
+			} else if method == b"hostbased" {
+				self.server_read_auth_request_hostbased(until, handler, buf, auth_user, user, r)
+					.await
 			} else if method == b"none" {
 				let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
 				{
@@ -362,18 +472,40 @@ impl Encrypted {
 
 				let (handler, auth) = handler.auth_none(user).await?;
 
-				if let Auth::Accept = auth {
-					server_auth_request_success(&mut self.write);
+				match auth {
+					Auth::Accept => {
+						server_auth_request_success(&mut self.write);
 
-					self.state = EncryptedState::InitCompression;
-				} else {
-					auth_user.clear();
+						self.state = EncryptedState::InitCompression;
+					},
+					Auth::PartialSuccess { proceed_with_methods } => {
+						auth_user.clear();
 
-					auth_request.methods -= MethodSet::NONE;
+						server_auth_request_partial_success(
+							&mut self.write,
+							auth_request,
+							proceed_with_methods,
+						);
+					},
+					auth => {
+						auth_user.clear();
 
-					auth_request.partial_success = false;
+						if let Auth::Reject { proceed_with_methods:Some(proceed_with_methods) } =
+							auth
+						{
+							auth_request.methods = proceed_with_methods;
+						}
 
-					reject_auth_request(until, &mut self.write, auth_request).await;
+						auth_request.methods -= MethodSet::NONE;
+
+						auth_request.partial_success = false;
+
+						reject_auth_request(until, &mut self.write, auth_request).await;
+
+						// Clients probe the methods with "none", this is not
+						// an attempt.
+						auth_request.rejection_count -= 1;
+					},
 				}
 
 				Ok(handler)
@@ -428,6 +560,72 @@ impl Encrypted {
 	}
 }
 
+impl Encrypted {
+	/// The methods offered to `user`, from
+	/// [`Handler::auth_methods_for_user`] when the client names a new
+	/// user, which starts authentication anew.
+	async fn offered_methods<H:Handler + Send>(
+		&mut self,
+		mut handler:H,
+		user:&str,
+		methods:MethodSet,
+	) -> Result<(H, MethodSet), H::Error> {
+		let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+			a
+		} else {
+			unreachable!()
+		};
+
+		if auth_request.user.as_deref() != Some(user) {
+			let (h, methods) = handler.auth_methods_for_user(user, methods).await?;
+
+			handler = h;
+
+			auth_request.methods = methods;
+
+			auth_request.user = Some(user.to_string());
+
+			auth_request.partial_success = false;
+
+			auth_request.current = None;
+		}
+
+		Ok((handler, auth_request.methods))
+	}
+
+	/// Reject a request with a method not offered, as its handler would.
+	async fn reject_not_offered<H:Handler + Send>(
+		&mut self,
+		until:Instant,
+		initial_auth_until:Instant,
+		handler:H,
+		method:MethodSet,
+		auth_user:&mut String,
+	) -> H {
+		let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+			a
+		} else {
+			unreachable!()
+		};
+
+		debug!("{:?} is not offered", method);
+
+		auth_user.clear();
+
+		if method == MethodSet::NONE {
+			reject_auth_request(initial_auth_until, &mut self.write, auth_request).await;
+
+			// Clients probe the methods with "none", this is not an
+			// attempt.
+			auth_request.rejection_count -= 1;
+		} else {
+			reject_auth_request(until, &mut self.write, auth_request).await;
+		}
+
+		handler
+	}
+}
+
 thread_local! {
 	static SIGNATURE_BUFFER: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
 }
@@ -456,19 +654,35 @@ impl Encrypted {
 
 		debug!("algo: {:?}, key: {:?}", pubkey_algo, pubkey_key);
 
-		match key::PublicKey::parse(pubkey_algo, pubkey_key) {
-			Ok(mut pubkey) => {
+		// The algorithm of the request decides the signature algorithm,
+		// which matters for RSA keys, used with `ssh-rsa`, `rsa-sha2-256`
+		// or `rsa-sha2-512`.
+		let parsed = if certificate::is_certificate_algorithm(pubkey_algo) {
+			Certificate::parse(pubkey_key).map(|mut cert| {
+				cert.set_algorithm(pubkey_algo);
+
+				(cert.key.clone(), Some(cert))
+			})
+		} else {
+			key::PublicKey::parse(pubkey_algo, pubkey_key).map(|key| (key, None))
+		};
+
+		match parsed {
+			Ok((pubkey, cert)) => {
 				debug!("is_real = {:?}", is_real);
 
 				if is_real != 0 {
 					let pos0 = r.position;
 
-					let sent_pk_ok = if let Some(CurrentRequest::PublicKey { sent_pk_ok, .. }) =
-						auth_request.current
-					{
-						sent_pk_ok
-					} else {
-						false
+					// This exact key was already offered in a query.
+					let pk_ok = match auth_request.current {
+						Some(CurrentRequest::PublicKey { ref key, ref algo, sent_pk_ok }) => {
+							sent_pk_ok
+								&& user == auth_user
+								&& key.as_ref() == pubkey_key
+								&& algo.as_ref() == pubkey_algo
+						},
+						_ => false,
 					};
 
 					let signature = r.read_string().map_err(crate::Error::from)?;
@@ -479,35 +693,41 @@ impl Encrypted {
 
 					let algo_ = s.read_string().map_err(crate::Error::from)?;
 
-					pubkey.set_algorithm(algo_);
-
 					debug!("algo_: {:?}", algo_);
 
 					let sig = s.read_string().map_err(crate::Error::from)?;
+
+					// Security keys also sign flags and a counter.
+					let sk = if pubkey.is_security_key() {
+						Some((
+							s.read_byte().map_err(crate::Error::from)?,
+							s.read_u32().map_err(crate::Error::from)?,
+						))
+					} else {
+						None
+					};
 					#[allow(clippy::indexing_slicing)] // length checked
 					let init = &buf[0..pos0];
 
-					let is_valid = if sent_pk_ok && user == auth_user {
+					let offered = if pk_ok {
 						true
-					} else if auth_user.is_empty() {
+					} else {
 						auth_user.clear();
 
 						auth_user.push_str(user);
 
-						let (h, auth) = handler.auth_publickey(user, &pubkey).await?;
+						let (h, auth) = handler.auth_publickey_offered(user, &pubkey).await?;
 
 						handler = h;
 
-						auth == Auth::Accept
-					} else {
-						false
+						matches!(auth, Auth::Accept | Auth::PartialSuccess { .. })
 					};
 
-					if is_valid {
+					// The signature must use the algorithm of the request.
+					let mut verified = offered && algo_ == pubkey.name().as_bytes() && {
 						let session_id = self.session_id.as_ref();
-						#[allow(clippy::blocks_in_if_conditions)]
-						// length checked
-						if SIGNATURE_BUFFER.with(|buf| {
+
+						SIGNATURE_BUFFER.with(|buf| {
 							let mut buf = buf.borrow_mut();
 
 							buf.clear();
@@ -516,20 +736,75 @@ impl Encrypted {
 
 							buf.extend(init);
 							// Verify signature.
-							pubkey.verify_client_auth(&buf, sig)
-						}) {
-							debug!("signature verified");
+							if let Some((flags, counter)) = sk {
+								pubkey.verify_sk_detached(&buf, sig, flags, counter)
+							} else {
+								pubkey.verify_client_auth(&buf, sig)
+							}
+						})
+					};
+
+					if verified
+						&& sk.map_or(false, |(flags, _)| flags & key::SK_USER_PRESENT == 0)
+					{
+						debug!("security key not touched");
+
+						let (h, accept) = handler.auth_sk_no_touch_required(user, &pubkey).await?;
+
+						handler = h;
+
+						verified = accept;
+					}
+
+					// Only now that the client has proven it holds the key
+					// is the handler asked to authenticate it.
+					let auth = if verified {
+						debug!("signature verified");
+
+						let (h, auth) = if let Some(ref cert) = cert {
+							handler.auth_openssh_certificate(user, cert).await?
+						} else {
+							handler.auth_publickey(user, &pubkey).await?
+						};
+
+						handler = h;
+
+						auth
+					} else {
+						debug!("signature wrong, or key not offered");
+
+						Auth::Reject { proceed_with_methods:None }
+					};
 
+					match auth {
+						Auth::Accept => {
 							server_auth_request_success(&mut self.write);
 
 							self.state = EncryptedState::InitCompression;
-						} else {
-							debug!("signature wrong");
+						},
+						Auth::PartialSuccess { proceed_with_methods } => {
+							auth_user.clear();
+
+							server_auth_request_partial_success(
+								&mut self.write,
+								auth_request,
+								proceed_with_methods,
+							);
+						},
+						auth => {
+							if let Auth::Reject {
+								proceed_with_methods: Some(proceed_with_methods),
+							} = auth
+							{
+								auth_request.methods = proceed_with_methods;
+							}
+
+							auth_request.partial_success = false;
+
+							auth_user.clear();
 
 							reject_auth_request(until, &mut self.write, auth_request).await;
-						}
-					} else {
-						reject_auth_request(until, &mut self.write, auth_request).await;
+						},
 					}
 
 					Ok(handler)
@@ -538,12 +813,12 @@ impl Encrypted {
 
 					auth_user.push_str(user);
 
-					let (h, auth) = handler.auth_publickey(user, &pubkey).await?;
+					let (h, auth) = handler.auth_publickey_offered(user, &pubkey).await?;
 
 					handler = h;
 
 					match auth {
-						Auth::Accept => {
+						Auth::Accept | Auth::PartialSuccess { .. } => {
 							let mut public_key = CryptoVec::new();
 
 							public_key.extend(pubkey_key);
@@ -598,11 +873,123 @@ impl Encrypted {
 			},
 		}
 	}
+
+	async fn server_read_auth_request_hostbased<H:Handler + Send>(
+		&mut self,
+		until:Instant,
+		mut handler:H,
+		buf:&[u8],
+		auth_user:&mut String,
+		user:&str,
+		mut r:Position<'_>,
+	) -> Result<H, H::Error> {
+		let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+			a
+		} else {
+			unreachable!()
+		};
+
+		auth_user.clear();
+
+		auth_user.push_str(user);
+
+		let algo = r.read_string().map_err(crate::Error::from)?;
+
+		let key_blob = r.read_string().map_err(crate::Error::from)?;
+
+		let client_hostname = std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
+			.map_err(crate::Error::from)?;
+
+		let client_user = std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
+			.map_err(crate::Error::from)?;
+
+		let pos0 = r.position;
+
+		let signature = r.read_string().map_err(crate::Error::from)?;
+
+		debug!(
+			"hostbased: {:?} {:?} {:?}",
+			std::str::from_utf8(algo),
+			client_hostname,
+			client_user
+		);
+
+		let mut pubkey = match key::PublicKey::parse(algo, key_blob) {
+			Ok(pubkey) => pubkey,
+			Err(russh_keys::Error::CouldNotReadKey) => {
+				auth_user.clear();
+
+				reject_auth_request(until, &mut self.write, auth_request).await;
+
+				return Ok(handler);
+			},
+			Err(e) => return Err(crate::Error::from(e).into()),
+		};
+
+		let mut s = signature.reader(0);
+
+		pubkey.set_algorithm(s.read_string().map_err(crate::Error::from)?);
+
+		let sig = s.read_string().map_err(crate::Error::from)?;
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		let init = &buf[0..pos0];
+
+		let session_id = self.session_id.as_ref();
+
+		let signature_valid = SIGNATURE_BUFFER.with(|buf| {
+			let mut buf = buf.borrow_mut();
+
+			buf.clear();
+
+			buf.extend_ssh_string(session_id);
+
+			buf.extend(init);
+
+			pubkey.verify_client_auth(&buf, sig)
+		});
+
+		let (h, auth) = handler
+			.auth_hostbased(user, &pubkey, client_hostname, client_user, signature_valid)
+			.await?;
+
+		handler = h;
+
+		match auth {
+			Auth::Accept if signature_valid => {
+				server_auth_request_success(&mut self.write);
+
+				self.state = EncryptedState::InitCompression;
+			},
+			Auth::PartialSuccess { proceed_with_methods } if signature_valid => {
+				auth_user.clear();
+
+				server_auth_request_partial_success(
+					&mut self.write,
+					auth_request,
+					proceed_with_methods,
+				);
+			},
+			auth => {
+				if let Auth::Reject { proceed_with_methods:Some(proceed_with_methods) } = auth {
+					auth_request.methods = proceed_with_methods;
+				}
+
+				auth_user.clear();
+
+				reject_auth_request(until, &mut self.write, auth_request).await;
+			},
+		}
+
+		Ok(handler)
+	}
 }
 
 async fn reject_auth_request(until:Instant, write:&mut CryptoVec, auth_request:&mut AuthRequest) {
 	debug!("rejecting {:?}", auth_request);
 
+	auth_request.partial_success = false;
+
 	push_packet!(write, {
 		write.push(msg::USERAUTH_FAILURE);
 
@@ -626,6 +1013,30 @@ fn server_auth_request_success(buffer:&mut CryptoVec) {
 	})
 }
 
+/// Accept one step of a multi-step authentication. Unlike rejections, this
+/// is not delayed.
+fn server_auth_request_partial_success(
+	write:&mut CryptoVec,
+	auth_request:&mut AuthRequest,
+	proceed_with_methods:MethodSet,
+) {
+	debug!("partial success, proceed with {:?}", proceed_with_methods);
+
+	auth_request.methods = proceed_with_methods;
+
+	auth_request.partial_success = true;
+
+	push_packet!(write, {
+		write.push(msg::USERAUTH_FAILURE);
+
+		write.extend_list(auth_request.methods);
+
+		write.push(1);
+	});
+
+	auth_request.current = None;
+}
+
 async fn read_userauth_info_response<H:Handler + Send>(
 	until:Instant,
 	mut handler:H,
@@ -700,7 +1111,26 @@ async fn reply_userauth_info_response(
 
 			Ok(false)
 		},
-		Auth::UnsupportedMethod => unreachable!(),
+		Auth::PartialSuccess { proceed_with_methods } => {
+			server_auth_request_partial_success(write, auth_request, proceed_with_methods);
+
+			Ok(false)
+		},
+		// Only meaningful for the "password" method.
+		Auth::PasswordChange { .. } => {
+			reject_auth_request(until, write, auth_request).await;
+
+			Ok(false)
+		},
+		Auth::UnsupportedMethod => {
+			auth_request.methods -= MethodSet::KEYBOARD_INTERACTIVE;
+
+			auth_request.partial_success = false;
+
+			reject_auth_request(until, write, auth_request).await;
+
+			Ok(false)
+		},
 	}
 }
 
@@ -715,8 +1145,17 @@ impl Session {
 			trace!("authenticated buf = {:?}", &buf[..std::cmp::min(buf.len(), 100)]);
 		}
 
+		// https://tools.ietf.org/html/rfc4254#section-5.3
+		if self.common.encrypted.as_ref().map_or(false, |enc| enc.is_for_closing_channel(buf)) {
+			debug!("discarding a message on a closed channel");
+
+			return Ok((handler, self));
+		}
+
 		match buf.first() {
 			Some(&msg::CHANNEL_OPEN) => {
+				self.common.stats.channel_opened();
+
 				self.server_handle_channel_open(handler, buf).await.map(|(h, _, s)| (h, s))
 			},
 			Some(&msg::CHANNEL_CLOSE) => {
@@ -725,7 +1164,7 @@ impl Session {
 				let channel_num = ChannelId(r.read_u32().map_err(crate::Error::from)?);
 
 				if let Some(ref mut enc) = self.common.encrypted {
-					enc.channels.remove(&channel_num);
+					enc.close_received(channel_num);
 				}
 
 				self.channels.remove(&channel_num);
@@ -789,18 +1228,22 @@ impl Session {
 				let mut new_value = 0;
 
 				if let Some(ref mut enc) = self.common.encrypted {
-					if let Some(channel) = enc.channels.get_mut(&channel_num) {
-						channel.recipient_window_size += amount;
-
-						new_value = channel.recipient_window_size;
-					} else {
-						return Err(Error::WrongChannel.into());
+					match enc.window_adjusted(channel_num, amount) {
+						Some(window) => new_value = window,
+						None => return Err(Error::WrongChannel.into()),
 					}
 				}
 
 				debug!("handler.window_adjusted {:?}", channel_num);
 
-				handler.window_adjusted(channel_num, new_value, self).await
+				let (handler, mut session) =
+					handler.window_adjusted(channel_num, new_value, self).await?;
+
+				if let Some(ref mut enc) = session.common.encrypted {
+					enc.wake_writers(&session.common.backlogs);
+				}
+
+				Ok((handler, session))
 			},
 
 			Some(&msg::CHANNEL_OPEN_CONFIRMATION) => {
@@ -815,6 +1258,9 @@ impl Session {
 				if let Some(ref mut enc) = self.common.encrypted {
 					if let Some(parameters) = enc.channels.get_mut(&local_id) {
 						parameters.confirm(&msg);
+
+						// Send the data queued before the confirmation.
+						enc.flush_pending(local_id);
 					} else {
 						// We've not requested this channel, close connection.
 						return Err(Error::Inconsistent.into());
@@ -862,45 +1308,22 @@ impl Session {
 
 						let pix_height = r.read_u32().map_err(crate::Error::from)?;
 
-						let mut modes = [(Pty::TTY_OP_END, 0); 130];
-
-						let mut i = 0;
-						{
-							let mode_string = r.read_string().map_err(crate::Error::from)?;
-
-							while 5 * i < mode_string.len() {
-								#[allow(clippy::indexing_slicing)]
-								// length checked
-								let code = mode_string[5 * i];
+						// Refuse malformed modes, but keep the connection.
+						let modes = match TerminalModes::decode(
+							r.read_string().map_err(crate::Error::from)?,
+						) {
+							Ok(modes) => modes,
+							Err(e) => {
+								debug!("invalid terminal modes: {:?}", e);
 
-								if code == 0 {
-									break;
-								}
-								#[allow(clippy::indexing_slicing)]
-								// length checked
-								let num = BigEndian::read_u32(&mode_string[5 * i + 1..]);
-
-								debug!("code = {:?}", code);
-
-								if let Some(code) = Pty::from_u8(code) {
-									#[allow(clippy::indexing_slicing)]
-									// length checked
-									if i < 130 {
-										modes[i] = (code, num);
-									} else {
-										error!("pty-req: too many pty codes");
-									}
-								} else {
-									info!("pty-req: unknown pty code {:?}", code);
-								}
+								self.channel_failure(channel_num);
 
-								i += 1
-							}
-						}
+								return Ok((handler, self));
+							},
+						};
 
 						debug!("handler.pty_request {:?}", channel_num);
-						#[allow(clippy::indexing_slicing)]
-						// `modes` length checked
+
 						handler
 							.pty_request(
 								channel_num,
@@ -909,7 +1332,7 @@ impl Session {
 								row_height,
 								pix_width,
 								pix_height,
-								&modes[0..i],
+								modes.as_slice(),
 								self,
 							)
 							.await
@@ -951,7 +1374,17 @@ impl Session {
 
 						debug!("handler.env_request {:?}", channel_num);
 
-						handler.env_request(channel_num, env_variable, env_value, self).await
+						let accepted;
+						(handler, accepted, self) =
+							handler.env_request(channel_num, env_variable, env_value, self).await?;
+
+						if accepted {
+							self.channel_success(channel_num)
+						} else {
+							self.channel_failure(channel_num)
+						}
+
+						Ok((handler, self))
 					},
 					b"shell" => {
 						debug!("handler.shell_request {:?}", channel_num);
@@ -966,9 +1399,9 @@ impl Session {
 							handler.agent_request(channel_num, self).await?;
 
 						if response {
-							self.request_success()
+							self.channel_success(channel_num)
 						} else {
-							self.request_failure()
+							self.channel_failure(channel_num)
 						}
 
 						Ok((handler, self))
@@ -1019,10 +1452,47 @@ impl Session {
 
 						handler.signal(channel_num, signal_name, self).await
 					},
+					b"break" => {
+						let break_length = r.read_u32().map_err(crate::Error::from)?;
+
+						debug!("handler.break_request {:?} {:?}", channel_num, break_length);
+
+						let accepted;
+						(handler, accepted, self) =
+							handler.break_request(channel_num, break_length, self).await?;
+
+						if accepted {
+							self.channel_success(channel_num)
+						} else {
+							self.channel_failure(channel_num)
+						}
+
+						Ok((handler, self))
+					},
 					x => {
-						warn!("unknown channel request {}", String::from_utf8_lossy(x));
+						let name = if let Ok(name) = std::str::from_utf8(x) {
+							name
+						} else {
+							warn!("unknown channel request {}", String::from_utf8_lossy(x));
+
+							self.channel_failure(channel_num);
+
+							return Ok((handler, self));
+						};
+
+						debug!("handler.channel_request_unknown {:?} {:?}", channel_num, name);
 
-						self.channel_failure(channel_num);
+						let data = buf.get(r.position..).unwrap_or(&[]);
+
+						let accepted;
+						(handler, accepted, self) =
+							handler.channel_request_unknown(channel_num, name, data, self).await?;
+
+						if accepted {
+							self.channel_success(channel_num)
+						} else {
+							self.channel_failure(channel_num)
+						}
 
 						Ok((handler, self))
 					},
@@ -1051,16 +1521,19 @@ impl Session {
 							handler.tcpip_forward(address, &mut returned_port, self).await?;
 
 						if let Some(ref mut enc) = s.common.encrypted {
-							if result {
-								push_packet!(enc.write, {
-									enc.write.push(msg::REQUEST_SUCCESS);
-
-									if s.common.wants_reply && port == 0 && returned_port != 0 {
-										enc.write.push_u32_be(returned_port);
-									}
-								})
-							} else {
-								push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+							if s.common.wants_reply {
+								if result {
+									push_packet!(enc.write, {
+										enc.write.push(msg::REQUEST_SUCCESS);
+
+										// The allocated port.
+										if port == 0 && returned_port != 0 {
+											enc.write.push_u32_be(returned_port);
+										}
+									})
+								} else {
+									push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+								}
 							}
 						}
 
@@ -1079,26 +1552,123 @@ impl Session {
 							handler.cancel_tcpip_forward(address, port, self).await?;
 
 						if let Some(ref mut enc) = s.common.encrypted {
-							if result {
-								push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
-							} else {
-								push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+							if s.common.wants_reply {
+								if result {
+									push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+								} else {
+									push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+								}
 							}
 						}
 
 						Ok((h, s))
 					},
-					_ => {
+					b"streamlocal-forward@openssh.com"
+					| b"cancel-streamlocal-forward@openssh.com" => {
+						let socket_path =
+							std::str::from_utf8(r.read_string().map_err(crate::Error::from)?)
+								.map_err(crate::Error::from)?;
+
+						debug!("{} {:?}", String::from_utf8_lossy(req_type), socket_path);
+
+						let (h, result, mut s) = if req_type.starts_with(b"cancel") {
+							handler.cancel_streamlocal_forward(socket_path, self).await?
+						} else {
+							handler.streamlocal_forward(socket_path, self).await?
+						};
+
+						if let Some(ref mut enc) = s.common.encrypted {
+							if s.common.wants_reply {
+								if result {
+									push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+								} else {
+									push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+								}
+							}
+						}
+
+						Ok((h, s))
+					},
+					b"no-more-sessions@openssh.com" => {
+						self.no_more_sessions = true;
+
+						if let Some(ref mut enc) = self.common.encrypted {
+							if self.common.wants_reply {
+								push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+							}
+						}
+
+						Ok((handler, self))
+					},
+					b"hostkeys-prove-00@openssh.com" => {
+						let signatures = self.prove_host_keys(&mut r);
+
 						if let Some(ref mut enc) = self.common.encrypted {
 							push_packet!(enc.write, {
-								enc.write.push(msg::REQUEST_FAILURE);
-							});
+								if let Some(signatures) = signatures {
+									enc.write.push(msg::REQUEST_SUCCESS);
+
+									for signature in signatures {
+										enc.write.extend_ssh_string(&signature);
+									}
+								} else {
+									enc.write.push(msg::REQUEST_FAILURE);
+								}
+							})
 						}
 
 						Ok((handler, self))
 					},
+					_ => {
+						let name = String::from_utf8_lossy(req_type);
+
+						let payload = buf.get(r.position..).unwrap_or_default();
+
+						let (h, response, mut s) =
+							handler.global_request(&name, payload, self).await?;
+
+						if let Some(ref mut enc) = s.common.encrypted {
+							if s.common.wants_reply {
+								push_packet!(enc.write, {
+									match response {
+										GlobalRequestResponse::Success(ref data) => {
+											enc.write.push(msg::REQUEST_SUCCESS);
+
+											enc.write.extend(data);
+										},
+										GlobalRequestResponse::Failure => {
+											enc.write.push(msg::REQUEST_FAILURE);
+										},
+									}
+								});
+							}
+						}
+
+						Ok((h, s))
+					},
 				}
 			},
+			// Any reply shows that the client is alive.
+			Some(&msg::REQUEST_SUCCESS) => {
+				self.keepalives_unanswered = 0;
+
+				if let Some(GlobalRequest::Reply(reply)) = self.pending_global_requests.pop_front()
+				{
+					reply.send(Some(buf.get(1..).unwrap_or_default().to_vec())).unwrap_or(());
+				}
+
+				Ok((handler, self))
+			},
+			Some(&msg::REQUEST_FAILURE) => {
+				self.keepalives_unanswered = 0;
+
+				if let Some(GlobalRequest::Reply(reply)) = self.pending_global_requests.pop_front()
+				{
+					reply.send(None).unwrap_or(());
+				}
+
+				Ok((handler, self))
+			},
 			Some(&msg::CHANNEL_OPEN_FAILURE) => {
 				debug!("channel_open_failure");
 
@@ -1151,12 +1721,36 @@ impl Session {
 
 		let msg = OpenChannelMessage::parse(&mut r)?;
 
+		if self.no_more_sessions && matches!(msg.typ, ChannelType::Session) {
+			debug!("session channel refused after no-more-sessions@openssh.com");
+
+			if let Some(ref mut enc) = self.common.encrypted {
+				msg.fail(&mut enc.write, SSH_OPEN_ADMINISTRATIVELY_PROHIBITED, b"No more sessions");
+			}
+
+			return Ok((handler, false, self));
+		}
+
+		if let (Some(max), Some(enc)) =
+			(self.common.config.max_channels_per_connection, &mut self.common.encrypted)
+		{
+			if enc.channels.len() >= max {
+				debug!("channel refused, {} already open", enc.channels.len());
+
+				msg.fail(&mut enc.write, msg::SSH_OPEN_RESOURCE_SHORTAGE, b"Too many channels");
+
+				return Ok((handler, false, self));
+			}
+		}
+
 		let sender_channel = if let Some(ref mut enc) = self.common.encrypted {
 			enc.new_channel_id()
 		} else {
 			unreachable!()
 		};
 
+		self.open_failure = None;
+
 		let channel_params = ChannelParams {
 			recipient_channel:msg.recipient_channel,
 
@@ -1168,9 +1762,15 @@ impl Session {
 			sender_window_size:self.common.config.window_size,
 			recipient_maximum_packet_size:msg.recipient_maximum_packet_size,
 			sender_maximum_packet_size:self.common.config.maximum_packet_size,
+			target_window_size:None,
 			confirmed:true,
 			wants_reply:false,
 			pending_data:std::collections::VecDeque::new(),
+			data_queued:0,
+			writers:std::collections::VecDeque::new(),
+			flushes:Vec::new(),
+			closes:Vec::new(),
+			pending_exit:None,
 		};
 
 		let (sender, receiver) = unbounded_channel();
@@ -1181,6 +1781,8 @@ impl Session {
 			receiver,
 			max_packet_size:channel_params.recipient_maximum_packet_size,
 			window_size:channel_params.recipient_window_size,
+			close_info:Default::default(),
+			closed:false,
 		};
 
 		match &msg.typ {
@@ -1248,7 +1850,19 @@ impl Session {
 
 				result
 			},
-			ChannelType::AgentForward => {
+			ChannelType::DirectStreamLocal(d) => {
+				let mut result =
+					handler.channel_open_direct_streamlocal(channel, &d.socket_path, self).await;
+
+				if let Ok((_, allowed, s)) = &mut result {
+					s.channels.insert(sender_channel, sender);
+
+					s.finalize_channel_open(&msg, channel_params, *allowed);
+				}
+
+				result
+			},
+			ChannelType::ForwardedStreamLocal(_) | ChannelType::AgentForward => {
 				if let Some(ref mut enc) = self.common.encrypted {
 					msg.fail(
 						&mut enc.write,
@@ -1277,6 +1891,8 @@ impl Session {
 		channel:ChannelParams,
 		allowed:bool,
 	) {
+		let failure = self.open_failure.take().filter(|f| f.channel == channel.sender_channel);
+
 		if let Some(ref mut enc) = self.common.encrypted {
 			if allowed {
 				open.confirm(
@@ -1287,6 +1903,13 @@ impl Session {
 				);
 
 				enc.channels.insert(channel.sender_channel, channel);
+			} else if let Some(failure) = failure {
+				open.fail_with_language(
+					&mut enc.write,
+					failure.reason as u8,
+					failure.description.as_bytes(),
+					failure.language.as_bytes(),
+				);
 			} else {
 				open.fail(&mut enc.write, SSH_OPEN_ADMINISTRATIVELY_PROHIBITED, b"Rejected");
 			}
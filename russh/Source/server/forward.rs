@@ -0,0 +1,484 @@
+//! Remote port forwarding, as OpenSSH's `-R` option: the server listens
+//! on the ports the client asks for, and forwards each connection to the
+//! client on a forwarded TCP/IP channel.
+//!
+//! ```no_run
+//! use russh::server::{self, Session, forward::TcpipForwards};
+//!
+//! struct Server {
+//! 	forwards:TcpipForwards,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl server::Handler for Server {
+//! 	type Error = russh::Error;
+//!
+//! 	async fn tcpip_forward(
+//! 		mut self,
+//! 		address:&str,
+//! 		port:&mut u32,
+//! 		session:Session,
+//! 	) -> Result<(Self, bool, Session), Self::Error> {
+//! 		// Only forward ports on the loopback interface.
+//! 		if address != "localhost" && address != "127.0.0.1" {
+//! 			return Ok((self, false, session));
+//! 		}
+//!
+//! 		match self.forwards.forward(session.handle(), address, *port).await {
+//! 			Ok(bound) => {
+//! 				*port = bound;
+//!
+//! 				Ok((self, true, session))
+//! 			},
+//! 			Err(_) => Ok((self, false, session)),
+//! 		}
+//! 	}
+//!
+//! 	async fn cancel_tcpip_forward(
+//! 		mut self,
+//! 		address:&str,
+//! 		port:u32,
+//! 		session:Session,
+//! 	) -> Result<(Self, bool, Session), Self::Error> {
+//! 		let cancelled = self.forwards.cancel(address, port);
+//!
+//! 		Ok((self, cancelled, session))
+//! 	}
+//! }
+//! ```
+
+use std::{collections::HashMap, convert::TryFrom, net::SocketAddr};
+
+use log::debug;
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::watch,
+};
+
+use super::Handle;
+
+/// The ports forwarded to a client with `tcpip-forward`, each with a
+/// listener on the server. Dropping this stops them all, closing their
+/// connections.
+#[derive(Debug, Default)]
+pub struct TcpipForwards {
+	listeners:HashMap<(String, u32), Listener>,
+}
+
+// Dropping the sender stops the listener and the connections.
+type Listener = watch::Sender<()>;
+
+impl TcpipForwards {
+	/// No forwarded ports.
+	pub fn new() -> Self { Self::default() }
+
+	/// Listen on `address` and `port`, as asked by the client in
+	/// [`Handler::tcpip_forward`](super::Handler::tcpip_forward), and
+	/// forward each connection to the client of `handle`. Return the
+	/// port bound, chosen by the system if `port` is 0. As in OpenSSH,
+	/// `""` and `"*"` listen on all IPv4 interfaces, and `"localhost"`
+	/// on the IPv4 loopback. The caller decides which addresses and
+	/// ports the client may bind.
+	pub async fn forward(
+		&mut self,
+		handle:Handle,
+		address:&str,
+		port:u32,
+	) -> Result<u32, crate::Error> {
+		let bind_port = u16::try_from(port).map_err(|_| {
+			std::io::Error::new(std::io::ErrorKind::InvalidInput, "port out of range")
+		})?;
+
+		let bind_addr = match address {
+			"" | "*" => "0.0.0.0",
+			"localhost" => "127.0.0.1",
+			address => address,
+		};
+
+		let listener = TcpListener::bind((bind_addr, bind_port)).await?;
+
+		let port = listener.local_addr()?.port() as u32;
+
+		let (shutdown, stopped) = watch::channel(());
+
+		tokio::spawn(accept_loop(listener, handle, address.to_string(), port, stopped));
+
+		// Replacing a forwarding stops the old one.
+		self.listeners.insert((address.to_string(), port), shutdown);
+
+		Ok(port)
+	}
+
+	/// Stop forwarding `address` and `port`, as asked by the client in
+	/// [`Handler::cancel_tcpip_forward`](super::Handler::cancel_tcpip_forward),
+	/// closing its connections. Return whether it was forwarded.
+	pub fn cancel(&mut self, address:&str, port:u32) -> bool {
+		self.listeners.remove(&(address.to_string(), port)).is_some()
+	}
+
+	/// The forwarded addresses and ports.
+	pub fn forwarded(&self) -> impl Iterator<Item = (&str, u32)> {
+		self.listeners.keys().map(|(address, port)| (address.as_str(), *port))
+	}
+}
+
+async fn accept_loop(
+	listener:TcpListener,
+	handle:Handle,
+	address:String,
+	port:u32,
+	mut stopped:watch::Receiver<()>,
+) {
+	loop {
+		let (stream, originator) = tokio::select! {
+			accepted = listener.accept() => {
+				match accepted {
+					Ok(accepted) => accepted,
+					Err(e) => {
+						debug!("forwarding: accept failed: {:?}", e);
+
+						continue;
+					},
+				}
+			},
+			// The sender is dropped.
+			_ = stopped.changed() => return,
+		};
+
+		tokio::spawn(forward_connection(
+			stream,
+			originator,
+			handle.clone(),
+			address.clone(),
+			port,
+			stopped.clone(),
+		));
+	}
+}
+
+async fn forward_connection(
+	mut stream:TcpStream,
+	originator:SocketAddr,
+	handle:Handle,
+	address:String,
+	port:u32,
+	mut stopped:watch::Receiver<()>,
+) {
+	let open = handle.channel_open_forwarded_tcpip(
+		address,
+		port,
+		originator.ip().to_string(),
+		originator.port() as u32,
+	);
+
+	let channel = tokio::select! {
+		channel = open => channel,
+		_ = stopped.changed() => return,
+	};
+
+	// Dropping the stream closes the connection.
+	let channel = match channel {
+		Ok(channel) => channel,
+		Err(e) => {
+			debug!("forwarding {:?}: {:?}", originator, e);
+
+			return;
+		},
+	};
+
+	let mut channel = channel.into_stream();
+
+	tokio::select! {
+		result = tokio::io::copy_bidirectional(&mut stream, &mut channel) => {
+			debug!("forwarding {:?} done: {:?}", originator, result);
+		},
+		_ = stopped.changed() => {},
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_tcpip_forwards() {
+		use std::time::Duration;
+
+		use tokio::net::TcpStream;
+
+		use crate::server::forward::TcpipForwards;
+
+		struct Client {
+			forwarded:UnboundedSender<(u32, Channel<client::Msg>)>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn server_channel_open_forwarded_tcpip(
+				self,
+				channel:Channel<client::Msg>,
+				_:&str,
+				connected_port:u32,
+				_:&str,
+				_:u32,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.forwarded.send((connected_port, channel)).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			forwards:TcpipForwards,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn tcpip_forward(
+				mut self,
+				address:&str,
+				port:&mut u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				match self.forwards.forward(session.handle(), address, *port).await {
+					Ok(bound) => {
+						*port = bound;
+
+						Ok((self, true, session))
+					},
+					Err(_) => Ok((self, false, session)),
+				}
+			}
+
+			async fn cancel_tcpip_forward(
+				mut self,
+				address:&str,
+				port:u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let cancelled = self.forwards.cancel(address, port);
+
+				Ok((self, cancelled, session))
+			}
+		}
+
+		let (forwarded, mut connections) = unbounded_channel();
+
+		test_session(
+			Client { forwarded },
+			ServerHandle { forwards:TcpipForwards::new() },
+			|client| {
+				async move {
+					let first = client.tcpip_forward("127.0.0.1", 0).await.unwrap();
+
+					let second = client.tcpip_forward("127.0.0.1", 0).await.unwrap();
+
+					assert!(first != 0 && second != 0 && first != second);
+
+					// A connection to each port reaches the client.
+					for &port in &[first, second] {
+						let mut stream =
+							TcpStream::connect(("127.0.0.1", port as u16)).await.unwrap();
+
+						stream.write_all(b"ping").await.unwrap();
+
+						let (connected_port, channel) = connections.recv().await.unwrap();
+
+						assert_eq!(connected_port, port);
+
+						let mut channel = channel.into_stream();
+
+						let mut ping = [0; 4];
+
+						channel.read_exact(&mut ping).await.unwrap();
+
+						assert_eq!(&ping, b"ping");
+
+						channel.write_all(b"pong").await.unwrap();
+
+						let mut pong = [0; 4];
+
+						stream.read_exact(&mut pong).await.unwrap();
+
+						assert_eq!(&pong, b"pong");
+					}
+
+					client.cancel_tcpip_forward("127.0.0.1", first).await.unwrap();
+
+					assert!(client.cancel_tcpip_forward("127.0.0.1", first).await.is_err());
+
+					// The first listener stops, the second one keeps going.
+					let mut refused = false;
+
+					for _ in 0..100 {
+						if TcpStream::connect(("127.0.0.1", first as u16)).await.is_err() {
+							refused = true;
+
+							break;
+						}
+
+						tokio::time::sleep(Duration::from_millis(10)).await;
+					}
+
+					assert!(refused);
+
+					let _stream = TcpStream::connect(("127.0.0.1", second as u16)).await.unwrap();
+
+					// Skipping the connections that raced with the cancellation.
+					while connections.recv().await.unwrap().0 != second {}
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	/// `ssh -R 0:localhost:port` against a russh server forwarding with
+	/// [`server::forward::TcpipForwards`], reading the allocated port from
+	/// ssh's output. Run with `cargo test -- --ignored`, with `ssh` in the
+	/// `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_tcpip_forwards_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{
+			io::{AsyncBufReadExt, BufReader},
+			net::{TcpListener, TcpStream},
+			process::Command,
+		};
+
+		use crate::server::forward::TcpipForwards;
+
+		struct ServerHandle {
+			forwards:TcpipForwards,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn tcpip_forward(
+				mut self,
+				address:&str,
+				port:&mut u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				match self.forwards.forward(session.handle(), address, *port).await {
+					Ok(bound) => {
+						*port = bound;
+
+						Ok((self, true, session))
+					},
+					Err(_) => Ok((self, false, session)),
+				}
+			}
+		}
+
+		// The destination of the forwarding, on the client's side.
+		let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let target_port = target.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (mut stream, _) = target.accept().await.unwrap();
+
+			let mut hello = [0; 5];
+
+			stream.read_exact(&mut hello).await.unwrap();
+
+			assert_eq!(&hello, b"hello");
+
+			stream.write_all(b"world").await.unwrap();
+		});
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			let handler = ServerHandle { forwards:TcpipForwards::new() };
+
+			server::run_stream(Arc::new(config), stream, handler).await.unwrap().await.ok();
+		});
+
+		let mut ssh = Command::new("ssh")
+			.args(["-N", "-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.args(["-o", "ExitOnForwardFailure=yes"])
+			.args(["-R", &format!("0:localhost:{}", target_port)])
+			.arg("user@127.0.0.1")
+			.stdin(std::process::Stdio::null())
+			.stderr(std::process::Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		let mut lines = BufReader::new(ssh.stderr.take().unwrap()).lines();
+
+		// "Allocated port 12345 for remote forward to localhost:80"
+		let allocated = tokio::time::timeout(Duration::from_secs(30), async {
+			while let Some(line) = lines.next_line().await.unwrap() {
+				if let Some(rest) = line.strip_prefix("Allocated port ") {
+					return rest.split(' ').next().and_then(|p| p.parse::<u16>().ok());
+				}
+			}
+
+			None
+		})
+		.await
+		.unwrap()
+		.unwrap();
+
+		let mut stream = TcpStream::connect(("127.0.0.1", allocated)).await.unwrap();
+
+		stream.write_all(b"hello").await.unwrap();
+
+		let mut world = [0; 5];
+
+		tokio::time::timeout(Duration::from_secs(30), stream.read_exact(&mut world))
+			.await
+			.unwrap()
+			.unwrap();
+
+		assert_eq!(&world, b"world");
+
+		ssh.kill().await.ok();
+	}
+}
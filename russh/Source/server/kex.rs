@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::{borrow::Cow, cell::RefCell};
 
 use log::debug;
 use russh_keys::encoding::{Encoding, Reader};
@@ -23,31 +23,41 @@ impl KexInit {
 				// read algorithms from packet.
 				self.exchange.client_kex_init.extend(buf);
 
-				super::negotiation::Server::read_kex(buf, &config.preferred)?
+				super::negotiation::Server::read_kex_with_host_keys(
+					buf,
+					&config.preferred,
+					&config.host_key_algorithms(),
+					self.none_cipher,
+					&config.ciphers,
+				)?
 			};
 
 			if !self.sent {
 				self.server_write(config, cipher, write_buffer)?
 			}
 
-			let mut key = 0;
-			#[allow(clippy::indexing_slicing)] // length checked
-			while key < config.keys.len() && config.keys[key].name() != algo.key.as_ref() {
-				key += 1
-			}
-
-			let next_kex = if key < config.keys.len() {
-				Kex::Dh(KexDh {
-					exchange:self.exchange,
-					key,
-					names:algo,
-					session_id:self.session_id,
-				})
-			} else {
-				return Err(Error::UnknownKey);
+			let signer = || {
+				config
+					.host_key_signers
+					.iter()
+					.position(|signer| signer.algorithms().contains(&algo.key))
+					.map(|signer| config.keys.len() + signer)
 			};
 
-			Ok(next_kex)
+			let key = config
+				.keys
+				.iter()
+				.position(|key| negotiation::host_key_algorithms(key).contains(&algo.key))
+				.or_else(signer)
+				.ok_or(Error::UnknownKey)?;
+
+			Ok(Kex::Dh(KexDh {
+				exchange:self.exchange,
+				key,
+				names:algo,
+				session_id:self.session_id,
+				kex:None,
+			}))
 		} else {
 			Ok(Kex::Init(self))
 		}
@@ -61,23 +71,31 @@ impl KexInit {
 	) -> Result<(), Error> {
 		self.exchange.server_kex_init.clear();
 
-		negotiation::write_kex(&config.preferred, &mut self.exchange.server_kex_init, true)?;
+		negotiation::write_kex_with_host_keys(
+			&config.preferred,
+			&config.host_key_algorithms(),
+			self.none_cipher,
+			&mut self.exchange.server_kex_init,
+			true,
+			// Only clients send the first key exchange packet.
+			false,
+		)?;
 
 		debug!("server kex init: {:?}", &self.exchange.server_kex_init[..]);
 
 		self.sent = true;
 
-		cipher.write(&self.exchange.server_kex_init, write_buffer);
+		cipher.write(&self.exchange.server_kex_init, write_buffer)?;
 
 		Ok(())
 	}
 }
 
 impl KexDh {
-	pub fn parse(
+	pub async fn parse(
 		mut self,
 		config:&Config,
-		cipher:&mut dyn SealingKey,
+		cipher:&mut (dyn SealingKey + Send),
 		buf:&[u8],
 		write_buffer:&mut SSHBuffer,
 	) -> Result<Kex, Error> {
@@ -88,14 +106,33 @@ impl KexDh {
 			Ok(Kex::Dh(self))
 		} else {
 			// Else, process it.
-			assert!(buf.first() == Some(&msg::KEX_ECDH_INIT));
+			let mut kex = if let Some(kex) = self.kex.take() {
+				kex
+			} else {
+				KEXES.get(&self.names.kex).ok_or(Error::UnknownAlgo)?.make()
+			};
+
+			if kex.is_group_exchange() && buf.first() == Some(&msg::KEX_DH_GEX_REQUEST) {
+				let mut group = CryptoVec::new();
+
+				kex.server_gex_group(buf, &mut group)?;
+
+				cipher.write(&group, write_buffer)?;
+
+				self.kex = Some(kex);
+
+				return Ok(Kex::Dh(self));
+			}
+
+			assert!(
+				buf.first() == Some(&msg::KEX_ECDH_INIT)
+					|| buf.first() == Some(&msg::KEX_DH_GEX_INIT)
+			);
 
 			let mut r = buf.reader(1);
 
 			self.exchange.client_ephemeral.extend(r.read_string()?);
 
-			let mut kex = KEXES.get(&self.names.kex).ok_or(Error::UnknownAlgo)?.make();
-
 			kex.server_dh(&mut self.exchange, buf)?;
 
 			// Then, we fill the write buffer right away, so that we
@@ -107,50 +144,121 @@ impl KexDh {
 				names:self.names,
 				session_id:self.session_id,
 			};
-			#[allow(clippy::indexing_slicing)] // key index checked
-			let hash:Result<_, Error> = HASH_BUF.with(|buffer| {
+
+			let key =
+				host_key(config, kexdhdone.key, kexdhdone.names.key).ok_or(Error::UnknownKey)?;
+
+			let mut pubkey_vec = CryptoVec::new();
+
+			key.push_to(&mut pubkey_vec);
+
+			let hash = HASH_BUF.with(|buffer| {
 				let mut buffer = buffer.borrow_mut();
 
 				buffer.clear();
 
 				debug!("server kexdhdone.exchange = {:?}", kexdhdone.exchange);
 
-				let mut pubkey_vec = CryptoVec::new();
+				kexdhdone.kex.compute_exchange_hash(&pubkey_vec, &kexdhdone.exchange, &mut buffer)
+			})?;
 
-				config.keys[kexdhdone.key].push_to(&mut pubkey_vec);
+			debug!("exchange hash: {:?}", hash);
 
-				let hash = kexdhdone.kex.compute_exchange_hash(
-					&pubkey_vec,
-					&kexdhdone.exchange,
-					&mut buffer,
-				)?;
+			// Hash signature
+			debug!("signing with key {:?}", kexdhdone.key);
 
-				debug!("exchange hash: {:?}", hash);
+			let mut signature = CryptoVec::new();
+
+			key.sign(kexdhdone.names.key, &hash, &mut signature).await?;
+
+			HASH_BUF.with(|buffer| -> Result<(), Error> {
+				let mut buffer = buffer.borrow_mut();
 
 				buffer.clear();
 
-				buffer.push(msg::KEX_ECDH_REPLY);
+				buffer.push(if kexdhdone.kex.is_group_exchange() {
+					msg::KEX_DH_GEX_REPLY
+				} else {
+					msg::KEX_ECDH_REPLY
+				});
 
-				config.keys[kexdhdone.key].push_to(&mut buffer);
+				buffer.extend(&pubkey_vec);
 				// Server ephemeral
 				buffer.extend_ssh_string(&kexdhdone.exchange.server_ephemeral);
-				// Hash signature
-				debug!("signing with key {:?}", kexdhdone.key);
 
-				debug!("hash: {:?}", hash);
+				buffer.extend(&signature);
 
-				debug!("key: {:?}", config.keys[kexdhdone.key]);
+				cipher.write(&buffer, write_buffer)?;
 
-				config.keys[kexdhdone.key].add_signature(&mut buffer, &hash)?;
+				cipher.write(&[msg::NEWKEYS], write_buffer)?;
 
-				cipher.write(&buffer, write_buffer);
+				Ok(())
+			})?;
 
-				cipher.write(&[msg::NEWKEYS], write_buffer);
+			Ok(Kex::Keys(kexdhdone.compute_keys(hash, &config.ciphers, &config.macs, true)?))
+		}
+	}
+}
 
-				Ok(hash)
-			});
+/// A host key of the server, with its secret key or a signer.
+enum HostKey<'a> {
+	Pair(Cow<'a, key::KeyPair>),
+	Signer(&'a dyn auth::Signer),
+}
 
-			Ok(Kex::Keys(kexdhdone.compute_keys(hash?, true)?))
+impl HostKey<'_> {
+	fn push_to(&self, buffer:&mut CryptoVec) {
+		match self {
+			HostKey::Pair(key) => key.push_to(buffer),
+			HostKey::Signer(signer) => signer.public_key().push_to(buffer),
 		}
 	}
+
+	/// Append the signature of `hash` with `algorithm` to `buffer`. The
+	/// signers of different connections sign concurrently.
+	async fn sign(
+		&self,
+		algorithm:key::Name,
+		hash:&[u8],
+		buffer:&mut CryptoVec,
+	) -> Result<(), Error> {
+		match self {
+			HostKey::Pair(key) => key.add_signature(buffer, hash)?,
+			HostKey::Signer(signer) => {
+				let signature = signer.sign(algorithm, hash).await.map_err(Error::Signer)?;
+
+				buffer.extend_ssh_string(&signature.to_bytes());
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// The host key at `index` in [`Config::keys`] followed by
+/// [`Config::host_key_signers`], to sign with `algorithm`.
+fn host_key(config:&Config, index:usize, algorithm:key::Name) -> Option<HostKey<'_>> {
+	if let Some(key) = config.keys.get(index) {
+		return Some(HostKey::Pair(signing_key(key, algorithm)));
+	}
+
+	let signer = config.host_key_signers.get(index - config.keys.len())?;
+
+	Some(HostKey::Signer(signer.as_ref()))
 }
+
+/// `key`, signing with the negotiated host key `algorithm`: RSA keys sign
+/// with the hash it names.
+#[cfg(feature = "openssl")]
+fn signing_key(key:&key::KeyPair, algorithm:key::Name) -> Cow<'_, key::KeyPair> {
+	let rsa = key::SignatureHash::from_rsa_hostkey_algo(algorithm.0.as_bytes())
+		.and_then(|hash| key.with_signature_hash(hash));
+
+	match rsa {
+		Some(key) => Cow::Owned(key),
+		None => Cow::Borrowed(key),
+	}
+}
+
+#[cfg(not(feature = "openssl"))]
+fn signing_key(key:&key::KeyPair, _:key::Name) -> Cow<'_, key::KeyPair> { Cow::Borrowed(key) }
@@ -133,7 +133,8 @@
 
 use std::{
 	self,
-	collections::HashMap,
+	collections::{HashMap, VecDeque},
+	num::Wrapping,
 	pin::Pin,
 	sync::Arc,
 	task::{Context, Poll},
@@ -141,12 +142,13 @@ use std::{
 
 use async_trait::async_trait;
 use futures::future::Future;
-use log::{error, info};
-use russh_keys::key;
+use log::{debug, error, info};
+use russh_keys::{certificate::Certificate, key};
 use tokio::{
 	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
 	net::{TcpListener, ToSocketAddrs},
 	pin,
+	sync::{OwnedSemaphorePermit, Semaphore},
 	task::JoinHandle,
 };
 
@@ -154,7 +156,9 @@ use crate::{
 	cipher::{CipherPair, OpeningKey, clear},
 	session::*,
 	ssh_read::*,
+	negotiation::Named,
 	sshbuffer::*,
+	stats::Stats,
 	*,
 };
 
@@ -162,41 +166,195 @@ mod kex;
 mod session;
 pub use self::{kex::*, session::*};
 mod encrypted;
+pub mod forward;
 
 #[derive(Debug)]
 /// Configuration of a server.
 pub struct Config {
-	/// The server ID string sent at the beginning of the protocol.
+	/// The server ID string sent at the beginning of the protocol. It must
+	/// start with `SSH-2.0-`, followed by a software version without
+	/// spaces or `-`, optionally a space and a comment, and fit in 255
+	/// bytes with its line ending, or sessions fail with
+	/// [`crate::Error::InvalidSshId`].
 	pub server_id:SshId,
-	/// Authentication methods proposed to the client.
+	/// The lines the client may send before its identification string,
+	/// after which the connection fails with
+	/// [`crate::Error::TooManyPreambleLines`].
+	pub max_preamble_lines:usize,
+	/// The bytes of the lines the client may send before its identification
+	/// string, after which the connection fails with
+	/// [`crate::Error::PreambleTooLong`].
+	pub max_preamble_bytes:usize,
+	/// Authentication methods proposed to the client. All but
+	/// `keyboard-interactive` by default: add
+	/// [`MethodSet::KEYBOARD_INTERACTIVE`](auth::MethodSet::KEYBOARD_INTERACTIVE)
+	/// when implementing [`Handler::auth_keyboard_interactive`]. Requests
+	/// with other methods are rejected without calling their handler,
+	/// and [`Handler::auth_methods_for_user`] may choose others for each
+	/// user.
 	pub methods:auth::MethodSet,
 	/// The authentication banner, usually a warning message shown to the
-	/// client.
-	pub auth_banner:Option<&'static str>,
-	/// Authentication rejections must happen in constant time for
-	/// security reasons. Russh does not handle this by default.
+	/// client, sent before the reply to its first authentication request.
+	pub auth_banner:Option<String>,
+	/// The time each rejected authentication request takes, from its
+	/// reception to the rejection, so that rejections do not reveal why
+	/// they happen, and brute force attempts are slowed down.
 	pub auth_rejection_time:std::time::Duration,
 	/// Authentication rejection time override for the initial "none" auth
 	/// attempt. OpenSSH clients will send an initial "none" auth to probe for
 	/// authentication methods.
 	pub auth_rejection_time_initial:Option<std::time::Duration>,
-	/// The server's keys. The first key pair in the client's preference order
-	/// will be chosen.
+	/// The server's host keys, at most one of each type. The server offers
+	/// every algorithm of [`Config::preferred`] one of them can sign with,
+	/// all the RSA signature hashes for an RSA key, and signs with the key
+	/// of the algorithm negotiated.
 	pub keys:Vec<key::KeyPair>,
-	/// The bytes and time limits before key re-exchange.
+	/// Host keys whose secret keys russh never sees, for instance in an
+	/// SSH agent or an HSM, next to [`Config::keys`] and at most one of
+	/// each type. Each connection asks the signer to sign its key
+	/// exchange in its own task, so a slow signer only delays that
+	/// handshake, and a failure of the signer fails it with
+	/// [`crate::Error::Signer`]. Signers are called concurrently by the
+	/// connections: an [`AgentSigner`](auth::AgentSigner) signs one at a
+	/// time on its agent connection. These keys are not announced with
+	/// [`Config::announce_host_keys`].
+	pub host_key_signers:Vec<Arc<dyn auth::Signer>>,
+	/// The bytes and time limits before key re-exchange, which starts
+	/// automatically once one of them is reached.
 	pub limits:Limits,
-	/// The initial size of a channel (used for flow control).
+	/// The initial size of a channel (used for flow control), unless the
+	/// channel is opened with its own [`WindowParams`].
 	pub window_size:u32,
-	/// The maximal size of a single packet.
+	/// The maximal size of the data of a packet sent to us on a channel.
+	/// It must be at least [`WindowParams::MIN_PACKET_SIZE`], and fit in
+	/// [`Config::max_packet_size`].
 	pub maximum_packet_size:u32,
+	/// The bytes of a channel that [`Handle::data`] may leave queued,
+	/// waiting for the peer's window, before waiting itself.
+	pub channel_buffer_size:usize,
 	/// Internal event buffer size
 	pub event_buffer_size:usize,
 	/// Lists of preferred algorithms.
 	pub preferred:Preferred,
-	/// Maximal number of allowed authentication attempts.
+	/// The zlib level, from 0 to 9, of the packets sent when `zlib` or
+	/// `zlib@openssh.com` is negotiated, see [`Preferred::COMPRESSED`].
+	pub compression_level:u32,
+	/// If set, each packet sent gets a random number of extra blocks of
+	/// padding in this range, on top of the minimum, so that the lengths
+	/// of packets do not reveal those of keystrokes or commands. Fewer
+	/// are added when the padding would exceed the 255 bytes allowed, or
+	/// the packet the 35000 bytes every peer must accept. The padding is
+	/// then random, whatever the cipher. Off by default.
+	pub random_padding:Option<std::ops::RangeInclusive<usize>>,
+	/// The authentication requests a client may see rejected, not
+	/// counting the "none" method, before the connection is closed with
+	/// [`Disconnect::NoMoreAuthMethodsAvailable`], as OpenSSH's
+	/// `MaxAuthTries`.
 	pub max_auth_attempts:usize,
+	/// The channels a client may have open at once, after which opening
+	/// one fails with [`ChannelOpenFailure::ResourceShortage`], without
+	/// calling the [`Handler`]. Channels opened by the server count too.
+	/// No limit by default.
+	pub max_channels_per_connection:Option<usize>,
+	/// Whether [`run`] and [`start`] set `TCP_NODELAY` on the connections
+	/// they accept, sending small packets such as keystrokes at once
+	/// instead of waiting to coalesce them.
+	pub nodelay:bool,
+	/// The connections [`run`] and [`start`] keep open at once, after
+	/// which new ones are closed as soon as accepted. No limit by default.
+	pub max_connections:Option<usize>,
+	/// The connections [`run`] and [`start`] keep open at once before
+	/// they are authenticated, as OpenSSH's `MaxStartups`, after which new
+	/// ones are closed as soon as accepted. No limit by default.
+	pub max_unauthenticated_connections:Option<usize>,
 	/// Time after which the connection is garbage-collected.
 	pub connection_timeout:Option<std::time::Duration>,
+	/// If set, the connection is closed with a
+	/// [`Disconnect::ByApplication`] message when nothing was received
+	/// from the client for this long, and the session ends with
+	/// [`crate::Error::InactivityTimeout`]. Any packet counts, including
+	/// replies to keepalives.
+	pub inactivity_timeout:Option<std::time::Duration>,
+	/// If set, the time the client has from connecting to being
+	/// authenticated, as OpenSSH's `LoginGraceTime`, after which the
+	/// connection is closed with a
+	/// [`Disconnect::NoMoreAuthMethodsAvailable`] message and the session
+	/// ends with [`crate::Error::AuthTimeout`].
+	pub auth_timeout:Option<std::time::Duration>,
+	/// If set, a `keepalive@openssh.com` global request is sent when
+	/// nothing was received from the client for this long, as OpenSSH's
+	/// `ClientAliveInterval`.
+	pub keepalive_interval:Option<std::time::Duration>,
+	/// How many keepalives may go unanswered before the connection is
+	/// closed with [`crate::Error::KeepaliveTimeout`], as OpenSSH's
+	/// `ClientAliveCountMax`.
+	pub keepalive_max:u32,
+	/// The ciphers available for negotiation.
+	pub ciphers:cipher::CipherRegistry,
+	/// The MACs available for negotiation.
+	pub macs:mac::MacRegistry,
+	/// The maximal length of an incoming packet. Larger packets are
+	/// rejected before being read.
+	pub max_packet_size:usize,
+	/// Whether authenticated clients may switch to the `none` cipher, if
+	/// [`Handler::allow_none_cipher`] agrees. Only use this on trusted
+	/// networks.
+	pub allow_none_cipher_after_auth:bool,
+	/// Whether to announce all of [`Config::keys`] to authenticated
+	/// clients with `hostkeys-00@openssh.com`, so that they can learn new
+	/// host keys before the old ones are retired (OpenSSH's
+	/// `UpdateHostKeys`). Requests to prove possession of these keys are
+	/// always answered.
+	pub announce_host_keys:bool,
+}
+
+impl Config {
+	/// The window parameters of channels opened with this configuration.
+	pub fn window_params(&self) -> WindowParams {
+		WindowParams {
+			window_size:self.window_size,
+			maximum_packet_size:self.maximum_packet_size,
+		}
+	}
+
+	/// Check the window parameters, and that [`Config::keys`] and
+	/// [`Config::host_key_signers`] have at most one key of each type.
+	pub(crate) fn check(&self) -> Result<(), Error> {
+		self.window_params().check(self.max_packet_size)?;
+
+		let types = self
+			.keys
+			.iter()
+			.filter_map(|key| negotiation::host_key_algorithms(key).first().map(|name| name.0))
+			.chain(self.host_key_signers.iter().map(|signer| Named::name(&signer.public_key())))
+			.collect::<Vec<_>>();
+
+		for (i, key_type) in types.iter().enumerate() {
+			if types.iter().skip(i + 1).any(|other| other == key_type) {
+				return Err(Error::DuplicateHostKey(key_type));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The host key algorithms of [`Config::preferred`] one of
+	/// [`Config::keys`] or [`Config::host_key_signers`] can sign with, in
+	/// preference order.
+	pub(crate) fn host_key_algorithms(&self) -> Vec<key::Name> {
+		let signers =
+			self.host_key_signers.iter().map(|signer| signer.algorithms()).collect::<Vec<_>>();
+
+		self.preferred
+			.key
+			.iter()
+			.copied()
+			.filter(|name| {
+				self.keys.iter().any(|key| negotiation::host_key_algorithms(key).contains(name))
+					|| signers.iter().any(|algorithms| algorithms.contains(name))
+			})
+			.collect()
+	}
 }
 
 impl Default for Config {
@@ -207,18 +365,37 @@ impl Default for Config {
 				env!("CARGO_PKG_NAME"),
 				env!("CARGO_PKG_VERSION")
 			)),
-			methods:auth::MethodSet::all(),
+			max_preamble_lines:1024,
+			max_preamble_bytes:65536,
+			methods:auth::MethodSet::all() - auth::MethodSet::KEYBOARD_INTERACTIVE,
 			auth_banner:None,
 			auth_rejection_time:std::time::Duration::from_secs(1),
 			auth_rejection_time_initial:None,
 			keys:Vec::new(),
+			host_key_signers:Vec::new(),
 			window_size:2097152,
 			maximum_packet_size:32768,
+			channel_buffer_size:65536,
 			event_buffer_size:10,
 			limits:Limits::default(),
 			preferred:Default::default(),
+			compression_level:crate::compression::DEFAULT_LEVEL,
+			random_padding:None,
 			max_auth_attempts:10,
+			max_channels_per_connection:None,
+			nodelay:false,
+			max_connections:None,
+			max_unauthenticated_connections:None,
 			connection_timeout:Some(std::time::Duration::from_secs(600)),
+			inactivity_timeout:None,
+			auth_timeout:None,
+			keepalive_interval:None,
+			keepalive_max:3,
+			ciphers:Default::default(),
+			macs:Default::default(),
+			max_packet_size:cipher::MAXIMUM_PACKET_LEN,
+			allow_none_cipher_after_auth:false,
+			announce_host_keys:false,
 		}
 	}
 }
@@ -258,6 +435,19 @@ pub enum Auth {
 	/// Method was not accepted, but no other check was performed.
 	UnsupportedMethod,
 
+	/// Accept this method as one step of a multi-step authentication:
+	/// the client must still authenticate with one of
+	/// `proceed_with_methods`.
+	PartialSuccess { proceed_with_methods:MethodSet },
+
+	/// Ask the client to change its expired password, see
+	/// [`Handler::auth_password_change`]. Only valid for the "password"
+	/// method.
+	PasswordChange {
+		/// Message to the user.
+		prompt:Cow<'static, str>,
+	},
+
 	/// Partially accept the challenge-response authentication
 	/// request, providing more instructions for the client to follow.
 	Partial {
@@ -280,6 +470,20 @@ pub enum Auth {
 pub trait Handler: Sized {
 	type Error: From<crate::Error> + Send;
 
+	/// The authentication methods offered to `user`, asked when the
+	/// client first names this user. Requests with other methods are
+	/// rejected without calling their handler, and rejections list these
+	/// methods, including those of "none" requests probing them. The
+	/// default is `methods`, which is [`Config::methods`].
+	#[allow(unused_variables)]
+	async fn auth_methods_for_user(
+		self,
+		user:&str,
+		methods:MethodSet,
+	) -> Result<(Self, MethodSet), Self::Error> {
+		Ok((self, methods))
+	}
+
 	/// Check authentication using the "none" method. Russh makes
 	/// sure rejection happens in time `config.auth_rejection_time`,
 	/// except if this method takes more than that.
@@ -297,13 +501,47 @@ pub trait Handler: Sized {
 		Ok((self, Auth::Reject { proceed_with_methods:None }))
 	}
 
-	/// Check authentication using the "publickey" method. This method
-	/// should just check whether the public key matches the
-	/// authorized ones. Russh then checks the signature. If the key
-	/// is unknown, or the signature is invalid, Russh guarantees
-	/// that rejection happens in constant time
-	/// `config.auth_rejection_time`, except if this method takes more
-	/// time than that.
+	/// Check authentication with a new password, when the client changes
+	/// its password after [`Auth::PasswordChange`]. Return
+	/// [`Auth::PasswordChange`] again to refuse `new_password`. Russh
+	/// makes sure rejection happens in time `config.auth_rejection_time`,
+	/// except if this method takes more than that.
+	#[allow(unused_variables)]
+	async fn auth_password_change(
+		self,
+		user:&str,
+		password:&str,
+		new_password:&str,
+	) -> Result<(Self, Auth), Self::Error> {
+		Ok((self, Auth::Reject { proceed_with_methods:None }))
+	}
+
+	/// Called when the client offers a key for the "publickey" method,
+	/// either in a query without a signature, which is answered with
+	/// `SSH_MSG_USERAUTH_PK_OK` if this returns [`Auth::Accept`], or
+	/// before the signature of a request is checked. Returning
+	/// [`Auth::Reject`] here rejects the key early, before the client
+	/// signs anything. For certificates, `public_key` is the certified
+	/// key. Accepting the offer does not authenticate the client: this
+	/// is left to [`Handler::auth_publickey`]. The default implementation
+	/// accepts all offers.
+	#[allow(unused_variables)]
+	async fn auth_publickey_offered(
+		self,
+		user:&str,
+		public_key:&key::PublicKey,
+	) -> Result<(Self, Auth), Self::Error> {
+		Ok((self, Auth::Accept))
+	}
+
+	/// Check authentication using the "publickey" method. This method is
+	/// only called once Russh has checked the signature of the request,
+	/// made with the algorithm named in the request (such as
+	/// `rsa-sha2-256` for an RSA key), and should just check whether the
+	/// public key matches the authorized ones. If the key is unknown, or
+	/// the signature is invalid, Russh guarantees that rejection happens
+	/// in constant time `config.auth_rejection_time`, except if this
+	/// method takes more time than that.
 	#[allow(unused_variables)]
 	async fn auth_publickey(
 		self,
@@ -313,8 +551,42 @@ pub trait Handler: Sized {
 		Ok((self, Auth::Reject { proceed_with_methods:None }))
 	}
 
+	/// Check authentication using the "publickey" method with an
+	/// OpenSSH certificate. [`Certificate::validate`] checks it against
+	/// the trusted CA keys. As with [`Handler::auth_publickey`], this is
+	/// only called once Russh has checked the signature, made with the
+	/// certified key.
+	#[allow(unused_variables)]
+	async fn auth_openssh_certificate(
+		self,
+		user:&str,
+		certificate:&Certificate,
+	) -> Result<(Self, Auth), Self::Error> {
+		Ok((self, Auth::Reject { proceed_with_methods:None }))
+	}
+
+	/// Called when a FIDO security key (such as `sk-ssh-ed25519@openssh.com`)
+	/// signed without the user touching it, once the signature was
+	/// checked and before [`Handler::auth_publickey`] is called. Return
+	/// `true` to accept the signature anyway, like the
+	/// `no-touch-required` option of OpenSSH's `authorized_keys`. The
+	/// default implementation requires a touch.
+	#[allow(unused_variables)]
+	async fn auth_sk_no_touch_required(
+		self,
+		user:&str,
+		public_key:&key::PublicKey,
+	) -> Result<(Self, bool), Self::Error> {
+		Ok((self, false))
+	}
+
 	/// Check authentication using the "keyboard-interactive"
-	/// method. Russh makes sure rejection happens in time
+	/// method. This method is first called with no `response`: return
+	/// [`Auth::Partial`] to send prompts to the client, and it is called
+	/// again with the client's answers, once per prompt, for as many
+	/// rounds as needed. A round without prompts only shows its
+	/// instructions. Clients only try this method if it is in
+	/// [`Config::methods`]. Russh makes sure rejection happens in time
 	/// `config.auth_rejection_time`, except if this method takes more
 	/// than that.
 	#[allow(unused_variables)]
@@ -327,12 +599,40 @@ pub trait Handler: Sized {
 		Ok((self, Auth::Reject { proceed_with_methods:None }))
 	}
 
+	/// Check authentication using the "hostbased" method: `key` is
+	/// presented as the host key of the client host `client_hostname`,
+	/// where the user is `client_user`. Russh has checked the
+	/// signature, and `signature_valid` tells whether it is valid; the
+	/// request is rejected when it isn't, whatever this method returns.
+	/// Russh makes sure rejection happens in time
+	/// `config.auth_rejection_time`, except if this method takes more
+	/// than that.
+	#[allow(unused_variables)]
+	async fn auth_hostbased(
+		self,
+		user:&str,
+		key:&key::PublicKey,
+		client_hostname:&str,
+		client_user:&str,
+		signature_valid:bool,
+	) -> Result<(Self, Auth), Self::Error> {
+		Ok((self, Auth::Reject { proceed_with_methods:None }))
+	}
+
 	/// Called when authentication succeeds for a session.
 	#[allow(unused_variables)]
 	async fn auth_succeeded(self, session:Session) -> Result<(Self, Session), Self::Error> {
 		Ok((self, session))
 	}
 
+	/// Called once a client is authenticated, if
+	/// [`Config::allow_none_cipher_after_auth`] is set, to decide whether
+	/// this client may switch the session to the `none` cipher.
+	#[allow(unused_variables)]
+	async fn allow_none_cipher(self, user:&str) -> Result<(Self, bool), Self::Error> {
+		Ok((self, true))
+	}
+
 	/// Called when the client closes a channel.
 	#[allow(unused_variables)]
 	async fn channel_close(
@@ -411,6 +711,19 @@ pub trait Handler: Sized {
 		Ok((self, false, session))
 	}
 
+	/// Called when the client asks to connect to the Unix domain socket at
+	/// `socket_path`, with a `direct-streamlocal@openssh.com` channel, as
+	/// OpenSSH's `-L` option with a socket path.
+	#[allow(unused_variables)]
+	async fn channel_open_direct_streamlocal(
+		self,
+		channel:Channel<Msg>,
+		socket_path:&str,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, false, session))
+	}
+
 	/// Called when the client confirmed our request to open a
 	/// channel. A channel can only be written to after receiving this
 	/// message (this library panics otherwise).
@@ -443,7 +756,7 @@ pub trait Handler: Sized {
 		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
 		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::Data { data:CryptoVec::from_slice(data) }).unwrap_or(())
+			chan.send(ChannelMsg::Data { data:crate::pool::from_slice(data) }).unwrap_or(())
 		}
 
 		Ok((self, session))
@@ -462,7 +775,7 @@ pub trait Handler: Sized {
 		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
 		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::ExtendedData { ext:code, data:CryptoVec::from_slice(data) })
+			chan.send(ChannelMsg::ExtendedData { ext:code, data:crate::pool::from_slice(data) })
 				.unwrap_or(())
 		}
 
@@ -470,18 +783,15 @@ pub trait Handler: Sized {
 	}
 
 	/// Called when the network window is adjusted, meaning that we
-	/// can send more bytes.
+	/// can send more bytes. The data waiting for the window was sent
+	/// first, and `new_size` is the window left.
 	#[allow(unused_variables)]
 	async fn window_adjusted(
 		self,
 		channel:ChannelId,
 		new_size:u32,
-		mut session:Session,
+		session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(ref mut enc) = session.common.encrypted {
-			enc.flush_pending(channel);
-		}
-
 		if let Some(chan) = session.channels.get(&channel) {
 			chan.send(ChannelMsg::WindowAdjusted { new_size }).unwrap_or(())
 		}
@@ -494,8 +804,26 @@ pub trait Handler: Sized {
 	#[allow(unused_variables)]
 	fn adjust_window(&mut self, channel:ChannelId, current:u32) -> u32 { current }
 
-	/// The client requests a pseudo-terminal with the given
-	/// specifications.
+	/// Called after each key re-exchange completes, with the algorithms
+	/// now in use. It is not called for the first key exchange.
+	#[allow(unused_variables)]
+	fn on_rekey(&mut self, negotiated:&crate::Negotiated) {}
+
+	/// Called once when the session ends: `reason` is `None` if either
+	/// side closed the connection cleanly,
+	/// [`crate::Error::Disconnected`] if the peer sent a disconnect
+	/// message, and the transport error otherwise. It is not called when
+	/// the session ends because a handler method returned an error.
+	#[allow(unused_variables)]
+	fn on_close(&mut self, reason:Option<&crate::Error>) {}
+
+	/// The client requests a pseudo-terminal for `term` (the client's
+	/// `TERM`), of the given size in characters and in pixels (zero if
+	/// unknown), with the terminal `modes` of RFC 4254, in the order the
+	/// client sent them. Opcodes this library does not know are skipped.
+	/// Answer as in [`Handler::shell_request`]. The default
+	/// implementation passes the request to the [`Channel`] of `channel`,
+	/// or refuses it if that channel was dropped.
 	#[allow(unused_variables, clippy::too_many_arguments)]
 	async fn pty_request(
 		self,
@@ -506,9 +834,9 @@ pub trait Handler: Sized {
 		pix_width:u32,
 		pix_height:u32,
 		modes:&[(Pty, u32)],
-		session:Session,
+		mut session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(chan) = session.channels.get(&channel) {
+		let passed = session.channels.get(&channel).map_or(false, |chan| {
 			chan.send(ChannelMsg::RequestPty {
 				want_reply:true,
 				term:term.into(),
@@ -518,7 +846,11 @@ pub trait Handler: Sized {
 				pix_height,
 				terminal_modes:modes.into(),
 			})
-			.unwrap_or(())
+			.is_ok()
+		});
+
+		if !passed {
+			session.channel_failure(channel)
 		}
 
 		Ok((self, session))
@@ -551,7 +883,9 @@ pub trait Handler: Sized {
 
 	/// The client wants to set the given environment variable. Check
 	/// these carefully, as it is dangerous to allow any variable
-	/// environment to be set.
+	/// environment to be set: returning `true` accepts the variable, for
+	/// instance if it is in an allowlist, as sshd's `AcceptEnv`. The
+	/// client is told whether the variable was accepted if it asked.
 	#[allow(unused_variables)]
 	async fn env_request(
 		self,
@@ -559,7 +893,7 @@ pub trait Handler: Sized {
 		variable_name:&str,
 		variable_value:&str,
 		session:Session,
-	) -> Result<(Self, Session), Self::Error> {
+	) -> Result<(Self, bool, Session), Self::Error> {
 		if let Some(chan) = session.channels.get(&channel) {
 			chan.send(ChannelMsg::SetEnv {
 				want_reply:true,
@@ -569,58 +903,77 @@ pub trait Handler: Sized {
 			.unwrap_or(())
 		}
 
-		Ok((self, session))
+		Ok((self, false, session))
 	}
 
-	/// The client requests a shell.
+	/// The client requests a shell. Answer with
+	/// [`Session::channel_success`] or [`Session::channel_failure`],
+	/// which the client may be waiting for, and finish with
+	/// [`Session::send_exit_and_close`]. The default implementation
+	/// passes the request to the [`Channel`] of `channel`, or refuses it
+	/// if that channel was dropped.
 	#[allow(unused_variables)]
 	async fn shell_request(
 		self,
 		channel:ChannelId,
-		session:Session,
+		mut session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::RequestShell { want_reply:true }).unwrap_or(())
+		let passed = session.channels.get(&channel).map_or(false, |chan| {
+			chan.send(ChannelMsg::RequestShell { want_reply:true }).is_ok()
+		});
+
+		if !passed {
+			session.channel_failure(channel)
 		}
 
 		Ok((self, session))
 	}
 
 	/// The client sends a command to execute, to be passed to a
-	/// shell. Make sure to check the command before doing so.
+	/// shell. Make sure to check the command before doing so, and
+	/// answer as in [`Handler::shell_request`].
 	#[allow(unused_variables)]
 	async fn exec_request(
 		self,
 		channel:ChannelId,
 		data:&[u8],
-		session:Session,
+		mut session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::Exec { want_reply:true, command:data.into() })
-				.unwrap_or(())
+		let passed = session.channels.get(&channel).map_or(false, |chan| {
+			chan.send(ChannelMsg::Exec { want_reply:true, command:data.into() }).is_ok()
+		});
+
+		if !passed {
+			session.channel_failure(channel)
 		}
 
 		Ok((self, session))
 	}
 
 	/// The client asks to start the subsystem with the given name
-	/// (such as sftp).
+	/// (such as sftp). Answer as in [`Handler::shell_request`].
 	#[allow(unused_variables)]
 	async fn subsystem_request(
 		self,
 		channel:ChannelId,
 		name:&str,
-		session:Session,
+		mut session:Session,
 	) -> Result<(Self, Session), Self::Error> {
-		if let Some(chan) = session.channels.get(&channel) {
-			chan.send(ChannelMsg::RequestSubsystem { want_reply:true, name:name.into() })
-				.unwrap_or(())
+		let passed = session.channels.get(&channel).map_or(false, |chan| {
+			chan.send(ChannelMsg::RequestSubsystem { want_reply:true, name:name.into() }).is_ok()
+		});
+
+		if !passed {
+			session.channel_failure(channel)
 		}
 
 		Ok((self, session))
 	}
 
-	/// The client's pseudo-terminal window size has changed.
+	/// The client's pseudo-terminal window size has changed, in characters
+	/// and in pixels (zero if unknown). Clients do not ask for a reply to
+	/// this request, but one that does gets it from
+	/// [`Session::channel_success`] or [`Session::channel_failure`].
 	#[allow(unused_variables)]
 	async fn window_change_request(
 		self,
@@ -639,7 +992,10 @@ pub trait Handler: Sized {
 		Ok((self, session))
 	}
 
-	/// The client requests OpenSSH agent forwarding
+	/// The client requests OpenSSH agent forwarding. Returning `true`
+	/// accepts it, after which agent channels can be opened toward the
+	/// client with [`Handle::channel_open_agent`], for instance one per
+	/// program wanting to use the agent.
 	#[allow(unused_variables)]
 	async fn agent_request(
 		self,
@@ -654,7 +1010,9 @@ pub trait Handler: Sized {
 	}
 
 	/// The client is sending a signal (usually to pass to the
-	/// currently running process).
+	/// currently running process). Signals not named in the RFC, such as
+	/// `WINCH`, are [`Sig::Custom`]. The client never waits for a reply to
+	/// this request.
 	#[allow(unused_variables)]
 	async fn signal(
 		self,
@@ -669,9 +1027,46 @@ pub trait Handler: Sized {
 		Ok((self, session))
 	}
 
+	/// The client sends a break of `break_length` milliseconds, as on a
+	/// serial line (RFC 4335), which serial console servers pass on.
+	/// Returning `true` tells the client the break was performed, if it
+	/// asked. The default implementation passes the break to the
+	/// [`Channel`] of `channel`, and refuses it.
+	#[allow(unused_variables)]
+	async fn break_request(
+		self,
+		channel:ChannelId,
+		break_length:u32,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		if let Some(chan) = session.channels.get(&channel) {
+			chan.send(ChannelMsg::Break { want_reply:true, break_length }).unwrap_or(())
+		}
+
+		Ok((self, false, session))
+	}
+
+	/// A channel request of a type russh does not know, `name`, with the
+	/// `data` following its `want_reply` byte. Returning `true` answers
+	/// with success if the client asked for a reply. The default refuses
+	/// it.
+	#[allow(unused_variables)]
+	async fn channel_request_unknown(
+		self,
+		channel:ChannelId,
+		name:&str,
+		data:&[u8],
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, false, session))
+	}
+
 	/// Used for reverse-forwarding ports, see
 	/// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7).
-	/// If `port` is 0, you should set it to the allocated port number.
+	/// If `port` is 0, you should set it to the allocated port number,
+	/// which is sent to the client. Each connection is then forwarded on
+	/// a channel opened with [`Handle::channel_open_forwarded_tcpip`].
+	/// [`forward::TcpipForwards`] does both.
 	#[allow(unused_variables)]
 	async fn tcpip_forward(
 		self,
@@ -681,8 +1076,10 @@ pub trait Handler: Sized {
 	) -> Result<(Self, bool, Session), Self::Error> {
 		Ok((self, false, session))
 	}
+
 	/// Used to stop the reverse-forwarding of a port, see
-	/// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7).
+	/// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7). When
+	/// `port` 0 was asked for, clients cancel the allocated port.
 	#[allow(unused_variables)]
 	async fn cancel_tcpip_forward(
 		self,
@@ -692,137 +1089,684 @@ pub trait Handler: Sized {
 	) -> Result<(Self, bool, Session), Self::Error> {
 		Ok((self, false, session))
 	}
+
+	/// Used for forwarding the Unix domain socket at `socket_path` to the
+	/// client, with `streamlocal-forward@openssh.com`. Each connection is
+	/// then forwarded on a channel opened with
+	/// [`Handle::channel_open_forwarded_streamlocal`].
+	#[allow(unused_variables)]
+	async fn streamlocal_forward(
+		self,
+		socket_path:&str,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, false, session))
+	}
+
+	/// Used to stop forwarding a Unix domain socket, with
+	/// `cancel-streamlocal-forward@openssh.com`.
+	#[allow(unused_variables)]
+	async fn cancel_streamlocal_forward(
+		self,
+		socket_path:&str,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, false, session))
+	}
+
+	/// Called when the client sends a global request that this library
+	/// does not handle, such as `no-more-sessions@openssh.com`, with its
+	/// request-specific data. The response is only sent if the client
+	/// asked for one. By default, the request is refused.
+	#[allow(unused_variables)]
+	async fn global_request(
+		self,
+		name:&str,
+		payload:&[u8],
+		session:Session,
+	) -> Result<(Self, GlobalRequestResponse, Session), Self::Error> {
+		Ok((self, GlobalRequestResponse::Failure, session))
+	}
+
+	/// Called when the client answers a packet we sent with an unimplemented
+	/// message, because it does not know its type. `seqn` is the sequence
+	/// number of that packet, counting the packets sent on the connection
+	/// from 0. Messages received before the first key exchange is done are
+	/// ignored.
+	#[allow(unused_variables)]
+	async fn unimplemented(
+		self,
+		seqn:u32,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		Ok((self, session))
+	}
+
+	/// Called when the client sends a debug message, such as those
+	/// OpenSSH logs at the `DEBUG` level, which should be shown to the
+	/// user if `always_display`. Invalid UTF-8 sequences are replaced with
+	/// `U+FFFD`. Messages received before the first key exchange is done
+	/// are ignored.
+	#[allow(unused_variables)]
+	async fn debug(
+		self,
+		always_display:bool,
+		message:&str,
+		language_tag:&str,
+		session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		Ok((self, session))
+	}
 }
 
 /// Trait used to create new handlers when clients connect.
+#[async_trait]
 pub trait Server {
 	/// The type of handlers.
 	type Handler: Handler + Send;
 	/// Called when a new client connects.
 	fn new_client(&mut self, peer_addr:Option<std::net::SocketAddr>) -> Self::Handler;
-}
-
-/// Run a server.
-/// Create a new `Connection` from the server's configuration, a
-/// stream and a [`Handler`](trait.Handler.html).
-pub async fn run<H:Server + Send + 'static, A:ToSocketAddrs>(
-	config:Arc<Config>,
-	addrs:A,
-	mut server:H,
-) -> Result<(), std::io::Error> {
-	let socket = TcpListener::bind(addrs).await?;
 
-	if config.maximum_packet_size > 65535 {
-		error!(
-			"Maximum packet size ({:?}) should not larger than a TCP packet (65535)",
-			config.maximum_packet_size
-		);
+	/// Called with each connection accepted by [`run`] or [`start`],
+	/// before the version exchange and [`Server::new_client`], and once
+	/// the connection fits in [`Config::max_connections`] and
+	/// [`Config::max_unauthenticated_connections`]. Rejected connections
+	/// are closed right away, and delayed ones keep their place in these
+	/// limits while waiting. Accepts all connections by default.
+	#[allow(unused_variables)]
+	async fn accept_filter(&mut self, peer_addr:Option<std::net::SocketAddr>) -> AcceptDecision {
+		AcceptDecision::Accept
 	}
 
-	while let Ok((socket, _)) = socket.accept().await {
-		let config = config.clone();
-
-		let server = server.new_client(socket.peer_addr().ok());
-
-		tokio::spawn(run_stream(config, socket, server));
+	/// Called with the error ending a session of [`run`] or [`start`],
+	/// the connection of `peer_addr`, while the server keeps accepting
+	/// connections. A client closing its connection with a disconnect
+	/// message ends its session with [`crate::Error::Disconnected`]. Only
+	/// logs the error by default.
+	#[allow(unused_variables)]
+	fn on_connection_error(
+		&mut self,
+		peer_addr:Option<std::net::SocketAddr>,
+		error:<Self::Handler as Handler>::Error,
+	) {
+		info!("Session from {:?} ended with an error", peer_addr);
 	}
-
-	Ok(())
 }
 
-use std::cell::RefCell;
-thread_local! {
-	static B1: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
+/// A closure creating the handler of each connection from its peer
+/// address, for [`run`] and [`start`].
+impl<F, H> Server for F
+where
+	F: FnMut(std::net::SocketAddr) -> H,
+	H: Handler + Send,
+{
+	type Handler = H;
+
+	fn new_client(&mut self, peer_addr:Option<std::net::SocketAddr>) -> H {
+		// `run` and `start` always know the address.
+		self(peer_addr.unwrap_or_else(|| ([0, 0, 0, 0], 0).into()))
+	}
+}
 
-	static B2: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
+/// What to do with a new connection, see [`Server::accept_filter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptDecision {
+	/// Run a session on the connection.
+	Accept,
+	/// Close the connection.
+	Reject,
+	/// Run a session on the connection after this delay.
+	Delay(std::time::Duration),
 }
 
-pub(crate) async fn timeout(delay:Option<std::time::Duration>) {
-	if let Some(delay) = delay {
-		tokio::time::sleep(delay).await
-	} else {
-		futures::future::pending().await
-	};
+/// The connections of [`run`] or [`start`], limited by
+/// [`Config::max_connections`] and
+/// [`Config::max_unauthenticated_connections`].
+struct Connections {
+	all:Option<Arc<Semaphore>>,
+	unauthenticated:Option<Arc<Semaphore>>,
 }
 
-async fn start_reading<R:AsyncRead + Unpin>(
-	mut stream_read:R,
-	mut buffer:SSHBuffer,
-	mut cipher:Box<dyn OpeningKey + Send>,
-) -> Result<(usize, R, SSHBuffer, Box<dyn OpeningKey + Send>), Error> {
-	buffer.buffer.clear();
+/// The place of a connection in the limits of [`Connections`], released
+/// when the session ends.
+#[derive(Debug, Default)]
+pub(crate) struct Admission {
+	_connection:Option<OwnedSemaphorePermit>,
+	/// Released once authenticated.
+	pub unauthenticated:Option<OwnedSemaphorePermit>,
+}
 
-	let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher).await?;
+impl Connections {
+	fn new(config:&Config) -> Self {
+		Connections {
+			all:config.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+			unauthenticated:config
+				.max_unauthenticated_connections
+				.map(|n| Arc::new(Semaphore::new(n))),
+		}
+	}
 
-	Ok((n, stream_read, buffer, cipher))
+	/// Admit a connection from `peer_addr`, with the delay it must wait
+	/// before its session starts, or `None` if it must be closed.
+	async fn admit<S:Server + Send>(
+		&self,
+		server:&mut S,
+		peer_addr:Option<std::net::SocketAddr>,
+	) -> Option<(Admission, Option<std::time::Duration>)> {
+		let permit = |limit:&Option<Arc<Semaphore>>| {
+			match limit {
+				Some(limit) => limit.clone().try_acquire_owned().map(Some),
+				None => Ok(None),
+			}
+		};
+
+		let admission = Admission {
+			_connection:permit(&self.all).ok()?,
+			unauthenticated:permit(&self.unauthenticated).ok()?,
+		};
+
+		match server.accept_filter(peer_addr).await {
+			AcceptDecision::Accept => Some((admission, None)),
+			AcceptDecision::Reject => None,
+			AcceptDecision::Delay(delay) => Some((admission, Some(delay))),
+		}
+	}
 }
 
-/// An active server session returned by [run_stream].
+/// Run a server, accepting connections on each address `addrs` resolves
+/// to, for instance both the IPv4 and the IPv6 loopbacks of
+/// `("localhost", 22)`, until accepting fails. Each connection gets the
+/// handler of [`Server::new_client`], which can be a closure of the peer
+/// address, with its type written out:
 ///
-/// Implements [Future] and needs to be awaited to allow the session to run.
-pub struct RunningSession<H:Handler> {
-	handle:Handle,
-	join:JoinHandle<Result<(), H::Error>>,
-}
+/// ```no_run
+/// # use std::sync::Arc;
+/// # struct Handler;
+/// # #[async_trait::async_trait]
+/// # impl russh::server::Handler for Handler {
+/// # 	type Error = russh::Error;
+/// # }
+/// # async fn serve(config:Arc<russh::server::Config>) -> std::io::Result<()> {
+/// let new_client = |_peer_addr:std::net::SocketAddr| Handler;
+///
+/// russh::server::run(config, ("localhost", 2222), new_client).await
+/// # }
+/// ```
+///
+/// Addresses failing to bind are skipped, as long as one binds.
+/// Sessions ending with an error are reported to
+/// [`Server::on_connection_error`].
+pub async fn run<H:Server + Send + 'static, A:ToSocketAddrs>(
+	config:Arc<Config>,
+	addrs:A,
+	server:H,
+) -> Result<(), std::io::Error> {
+	config.check().map_err(|e| {
+		std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+	})?;
 
-impl<H:Handler> RunningSession<H> {
-	/// Returns a new handle for the session.
-	pub fn handle(&self) -> Handle { self.handle.clone() }
+	let listeners = bind_all(addrs).await?;
+
+	run_on_listeners(config, listeners, server).await
 }
 
-impl<H:Handler> Future for RunningSession<H> {
-	type Output = Result<(), H::Error>;
+/// [`run`] on a bound listener, for instance one inherited from systemd's
+/// socket activation with [`TcpListener::from_std`].
+pub async fn run_on_listener<H:Server + Send + 'static>(
+	config:Arc<Config>,
+	listener:TcpListener,
+	server:H,
+) -> Result<(), std::io::Error> {
+	config.check().map_err(|e| {
+		std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+	})?;
 
-	fn poll(mut self: Pin<&mut Self>, cx:&mut Context) -> Poll<Self::Output> {
-		match Future::poll(Pin::new(&mut self.join), cx) {
-			Poll::Ready(r) => {
-				Poll::Ready(match r {
-					Ok(Ok(x)) => Ok(x),
-					Err(e) => Err(crate::Error::from(e).into()),
-					Ok(Err(e)) => Err(e),
-				})
-			},
-			Poll::Pending => Poll::Pending,
-		}
-	}
+	run_on_listeners(config, vec![listener], server).await
 }
 
-/// Run a single connection to completion.
-pub async fn run_stream<H, R>(
+async fn run_on_listeners<H:Server + Send + 'static>(
 	config:Arc<Config>,
-	mut stream:R,
-	handler:H,
-) -> Result<RunningSession<H>, H::Error>
-where
-	H: Handler + Send + 'static,
-	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
-	// Writing SSH id.
-	let mut write_buffer = SSHBuffer::new();
+	listeners:Vec<TcpListener>,
+	server:H,
+) -> Result<(), std::io::Error> {
+	// Nothing disconnects the sessions, and nothing waits for them.
+	let (_, disconnected) = tokio::sync::watch::channel(false);
 
-	write_buffer.send_ssh_id(&config.as_ref().server_id);
+	let (alive, _) = tokio::sync::mpsc::channel(1);
 
-	stream.write_all(&write_buffer.buffer[..]).await.map_err(crate::Error::from)?;
+	accept_loop(config, listeners, server, disconnected, alive).await
+}
 
-	info!("wrote id");
-	// Reading SSH id and allocating a session.
-	let mut stream = SshRead::new(stream);
+/// Bind a listener on each address of `addrs`, skipping those failing to
+/// bind, as long as one binds.
+async fn bind_all<A:ToSocketAddrs>(addrs:A) -> Result<Vec<TcpListener>, std::io::Error> {
+	let mut listeners = Vec::new();
 
-	let (sender, receiver) = tokio::sync::mpsc::channel(config.event_buffer_size);
+	let mut last_error = None;
 
-	let common = read_ssh_id(config, &mut stream).await?;
+	for addr in tokio::net::lookup_host(addrs).await? {
+		match TcpListener::bind(addr).await {
+			Ok(listener) => listeners.push(listener),
+			Err(e) => {
+				error!("Failed to listen on {}: {}", addr, e);
 
-	info!("read other id");
+				last_error = Some(e);
+			},
+		}
+	}
 
-	let handle = server::session::Handle { sender };
+	if listeners.is_empty() {
+		return Err(last_error.unwrap_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"could not resolve to any address",
+			)
+		}));
+	}
 
-	let session = Session {
-		target_window_size:common.config.window_size,
-		common,
+	Ok(listeners)
+}
+
+/// Accept a connection on any of `listeners`.
+async fn accept_any(
+	listeners:&[TcpListener],
+) -> Result<(tokio::net::TcpStream, std::net::SocketAddr), std::io::Error> {
+	let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+
+	let (accepted, _, _) = futures::future::select_all(accepts).await;
+
+	accepted
+}
+
+/// Accept connections on `listeners`, running a session of `server` on
+/// each until `disconnected` is set, and reporting their errors to
+/// [`Server::on_connection_error`]. Returns when accepting fails, but for
+/// the failures of a single connection. Each session holds a clone of
+/// `alive` until its connection is closed.
+async fn accept_loop<H:Server + Send + 'static>(
+	config:Arc<Config>,
+	listeners:Vec<TcpListener>,
+	mut server:H,
+	disconnected:tokio::sync::watch::Receiver<bool>,
+	alive:tokio::sync::mpsc::Sender<()>,
+) -> Result<(), std::io::Error> {
+	if config.maximum_packet_size > 65535 {
+		error!(
+			"Maximum packet size ({:?}) should not larger than a TCP packet (65535)",
+			config.maximum_packet_size
+		);
+	}
+
+	let connections = Connections::new(&config);
+
+	let (errors, mut session_errors) = tokio::sync::mpsc::unbounded_channel();
+
+	loop {
+		let (socket, peer_addr) = tokio::select! {
+			accepted = accept_any(&listeners) => {
+				match accepted {
+					Ok(accepted) => accepted,
+					Err(e) if is_connection_error(&e) => {
+						info!("Failed to accept a connection: {:?}", e);
+
+						continue;
+					},
+					Err(e) => return Err(e),
+				}
+			},
+			Some((peer_addr, e)) = session_errors.recv() => {
+				server.on_connection_error(peer_addr, e);
+
+				continue;
+			},
+		};
+
+		if config.nodelay {
+			if let Err(e) = socket.set_nodelay(true) {
+				error!("Failed to set TCP_NODELAY on a connection: {:?}", e);
+			}
+		}
+
+		let peer_addr = Some(peer_addr);
+
+		let (admission, delay) = match connections.admit(&mut server, peer_addr).await {
+			Some(admitted) => admitted,
+			None => {
+				info!("Refused a connection from {:?}", peer_addr);
+
+				continue;
+			},
+		};
+
+		let handler = server.new_client(peer_addr);
+
+		let session = run_admitted(config.clone(), socket, peer_addr, handler, admission, delay);
+
+		let session = run_until_disconnected(session, disconnected.clone(), alive.clone());
+
+		let errors = errors.clone();
+
+		tokio::spawn(async move {
+			if let Err(e) = session.await {
+				errors.send((peer_addr, e)).unwrap_or(());
+			}
+		});
+	}
+}
+
+/// Whether accepting failed because of the connection being accepted
+/// only, and the listener can keep accepting.
+fn is_connection_error(e:&std::io::Error) -> bool {
+	matches!(
+		e.kind(),
+		std::io::ErrorKind::ConnectionAborted
+			| std::io::ErrorKind::ConnectionReset
+			| std::io::ErrorKind::ConnectionRefused
+	)
+}
+
+/// A server started with [`start`], accepting connections until it is
+/// [shut down](RunningServer::shutdown). Dropping it leaves the server
+/// running in the background.
+#[derive(Debug)]
+pub struct RunningServer {
+	local_addrs:Vec<std::net::SocketAddr>,
+	accepting:JoinHandle<()>,
+	/// Set to disconnect the remaining sessions.
+	disconnect:tokio::sync::watch::Sender<bool>,
+	/// Closed once every connection has ended.
+	connections:tokio::sync::mpsc::Receiver<()>,
+}
+
+/// [`run`], but returning once listening, with a [`RunningServer`] to
+/// shut the server down.
+pub async fn start<H:Server + Send + 'static, A:ToSocketAddrs>(
+	config:Arc<Config>,
+	addrs:A,
+	server:H,
+) -> Result<RunningServer, std::io::Error> {
+	config.check().map_err(|e| {
+		std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+	})?;
+
+	let listeners = bind_all(addrs).await?;
+
+	start_on_listeners(config, listeners, server)
+}
+
+/// [`start`] on a bound listener, as [`run_on_listener`].
+pub async fn start_on_listener<H:Server + Send + 'static>(
+	config:Arc<Config>,
+	listener:TcpListener,
+	server:H,
+) -> Result<RunningServer, std::io::Error> {
+	config.check().map_err(|e| {
+		std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+	})?;
+
+	start_on_listeners(config, vec![listener], server)
+}
+
+fn start_on_listeners<H:Server + Send + 'static>(
+	config:Arc<Config>,
+	listeners:Vec<TcpListener>,
+	server:H,
+) -> Result<RunningServer, std::io::Error> {
+	let local_addrs =
+		listeners.iter().map(|listener| listener.local_addr()).collect::<Result<Vec<_>, _>>()?;
+
+	let (disconnect, disconnected) = tokio::sync::watch::channel(false);
+
+	// Each connection holds a sender, so that the receiver is closed once
+	// they have all ended.
+	let (alive, connections) = tokio::sync::mpsc::channel(1);
+
+	let accepting = tokio::spawn(async move {
+		if let Err(e) = accept_loop(config, listeners, server, disconnected, alive).await {
+			error!("Failed to accept a connection: {:?}", e);
+		}
+	});
+
+	Ok(RunningServer { local_addrs, accepting, disconnect, connections })
+}
+
+impl RunningServer {
+	/// The address the server listens on, the first of
+	/// [`RunningServer::local_addrs`].
+	pub fn local_addr(&self) -> std::net::SocketAddr {
+		self.local_addrs.first().copied().unwrap_or_else(|| ([0, 0, 0, 0], 0).into())
+	}
+
+	/// The addresses the server listens on.
+	pub fn local_addrs(&self) -> &[std::net::SocketAddr] { &self.local_addrs }
+
+	/// Stop accepting connections, and wait for the sessions to end, for
+	/// at most `grace`. The remaining sessions are then ended with a
+	/// [`Disconnect::ByApplication`] message. Returns once every
+	/// connection is closed.
+	pub async fn shutdown(mut self, grace:std::time::Duration) {
+		// Closes the listener.
+		self.accepting.abort();
+
+		self.accepting.await.ok();
+
+		if tokio::time::timeout(grace, self.connections.recv()).await.is_err() {
+			info!("Disconnecting the remaining sessions");
+
+			self.disconnect.send(true).unwrap_or(());
+
+			self.connections.recv().await;
+		}
+	}
+}
+
+/// Run a session started by [`run`] or [`start`], until it ends or the
+/// server disconnects it, returning its result. `_alive` is dropped when
+/// the connection is closed.
+async fn run_until_disconnected<H, F>(
+	session:F,
+	mut disconnected:tokio::sync::watch::Receiver<bool>,
+	_alive:tokio::sync::mpsc::Sender<()>,
+) -> Result<(), H::Error>
+where
+	H: Handler,
+	F: Future<Output = Result<RunningSession<H>, H::Error>>, {
+	// The connection is dropped if the handshake has not yet started.
+	let mut session = tokio::select! {
+		session = session => session?,
+		_ = until_set(&mut disconnected) => return Ok(()),
+	};
+
+	let handle = session.handle();
+
+	tokio::select! {
+		result = &mut session => return result,
+		_ = until_set(&mut disconnected) => {},
+	}
+
+	handle.disconnect(Disconnect::ByApplication, "Server shutting down", "").await.unwrap_or(());
+
+	// The session ends with the error of the disconnection.
+	session.await.ok();
+
+	Ok(())
+}
+
+/// Wait until `flag` is set, or forever if its sender is dropped.
+async fn until_set(flag:&mut tokio::sync::watch::Receiver<bool>) {
+	while !*flag.borrow() {
+		if flag.changed().await.is_err() {
+			futures::future::pending::<()>().await;
+		}
+	}
+}
+
+use std::cell::RefCell;
+thread_local! {
+	static B1: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
+
+	static B2: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
+}
+
+pub(crate) async fn timeout(delay:Option<std::time::Duration>) {
+	if let Some(delay) = delay {
+		tokio::time::sleep(delay).await
+	} else {
+		futures::future::pending().await
+	};
+}
+
+async fn start_reading<R:AsyncRead + Unpin>(
+	mut stream_read:R,
+	mut buffer:SSHBuffer,
+	mut cipher:Box<dyn OpeningKey + Send>,
+	max_packet_size:usize,
+) -> Result<(usize, R, SSHBuffer, Box<dyn OpeningKey + Send>), Error> {
+	buffer.buffer.clear();
+
+	let n = cipher::read(&mut stream_read, &mut buffer, &mut *cipher, max_packet_size).await?;
+
+	Ok((n, stream_read, buffer, cipher))
+}
+
+/// An active server session returned by [run_stream].
+///
+/// Implements [Future] and needs to be awaited to allow the session to run.
+pub struct RunningSession<H:Handler> {
+	handle:Handle,
+	join:JoinHandle<Result<(), H::Error>>,
+}
+
+impl<H:Handler> RunningSession<H> {
+	/// Returns a new handle for the session.
+	pub fn handle(&self) -> Handle { self.handle.clone() }
+}
+
+impl<H:Handler> Future for RunningSession<H> {
+	type Output = Result<(), H::Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx:&mut Context) -> Poll<Self::Output> {
+		match Future::poll(Pin::new(&mut self.join), cx) {
+			Poll::Ready(r) => {
+				Poll::Ready(match r {
+					Ok(Ok(x)) => Ok(x),
+					Err(e) => Err(crate::Error::from(e).into()),
+					Ok(Err(e)) => Err(e),
+				})
+			},
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+/// Run a single connection to completion. The session has no
+/// [`ConnectionInfo::peer_addr`], see [`run_stream_with_peer_addr`].
+pub async fn run_stream<H, R>(
+	config:Arc<Config>,
+	stream:R,
+	handler:H,
+) -> Result<RunningSession<H>, H::Error>
+where
+	H: Handler + Send + 'static,
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	run_stream_with_peer_addr(config, stream, None, handler).await
+}
+
+/// [`run_stream_with_peer_addr`] for [`run`] and [`start`], after
+/// `delay`, with the place of the connection in their limits.
+async fn run_admitted<H, R>(
+	config:Arc<Config>,
+	stream:R,
+	peer_addr:Option<std::net::SocketAddr>,
+	handler:H,
+	admission:Admission,
+	delay:Option<std::time::Duration>,
+) -> Result<RunningSession<H>, H::Error>
+where
+	H: Handler + Send + 'static,
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	if let Some(delay) = delay {
+		tokio::time::sleep(delay).await;
+	}
+
+	run_session(config, stream, peer_addr, handler, admission).await
+}
+
+/// [`run_stream`], on a connection from `peer_addr`, as returned by
+/// [`Session::connection_info`].
+pub async fn run_stream_with_peer_addr<H, R>(
+	config:Arc<Config>,
+	stream:R,
+	peer_addr:Option<std::net::SocketAddr>,
+	handler:H,
+) -> Result<RunningSession<H>, H::Error>
+where
+	H: Handler + Send + 'static,
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	run_session(config, stream, peer_addr, handler, Admission::default()).await
+}
+
+async fn run_session<H, R>(
+	config:Arc<Config>,
+	mut stream:R,
+	peer_addr:Option<std::net::SocketAddr>,
+	handler:H,
+	admission:Admission,
+) -> Result<RunningSession<H>, H::Error>
+where
+	H: Handler + Send + 'static,
+	R: AsyncRead + AsyncWrite + Unpin + Send + 'static, {
+	config.check()?;
+
+	config.server_id.check()?;
+
+	// Writing SSH id.
+	let mut write_buffer = SSHBuffer::new();
+
+	write_buffer.send_ssh_id(&config.as_ref().server_id);
+
+	stream.write_all(&write_buffer.buffer[..]).await.map_err(crate::Error::from)?;
+
+	info!("wrote id");
+	// Reading SSH id and allocating a session.
+	let mut stream = SshRead::new(stream);
+
+	let (sender, receiver) = tokio::sync::mpsc::channel(config.event_buffer_size);
+
+	let auth_deadline = config.auth_timeout.map(|t| tokio::time::Instant::now() + t);
+
+	let common = read_ssh_id(config, &mut stream, peer_addr);
+
+	// The version exchange counts in the authentication time.
+	let common = match auth_deadline {
+		Some(deadline) => {
+			tokio::time::timeout_at(deadline, common).await.map_err(|_| Error::AuthTimeout)??
+		},
+		None => common.await?,
+	};
+
+	info!("read other id");
+
+	let handle = server::session::Handle {
+		sender,
+		backlogs:common.backlogs.clone(),
+		stats:common.stats.clone(),
+	};
+
+	let session = Session {
+		target_window_size:common.config.window_size,
+		common,
 		receiver,
 		sender:handle.clone(),
 		pending_reads:Vec::new(),
 		pending_len:0,
 		channels:HashMap::new(),
+		keepalives_unanswered:0,
+		pending_global_requests:VecDeque::new(),
+		no_more_sessions:false,
+		admission,
+		auth_deadline,
+		open_failure:None,
 	};
 
 	let join = tokio::spawn(session.run(stream, handler));
@@ -835,28 +1779,37 @@ where
 async fn read_ssh_id<R:AsyncRead + Unpin>(
 	config:Arc<Config>,
 	read:&mut SshRead<R>,
+	peer_addr:Option<std::net::SocketAddr>,
 ) -> Result<CommonSession<Arc<Config>>, Error> {
+	let id = read.read_ssh_id(config.max_preamble_lines, config.max_preamble_bytes);
+
 	let sshid = if let Some(t) = config.connection_timeout {
-		tokio::time::timeout(t, read.read_ssh_id()).await??
+		tokio::time::timeout(t, id).await??
 	} else {
-		read.read_ssh_id().await?
+		id.await?
 	};
 
+	let info = ConnectionInfo::new(peer_addr, sshid, config.server_id.as_kex_hash_bytes());
+
 	let mut exchange = Exchange::new();
 
 	exchange.client_id.extend(sshid);
 	// Preparing the response
 	exchange.server_id.extend(config.as_ref().server_id.as_kex_hash_bytes());
 
-	let mut kexinit = KexInit { exchange, algo:None, sent:false, session_id:None };
+	let mut kexinit = KexInit::new(exchange);
 
 	let mut cipher =
 		CipherPair { local_to_remote:Box::new(clear::Key), remote_to_local:Box::new(clear::Key) };
 
 	let mut write_buffer = SSHBuffer::new();
 
+	write_buffer.random_padding = config.random_padding.clone();
+
 	kexinit.server_write(config.as_ref(), &mut *cipher.local_to_remote, &mut write_buffer)?;
 
+	let backlogs = Backlogs::new(config.channel_buffer_size);
+
 	Ok(CommonSession {
 		write_buffer,
 		kex:Some(Kex::Init(kexinit)),
@@ -869,25 +1822,87 @@ async fn read_ssh_id<R:AsyncRead + Unpin>(
 		wants_reply:false,
 		disconnected:false,
 		buffer:CryptoVec::new(),
+		strict_kex:false,
+		backlogs,
+		stats:Default::default(),
+		info,
 	})
 }
 
 async fn reply<H:Handler + Send>(
 	mut session:Session,
 	handler:H,
+	seqn:&mut Wrapping<u32>,
 	buf:&[u8],
 ) -> Result<(H, Session), H::Error> {
+	session.common.check_strict_kex(buf, *seqn)?;
+
+	if buf.first().map_or(true, |&t| t <= msg::DEBUG) {
+		// IGNORE, UNIMPLEMENTED and DEBUG, of which the handler sees the
+		// last two once the first key exchange is done.
+		if session.common.encrypted.is_some() {
+			match buf.first() {
+				Some(&msg::UNIMPLEMENTED) => {
+					if let Some(seqn) = crate::session::read_unimplemented(buf) {
+						debug!("unimplemented message: {:?}", seqn);
+
+						return handler.unimplemented(seqn, session).await;
+					}
+				},
+				Some(&msg::DEBUG) => {
+					if let Some((always_display, message, language_tag)) =
+						crate::session::read_debug(buf)
+					{
+						debug!("debug message: {:?}", message);
+
+						return handler
+							.debug(always_display, &message, &language_tag, session)
+							.await;
+					}
+				},
+				_ => {},
+			}
+		}
+
+		return Ok((handler, session));
+	}
+
+	if buf.first().map_or(false, |&t| !msg::is_known(t)) {
+		debug!("unknown message type: {:?}", buf.first());
+
+		session.common.send_unimplemented(seqn.0.wrapping_sub(1))?;
+
+		return Ok((handler, session));
+	}
+
 	// Handle key exchange/re-exchange.
 	if session.common.encrypted.is_none() {
 		match session.common.kex.take() {
 			Some(Kex::Init(kexinit)) => {
 				if kexinit.algo.is_some() || buf.first() == Some(&msg::KEXINIT) {
-					session.common.kex = Some(kexinit.server_parse(
+					let kex = kexinit.server_parse(
 						session.common.config.as_ref(),
 						&mut *session.common.cipher.local_to_remote,
 						buf,
 						&mut session.common.write_buffer,
-					)?);
+					)?;
+
+					if let Kex::Dh(ref kexdh) = kex {
+						if kexdh.names.strict_kex {
+							// The client's KEXINIT must be its very first packet.
+							if seqn.0 != 1 {
+								return Err(Error::StrictKeyExchangeViolation {
+									message_type:msg::KEXINIT,
+									sequence_number:seqn.0.wrapping_sub(1),
+								}
+								.into());
+							}
+
+							session.common.strict_kex = true;
+						}
+					}
+
+					session.common.kex = Some(kex);
 
 					return Ok((handler, session));
 				} else {
@@ -898,12 +1913,21 @@ async fn reply<H:Handler + Send>(
 				}
 			},
 			Some(Kex::Dh(kexdh)) => {
-				session.common.kex = Some(kexdh.parse(
-					session.common.config.as_ref(),
-					&mut *session.common.cipher.local_to_remote,
-					buf,
-					&mut session.common.write_buffer,
-				)?);
+				let kex = kexdh
+					.parse(
+						session.common.config.as_ref(),
+						&mut *session.common.cipher.local_to_remote,
+						buf,
+						&mut session.common.write_buffer,
+					)
+					.await?;
+
+				// `Keys` means our NEWKEYS was just written.
+				if session.common.strict_kex && matches!(kex, Kex::Keys(_)) {
+					session.common.write_buffer.seqn = Wrapping(0);
+				}
+
+				session.common.kex = Some(kex);
 
 				return Ok((handler, session));
 			},
@@ -911,10 +1935,15 @@ async fn reply<H:Handler + Send>(
 				if buf.first() != Some(&msg::NEWKEYS) {
 					return Err(Error::Kex.into());
 				}
+
+				if session.common.strict_kex {
+					*seqn = Wrapping(0);
+				}
 				// Ok, NEWKEYS received, now encrypted.
 				session.common.encrypted(
 					EncryptedState::WaitingAuthServiceRequest { sent:false, accepted:false },
 					newkeys,
+					session.common.config.compression_level,
 				);
 
 				session.maybe_send_ext_info();
@@ -931,6 +1960,1130 @@ async fn reply<H:Handler + Send>(
 
 		Ok((handler, session))
 	} else {
-		Ok(session.server_read_encrypted(handler, buf).await?)
+		Ok(session.server_read_encrypted(handler, seqn, buf).await?)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// The server closes connections that stall before authentication or
+	/// stay silent for too long, but not those sending keepalives.
+	#[tokio::test]
+	async fn test_server_timeouts() {
+		use std::{sync::Arc, time::Duration};
+
+		use russh_keys::key::KeyPair;
+		use tokio::time::Instant;
+
+		use crate::Disconnect;
+
+		let auth_timeout = Duration::from_millis(300);
+
+		let inactivity_timeout = Duration::from_millis(600);
+
+		let slack = Duration::from_secs(2);
+
+		/// A server session on one end of a stream, and the other end.
+		fn serve(
+			auth_timeout:Duration,
+			inactivity_timeout:Duration,
+		) -> (tokio::io::DuplexStream, tokio::task::JoinHandle<Result<(), crate::Error>>) {
+			let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+			let mut config = server::Config::default();
+
+			config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+			config.auth_timeout = Some(auth_timeout);
+
+			config.inactivity_timeout = Some(inactivity_timeout);
+
+			let server = tokio::spawn(async move {
+				server::run_stream(Arc::new(config), server_stream, Server {}).await?.await
+			});
+
+			(client_stream, server)
+		}
+
+		// A client that never sends its version.
+		let (mut client_stream, server) = serve(auth_timeout, inactivity_timeout);
+
+		let start = Instant::now();
+
+		let mut received = Vec::new();
+
+		tokio::time::timeout(slack, client_stream.read_to_end(&mut received))
+			.await
+			.unwrap()
+			.unwrap();
+
+		assert!(received.starts_with(b"SSH-2.0-"));
+
+		assert!(matches!(server.await.unwrap(), Err(crate::Error::AuthTimeout)));
+
+		assert!(start.elapsed() >= auth_timeout);
+
+		// A client that never authenticates, even if it was not silent.
+		let (client_stream, server) = serve(auth_timeout, inactivity_timeout);
+
+		let start = Instant::now();
+
+		let session =
+			client::connect_stream(Default::default(), client_stream, Client {}).await.unwrap();
+
+		let result = tokio::time::timeout(slack, session).await.unwrap();
+
+		assert!(matches!(
+			result,
+			Err(crate::Error::Disconnected { reason:Disconnect::NoMoreAuthMethodsAvailable, .. })
+		));
+
+		assert!(matches!(server.await.unwrap(), Err(crate::Error::AuthTimeout)));
+
+		assert!(start.elapsed() >= auth_timeout);
+
+		// A client that authenticates, then stays silent.
+		let (client_stream, server) = serve(auth_timeout, inactivity_timeout);
+
+		let mut session =
+			client::connect_stream(Default::default(), client_stream, Client {}).await.unwrap();
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		let start = Instant::now();
+
+		let result = tokio::time::timeout(inactivity_timeout + slack, session).await.unwrap();
+
+		assert!(matches!(
+			result,
+			Err(crate::Error::Disconnected { reason:Disconnect::ByApplication, .. })
+		));
+
+		assert!(matches!(server.await.unwrap(), Err(crate::Error::InactivityTimeout)));
+
+		assert!(start.elapsed() >= inactivity_timeout);
+
+		// A client sending keepalives is not inactive.
+		let (client_stream, server) = serve(auth_timeout, inactivity_timeout);
+
+		let config = client::Config {
+			keepalive_interval:Some(inactivity_timeout / 4),
+			..Default::default()
+		};
+
+		let mut session =
+			client::connect_stream(Arc::new(config), client_stream, Client {}).await.unwrap();
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		tokio::time::sleep(inactivity_timeout * 3).await;
+
+		assert!(session.channel_open_session().await.is_ok());
+
+		drop(server);
+	}
+
+	/// A server with several host keys signs with the algorithm the client
+	/// prefers, among those of its keys.
+	#[tokio::test]
+	#[cfg(all(feature = "rs-crypto", feature = "openssl"))]
+	async fn test_host_key_selection() {
+		use std::sync::Arc;
+
+		use russh_keys::key::{self, KeyPair, SignatureHash};
+
+		// The RSA key, whose hash is SHA-256, signs with any of them.
+		let algorithms:[&'static [key::Name]; 3] =
+			[&[key::ED25519], &[key::RSA_SHA2_256], &[key::RSA_SHA2_512]];
+
+		for algorithm in algorithms {
+			let client_config = client::Config {
+				preferred:crate::Preferred { key:algorithm, ..crate::Preferred::DEFAULT },
+				..Default::default()
+			};
+
+			// Next to the Ed25519 key of `test_session_with_configs`.
+			let mut server_config = server::Config::default();
+
+			server_config.keys.push(KeyPair::generate_rsa(2048, SignatureHash::SHA2_256).unwrap());
+
+			test_session_with_configs(
+				client_config,
+				server_config,
+				Client {},
+				Server {},
+				move |c| {
+					async move {
+						let negotiated = c.negotiated_algorithms().await.unwrap().unwrap();
+
+						assert_eq!(&[negotiated.key][..], algorithm);
+
+						c
+					}
+				},
+				|s| async move { s },
+			)
+			.await;
+		}
+
+		// Host key algorithms without a key are not offered.
+		let client_config = client::Config {
+			preferred:crate::Preferred { key:&[key::RSA_SHA2_512], ..crate::Preferred::DEFAULT },
+			..Default::default()
+		};
+
+		let mut server_config = server::Config::default();
+
+		server_config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+		tokio::spawn(async move {
+			server::run_stream(Arc::new(server_config), server_stream, Server {})
+				.await
+				.ok();
+		});
+
+		assert!(matches!(
+			client::connect_stream(Arc::new(client_config), client_stream, Client {}).await,
+			Err(crate::Error::NoCommonKeyAlgo)
+		));
+
+		// Two keys of the same type are rejected.
+		let mut server_config = server::Config::default();
+
+		server_config.keys.push(KeyPair::generate_rsa(2048, SignatureHash::SHA2_256).unwrap());
+
+		server_config.keys.push(KeyPair::generate_rsa(2048, SignatureHash::SHA2_512).unwrap());
+
+		let (_, server_stream) = tokio::io::duplex(65536);
+
+		assert!(matches!(
+			server::run_stream(Arc::new(server_config), server_stream, Server {}).await,
+			Err(crate::Error::DuplicateHostKey("ssh-rsa"))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_server_shutdown() {
+		use std::{sync::Arc, time::Duration};
+
+		use russh_keys::key::KeyPair;
+
+		use crate::Disconnect;
+
+		let mut config = server::Config::default();
+
+		config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		let server = server::start(Arc::new(config), "127.0.0.1:0", |_| Server {}).await.unwrap();
+
+		let addr = server.local_addr();
+
+		let mut sessions = Vec::new();
+
+		for _ in 0..2 {
+			let mut session = client::connect(Default::default(), addr, Client {}).await.unwrap();
+
+			assert!(session.authenticate_none("user").await.unwrap().success());
+
+			// An open channel keeps the session busy.
+			let channel = session.channel_open_session().await.unwrap();
+
+			sessions.push((session, channel));
+		}
+
+		let grace = Duration::from_millis(200);
+
+		let shutdown = tokio::spawn(server.shutdown(grace));
+
+		for (session, _channel) in sessions {
+			let result = tokio::time::timeout(Duration::from_secs(5), session).await.unwrap();
+
+			assert!(matches!(
+				result,
+				Err(crate::Error::Disconnected { reason:Disconnect::ByApplication, .. })
+			));
+		}
+
+		tokio::time::timeout(Duration::from_secs(5), shutdown).await.unwrap().unwrap();
+
+		assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+	}
+
+	/// A closure creates the handlers of a server on a bound listener, and
+	/// sessions ending with an error are reported without stopping it.
+	#[tokio::test]
+	async fn test_start_on_listener() {
+		use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+		use russh_keys::key::KeyPair;
+		use tokio::net::TcpListener;
+
+		struct Server {
+			errors:UnboundedSender<(Option<SocketAddr>, crate::Error)>,
+		}
+
+		impl server::Server for Server {
+			type Handler = ServerHandle;
+
+			fn new_client(&mut self, _:Option<SocketAddr>) -> ServerHandle { ServerHandle {} }
+
+			fn on_connection_error(&mut self, peer_addr:Option<SocketAddr>, error:crate::Error) {
+				self.errors.send((peer_addr, error)).unwrap();
+			}
+		}
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, user:&str) -> Result<(Self, server::Auth), Self::Error> {
+				if user == "mallory" {
+					return Err(crate::Error::Inconsistent);
+				}
+
+				Ok((self, server::Auth::Accept))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		let config = Arc::new(config);
+
+		// A closure of the peer address.
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let (peers, mut peers_received) = unbounded_channel();
+
+		let server = server::start_on_listener(config.clone(), listener, move |peer:SocketAddr| {
+			peers.send(peer).unwrap();
+
+			ServerHandle {}
+		})
+		.await
+		.unwrap();
+
+		let addr = server.local_addr();
+
+		assert_eq!(server.local_addrs(), &[addr]);
+
+		let mut session = client::connect(Default::default(), addr, Client {}).await.unwrap();
+
+		assert!(session.authenticate_none("alice").await.unwrap().success());
+
+		assert!(peers_received.recv().await.unwrap().ip().is_loopback());
+
+		server.shutdown(Duration::from_millis(200)).await;
+
+		// Errors go to the server, which keeps accepting.
+		let (errors, mut errors_received) = unbounded_channel();
+
+		let server = server::start(config, "127.0.0.1:0", Server { errors }).await.unwrap();
+
+		let addr = server.local_addr();
+
+		let mut session = client::connect(Default::default(), addr, Client {}).await.unwrap();
+
+		// The connection ends instead of replying.
+		assert!(!session.authenticate_none("mallory").await.map_or(false, |r| r.success()));
+
+		let received = tokio::time::timeout(Duration::from_secs(5), errors_received.recv()).await;
+
+		let (peer, error) = received.unwrap().unwrap();
+
+		assert!(peer.unwrap().ip().is_loopback());
+
+		assert!(matches!(error, crate::Error::Inconsistent));
+
+		let mut session = client::connect(Default::default(), addr, Client {}).await.unwrap();
+
+		assert!(session.authenticate_none("alice").await.unwrap().success());
+	}
+
+	/// Connections beyond `max_unauthenticated_connections` are closed
+	/// right away, and authenticated sessions do not count.
+	#[tokio::test]
+	async fn test_max_unauthenticated_connections() {
+		use std::{sync::Arc, time::Duration};
+
+		use russh_keys::key::KeyPair;
+		use tokio::net::TcpStream;
+
+		/// Connect without sending anything, and read the identification
+		/// string of the server, or `None` if the connection is closed.
+		async fn idle(addr:std::net::SocketAddr) -> Option<TcpStream> {
+			let mut stream = TcpStream::connect(addr).await.unwrap();
+
+			let mut id = [0; 8];
+
+			match tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut id)).await {
+				Ok(Ok(_)) => {
+					assert_eq!(&id, b"SSH-2.0-");
+
+					Some(stream)
+				},
+				Ok(Err(_)) => None,
+				Err(_) => panic!("the server neither answered nor closed the connection"),
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		config.max_unauthenticated_connections = Some(2);
+
+		let server = server::start(Arc::new(config), "127.0.0.1:0", |_| Server {}).await.unwrap();
+
+		let addr = server.local_addr();
+
+		let mut session = client::connect(Default::default(), addr, Client {}).await.unwrap();
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		let first = idle(addr).await.unwrap();
+
+		let _second = idle(addr).await.unwrap();
+
+		assert!(idle(addr).await.is_none());
+
+		// The authenticated session is unaffected.
+		session.channel_open_session().await.unwrap();
+
+		// Closing an idle connection makes room for another one.
+		drop(first);
+
+		let mut third = None;
+
+		for _ in 0..50 {
+			third = idle(addr).await;
+
+			if third.is_some() {
+				break;
+			}
+
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+
+		assert!(third.is_some());
+
+		server.shutdown(Duration::from_secs(1)).await;
+	}
+
+	/// After `no-more-sessions@openssh.com`, the server refuses session
+	/// channels without asking its handler, and the open ones still work.
+	#[tokio::test]
+	async fn test_no_more_sessions() {
+		use std::sync::{
+			Arc,
+			atomic::{AtomicUsize, Ordering},
+		};
+
+		use crate::ChannelOpenFailure;
+
+		/// Echoes session channels, counting them.
+		struct ServerHandle {
+			sessions:Arc<AtomicUsize>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				self.sessions.fetch_add(1, Ordering::SeqCst);
+
+				tokio::spawn(async move {
+					let (mut read, mut write) = tokio::io::split(channel.into_stream());
+
+					tokio::io::copy(&mut read, &mut write).await.ok();
+
+					write.shutdown().await.ok();
+				});
+
+				Ok((self, true, session))
+			}
+		}
+
+		let sessions = Arc::new(AtomicUsize::new(0));
+
+		let counted = sessions.clone();
+
+		test_session(
+			Client {},
+			ServerHandle { sessions },
+			|client| {
+				async move {
+					let channel = client.channel_open_session().await.unwrap();
+
+					client.no_more_sessions().await.unwrap();
+
+					let refused = client.channel_open_session().await;
+
+					assert!(matches!(
+						refused,
+						Err(crate::Error::ChannelOpenFailure(
+							ChannelOpenFailure::AdministrativelyProhibited
+						))
+					));
+
+					let mut stream = channel.into_stream();
+
+					stream.write_all(b"still open").await.unwrap();
+
+					stream.shutdown().await.unwrap();
+
+					let mut echoed = Vec::new();
+
+					stream.read_to_end(&mut echoed).await.unwrap();
+
+					assert_eq!(echoed, b"still open");
+
+					assert_eq!(counted.load(Ordering::SeqCst), 1);
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+
+	#[tokio::test]
+	#[cfg(all(unix, feature = "rs-crypto"))]
+	async fn test_host_key_signer() {
+		use std::sync::Arc;
+
+		use russh_keys::{
+			agent::client::AgentClient,
+			key::{self, KeyPair, PublicKey, Signature},
+		};
+		use tokio::net::UnixListener;
+
+		use crate::auth::{AgentSigner, Signer, SignerError};
+
+		// Only accepts the key of the agent.
+		struct Client {
+			host_key:PublicKey,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				server_public_key:&PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				let known = *server_public_key == self.host_key;
+
+				Ok((self, known))
+			}
+		}
+
+		struct FailingSigner {
+			key:PublicKey,
+		}
+
+		#[async_trait]
+		impl Signer for FailingSigner {
+			fn public_key(&self) -> PublicKey { self.key.clone() }
+
+			async fn sign(&self, _:key::Name, _:&[u8]) -> Result<Signature, SignerError> {
+				Err("agent gone".into())
+			}
+		}
+
+		// The agent holds the only copy of the host key.
+		let agent_path =
+			std::env::temp_dir().join(format!("russh-host-agent-{}.sock", std::process::id()));
+
+		std::fs::remove_file(&agent_path).ok();
+
+		let listener = UnixListener::bind(&agent_path).unwrap();
+
+		let connections = Box::pin(futures::stream::unfold(listener, |listener| {
+			async move {
+				let connection = listener.accept().await.map(|(stream, _)| stream);
+
+				Some((connection, listener))
+			}
+		}));
+
+		tokio::spawn(russh_keys::agent::server::serve(connections, ()));
+
+		let host_key = {
+			let key = KeyPair::generate_ed25519().unwrap();
+
+			let mut agent = AgentClient::connect_uds(&agent_path).await.unwrap();
+
+			agent.add_identity(&key, &[]).await.unwrap();
+
+			key.clone_public_key().unwrap()
+		};
+
+		// Starts a server signing with `signer` only, and connects to it.
+		let connect = |signer:Arc<dyn Signer>| {
+			let mut server_config = server::Config::default();
+
+			server_config.host_key_signers.push(signer);
+
+			let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+			let server = tokio::spawn(async move {
+				server::run_stream(Arc::new(server_config), server_stream, Server {})
+					.await?
+					.await
+			});
+
+			let client = Client { host_key:host_key.clone() };
+
+			let client =
+				client::connect_stream(Arc::new(client::Config::default()), client_stream, client);
+
+			async move { (client.await, server) }
+		};
+
+		let agent = AgentClient::connect_uds(&agent_path).await.unwrap();
+
+		let (client, _server) = connect(Arc::new(AgentSigner::new(agent, host_key.clone()))).await;
+
+		// Checked the agent's signature.
+		let client = client.unwrap();
+
+		// A failing signer only fails its own handshake.
+		let (failed, server) = connect(Arc::new(FailingSigner { key:host_key.clone() })).await;
+
+		assert!(failed.is_err());
+
+		assert!(matches!(server.await.unwrap(), Err(crate::Error::Signer(_))));
+
+		assert!(!client.is_closed());
+
+		std::fs::remove_file(&agent_path).ok();
+	}
+
+	/// `ssh host true && echo ok` against a russh server, which refuses
+	/// other commands. Run with `cargo test -- --ignored`, with `ssh` in
+	/// the `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_exec_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{net::TcpListener, process::Command};
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				if data == b"true" {
+					session.channel_success(channel);
+
+					session.send_exit_and_close(channel, 0);
+				} else {
+					session.channel_failure(channel);
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let config = Arc::new(config);
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let config = config.clone();
+
+				tokio::spawn(async move {
+					server::run_stream(config, stream, ServerHandle {}).await.unwrap().await.ok();
+				});
+			}
+		});
+
+		let ssh = format!(
+			"ssh -p {} -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null \
+			 -o BatchMode=yes user@127.0.0.1",
+			port
+		);
+
+		let run = |command:String| {
+			let mut sh = Command::new("sh");
+
+			sh.arg("-c").arg(command).stdin(std::process::Stdio::null());
+
+			tokio::time::timeout(Duration::from_secs(30), sh.output())
+		};
+
+		let output = run(format!("{} true && echo ok", ssh)).await.unwrap().unwrap();
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		assert!(output.status.success(), "{}", stderr);
+
+		assert_eq!(output.stdout, b"ok\n");
+
+		// A refused command fails, instead of waiting for output.
+		let output = run(format!("{} reboot", ssh)).await.unwrap().unwrap();
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		assert!(!output.status.success());
+
+		assert!(stderr.contains("exec request failed"), "{}", stderr);
+	}
+
+	// Pinned OpenSSH clients connect to one server with both host keys.
+	// Russh has no ECDSA keys, so `ecdsa-sha2-nistp256` is not covered.
+	#[cfg(all(unix, feature = "rs-crypto", feature = "openssl"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_host_key_selection_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use russh_keys::key::{KeyPair, SignatureHash};
+		use tokio::{
+			io::{AsyncBufReadExt, BufReader},
+			process::Command,
+		};
+
+		let mut config = server::Config::default();
+
+		config.keys.push(KeyPair::generate_ed25519().unwrap());
+
+		config.keys.push(KeyPair::generate_rsa(2048, SignatureHash::SHA2_256).unwrap());
+
+		let server = server::start(Arc::new(config), "127.0.0.1:0", |_| Server {}).await.unwrap();
+
+		let port = server.local_addr().port();
+
+		for algorithm in ["ssh-ed25519", "rsa-sha2-512", "rsa-sha2-256"] {
+			let mut ssh = Command::new("ssh")
+				.args(["-v", "-N", "-p", &port.to_string()])
+				.args(["-o", "StrictHostKeyChecking=no"])
+				.args(["-o", "UserKnownHostsFile=/dev/null"])
+				.args(["-o", "BatchMode=yes"])
+				.args(["-o", &format!("HostKeyAlgorithms={}", algorithm)])
+				.arg("user@127.0.0.1")
+				.stdin(std::process::Stdio::null())
+				.stderr(std::process::Stdio::piped())
+				.kill_on_drop(true)
+				.spawn()
+				.unwrap();
+
+			let mut lines = BufReader::new(ssh.stderr.take().unwrap()).lines();
+
+			let negotiated = format!("kex: host key algorithm: {}", algorithm);
+
+			let (mut offered, mut authenticated) = (false, false);
+
+			tokio::time::timeout(Duration::from_secs(30), async {
+				while let Some(line) = lines.next_line().await.unwrap() {
+					offered |= line.contains(&negotiated);
+
+					authenticated |= line.starts_with("Authenticated to");
+
+					if authenticated {
+						break;
+					}
+				}
+			})
+			.await
+			.unwrap();
+
+			assert!(offered && authenticated, "{}", algorithm);
+
+			ssh.kill().await.ok();
+		}
+
+		server.shutdown(Duration::from_secs(1)).await;
+	}
+
+	// OpenSSH connects to a server whose host key is only in `ssh-agent`.
+	// Run with `cargo test -- --ignored`, with `ssh`, `ssh-agent`,
+	// `ssh-add` and `ssh-keygen` in the `PATH`.
+	#[cfg(all(unix, feature = "rs-crypto"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_host_key_signer_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use russh_keys::agent::client::AgentClient;
+		use tokio::{
+			io::{AsyncBufReadExt, BufReader},
+			net::TcpListener,
+			process::Command,
+		};
+
+		use crate::auth::AgentSigner;
+
+		let dir = std::env::temp_dir().join(format!("russh-host-agent-{}", std::process::id()));
+
+		std::fs::create_dir_all(&dir).unwrap();
+
+		let agent_path = dir.join("agent.sock");
+
+		let _agent = Command::new("ssh-agent")
+			.arg("-D")
+			.arg("-a")
+			.arg(&agent_path)
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		while !agent_path.exists() {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		// The key file is removed once in the agent.
+		let status = Command::new("ssh-keygen")
+			.args(["-q", "-t", "ed25519", "-N", "", "-f"])
+			.arg(dir.join("host"))
+			.status()
+			.await
+			.unwrap();
+
+		assert!(status.success());
+
+		let status = Command::new("ssh-add")
+			.arg(dir.join("host"))
+			.env("SSH_AUTH_SOCK", &agent_path)
+			.status()
+			.await
+			.unwrap();
+
+		assert!(status.success());
+
+		std::fs::remove_file(dir.join("host")).unwrap();
+
+		let mut agent = AgentClient::connect_uds(&agent_path).await.unwrap();
+
+		let host_key = agent.request_identities().await.unwrap().pop().unwrap();
+
+		let mut config = server::Config::default();
+
+		config.host_key_signers.push(Arc::new(AgentSigner::new(agent, host_key)));
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			server::run_stream(Arc::new(config), stream, Server {}).await.unwrap().await.ok();
+		});
+
+		// Only trusts the key in the agent.
+		let known_hosts = dir.join("known_hosts");
+
+		let public = std::fs::read_to_string(dir.join("host.pub")).unwrap();
+
+		std::fs::write(&known_hosts, format!("[127.0.0.1]:{} {}", port, public)).unwrap();
+
+		let mut ssh = Command::new("ssh")
+			.args(["-v", "-N", "-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=yes"])
+			.arg("-o")
+			.arg(format!("UserKnownHostsFile={}", known_hosts.display()))
+			.args(["-o", "BatchMode=yes"])
+			.arg("user@127.0.0.1")
+			.stdin(std::process::Stdio::null())
+			.stderr(std::process::Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		let mut lines = BufReader::new(ssh.stderr.take().unwrap()).lines();
+
+		let authenticated = tokio::time::timeout(Duration::from_secs(30), async {
+			while let Some(line) = lines.next_line().await.unwrap() {
+				if line.starts_with("Authenticated to") {
+					return true;
+				}
+			}
+
+			false
+		})
+		.await
+		.unwrap();
+
+		assert!(authenticated);
+
+		ssh.kill().await.ok();
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// `ssh -o SendEnv=FOO` passing `FOO` to a russh server, then sending
+	/// a break with its `~B` escape, to which the server answers with the
+	/// variable and the break length. Run with `cargo test -- --ignored`,
+	/// with `ssh` in the `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_env_break_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{net::TcpListener, process::Command};
+
+		use crate::Pty;
+
+		struct ServerHandle {
+			foo:Option<String>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn pty_request(
+				self,
+				channel:ChannelId,
+				_:&str,
+				_:u32,
+				_:u32,
+				_:u32,
+				_:u32,
+				_:&[(Pty, u32)],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				Ok((self, session))
+			}
+
+			async fn env_request(
+				mut self,
+				_:ChannelId,
+				variable_name:&str,
+				variable_value:&str,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if variable_name == "FOO" {
+					self.foo = Some(variable_value.to_string());
+				}
+
+				Ok((self, true, session))
+			}
+
+			async fn shell_request(
+				self,
+				channel:ChannelId,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				Ok((self, session))
+			}
+
+			async fn break_request(
+				self,
+				channel:ChannelId,
+				break_length:u32,
+				mut session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let reply = format!("FOO={:?} break {}\r\n", self.foo, break_length);
+
+				session.data(channel, CryptoVec::from(reply))?;
+
+				session.send_exit_and_close(channel, 0);
+
+				Ok((self, true, session))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			server::run_stream(Arc::new(config), stream, ServerHandle { foo:None })
+				.await
+				.unwrap()
+				.await
+				.ok();
+		});
+
+		let mut ssh = Command::new("ssh")
+			.args(["-tt", "-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.args(["-o", "SendEnv=FOO"])
+			.env("FOO", "bar")
+			.arg("user@127.0.0.1")
+			.stdin(std::process::Stdio::piped())
+			.stdout(std::process::Stdio::piped())
+			.stderr(std::process::Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		let mut stdin = ssh.stdin.take().unwrap();
+
+		// The escape character is only recognised at the start of a line.
+		stdin.write_all(b"\r~B").await.unwrap();
+
+		let output =
+			tokio::time::timeout(Duration::from_secs(30), ssh.wait_with_output()).await.unwrap();
+
+		let output = output.unwrap();
+
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		assert!(output.status.success(), "{}", stderr);
+
+		assert_eq!(output.stdout, b"FOO=Some(\"bar\") break 1000\r\n");
+	}
+
+	/// `ssh` connecting to both addresses of a russh server listening on
+	/// the IPv4 and IPv6 loopbacks, with `TCP_NODELAY`. Run with `cargo
+	/// test -- --ignored`, with `ssh` in the `PATH` and IPv6 enabled.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_run_dual_stack_openssh_client() {
+		use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+		use tokio::process::Command;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				session.data(channel, CryptoVec::from_slice(b"ok\n"))?;
+
+				session.send_exit_and_close(channel, 0);
+
+				Ok((self, session))
+			}
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		config.nodelay = true;
+
+		let addrs:&[SocketAddr] = &["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+
+		let server =
+			server::start(Arc::new(config), addrs, |_:SocketAddr| ServerHandle {}).await.unwrap();
+
+		assert_eq!(server.local_addrs().len(), 2);
+
+		for addr in server.local_addrs() {
+			let ssh = Command::new("ssh")
+				.args(["-p", &addr.port().to_string()])
+				.args(["-o", "StrictHostKeyChecking=no"])
+				.args(["-o", "UserKnownHostsFile=/dev/null"])
+				.args(["-o", "BatchMode=yes"])
+				.arg(format!("user@{}", addr.ip()))
+				.arg("true")
+				.stdin(std::process::Stdio::null())
+				.output();
+
+			let output = tokio::time::timeout(Duration::from_secs(30), ssh).await.unwrap().unwrap();
+
+			let stderr = String::from_utf8_lossy(&output.stderr);
+
+			assert!(output.status.success(), "{}: {}", addr, stderr);
+
+			assert_eq!(output.stdout, b"ok\n");
+		}
+
+		assert!(server.local_addrs().iter().any(|addr| addr.is_ipv6()));
+
+		server.shutdown(Duration::from_secs(1)).await;
 	}
 }
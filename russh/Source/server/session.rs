@@ -1,10 +1,25 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::Arc,
+};
 
 use log::debug;
-use russh_keys::encoding::{Encoding, Reader};
+use russh_keys::{
+	PublicKeyBase64,
+	encoding::{Encoding, Position, Reader},
+	key,
+};
 use tokio::{
 	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
-	sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, unbounded_channel},
+	sync::mpsc::{
+		Receiver,
+		Sender,
+		UnboundedReceiver,
+		UnboundedSender,
+		error::TrySendError,
+		unbounded_channel,
+	},
+	time::Instant,
 };
 
 use super::*;
@@ -23,6 +38,40 @@ pub struct Session {
 	pub(crate) pending_reads:Vec<CryptoVec>,
 	pub(crate) pending_len:u32,
 	pub(crate) channels:HashMap<ChannelId, UnboundedSender<ChannelMsg>>,
+	/// The keepalives sent since the last reply to one.
+	pub(crate) keepalives_unanswered:u32,
+	/// The global requests sent with `want_reply`, in order.
+	pub(crate) pending_global_requests:VecDeque<GlobalRequest>,
+	/// Whether the client sent `no-more-sessions@openssh.com`, after
+	/// which session channels are refused.
+	pub(crate) no_more_sessions:bool,
+	/// The place of the connection in the limits of [`run`] and [`start`].
+	pub(crate) admission:Admission,
+	/// When the client must be authenticated by, see
+	/// [`Config::auth_timeout`].
+	pub(crate) auth_deadline:Option<Instant>,
+	/// How to refuse the channel being opened, see
+	/// [`Session::channel_open_failure`].
+	pub(crate) open_failure:Option<OpenFailure>,
+}
+
+/// A refusal of a channel open, chosen by the handler.
+#[derive(Debug)]
+pub(crate) struct OpenFailure {
+	pub channel:ChannelId,
+	pub reason:ChannelOpenFailure,
+	pub description:String,
+	pub language:String,
+}
+
+/// A global request waiting for its reply.
+#[derive(Debug)]
+pub(crate) enum GlobalRequest {
+	/// `keepalive@openssh.com`.
+	Keepalive,
+	/// A request of [`Handle::global_request`], replying with the data of
+	/// its success, or `None` on failure.
+	Reply(tokio::sync::oneshot::Sender<Option<Vec<u8>>>),
 }
 #[derive(Debug)]
 pub enum Msg {
@@ -48,6 +97,13 @@ pub enum Msg {
 		originator_port:u32,
 		sender:UnboundedSender<ChannelMsg>,
 	},
+	ChannelOpenForwardedStreamLocal {
+		socket_path:String,
+		sender:UnboundedSender<ChannelMsg>,
+	},
+	ChannelOpenAgent {
+		sender:UnboundedSender<ChannelMsg>,
+	},
 	TcpIpForward {
 		address:String,
 		port:u32,
@@ -56,6 +112,58 @@ pub enum Msg {
 		address:String,
 		port:u32,
 	},
+	Rekey {
+		reply:tokio::sync::oneshot::Sender<Result<(), Error>>,
+	},
+	NegotiatedAlgorithms {
+		reply:tokio::sync::oneshot::Sender<Option<crate::Negotiated>>,
+	},
+	SessionIds {
+		reply:tokio::sync::oneshot::Sender<Option<(CryptoVec, CryptoVec)>>,
+	},
+	Disconnect {
+		reason:Disconnect,
+		description:String,
+		language_tag:String,
+	},
+	GlobalRequest {
+		name:String,
+		payload:Vec<u8>,
+		reply:Option<tokio::sync::oneshot::Sender<Option<Vec<u8>>>>,
+	},
+	Data {
+		id:ChannelId,
+		ext:Option<u32>,
+		data:CryptoVec,
+		sent:Option<tokio::sync::oneshot::Sender<()>>,
+	},
+	ExitAndClose {
+		id:ChannelId,
+		exit_status:u32,
+	},
+	Flush {
+		id:ChannelId,
+		flushed:tokio::sync::oneshot::Sender<()>,
+	},
+	Eof {
+		id:ChannelId,
+		reply:tokio::sync::oneshot::Sender<Result<(), Error>>,
+	},
+	Close {
+		id:ChannelId,
+		reply:tokio::sync::oneshot::Sender<Result<(), Error>>,
+	},
+	Debug {
+		always_display:bool,
+		message:String,
+	},
+	Ignore {
+		payload_len:usize,
+	},
+	ChannelClosed {
+		id:ChannelId,
+		closed:tokio::sync::oneshot::Sender<()>,
+	},
 	Channel(ChannelId, ChannelMsg),
 }
 
@@ -68,43 +176,226 @@ impl From<(ChannelId, ChannelMsg)> for Msg {
 /// the request/response cycle.
 pub struct Handle {
 	pub(crate) sender:Sender<Msg>,
+	pub(crate) backlogs:Arc<Backlogs>,
+	pub(crate) stats:Arc<Stats>,
 }
 
 impl Handle {
-	/// Send data to the session referenced by this handler.
-	pub async fn data(&self, id:ChannelId, data:CryptoVec) -> Result<(), CryptoVec> {
+	/// Re-exchange the session keys, and wait until the new keys are in
+	/// use. Channel data sent in the meantime is queued, and sent with the
+	/// new keys.
+	///
+	/// Returns [`Error::RekeyInProgress`] if a key exchange is already
+	/// running, and [`Error::Inconsistent`] if none could be started.
+	pub async fn rekey(&self) -> Result<(), Error> {
+		let (reply, done) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Rekey { reply }).await.map_err(|_| Error::SendError)?;
+
+		done.await.map_err(|_| Error::Disconnect)?
+	}
+
+	/// Algorithms negotiated by the last key exchange, or `None` before
+	/// the first one has completed. This is updated after each rekey.
+	pub async fn negotiated_algorithms(&self) -> Result<Option<crate::Negotiated>, Error> {
+		let (reply, negotiated) = tokio::sync::oneshot::channel();
+
 		self.sender
-			.send(Msg::Channel(id, ChannelMsg::Data { data }))
+			.send(Msg::NegotiatedAlgorithms { reply })
 			.await
-			.map_err(|e| {
-				match e.0 {
-					Msg::Channel(_, ChannelMsg::Data { data }) => data,
-					_ => unreachable!(),
-				}
+			.map_err(|_| Error::SendError)?;
+
+		negotiated.await.map_err(|_| Error::Disconnect)
+	}
+
+	/// The session identifier, i.e. the exchange hash of the initial key
+	/// exchange, or `None` before it has completed. It stays the same
+	/// after a rekey (RFC 4253, section 7.2).
+	pub async fn session_id(&self) -> Result<Option<CryptoVec>, Error> {
+		Ok(self.session_ids().await?.map(|(session_id, _)| session_id))
+	}
+
+	/// The exchange hash of the last key exchange, or `None` before the
+	/// first one has completed.
+	pub async fn exchange_hash(&self) -> Result<Option<CryptoVec>, Error> {
+		Ok(self.session_ids().await?.map(|(_, exchange_hash)| exchange_hash))
+	}
+
+	async fn session_ids(&self) -> Result<Option<(CryptoVec, CryptoVec)>, Error> {
+		let (reply, ids) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::SessionIds { reply }).await.map_err(|_| Error::SendError)?;
+
+		ids.await.map_err(|_| Error::Disconnect)
+	}
+
+	/// Send the global request `name`, with its request-specific
+	/// `payload`. With `want_reply`, this waits for the reply of the
+	/// client, and returns the data of its success, or
+	/// [`Error::GlobalRequestFailure`]. Replies are matched with requests
+	/// in the order they were sent.
+	pub async fn global_request<N:Into<String>>(
+		&self,
+		name:N,
+		want_reply:bool,
+		payload:&[u8],
+	) -> Result<Option<Vec<u8>>, Error> {
+		let (reply, replied) = tokio::sync::oneshot::channel();
+
+		self.sender
+			.send(Msg::GlobalRequest {
+				name:name.into(),
+				payload:payload.to_vec(),
+				reply:if want_reply { Some(reply) } else { None },
+			})
+			.await
+			.map_err(|_| Error::SendError)?;
+
+		if !want_reply {
+			return Ok(None);
+		}
+
+		match replied.await.map_err(|_| Error::Disconnect)? {
+			Some(data) => Ok(Some(data)),
+			None => Err(Error::GlobalRequestFailure),
+		}
+	}
+
+	/// Sends a disconnect message, and waits until the messages sent
+	/// before it are written and the client has closed the connection.
+	/// Channels of the session see their messages end.
+	pub async fn disconnect(
+		&self,
+		reason:Disconnect,
+		description:&str,
+		language_tag:&str,
+	) -> Result<(), Error> {
+		self.sender
+			.send(Msg::Disconnect {
+				reason,
+				description:description.into(),
+				language_tag:language_tag.into(),
 			})
+			.await
+			.map_err(|_| Error::SendError)?;
+
+		// The session drops its receiver once the stream is closed.
+		self.sender.closed().await;
+
+		Ok(())
+	}
+
+	/// Send a debug message to the client, which OpenSSH logs at the `DEBUG`
+	/// level, or shows to the user if `always_display`.
+	pub async fn send_debug(&self, always_display:bool, message:&str) -> Result<(), Error> {
+		self.sender
+			.send(Msg::Debug { always_display, message:message.into() })
+			.await
+			.map_err(|_| Error::SendError)
+	}
+
+	/// Send `payload_len` random bytes the client discards, in ignore
+	/// messages, for instance to pad the traffic against its analysis.
+	pub async fn send_ignore(&self, payload_len:usize) -> Result<(), Error> {
+		self.sender.send(Msg::Ignore { payload_len }).await.map_err(|_| Error::SendError)
 	}
 
+	/// Wait until the session has ended, and its connection is closed.
+	pub async fn closed(&self) { self.sender.closed().await }
+
+	/// Whether the session has ended.
+	pub fn is_closed(&self) -> bool { self.sender.is_closed() }
+
 	/// Send data to the session referenced by this handler.
+	///
+	/// The returned future completes once the data is sent within the
+	/// client's window, but for [`Config::channel_buffer_size`] bytes left
+	/// queued, so that a client not reading its channel holds writers back
+	/// instead of filling our memory. It fails with [`Error::SendError`]
+	/// if the session is closed, and with [`Error::WrongChannel`] if the
+	/// channel is unknown, or closed before the data is sent.
+	/// [`Handle::flush`] waits for the rest.
+	/// Since the session replies, this must not be awaited from a
+	/// [`Handler`] method of the same session.
+	pub async fn data(&self, id:ChannelId, data:CryptoVec) -> Result<(), Error> {
+		self.write(id, None, data).await
+	}
+
+	/// Send data to the session referenced by this handler, waiting as
+	/// [`Handle::data`].
 	pub async fn extended_data(
 		&self,
 		id:ChannelId,
 		ext:u32,
 		data:CryptoVec,
-	) -> Result<(), CryptoVec> {
+	) -> Result<(), Error> {
+		self.write(id, Some(ext), data).await
+	}
+
+	async fn write(&self, id:ChannelId, ext:Option<u32>, data:CryptoVec) -> Result<(), Error> {
+		let (sent, sent_receiver) = tokio::sync::oneshot::channel();
+
 		self.sender
-			.send(Msg::Channel(id, ChannelMsg::ExtendedData { ext, data }))
+			.send(Msg::Data { id, ext, data, sent:Some(sent) })
 			.await
-			.map_err(|e| {
-				match e.0 {
-					Msg::Channel(_, ChannelMsg::ExtendedData { data, .. }) => data,
-					_ => unreachable!(),
-				}
-			})
+			.map_err(|_| Error::SendError)?;
+
+		// The session drops `sent` if the channel is closed.
+		sent_receiver.await.map_err(|_| Error::WrongChannel)
+	}
+
+	/// Wait until the data written to channel `id` is sent: none is left
+	/// waiting for the client's window, and the packets are written to
+	/// the connection. Data written meanwhile is waited for too. Fails
+	/// with [`Error::WrongChannel`] if the channel is unknown, or closed
+	/// first.
+	/// Since the session replies, this must not be awaited from a
+	/// [`Handler`] method of the same session.
+	pub async fn flush(&self, id:ChannelId) -> Result<(), Error> {
+		let (flushed, flushed_receiver) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Flush { id, flushed }).await.map_err(|_| Error::SendError)?;
+
+		flushed_receiver.await.map_err(|_| Error::WrongChannel)
 	}
 
-	/// Send EOF to the session referenced by this handler.
-	pub async fn eof(&self, id:ChannelId) -> Result<(), ()> {
-		self.sender.send(Msg::Channel(id, ChannelMsg::Eof)).await.map_err(|_| ())
+	/// Wait until channel `id` is closed, which is when the client closes
+	/// it or replies to our close. Returns at once if there is no such
+	/// channel, and when the session ends.
+	pub async fn channel_closed(&self, id:ChannelId) {
+		let (closed, closed_receiver) = tokio::sync::oneshot::channel();
+
+		if self.sender.send(Msg::ChannelClosed { id, closed }).await.is_ok() {
+			closed_receiver.await.unwrap_or(())
+		}
+	}
+
+	/// The traffic and event counters of this connection, cheap to read.
+	pub fn stats(&self) -> ConnectionStats { self.stats.snapshot() }
+
+	/// Send data without waiting. The data is returned if the channel has
+	/// more than [`Config::channel_buffer_size`] bytes waiting for the
+	/// client's window, or if the session is busy or closed.
+	pub fn try_data(&self, id:ChannelId, data:CryptoVec) -> Result<(), CryptoVec> {
+		if !self.backlogs.has_room(id, data.len()) {
+			return Err(data);
+		}
+
+		self.sender.try_send(Msg::Data { id, ext:None, data, sent:None }).map_err(|e| {
+			match e {
+				TrySendError::Full(msg) | TrySendError::Closed(msg) => returned_data(msg),
+			}
+		})
+	}
+
+	/// Send EOF to the session referenced by this handler. Fails with
+	/// [`Error::WrongChannel`] if the channel is unknown or closed.
+	pub async fn eof(&self, id:ChannelId) -> Result<(), Error> {
+		let (reply, checked) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Eof { id, reply }).await.map_err(|_| Error::SendError)?;
+
+		checked.await.map_err(|_| Error::Disconnect)?
 	}
 
 	/// Send success to the session referenced by this handler.
@@ -117,9 +408,14 @@ impl Handle {
 		self.sender.send(Msg::Channel(id, ChannelMsg::Failure)).await.map_err(|_| ())
 	}
 
-	/// Close a channel.
-	pub async fn close(&self, id:ChannelId) -> Result<(), ()> {
-		self.sender.send(Msg::Channel(id, ChannelMsg::Close)).await.map_err(|_| ())
+	/// Close a channel. Fails with [`Error::WrongChannel`] if the channel
+	/// is unknown or already closed.
+	pub async fn close(&self, id:ChannelId) -> Result<(), Error> {
+		let (reply, checked) = tokio::sync::oneshot::channel();
+
+		self.sender.send(Msg::Close { id, reply }).await.map_err(|_| Error::SendError)?;
+
+		checked.await.map_err(|_| Error::Disconnect)?
 	}
 
 	/// Inform the client of whether they may perform
@@ -140,6 +436,13 @@ impl Handle {
 			.map_err(|_| ())
 	}
 
+	/// Finish a command: send its exit status, then EOF, and close the
+	/// channel, once the data sent before is out. See
+	/// [`Session::send_exit_and_close`].
+	pub async fn send_exit_and_close(&self, id:ChannelId, exit_status:u32) -> Result<(), ()> {
+		self.sender.send(Msg::ExitAndClose { id, exit_status }).await.map_err(|_| ())
+	}
+
 	/// Notifies the client that it can open TCP/IP forwarding channels for a
 	/// port.
 	pub async fn forward_tcpip(&self, address:String, port:u32) -> Result<(), ()> {
@@ -199,6 +502,12 @@ impl Handle {
 		self.wait_channel_confirmation(receiver).await
 	}
 
+	/// Open a channel for a connection to a port forwarded to the
+	/// client, see [`Handler::tcpip_forward`]. `connected_address` and
+	/// `connected_port` are those of the forwarding request, and the
+	/// originator is the peer of the connection. This waits for the
+	/// client's answer: a refusal is returned as
+	/// [`Error::ChannelOpenFailure`] with the client's reason.
 	pub async fn channel_open_forwarded_tcpip<A:Into<String>, B:Into<String>>(
 		&self,
 		connected_address:A,
@@ -222,6 +531,43 @@ impl Handle {
 		self.wait_channel_confirmation(receiver).await
 	}
 
+	/// Open a channel for a connection to a Unix domain socket forwarded
+	/// to the client, see [`Handler::streamlocal_forward`].
+	pub async fn channel_open_forwarded_streamlocal<A:Into<String>>(
+		&self,
+		socket_path:A,
+	) -> Result<Channel<Msg>, Error> {
+		let (sender, receiver) = unbounded_channel();
+
+		self.sender
+			.send(Msg::ChannelOpenForwardedStreamLocal { socket_path:socket_path.into(), sender })
+			.await
+			.map_err(|_| Error::SendError)?;
+
+		self.wait_channel_confirmation(receiver).await
+	}
+
+	/// Open an agent forwarding channel, on which the client serves its
+	/// SSH agent, for instance with an
+	/// [`AgentClient`](russh_keys::agent::client::AgentClient) over
+	/// [`Channel::into_stream`]. The client only accepts it if it has
+	/// requested agent forwarding, see [`Handler::agent_request`].
+	pub async fn channel_open_agent(&self) -> Result<Channel<Msg>, Error> {
+		let (sender, receiver) = unbounded_channel();
+
+		self.sender
+			.send(Msg::ChannelOpenAgent { sender })
+			.await
+			.map_err(|_| Error::SendError)?;
+
+		self.wait_channel_confirmation(receiver).await
+	}
+
+	/// Open a channel for a connection to the X11 display forwarded to
+	/// the client, after [`Handler::x11_request`], from the X client at
+	/// `originator_address` and `originator_port`. As with
+	/// [`Handle::channel_open_forwarded_tcpip`], this waits for the
+	/// client's answer.
 	pub async fn channel_open_x11<A:Into<String>>(
 		&self,
 		originator_address:A,
@@ -254,11 +600,16 @@ impl Handle {
 						receiver,
 						max_packet_size,
 						window_size,
+						close_info:Default::default(),
+						closed:false,
 					});
 				},
 				Some(ChannelMsg::OpenFailure(reason)) => {
 					return Err(Error::ChannelOpenFailure(reason));
 				},
+				Some(ChannelMsg::Disconnected { reason, description }) => {
+					return Err(Error::Disconnected { reason, description });
+				},
 				None => {
 					return Err(Error::Disconnect);
 				},
@@ -289,6 +640,27 @@ impl Handle {
 	}
 }
 
+/// The data of a [`Msg::Data`] the session did not take.
+fn returned_data(msg:Msg) -> CryptoVec {
+	match msg {
+		Msg::Data { data, .. } => data,
+		_ => CryptoVec::new(),
+	}
+}
+
+/// Signature algorithms accepted for public key authentication, sent to
+/// clients in `server-sig-algs`.
+const SERVER_SIG_ALGS:&[key::Name] = &[
+	#[cfg(feature = "rs-crypto")]
+	key::ED25519,
+	#[cfg(feature = "openssl")]
+	key::RSA_SHA2_512,
+	#[cfg(feature = "openssl")]
+	key::RSA_SHA2_256,
+	#[cfg(feature = "openssl")]
+	key::SSH_RSA,
+];
+
 impl Session {
 	pub(crate) fn is_rekeying(&self) -> bool {
 		if let Some(ref enc) = self.common.encrypted {
@@ -298,6 +670,13 @@ impl Session {
 		}
 	}
 
+	/// Whether the client is authenticated.
+	pub(crate) fn is_authenticated(&self) -> bool {
+		self.common.encrypted.as_ref().map_or(false, |enc| {
+			matches!(enc.state, EncryptedState::Authenticated | EncryptedState::InitCompression)
+		})
+	}
+
 	pub(crate) async fn run<H, R>(
 		mut self,
 		mut stream:SshRead<R>,
@@ -313,6 +692,8 @@ impl Session {
 			.await
 			.map_err(crate::Error::from)?;
 
+		self.common.stats.sent(&self.common.write_buffer);
+
 		self.common.write_buffer.buffer.clear();
 
 		let (stream_read, mut stream_write) = stream.split();
@@ -324,7 +705,9 @@ impl Session {
 
 		std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 
-		let reading = start_reading(stream_read, buffer, opening_cipher);
+		let max_packet_size = self.common.config.max_packet_size;
+
+		let reading = start_reading(stream_read, buffer, opening_cipher, max_packet_size);
 
 		pin!(reading);
 
@@ -334,13 +717,50 @@ impl Session {
 
 		let delay = self.common.config.connection_timeout;
 
+		let keepalive_interval = self.common.config.keepalive_interval;
+
+		let mut keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
+
+		let inactivity_timeout = self.common.config.inactivity_timeout;
+
+		let mut inactivity_deadline = inactivity_timeout.map(|t| Instant::now() + t);
+
+		// The error ending the session if the client disconnects.
+		let mut peer_disconnected = None;
+
+		// The error ending the session if it times out.
+		let mut timed_out = None;
+
 		#[allow(clippy::panic)] // false positive in macro
 		while !self.common.disconnected {
+			let time_until_rekey = self
+				.common
+				.encrypted
+				.as_ref()
+				.and_then(|enc| enc.time_until_rekey(&self.common.config.limits));
+
+			let keepalive_at = keepalive_deadline.unwrap_or_else(Instant::now);
+
+			let inactive_at = inactivity_deadline.unwrap_or_else(Instant::now);
+
+			// Only until authenticated.
+			let auth_deadline = self.auth_deadline.filter(|_| !self.is_authenticated());
+
+			let auth_at = auth_deadline.unwrap_or_else(Instant::now);
+
 			tokio::select! {
 				r = &mut reading => {
-					let (stream_read, buffer, mut opening_cipher) = match r {
+					keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
+
+					inactivity_deadline = inactivity_timeout.map(|t| Instant::now() + t);
+
+					let (stream_read, mut buffer, mut opening_cipher) = match r {
 						Ok((_, stream_read, buffer, opening_cipher)) => (stream_read, buffer, opening_cipher),
-						Err(e) => return Err(e.into())
+						Err(e) => {
+							handler.on_close(Some(&e));
+
+							return Err(e.into())
+						}
 					};
 
 					if buffer.buffer.len() < 5 {
@@ -353,6 +773,7 @@ impl Session {
 						let d = enc.decompress.decompress(
 							&buffer.buffer[5..],
 							&mut decomp,
+							max_packet_size,
 						);
 
 						if let Ok(buf) = d {
@@ -368,18 +789,22 @@ impl Session {
 						&buffer.buffer[5..]
 					};
 
+					self.common.stats.received(&buffer, buf.len());
+
 					if !buf.is_empty() {
 						#[allow(clippy::indexing_slicing)] // length checked
 						if buf[0] == crate::msg::DISCONNECT {
 							debug!("break");
 
+							peer_disconnected = Some(self.peer_disconnected(buf));
+
 							is_reading = Some((stream_read, buffer, opening_cipher));
 
 							break;
-						} else if buf[0] > 4 {
+						} else {
 							std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
 							// TODO it'd be cleaner to just pass cipher to reply()
-							match reply(self, handler, buf).await {
+							match reply(self, handler, &mut buffer.seqn, buf).await {
 								Ok((h, s)) => {
 									handler = h;
 
@@ -392,22 +817,59 @@ impl Session {
 						}
 					}
 
-					reading.set(start_reading(stream_read, buffer, opening_cipher));
+					if self.common.config.limits.read_limit_reached(&buffer) {
+						self.initiate_rekey()?;
+
+						buffer.bytes = 0;
+					}
+
+					reading.set(start_reading(stream_read, buffer, opening_cipher, max_packet_size));
 				}
 
+				// `flush` below starts the key exchange once the time limit is reached.
+				_ = tokio::time::sleep(time_until_rekey.unwrap_or_default()), if time_until_rekey.is_some() => {}
+
 				_ = timeout(delay) => {
 					debug!("timeout");
 
 					break
 				},
+				_ = tokio::time::sleep_until(keepalive_at), if keepalive_deadline.is_some() => {
+					self.send_keepalive()?;
+
+					keepalive_deadline = keepalive_interval.map(|i| Instant::now() + i);
+				},
+				_ = tokio::time::sleep_until(inactive_at), if inactivity_deadline.is_some() => {
+					debug!("inactivity timeout");
+
+					self.common.disconnect(Disconnect::ByApplication, "Inactivity timeout", "en");
+
+					timed_out = Some(Error::InactivityTimeout);
+				},
+				_ = tokio::time::sleep_until(auth_at), if auth_deadline.is_some() => {
+					debug!("authentication timeout");
+
+					self.common.disconnect(
+						Disconnect::NoMoreAuthMethodsAvailable,
+						"Authentication timeout",
+						"en",
+					);
+
+					timed_out = Some(Error::AuthTimeout);
+				},
 				msg = self.receiver.recv(), if !self.is_rekeying() => {
 					match msg {
+						Some(Msg::Data { id, ext, data, sent }) => {
+							self.handle_data(id, ext, data, sent);
+						}
+
+						// Channels drop their data once closed.
 						Some(Msg::Channel(id, ChannelMsg::Data { data })) => {
-							self.data(id, data);
+							self.data(id, data).unwrap_or(());
 						}
 
 						Some(Msg::Channel(id, ChannelMsg::ExtendedData { ext, data })) => {
-							self.extended_data(id, ext, data);
+							self.extended_data(id, ext, data).unwrap_or(());
 						}
 
 						Some(Msg::Channel(id, ChannelMsg::Eof)) => {
@@ -434,6 +896,42 @@ impl Session {
 							self.exit_status_request(id, exit_status);
 						}
 
+						Some(Msg::ExitAndClose { id, exit_status }) => {
+							self.send_exit_and_close(id, exit_status);
+						}
+
+						Some(Msg::Flush { id, flushed }) => {
+							if let Some(ref mut enc) = self.common.encrypted {
+								enc.wait_flushed(id, flushed);
+							}
+						}
+
+						Some(Msg::Eof { id, reply }) => {
+							let eof = self.common.check_channel(id).map(|()| self.eof(id));
+
+							reply.send(eof).unwrap_or(());
+						}
+
+						Some(Msg::Close { id, reply }) => {
+							let close = self.common.check_channel(id).map(|()| self.close(id));
+
+							reply.send(close).unwrap_or(());
+						}
+
+						Some(Msg::Debug { always_display, message }) => {
+							self.send_debug(always_display, &message);
+						}
+
+						Some(Msg::Ignore { payload_len }) => {
+							self.send_ignore(payload_len);
+						}
+
+						Some(Msg::ChannelClosed { id, closed }) => {
+							if let Some(ref mut enc) = self.common.encrypted {
+								enc.wait_closed(id, closed);
+							}
+						}
+
 						Some(Msg::Channel(id, ChannelMsg::ExitSignal { signal_name, core_dumped, error_message, lang_tag })) => {
 							self.exit_signal_request(id, signal_name, core_dumped, &error_message, &lang_tag);
 						}
@@ -466,6 +964,18 @@ impl Session {
 							self.channels.insert(id, sender);
 						}
 
+						Some(Msg::ChannelOpenForwardedStreamLocal { socket_path, sender }) => {
+							let id = self.channel_open_forwarded_streamlocal(&socket_path)?;
+
+							self.channels.insert(id, sender);
+						}
+
+						Some(Msg::ChannelOpenAgent { sender }) => {
+							let id = self.channel_open_agent()?;
+
+							self.channels.insert(id, sender);
+						}
+
 						Some(Msg::TcpIpForward { address, port }) => {
 							self.tcpip_forward(&address, port);
 						}
@@ -474,6 +984,30 @@ impl Session {
 							self.cancel_tcpip_forward(&address, port);
 						}
 
+						Some(Msg::Rekey { reply }) => {
+							self.request_rekey(reply)?;
+						}
+
+						Some(Msg::NegotiatedAlgorithms { reply }) => {
+							reply.send(self.negotiated_algorithms().cloned()).unwrap_or(());
+						}
+
+						Some(Msg::Disconnect { reason, description, language_tag }) => {
+							self.disconnect(reason, &description, &language_tag);
+						}
+
+						Some(Msg::GlobalRequest { name, payload, reply }) => {
+							self.global_request(&name, reply, &payload);
+						}
+
+						Some(Msg::SessionIds { reply }) => {
+							let ids = self.common.encrypted.as_ref().map(|enc| {
+								(enc.session_id.clone(), enc.exchange_hash.clone())
+							});
+
+							reply.send(ids).unwrap_or(());
+						}
+
 						Some(_) => {
 							// should be unreachable, since the receiver only gets
 							// messages from methods implemented within russh
@@ -494,19 +1028,37 @@ impl Session {
 				.await
 				.map_err(crate::Error::from)?;
 
-			self.common.write_buffer.buffer.clear();
+			self.common.stats.sent(&self.common.write_buffer);
+
+			crate::pool::release(&mut self.common.write_buffer.buffer);
+
+			if let Some(ref mut enc) = self.common.encrypted {
+				enc.wake_flushes();
+			}
 		}
 
 		debug!("disconnected");
 		// Shutdown
 		stream_write.shutdown().await.map_err(crate::Error::from)?;
 
+		// A stalled client may never close its side.
+		if let Some(e) = timed_out {
+			handler.on_close(Some(&e));
+
+			return Err(e.into());
+		}
+
 		loop {
 			if let Some((stream_read, buffer, opening_cipher)) = is_reading.take() {
-				reading.set(start_reading(stream_read, buffer, opening_cipher));
+				reading.set(start_reading(stream_read, buffer, opening_cipher, max_packet_size));
 			}
 
-			let (n, r, b, opening_cipher) = (&mut reading).await?;
+			let (n, r, b, opening_cipher) = match (&mut reading).await {
+				Ok(read) => read,
+				// The client closed the connection in turn.
+				Err(Error::IO(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+				Err(e) => return Err(e.into()),
+			};
 
 			is_reading = Some((r, b, opening_cipher));
 
@@ -515,7 +1067,12 @@ impl Session {
 			}
 		}
 
-		Ok(())
+		handler.on_close(peer_disconnected.as_ref());
+
+		match peer_disconnected {
+			Some(e) => Err(e.into()),
+			None => Ok(()),
+		}
 	}
 
 	/// Get a handle to this session.
@@ -553,17 +1110,57 @@ impl Session {
 
 	/// Flush the session, i.e. encrypt the pending buffer.
 	pub fn flush(&mut self) -> Result<(), Error> {
-		if let Some(ref mut enc) = self.common.encrypted {
-			if enc.flush(
+		let rekey = if let Some(ref mut enc) = self.common.encrypted {
+			enc.flush(
 				&self.common.config.as_ref().limits,
 				&mut *self.common.cipher.local_to_remote,
 				&mut self.common.write_buffer,
-			)? && enc.rekey.is_none()
-			{
+			)?
+		} else {
+			false
+		};
+
+		if rekey {
+			self.initiate_rekey()?;
+		}
+
+		Ok(())
+	}
+
+	/// Start a key exchange on behalf of [`Handle::rekey`].
+	fn request_rekey(
+		&mut self,
+		reply:tokio::sync::oneshot::Sender<Result<(), Error>>,
+	) -> Result<(), Error> {
+		if self.common.encrypted.as_ref().map(|enc| enc.is_rekeying()).unwrap_or(true) {
+			reply.send(Err(Error::RekeyInProgress)).unwrap_or(());
+
+			return Ok(());
+		}
+
+		self.initiate_rekey()?;
+
+		match self.common.encrypted {
+			Some(ref mut enc) if enc.rekey.is_some() => {
+				enc.rekey_done = Some(reply);
+			},
+			// No KEXINIT was sent, there is nothing to wait for.
+			_ => {
+				reply.send(Err(Error::Inconsistent)).unwrap_or(());
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Send a KEXINIT, unless a key exchange is already running.
+	pub(crate) fn initiate_rekey(&mut self) -> Result<(), Error> {
+		if let Some(ref mut enc) = self.common.encrypted {
+			if enc.rekey.is_none() {
 				debug!("starting rekeying");
 
 				if let Some(exchange) = enc.exchange.take() {
-					let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
+					let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id, enc.none_cipher);
 
 					kexinit.server_write(
 						self.common.config.as_ref(),
@@ -606,13 +1203,62 @@ impl Session {
 	/// Retrieves the configuration of this session.
 	pub fn config(&self) -> &Config { &self.common.config }
 
+	/// The address of the client, if known, and the version strings of
+	/// both sides, as exchanged before any handler method is called.
+	pub fn connection_info(&self) -> &crate::ConnectionInfo { &self.common.info }
+
+	/// Algorithms negotiated by the last key exchange, or `None` before
+	/// the first one has completed.
+	pub fn negotiated_algorithms(&self) -> Option<&crate::Negotiated> {
+		self.common.encrypted.as_ref().map(|enc| &enc.negotiated)
+	}
+
+	/// The session identifier, i.e. the exchange hash of the initial key
+	/// exchange, or `None` before it has completed. It stays the same
+	/// after a rekey.
+	pub fn session_id(&self) -> Option<&[u8]> {
+		self.common.encrypted.as_ref().map(|enc| enc.session_id.as_ref())
+	}
+
+	/// The exchange hash of the last key exchange, or `None` before the
+	/// first one has completed.
+	pub fn exchange_hash(&self) -> Option<&[u8]> {
+		self.common.encrypted.as_ref().map(|enc| enc.exchange_hash.as_ref())
+	}
+
+	/// Send a debug message, which the peer may log, or show to its user
+	/// if `always_display`.
+	pub fn send_debug(&mut self, always_display:bool, message:&str) {
+		self.common.send_debug(always_display, message, "");
+	}
+
+	/// Send `payload_len` random bytes the peer discards, for instance to
+	/// pad the traffic.
+	pub fn send_ignore(&mut self, payload_len:usize) { self.common.send_ignore(payload_len); }
+
 	/// Sends a disconnect message.
 	pub fn disconnect(&mut self, reason:Disconnect, description:&str, language_tag:&str) {
 		self.common.disconnect(reason, description, language_tag);
 	}
 
-	/// Send a "success" reply to a /global/ request (requests without
-	/// a channel number, such as TCP/IP forwarding or
+	/// End the session on the disconnect message `buf` of the peer, passing
+	/// its reason on to the open channels.
+	pub(crate) fn peer_disconnected(&mut self, buf:&[u8]) -> Error {
+		let error = crate::session::disconnected_by_peer(buf);
+
+		if let crate::Error::Disconnected { reason, ref description } = error {
+			for sender in self.channels.values() {
+				let msg = ChannelMsg::Disconnected { reason, description:description.clone() };
+
+				sender.send(msg).unwrap_or(());
+			}
+		}
+
+		error
+	}
+
+	/// Send a "success" reply to a /global/ request (requests without
+	/// a channel number, such as TCP/IP forwarding or
 	/// cancelling). Always call this function if the request was
 	/// successful (it checks whether the client expects an answer).
 	pub fn request_success(&mut self) {
@@ -657,7 +1303,8 @@ impl Session {
 		}
 	}
 
-	/// Send a "failure" reply to a global request.
+	/// Send a "failure" reply to a channel request, if the client expects
+	/// an answer.
 	pub fn channel_failure(&mut self, channel:ChannelId) {
 		if let Some(ref mut enc) = self.common.encrypted {
 			if let Some(channel) = enc.channels.get_mut(&channel) {
@@ -676,7 +1323,11 @@ impl Session {
 		}
 	}
 
-	/// Send a "failure" reply to a request to open a channel open.
+	/// Choose the reason and description sent to the client when a
+	/// `channel_open_*` method of the [`Handler`] refuses `channel`, by
+	/// returning `false`, instead of
+	/// [`ChannelOpenFailure::AdministrativelyProhibited`] and `Rejected`.
+	/// Does nothing for other channels.
 	pub fn channel_open_failure(
 		&mut self,
 		channel:ChannelId,
@@ -684,24 +1335,22 @@ impl Session {
 		description:&str,
 		language:&str,
 	) {
-		if let Some(ref mut enc) = self.common.encrypted {
-			push_packet!(enc.write, {
-				enc.write.push(msg::CHANNEL_OPEN_FAILURE);
-
-				enc.write.push_u32_be(channel.0);
-
-				enc.write.push_u32_be(reason as u32);
-
-				enc.write.extend_ssh_string(description.as_bytes());
+		self.open_failure = Some(OpenFailure {
+			channel,
+			reason,
+			description:description.to_string(),
+			language:language.to_string(),
+		});
+	}
 
-				enc.write.extend_ssh_string(language.as_bytes());
-			})
+	/// Close a channel. Nothing more is sent on it, and what the client
+	/// sends on it until it closes it too is discarded.
+	pub fn close(&mut self, channel:ChannelId) {
+		if let Some(ref mut enc) = self.common.encrypted {
+			enc.close(channel)
 		}
 	}
 
-	/// Close a channel.
-	pub fn close(&mut self, channel:ChannelId) { self.common.byte(channel, msg::CHANNEL_CLOSE); }
-
 	/// Send EOF to a channel
 	pub fn eof(&mut self, channel:ChannelId) { self.common.byte(channel, msg::CHANNEL_EOF); }
 
@@ -709,27 +1358,64 @@ impl Session {
 	/// used to encode standard error by passing `Some(1)`, and stdout
 	/// by passing `None`.
 	///
-	/// The number of bytes added to the "sending pipeline" (to be
-	/// processed by the event loop) is returned.
-	pub fn data(&mut self, channel:ChannelId, data:CryptoVec) {
+	/// Fails with [`Error::WrongChannel`] if the channel is unknown or
+	/// closed.
+	pub fn data(&mut self, channel:ChannelId, data:CryptoVec) -> Result<(), Error> {
+		self.common.check_channel(channel)?;
+
 		if let Some(ref mut enc) = self.common.encrypted {
 			enc.data(channel, data)
-		} else {
-			unreachable!()
 		}
+
+		Ok(())
 	}
 
 	/// Send data to a channel. On session channels, `extended` can be
 	/// used to encode standard error by passing `Some(1)`, and stdout
 	/// by passing `None`.
 	///
-	/// The number of bytes added to the "sending pipeline" (to be
-	/// processed by the event loop) is returned.
-	pub fn extended_data(&mut self, channel:ChannelId, extended:u32, data:CryptoVec) {
+	/// Fails with [`Error::WrongChannel`] if the channel is unknown or
+	/// closed.
+	pub fn extended_data(
+		&mut self,
+		channel:ChannelId,
+		extended:u32,
+		data:CryptoVec,
+	) -> Result<(), Error> {
+		self.common.check_channel(channel)?;
+
 		if let Some(ref mut enc) = self.common.encrypted {
 			enc.extended_data(channel, extended, data)
-		} else {
-			unreachable!()
+		}
+
+		Ok(())
+	}
+
+	/// Send data written through a [`Handle`], replying on `sent` once it
+	/// is sent, but for [`Config::channel_buffer_size`] bytes.
+	pub(crate) fn handle_data(
+		&mut self,
+		channel:ChannelId,
+		extended:Option<u32>,
+		data:CryptoVec,
+		sent:Option<tokio::sync::oneshot::Sender<()>>,
+	) {
+		let written = match extended {
+			Some(extended) => self.extended_data(channel, extended, data),
+			None => self.data(channel, data),
+		};
+
+		// Dropping `sent` fails the writer with `Error::WrongChannel`.
+		if written.is_err() {
+			return;
+		}
+
+		if let Some(ref mut enc) = self.common.encrypted {
+			if let Some(sent) = sent {
+				enc.wait_sent(channel, sent);
+			}
+
+			enc.wake_writers(&self.common.backlogs);
 		}
 	}
 
@@ -777,6 +1463,17 @@ impl Session {
 		}
 	}
 
+	/// Finish a command: send its exit status, then EOF, and close the
+	/// channel. Unlike [`Session::exit_status_request`],
+	/// [`Session::eof`] and [`Session::close`], these messages wait for
+	/// the data still queued on the channel for the client's window, so
+	/// that the client sees all of the output before the exit status.
+	pub fn send_exit_and_close(&mut self, channel:ChannelId, exit_status:u32) {
+		if let Some(ref mut enc) = self.common.encrypted {
+			enc.exit_and_close(channel, exit_status)
+		}
+	}
+
 	/// If the program was killed by a signal, send the details about the signal
 	/// to the client.
 	pub fn exit_signal_request(
@@ -857,6 +1554,20 @@ impl Session {
 		})
 	}
 
+	/// Open a `forwarded-streamlocal@openssh.com` channel, when a
+	/// connection comes to a Unix domain socket for which forwarding has
+	/// been requested, see [`Handler::streamlocal_forward`].
+	pub fn channel_open_forwarded_streamlocal(
+		&mut self,
+		socket_path:&str,
+	) -> Result<ChannelId, Error> {
+		self.channel_open_generic(b"forwarded-streamlocal@openssh.com", |write| {
+			write.extend_ssh_string(socket_path.as_bytes());
+
+			write.extend_ssh_string(b""); // reserved
+		})
+	}
+
 	/// Open a new X11 channel, when a connection comes to a
 	/// local port. See [RFC4254](https://tools.ietf.org/html/rfc4254#section-6.3.2).
 	/// TCP/IP packets can then be tunneled through the channel using `.data()`.
@@ -891,6 +1602,8 @@ impl Session {
 				self.common.config.maximum_packet_size,
 			);
 
+			self.common.stats.channel_opened();
+
 			push_packet!(enc.write, {
 				enc.write.push(msg::CHANNEL_OPEN);
 
@@ -952,6 +1665,149 @@ impl Session {
 		}
 	}
 
+	/// Send a `keepalive@openssh.com` global request, or fail if the
+	/// last [`Config::keepalive_max`] ones were not answered. Nothing is
+	/// sent before authentication. During a key exchange nothing is sent
+	/// either, but the silence of the client still counts.
+	pub(crate) fn send_keepalive(&mut self) -> Result<(), Error> {
+		let rekeying = self.is_rekeying();
+
+		let enc = match self.common.encrypted {
+			Some(ref mut enc) if matches!(enc.state, EncryptedState::Authenticated) => enc,
+			_ => return Ok(()),
+		};
+
+		if self.keepalives_unanswered >= self.common.config.keepalive_max {
+			return Err(Error::KeepaliveTimeout);
+		}
+
+		self.keepalives_unanswered += 1;
+
+		if !rekeying {
+			push_packet!(enc.write, {
+				enc.write.push(msg::GLOBAL_REQUEST);
+
+				enc.write.extend_ssh_string(b"keepalive@openssh.com");
+
+				enc.write.push(1);
+			});
+
+			self.pending_global_requests.push_back(GlobalRequest::Keepalive);
+		}
+
+		Ok(())
+	}
+
+	/// Send the global request `name`, followed by `payload`, waiting for
+	/// its reply on `reply` if set. Nothing is sent before
+	/// authentication, which drops `reply`.
+	pub(crate) fn global_request(
+		&mut self,
+		name:&str,
+		reply:Option<tokio::sync::oneshot::Sender<Option<Vec<u8>>>>,
+		payload:&[u8],
+	) {
+		if let Some(ref mut enc) = self.common.encrypted {
+			push_packet!(enc.write, {
+				enc.write.push(msg::GLOBAL_REQUEST);
+
+				enc.write.extend_ssh_string(name.as_bytes());
+
+				enc.write.push(reply.is_some() as u8);
+
+				enc.write.extend(payload);
+			});
+
+			if let Some(reply) = reply {
+				self.pending_global_requests.push_back(GlobalRequest::Reply(reply));
+			}
+		}
+	}
+
+	/// Announce the host keys with `hostkeys-00@openssh.com`, if
+	/// [`Config::announce_host_keys`] is set.
+	pub(crate) fn maybe_announce_host_keys(&mut self) {
+		if !self.common.config.announce_host_keys {
+			return;
+		}
+
+		if let Some(ref mut enc) = self.common.encrypted {
+			push_packet!(enc.write, {
+				enc.write.push(msg::GLOBAL_REQUEST);
+
+				enc.write.extend_ssh_string(b"hostkeys-00@openssh.com");
+
+				enc.write.push(0);
+
+				for key in self.common.config.keys.iter() {
+					if let Ok(public) = key.clone_public_key() {
+						enc.write.extend_ssh_string(&public.public_key_bytes());
+					}
+				}
+			});
+		}
+	}
+
+	/// Disconnect once the client has seen more authentication requests
+	/// rejected than [`Config::max_auth_attempts`] allows.
+	pub(crate) fn check_auth_rejections(&mut self) {
+		let rejections = match self.common.encrypted {
+			Some(Encrypted { state:EncryptedState::WaitingAuthRequest(ref auth), .. }) => {
+				auth.rejection_count
+			},
+			_ => return,
+		};
+
+		if rejections >= self.common.config.max_auth_attempts {
+			debug!("too many authentication failures: {}", rejections);
+
+			self.common.disconnect(
+				Disconnect::NoMoreAuthMethodsAvailable,
+				"Too many authentication failures",
+				"",
+			);
+		}
+	}
+
+	/// Sign the host keys of a `hostkeys-prove-00@openssh.com` request,
+	/// or return `None` if one of them is not ours.
+	pub(crate) fn prove_host_keys(&self, r:&mut Position) -> Option<Vec<Vec<u8>>> {
+		let enc = self.common.encrypted.as_ref()?;
+
+		let mut signatures = Vec::new();
+
+		while !r.is_at_end() {
+			let blob = r.read_string().ok()?;
+
+			let key = self.common.config.keys.iter().find(|key| {
+				key.clone_public_key().map_or(false, |public| public.public_key_bytes() == blob)
+			})?;
+
+			let mut data = CryptoVec::new();
+
+			data.extend_ssh_string(b"hostkeys-prove-00@openssh.com");
+
+			data.extend_ssh_string(&enc.session_id);
+
+			data.extend_ssh_string(blob);
+
+			// RSA keys sign with the negotiated hash, or else SHA-512.
+			#[cfg(feature = "openssl")]
+			let rsa = key.with_signature_hash(
+				key::SignatureHash::from_rsa_hostkey_algo(enc.negotiated.key.0.as_bytes())
+					.filter(|hash| *hash != key::SignatureHash::SHA1)
+					.unwrap_or(key::SignatureHash::SHA2_512),
+			);
+
+			#[cfg(feature = "openssl")]
+			let key = rsa.as_ref().unwrap_or(key);
+
+			signatures.push(key.sign_detached(&data).ok()?.to_bytes());
+		}
+
+		Some(signatures)
+	}
+
 	pub(crate) fn maybe_send_ext_info(&mut self) {
 		if let Some(ref mut enc) = self.common.encrypted {
 			// If client sent a ext-info-c message in the kex list, it supports
@@ -985,12 +1841,673 @@ impl Session {
 
 				enc.write.extend_ssh_string(b"server-sig-algs");
 
-				if cfg!(feature = "openssl") {
-					enc.write.extend_ssh_string(b"ssh-rsa,ssh-ed25519,rsa-sha2-256,rsa-sha2-512");
-				} else {
-					enc.write.extend_ssh_string(b"ssh-ed25519");
-				}
+				enc.write.extend_list(SERVER_SIG_ALGS.iter());
 			});
 		}
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// A client which does not read its channel holds the writers of the
+	/// server back, instead of having its data queued without limit.
+	#[tokio::test]
+	async fn test_data_backpressure() {
+		use std::sync::{
+			Arc,
+			atomic::{AtomicUsize, Ordering},
+		};
+
+		use tokio::{
+			sync::{Semaphore, oneshot},
+			time::{Duration, timeout},
+		};
+
+		const CHUNK:usize = 32768;
+
+		/// Stops reading at the first data, until `reading` has a permit.
+		struct Client {
+			reading:Arc<Semaphore>,
+			received:Arc<AtomicUsize>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn data(
+				self,
+				_:ChannelId,
+				data:&[u8],
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.reading.acquire().await.ok();
+
+				self.received.fetch_add(data.len(), Ordering::SeqCst);
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			opened:Option<oneshot::Sender<ChannelId>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				mut self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if let Some(opened) = self.opened.take() {
+					opened.send(channel.id()).ok();
+				}
+
+				Ok((self, true, session))
+			}
+		}
+
+		let (opened, opened_receiver) = oneshot::channel();
+
+		let reading = Arc::new(Semaphore::new(0));
+
+		let received = Arc::new(AtomicUsize::new(0));
+
+		let client = Client { reading:reading.clone(), received:received.clone() };
+
+		// Two chunks fill the window of the client.
+		let client_config = client::Config { window_size:2 * CHUNK as u32, ..Default::default() };
+
+		let server_config = server::Config { channel_buffer_size:0, ..Default::default() };
+
+		let (mut client, server) = connect_with(
+			client_config,
+			server_config,
+			client,
+			ServerHandle { opened:Some(opened) },
+		)
+		.await;
+
+		let server = server.handle();
+
+		assert!(client.authenticate_password("user", "password").await.unwrap().success());
+
+		let _channel = client.channel_open_session().await.unwrap();
+
+		let id = opened_receiver.await.unwrap();
+
+		let chunk = || CryptoVec::from_slice(&[0; CHUNK]);
+
+		for _ in 0..2 {
+			timeout(Duration::from_secs(5), server.data(id, chunk())).await.unwrap().unwrap();
+		}
+
+		// The window is exhausted, and the client does not adjust it.
+		let writer = server.clone();
+
+		let mut blocked = tokio::spawn(async move { writer.data(id, chunk()).await });
+
+		assert!(timeout(Duration::from_millis(300), &mut blocked).await.is_err());
+
+		assert!(server.try_data(id, chunk()).is_err());
+
+		// Reading adjusts the window, and lets the writer go.
+		reading.add_permits(1);
+
+		timeout(Duration::from_secs(5), blocked).await.unwrap().unwrap().unwrap();
+
+		timeout(Duration::from_secs(5), async {
+			while received.load(Ordering::SeqCst) < 3 * CHUNK {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		assert_eq!(received.load(Ordering::SeqCst), 3 * CHUNK);
+	}
+
+	/// `Handle::flush` waits for the data a client does not read yet, and
+	/// `Handle::channel_closed` for the client to close the channel, after
+	/// which writing to it fails with `Error::WrongChannel`, as on a
+	/// channel that never existed.
+	#[tokio::test]
+	async fn test_handle_flush_and_channel_closed() {
+		use std::sync::Arc;
+
+		use tokio::{
+			sync::{Semaphore, oneshot},
+			time::{Duration, timeout},
+		};
+
+		const CHUNK:usize = 32768;
+
+		/// Stops reading at the first data, until `reading` has a permit.
+		struct Client {
+			reading:Arc<Semaphore>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn data(
+				self,
+				_:ChannelId,
+				_:&[u8],
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.reading.acquire().await.ok();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			opened:Option<oneshot::Sender<ChannelId>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				mut self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				if let Some(opened) = self.opened.take() {
+					opened.send(channel.id()).ok();
+				}
+
+				Ok((self, true, session))
+			}
+		}
+
+		let (opened, opened_receiver) = oneshot::channel();
+
+		let reading = Arc::new(Semaphore::new(0));
+
+		let client = Client { reading:reading.clone() };
+
+		// Two chunks fill the window of the client.
+		let config = client::Config { window_size:2 * CHUNK as u32, ..Default::default() };
+
+		let (mut client, server) = connect_with(
+			config,
+			server::Config::default(),
+			client,
+			ServerHandle { opened:Some(opened) },
+		)
+		.await;
+
+		let server = server.handle();
+
+		assert!(client.authenticate_none("user").await.unwrap().success());
+
+		let channel = client.channel_open_session().await.unwrap();
+
+		let id = opened_receiver.await.unwrap();
+
+		// The third chunk waits for the window, within the channel buffer.
+		for _ in 0..3 {
+			let data = CryptoVec::from_slice(&[0; CHUNK]);
+
+			timeout(Duration::from_secs(5), server.data(id, data)).await.unwrap().unwrap();
+		}
+
+		let flusher = server.clone();
+
+		let mut flushed = tokio::spawn(async move { flusher.flush(id).await });
+
+		assert!(timeout(Duration::from_millis(300), &mut flushed).await.is_err());
+
+		let closer = server.clone();
+
+		let mut closed = tokio::spawn(async move { closer.channel_closed(id).await });
+
+		reading.add_permits(1);
+
+		timeout(Duration::from_secs(5), flushed).await.unwrap().unwrap().unwrap();
+
+		assert!(timeout(Duration::from_millis(300), &mut closed).await.is_err());
+
+		channel.close().await.unwrap();
+
+		timeout(Duration::from_secs(5), closed).await.unwrap().unwrap();
+
+		let late = || CryptoVec::from_slice(b"late");
+
+		assert!(matches!(server.data(id, late()).await, Err(crate::Error::WrongChannel)));
+
+		let extended = server.extended_data(id, 1, late()).await;
+
+		assert!(matches!(extended, Err(crate::Error::WrongChannel)));
+
+		assert!(matches!(server.eof(id).await, Err(crate::Error::WrongChannel)));
+
+		assert!(matches!(server.close(id).await, Err(crate::Error::WrongChannel)));
+
+		assert!(matches!(server.flush(id).await, Err(crate::Error::WrongChannel)));
+
+		// Nothing to wait for on a channel that never existed, and nothing
+		// to send on it.
+		let unknown = ChannelId(1234);
+
+		timeout(Duration::from_secs(5), server.channel_closed(unknown)).await.unwrap();
+
+		assert!(matches!(server.data(unknown, late()).await, Err(crate::Error::WrongChannel)));
+
+		assert!(matches!(server.eof(unknown).await, Err(crate::Error::WrongChannel)));
+	}
+
+	#[tokio::test]
+	async fn test_send_exit_and_close() {
+		const LARGE:usize = 5 << 20;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			// Drops the channel, so that the default handlers refuse the
+			// requests they cannot pass on.
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				match data {
+					b"true" => {
+						session.channel_success(channel);
+
+						session.send_exit_and_close(channel, 0);
+					},
+					b"large" => {
+						session.channel_success(channel);
+
+						session.data(channel, CryptoVec::from_slice(&vec![b'x'; LARGE]))?;
+
+						// Waits for the output beyond the window.
+						session.send_exit_and_close(channel, 7);
+					},
+					_ => session.channel_failure(channel),
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let (mut session, _server) = connect_with(
+			client::Config::default(),
+			server::Config::default(),
+			Client {},
+			ServerHandle {},
+		)
+		.await;
+
+		assert!(session.authenticate_password("user", "password").await.unwrap().success());
+
+		let output = session.exec_collect("true").await.unwrap();
+
+		assert_eq!((output.exit_status, output.exit_signal), (Some(0), None));
+
+		let mut command = session.exec_stream("large").await.unwrap();
+
+		let mut stdout = Vec::new();
+
+		command.stdout.read_to_end(&mut stdout).await.unwrap();
+
+		assert_eq!(stdout.len(), LARGE);
+
+		assert_eq!(command.wait().await.unwrap(), (Some(7), None));
+
+		assert!(matches!(
+			session.exec_collect("rm -rf /").await,
+			Err(crate::Error::ChannelRequestFailure)
+		));
+
+		// Nothing handles shells or subsystems.
+		let mut channel = session.channel_open_session().await.unwrap();
+
+		channel.request_shell(true).await.unwrap();
+
+		assert!(matches!(channel.wait().await, Some(ChannelMsg::Failure)));
+
+		channel.request_subsystem(true, "sftp").await.unwrap();
+
+		assert!(matches!(channel.wait().await, Some(ChannelMsg::Failure)));
+	}
+
+	/// A russh server streaming 100 MB to `ssh`, read slowly: the writer
+	/// waits for the client, so that the memory of the server stays far
+	/// below the size of the stream. Run with `cargo test -- --ignored`,
+	/// with `ssh` in the `PATH`.
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	#[ignore]
+	async fn test_data_backpressure_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{net::TcpListener, process::Command};
+
+		const TOTAL:usize = 100 << 20;
+
+		const CHUNK:usize = 32768;
+
+		struct ServerHandle {}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				let handle = session.handle();
+
+				tokio::spawn(async move {
+					for _ in 0..TOTAL / CHUNK {
+						let data = CryptoVec::from_slice(&[b'x'; CHUNK]);
+
+						if handle.data(channel, data).await.is_err() {
+							return;
+						}
+					}
+
+					if handle.flush(channel).await.is_ok() {
+						handle.send_exit_and_close(channel, 0).await.ok();
+					}
+				});
+
+				Ok((self, session))
+			}
+		}
+
+		fn resident() -> usize {
+			let status = std::fs::read_to_string("/proc/self/status").unwrap();
+
+			let kb = status.lines().find_map(|line| line.strip_prefix("VmRSS:")).unwrap();
+
+			kb.trim().trim_end_matches("kB").trim().parse::<usize>().unwrap() * 1024
+		}
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			server::run_stream(Arc::new(config), stream, ServerHandle {}).await.unwrap().await.ok();
+		});
+
+		let mut ssh = Command::new("ssh")
+			.args(["-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.arg("user@127.0.0.1")
+			.arg("stream")
+			.stdin(std::process::Stdio::null())
+			.stdout(std::process::Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		let mut stdout = ssh.stdout.take().unwrap();
+
+		let baseline = resident();
+
+		let read = async {
+			let mut peak = baseline;
+
+			let mut received = 0;
+
+			let mut buf = vec![0; 65536];
+
+			// Not reading at first fills the windows and the pipe.
+			tokio::time::sleep(Duration::from_secs(2)).await;
+
+			loop {
+				peak = peak.max(resident());
+
+				let n = stdout.read(&mut buf).await.unwrap();
+
+				if n == 0 {
+					break;
+				}
+
+				received += n;
+
+				tokio::time::sleep(Duration::from_millis(1)).await;
+			}
+
+			(received, peak)
+		};
+
+		let (received, peak) =
+			tokio::time::timeout(Duration::from_secs(120), read).await.unwrap();
+
+		assert!(ssh.wait().await.unwrap().success());
+
+		assert_eq!(received, TOTAL);
+
+		assert!(
+			peak.saturating_sub(baseline) < 32 << 20,
+			"{} bytes more resident",
+			peak - baseline
+		);
+	}
+
+	/// A russh server pushing a connection to a port forwarded by
+	/// `ssh -R` back to the client, which connects it to a local
+	/// listener. Run with `cargo test -- --ignored`, with `ssh` in the
+	/// `PATH`.
+	#[cfg(unix)]
+	#[tokio::test]
+	#[ignore]
+	async fn test_forwarded_tcpip_openssh_client() {
+		use std::{sync::Arc, time::Duration};
+
+		use tokio::{net::TcpListener, process::Command};
+
+		use crate::ChannelOpenFailure;
+
+		type Forwarded = Result<(Vec<u8>, Option<crate::Error>), crate::Error>;
+
+		struct ServerHandle {
+			results:UnboundedSender<Forwarded>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn tcpip_forward(
+				self,
+				address:&str,
+				port:&mut u32,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				let (handle, address, port) = (session.handle(), address.to_string(), *port);
+
+				let results = self.results.clone();
+
+				tokio::spawn(async move {
+					let forwarded = async {
+						let mut channel = handle
+							.channel_open_forwarded_tcpip(&*address, port, "10.0.0.1", 1234)
+							.await?;
+
+						channel.data(&b"hello"[..]).await?;
+
+						channel.eof().await?;
+
+						let mut reply = Vec::new();
+
+						while let Some(msg) = channel.wait().await {
+							match msg {
+								ChannelMsg::Data { data } => reply.extend_from_slice(&data),
+								ChannelMsg::Eof | ChannelMsg::Close => break,
+								_ => {},
+							}
+						}
+
+						// No such port was forwarded.
+						let refused = handle
+							.channel_open_forwarded_tcpip(&*address, port + 1, "10.0.0.1", 1234)
+							.await
+							.err();
+
+						Ok::<_, crate::Error>((reply, refused))
+					};
+
+					results.send(forwarded.await).unwrap_or(());
+				});
+
+				Ok((self, true, session))
+			}
+		}
+
+		// The destination of the forwarding, on the client's side.
+		let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let target_port = target.local_addr().unwrap().port();
+
+		let (received, mut target_received) = unbounded_channel();
+
+		tokio::spawn(async move {
+			let (mut stream, _) = target.accept().await.unwrap();
+
+			let mut data = Vec::new();
+
+			stream.read_to_end(&mut data).await.unwrap();
+
+			stream.write_all(b"world").await.unwrap();
+
+			received.send(data).unwrap();
+		});
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		let (results, mut forwarded) = unbounded_channel();
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			let handler = ServerHandle { results };
+
+			server::run_stream(Arc::new(config), stream, handler).await.unwrap().await.ok();
+		});
+
+		let mut ssh = Command::new("ssh")
+			.args(["-N", "-p", &port.to_string()])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.args(["-o", "BatchMode=yes"])
+			.args(["-o", "ExitOnForwardFailure=yes"])
+			.args(["-R", &format!("127.0.0.1:40000:127.0.0.1:{}", target_port)])
+			.arg("user@127.0.0.1")
+			.stdin(std::process::Stdio::null())
+			.kill_on_drop(true)
+			.spawn()
+			.unwrap();
+
+		let result = tokio::time::timeout(Duration::from_secs(30), forwarded.recv()).await;
+
+		ssh.kill().await.ok();
+
+		let (reply, refused) = result.unwrap().unwrap().unwrap();
+
+		assert_eq!(target_received.recv().await.unwrap(), b"hello");
+
+		assert_eq!(reply, b"world");
+
+		assert!(matches!(
+			refused,
+			Some(crate::Error::ChannelOpenFailure(ChannelOpenFailure::AdministrativelyProhibited))
+		));
+	}
+}
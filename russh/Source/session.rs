@@ -15,18 +15,22 @@
 
 use std::{
 	collections::HashMap,
+	convert::TryFrom,
 	fmt::{Debug, Formatter},
 	num::Wrapping,
+	sync::{Arc, Mutex},
 };
 
 use byteorder::{BigEndian, ByteOrder};
-use log::{debug, trace};
+use log::{debug, trace, warn};
+use rand::RngCore;
 use russh_cryptovec::CryptoVec;
-use russh_keys::encoding::Encoding;
+use russh_keys::encoding::{Encoding, Reader};
 
 use crate::{
 	ChannelId,
 	ChannelParams,
+	ConnectionInfo,
 	Disconnect,
 	Limits,
 	auth,
@@ -37,6 +41,7 @@ use crate::{
 	msg,
 	negotiation,
 	sshbuffer::SSHBuffer,
+	stats::Stats,
 };
 
 #[derive(Debug)]
@@ -50,17 +55,27 @@ pub(crate) struct Encrypted {
 	pub client_mac:mac::Name,
 	pub server_mac:mac::Name,
 	pub session_id:CryptoVec,
+	/// Exchange hash of the last key exchange.
+	pub exchange_hash:CryptoVec,
 	pub rekey:Option<Kex>,
 	pub channels:HashMap<ChannelId, ChannelParams>,
+	/// The channels we closed, until the peer closes them too, with the
+	/// tasks waiting for that. Their IDs are not reused until then.
+	pub closing:HashMap<ChannelId, Vec<tokio::sync::oneshot::Sender<()>>>,
 	pub last_channel_id:Wrapping<u32>,
 	pub write:CryptoVec,
 	pub write_cursor:usize,
 	pub last_rekey:std::time::Instant,
-	pub server_compression:crate::compression::Compression,
-	pub client_compression:crate::compression::Compression,
-	pub compress:crate::compression::Compress,
+	pub local_to_remote_compression:crate::compression::Compression,
+	pub remote_to_local_compression:crate::compression::Compression,
+	/// The zlib level of the packets sent.
+	pub compression_level:u32,
 	pub decompress:crate::compression::Decompress,
-	pub compress_buffer:CryptoVec,
+	/// Notified when a key exchange requested through a `Handle` is done.
+	pub rekey_done:Option<tokio::sync::oneshot::Sender<Result<(), crate::Error>>>,
+	pub negotiated:negotiation::Negotiated,
+	/// How the `none` cipher is proposed in the next key exchanges.
+	pub none_cipher:negotiation::NoneCipher,
 }
 
 pub(crate) struct CommonSession<Config> {
@@ -75,11 +90,51 @@ pub(crate) struct CommonSession<Config> {
 	pub wants_reply:bool,
 	pub disconnected:bool,
 	pub buffer:CryptoVec,
+	/// Strict key exchange was agreed on during the initial key exchange.
+	pub strict_kex:bool,
+	/// The channel data waiting for the peer's window, shared with the
+	/// handles of the session.
+	pub backlogs:Arc<Backlogs>,
+	/// The traffic counters, shared with the handles of the session.
+	pub stats:Arc<Stats>,
+	/// The peer address and the version strings of the connection.
+	pub info:ConnectionInfo,
+}
+
+/// The bytes of each channel waiting for the peer's window, published by
+/// a session for the `try_data` methods of its handles.
+#[derive(Debug)]
+pub(crate) struct Backlogs {
+	/// The bytes of a channel that writers may leave queued.
+	pub cap:usize,
+	channels:Mutex<HashMap<ChannelId, usize>>,
+}
+
+impl Backlogs {
+	pub fn new(cap:usize) -> Arc<Self> {
+		Arc::new(Backlogs { cap, channels:Mutex::new(HashMap::new()) })
+	}
+
+	/// Whether `len` more bytes can be queued on `channel` without waiting:
+	/// if nothing is queued yet, or if they fit in the cap.
+	pub fn has_room(&self, channel:ChannelId, len:usize) -> bool {
+		match self.channels.lock() {
+			Ok(channels) => {
+				match channels.get(&channel) {
+					Some(backlog) => backlog + len <= self.cap,
+					None => true,
+				}
+			},
+			Err(_) => false,
+		}
+	}
 }
 
 impl<C> CommonSession<C> {
 	pub fn newkeys(&mut self, newkeys:NewKeys) {
 		if let Some(ref mut enc) = self.encrypted {
+			enc.negotiated = newkeys.names.negotiated();
+
 			enc.exchange = Some(newkeys.exchange);
 
 			enc.kex = newkeys.kex;
@@ -90,35 +145,81 @@ impl<C> CommonSession<C> {
 
 			enc.server_mac = newkeys.names.server_mac;
 
+			enc.exchange_hash = newkeys.exchange_hash;
+
+			enc.local_to_remote_compression = newkeys.local_to_remote_compression;
+
+			enc.remote_to_local_compression = newkeys.remote_to_local_compression;
+
+			enc.start_compression(&mut self.write_buffer.compress);
+
 			self.cipher = newkeys.cipher;
+
+			if let Some(done) = enc.rekey_done.take() {
+				done.send(Ok(())).unwrap_or(());
+			}
 		}
 	}
 
-	pub fn encrypted(&mut self, state:EncryptedState, newkeys:NewKeys) {
-		self.encrypted = Some(Encrypted {
+	pub fn encrypted(&mut self, state:EncryptedState, newkeys:NewKeys, compression_level:u32) {
+		let mut enc = Encrypted {
+			negotiated:newkeys.names.negotiated(),
 			exchange:Some(newkeys.exchange),
 			kex:newkeys.kex,
 			key:newkeys.key,
 			client_mac:newkeys.names.client_mac,
 			server_mac:newkeys.names.server_mac,
 			session_id:newkeys.session_id,
+			exchange_hash:newkeys.exchange_hash,
 			state,
 			rekey:None,
 			channels:HashMap::new(),
+			closing:HashMap::new(),
 			last_channel_id:Wrapping(1),
 			write:CryptoVec::new(),
 			write_cursor:0,
 			last_rekey:std::time::Instant::now(),
-			server_compression:newkeys.names.server_compression,
-			client_compression:newkeys.names.client_compression,
-			compress:crate::compression::Compress::None,
-			compress_buffer:CryptoVec::new(),
+			local_to_remote_compression:newkeys.local_to_remote_compression,
+			remote_to_local_compression:newkeys.remote_to_local_compression,
+			compression_level,
 			decompress:crate::compression::Decompress::None,
-		});
+			rekey_done:None,
+			none_cipher:negotiation::NoneCipher::Unchanged,
+		};
+
+		enc.start_compression(&mut self.write_buffer.compress);
+
+		self.encrypted = Some(enc);
 
 		self.cipher = newkeys.cipher;
 	}
 
+	/// Under strict key exchange, only key exchange messages may be
+	/// received until the first `NEWKEYS`. `seqn` is the read sequence
+	/// number, which was already incremented past this packet.
+	pub(crate) fn check_strict_kex(
+		&self,
+		buf:&[u8],
+		seqn:Wrapping<u32>,
+	) -> Result<(), crate::Error> {
+		if !self.strict_kex || self.encrypted.is_some() {
+			return Ok(());
+		}
+
+		let message_type = buf.first().copied().unwrap_or_default();
+
+		match message_type {
+			// 30 to 49 are specific to the key exchange method (RFC 4250).
+			msg::KEXINIT | msg::NEWKEYS | 30..=49 => Ok(()),
+			_ => {
+				Err(crate::Error::StrictKeyExchangeViolation {
+					message_type,
+					sequence_number:(seqn - Wrapping(1)).0,
+				})
+			},
+		}
+	}
+
 	/// Send a disconnect message.
 	pub fn disconnect(&mut self, reason:Disconnect, description:&str, language_tag:&str) {
 		let disconnect = |buf:&mut CryptoVec| {
@@ -144,12 +245,131 @@ impl<C> CommonSession<C> {
 		}
 	}
 
+	/// Send a debug message, which the peer may log, or show to its user
+	/// if `always_display`. Nothing is sent before the first key exchange
+	/// is done.
+	pub fn send_debug(&mut self, always_display:bool, message:&str, language_tag:&str) {
+		if let Some(ref mut enc) = self.encrypted {
+			push_packet!(enc.write, {
+				enc.write.push(msg::DEBUG);
+
+				enc.write.push(always_display as u8);
+
+				enc.write.extend_ssh_string(message.as_bytes());
+
+				enc.write.extend_ssh_string(language_tag.as_bytes());
+			});
+		}
+	}
+
+	/// Send `payload_len` random bytes the peer discards, in ignore
+	/// messages of at most [`MAX_IGNORE_PAYLOAD`] bytes. Nothing is sent
+	/// before the first key exchange is done.
+	pub fn send_ignore(&mut self, payload_len:usize) {
+		if let Some(ref mut enc) = self.encrypted {
+			let mut payload = vec![0; payload_len.min(MAX_IGNORE_PAYLOAD)];
+
+			let mut left = payload_len;
+
+			loop {
+				let len = left.min(MAX_IGNORE_PAYLOAD);
+
+				#[allow(clippy::indexing_slicing)] // length checked
+				let payload = &mut payload[..len];
+
+				rand::thread_rng().fill_bytes(payload);
+
+				push_packet!(enc.write, {
+					enc.write.push(msg::IGNORE);
+
+					enc.write.extend_ssh_string(payload);
+				});
+
+				left -= len;
+
+				if left == 0 {
+					break;
+				}
+			}
+		}
+	}
+
+	/// Answer the packet of sequence number `seqn`, of a type unknown to
+	/// us, with an unimplemented message.
+	pub fn send_unimplemented(&mut self, seqn:u32) -> Result<(), crate::Error> {
+		if let Some(ref mut enc) = self.encrypted {
+			push_packet!(enc.write, {
+				enc.write.push(msg::UNIMPLEMENTED);
+
+				enc.write.push_u32_be(seqn);
+			});
+		} else {
+			let mut payload = [msg::UNIMPLEMENTED, 0, 0, 0, 0];
+			#[allow(clippy::indexing_slicing)] // length checked
+			BigEndian::write_u32(&mut payload[1..], seqn);
+
+			self.cipher.local_to_remote.write(&payload, &mut self.write_buffer)?;
+		}
+
+		Ok(())
+	}
+
 	/// Send a single byte message onto the channel.
 	pub fn byte(&mut self, channel:ChannelId, msg:u8) {
 		if let Some(ref mut enc) = self.encrypted {
 			enc.byte(channel, msg)
 		}
 	}
+
+	/// Fail with [`crate::Error::WrongChannel`] unless `channel` is open:
+	/// it is known, and neither side closed it.
+	pub fn check_channel(&self, channel:ChannelId) -> Result<(), crate::Error> {
+		match self.encrypted {
+			Some(ref enc) if enc.channels.contains_key(&channel) => Ok(()),
+			_ => Err(crate::Error::WrongChannel),
+		}
+	}
+}
+
+/// The largest payload of the ignore messages of
+/// [`CommonSession::send_ignore`], so that they fit in the packets any
+/// peer accepts.
+pub(crate) const MAX_IGNORE_PAYLOAD:usize = 32768;
+
+/// Read the debug message `buf` sent by the peer, into whether to always
+/// display it, the message and its language tag. Invalid UTF-8 sequences
+/// are replaced with `U+FFFD`.
+pub(crate) fn read_debug(buf:&[u8]) -> Option<(bool, String, String)> {
+	let mut r = buf.reader(1);
+
+	let always_display = r.read_byte().ok()? != 0;
+
+	let message = String::from_utf8_lossy(r.read_string().ok()?).into_owned();
+
+	// Some peers omit the language tag.
+	let language_tag = r.read_string().map(String::from_utf8_lossy).unwrap_or_default();
+
+	Some((always_display, message, language_tag.into_owned()))
+}
+
+/// Read the unimplemented message `buf` sent by the peer, into the
+/// sequence number of the packet it answers.
+pub(crate) fn read_unimplemented(buf:&[u8]) -> Option<u32> { buf.reader(1).read_u32().ok() }
+
+/// Read the disconnect message `buf` sent by the peer, into the error
+/// ending the session.
+pub(crate) fn disconnected_by_peer(buf:&[u8]) -> crate::Error {
+	let mut r = buf.reader(1);
+
+	match (r.read_u32(), r.read_string()) {
+		(Ok(reason), Ok(description)) => {
+			crate::Error::Disconnected {
+				reason:Disconnect::from_code(reason),
+				description:String::from_utf8_lossy(description).into_owned(),
+			}
+		},
+		_ => crate::Error::Disconnect,
+	}
 }
 
 impl Encrypted {
@@ -163,17 +383,88 @@ impl Encrypted {
 		}
 	}
 
-	// pub fn authenticated(&mut self) {
-	// self.server_compression.init_compress(&mut self.compress);
-	// self.state = EncryptedState::Authenticated;
-	// }
+	/// Start the compression of both directions with the keys of a key
+	/// exchange. As in OpenSSH, the streams restart with each key
+	/// exchange, and `zlib@openssh.com` waits for authentication.
+	fn start_compression(&mut self, compress:&mut crate::compression::Compress) {
+		let authenticated =
+			matches!(self.state, EncryptedState::InitCompression | EncryptedState::Authenticated);
+
+		if authenticated || !self.local_to_remote_compression.is_delayed() {
+			self.local_to_remote_compression.init_compress(compress, self.compression_level);
+		} else {
+			*compress = crate::compression::Compress::None;
+		}
+
+		if authenticated || !self.remote_to_local_compression.is_delayed() {
+			self.remote_to_local_compression.init_decompress(&mut self.decompress);
+		} else {
+			self.decompress = crate::compression::Decompress::None;
+		}
+	}
+
+	/// Start the delayed compression of the packets sent, right after
+	/// `USERAUTH_SUCCESS`.
+	pub fn start_delayed_compress(&self, compress:&mut crate::compression::Compress) {
+		if self.local_to_remote_compression.is_delayed() {
+			self.local_to_remote_compression.init_compress(compress, self.compression_level);
+		}
+	}
+
+	/// Start the delayed decompression of the packets received, right
+	/// after `USERAUTH_SUCCESS`.
+	pub fn start_delayed_decompress(&mut self) {
+		if self.remote_to_local_compression.is_delayed() {
+			self.remote_to_local_compression.init_decompress(&mut self.decompress);
+		}
+	}
 
 	pub fn eof(&mut self, channel:ChannelId) { self.byte(channel, msg::CHANNEL_EOF); }
 
+	/// Send CHANNEL_CLOSE on `channel`, unless it was already sent. The
+	/// channel is forgotten, but its ID stays in `closing` until the peer
+	/// closes it too, as the peer may refer to it until then.
 	pub fn close(&mut self, channel:ChannelId) {
-		self.byte(channel, msg::CHANNEL_CLOSE);
+		if let Some(params) = self.channels.remove(&channel) {
+			push_packet!(self.write, {
+				self.write.push(msg::CHANNEL_CLOSE);
+
+				self.write.push_u32_be(params.recipient_channel);
+			});
+
+			self.closing.insert(channel, params.closes);
+		}
+	}
+
+	/// The peer closed `channel`: answer with CHANNEL_CLOSE, unless we
+	/// closed it first, after which its ID may be reused. Return whether
+	/// there was such a channel.
+	/// https://tools.ietf.org/html/rfc4254#section-5.3
+	pub fn close_received(&mut self, channel:ChannelId) -> bool {
+		if self.closing.remove(&channel).is_some() {
+			return true;
+		}
+
+		self.close(channel);
+
+		self.closing.remove(&channel).is_some()
+	}
 
-		self.channels.remove(&channel);
+	/// Whether `buf` is a message on a channel we closed, which the peer
+	/// may have sent before receiving our CHANNEL_CLOSE, and which is to
+	/// be discarded.
+	pub fn is_for_closing_channel(&self, buf:&[u8]) -> bool {
+		match buf.first() {
+			Some(&t)
+				if (msg::CHANNEL_WINDOW_ADJUST..=msg::CHANNEL_FAILURE).contains(&t)
+					&& t != msg::CHANNEL_CLOSE =>
+			{
+				buf.get(1..5).map_or(false, |id| {
+					self.closing.contains_key(&ChannelId(BigEndian::read_u32(id)))
+				})
+			},
+			_ => false,
+		}
 	}
 
 	pub fn sender_window_size(&self, channel:ChannelId) -> usize {
@@ -184,16 +475,27 @@ impl Encrypted {
 		}
 	}
 
+	/// Count `data` received on `channel` against our window, and
+	/// restore the window to `target` once it is half used or empty.
+	/// Return whether a window adjustment was sent.
 	pub fn adjust_window_size(&mut self, channel:ChannelId, data:&[u8], target:u32) -> bool {
 		if let Some(channel) = self.channels.get_mut(&channel) {
+			let target = channel.target_window_size.unwrap_or(target);
+
 			trace!("adjust_window_size, channel = {}, size = {},", channel.sender_channel, target);
-			// Ignore extra data.
+			// Data past the window empties it, so that adjustments never
+			// advertise more than `target` bytes of room.
 			// https://tools.ietf.org/html/rfc4254#section-5.2
-			if data.len() as u32 <= channel.sender_window_size {
-				channel.sender_window_size -= data.len() as u32;
+			let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
+
+			if len > channel.sender_window_size {
+				debug!("{} bytes past the window of {:?}", len, channel.sender_channel);
 			}
 
-			if channel.sender_window_size < target / 2 {
+			channel.sender_window_size = channel.sender_window_size.saturating_sub(len);
+
+			// A window of 1 byte, once empty, is not less than half of it.
+			if channel.sender_window_size == 0 || channel.sender_window_size < target / 2 {
 				debug!("sender_window_size {:?}, target {:?}", channel.sender_window_size, target);
 
 				push_packet!(self.write, {
@@ -213,36 +515,214 @@ impl Encrypted {
 		false
 	}
 
+	/// Add `amount` to the window of the peer on `channel`, and send the
+	/// data waiting for it. The window saturates at `u32::MAX` instead of
+	/// wrapping around to a tiny window, for peers whose adjustments sum
+	/// past it. Return the window left, or `None` if there is no such
+	/// channel.
+	/// https://tools.ietf.org/html/rfc4254#section-5.2
+	pub fn window_adjusted(&mut self, channel:ChannelId, amount:u32) -> Option<u32> {
+		let params = self.channels.get_mut(&channel)?;
+
+		params.recipient_window_size =
+			params.recipient_window_size.checked_add(amount).unwrap_or_else(|| {
+				warn!("window of {:?} adjusted past u32::MAX, saturating", channel);
+
+				u32::MAX
+			});
+
+		let window = params.recipient_window_size;
+
+		// The flush may close the channel, once its pending exit is sent.
+		let sent = self.flush_pending(channel);
+
+		Some(window.saturating_sub(u32::try_from(sent).unwrap_or(u32::MAX)))
+	}
+
 	pub fn flush_pending(&mut self, channel:ChannelId) -> usize {
 		let mut pending_size = 0;
 
-		if let Some(channel) = self.channels.get_mut(&channel) {
-			while let Some((buf, a, from)) = channel.pending_data.pop_front() {
-				let size = Self::data_noqueue(&mut self.write, channel, &buf, from);
+		let mut exited = false;
+
+		if let Some(params) = self.channels.get_mut(&channel) {
+			while let Some((buf, a, from)) = params.pending_data.pop_front() {
+				let size = Self::data_noqueue(&mut self.write, params, a, &buf, from);
 
 				pending_size += size;
 
 				if from + size < buf.len() {
-					channel.pending_data.push_front((buf, a, from + size));
+					params.pending_data.push_front((buf, a, from + size));
 
 					break;
 				}
+
+				crate::pool::give(buf);
 			}
+
+			exited = Self::exit_if_sent(&mut self.write, params);
+		}
+
+		if exited {
+			self.close(channel);
 		}
 
 		pending_size
 	}
 
 	pub fn flush_all_pending(&mut self) {
-		for (_, channel) in self.channels.iter_mut() {
+		let mut exited = Vec::new();
+
+		for (id, channel) in self.channels.iter_mut() {
 			while let Some((buf, a, from)) = channel.pending_data.pop_front() {
-				let size = Self::data_noqueue(&mut self.write, channel, &buf, from);
+				let size = Self::data_noqueue(&mut self.write, channel, a, &buf, from);
 
 				if from + size < buf.len() {
 					channel.pending_data.push_front((buf, a, from + size));
 
 					break;
 				}
+
+				crate::pool::give(buf);
+			}
+
+			if Self::exit_if_sent(&mut self.write, channel) {
+				exited.push(*id);
+			}
+		}
+
+		for id in exited {
+			self.close(id);
+		}
+	}
+
+	/// Send `exit_status`, then EOF and close `channel`, once the data
+	/// still waiting for the peer's window has been sent.
+	pub fn exit_and_close(&mut self, channel:ChannelId, exit_status:u32) {
+		let exited = match self.channels.get_mut(&channel) {
+			Some(params) => {
+				params.pending_exit = Some(exit_status);
+
+				Self::exit_if_sent(&mut self.write, params)
+			},
+			None => false,
+		};
+
+		if exited {
+			self.close(channel);
+		}
+	}
+
+	/// Send the pending exit status of `channel` and EOF, once its data is
+	/// sent. Return whether they were sent, and the channel is to be
+	/// closed.
+	fn exit_if_sent(write:&mut CryptoVec, channel:&mut ChannelParams) -> bool {
+		if !channel.pending_data.is_empty() {
+			return false;
+		}
+
+		if let Some(exit_status) = channel.pending_exit.take() {
+			push_packet!(write, {
+				write.push(msg::CHANNEL_REQUEST);
+
+				write.push_u32_be(channel.recipient_channel);
+
+				write.extend_ssh_string(b"exit-status");
+
+				write.push(0);
+
+				write.push_u32_be(exit_status)
+			});
+
+			push_packet!(write, {
+				write.push(msg::CHANNEL_EOF);
+
+				write.push_u32_be(channel.recipient_channel);
+			});
+
+			return true;
+		}
+
+		false
+	}
+
+	/// Reply to `writer` once the data queued so far on `channel` is sent,
+	/// see [`Encrypted::wake_writers`]. The writer is dropped if there is
+	/// no such channel, or when it is closed.
+	pub fn wait_sent(&mut self, channel:ChannelId, writer:tokio::sync::oneshot::Sender<()>) {
+		if let Some(channel) = self.channels.get_mut(&channel) {
+			channel.writers.push_back((channel.data_queued, writer));
+		}
+	}
+
+	/// Reply to `flushed` once the data queued on `channel` is sent, see
+	/// [`Encrypted::wake_flushes`]. `flushed` is dropped if there is no
+	/// such channel, or when it is closed.
+	pub fn wait_flushed(&mut self, channel:ChannelId, flushed:tokio::sync::oneshot::Sender<()>) {
+		if let Some(channel) = self.channels.get_mut(&channel) {
+			channel.flushes.push(flushed);
+		}
+	}
+
+	/// Reply to the flushes of the channels with no data waiting, once
+	/// the packets queued so far are written to the connection.
+	pub fn wake_flushes(&mut self) {
+		if !self.write.is_empty() {
+			return;
+		}
+
+		for channel in self.channels.values_mut() {
+			if channel.pending_data.is_empty() {
+				for flushed in channel.flushes.drain(..) {
+					flushed.send(()).unwrap_or(());
+				}
+			}
+		}
+	}
+
+	/// Drop `closed` when the peer closed `channel`, or now if there is
+	/// no such channel.
+	pub fn wait_closed(&mut self, channel:ChannelId, closed:tokio::sync::oneshot::Sender<()>) {
+		if let Some(params) = self.channels.get_mut(&channel) {
+			params.closes.push(closed);
+		} else if let Some(closes) = self.closing.get_mut(&channel) {
+			closes.push(closed);
+		}
+	}
+
+	/// Reply to the writers whose data is sent, but for `backlogs.cap`
+	/// bytes, and publish the data of each channel still waiting for the
+	/// peer's window.
+	pub fn wake_writers(&mut self, backlogs:&Backlogs) {
+		let cap = backlogs.cap as u64;
+
+		let mut published = match backlogs.channels.lock() {
+			Ok(published) => published,
+			Err(_) => return,
+		};
+
+		published.clear();
+
+		for (id, channel) in self.channels.iter_mut() {
+			let backlog:usize = channel
+				.pending_data
+				.iter()
+				.map(|(buf, _, from)| buf.len().saturating_sub(*from))
+				.sum();
+
+			let sent = channel.data_queued.saturating_sub(backlog as u64);
+
+			while let Some((end, _)) = channel.writers.front() {
+				if end.saturating_sub(cap) > sent {
+					break;
+				}
+
+				if let Some((_, writer)) = channel.writers.pop_front() {
+					writer.send(()).unwrap_or(());
+				}
+			}
+
+			if backlog > 0 {
+				published.insert(*id, backlog);
 			}
 		}
 	}
@@ -256,11 +736,13 @@ impl Encrypted {
 	}
 
 	/// Push the largest amount of `&buf0[from..]` that can fit into
-	/// the window, dividing it into packets if it is too large, and
-	/// return the length that was written.
+	/// the window, as extended data of type `ext` if any, dividing it
+	/// into packets if it is too large, and return the length that was
+	/// written.
 	fn data_noqueue(
 		write:&mut CryptoVec,
 		channel:&mut ChannelParams,
+		ext:Option<u32>,
 		buf0:&[u8],
 		from:usize,
 	) -> usize {
@@ -268,24 +750,31 @@ impl Encrypted {
 			return 0;
 		}
 
-		let mut buf = if buf0.len() as u32 > from as u32 + channel.recipient_window_size {
-			#[allow(clippy::indexing_slicing)] // length checked
-			&buf0[from..from + channel.recipient_window_size as usize]
-		} else {
-			#[allow(clippy::indexing_slicing)] // length checked
-			&buf0[from..]
-		};
+		// In `usize`, so that a window near `u32::MAX` does not wrap.
+		let end = buf0.len().min(from.saturating_add(channel.recipient_window_size as usize));
+
+		#[allow(clippy::indexing_slicing)] // length checked
+		let mut buf = &buf0[from..end];
 
 		let buf_len = buf.len();
 
 		while !buf.is_empty() {
-			// Compute the length we're allowed to send.
-			let off = std::cmp::min(buf.len(), channel.recipient_maximum_packet_size as usize);
+			// Compute the length we're allowed to send, making progress even
+			// if the peer announced empty packets.
+			let off = buf.len().min(channel.recipient_maximum_packet_size.max(1) as usize);
 
 			push_packet!(write, {
-				write.push(msg::CHANNEL_DATA);
+				if let Some(ext) = ext {
+					write.push(msg::CHANNEL_EXTENDED_DATA);
 
-				write.push_u32_be(channel.recipient_channel);
+					write.push_u32_be(channel.recipient_channel);
+
+					write.push_u32_be(ext);
+				} else {
+					write.push(msg::CHANNEL_DATA);
+
+					write.push_u32_be(channel.recipient_channel);
+				}
 				#[allow(clippy::indexing_slicing)] // length checked
 				write.extend_ssh_string(&buf[..off]);
 			});
@@ -306,18 +795,21 @@ impl Encrypted {
 
 	pub fn data(&mut self, channel:ChannelId, buf0:CryptoVec) {
 		if let Some(channel) = self.channels.get_mut(&channel) {
-			assert!(channel.confirmed);
+			channel.data_queued += buf0.len() as u64;
 
-			if !channel.pending_data.is_empty() || self.rekey.is_some() {
+			// Data for a channel not yet confirmed waits for its window.
+			if !channel.confirmed || !channel.pending_data.is_empty() || self.rekey.is_some() {
 				channel.pending_data.push_back((buf0, None, 0));
 
 				return;
 			}
 
-			let buf_len = Self::data_noqueue(&mut self.write, channel, &buf0, 0);
+			let buf_len = Self::data_noqueue(&mut self.write, channel, None, &buf0, 0);
 
 			if buf_len < buf0.len() {
 				channel.pending_data.push_back((buf0, None, buf_len))
+			} else {
+				crate::pool::give(buf0)
 			}
 		} else {
 			debug!("{:?} not saved for this session", channel);
@@ -325,53 +817,22 @@ impl Encrypted {
 	}
 
 	pub fn extended_data(&mut self, channel:ChannelId, ext:u32, buf0:CryptoVec) {
-		use std::ops::Deref;
-
 		if let Some(channel) = self.channels.get_mut(&channel) {
-			assert!(channel.confirmed);
+			channel.data_queued += buf0.len() as u64;
 
-			if !channel.pending_data.is_empty() {
+			// Data for a channel not yet confirmed waits for its window.
+			if !channel.confirmed || !channel.pending_data.is_empty() || self.rekey.is_some() {
 				channel.pending_data.push_back((buf0, Some(ext), 0));
 
 				return;
 			}
 
-			let mut buf = if buf0.len() as u32 > channel.recipient_window_size {
-				#[allow(clippy::indexing_slicing)] // length checked
-				&buf0[0..channel.recipient_window_size as usize]
-			} else {
-				&buf0
-			};
-
-			let buf_len = buf.len();
-
-			while !buf.is_empty() {
-				// Compute the length we're allowed to send.
-				let off = std::cmp::min(buf.len(), channel.recipient_maximum_packet_size as usize);
-
-				push_packet!(self.write, {
-					self.write.push(msg::CHANNEL_EXTENDED_DATA);
-
-					self.write.push_u32_be(channel.recipient_channel);
-
-					self.write.push_u32_be(ext);
-					#[allow(clippy::indexing_slicing)] // length checked
-					self.write.extend_ssh_string(&buf[..off]);
-				});
-
-				trace!("buffer: {:?}", self.write.deref().len());
-
-				channel.recipient_window_size -= off as u32;
-				#[allow(clippy::indexing_slicing)] // length checked
-				{
-					buf = &buf[off..]
-				}
-			}
-
-			trace!("buf.len() = {:?}, buf_len = {:?}", buf.len(), buf_len);
+			let buf_len = Self::data_noqueue(&mut self.write, channel, Some(ext), &buf0, 0);
 
 			if buf_len < buf0.len() {
 				channel.pending_data.push_back((buf0, Some(ext), buf_len))
+			} else {
+				crate::pool::give(buf0)
 			}
 		}
 	}
@@ -383,8 +844,8 @@ impl Encrypted {
 		write_buffer:&mut SSHBuffer,
 	) -> Result<bool, crate::Error> {
 		// If there are pending packets (and we've not started to rekey), flush
-		// them.
-		{
+		// them. Packets queued during a key exchange are held until NEWKEYS.
+		if self.rekey.is_none() {
 			while self.write_cursor < self.write.len() {
 				// Read a single packet, encrypt and send it.
 				#[allow(clippy::indexing_slicing)] // length checked
@@ -393,10 +854,14 @@ impl Encrypted {
 				let to_write = &self.write[(self.write_cursor + 4)..(self.write_cursor + 4 + len)];
 
 				trace!("server_write_encrypted, buf = {:?}", to_write);
-				#[allow(clippy::indexing_slicing)]
-				let packet = self.compress.compress(to_write, &mut self.compress_buffer)?;
 
-				cipher.write(packet, write_buffer);
+				cipher.write(to_write, write_buffer)?;
+
+				// As in OpenSSH, a server compresses from the packet after
+				// USERAUTH_SUCCESS.
+				if to_write.first() == Some(&msg::USERAUTH_SUCCESS) {
+					self.start_delayed_compress(&mut write_buffer.compress);
+				}
 
 				self.write_cursor += 4 + len
 			}
@@ -407,6 +872,8 @@ impl Encrypted {
 			self.write_cursor = 0;
 
 			self.write.clear();
+
+			crate::pool::trim(&mut self.write);
 		}
 
 		if self.kex.skip_exchange() {
@@ -417,13 +884,29 @@ impl Encrypted {
 
 		let dur = now.duration_since(self.last_rekey);
 
-		Ok(write_buffer.bytes >= limits.rekey_write_limit || dur >= limits.rekey_time_limit)
+		Ok(limits.write_limit_reached(write_buffer) || dur >= limits.rekey_time_limit)
+	}
+
+	/// Whether a key exchange is running, or was requested and has not
+	/// completed yet.
+	pub fn is_rekeying(&self) -> bool { self.rekey.is_some() || self.rekey_done.is_some() }
+
+	/// Time left before the keys must be re-exchanged, or `None` if a key
+	/// exchange is already running or none is needed.
+	pub fn time_until_rekey(&self, limits:&Limits) -> Option<std::time::Duration> {
+		if self.rekey.is_some() || self.kex.skip_exchange() {
+			return None;
+		}
+
+		Some(limits.rekey_time_limit.saturating_sub(self.last_rekey.elapsed()))
 	}
 
 	pub fn new_channel_id(&mut self) -> ChannelId {
 		self.last_channel_id += Wrapping(1);
 
-		while self.channels.contains_key(&ChannelId(self.last_channel_id.0)) {
+		while self.channels.contains_key(&ChannelId(self.last_channel_id.0))
+			|| self.closing.contains_key(&ChannelId(self.last_channel_id.0))
+		{
 			self.last_channel_id += Wrapping(1)
 		}
 
@@ -434,6 +917,10 @@ impl Encrypted {
 		loop {
 			self.last_channel_id += Wrapping(1);
 
+			if self.closing.contains_key(&ChannelId(self.last_channel_id.0)) {
+				continue;
+			}
+
 			if let std::collections::hash_map::Entry::Vacant(vacant_entry) =
 				self.channels.entry(ChannelId(self.last_channel_id.0))
 			{
@@ -444,9 +931,15 @@ impl Encrypted {
 					recipient_window_size:0,
 					sender_maximum_packet_size:maxpacket,
 					recipient_maximum_packet_size:0,
+					target_window_size:None,
 					confirmed:false,
 					wants_reply:false,
 					pending_data:std::collections::VecDeque::new(),
+					data_queued:0,
+					writers:std::collections::VecDeque::new(),
+					flushes:Vec::new(),
+					closes:Vec::new(),
+					pending_exit:None,
 				});
 
 				return ChannelId(self.last_channel_id.0);
@@ -509,15 +1002,37 @@ pub(crate) struct KexInit {
 	pub exchange:Exchange,
 	pub session_id:Option<CryptoVec>,
 	pub sent:bool,
+	pub none_cipher:negotiation::NoneCipher,
+	/// Our guessed key exchange, if its first packet was sent along with
+	/// the KEXINIT.
+	pub guessed:Option<Box<dyn KexAlgorithm + Send>>,
 }
 
 impl KexInit {
-	pub fn received_rekey(ex:Exchange, algo:negotiation::Names, session_id:&CryptoVec) -> Self {
+	pub fn new(exchange:Exchange) -> Self {
+		KexInit {
+			exchange,
+			algo:None,
+			sent:false,
+			session_id:None,
+			none_cipher:negotiation::NoneCipher::Unchanged,
+			guessed:None,
+		}
+	}
+
+	pub fn received_rekey(
+		ex:Exchange,
+		algo:negotiation::Names,
+		session_id:&CryptoVec,
+		none_cipher:negotiation::NoneCipher,
+	) -> Self {
 		let mut kexinit = KexInit {
 			exchange:ex,
 			algo:Some(algo),
 			sent:false,
 			session_id:Some(session_id.clone()),
+			none_cipher,
+			guessed:None,
 		};
 
 		kexinit.exchange.client_kex_init.clear();
@@ -531,9 +1046,19 @@ impl KexInit {
 		kexinit
 	}
 
-	pub fn initiate_rekey(ex:Exchange, session_id:&CryptoVec) -> Self {
-		let mut kexinit =
-			KexInit { exchange:ex, algo:None, sent:true, session_id:Some(session_id.clone()) };
+	pub fn initiate_rekey(
+		ex:Exchange,
+		session_id:&CryptoVec,
+		none_cipher:negotiation::NoneCipher,
+	) -> Self {
+		let mut kexinit = KexInit {
+			exchange:ex,
+			algo:None,
+			sent:true,
+			session_id:Some(session_id.clone()),
+			none_cipher,
+			guessed:None,
+		};
 
 		kexinit.exchange.client_kex_init.clear();
 
@@ -553,6 +1078,8 @@ pub(crate) struct KexDh {
 	pub names:negotiation::Names,
 	pub key:usize,
 	pub session_id:Option<CryptoVec>,
+	/// The algorithm, once a group exchange has started.
+	pub kex:Option<Box<dyn KexAlgorithm + Send>>,
 }
 
 pub(crate) struct KexDhDone {
@@ -568,26 +1095,53 @@ impl Debug for KexDhDone {
 }
 
 impl KexDhDone {
-	pub fn compute_keys(self, hash:CryptoVec, is_server:bool) -> Result<NewKeys, crate::Error> {
+	pub fn compute_keys(
+		self,
+		hash:CryptoVec,
+		ciphers:&cipher::CipherRegistry,
+		macs:&mac::MacRegistry,
+		is_server:bool,
+	) -> Result<NewKeys, crate::Error> {
 		let session_id =
 			if let Some(session_id) = self.session_id { session_id } else { hash.clone() };
+
+		let cipher = ciphers.get(&self.names.cipher).ok_or(crate::Error::UnknownAlgo)?;
+
+		let (remote_to_local_mac, local_to_remote_mac) = if is_server {
+			(self.names.client_mac, self.names.server_mac)
+		} else {
+			(self.names.server_mac, self.names.client_mac)
+		};
+
+		let remote_to_local_mac = macs.get(&remote_to_local_mac).ok_or(crate::Error::UnknownAlgo)?;
+
+		let local_to_remote_mac = macs.get(&local_to_remote_mac).ok_or(crate::Error::UnknownAlgo)?;
 		// Now computing keys.
 		let c = self.kex.compute_keys(
 			&session_id,
 			&hash,
-			self.names.cipher,
-			if is_server { self.names.client_mac } else { self.names.server_mac },
-			if is_server { self.names.server_mac } else { self.names.client_mac },
+			cipher,
+			remote_to_local_mac,
+			local_to_remote_mac,
 			is_server,
 		)?;
 
+		let (local_to_remote_compression, remote_to_local_compression) = if is_server {
+			(self.names.server_compression, self.names.client_compression)
+		} else {
+			(self.names.client_compression, self.names.server_compression)
+		};
+
 		Ok(NewKeys {
+			local_to_remote_compression,
+			remote_to_local_compression,
 			exchange:self.exchange,
 			names:self.names,
 			kex:self.kex,
 			key:self.key,
 			cipher:c,
 			session_id,
+			exchange_hash:hash,
 			sent:false,
 		})
 	}
@@ -600,6 +1154,225 @@ pub(crate) struct NewKeys {
 	pub kex:Box<dyn KexAlgorithm + Send>,
 	pub key:usize,
 	pub cipher:cipher::CipherPair,
+	pub local_to_remote_compression:crate::compression::Compression,
+	pub remote_to_local_compression:crate::compression::Compression,
 	pub session_id:CryptoVec,
+	pub exchange_hash:CryptoVec,
 	pub sent:bool,
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// Debug messages reach the handler of each side, and ignore messages
+	/// sent in the middle of a transfer leave it intact.
+	#[tokio::test]
+	async fn test_debug_and_ignore() {
+		const LEN:usize = 4 << 20;
+
+		const CHUNK:usize = 32768;
+
+		struct Client {
+			debug:UnboundedSender<(bool, String)>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn debug(
+				self,
+				always_display:bool,
+				message:&str,
+				_:&str,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				self.debug.send((always_display, message.to_string())).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			debug:UnboundedSender<(bool, String)>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				mut session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				session.send_debug(true, "welcome");
+
+				Ok((self, true, session))
+			}
+
+			async fn exec_request(
+				self,
+				channel:ChannelId,
+				_:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				session.channel_success(channel);
+
+				let handle = session.handle();
+
+				tokio::spawn(async move {
+					for i in 0..LEN / CHUNK {
+						let data = CryptoVec::from_slice(&[i as u8; CHUNK]);
+
+						handle.data(channel, data).await.unwrap();
+
+						// Some large enough to be split.
+						handle.send_ignore(i * 1000).await.unwrap();
+					}
+
+					handle.send_exit_and_close(channel, 0).await.unwrap();
+				});
+
+				Ok((self, session))
+			}
+
+			async fn debug(
+				self,
+				always_display:bool,
+				message:&str,
+				_:&str,
+				session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.debug.send((always_display, message.to_string())).unwrap();
+
+				Ok((self, session))
+			}
+		}
+
+		let (server_debug, mut server_received) = unbounded_channel();
+
+		let (client_debug, mut client_received) = unbounded_channel();
+
+		let (mut session, _server) = connect_with(
+			client::Config::default(),
+			server::Config::default(),
+			Client { debug:client_debug },
+			ServerHandle { debug:server_debug },
+		)
+		.await;
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		session.send_debug(false, "hello").await.unwrap();
+
+		session.send_ignore(100).await.unwrap();
+
+		let output = session.exec_collect("stream").await.unwrap();
+
+		assert_eq!(server_received.recv().await.unwrap(), (false, "hello".to_string()));
+
+		assert_eq!(client_received.recv().await.unwrap(), (true, "welcome".to_string()));
+
+		assert_eq!(output.exit_status, Some(0));
+
+		assert_eq!(output.stdout.len(), LEN);
+
+		assert!(output.stdout.chunks(CHUNK).enumerate().all(|(i, chunk)| {
+			chunk.iter().all(|&b| b == i as u8)
+		}));
+	}
+
+	#[tokio::test]
+	async fn test_rekey() {
+		test_session(
+			Client {},
+			EchoServer {},
+			|c| {
+				async move {
+					let mut ch = c.channel_open_session().await.unwrap();
+
+					for data in [&b"before rekey"[..], &b"after rekey"[..]] {
+						ch.data(data).await.unwrap();
+
+						let msg = ch.wait().await.unwrap();
+
+						if let ChannelMsg::Data { data: echo } = msg {
+							assert_eq!(echo.as_ref(), data);
+						} else {
+							panic!("Unexpected message {:?}", msg);
+						}
+
+						let exchange_hash = c.exchange_hash().await.unwrap();
+
+						c.rekey().await.unwrap();
+
+						// New keys, derived from a new exchange hash.
+						let new_exchange_hash = c.exchange_hash().await.unwrap();
+
+						assert_ne!(new_exchange_hash.as_ref(), exchange_hash.as_ref());
+					}
+
+					c
+				}
+			},
+			|s| async move { s },
+		)
+		.await;
+	}
+
+	/// Past the byte limits, the client re-exchanges keys by itself, without
+	/// losing or corrupting the data in flight.
+	#[tokio::test]
+	async fn test_rekey_limits() {
+		const LEN:usize = 1 << 20;
+
+		let client_config = client::Config {
+			limits:crate::Limits::new(1 << 16, 1 << 16, std::time::Duration::from_secs(3600)),
+			..Default::default()
+		};
+
+		let (mut session, _server) =
+			connect_with(client_config, server::Config::default(), Client {}, EchoServer {}).await;
+
+		assert!(session.authenticate_none("user").await.unwrap().success());
+
+		let session_id = session.session_id().to_vec();
+
+		let data:Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+		let mut stream = session.channel_open_session().await.unwrap().into_stream();
+
+		let mut echo = vec![0; LEN];
+
+		// A chunk at a time, as the session loops do not read while they
+		// write, and the in-memory stream only holds 64 KiB each way.
+		for (sent, echoed) in data.chunks(1 << 14).zip(echo.chunks_mut(1 << 14)) {
+			stream.write_all(sent).await.unwrap();
+
+			stream.read_exact(echoed).await.unwrap();
+		}
+
+		assert!(echo == data);
+
+		// Each limit was passed 16 times.
+		assert!(session.stats().rekeys >= 8);
+
+		assert_eq!(session.session_id(), &session_id[..]);
+
+		assert_ne!(session.exchange_hash().await.unwrap().as_ref(), &session_id[..]);
+	}
+}
@@ -0,0 +1,1227 @@
+//! An SFTP version 3 client, on a channel running the `sftp` subsystem.
+//!
+//! Requests may be sent concurrently from several tasks: each has its own
+//! request ID, and replies are matched to them as they come. Reads and
+//! writes are split into 32 KiB requests, which is what most servers
+//! accept, and large transfers keep several requests in flight.
+//!
+//! ```no_run
+//! # async fn run<H:russh::client::Handler>(
+//! # 	session:&russh::client::Handle<H>,
+//! # ) -> Result<(), russh::Error> {
+//! use russh::sftp::SftpSession;
+//!
+//! let mut channel = session.channel_open_session().await?;
+//!
+//! channel.request_subsystem(true, "sftp").await?;
+//!
+//! let sftp = SftpSession::new(channel).await?;
+//!
+//! sftp.upload("hello.txt", b"Hello, world!\n").await?;
+//!
+//! for entry in sftp.read_dir(".").await? {
+//! 	println!("{}", entry.longname);
+//! }
+//!
+//! assert_eq!(sftp.download("hello.txt").await?, b"Hello, world!\n");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The [`server`] module serves the `sftp` subsystem.
+
+mod protocol;
+
+pub mod server;
+
+pub use protocol::{DirEntry, FileAttributes, OpenFlags, Status, StatusCode};
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		Arc,
+		atomic::{AtomicU32, Ordering},
+	},
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use protocol::*;
+use russh_keys::encoding::{Encoding, Position, Reader};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+	sync::{Mutex, oneshot},
+	task::JoinHandle,
+};
+
+use crate::{Channel, ChannelId, ChannelMsg, ChannelStream, Error};
+
+/// The size of read and write requests.
+const CHUNK_LEN:usize = 32 * 1024;
+
+/// How many read or write requests a transfer keeps in flight, as
+/// OpenSSH's `sftp -R`.
+const PIPELINE:usize = 64;
+
+/// The longest packet accepted, as OpenSSH's `SFTP_MAX_MSG_LENGTH`.
+const MAX_PACKET_LEN:usize = 256 * 1024;
+
+/// An open file or directory on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHandle(Vec<u8>);
+
+/// A reply, with its type and the whole packet.
+struct Response {
+	kind:u8,
+	packet:Vec<u8>,
+}
+
+impl Response {
+	/// A reader after the type and the request ID.
+	fn reader(&self) -> Position<'_> { self.packet.reader(5) }
+
+	/// Check an `SSH_FXP_STATUS` reply.
+	fn status(&self) -> Result<(), Error> {
+		match self.kind {
+			SSH_FXP_STATUS => {
+				let status = Status::decode(&mut self.reader())?;
+
+				if status.code == StatusCode::Ok { Ok(()) } else { Err(Error::Sftp(status)) }
+			},
+			_ => Err(Error::Inconsistent),
+		}
+	}
+
+	/// The error of a reply of an unexpected type.
+	fn unexpected(&self) -> Error {
+		match self.status() {
+			Ok(()) => Error::Inconsistent,
+			Err(e) => e,
+		}
+	}
+
+	fn handle(&self) -> Result<FileHandle, Error> {
+		if self.kind != SSH_FXP_HANDLE {
+			return Err(self.unexpected());
+		}
+
+		Ok(FileHandle(self.reader().read_string()?.to_vec()))
+	}
+
+	fn attrs(&self) -> Result<FileAttributes, Error> {
+		if self.kind != SSH_FXP_ATTRS {
+			return Err(self.unexpected());
+		}
+
+		Ok(FileAttributes::decode(&mut self.reader())?)
+	}
+
+	fn names(&self) -> Result<Vec<DirEntry>, Error> {
+		if self.kind != SSH_FXP_NAME {
+			return Err(self.unexpected());
+		}
+
+		let mut r = self.reader();
+
+		let count = r.read_u32()?;
+
+		let mut names = Vec::new();
+
+		for _ in 0..count {
+			names.push(DirEntry::decode(&mut r)?);
+		}
+
+		Ok(names)
+	}
+
+	/// The single name of the reply to `SSH_FXP_REALPATH` or
+	/// `SSH_FXP_READLINK`.
+	fn name(&self) -> Result<String, Error> {
+		self.names()?.pop().map(|entry| entry.filename).ok_or(Error::Inconsistent)
+	}
+}
+
+/// The requests waiting for their reply.
+#[derive(Default)]
+struct Pending {
+	requests:HashMap<u32, oneshot::Sender<Response>>,
+	/// Whether the server has closed the channel.
+	closed:bool,
+}
+
+/// An SFTP session, see the [module documentation](self).
+pub struct SftpSession {
+	writer:Mutex<WriteHalf<ChannelStream>>,
+	pending:Arc<Mutex<Pending>>,
+	next_id:AtomicU32,
+	version:u32,
+	extensions:Vec<(String, String)>,
+	reader:JoinHandle<()>,
+}
+
+impl std::fmt::Debug for SftpSession {
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SftpSession")
+			.field("version", &self.version)
+			.field("extensions", &self.extensions)
+			.finish()
+	}
+}
+
+impl Drop for SftpSession {
+	fn drop(&mut self) { self.reader.abort() }
+}
+
+impl SftpSession {
+	/// Start a session on `channel`, on which the `sftp` subsystem was
+	/// requested, with [`Channel::request_subsystem`].
+	pub async fn new<S:From<(ChannelId, ChannelMsg)> + Send + 'static>(
+		channel:Channel<S>,
+	) -> Result<Self, Error> {
+		let (mut reader, mut writer) = tokio::io::split(channel.into_stream());
+
+		let mut init = Vec::with_capacity(9);
+
+		init.extend_from_slice(&5u32.to_be_bytes());
+
+		init.push(SSH_FXP_INIT);
+
+		init.extend_from_slice(&VERSION.to_be_bytes());
+
+		writer.write_all(&init).await?;
+
+		let packet = read_packet(&mut reader).await?;
+
+		let mut r = packet.reader(0);
+
+		if r.read_byte()? != SSH_FXP_VERSION {
+			return Err(Error::Inconsistent);
+		}
+
+		let version = r.read_u32()?;
+
+		let mut extensions = Vec::new();
+
+		while !r.is_at_end() {
+			let name = String::from_utf8_lossy(r.read_string()?).into_owned();
+
+			let data = String::from_utf8_lossy(r.read_string()?).into_owned();
+
+			extensions.push((name, data));
+		}
+
+		debug!("sftp version {}, extensions {:?}", version, extensions);
+
+		let pending = Arc::new(Mutex::new(Pending::default()));
+
+		let reader = tokio::spawn(read_replies(reader, pending.clone()));
+
+		Ok(SftpSession {
+			writer:Mutex::new(writer),
+			pending,
+			next_id:AtomicU32::new(0),
+			version,
+			extensions,
+			reader,
+		})
+	}
+
+	/// The protocol version of the server, 3 for OpenSSH.
+	pub fn version(&self) -> u32 { self.version }
+
+	/// The extensions announced by the server, with their data.
+	pub fn extensions(&self) -> &[(String, String)] { &self.extensions }
+
+	/// Send a request, whose fields after the request ID are in `payload`,
+	/// and wait for the reply.
+	async fn request(&self, kind:u8, payload:&[u8]) -> Result<Response, Error> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+		let (sender, reply) = oneshot::channel();
+
+		{
+			let mut pending = self.pending.lock().await;
+
+			if pending.closed {
+				return Err(Error::Disconnect);
+			}
+
+			pending.requests.insert(id, sender);
+		}
+
+		let mut packet = Vec::with_capacity(9 + payload.len());
+
+		packet.extend_from_slice(&(5 + payload.len() as u32).to_be_bytes());
+
+		packet.push(kind);
+
+		packet.extend_from_slice(&id.to_be_bytes());
+
+		packet.extend_from_slice(payload);
+
+		self.writer.lock().await.write_all(&packet).await?;
+
+		reply.await.map_err(|_| Error::Disconnect)
+	}
+
+	/// A request whose only field is a path.
+	async fn path_request(&self, kind:u8, path:&str) -> Result<Response, Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(path.as_bytes());
+
+		self.request(kind, &payload).await
+	}
+
+	/// A request whose only field is a handle.
+	async fn handle_request(&self, kind:u8, handle:&FileHandle) -> Result<Response, Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(&handle.0);
+
+		self.request(kind, &payload).await
+	}
+
+	/// Open the file at `path`. `attrs` are the attributes of a created
+	/// file, for instance its permissions.
+	pub async fn open(
+		&self,
+		path:&str,
+		flags:OpenFlags,
+		attrs:&FileAttributes,
+	) -> Result<FileHandle, Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(path.as_bytes());
+
+		payload.extend_from_slice(&flags.bits().to_be_bytes());
+
+		attrs.encode(&mut payload);
+
+		self.request(SSH_FXP_OPEN, &payload).await?.handle()
+	}
+
+	/// Close a file or a directory.
+	pub async fn close(&self, handle:&FileHandle) -> Result<(), Error> {
+		self.handle_request(SSH_FXP_CLOSE, handle).await?.status()
+	}
+
+	/// Read up to `len` bytes at `offset`, at most 32 KiB. Returns `None` at
+	/// the end of the file. Servers may return less than asked, even
+	/// before the end of the file.
+	pub async fn read(
+		&self,
+		handle:&FileHandle,
+		offset:u64,
+		len:u32,
+	) -> Result<Option<Vec<u8>>, Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(&handle.0);
+
+		payload.extend_from_slice(&offset.to_be_bytes());
+
+		payload.extend_from_slice(&len.min(CHUNK_LEN as u32).to_be_bytes());
+
+		let response = self.request(SSH_FXP_READ, &payload).await?;
+
+		match response.kind {
+			SSH_FXP_DATA => {
+				let data = response.reader().read_string()?.to_vec();
+
+				// An empty read would never end a transfer.
+				if data.is_empty() { Ok(None) } else { Ok(Some(data)) }
+			},
+			_ => match response.status() {
+				Err(Error::Sftp(Status { code:StatusCode::Eof, .. })) => Ok(None),
+				Err(e) => Err(e),
+				Ok(()) => Err(Error::Inconsistent),
+			},
+		}
+	}
+
+	/// Read a file from the beginning to the end, keeping several
+	/// requests in flight.
+	pub async fn read_to_end(&self, handle:&FileHandle) -> Result<Vec<u8>, Error> {
+		let mut data = Vec::new();
+
+		// The chunks received after the end of `data`, by offset.
+		let mut received = BTreeMap::new();
+
+		let mut requests = FuturesUnordered::new();
+
+		let mut next = 0;
+
+		loop {
+			while requests.len() < PIPELINE {
+				let offset = next;
+
+				requests.push(async move {
+					(offset, self.read(handle, offset, CHUNK_LEN as u32).await)
+				});
+
+				next += CHUNK_LEN as u64;
+			}
+
+			let (offset, chunk) = match requests.next().await {
+				Some(reply) => reply,
+				None => return Ok(data),
+			};
+
+			received.insert(offset, chunk?);
+
+			while let Some(chunk) = received.remove(&(data.len() as u64)) {
+				let chunk = match chunk {
+					Some(chunk) => chunk,
+					None => return Ok(data),
+				};
+
+				data.extend_from_slice(&chunk);
+
+				// The rest of this chunk is missing: read again from there.
+				// The replies already received stay valid.
+				if chunk.len() < CHUNK_LEN {
+					next = data.len() as u64;
+				}
+			}
+
+			let end = data.len() as u64;
+
+			received = received.split_off(&end);
+		}
+	}
+
+	/// Write `data` at `offset`, keeping several requests in flight.
+	pub async fn write(&self, handle:&FileHandle, offset:u64, data:&[u8]) -> Result<(), Error> {
+		let mut chunks = data.chunks(CHUNK_LEN);
+
+		let mut requests = FuturesUnordered::new();
+
+		let mut chunk_offset = offset;
+
+		loop {
+			while requests.len() < PIPELINE {
+				let chunk = if let Some(chunk) = chunks.next() { chunk } else { break };
+
+				let mut payload = Vec::with_capacity(chunk.len() + handle.0.len() + 16);
+
+				payload.extend_ssh_string(&handle.0);
+
+				payload.extend_from_slice(&chunk_offset.to_be_bytes());
+
+				payload.extend_ssh_string(chunk);
+
+				requests.push(async move {
+					let response = self.request(SSH_FXP_WRITE, &payload).await?;
+
+					response.status()
+				});
+
+				chunk_offset += chunk.len() as u64;
+			}
+
+			match requests.next().await {
+				Some(result) => result?,
+				None => return Ok(()),
+			}
+		}
+	}
+
+	/// The attributes of a file, following symbolic links.
+	pub async fn stat(&self, path:&str) -> Result<FileAttributes, Error> {
+		self.path_request(SSH_FXP_STAT, path).await?.attrs()
+	}
+
+	/// The attributes of a file, without following symbolic links.
+	pub async fn lstat(&self, path:&str) -> Result<FileAttributes, Error> {
+		self.path_request(SSH_FXP_LSTAT, path).await?.attrs()
+	}
+
+	/// The attributes of an open file.
+	pub async fn fstat(&self, handle:&FileHandle) -> Result<FileAttributes, Error> {
+		self.handle_request(SSH_FXP_FSTAT, handle).await?.attrs()
+	}
+
+	/// Change the attributes of a file, for instance its size or its
+	/// permissions.
+	pub async fn setstat(&self, path:&str, attrs:&FileAttributes) -> Result<(), Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(path.as_bytes());
+
+		attrs.encode(&mut payload);
+
+		self.request(SSH_FXP_SETSTAT, &payload).await?.status()
+	}
+
+	/// Change the attributes of an open file.
+	pub async fn fsetstat(&self, handle:&FileHandle, attrs:&FileAttributes) -> Result<(), Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(&handle.0);
+
+		attrs.encode(&mut payload);
+
+		self.request(SSH_FXP_FSETSTAT, &payload).await?.status()
+	}
+
+	/// Open a directory, to list it with [`SftpSession::readdir`].
+	pub async fn opendir(&self, path:&str) -> Result<FileHandle, Error> {
+		self.path_request(SSH_FXP_OPENDIR, path).await?.handle()
+	}
+
+	/// The next entries of a directory, `None` once they have all been
+	/// returned.
+	pub async fn readdir(&self, handle:&FileHandle) -> Result<Option<Vec<DirEntry>>, Error> {
+		let response = self.handle_request(SSH_FXP_READDIR, handle).await?;
+
+		match response.names() {
+			Ok(names) => Ok(Some(names)),
+			Err(Error::Sftp(Status { code:StatusCode::Eof, .. })) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// All the entries of a directory, including `.` and `..` on most
+	/// servers.
+	pub async fn read_dir(&self, path:&str) -> Result<Vec<DirEntry>, Error> {
+		let handle = self.opendir(path).await?;
+
+		let mut entries = Vec::new();
+
+		let result = loop {
+			match self.readdir(&handle).await {
+				Ok(Some(names)) => entries.extend(names),
+				Ok(None) => break Ok(entries),
+				Err(e) => break Err(e),
+			}
+		};
+
+		self.close(&handle).await?;
+
+		result
+	}
+
+	/// Create a directory.
+	pub async fn mkdir(&self, path:&str, attrs:&FileAttributes) -> Result<(), Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(path.as_bytes());
+
+		attrs.encode(&mut payload);
+
+		self.request(SSH_FXP_MKDIR, &payload).await?.status()
+	}
+
+	/// Remove an empty directory.
+	pub async fn rmdir(&self, path:&str) -> Result<(), Error> {
+		self.path_request(SSH_FXP_RMDIR, path).await?.status()
+	}
+
+	/// Rename a file or a directory. Servers usually refuse to replace an
+	/// existing file.
+	pub async fn rename(&self, old_path:&str, new_path:&str) -> Result<(), Error> {
+		let mut payload = Vec::new();
+
+		payload.extend_ssh_string(old_path.as_bytes());
+
+		payload.extend_ssh_string(new_path.as_bytes());
+
+		self.request(SSH_FXP_RENAME, &payload).await?.status()
+	}
+
+	/// Remove a file.
+	pub async fn remove(&self, path:&str) -> Result<(), Error> {
+		self.path_request(SSH_FXP_REMOVE, path).await?.status()
+	}
+
+	/// The target of a symbolic link.
+	pub async fn readlink(&self, path:&str) -> Result<String, Error> {
+		self.path_request(SSH_FXP_READLINK, path).await?.name()
+	}
+
+	/// Create a symbolic link at `link_path`, pointing to `target`.
+	pub async fn symlink(&self, target:&str, link_path:&str) -> Result<(), Error> {
+		let mut payload = Vec::new();
+
+		// The draft sends the link path first, but OpenSSH, whose order
+		// most servers follow, sends the target first.
+		payload.extend_ssh_string(target.as_bytes());
+
+		payload.extend_ssh_string(link_path.as_bytes());
+
+		self.request(SSH_FXP_SYMLINK, &payload).await?.status()
+	}
+
+	/// The canonical absolute path of `path`, for instance of `.` to know
+	/// the current directory.
+	pub async fn realpath(&self, path:&str) -> Result<String, Error> {
+		self.path_request(SSH_FXP_REALPATH, path).await?.name()
+	}
+
+	/// The contents of the file at `path`.
+	pub async fn download(&self, path:&str) -> Result<Vec<u8>, Error> {
+		let handle = self.open(path, OpenFlags::READ, &FileAttributes::default()).await?;
+
+		let result = self.read_to_end(&handle).await;
+
+		self.close(&handle).await?;
+
+		result
+	}
+
+	/// Create or replace the file at `path` with `data`.
+	pub async fn upload(&self, path:&str, data:&[u8]) -> Result<(), Error> {
+		let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+
+		let handle = self.open(path, flags, &FileAttributes::default()).await?;
+
+		let result = self.write(&handle, 0, data).await;
+
+		self.close(&handle).await?;
+
+		result
+	}
+}
+
+async fn read_packet<R:AsyncRead + Unpin>(reader:&mut R) -> Result<Vec<u8>, Error> {
+	let len = reader.read_u32().await? as usize;
+
+	if len == 0 || len > MAX_PACKET_LEN {
+		return Err(Error::Inconsistent);
+	}
+
+	let mut packet = vec![0; len];
+
+	reader.read_exact(&mut packet).await?;
+
+	Ok(packet)
+}
+
+/// Pass the replies to the requests waiting for them, until the channel
+/// is closed.
+async fn read_replies(mut reader:ReadHalf<ChannelStream>, pending:Arc<Mutex<Pending>>) {
+	loop {
+		let packet = match read_packet(&mut reader).await {
+			Ok(packet) => packet,
+			Err(e) => {
+				debug!("sftp: {:?}", e);
+
+				break;
+			},
+		};
+
+		let mut r = packet.reader(0);
+
+		let (kind, id) = match (r.read_byte(), r.read_u32()) {
+			(Ok(kind), Ok(id)) => (kind, id),
+			_ => break,
+		};
+
+		if let Some(sender) = pending.lock().await.requests.remove(&id) {
+			sender.send(Response { kind, packet }).unwrap_or(());
+		} else {
+			debug!("sftp: reply to an unknown request {}", id);
+		}
+	}
+
+	let mut pending = pending.lock().await;
+
+	pending.closed = true;
+
+	pending.requests.clear();
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_attributes_round_trip() {
+		let attrs = FileAttributes {
+			size:Some(1 << 40),
+			uid:Some(1000),
+			gid:Some(100),
+			permissions:Some(0o100_644),
+			atime:Some(1_700_000_000),
+			mtime:Some(1_700_000_001),
+			extended:vec![(b"name@example.com".to_vec(), b"value".to_vec())],
+		};
+
+		let mut encoded = Vec::new();
+
+		attrs.encode(&mut encoded);
+
+		assert_eq!(encoded.get(..4), Some(&[0x80, 0, 0, 0xf][..]));
+
+		let decoded = FileAttributes::decode(&mut encoded.reader(0)).unwrap();
+
+		assert_eq!(decoded, attrs);
+
+		assert!(decoded.is_file() && !decoded.is_dir());
+
+		// A lone `uid` is not sent.
+		let mut encoded = Vec::new();
+
+		FileAttributes { uid:Some(0), ..Default::default() }.encode(&mut encoded);
+
+		assert_eq!(encoded, [0, 0, 0, 0]);
+	}
+
+	#[test]
+	#[allow(clippy::unwrap_used)]
+	fn test_replies() {
+		// `SSH_FXP_NAME`, with one name, as a reply to request 7.
+		let mut packet = vec![SSH_FXP_NAME, 0, 0, 0, 7, 0, 0, 0, 1];
+
+		packet.extend_ssh_string(b"/home/user");
+
+		packet.extend_ssh_string(b"");
+
+		packet.extend_from_slice(&4u32.to_be_bytes());
+
+		packet.extend_from_slice(&0o040_755u32.to_be_bytes());
+
+		let response = Response { kind:SSH_FXP_NAME, packet };
+
+		assert_eq!(response.name().unwrap(), "/home/user");
+
+		assert!(response.names().unwrap().first().unwrap().attrs.is_dir());
+
+		// `SSH_FXP_STATUS`, without a message.
+		let packet = vec![SSH_FXP_STATUS, 0, 0, 0, 8, 0, 0, 0, 2];
+
+		let response = Response { kind:SSH_FXP_STATUS, packet };
+
+		assert!(matches!(
+			response.handle(),
+			Err(Error::Sftp(Status { code:StatusCode::NoSuchFile, .. }))
+		));
+	}
+}
+
+#[cfg(test)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// A round trip between the SFTP client and server, serving a
+	/// temporary directory.
+	#[cfg(all(unix, feature = "sftp"))]
+	#[tokio::test]
+	async fn test_sftp_subsystem() {
+		use crate::sftp::{
+			FileAttributes,
+			OpenFlags,
+			SftpSession,
+			StatusCode,
+			server::{FsHandler, SftpServer},
+		};
+
+		struct ServerHandle {
+			root:std::path::PathBuf,
+			channel:Option<Channel<server::Msg>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				mut self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				self.channel = Some(channel);
+
+				Ok((self, true, session))
+			}
+
+			async fn subsystem_request(
+				mut self,
+				channel:ChannelId,
+				name:&str,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				match self.channel.take() {
+					Some(sftp) if name == "sftp" => {
+						session.channel_success(channel);
+
+						let server = SftpServer::new(FsHandler::new(self.root.clone()));
+
+						tokio::spawn(server.run(sftp));
+					},
+					_ => session.channel_failure(channel),
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let root = std::env::temp_dir().join(format!("russh-sftp-root-{}", std::process::id()));
+
+		std::fs::remove_dir_all(&root).ok();
+
+		std::fs::create_dir(&root).unwrap();
+
+		test_session(
+			Client {},
+			ServerHandle { root:root.clone(), channel:None },
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					channel.request_subsystem(true, "sftp").await.unwrap();
+
+					let sftp = SftpSession::new(channel).await.unwrap();
+
+					assert_eq!(sftp.version(), 3);
+
+					assert_eq!(sftp.realpath(".").await.unwrap(), "/");
+
+					assert_eq!(sftp.realpath("/../dir/./a/..").await.unwrap(), "/dir");
+
+					sftp.mkdir("dir", &FileAttributes::default()).await.unwrap();
+
+					// Larger than the pipeline, and not a multiple of the chunks.
+					let large:Vec<u8> = (0..3 * 1024 * 1024 + 1234).map(|i| i as u8).collect();
+
+					let (uploaded, small) = tokio::join!(
+						sftp.upload("/dir/large.bin", &large),
+						sftp.upload("dir/small.txt", b"small\n")
+					);
+
+					uploaded.unwrap();
+
+					small.unwrap();
+
+					let mut names:Vec<_> = sftp
+						.read_dir("/dir")
+						.await
+						.unwrap()
+						.into_iter()
+						.map(|entry| entry.filename)
+						.collect();
+
+					names.sort();
+
+					assert_eq!(names, ["large.bin", "small.txt"]);
+
+					let attrs = sftp.stat("/dir/large.bin").await.unwrap();
+
+					assert_eq!(attrs.size, Some(large.len() as u64));
+
+					assert!(attrs.is_file());
+
+					assert!(sftp.stat("/dir").await.unwrap().is_dir());
+
+					assert_eq!(sftp.download("/dir/large.bin").await.unwrap(), large);
+
+					// `..` does not leave the root.
+					assert_eq!(sftp.download("/../../dir/small.txt").await.unwrap(), b"small\n");
+
+					// Renaming does not replace an existing file.
+					let status = sftp.rename("/dir/small.txt", "/dir/large.bin").await;
+
+					assert!(matches!(
+						status,
+						Err(crate::Error::Sftp(crate::sftp::Status {
+							code:StatusCode::Failure,
+							..
+						}))
+					));
+
+					sftp.rename("/dir/small.txt", "/dir/renamed.txt").await.unwrap();
+
+					sftp.symlink("renamed.txt", "/dir/link").await.unwrap();
+
+					assert_eq!(sftp.readlink("/dir/link").await.unwrap(), "/dir/renamed.txt");
+
+					assert!(sftp.lstat("/dir/link").await.unwrap().is_symlink());
+
+					assert_eq!(sftp.download("/dir/link").await.unwrap(), b"small\n");
+
+					let attrs = FileAttributes {
+						permissions:Some(0o600),
+						atime:Some(1_000_000_000),
+						mtime:Some(1_000_000_000),
+						..Default::default()
+					};
+
+					sftp.setstat("/dir/renamed.txt", &attrs).await.unwrap();
+
+					let changed = sftp.stat("/dir/renamed.txt").await.unwrap();
+
+					assert_eq!(changed.permissions.map(|p| p & 0o777), Some(0o600));
+
+					assert_eq!(changed.mtime, Some(1_000_000_000));
+
+					// Reading past the end. The file is left open, for the
+					// server to close.
+					let file = sftp
+						.open("/dir/renamed.txt", OpenFlags::READ, &FileAttributes::default())
+						.await
+						.unwrap();
+
+					assert_eq!(sftp.read(&file, 6, 1024).await.unwrap(), None);
+
+					match sftp.stat("/dir/small.txt").await {
+						Err(crate::Error::Sftp(status)) => {
+							assert_eq!(status.code, StatusCode::NoSuchFile)
+						},
+						result => panic!("unexpected result: {:?}", result),
+					}
+
+					let create = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUDE;
+
+					let attrs = FileAttributes::default();
+
+					let exists = sftp.open("/dir/large.bin", create, &attrs).await;
+
+					assert!(matches!(exists, Err(crate::Error::Sftp(_))));
+
+					for path in ["/dir/link", "/dir/renamed.txt", "/dir/large.bin"] {
+						sftp.remove(path).await.unwrap();
+					}
+
+					sftp.rmdir("/dir").await.unwrap();
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+
+		assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+
+		std::fs::remove_dir(&root).unwrap();
+	}
+
+	/// Upload and download files with OpenSSH's `sftp`, against a russh
+	/// server serving a temporary directory with
+	/// [`FsHandler`](crate::sftp::server::FsHandler). Run with `cargo test
+	/// --features sftp -- --ignored`, with `sftp` in the `PATH`.
+	#[cfg(all(unix, feature = "sftp", feature = "rs-crypto"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_sftp_subsystem_openssh() {
+		use std::sync::Arc;
+
+		use tokio::{net::TcpListener, process::Command};
+
+		use crate::sftp::server::{FsHandler, SftpServer};
+
+		struct ServerHandle {
+			root:std::path::PathBuf,
+			channel:Option<Channel<server::Msg>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				mut self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				self.channel = Some(channel);
+
+				Ok((self, true, session))
+			}
+
+			async fn subsystem_request(
+				mut self,
+				channel:ChannelId,
+				name:&str,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				match self.channel.take() {
+					Some(sftp) if name == "sftp" => {
+						session.channel_success(channel);
+
+						let server = SftpServer::new(FsHandler::new(self.root.clone()));
+
+						tokio::spawn(server.run(sftp));
+					},
+					_ => session.channel_failure(channel),
+				}
+
+				Ok((self, session))
+			}
+		}
+
+		let dir = std::env::temp_dir().join(format!("russh-sftp-openssh-{}", std::process::id()));
+
+		std::fs::remove_dir_all(&dir).ok();
+
+		let (root, local) = (dir.join("root"), dir.join("local"));
+
+		std::fs::create_dir_all(&root).unwrap();
+
+		std::fs::create_dir_all(&local).unwrap();
+
+		let data:Vec<u8> = (0..1024 * 1024 + 1234).map(|i| (i % 251) as u8).collect();
+
+		std::fs::write(local.join("upload.bin"), &data).unwrap();
+
+		let batch = dir.join("batch");
+
+		std::fs::write(
+			&batch,
+			format!(
+				"mkdir sub\nput {0}/upload.bin sub/upload.bin\nrename sub/upload.bin \
+				 sub/renamed.bin\nls -l sub\nget sub/renamed.bin {0}/download.bin\n",
+				local.display()
+			),
+		)
+		.unwrap();
+
+		let mut config = server::Config::default();
+
+		config.keys.push(russh_keys::key::KeyPair::generate_ed25519().unwrap());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let port = listener.local_addr().unwrap().port();
+
+		let handler = ServerHandle { root:root.clone(), channel:None };
+
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+
+			server::run_stream(Arc::new(config), stream, handler).await.unwrap().await.ok();
+		});
+
+		let output = Command::new("sftp")
+			.arg("-b")
+			.arg(&batch)
+			.args(["-P", &port.to_string()])
+			.args(["-o", "BatchMode=yes"])
+			.args(["-o", "StrictHostKeyChecking=no"])
+			.args(["-o", "UserKnownHostsFile=/dev/null"])
+			.arg("user@127.0.0.1")
+			.output()
+			.await
+			.unwrap();
+
+		let stdout = String::from_utf8_lossy(&output.stdout);
+
+		assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+
+		assert!(stdout.contains("renamed.bin"));
+
+		assert_eq!(std::fs::read(root.join("sub/renamed.bin")).unwrap(), data);
+
+		assert_eq!(std::fs::read(local.join("download.bin")).unwrap(), data);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// A directory round trip against OpenSSH's `sftp-server`, run by a
+	/// russh server for the `sftp` subsystem. Run with `cargo test
+	/// --features sftp -- --ignored`, with `sftp-server` in
+	/// `/usr/lib/openssh`, or at the path in `SFTP_SERVER`.
+	#[cfg(all(unix, feature = "sftp"))]
+	#[tokio::test]
+	#[ignore]
+	async fn test_sftp_server() {
+		use std::process::Stdio;
+
+		use rand::RngCore;
+		use tokio::process::Command;
+
+		use crate::sftp::{FileAttributes, SftpSession, StatusCode};
+
+		struct ServerHandle {
+			channel:Option<Channel<server::Msg>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				mut self,
+				channel:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				self.channel = Some(channel);
+
+				Ok((self, true, session))
+			}
+
+			async fn subsystem_request(
+				mut self,
+				channel:ChannelId,
+				name:&str,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				let stream = match self.channel.take() {
+					Some(stream) if name == "sftp" => stream.into_stream(),
+					_ => {
+						session.channel_failure(channel);
+
+						return Ok((self, session));
+					},
+				};
+
+				let path = std::env::var("SFTP_SERVER")
+					.unwrap_or_else(|_| "/usr/lib/openssh/sftp-server".to_string());
+
+				let mut child = Command::new(path)
+					.stdin(Stdio::piped())
+					.stdout(Stdio::piped())
+					.spawn()?;
+
+				let (mut stdin, mut stdout) = (child.stdin.take(), child.stdout.take());
+
+				let (mut read, mut write) = tokio::io::split(stream);
+
+				tokio::spawn(async move {
+					if let Some(ref mut stdin) = stdin {
+						tokio::io::copy(&mut read, stdin).await.ok();
+					}
+				});
+
+				tokio::spawn(async move {
+					if let Some(ref mut stdout) = stdout {
+						tokio::io::copy(stdout, &mut write).await.ok();
+					}
+
+					write.shutdown().await.ok();
+
+					child.wait().await.ok();
+				});
+
+				session.channel_success(channel);
+
+				Ok((self, session))
+			}
+		}
+
+		let dir = std::env::temp_dir().join(format!("russh-sftp-{}", std::process::id()));
+
+		std::fs::remove_dir_all(&dir).ok();
+
+		let dir = dir.to_str().unwrap().to_string();
+
+		test_session(
+			Client {},
+			ServerHandle { channel:None },
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					channel.request_subsystem(true, "sftp").await.unwrap();
+
+					let sftp = SftpSession::new(channel).await.unwrap();
+
+					assert_eq!(sftp.version(), 3);
+
+					sftp.mkdir(&dir, &FileAttributes::default()).await.unwrap();
+
+					assert_eq!(sftp.realpath(&format!("{}/.", dir)).await.unwrap(), dir);
+
+					// Larger than the pipeline, and not a multiple of the chunks.
+					let mut large = vec![0; 5 * 1024 * 1024 + 1234];
+
+					rand::thread_rng().fill_bytes(&mut large);
+
+					let (large_path, small_path) =
+						(format!("{}/large.bin", dir), format!("{}/small.txt", dir));
+
+					let (uploaded, small) = tokio::join!(
+						sftp.upload(&large_path, &large),
+						sftp.upload(&small_path, b"small\n")
+					);
+
+					uploaded.unwrap();
+
+					small.unwrap();
+
+					let mut names:Vec<_> = sftp
+						.read_dir(&dir)
+						.await
+						.unwrap()
+						.into_iter()
+						.map(|entry| entry.filename)
+						.filter(|name| name != "." && name != "..")
+						.collect();
+
+					names.sort();
+
+					assert_eq!(names, ["large.bin", "small.txt"]);
+
+					let size = sftp.stat(&large_path).await.unwrap().size;
+
+					assert_eq!(size, Some(large.len() as u64));
+
+					assert!(sftp.stat(&dir).await.unwrap().is_dir());
+
+					assert_eq!(sftp.download(&large_path).await.unwrap(), large);
+
+					let renamed = format!("{}/renamed.txt", dir);
+
+					sftp.rename(&small_path, &renamed).await.unwrap();
+
+					let link = format!("{}/link", dir);
+
+					sftp.symlink(&renamed, &link).await.unwrap();
+
+					assert_eq!(sftp.readlink(&link).await.unwrap(), renamed);
+
+					assert!(sftp.lstat(&link).await.unwrap().is_symlink());
+
+					assert_eq!(sftp.download(&link).await.unwrap(), b"small\n");
+
+					let attrs = FileAttributes { permissions:Some(0o600), ..Default::default() };
+
+					sftp.setstat(&renamed, &attrs).await.unwrap();
+
+					let permissions = sftp.stat(&renamed).await.unwrap().permissions;
+
+					assert_eq!(permissions.map(|p| p & 0o777), Some(0o600));
+
+					match sftp.stat(&small_path).await {
+						Err(crate::Error::Sftp(status)) => {
+							assert_eq!(status.code, StatusCode::NoSuchFile)
+						},
+						result => panic!("unexpected result: {:?}", result),
+					}
+
+					for path in [&link, &renamed, &large_path] {
+						sftp.remove(path).await.unwrap();
+					}
+
+					sftp.rmdir(&dir).await.unwrap();
+
+					client
+				}
+			},
+			|server| async move { server },
+		)
+		.await;
+	}
+}
@@ -0,0 +1,332 @@
+//! The SFTP version 3 wire format, from
+//! [draft-ietf-secsh-filexfer-02](https://tools.ietf.org/html/draft-ietf-secsh-filexfer-02).
+
+use std::io::ErrorKind;
+
+use bitflags::bitflags;
+use russh_keys::encoding::{Encoding, Position};
+
+pub const VERSION:u32 = 3;
+
+pub const SSH_FXP_INIT:u8 = 1;
+pub const SSH_FXP_VERSION:u8 = 2;
+pub const SSH_FXP_OPEN:u8 = 3;
+pub const SSH_FXP_CLOSE:u8 = 4;
+pub const SSH_FXP_READ:u8 = 5;
+pub const SSH_FXP_WRITE:u8 = 6;
+pub const SSH_FXP_LSTAT:u8 = 7;
+pub const SSH_FXP_FSTAT:u8 = 8;
+pub const SSH_FXP_SETSTAT:u8 = 9;
+pub const SSH_FXP_FSETSTAT:u8 = 10;
+pub const SSH_FXP_OPENDIR:u8 = 11;
+pub const SSH_FXP_READDIR:u8 = 12;
+pub const SSH_FXP_REMOVE:u8 = 13;
+pub const SSH_FXP_MKDIR:u8 = 14;
+pub const SSH_FXP_RMDIR:u8 = 15;
+pub const SSH_FXP_REALPATH:u8 = 16;
+pub const SSH_FXP_STAT:u8 = 17;
+pub const SSH_FXP_RENAME:u8 = 18;
+pub const SSH_FXP_READLINK:u8 = 19;
+pub const SSH_FXP_SYMLINK:u8 = 20;
+
+pub const SSH_FXP_STATUS:u8 = 101;
+pub const SSH_FXP_HANDLE:u8 = 102;
+pub const SSH_FXP_DATA:u8 = 103;
+pub const SSH_FXP_NAME:u8 = 104;
+pub const SSH_FXP_ATTRS:u8 = 105;
+
+const SSH_FILEXFER_ATTR_SIZE:u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID:u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS:u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME:u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED:u32 = 0x8000_0000;
+
+/// The file type bits of [`FileAttributes::permissions`].
+pub(crate) const S_IFMT:u32 = 0o170_000;
+pub(crate) const S_IFDIR:u32 = 0o040_000;
+pub(crate) const S_IFREG:u32 = 0o100_000;
+pub(crate) const S_IFLNK:u32 = 0o120_000;
+
+bitflags! {
+	/// How to open a file, see [`SftpSession::open`](super::SftpSession::open).
+	pub struct OpenFlags: u32 {
+		const READ = 0x1;
+		const WRITE = 0x2;
+		/// Writes go to the end of the file.
+		const APPEND = 0x4;
+		/// Create the file if it does not exist.
+		const CREATE = 0x8;
+		/// Truncate an existing file, with `CREATE`.
+		const TRUNCATE = 0x10;
+		/// Fail if the file exists, with `CREATE`.
+		const EXCLUDE = 0x20;
+	}
+}
+
+/// The attributes of a file. Only the attributes that are set are sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+	pub size:Option<u64>,
+	/// Sent only with [`FileAttributes::gid`].
+	pub uid:Option<u32>,
+	pub gid:Option<u32>,
+	/// The mode, including the file type bits.
+	pub permissions:Option<u32>,
+	/// Sent only with [`FileAttributes::mtime`], in seconds since the epoch.
+	pub atime:Option<u32>,
+	pub mtime:Option<u32>,
+	/// Extended attributes, as name and value pairs.
+	pub extended:Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl FileAttributes {
+	/// Whether this is a directory, according to the permissions.
+	pub fn is_dir(&self) -> bool { self.file_type() == Some(S_IFDIR) }
+
+	/// Whether this is a regular file, according to the permissions.
+	pub fn is_file(&self) -> bool { self.file_type() == Some(S_IFREG) }
+
+	/// Whether this is a symbolic link, according to the permissions.
+	pub fn is_symlink(&self) -> bool { self.file_type() == Some(S_IFLNK) }
+
+	fn file_type(&self) -> Option<u32> { self.permissions.map(|p| p & S_IFMT) }
+
+	pub(crate) fn encode(&self, buf:&mut Vec<u8>) {
+		let uid_gid = self.uid.zip(self.gid);
+
+		let times = self.atime.zip(self.mtime);
+
+		let mut flags = 0;
+
+		if self.size.is_some() {
+			flags |= SSH_FILEXFER_ATTR_SIZE
+		}
+
+		if uid_gid.is_some() {
+			flags |= SSH_FILEXFER_ATTR_UIDGID
+		}
+
+		if self.permissions.is_some() {
+			flags |= SSH_FILEXFER_ATTR_PERMISSIONS
+		}
+
+		if times.is_some() {
+			flags |= SSH_FILEXFER_ATTR_ACMODTIME
+		}
+
+		if !self.extended.is_empty() {
+			flags |= SSH_FILEXFER_ATTR_EXTENDED
+		}
+
+		buf.extend_from_slice(&flags.to_be_bytes());
+
+		if let Some(size) = self.size {
+			buf.extend_from_slice(&size.to_be_bytes());
+		}
+
+		if let Some((uid, gid)) = uid_gid {
+			buf.extend_from_slice(&uid.to_be_bytes());
+
+			buf.extend_from_slice(&gid.to_be_bytes());
+		}
+
+		if let Some(permissions) = self.permissions {
+			buf.extend_from_slice(&permissions.to_be_bytes());
+		}
+
+		if let Some((atime, mtime)) = times {
+			buf.extend_from_slice(&atime.to_be_bytes());
+
+			buf.extend_from_slice(&mtime.to_be_bytes());
+		}
+
+		if !self.extended.is_empty() {
+			buf.extend_from_slice(&(self.extended.len() as u32).to_be_bytes());
+
+			for (name, value) in &self.extended {
+				buf.extend_ssh_string(name);
+
+				buf.extend_ssh_string(value);
+			}
+		}
+	}
+
+	pub(crate) fn decode(r:&mut Position) -> Result<Self, russh_keys::Error> {
+		let flags = r.read_u32()?;
+
+		let mut attrs = FileAttributes::default();
+
+		if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+			attrs.size = Some(r.read_u64()?);
+		}
+
+		if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+			attrs.uid = Some(r.read_u32()?);
+
+			attrs.gid = Some(r.read_u32()?);
+		}
+
+		if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+			attrs.permissions = Some(r.read_u32()?);
+		}
+
+		if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+			attrs.atime = Some(r.read_u32()?);
+
+			attrs.mtime = Some(r.read_u32()?);
+		}
+
+		if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+			let count = r.read_u32()?;
+
+			for _ in 0..count {
+				let name = r.read_string()?.to_vec();
+
+				attrs.extended.push((name, r.read_string()?.to_vec()));
+			}
+		}
+
+		Ok(attrs)
+	}
+}
+
+/// A directory entry, or the answer to
+/// [`SftpSession::realpath`](super::SftpSession::realpath) and
+/// [`SftpSession::readlink`](super::SftpSession::readlink).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+	pub filename:String,
+	/// The entry as `ls -l` would show it, on servers that send it.
+	pub longname:String,
+	pub attrs:FileAttributes,
+}
+
+impl DirEntry {
+	pub(crate) fn encode(&self, buf:&mut Vec<u8>) {
+		buf.extend_ssh_string(self.filename.as_bytes());
+
+		buf.extend_ssh_string(self.longname.as_bytes());
+
+		self.attrs.encode(buf);
+	}
+
+	pub(crate) fn decode(r:&mut Position) -> Result<Self, russh_keys::Error> {
+		let filename = String::from_utf8_lossy(r.read_string()?).into_owned();
+
+		let longname = String::from_utf8_lossy(r.read_string()?).into_owned();
+
+		Ok(DirEntry { filename, longname, attrs:FileAttributes::decode(r)? })
+	}
+}
+
+/// The code of an `SSH_FXP_STATUS` reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+	Ok,
+	Eof,
+	NoSuchFile,
+	PermissionDenied,
+	Failure,
+	BadMessage,
+	NoConnection,
+	ConnectionLost,
+	OpUnsupported,
+	Other(u32),
+}
+
+impl From<u32> for StatusCode {
+	fn from(code:u32) -> Self {
+		match code {
+			0 => StatusCode::Ok,
+			1 => StatusCode::Eof,
+			2 => StatusCode::NoSuchFile,
+			3 => StatusCode::PermissionDenied,
+			4 => StatusCode::Failure,
+			5 => StatusCode::BadMessage,
+			6 => StatusCode::NoConnection,
+			7 => StatusCode::ConnectionLost,
+			8 => StatusCode::OpUnsupported,
+			code => StatusCode::Other(code),
+		}
+	}
+}
+
+impl From<StatusCode> for u32 {
+	fn from(code:StatusCode) -> Self {
+		match code {
+			StatusCode::Ok => 0,
+			StatusCode::Eof => 1,
+			StatusCode::NoSuchFile => 2,
+			StatusCode::PermissionDenied => 3,
+			StatusCode::Failure => 4,
+			StatusCode::BadMessage => 5,
+			StatusCode::NoConnection => 6,
+			StatusCode::ConnectionLost => 7,
+			StatusCode::OpUnsupported => 8,
+			StatusCode::Other(code) => code,
+		}
+	}
+}
+
+/// An `SSH_FXP_STATUS` reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+	pub code:StatusCode,
+	/// The server's description of the error.
+	pub message:String,
+}
+
+impl Status {
+	pub fn new<M:Into<String>>(code:StatusCode, message:M) -> Self {
+		Status { code, message:message.into() }
+	}
+
+	pub(crate) fn encode(&self, buf:&mut Vec<u8>) {
+		buf.extend_from_slice(&u32::from(self.code).to_be_bytes());
+
+		buf.extend_ssh_string(self.message.as_bytes());
+
+		// The language tag.
+		buf.extend_ssh_string(b"");
+	}
+
+	pub(crate) fn decode(r:&mut Position) -> Result<Self, russh_keys::Error> {
+		let code = StatusCode::from(r.read_u32()?);
+
+		// Some servers omit the message and language tag.
+		let message = r.read_string().map(String::from_utf8_lossy).unwrap_or_default();
+
+		Ok(Status { code, message:message.into_owned() })
+	}
+}
+
+/// A status with the message OpenSSH's `sftp-server` sends.
+impl From<StatusCode> for Status {
+	fn from(code:StatusCode) -> Self {
+		let message = match code {
+			StatusCode::Ok => "Success",
+			StatusCode::Eof => "End of file",
+			StatusCode::NoSuchFile => "No such file",
+			StatusCode::PermissionDenied => "Permission denied",
+			StatusCode::Failure | StatusCode::Other(_) => "Failure",
+			StatusCode::BadMessage => "Bad message",
+			StatusCode::NoConnection => "No connection",
+			StatusCode::ConnectionLost => "Connection lost",
+			StatusCode::OpUnsupported => "Operation unsupported",
+		};
+
+		Status::new(code, message)
+	}
+}
+
+impl From<std::io::Error> for Status {
+	fn from(e:std::io::Error) -> Self {
+		match e.kind() {
+			ErrorKind::NotFound => StatusCode::NoSuchFile.into(),
+			ErrorKind::PermissionDenied => StatusCode::PermissionDenied.into(),
+			ErrorKind::UnexpectedEof => StatusCode::Eof.into(),
+			ErrorKind::Unsupported => StatusCode::OpUnsupported.into(),
+			_ => Status::new(StatusCode::Failure, e.to_string()),
+		}
+	}
+}
@@ -0,0 +1,490 @@
+use std::{
+	fs::Metadata,
+	io::SeekFrom,
+	path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use tokio::{
+	fs::{DirBuilder, File, OpenOptions, ReadDir},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::SftpHandler;
+use crate::sftp::{DirEntry, FileAttributes, OpenFlags, Status, StatusCode};
+
+/// How many entries [`FsHandler::readdir`] returns at once.
+const READDIR_LEN:usize = 100;
+
+/// An [`SftpHandler`] serving the files under a directory, which clients
+/// see as `/`, and where relative paths start.
+///
+/// `..` never leaves the directory, but symbolic links are followed,
+/// including those pointing out of it: this is not a sandbox by itself.
+/// Files are created with the permissions the client asks for, minus the
+/// umask of the process. The root should be absolute, for the symbolic
+/// links clients create to point to it.
+#[derive(Debug, Clone)]
+pub struct FsHandler {
+	root:PathBuf,
+}
+
+impl FsHandler {
+	pub fn new<P:Into<PathBuf>>(root:P) -> Self { FsHandler { root:root.into() } }
+
+	/// The path on the local filesystem of `path`.
+	fn real_path(&self, path:&str) -> Result<PathBuf, Status> {
+		let mut real = self.root.clone();
+
+		for component in components(path) {
+			// Separators and drives of Windows are not names.
+			match Path::new(component).components().next() {
+				Some(Component::Normal(_)) if !component.contains('\\') => real.push(component),
+				_ => return Err(StatusCode::NoSuchFile.into()),
+			}
+		}
+
+		Ok(real)
+	}
+
+	/// The path the client sees of `real`, a path under the root.
+	fn client_path(&self, real:&Path) -> Option<String> {
+		let relative = real.strip_prefix(&self.root).ok()?;
+
+		let components:Option<Vec<_>> =
+			relative.components().map(|c| c.as_os_str().to_str()).collect();
+
+		Some(format!("/{}", components?.join("/")))
+	}
+}
+
+/// The components of `path` from the root, without `.` and `..`.
+fn components(path:&str) -> Vec<&str> {
+	let mut components = Vec::new();
+
+	for component in path.split('/') {
+		match component {
+			"" | "." => {},
+			".." => {
+				components.pop();
+			},
+			component => components.push(component),
+		}
+	}
+
+	components
+}
+
+#[async_trait]
+impl SftpHandler for FsHandler {
+	type File = File;
+
+	type Dir = ReadDir;
+
+	#[cfg_attr(not(unix), allow(unused_variables))]
+	async fn open(
+		&mut self,
+		path:&str,
+		flags:OpenFlags,
+		attrs:&FileAttributes,
+	) -> Result<File, Status> {
+		let mut options = OpenOptions::new();
+
+		options
+			.read(flags.contains(OpenFlags::READ))
+			.write(flags.contains(OpenFlags::WRITE))
+			.append(flags.contains(OpenFlags::APPEND))
+			.truncate(flags.contains(OpenFlags::TRUNCATE));
+
+		if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) {
+			options.create_new(true);
+		} else if flags.contains(OpenFlags::CREATE) {
+			options.create(true);
+		}
+
+		#[cfg(unix)]
+		options.mode(attrs.permissions.map_or(0o666, |p| p & 0o7777));
+
+		Ok(options.open(self.real_path(path)?).await?)
+	}
+
+	async fn read(&mut self, file:&mut File, offset:u64, len:u32) -> Result<Vec<u8>, Status> {
+		file.seek(SeekFrom::Start(offset)).await?;
+
+		let mut data = Vec::new();
+
+		(&mut *file).take(u64::from(len)).read_to_end(&mut data).await?;
+
+		Ok(data)
+	}
+
+	async fn write(&mut self, file:&mut File, offset:u64, data:&[u8]) -> Result<(), Status> {
+		file.seek(SeekFrom::Start(offset)).await?;
+
+		file.write_all(data).await?;
+
+		// Wait for the write, to reply with its error.
+		file.flush().await?;
+
+		Ok(())
+	}
+
+	async fn fstat(&mut self, file:&mut File) -> Result<FileAttributes, Status> {
+		Ok(attributes(&file.metadata().await?))
+	}
+
+	async fn fsetstat(&mut self, file:&mut File, attrs:&FileAttributes) -> Result<(), Status> {
+		if let Some(size) = attrs.size {
+			file.set_len(size).await?;
+		}
+
+		if let Some(permissions) = attrs.permissions {
+			file.set_permissions(permissions_from(permissions, file.metadata().await?)).await?;
+		}
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::io::AsRawFd;
+
+			if let (Some(uid), Some(gid)) = (attrs.uid, attrs.gid) {
+				unix::check(unsafe { libc::fchown(file.as_raw_fd(), uid, gid) })?;
+			}
+
+			if let (Some(atime), Some(mtime)) = (attrs.atime, attrs.mtime) {
+				let times = unix::timevals(atime, mtime);
+
+				unix::check(unsafe { libc::futimes(file.as_raw_fd(), times.as_ptr()) })?;
+			}
+		}
+
+		#[cfg(not(unix))]
+		if attrs.uid.is_some() || attrs.atime.is_some() {
+			return Err(StatusCode::OpUnsupported.into());
+		}
+
+		Ok(())
+	}
+
+	async fn opendir(&mut self, path:&str) -> Result<ReadDir, Status> {
+		Ok(tokio::fs::read_dir(self.real_path(path)?).await?)
+	}
+
+	async fn readdir(&mut self, dir:&mut ReadDir) -> Result<Vec<DirEntry>, Status> {
+		let mut entries = Vec::new();
+
+		while entries.len() < READDIR_LEN {
+			let entry = match dir.next_entry().await? {
+				Some(entry) => entry,
+				None => break,
+			};
+
+			let filename = entry.file_name().to_string_lossy().into_owned();
+
+			// The entry may have been removed since it was listed.
+			let attrs = entry.metadata().await.map(|m| attributes(&m)).unwrap_or_default();
+
+			entries.push(DirEntry { longname:longname(&filename, &attrs), filename, attrs });
+		}
+
+		Ok(entries)
+	}
+
+	async fn stat(&mut self, path:&str) -> Result<FileAttributes, Status> {
+		Ok(attributes(&tokio::fs::metadata(self.real_path(path)?).await?))
+	}
+
+	async fn lstat(&mut self, path:&str) -> Result<FileAttributes, Status> {
+		Ok(attributes(&tokio::fs::symlink_metadata(self.real_path(path)?).await?))
+	}
+
+	async fn setstat(&mut self, path:&str, attrs:&FileAttributes) -> Result<(), Status> {
+		let real = self.real_path(path)?;
+
+		if let Some(size) = attrs.size {
+			OpenOptions::new().write(true).open(&real).await?.set_len(size).await?;
+		}
+
+		if let Some(permissions) = attrs.permissions {
+			let metadata = tokio::fs::metadata(&real).await?;
+
+			tokio::fs::set_permissions(&real, permissions_from(permissions, metadata)).await?;
+		}
+
+		#[cfg(unix)]
+		{
+			if let (Some(uid), Some(gid)) = (attrs.uid, attrs.gid) {
+				let path = unix::c_path(&real)?;
+
+				unix::check(unsafe { libc::chown(path.as_ptr(), uid, gid) })?;
+			}
+
+			if let (Some(atime), Some(mtime)) = (attrs.atime, attrs.mtime) {
+				let (path, times) = (unix::c_path(&real)?, unix::timevals(atime, mtime));
+
+				unix::check(unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) })?;
+			}
+		}
+
+		#[cfg(not(unix))]
+		if attrs.uid.is_some() || attrs.atime.is_some() {
+			return Err(StatusCode::OpUnsupported.into());
+		}
+
+		Ok(())
+	}
+
+	async fn remove(&mut self, path:&str) -> Result<(), Status> {
+		Ok(tokio::fs::remove_file(self.real_path(path)?).await?)
+	}
+
+	#[cfg_attr(not(unix), allow(unused_variables))]
+	async fn mkdir(&mut self, path:&str, attrs:&FileAttributes) -> Result<(), Status> {
+		let mut builder = DirBuilder::new();
+
+		#[cfg(unix)]
+		builder.mode(attrs.permissions.map_or(0o777, |p| p & 0o7777));
+
+		Ok(builder.create(self.real_path(path)?).await?)
+	}
+
+	async fn rmdir(&mut self, path:&str) -> Result<(), Status> {
+		Ok(tokio::fs::remove_dir(self.real_path(path)?).await?)
+	}
+
+	async fn realpath(&mut self, path:&str) -> Result<String, Status> {
+		Ok(format!("/{}", components(path).join("/")))
+	}
+
+	async fn rename(&mut self, old_path:&str, new_path:&str) -> Result<(), Status> {
+		let (old_path, new_path) = (self.real_path(old_path)?, self.real_path(new_path)?);
+
+		if tokio::fs::symlink_metadata(&new_path).await.is_ok() {
+			return Err(StatusCode::Failure.into());
+		}
+
+		Ok(tokio::fs::rename(old_path, new_path).await?)
+	}
+
+	async fn readlink(&mut self, path:&str) -> Result<String, Status> {
+		let target = tokio::fs::read_link(self.real_path(path)?).await?;
+
+		match self.client_path(&target) {
+			Some(target) => Ok(target),
+			None => Ok(target.to_string_lossy().into_owned()),
+		}
+	}
+
+	#[cfg(unix)]
+	async fn symlink(&mut self, target:&str, link_path:&str) -> Result<(), Status> {
+		// A relative target is from the directory of the link, and both
+		// are under the root.
+		let target = if target.starts_with('/') {
+			self.real_path(target)?
+		} else {
+			self.real_path(&format!("{}/../{}", link_path, target))?
+		};
+
+		Ok(tokio::fs::symlink(target, self.real_path(link_path)?).await?)
+	}
+}
+
+/// The attributes of a file, as OpenSSH sends them.
+fn attributes(metadata:&Metadata) -> FileAttributes {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+
+		FileAttributes {
+			size:Some(metadata.size()),
+			uid:Some(metadata.uid()),
+			gid:Some(metadata.gid()),
+			permissions:Some(metadata.mode()),
+			atime:Some(metadata.atime() as u32),
+			mtime:Some(metadata.mtime() as u32),
+			extended:Vec::new(),
+		}
+	}
+
+	#[cfg(not(unix))]
+	{
+		use std::time::{SystemTime, UNIX_EPOCH};
+
+		use crate::sftp::protocol::{S_IFDIR, S_IFLNK, S_IFREG};
+
+		let (kind, mode) = if metadata.is_dir() {
+			(S_IFDIR, 0o755)
+		} else if metadata.file_type().is_symlink() {
+			(S_IFLNK, 0o777)
+		} else {
+			(S_IFREG, 0o644)
+		};
+
+		let mode = if metadata.permissions().readonly() { mode & !0o222 } else { mode };
+
+		let time = |time:std::io::Result<SystemTime>| {
+			Some(time.ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs() as u32)
+		};
+
+		FileAttributes {
+			size:Some(metadata.len()),
+			permissions:Some(kind | mode),
+			atime:time(metadata.accessed()),
+			mtime:time(metadata.modified()),
+			..Default::default()
+		}
+	}
+}
+
+/// The permissions of a file with `metadata`, changed to `mode`.
+#[cfg(unix)]
+fn permissions_from(mode:u32, _:Metadata) -> std::fs::Permissions {
+	use std::os::unix::fs::PermissionsExt;
+
+	std::fs::Permissions::from_mode(mode & 0o7777)
+}
+
+/// The permissions of a file with `metadata`, read-only if `mode` has no
+/// write permission.
+#[cfg(not(unix))]
+fn permissions_from(mode:u32, metadata:Metadata) -> std::fs::Permissions {
+	let mut permissions = metadata.permissions();
+
+	permissions.set_readonly(mode & 0o222 == 0);
+
+	permissions
+}
+
+/// A directory entry as `ls -l` shows it, for clients that show the
+/// long name as is, such as OpenSSH's `sftp`.
+fn longname(filename:&str, attrs:&FileAttributes) -> String {
+	let mode = attrs.permissions.unwrap_or(0);
+
+	let kind = if attrs.is_dir() {
+		'd'
+	} else if attrs.is_symlink() {
+		'l'
+	} else if attrs.is_file() {
+		'-'
+	} else {
+		'?'
+	};
+
+	let mut perms = String::with_capacity(10);
+
+	perms.push(kind);
+
+	for (bit, c) in (0..9).rev().zip("rwxrwxrwx".chars()) {
+		perms.push(if mode & (1 << bit) != 0 { c } else { '-' });
+	}
+
+	format!(
+		"{} {:>4} {:<8} {:<8} {:>8} {} {}",
+		perms,
+		1,
+		attrs.uid.unwrap_or(0),
+		attrs.gid.unwrap_or(0),
+		attrs.size.unwrap_or(0),
+		format_time(attrs.mtime.unwrap_or(0)),
+		filename
+	)
+}
+
+/// `time`, in seconds since the epoch, as `YYYY-MM-DD HH:MM` in UTC.
+fn format_time(time:u32) -> String {
+	let (days, seconds) = (i64::from(time / 86400), time % 86400);
+
+	// Howard Hinnant's `civil_from_days`.
+	let z = days + 719_468;
+
+	let era = z / 146_097;
+
+	let doe = z - era * 146_097;
+
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+
+	let mp = (5 * doy + 2) / 153;
+
+	let day = doy - (153 * mp + 2) / 5 + 1;
+
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!(
+		"{:04}-{:02}-{:02} {:02}:{:02}",
+		year,
+		month,
+		day,
+		seconds / 3600,
+		seconds % 3600 / 60
+	)
+}
+
+#[cfg(unix)]
+mod unix {
+	use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+	pub(super) fn c_path(path:&Path) -> io::Result<CString> {
+		CString::new(path.as_os_str().as_bytes())
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+	}
+
+	pub(super) fn timevals(atime:u32, mtime:u32) -> [libc::timeval; 2] {
+		let timeval = |time:u32| libc::timeval { tv_sec:time as libc::time_t, tv_usec:0 };
+
+		[timeval(atime), timeval(mtime)]
+	}
+
+	/// The error of a libc call that returned `result`.
+	pub(super) fn check(result:libc::c_int) -> io::Result<()> {
+		if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_paths() {
+		assert_eq!(components("/a/./b/../c/"), ["a", "c"]);
+
+		assert_eq!(components("../../a"), ["a"]);
+
+		let handler = FsHandler::new("/srv/sftp");
+
+		assert_eq!(handler.real_path("/../etc/passwd").ok(), Some("/srv/sftp/etc/passwd".into()));
+
+		assert_eq!(handler.real_path("a/b").ok(), Some("/srv/sftp/a/b".into()));
+
+		let client_path = handler.client_path(Path::new("/srv/sftp/a/b"));
+
+		assert_eq!(client_path.as_deref(), Some("/a/b"));
+
+		assert_eq!(handler.client_path(Path::new("/etc/passwd")), None);
+	}
+
+	#[test]
+	fn test_longname() {
+		assert_eq!(format_time(0), "1970-01-01 00:00");
+
+		assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
+
+		let attrs = FileAttributes {
+			size:Some(1234),
+			uid:Some(1000),
+			gid:Some(100),
+			permissions:Some(0o100_640),
+			atime:Some(0),
+			mtime:Some(951_782_400),
+			extended:Vec::new(),
+		};
+
+		assert_eq!(
+			longname("notes.txt", &attrs),
+			"-rw-r-----    1 1000     100          1234 2000-02-29 00:00 notes.txt"
+		);
+	}
+}
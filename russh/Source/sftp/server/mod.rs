@@ -0,0 +1,708 @@
+//! An SFTP version 3 server, for the `sftp` subsystem of an embedded
+//! server.
+//!
+//! [`SftpServer`] parses the requests of the client and passes them to an
+//! [`SftpHandler`], whose errors are [`Status`] replies: `std::io::Error`
+//! converts to the status an SFTP client expects, such as
+//! [`StatusCode::NoSuchFile`]. The server owns the handles the client
+//! holds, which are opaque numbers on the wire, and gives the handler the
+//! files and directories it opened. Handles left open are closed when the
+//! channel closes.
+//!
+//! [`FsHandler`] serves the files under a directory, which is the common
+//! case. To serve them when a client requests the `sftp` subsystem, keep
+//! the channel from [`server::Handler::channel_open_session`], and in
+//! [`server::Handler::subsystem_request`]:
+//!
+//! ```no_run
+//! # fn run(
+//! # 	channel:russh::Channel<russh::server::Msg>,
+//! # 	session:&mut russh::server::Session,
+//! # ) {
+//! use russh::sftp::server::{FsHandler, SftpServer};
+//!
+//! session.channel_success(channel.id());
+//!
+//! tokio::spawn(SftpServer::new(FsHandler::new("/srv/sftp")).run(channel));
+//! # }
+//! ```
+//!
+//! [`server::Handler::channel_open_session`]: crate::server::Handler::channel_open_session
+//! [`server::Handler::subsystem_request`]: crate::server::Handler::subsystem_request
+
+mod fs;
+
+pub use fs::FsHandler;
+
+use std::{collections::HashMap, convert::TryFrom, io::ErrorKind};
+
+use async_trait::async_trait;
+use log::debug;
+use russh_keys::encoding::{Encoding, Position, Reader};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::{
+	DirEntry,
+	FileAttributes,
+	MAX_PACKET_LEN,
+	OpenFlags,
+	Status,
+	StatusCode,
+	protocol::*,
+	read_packet,
+};
+use crate::{Channel, ChannelId, ChannelMsg, Error};
+
+/// The longest read answered, as OpenSSH's `SFTP_MAX_READ_LENGTH`.
+const MAX_READ_LEN:u32 = MAX_PACKET_LEN as u32 - 1024;
+
+/// The requests of an SFTP client. Paths are as the client sent them,
+/// relative paths are for the handler to resolve.
+///
+/// All methods reply [`StatusCode::OpUnsupported`] by default, except
+/// `close` and `closedir`, which drop the file or directory. OpenSSH's
+/// `sftp` needs at least [`SftpHandler::realpath`] and
+/// [`SftpHandler::stat`] to start.
+#[async_trait]
+#[allow(unused_variables)]
+pub trait SftpHandler: Send {
+	/// A file open for the client.
+	type File: Send;
+
+	/// A directory open for the client, which [`SftpHandler::readdir`]
+	/// lists.
+	type Dir: Send;
+
+	/// Open a file. `attrs` are those of a created file.
+	async fn open(
+		&mut self,
+		path:&str,
+		flags:OpenFlags,
+		attrs:&FileAttributes,
+	) -> Result<Self::File, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn close(&mut self, file:Self::File) -> Result<(), Status> { Ok(()) }
+
+	/// Read at most `len` bytes at `offset`. Fewer bytes may be returned,
+	/// none at the end of the file.
+	async fn read(
+		&mut self,
+		file:&mut Self::File,
+		offset:u64,
+		len:u32,
+	) -> Result<Vec<u8>, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn write(
+		&mut self,
+		file:&mut Self::File,
+		offset:u64,
+		data:&[u8],
+	) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn fstat(&mut self, file:&mut Self::File) -> Result<FileAttributes, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// Change the attributes that are set in `attrs`.
+	async fn fsetstat(
+		&mut self,
+		file:&mut Self::File,
+		attrs:&FileAttributes,
+	) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn opendir(&mut self, path:&str) -> Result<Self::Dir, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// The next entries of the directory, none at the end.
+	async fn readdir(&mut self, dir:&mut Self::Dir) -> Result<Vec<DirEntry>, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn closedir(&mut self, dir:Self::Dir) -> Result<(), Status> { Ok(()) }
+
+	/// The attributes of a file, following symbolic links.
+	async fn stat(&mut self, path:&str) -> Result<FileAttributes, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// The attributes of a file, without following symbolic links.
+	async fn lstat(&mut self, path:&str) -> Result<FileAttributes, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// Change the attributes that are set in `attrs`.
+	async fn setstat(&mut self, path:&str, attrs:&FileAttributes) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn remove(&mut self, path:&str) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn mkdir(&mut self, path:&str, attrs:&FileAttributes) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn rmdir(&mut self, path:&str) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// The absolute, canonical form of `path`. Clients ask for `.` to
+	/// know their initial directory.
+	async fn realpath(&mut self, path:&str) -> Result<String, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// Rename a file. SFTP version 3 does not replace an existing
+	/// `new_path`.
+	async fn rename(&mut self, old_path:&str, new_path:&str) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	async fn readlink(&mut self, path:&str) -> Result<String, Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+
+	/// Create a symbolic link at `link_path`, pointing to `target`.
+	async fn symlink(&mut self, target:&str, link_path:&str) -> Result<(), Status> {
+		Err(StatusCode::OpUnsupported.into())
+	}
+}
+
+/// A request, after its ID.
+#[derive(Debug)]
+enum Request {
+	Open { path:String, flags:OpenFlags, attrs:FileAttributes },
+	Close(Vec<u8>),
+	Read { handle:Vec<u8>, offset:u64, len:u32 },
+	Write { handle:Vec<u8>, offset:u64, data:Vec<u8> },
+	Lstat(String),
+	Fstat(Vec<u8>),
+	Setstat { path:String, attrs:FileAttributes },
+	Fsetstat { handle:Vec<u8>, attrs:FileAttributes },
+	Opendir(String),
+	Readdir(Vec<u8>),
+	Remove(String),
+	Mkdir { path:String, attrs:FileAttributes },
+	Rmdir(String),
+	Realpath(String),
+	Stat(String),
+	Rename { old_path:String, new_path:String },
+	Readlink(String),
+	Symlink { target:String, link_path:String },
+	Unsupported(u8),
+}
+
+impl Request {
+	fn decode(kind:u8, r:&mut Position) -> Result<Self, russh_keys::Error> {
+		Ok(match kind {
+			SSH_FXP_OPEN => {
+				Request::Open {
+					path:read_path(r)?,
+					flags:OpenFlags::from_bits_truncate(r.read_u32()?),
+					attrs:FileAttributes::decode(r)?,
+				}
+			},
+			SSH_FXP_CLOSE => Request::Close(r.read_string()?.to_vec()),
+			SSH_FXP_READ => {
+				Request::Read {
+					handle:r.read_string()?.to_vec(),
+					offset:r.read_u64()?,
+					len:r.read_u32()?,
+				}
+			},
+			SSH_FXP_WRITE => {
+				Request::Write {
+					handle:r.read_string()?.to_vec(),
+					offset:r.read_u64()?,
+					data:r.read_string()?.to_vec(),
+				}
+			},
+			SSH_FXP_LSTAT => Request::Lstat(read_path(r)?),
+			SSH_FXP_FSTAT => Request::Fstat(r.read_string()?.to_vec()),
+			SSH_FXP_SETSTAT => {
+				Request::Setstat { path:read_path(r)?, attrs:FileAttributes::decode(r)? }
+			},
+			SSH_FXP_FSETSTAT => {
+				Request::Fsetstat {
+					handle:r.read_string()?.to_vec(),
+					attrs:FileAttributes::decode(r)?,
+				}
+			},
+			SSH_FXP_OPENDIR => Request::Opendir(read_path(r)?),
+			SSH_FXP_READDIR => Request::Readdir(r.read_string()?.to_vec()),
+			SSH_FXP_REMOVE => Request::Remove(read_path(r)?),
+			SSH_FXP_MKDIR => {
+				Request::Mkdir { path:read_path(r)?, attrs:FileAttributes::decode(r)? }
+			},
+			SSH_FXP_RMDIR => Request::Rmdir(read_path(r)?),
+			SSH_FXP_REALPATH => Request::Realpath(read_path(r)?),
+			SSH_FXP_STAT => Request::Stat(read_path(r)?),
+			SSH_FXP_RENAME => Request::Rename { old_path:read_path(r)?, new_path:read_path(r)? },
+			SSH_FXP_READLINK => Request::Readlink(read_path(r)?),
+			// The target first, as OpenSSH, whose order is the reverse of
+			// the draft.
+			SSH_FXP_SYMLINK => Request::Symlink { target:read_path(r)?, link_path:read_path(r)? },
+			kind => Request::Unsupported(kind),
+		})
+	}
+}
+
+fn read_path(r:&mut Position) -> Result<String, russh_keys::Error> {
+	Ok(String::from_utf8_lossy(r.read_string()?).into_owned())
+}
+
+/// What a handle refers to.
+enum Open<H:SftpHandler> {
+	File(H::File),
+	Dir(H::Dir),
+}
+
+/// An SFTP server, see the [module documentation](self).
+pub struct SftpServer<H:SftpHandler> {
+	handler:H,
+	handles:HashMap<u32, Open<H>>,
+	next_handle:u32,
+}
+
+impl<H:SftpHandler> SftpServer<H> {
+	pub fn new(handler:H) -> Self { SftpServer { handler, handles:HashMap::new(), next_handle:0 } }
+
+	/// Serve the requests of the client on `channel`, on which the `sftp`
+	/// subsystem was requested, until the client closes it.
+	pub async fn run<S:From<(ChannelId, ChannelMsg)> + Send + 'static>(
+		self,
+		channel:Channel<S>,
+	) -> Result<(), Error> {
+		self.serve(channel.into_stream()).await
+	}
+
+	async fn serve<T:AsyncRead + AsyncWrite + Unpin>(mut self, mut stream:T) -> Result<(), Error> {
+		let result = self.serve_requests(&mut stream).await;
+
+		for (_, open) in std::mem::take(&mut self.handles) {
+			let closed = match open {
+				Open::File(file) => self.handler.close(file).await,
+				Open::Dir(dir) => self.handler.closedir(dir).await,
+			};
+
+			if let Err(status) = closed {
+				debug!("sftp: closing a handle left open: {:?}", status);
+			}
+		}
+
+		stream.shutdown().await.ok();
+
+		result
+	}
+
+	async fn serve_requests<T:AsyncRead + AsyncWrite + Unpin>(
+		&mut self,
+		stream:&mut T,
+	) -> Result<(), Error> {
+		let init = read_packet(stream).await?;
+
+		let mut r = init.reader(0);
+
+		if r.read_byte()? != SSH_FXP_INIT {
+			return Err(Error::Inconsistent);
+		}
+
+		debug!("sftp client version {}", r.read_u32()?);
+
+		let mut version = vec![0, 0, 0, 5, SSH_FXP_VERSION];
+
+		version.extend_from_slice(&VERSION.to_be_bytes());
+
+		stream.write_all(&version).await?;
+
+		loop {
+			let packet = match read_packet(stream).await {
+				Ok(packet) => packet,
+				Err(Error::IO(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+				Err(e) => return Err(e),
+			};
+
+			let mut r = packet.reader(0);
+
+			let kind = r.read_byte()?;
+
+			let id = r.read_u32()?;
+
+			let reply = match Request::decode(kind, &mut r) {
+				Ok(request) => self.process(id, request).await,
+				Err(e) => {
+					debug!("sftp: malformed request of type {}: {:?}", kind, e);
+
+					Err(StatusCode::BadMessage.into())
+				},
+			};
+
+			let reply = reply.unwrap_or_else(|status| status_reply(id, &status));
+
+			stream.write_all(&reply).await?;
+		}
+	}
+
+	/// The reply to a request, or the status if it failed.
+	async fn process(&mut self, id:u32, request:Request) -> Result<Vec<u8>, Status> {
+		let handler = &mut self.handler;
+
+		let handles = &mut self.handles;
+
+		match request {
+			Request::Open { path, flags, attrs } => {
+				let file = handler.open(&path, flags, &attrs).await?;
+
+				Ok(self.handle_reply(id, Open::File(file)))
+			},
+			Request::Opendir(path) => {
+				let dir = handler.opendir(&path).await?;
+
+				Ok(self.handle_reply(id, Open::Dir(dir)))
+			},
+			Request::Close(handle) => {
+				match handles.remove(&handle_id(&handle)?) {
+					Some(Open::File(file)) => handler.close(file).await?,
+					Some(Open::Dir(dir)) => handler.closedir(dir).await?,
+					None => return Err(invalid_handle()),
+				}
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Read { handle, offset, len } => {
+				let file = file::<H>(handles, &handle)?;
+
+				let len = len.min(MAX_READ_LEN);
+
+				let mut data = handler.read(file, offset, len).await?;
+
+				if data.is_empty() {
+					return Err(StatusCode::Eof.into());
+				}
+
+				data.truncate(len as usize);
+
+				let mut reply = reply(SSH_FXP_DATA, id);
+
+				reply.extend_ssh_string(&data);
+
+				Ok(finish(reply))
+			},
+			Request::Write { handle, offset, data } => {
+				handler.write(file::<H>(handles, &handle)?, offset, &data).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Fstat(handle) => {
+				let attrs = handler.fstat(file::<H>(handles, &handle)?).await?;
+
+				Ok(attrs_reply(id, &attrs))
+			},
+			Request::Fsetstat { handle, attrs } => {
+				handler.fsetstat(file::<H>(handles, &handle)?, &attrs).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Readdir(handle) => {
+				let dir = match handles.get_mut(&handle_id(&handle)?) {
+					Some(Open::Dir(dir)) => dir,
+					_ => return Err(invalid_handle()),
+				};
+
+				let entries = handler.readdir(dir).await?;
+
+				if entries.is_empty() {
+					return Err(StatusCode::Eof.into());
+				}
+
+				Ok(names_reply(id, &entries))
+			},
+			Request::Stat(path) => Ok(attrs_reply(id, &handler.stat(&path).await?)),
+			Request::Lstat(path) => Ok(attrs_reply(id, &handler.lstat(&path).await?)),
+			Request::Setstat { path, attrs } => {
+				handler.setstat(&path, &attrs).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Remove(path) => {
+				handler.remove(&path).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Mkdir { path, attrs } => {
+				handler.mkdir(&path, &attrs).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Rmdir(path) => {
+				handler.rmdir(&path).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Realpath(path) => Ok(name_reply(id, handler.realpath(&path).await?)),
+			Request::Rename { old_path, new_path } => {
+				handler.rename(&old_path, &new_path).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Readlink(path) => Ok(name_reply(id, handler.readlink(&path).await?)),
+			Request::Symlink { target, link_path } => {
+				handler.symlink(&target, &link_path).await?;
+
+				Ok(status_reply(id, &StatusCode::Ok.into()))
+			},
+			Request::Unsupported(kind) => {
+				debug!("sftp: unsupported request of type {}", kind);
+
+				Err(StatusCode::OpUnsupported.into())
+			},
+		}
+	}
+
+	/// Keep `open` under a new handle, and reply with the handle.
+	fn handle_reply(&mut self, id:u32, open:Open<H>) -> Vec<u8> {
+		while self.handles.contains_key(&self.next_handle) {
+			self.next_handle = self.next_handle.wrapping_add(1);
+		}
+
+		let handle = self.next_handle;
+
+		self.handles.insert(handle, open);
+
+		let mut reply = reply(SSH_FXP_HANDLE, id);
+
+		reply.extend_ssh_string(&handle.to_be_bytes());
+
+		finish(reply)
+	}
+}
+
+fn handle_id(handle:&[u8]) -> Result<u32, Status> {
+	<[u8; 4]>::try_from(handle).map(u32::from_be_bytes).map_err(|_| invalid_handle())
+}
+
+/// The file under `handle`.
+fn file<'a, H:SftpHandler>(
+	handles:&'a mut HashMap<u32, Open<H>>,
+	handle:&[u8],
+) -> Result<&'a mut H::File, Status> {
+	match handles.get_mut(&handle_id(handle)?) {
+		Some(Open::File(file)) => Ok(file),
+		_ => Err(invalid_handle()),
+	}
+}
+
+/// The status of a request on a handle that is not open, or of the wrong
+/// kind, as OpenSSH.
+fn invalid_handle() -> Status { Status::new(StatusCode::Failure, "Invalid handle") }
+
+/// The beginning of a reply, whose length is set by [`finish`].
+fn reply(kind:u8, id:u32) -> Vec<u8> {
+	let mut reply = vec![0, 0, 0, 0, kind];
+
+	reply.extend_from_slice(&id.to_be_bytes());
+
+	reply
+}
+
+fn finish(mut reply:Vec<u8>) -> Vec<u8> {
+	let len = (reply.len() - 4) as u32;
+
+	if let Some(prefix) = reply.get_mut(..4) {
+		prefix.copy_from_slice(&len.to_be_bytes());
+	}
+
+	reply
+}
+
+fn status_reply(id:u32, status:&Status) -> Vec<u8> {
+	let mut reply = reply(SSH_FXP_STATUS, id);
+
+	status.encode(&mut reply);
+
+	finish(reply)
+}
+
+fn attrs_reply(id:u32, attrs:&FileAttributes) -> Vec<u8> {
+	let mut reply = reply(SSH_FXP_ATTRS, id);
+
+	attrs.encode(&mut reply);
+
+	finish(reply)
+}
+
+fn names_reply(id:u32, entries:&[DirEntry]) -> Vec<u8> {
+	let mut reply = reply(SSH_FXP_NAME, id);
+
+	reply.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+
+	for entry in entries {
+		entry.encode(&mut reply);
+	}
+
+	finish(reply)
+}
+
+/// The reply to `SSH_FXP_REALPATH` or `SSH_FXP_READLINK`, without
+/// attributes, as OpenSSH.
+fn name_reply(id:u32, name:String) -> Vec<u8> {
+	names_reply(id, &[DirEntry { longname:name.clone(), filename:name, attrs:Default::default() }])
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::io::AsyncReadExt;
+
+	use super::*;
+
+	/// A handler with a single file, `/hello`.
+	struct Hello;
+
+	#[async_trait]
+	impl SftpHandler for Hello {
+		type File = ();
+
+		type Dir = ();
+
+		async fn open(&mut self, path:&str, _:OpenFlags, _:&FileAttributes) -> Result<(), Status> {
+			if path == "/hello" { Ok(()) } else { Err(StatusCode::NoSuchFile.into()) }
+		}
+
+		async fn read(&mut self, _:&mut (), offset:u64, len:u32) -> Result<Vec<u8>, Status> {
+			let data = b"Hello, world!\n".iter().skip(offset as usize);
+
+			Ok(data.take(len as usize).copied().collect())
+		}
+	}
+
+	/// Send a request and read the reply, with its type and ID.
+	#[allow(clippy::unwrap_used)]
+	async fn request<T:AsyncRead + AsyncWrite + Unpin>(
+		stream:&mut T,
+		kind:u8,
+		id:u32,
+		payload:&[u8],
+	) -> Vec<u8> {
+		let mut request = reply(kind, id);
+
+		request.extend_from_slice(payload);
+
+		stream.write_all(&finish(request)).await.unwrap();
+
+		let len = stream.read_u32().await.unwrap();
+
+		let mut reply = vec![0; len as usize];
+
+		stream.read_exact(&mut reply).await.unwrap();
+
+		reply
+	}
+
+	#[tokio::test]
+	#[allow(clippy::unwrap_used)]
+	async fn test_requests() {
+		let (mut client, server) = tokio::io::duplex(65536);
+
+		let server = tokio::spawn(SftpServer::new(Hello).serve(server));
+
+		client.write_all(&[0, 0, 0, 5, SSH_FXP_INIT, 0, 0, 0, 3]).await.unwrap();
+
+		let mut version = [0; 9];
+
+		client.read_exact(&mut version).await.unwrap();
+
+		assert_eq!(version, [0, 0, 0, 5, SSH_FXP_VERSION, 0, 0, 0, 3]);
+
+		let mut open = Vec::new();
+
+		open.extend_ssh_string(b"/hello");
+
+		open.extend_from_slice(&OpenFlags::READ.bits().to_be_bytes());
+
+		FileAttributes::default().encode(&mut open);
+
+		let reply = request(&mut client, SSH_FXP_OPEN, 1, &open).await;
+
+		assert_eq!(reply, [SSH_FXP_HANDLE, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 0]);
+
+		let read = |offset:u64| {
+			let mut read = Vec::new();
+
+			read.extend_ssh_string(&[0, 0, 0, 0]);
+
+			read.extend_from_slice(&offset.to_be_bytes());
+
+			read.extend_from_slice(&1024u32.to_be_bytes());
+
+			read
+		};
+
+		let reply = request(&mut client, SSH_FXP_READ, 2, &read(7)).await;
+
+		assert_eq!(reply.get(..5), Some(&[SSH_FXP_DATA, 0, 0, 0, 2][..]));
+
+		assert_eq!(reply.reader(5).read_string().unwrap(), b"world!\n");
+
+		// At the end of the file.
+		let reply = request(&mut client, SSH_FXP_READ, 3, &read(14)).await;
+
+		assert_eq!(reply.get(..9), Some(&[SSH_FXP_STATUS, 0, 0, 0, 3, 0, 0, 0, 1][..]));
+
+		let mut close = Vec::new();
+
+		close.extend_ssh_string(&[0, 0, 0, 0]);
+
+		let reply = request(&mut client, SSH_FXP_CLOSE, 4, &close).await;
+
+		assert_eq!(reply.get(..9), Some(&[SSH_FXP_STATUS, 0, 0, 0, 4, 0, 0, 0, 0][..]));
+
+		// The handle is closed.
+		let reply = request(&mut client, SSH_FXP_READ, 5, &read(0)).await;
+
+		let status = Status::decode(&mut reply.reader(5)).unwrap();
+
+		assert_eq!(status, Status::new(StatusCode::Failure, "Invalid handle"));
+
+		let mut open = Vec::new();
+
+		open.extend_ssh_string(b"/missing");
+
+		open.extend_from_slice(&OpenFlags::READ.bits().to_be_bytes());
+
+		FileAttributes::default().encode(&mut open);
+
+		let reply = request(&mut client, SSH_FXP_OPEN, 6, &open).await;
+
+		let status = Status::decode(&mut reply.reader(5)).unwrap();
+
+		assert_eq!(status.code, StatusCode::NoSuchFile);
+
+		// Not implemented by the handler.
+		let reply = request(&mut client, SSH_FXP_REMOVE, 7, b"\0\0\0\x01a").await;
+
+		let status = Status::decode(&mut reply.reader(5)).unwrap();
+
+		assert_eq!(status.code, StatusCode::OpUnsupported);
+
+		// Truncated.
+		let reply = request(&mut client, SSH_FXP_READ, 8, &[0, 0, 0, 4, 0]).await;
+
+		let status = Status::decode(&mut reply.reader(5)).unwrap();
+
+		assert_eq!(status.code, StatusCode::BadMessage);
+
+		drop(client);
+
+		server.await.unwrap().unwrap();
+	}
+}
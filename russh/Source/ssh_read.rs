@@ -2,32 +2,27 @@ use std::pin::Pin;
 
 use futures::task::*;
 use log::debug;
-use russh_cryptovec::CryptoVec;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
 use crate::Error;
 
 /// The buffer to read the identification string (first line in the
-/// protocol).
+/// protocol), and the lines before it.
 struct ReadSshIdBuffer {
-	pub buf:CryptoVec,
-	pub total:usize,
+	/// Everything read so far.
+	pub buf:Vec<u8>,
+	/// The bytes of `buf` already returned by `poll_read`, or, while the
+	/// identification string is being read, the start of the current line.
 	pub bytes_read:usize,
-	pub sshid_len:usize,
+	/// The identification string in `buf`, without its line ending.
+	pub sshid:std::ops::Range<usize>,
 }
 
 impl ReadSshIdBuffer {
-	pub fn id(&self) -> &[u8] {
-		#[allow(clippy::indexing_slicing)] // length checked
-		&self.buf[..self.sshid_len]
-	}
+	pub fn id(&self) -> &[u8] { self.buf.get(self.sshid.clone()).unwrap_or(&[]) }
 
 	pub fn new() -> ReadSshIdBuffer {
-		let mut buf = CryptoVec::new();
-
-		buf.resize(256);
-
-		ReadSshIdBuffer { buf, sshid_len:0, bytes_read:0, total:0 }
+		ReadSshIdBuffer { buf:Vec::with_capacity(256), bytes_read:0, sshid:0..0 }
 	}
 }
 
@@ -59,14 +54,14 @@ impl<R:AsyncRead + Unpin> AsyncRead for SshRead<R> {
 		buf:&mut ReadBuf,
 	) -> Poll<Result<(), std::io::Error>> {
 		if let Some(mut id) = self.id.take() {
-			debug!("id {:?} {:?}", id.total, id.bytes_read);
+			debug!("id {:?} {:?}", id.buf.len(), id.bytes_read);
 
-			if id.total > id.bytes_read {
-				let total = id.total.min(id.bytes_read + buf.remaining());
+			if id.buf.len() > id.bytes_read {
+				let total = id.buf.len().min(id.bytes_read + buf.remaining());
 				#[allow(clippy::indexing_slicing)] // length checked
 				buf.put_slice(&id.buf[id.bytes_read..total]);
 
-				id.bytes_read += total - id.bytes_read;
+				id.bytes_read = total;
 
 				self.id = Some(id);
 
@@ -108,69 +103,195 @@ impl<R:AsyncWrite + Unpin> AsyncWrite for SshRead<R> {
 impl<R:AsyncRead + Unpin> SshRead<R> {
 	pub fn new(r:R) -> Self { SshRead { id:Some(ReadSshIdBuffer::new()), r } }
 
+	/// Read the identification string of the peer, without its line
+	/// ending, skipping at most `max_lines` other lines of at most
+	/// `max_bytes` in total before it (RFC 4253, section 4.2). Lines may
+	/// end with CRLF or LF, and the identification string may end with
+	/// the stream.
 	#[allow(clippy::unwrap_used)]
-	pub async fn read_ssh_id(&mut self) -> Result<&[u8], Error> {
+	pub async fn read_ssh_id(
+		&mut self,
+		max_lines:usize,
+		max_bytes:usize,
+	) -> Result<&[u8], Error> {
 		let ssh_id = self.id.as_mut().unwrap();
 
+		let mut lines = 0;
+
+		let mut chunk = [0; 256];
+
 		loop {
-			let mut i = 0;
+			#[allow(clippy::indexing_slicing)] // bytes_read <= buf.len()
+			let line = &ssh_id.buf[ssh_id.bytes_read..];
 
-			debug!("read_ssh_id: reading");
+			if let Some(n) = line.iter().position(|&c| c == b'\n') {
+				let start = ssh_id.bytes_read;
 
-			#[allow(clippy::indexing_slicing)] // length checked
-			let n = AsyncReadExt::read(&mut self.r, &mut ssh_id.buf[ssh_id.total..]).await?;
+				let mut end = start + n;
 
-			debug!("read {:?}", n);
+				if n > 0 && line.get(n - 1) == Some(&b'\r') {
+					end -= 1;
+				}
 
-			ssh_id.total += n;
-			#[allow(clippy::indexing_slicing)] // length checked
-			{
-				debug!("{:?}", std::str::from_utf8(&ssh_id.buf[..ssh_id.total]));
-			}
+				ssh_id.bytes_read = start + n + 1;
 
-			if n == 0 {
-				return Err(Error::Disconnect);
-			}
-			#[allow(clippy::indexing_slicing)] // length checked
-			loop {
-				if i >= ssh_id.total - 1 {
-					break;
+				if line.starts_with(b"SSH-") {
+					ssh_id.sshid = start..end;
+
+					return check_ssh_id(ssh_id.id());
 				}
 
-				if ssh_id.buf[i] == b'\r' && ssh_id.buf[i + 1] == b'\n' {
-					ssh_id.bytes_read = i + 2;
+				let skipped = line.get(..n).unwrap_or_default();
+
+				debug!("read_ssh_id: skipping {:?}", String::from_utf8_lossy(skipped));
+
+				lines += 1;
 
-					break;
-				} else if ssh_id.buf[i + 1] == b'\n' {
-					// This is really wrong, but OpenSSH 7.4 uses
-					// it.
-					ssh_id.bytes_read = i + 2;
+				if lines > max_lines {
+					return Err(Error::TooManyPreambleLines(max_lines));
+				}
+
+				if ssh_id.bytes_read > max_bytes {
+					return Err(Error::PreambleTooLong(max_bytes));
+				}
 
-					i += 1;
+				continue;
+			}
 
-					break;
-				} else {
-					i += 1;
+			// No full line yet, fail early if it is already too long.
+			if line.starts_with(b"SSH-") {
+				if line.len() > MAX_SSH_ID_LEN {
+					return Err(Error::Version);
 				}
+			} else if ssh_id.buf.len() > max_bytes {
+				return Err(Error::PreambleTooLong(max_bytes));
 			}
 
-			if ssh_id.bytes_read > 0 {
-				// If we have a full line, handle it.
-				if i >= 8 && ssh_id.buf.get(0..8) == Some(b"SSH-2.0-") {
-					// Either the line starts with "SSH-2.0-"
-					ssh_id.sshid_len = i;
-					#[allow(clippy::indexing_slicing)] // length checked
-					return Ok(&ssh_id.buf[..ssh_id.sshid_len]);
+			let n = AsyncReadExt::read(&mut self.r, &mut chunk).await?;
+
+			debug!("read_ssh_id: read {:?}", n);
+
+			if n == 0 {
+				if line.starts_with(b"SSH-") {
+					// The identification string, without a line ending.
+					ssh_id.sshid = ssh_id.bytes_read..ssh_id.buf.len();
+
+					ssh_id.bytes_read = ssh_id.buf.len();
+
+					return check_ssh_id(ssh_id.id());
 				}
-				// Else, it is a "preliminary" (see
-				// https://tools.ietf.org/html/rfc4253#section-4.2),
-				// and we can discard it and read the next one.
-				ssh_id.total = 0;
 
-				ssh_id.bytes_read = 0;
+				return Err(Error::Disconnect);
 			}
 
-			debug!("bytes_read: {:?}", ssh_id.bytes_read);
+			#[allow(clippy::indexing_slicing)] // n <= chunk.len()
+			ssh_id.buf.extend_from_slice(&chunk[..n]);
 		}
 	}
 }
+
+/// The maximal length of an identification string, without its line
+/// ending (RFC 4253, section 4.2).
+pub(crate) const MAX_SSH_ID_LEN:usize = 253;
+
+/// Check an identification string received from the peer: SSH 2.0 (or
+/// 1.99, meaning 2.0 and 1.x), and not too long.
+fn check_ssh_id(id:&[u8]) -> Result<&[u8], Error> {
+	debug!("read_ssh_id: {:?}", String::from_utf8_lossy(id));
+
+	let version = id.starts_with(b"SSH-2.0-") || id.starts_with(b"SSH-1.99-");
+
+	if !version || id.len() > MAX_SSH_ID_LEN {
+		return Err(Error::Version);
+	}
+
+	Ok(id)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+	use super::*;
+
+	/// The identification string read from `stream`, and what is read
+	/// after it.
+	async fn read(
+		stream:&[u8],
+		max_lines:usize,
+		max_bytes:usize,
+	) -> Result<(Vec<u8>, Vec<u8>), Error> {
+		let mut read = SshRead::new(stream);
+
+		let id = read.read_ssh_id(max_lines, max_bytes).await?.to_vec();
+
+		let mut rest = Vec::new();
+
+		read.read_to_end(&mut rest).await?;
+
+		Ok((id, rest))
+	}
+
+	#[tokio::test]
+	async fn test_line_endings() {
+		let (id, rest) = read(b"SSH-2.0-crlf\r\n\x00\x00\x01", 0, 0).await.unwrap();
+
+		assert_eq!(id, b"SSH-2.0-crlf");
+
+		assert_eq!(rest, b"\x00\x00\x01");
+
+		let (id, rest) = read(b"SSH-2.0-lf comment\n\x00", 0, 0).await.unwrap();
+
+		assert_eq!(id, b"SSH-2.0-lf comment");
+
+		assert_eq!(rest, b"\x00");
+
+		// The stream ends with the identification string.
+		let (id, rest) = read(b"SSH-2.0-eof", 0, 0).await.unwrap();
+
+		assert_eq!(id, b"SSH-2.0-eof");
+
+		assert!(rest.is_empty());
+
+		let (id, _) = read(b"SSH-1.99-compat\r\n", 0, 0).await.unwrap();
+
+		assert_eq!(id, b"SSH-1.99-compat");
+	}
+
+	#[tokio::test]
+	async fn test_preamble() {
+		let stream = b"Welcome\r\n\r\nto the server\nSSH-2.0-server\r\nrest";
+
+		let (id, rest) = read(stream, 3, 1024).await.unwrap();
+
+		assert_eq!(id, b"SSH-2.0-server");
+
+		assert_eq!(rest, b"rest");
+
+		assert!(matches!(read(stream, 2, 1024).await, Err(Error::TooManyPreambleLines(2))));
+
+		assert!(matches!(read(stream, 3, 20).await, Err(Error::PreambleTooLong(20))));
+
+		// A long line without a line ending.
+		let stream = [b'x'; 2000];
+
+		assert!(matches!(read(&stream, 3, 1024).await, Err(Error::PreambleTooLong(1024))));
+
+		// No identification string.
+		assert!(matches!(read(b"Welcome\r\n", 3, 1024).await, Err(Error::Disconnect)));
+	}
+
+	#[tokio::test]
+	async fn test_invalid_version() {
+		assert!(matches!(read(b"SSH-1.5-old\r\n", 0, 0).await, Err(Error::Version)));
+
+		let mut long = b"SSH-2.0-".to_vec();
+
+		long.resize(300, b'x');
+
+		assert!(matches!(read(&long, 0, 0).await, Err(Error::Version)));
+
+		long.extend(b"\r\n");
+
+		assert!(matches!(read(&long, 0, 0).await, Err(Error::Version)));
+	}
+}
@@ -36,6 +36,37 @@ impl SshId {
 		}
 	}
 
+	/// Check that this is a valid SSH 2.0 identification string (RFC
+	/// 4253, section 4.2).
+	pub(crate) fn check(&self) -> Result<(), Error> {
+		if let Self::Raw(s) = self {
+			if !s.ends_with('\n') {
+				return Err(Error::InvalidSshId("no line ending"));
+			}
+		}
+
+		let id = self.as_kex_hash_bytes();
+
+		let software = match id.strip_prefix(b"SSH-2.0-") {
+			Some(rest) => rest.split(|&c| c == b' ').next().unwrap_or_default(),
+			None => return Err(Error::InvalidSshId("not starting with SSH-2.0-")),
+		};
+
+		if id.len() > crate::ssh_read::MAX_SSH_ID_LEN {
+			return Err(Error::InvalidSshId("longer than 255 bytes"));
+		}
+
+		if software.is_empty() || software.iter().any(|&c| !c.is_ascii_graphic() || c == b'-') {
+			return Err(Error::InvalidSshId("invalid software version"));
+		}
+
+		if id.iter().any(|&c| !c.is_ascii_graphic() && c != b' ') {
+			return Err(Error::InvalidSshId("invalid comment"));
+		}
+
+		Ok(())
+	}
+
 	pub(crate) fn write(&self, buffer:&mut CryptoVec) {
 		match self {
 			Self::Standard(s) => buffer.extend(format!("{}\r\n", s).as_bytes()),
@@ -63,6 +94,29 @@ fn test_ssh_id() {
 	assert_eq!(SshId::Raw("SSH-2.0-raw\n".to_string()).as_kex_hash_bytes(), b"SSH-2.0-raw");
 }
 
+#[test]
+fn test_ssh_id_check() {
+	let valid = |id:&str| SshId::Standard(id.to_string()).check().is_ok();
+
+	assert!(valid("SSH-2.0-OurAppliance_1.2"));
+
+	assert!(valid("SSH-2.0-OurAppliance_1.2 a comment"));
+
+	assert!(SshId::Raw("SSH-2.0-raw\r\n".to_string()).check().is_ok());
+
+	assert!(!valid("SSH-1.99-old"));
+
+	assert!(!valid("SSH-2.0-"));
+
+	assert!(!valid("SSH-2.0-Our-Appliance"));
+
+	assert!(!valid("SSH-2.0-OurAppliance\r\nSSH-2.0-again"));
+
+	assert!(!valid(&format!("SSH-2.0-{}", "x".repeat(250))));
+
+	assert!(SshId::Raw("SSH-2.0-raw".to_string()).check().is_err());
+}
+
 #[derive(Debug, Default)]
 pub struct SSHBuffer {
 	pub buffer:CryptoVec,
@@ -71,10 +125,24 @@ pub struct SSHBuffer {
 	// Sequence numbers are on 32 bits and wrap.
 	// https://tools.ietf.org/html/rfc4253#section-6.4
 	pub seqn:Wrapping<u32>,
+	/// The packets written or read, never reset unlike `bytes`.
+	pub packets:u64,
+	/// The bytes of these packets, including framing, padding and MAC.
+	pub wire_bytes:u64,
+	/// The bytes of the payloads of the packets written, before
+	/// compression.
+	pub payload_bytes:u64,
+	/// The compression of the packets written, which lasts across
+	/// packets until the next key exchange.
+	pub compress:crate::compression::Compress,
+	pub compress_buffer:CryptoVec,
+	/// The extra blocks of random padding of the packets written, see
+	/// [`crate::server::Config::random_padding`].
+	pub random_padding:Option<std::ops::RangeInclusive<usize>>,
 }
 
 impl SSHBuffer {
-	pub fn new() -> Self { SSHBuffer { buffer:CryptoVec::new(), len:0, bytes:0, seqn:Wrapping(0) } }
+	pub fn new() -> Self { SSHBuffer::default() }
 
 	pub fn send_ssh_id(&mut self, id:&SshId) { id.write(&mut self.buffer); }
 }
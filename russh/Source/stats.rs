@@ -0,0 +1,279 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sshbuffer::SSHBuffer;
+
+/// Traffic and event counters of a connection, see
+/// [`crate::client::Handle::stats`] and [`crate::server::Handle::stats`].
+///
+/// Counters start with the connection and are never reset, in particular
+/// not by key re-exchanges. The version strings exchanged before the first
+/// packet are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+	/// The bytes of the packets sent, including their framing, padding
+	/// and MAC.
+	pub bytes_sent:u64,
+	/// The bytes of the packets received, including their framing,
+	/// padding and MAC.
+	pub bytes_received:u64,
+	/// The bytes of the payloads of the packets sent, before compression.
+	pub payload_bytes_sent:u64,
+	/// The bytes of the payloads of the packets received, after
+	/// decompression.
+	pub payload_bytes_received:u64,
+	/// The packets sent.
+	pub packets_sent:u64,
+	/// The packets received.
+	pub packets_received:u64,
+	/// The key re-exchanges completed, not counting the first key
+	/// exchange.
+	pub rekeys:u64,
+	/// The channels opened by either side, including those refused.
+	pub channels_opened:u64,
+	/// The authentication requests sent by a client, or received by a
+	/// server.
+	pub auth_attempts:u64,
+}
+
+/// The counters behind [`ConnectionStats`], updated by a session and read
+/// by its handles.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+	bytes_sent:AtomicU64,
+	bytes_received:AtomicU64,
+	payload_bytes_sent:AtomicU64,
+	payload_bytes_received:AtomicU64,
+	packets_sent:AtomicU64,
+	packets_received:AtomicU64,
+	rekeys:AtomicU64,
+	channels_opened:AtomicU64,
+	auth_attempts:AtomicU64,
+}
+
+impl Stats {
+	pub fn snapshot(&self) -> ConnectionStats {
+		ConnectionStats {
+			bytes_sent:self.bytes_sent.load(Ordering::Relaxed),
+			bytes_received:self.bytes_received.load(Ordering::Relaxed),
+			payload_bytes_sent:self.payload_bytes_sent.load(Ordering::Relaxed),
+			payload_bytes_received:self.payload_bytes_received.load(Ordering::Relaxed),
+			packets_sent:self.packets_sent.load(Ordering::Relaxed),
+			packets_received:self.packets_received.load(Ordering::Relaxed),
+			rekeys:self.rekeys.load(Ordering::Relaxed),
+			channels_opened:self.channels_opened.load(Ordering::Relaxed),
+			auth_attempts:self.auth_attempts.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Publish the counters of the buffer packets are written to, once
+	/// they are sent.
+	pub fn sent(&self, write_buffer:&SSHBuffer) {
+		self.bytes_sent.store(write_buffer.wire_bytes, Ordering::Relaxed);
+
+		self.payload_bytes_sent.store(write_buffer.payload_bytes, Ordering::Relaxed);
+
+		self.packets_sent.store(write_buffer.packets, Ordering::Relaxed);
+	}
+
+	/// Publish the counters of the buffer packets are read into, after
+	/// reading a packet whose payload is `payload_len` bytes once
+	/// decompressed.
+	pub fn received(&self, read_buffer:&SSHBuffer, payload_len:usize) {
+		self.bytes_received.store(read_buffer.wire_bytes, Ordering::Relaxed);
+
+		self.payload_bytes_received.fetch_add(payload_len as u64, Ordering::Relaxed);
+
+		self.packets_received.store(read_buffer.packets, Ordering::Relaxed);
+	}
+
+	pub fn rekeyed(&self) { self.rekeys.fetch_add(1, Ordering::Relaxed); }
+
+	pub fn channel_opened(&self) { self.channels_opened.fetch_add(1, Ordering::Relaxed); }
+
+	pub fn auth_attempted(&self) { self.auth_attempts.fetch_add(1, Ordering::Relaxed); }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	/// Handles count the traffic of their session, and handlers are told
+	/// of key re-exchanges and of the end of the session.
+	#[tokio::test]
+	async fn test_connection_stats() {
+		use std::sync::{
+			Arc,
+			atomic::{AtomicUsize, Ordering},
+		};
+
+		use tokio::{
+			sync::oneshot,
+			time::{Duration, timeout},
+		};
+
+		const PAYLOAD:usize = 1 << 20;
+
+		struct Client {
+			rekeys:Arc<AtomicUsize>,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			fn on_rekey(&mut self, _:&crate::Negotiated) {
+				self.rekeys.fetch_add(1, Ordering::SeqCst);
+			}
+		}
+
+		/// Replies once it has received the whole payload.
+		struct ServerHandle {
+			received:usize,
+			rekeys:Arc<AtomicUsize>,
+			closed:Option<oneshot::Sender<bool>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_password(
+				self,
+				_:&str,
+				_:&str,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn data(
+				mut self,
+				channel:ChannelId,
+				data:&[u8],
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				self.received += data.len();
+
+				if self.received == PAYLOAD {
+					session.data(channel, CryptoVec::from_slice(b"done"))?;
+				}
+
+				Ok((self, session))
+			}
+
+			fn on_rekey(&mut self, _:&crate::Negotiated) {
+				self.rekeys.fetch_add(1, Ordering::SeqCst);
+			}
+
+			fn on_close(&mut self, reason:Option<&crate::Error>) {
+				if let Some(closed) = self.closed.take() {
+					closed.send(matches!(reason, Some(crate::Error::Disconnected { .. }))).ok();
+				}
+			}
+		}
+
+		let server_rekeys = Arc::new(AtomicUsize::new(0));
+
+		let (closed, closed_receiver) = oneshot::channel();
+
+		let client_rekeys = Arc::new(AtomicUsize::new(0));
+
+		let (mut client, server_session) = connect_with(
+			client::Config::default(),
+			server::Config::default(),
+			Client { rekeys:client_rekeys.clone() },
+			ServerHandle { received:0, rekeys:server_rekeys.clone(), closed:Some(closed) },
+		)
+		.await;
+
+		let server = server_session.handle();
+
+		assert!(client.authenticate_password("user", "password").await.unwrap().success());
+
+		let before = client.stats();
+
+		let mut channel = client.channel_open_session().await.unwrap();
+
+		let payload = vec![0; PAYLOAD];
+
+		channel.data(&payload[..]).await.unwrap();
+
+		// The reply is read once the server has read the whole payload,
+		// and after the client has accounted for what it sent.
+		timeout(Duration::from_secs(10), async {
+			while !matches!(channel.wait().await, Some(ChannelMsg::Data { .. })) {}
+		})
+		.await
+		.unwrap();
+
+		let sent = client.stats();
+
+		let received = server.stats();
+
+		assert!(sent.payload_bytes_sent >= before.payload_bytes_sent + PAYLOAD as u64);
+
+		assert!(sent.bytes_sent >= sent.payload_bytes_sent);
+
+		assert!(sent.packets_sent > before.packets_sent + (PAYLOAD / 32768) as u64);
+
+		// The server reads everything the client sent after the version
+		// strings, up to the reply.
+		assert_eq!(received.bytes_received, sent.bytes_sent);
+
+		assert_eq!(received.packets_received, sent.packets_sent);
+
+		assert_eq!(received.payload_bytes_received, sent.payload_bytes_sent);
+
+		// Framing, padding and MACs stay well below the payload.
+		assert!(received.bytes_received < 2 * PAYLOAD as u64);
+
+		assert_eq!((sent.channels_opened, received.channels_opened), (1, 1));
+
+		assert_eq!((sent.auth_attempts, received.auth_attempts), (1, 1));
+
+		assert_eq!((sent.rekeys, received.rekeys), (0, 0));
+
+		client.rekey().await.unwrap();
+
+		timeout(Duration::from_secs(5), async {
+			while client_rekeys.load(Ordering::SeqCst) < 1
+				|| server_rekeys.load(Ordering::SeqCst) < 1
+			{
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		let after = client.stats();
+
+		assert_eq!(after.rekeys, 1);
+
+		assert_eq!(server.stats().rekeys, 1);
+
+		assert!(after.bytes_sent > sent.bytes_sent);
+
+		assert!(after.bytes_received >= sent.bytes_received);
+
+		client.disconnect(crate::Disconnect::ByApplication, "", "").await.unwrap();
+
+		assert!(timeout(Duration::from_secs(5), closed_receiver).await.unwrap().unwrap());
+
+		assert!(server_session.await.is_err());
+	}
+}
@@ -0,0 +1,211 @@
+//! Handlers and helpers shared by the tests running a client against a
+//! server. Test modules import them all, with the names they use most,
+//! with `use crate::test_harness::*`.
+
+use std::sync::Arc;
+
+pub(crate) use async_trait::async_trait;
+pub(crate) use russh_cryptovec::CryptoVec;
+pub(crate) use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	sync::mpsc::{UnboundedSender, unbounded_channel},
+};
+
+pub(crate) use crate::{
+	Channel,
+	ChannelId,
+	ChannelMsg,
+	ChannelReadHalf,
+	ChannelStream,
+	cipher,
+	client,
+	kex,
+	mac,
+	server,
+	server::Session,
+	test_session,
+	test_session_with_configs,
+};
+
+/// A client accepting any server key.
+#[derive(Debug)]
+pub(crate) struct Client {}
+
+#[async_trait]
+impl client::Handler for Client {
+	type Error = crate::Error;
+
+	async fn check_server_key(
+		self,
+		_server_public_key:&russh_keys::key::PublicKey,
+	) -> Result<(Self, bool), Self::Error> {
+		Ok((self, true))
+	}
+}
+
+/// A server accepting any user with the `none`, `publickey` or `password`
+/// methods, and their session channels.
+pub(crate) struct Server {}
+
+#[async_trait]
+impl server::Handler for Server {
+	type Error = crate::Error;
+
+	async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_publickey(
+		self,
+		_:&str,
+		_:&russh_keys::key::PublicKey,
+	) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_password(self, _:&str, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn channel_open_session(
+		self,
+		_:Channel<server::Msg>,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, true, session))
+	}
+}
+
+/// A [`Server`] sending back the data it receives on its channels.
+pub(crate) struct EchoServer {}
+
+#[async_trait]
+impl server::Handler for EchoServer {
+	type Error = crate::Error;
+
+	async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_publickey(
+		self,
+		_:&str,
+		_:&russh_keys::key::PublicKey,
+	) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_password(self, _:&str, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn channel_open_session(
+		self,
+		_:Channel<server::Msg>,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		Ok((self, true, session))
+	}
+
+	async fn data(
+		self,
+		channel:ChannelId,
+		data:&[u8],
+		mut session:Session,
+	) -> Result<(Self, Session), Self::Error> {
+		session.data(channel, CryptoVec::from_slice(data))?;
+
+		Ok((self, session))
+	}
+}
+
+/// A [`Server`] sending the session channels it opens to the test.
+pub(crate) struct ChannelServer {
+	pub(crate) channels:UnboundedSender<Channel<server::Msg>>,
+}
+
+#[async_trait]
+impl server::Handler for ChannelServer {
+	type Error = crate::Error;
+
+	async fn auth_none(self, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_publickey(
+		self,
+		_:&str,
+		_:&russh_keys::key::PublicKey,
+	) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn auth_password(self, _:&str, _:&str) -> Result<(Self, server::Auth), Self::Error> {
+		Ok((self, server::Auth::Accept))
+	}
+
+	async fn channel_open_session(
+		self,
+		channel:Channel<server::Msg>,
+		session:Session,
+	) -> Result<(Self, bool, Session), Self::Error> {
+		self.channels.send(channel).unwrap();
+
+		Ok((self, true, session))
+	}
+}
+
+#[cfg(feature = "rs-crypto")]
+pub(crate) fn generate_keypair() -> russh_keys::key::KeyPair {
+	russh_keys::key::KeyPair::generate_ed25519().unwrap()
+}
+
+#[cfg(not(feature = "rs-crypto"))]
+pub(crate) fn generate_keypair() -> russh_keys::key::KeyPair {
+	russh_keys::key::KeyPair::generate_rsa(2048, russh_keys::key::SignatureHash::SHA2_256).unwrap()
+}
+
+/// Run `server` on one end of an in-memory stream, with a new host key if
+/// `server_config` has none, and connect `client` on the other end. The
+/// client is not authenticated yet.
+pub(crate) async fn connect_with<C, S>(
+	client_config:client::Config,
+	mut server_config:server::Config,
+	client:C,
+	server:S,
+) -> (client::Handle<C>, server::RunningSession<S>)
+where
+	C: client::Handler + Send + 'static,
+	C::Error: std::fmt::Debug,
+	S: server::Handler + Send + 'static,
+	S::Error: std::fmt::Debug, {
+	if server_config.keys.is_empty() {
+		server_config.keys.push(generate_keypair());
+	}
+
+	let (client_stream, server_stream) = tokio::io::duplex(65536);
+
+	// The server reads the identification string of the client before
+	// returning.
+	let (server, session) = tokio::join!(
+		server::run_stream(Arc::new(server_config), server_stream, server),
+		client::connect_stream(Arc::new(client_config), client_stream, client),
+	);
+
+	(session.unwrap(), server.unwrap())
+}
+
+/// Run `server` with the default configuration, and connect a [`Client`]
+/// to it, authenticated with the `none` method.
+pub(crate) async fn connect<S>(server:S) -> (client::Handle<Client>, server::RunningSession<S>)
+where
+	S: server::Handler + Send + 'static,
+	S::Error: std::fmt::Debug, {
+	let (mut session, server) =
+		connect_with(client::Config::default(), server::Config::default(), Client {}, server)
+			.await;
+
+	assert!(session.authenticate_none("user").await.unwrap().success());
+
+	(session, server)
+}
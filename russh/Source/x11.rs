@@ -0,0 +1,329 @@
+//! X11 forwarding.
+//!
+//! A client asks for X11 forwarding on a session channel with
+//! [`Channel::request_x11`](crate::Channel::request_x11), sending a fake
+//! authentication cookie, hex-encoded. The server gives this cookie to
+//! the X clients it runs, and forwards each of them on an `x11` channel,
+//! see [`client::Handler::server_channel_open_x11`]. Before connecting to
+//! the X server, the client checks that the connection setup of the X
+//! client carries the fake cookie, and replaces it with the real one,
+//! with [`replace_cookie`]. The real cookie never leaves the client.
+//!
+//! [`client::Handler::server_channel_open_x11`]: crate::client::Handler::server_channel_open_x11
+
+use std::convert::TryFrom;
+
+use subtle::ConstantTimeEq;
+
+/// The size of the fixed part of an X11 connection setup.
+const SETUP_HEADER_LEN:usize = 12;
+
+/// The result of [`replace_cookie`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieReplacement {
+	/// The connection setup is incomplete, more data is needed.
+	Incomplete,
+	/// The connection setup does not carry the fake cookie, the channel
+	/// should be closed.
+	Rejected,
+	/// The data with the real cookie, to send to the X server.
+	Replaced(Vec<u8>),
+}
+
+/// Check that `data`, the beginning of what an X client sent on an `x11`
+/// channel, is a connection setup authenticated with `protocol` and
+/// `fake_cookie`, and replace the cookie with `real_cookie`. The cookies
+/// are binary, not hex-encoded.
+pub fn replace_cookie(
+	data:&[u8],
+	protocol:&str,
+	fake_cookie:&[u8],
+	real_cookie:&[u8],
+) -> CookieReplacement {
+	let big_endian = match data.first() {
+		Some(b'B') => true,
+		Some(b'l') => false,
+		Some(_) => return CookieReplacement::Rejected,
+		None => return CookieReplacement::Incomplete,
+	};
+
+	let read_u16 = |i:usize| {
+		let bytes = [*data.get(i)?, *data.get(i + 1)?];
+
+		Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+	};
+
+	let (name_len, cookie_len) = match (read_u16(6), read_u16(8)) {
+		(Some(name_len), Some(cookie_len)) => (name_len as usize, cookie_len as usize),
+		_ => return CookieReplacement::Incomplete,
+	};
+
+	let name_end = SETUP_HEADER_LEN + padded(name_len);
+
+	let cookie_end = name_end + padded(cookie_len);
+
+	if data.len() < cookie_end {
+		return CookieReplacement::Incomplete;
+	}
+
+	let name = data.get(SETUP_HEADER_LEN..SETUP_HEADER_LEN + name_len);
+
+	let cookie = data.get(name_end..name_end + cookie_len);
+
+	let authenticated = match (name, cookie) {
+		(Some(name), Some(cookie)) => {
+			name == protocol.as_bytes()
+				&& cookie.len() == fake_cookie.len()
+				&& bool::from(cookie.ct_eq(fake_cookie))
+		},
+		_ => false,
+	};
+
+	let real_len = match u16::try_from(real_cookie.len()) {
+		Ok(len) if authenticated => len,
+		_ => return CookieReplacement::Rejected,
+	};
+
+	// The header up to the cookie length, then the rest of the header and
+	// the protocol name.
+	let (prefix, name, rest) =
+		match (data.get(..8), data.get(10..name_end), data.get(cookie_end..)) {
+			(Some(prefix), Some(name), Some(rest)) => (prefix, name, rest),
+			_ => return CookieReplacement::Rejected,
+		};
+
+	let mut replaced = Vec::with_capacity(data.len() + real_cookie.len());
+
+	replaced.extend_from_slice(prefix);
+
+	if big_endian {
+		replaced.extend_from_slice(&real_len.to_be_bytes());
+	} else {
+		replaced.extend_from_slice(&real_len.to_le_bytes());
+	}
+
+	replaced.extend_from_slice(name);
+
+	replaced.extend_from_slice(real_cookie);
+
+	replaced.resize(replaced.len() + padded(real_cookie.len()) - real_cookie.len(), 0);
+
+	replaced.extend_from_slice(rest);
+
+	CookieReplacement::Replaced(replaced)
+}
+
+/// `len` rounded up to a multiple of 4.
+fn padded(len:usize) -> usize { (len + 3) & !3 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::async_yields_async)]
+mod test_sessions {
+	use crate::test_harness::*;
+
+	#[tokio::test]
+	async fn test_x11_forwarding() {
+		use tokio::net::{TcpListener, TcpStream};
+
+		use crate::x11::{CookieReplacement, replace_cookie};
+
+		const PROTOCOL:&str = "MIT-MAGIC-COOKIE-1";
+
+		const FAKE_COOKIE:&[u8] = &[0xfa; 16];
+
+		const REAL_COOKIE:&[u8] = &[0x5e; 16];
+
+		/// The connection setup of an X client, little-endian.
+		fn setup(cookie:&[u8]) -> Vec<u8> {
+			let mut setup = vec![b'l', 0, 11, 0, 0, 0, PROTOCOL.len() as u8, 0, 16, 0, 0, 0];
+
+			setup.extend_from_slice(PROTOCOL.as_bytes());
+
+			setup.extend_from_slice(&[0, 0]);
+
+			setup.extend_from_slice(cookie);
+
+			setup
+		}
+
+		struct Client {
+			x_server:std::net::SocketAddr,
+		}
+
+		#[async_trait]
+		impl client::Handler for Client {
+			type Error = crate::Error;
+
+			async fn check_server_key(
+				self,
+				_server_public_key:&russh_keys::key::PublicKey,
+			) -> Result<(Self, bool), Self::Error> {
+				Ok((self, true))
+			}
+
+			async fn server_channel_open_x11(
+				self,
+				mut channel:Channel<client::Msg>,
+				_:&str,
+				_:u32,
+				session:client::Session,
+			) -> Result<(Self, client::Session), Self::Error> {
+				let x_server = self.x_server;
+
+				tokio::spawn(async move {
+					let mut received = Vec::new();
+
+					let setup = loop {
+						match channel.wait().await {
+							Some(ChannelMsg::Data { data }) => received.extend_from_slice(&data),
+							Some(_) => continue,
+							None => return,
+						}
+
+						match replace_cookie(&received, PROTOCOL, FAKE_COOKIE, REAL_COOKIE) {
+							CookieReplacement::Incomplete => {},
+							CookieReplacement::Rejected => {
+								channel.close().await.unwrap();
+
+								return;
+							},
+							CookieReplacement::Replaced(setup) => break setup,
+						}
+					};
+
+					let mut x_server = TcpStream::connect(x_server).await.unwrap();
+
+					x_server.write_all(&setup).await.unwrap();
+
+					let mut channel = channel.into_stream();
+
+					tokio::io::copy_bidirectional(&mut x_server, &mut channel).await.ok();
+				});
+
+				Ok((self, session))
+			}
+		}
+
+		struct ServerHandle {
+			responses:UnboundedSender<Vec<u8>>,
+		}
+
+		#[async_trait]
+		impl server::Handler for ServerHandle {
+			type Error = crate::Error;
+
+			async fn auth_publickey(
+				self,
+				_:&str,
+				_:&russh_keys::key::PublicKey,
+			) -> Result<(Self, server::Auth), Self::Error> {
+				Ok((self, server::Auth::Accept))
+			}
+
+			async fn channel_open_session(
+				self,
+				_:Channel<server::Msg>,
+				session:Session,
+			) -> Result<(Self, bool, Session), Self::Error> {
+				Ok((self, true, session))
+			}
+
+			async fn x11_request(
+				self,
+				channel:ChannelId,
+				_:bool,
+				x11_auth_protocol:&str,
+				x11_auth_cookie:&str,
+				_:u32,
+				mut session:Session,
+			) -> Result<(Self, Session), Self::Error> {
+				let fake_cookie =
+					FAKE_COOKIE.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+				if x11_auth_protocol != PROTOCOL || x11_auth_cookie != fake_cookie {
+					session.channel_failure(channel);
+
+					return Ok((self, session));
+				}
+
+				session.channel_success(channel);
+
+				// Two X clients, with the fake cookie and with another one.
+				let (handle, responses) = (session.handle(), self.responses.clone());
+
+				tokio::spawn(async move {
+					for cookie in [FAKE_COOKIE, REAL_COOKIE] {
+						let mut channel = handle.channel_open_x11("127.0.0.1", 40000).await?;
+
+						channel.data(&setup(cookie)[..]).await?;
+
+						let mut response = Vec::new();
+
+						while let Some(msg) = channel.wait().await {
+							match msg {
+								ChannelMsg::Data { data } => response.extend_from_slice(&data),
+								ChannelMsg::Eof | ChannelMsg::Close => break,
+								_ => {},
+							}
+						}
+
+						responses.send(response).unwrap();
+					}
+
+					Ok::<_, crate::Error>(())
+				});
+
+				Ok((self, session))
+			}
+		}
+
+		// An X server accepting the real cookie.
+		let x_server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let x_server_addr = x_server.local_addr().unwrap();
+
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = x_server.accept().await {
+				let mut received = vec![0; setup(REAL_COOKIE).len()];
+
+				stream.read_exact(&mut received).await.unwrap();
+
+				let response: &[u8] =
+					if received == setup(REAL_COOKIE) { b"accepted" } else { b"refused" };
+
+				stream.write_all(response).await.unwrap();
+			}
+		});
+
+		let (responses, mut received) = unbounded_channel();
+
+		test_session(
+			Client { x_server:x_server_addr },
+			ServerHandle { responses },
+			|client| {
+				async move {
+					let mut channel = client.channel_open_session().await.unwrap();
+
+					let fake_cookie =
+						FAKE_COOKIE.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+					channel.request_x11(true, false, PROTOCOL, fake_cookie, 0).await.unwrap();
+
+					client
+				}
+			},
+			|server| {
+				async move {
+					// The real cookie was substituted for the fake one.
+					assert_eq!(received.recv().await.unwrap(), b"accepted");
+
+					// Other cookies are rejected before reaching the X server.
+					assert_eq!(received.recv().await.unwrap(), b"");
+
+					server
+				}
+			},
+		)
+		.await;
+	}
+}
@@ -0,0 +1,49 @@
+//! Throughput of `SealingKey::write` on packets of 32 kB, the size of a
+//! full channel data message. Run with `cargo bench --bench write`.
+#![allow(clippy::unwrap_used)]
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use russh::{
+	cipher::{self, CipherRegistry, SSHBuffer},
+	mac::{self, MacRegistry},
+};
+
+fn bench_write(c:&mut Criterion) {
+	let payload = vec![0x5e; 32768];
+
+	let (ciphers, macs) = (CipherRegistry::default(), MacRegistry::default());
+
+	let mut group = c.benchmark_group("write");
+
+	group.throughput(Throughput::Bytes(payload.len() as u64));
+
+	for name in [cipher::AES_256_CTR, cipher::AES_256_GCM, cipher::CHACHA20_POLY1305] {
+		let cipher = if let Some(cipher) = ciphers.get(&name) { cipher } else { continue };
+
+		let mac = macs.get(if cipher.needs_mac() { &mac::HMAC_SHA256 } else { &mac::NONE }).unwrap();
+
+		let key = vec![0x2a; cipher.key_len()];
+
+		let nonce = vec![0x17; cipher.nonce_len()];
+
+		let mac_key = vec![0x55; mac.key_len()];
+
+		let mut sealing = cipher.make_sealing_key(&key, &nonce, &mac_key, mac).unwrap();
+
+		let mut buffer = SSHBuffer::new();
+
+		group.bench_function(name.0, |b| {
+			b.iter(|| {
+				buffer.buffer.clear();
+
+				sealing.write(&payload, &mut buffer).unwrap();
+			})
+		});
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_write);
+
+criterion_main!(benches);
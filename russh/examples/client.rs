@@ -41,7 +41,7 @@ async fn main() {
 
 	let auth_res = auth_res.unwrap();
 
-	println!("=== auth: {}", auth_res);
+	println!("=== auth: {:?}", auth_res);
 
 	let mut channel = session
 		.channel_open_direct_tcpip("localhost", 8000, "localhost", 3333)
@@ -22,7 +22,7 @@ async fn main() {
 
 	let config = Arc::new(config);
 
-	let sh = Server { clients:Arc::new(Mutex::new(HashMap::new())), id:0 };
+	let sh = Server { clients:Arc::new(Mutex::new(HashMap::new())), id:0, peer:None };
 
 	russh::server::run(config, ("0.0.0.0", 2222), sh).await.unwrap();
 }
@@ -31,6 +31,7 @@ async fn main() {
 struct Server {
 	clients:Arc<Mutex<HashMap<(usize, ChannelId), russh::server::Handle>>>,
 	id:usize,
+	peer:Option<std::net::SocketAddr>,
 }
 
 impl Server {
@@ -48,8 +49,8 @@ impl Server {
 impl server::Server for Server {
 	type Handler = Self;
 
-	fn new_client(&mut self, _:Option<std::net::SocketAddr>) -> Self {
-		let s = self.clone();
+	fn new_client(&mut self, peer:Option<std::net::SocketAddr>) -> Self {
+		let s = Server { peer, ..self.clone() };
 
 		self.id += 1;
 
@@ -75,12 +76,31 @@ impl server::Handler for Server {
 		Ok((self, true, session))
 	}
 
+	/// Accept the keys of the `authorized_keys` file named by the
+	/// `AUTHORIZED_KEYS` environment variable (`./authorized_keys` by
+	/// default), honouring their `from=` and `expiry-time=` options.
 	async fn auth_publickey(
 		self,
 		_:&str,
-		_:&key::PublicKey,
+		key:&key::PublicKey,
 	) -> Result<(Self, server::Auth), Self::Error> {
-		Ok((self, server::Auth::Accept))
+		let path =
+			std::env::var("AUTHORIZED_KEYS").unwrap_or_else(|_| "authorized_keys".to_string());
+
+		let now = std::time::SystemTime::now();
+
+		let accepted = authorized_keys::load_authorized_keys(path)?.iter().any(|entry| {
+			entry.key == *key
+				&& !entry.options.cert_authority
+				&& !entry.options.is_expired(now)
+				&& self.peer.map_or(false, |peer| entry.options.from_allows(peer.ip(), None))
+		});
+
+		if accepted {
+			Ok((self, server::Auth::Accept))
+		} else {
+			Ok((self, server::Auth::Reject { proceed_with_methods:None }))
+		}
 	}
 
 	async fn data(
@@ -93,7 +113,7 @@ impl server::Handler for Server {
 
 		self.post(data.clone()).await;
 
-		session.data(channel, data);
+		session.data(channel, data)?;
 
 		Ok((self, session))
 	}
@@ -132,5 +152,5 @@ fn generate_keypair() -> russh_keys::key::KeyPair {
 
 #[cfg(not(feature = "rs-crypto"))]
 fn generate_keypair() -> russh_keys::key::KeyPair {
-	russh_keys::key::KeyPair::generate_rsa(1024, russh_keys::key::SignatureHash::SHA2_512).unwrap()
+	russh_keys::key::KeyPair::generate_rsa(2048, russh_keys::key::SignatureHash::SHA2_512).unwrap()
 }
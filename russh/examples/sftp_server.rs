@@ -1,4 +1,12 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+//! An SFTP server, serving the directory in `SFTP_ROOT`, or the current
+//! directory, to any user with any password or key. Try it with:
+//!
+//! ```text
+//! PORT=2222 cargo run --example sftp_server --features sftp
+//! sftp -P 2222 -o UserKnownHostsFile=/dev/null user@127.0.0.1
+//! ```
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use log::{LevelFilter, error, info};
@@ -6,34 +14,26 @@ use russh::{
 	Channel,
 	ChannelId,
 	server::{Auth, Msg, Session},
+	sftp::server::{FsHandler, SftpServer},
 };
 use russh_keys::key::KeyPair;
-use russh_sftp::protocol::{File, FileAttributes, Handle, Name, Status, StatusCode, Version};
-use tokio::sync::Mutex;
 
 #[derive(Clone)]
-struct Server;
+struct Server {
+	root:PathBuf,
+}
 
 impl russh::server::Server for Server {
 	type Handler = SshSession;
 
-	fn new_client(&mut self, _:Option<SocketAddr>) -> Self::Handler { SshSession::default() }
+	fn new_client(&mut self, _:Option<SocketAddr>) -> Self::Handler {
+		SshSession { root:self.root.clone(), channels:HashMap::new() }
+	}
 }
 
 struct SshSession {
-	clients:Arc<Mutex<HashMap<ChannelId, Channel<Msg>>>>,
-}
-
-impl Default for SshSession {
-	fn default() -> Self { Self { clients:Arc::new(Mutex::new(HashMap::new())) } }
-}
-
-impl SshSession {
-	pub async fn get_channel(&mut self, channel_id:ChannelId) -> Channel<Msg> {
-		let mut clients = self.clients.lock().await;
-
-		clients.remove(&channel_id).unwrap()
-	}
+	root:PathBuf,
+	channels:HashMap<ChannelId, Channel<Msg>>,
 }
 
 #[async_trait]
@@ -61,11 +61,7 @@ impl russh::server::Handler for SshSession {
 		channel:Channel<Msg>,
 		session:Session,
 	) -> Result<(Self, bool, Session), Self::Error> {
-		{
-			let mut clients = self.clients.lock().await;
-
-			clients.insert(channel.id(), channel);
-		}
+		self.channels.insert(channel.id(), channel);
 
 		Ok((self, true, session))
 	}
@@ -78,97 +74,25 @@ impl russh::server::Handler for SshSession {
 	) -> Result<(Self, Session), Self::Error> {
 		info!("subsystem: {}", name);
 
-		if name == "sftp" {
-			let channel = self.get_channel(channel_id).await;
+		match self.channels.remove(&channel_id) {
+			Some(channel) if name == "sftp" => {
+				session.channel_success(channel_id);
 
-			let sftp = SftpSession::default();
+				let server = SftpServer::new(FsHandler::new(self.root.clone()));
 
-			session.channel_success(channel_id);
-
-			russh_sftp::server::run(channel.into_stream(), sftp).await;
-		} else {
-			session.channel_failure(channel_id);
+				tokio::spawn(async move {
+					if let Err(e) = server.run(channel).await {
+						error!("sftp: {}", e);
+					}
+				});
+			},
+			_ => session.channel_failure(channel_id),
 		}
 
 		Ok((self, session))
 	}
 }
 
-#[derive(Default)]
-struct SftpSession {
-	version:Option<u32>,
-	root_dir_read_done:bool,
-}
-
-#[async_trait]
-impl russh_sftp::server::Handler for SftpSession {
-	type Error = StatusCode;
-
-	fn unimplemented(&self) -> Self::Error { StatusCode::OpUnsupported }
-
-	async fn init(
-		&mut self,
-		version:u32,
-		extensions:HashMap<String, String>,
-	) -> Result<Version, Self::Error> {
-		if self.version.is_some() {
-			error!("duplicate SSH_FXP_VERSION packet");
-
-			return Err(StatusCode::ConnectionLost);
-		}
-
-		self.version = Some(version);
-
-		info!("version: {:?}, extensions: {:?}", self.version, extensions);
-
-		Ok(Version::new())
-	}
-
-	async fn close(&mut self, id:u32, _handle:String) -> Result<Status, Self::Error> {
-		Ok(Status {
-			id,
-			status_code:StatusCode::Ok,
-			error_message:"Ok".to_string(),
-			language_tag:"en-US".to_string(),
-		})
-	}
-
-	async fn opendir(&mut self, id:u32, path:String) -> Result<Handle, Self::Error> {
-		info!("opendir: {}", path);
-
-		self.root_dir_read_done = false;
-
-		Ok(Handle { id, handle:path })
-	}
-
-	async fn readdir(&mut self, id:u32, handle:String) -> Result<Name, Self::Error> {
-		info!("readdir handle: {}", handle);
-
-		if handle == "/" && !self.root_dir_read_done {
-			self.root_dir_read_done = true;
-
-			return Ok(Name {
-				id,
-				files:vec![
-					File { filename:"foo".to_string(), attrs:FileAttributes::default() },
-					File { filename:"bar".to_string(), attrs:FileAttributes::default() },
-				],
-			});
-		}
-
-		Ok(Name { id, files:vec![] })
-	}
-
-	async fn realpath(&mut self, id:u32, path:String) -> Result<Name, Self::Error> {
-		info!("realpath: {}", path);
-
-		Ok(Name {
-			id,
-			files:vec![File { filename:"/".to_string(), attrs:FileAttributes::default() }],
-		})
-	}
-}
-
 #[tokio::main]
 async fn main() {
 	env_logger::builder().filter_level(LevelFilter::Debug).init();
@@ -181,7 +105,13 @@ async fn main() {
 		..Default::default()
 	};
 
-	let server = Server;
+	let root = match std::env::var_os("SFTP_ROOT") {
+		Some(root) => PathBuf::from(root),
+		None => std::env::current_dir().unwrap(),
+	};
+
+	// Symbolic links created by clients point to absolute paths.
+	let server = Server { root:root.canonicalize().unwrap() };
 
 	russh::server::run(
 		Arc::new(config),
@@ -197,5 +127,5 @@ fn generate_keypair() -> KeyPair { KeyPair::generate_ed25519().unwrap() }
 
 #[cfg(not(feature = "rs-crypto"))]
 fn generate_keypair() -> KeyPair {
-	KeyPair::generate_rsa(1024, russh_keys::key::SignatureHash::SHA2_512).unwrap()
+	KeyPair::generate_rsa(2048, russh_keys::key::SignatureHash::SHA2_512).unwrap()
 }